@@ -0,0 +1,107 @@
+#ifndef OSBUILD_H
+#define OSBUILD_H
+
+/* Generated with cbindgen from src/lib.rs, don't edit. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * The version of the ABI, it changes whenever a function or a type changes.
+ */
+#define OSBUILD_ABI_VERSION 1
+
+/**
+ * What a call did.
+ */
+typedef enum OsbuildStatus {
+  OSBUILD_STATUS_OK = 0,
+  /**
+   * The manifest or the options are invalid.
+   */
+  OSBUILD_STATUS_INVALID = 1,
+  /**
+   * The build failed.
+   */
+  OSBUILD_STATUS_FAILED = 2,
+  /**
+   * Something else failed, such as loading the modules or a NULL argument.
+   */
+  OSBUILD_STATUS_ERROR = 3,
+} OsbuildStatus;
+
+/**
+ * Callbacks for the progress of a build, all of them are optional. `userdata` is passed to
+ * each of them. Strings are only valid during the call.
+ */
+typedef struct OsbuildMonitor {
+  void *userdata;
+  /**
+   * A pipeline starts, with how many stages it runs.
+   */
+  void (*begin)(void *userdata, const char *pipeline, size_t stages);
+  /**
+   * A stage starts.
+   */
+  void (*stage)(void *userdata, size_t index, const char *name);
+  void (*log)(void *userdata, const char *message);
+  /**
+   * A stage is done, with how long it ran in milliseconds.
+   */
+  void (*result)(void *userdata, size_t index, bool success, uint64_t duration);
+  /**
+   * The build is done.
+   */
+  void (*finish)(void *userdata, bool success);
+} OsbuildMonitor;
+
+uint32_t osbuild_abi_version(void);
+
+/**
+ * Validate the manifest `manifest` against the format schemas. `diagnostics` is set to a JSON
+ * array with the `severity`, `rule`, `path`, and `message` of each diagnostic, or to why the
+ * manifest couldn't be validated. Returns `OSBUILD_STATUS_INVALID` when there are errors.
+ *
+ * # Safety
+ *
+ * `manifest` is a NUL-terminated string, `diagnostics` is NULL or points to a string pointer.
+ */
+OsbuildStatus osbuild_validate(const char *manifest, char **diagnostics);
+
+/**
+ * Plan the manifest `manifest`. `plan` is set to the plan as JSON, as `osbuild plan --json`
+ * prints it, or to why the manifest couldn't be planned.
+ *
+ * # Safety
+ *
+ * `manifest` is a NUL-terminated string, `plan` is NULL or points to a string pointer.
+ */
+OsbuildStatus osbuild_plan(const char *manifest, char **plan);
+
+/**
+ * Build the manifest `manifest` with `options`, a JSON object with the settings of the
+ * configuration file such as `store` and `module-paths`. Progress is reported to `monitor`.
+ * `error` is set to why the build failed, or to an empty string.
+ *
+ * # Safety
+ *
+ * `manifest` and `options` are NUL-terminated strings, `options` and `monitor` may be NULL,
+ * `error` is NULL or points to a string pointer. The callbacks of `monitor` are called on the
+ * calling thread before the build returns.
+ */
+OsbuildStatus osbuild_build(const char *manifest,
+                            const char *options,
+                            const struct OsbuildMonitor *monitor,
+                            char **error);
+
+/**
+ * Free a string returned by any of the other functions, NULL is ignored.
+ *
+ * # Safety
+ *
+ * `text` is NULL or a string returned by this library that wasn't freed yet.
+ */
+void osbuild_string_free(char *text);
+
+#endif /* OSBUILD_H */
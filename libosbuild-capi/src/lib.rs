@@ -0,0 +1,467 @@
+//! A C ABI to validate, plan, and build manifests from other languages, such as Go in
+//! osbuild-composer. Strings are NUL-terminated UTF-8. Strings returned through out parameters
+//! belong to the caller and are freed with `osbuild_string_free`. A call that panics returns
+//! `OSBUILD_STATUS_ERROR` with why it panicked rather than unwinding into the caller.
+//!
+//! `include/osbuild.h` is generated from this file with
+//! `cbindgen --config cbindgen.toml --output include/osbuild.h`.
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use libosbuild::core::config::{BuildConfig, Config};
+use libosbuild::core::executor::build::{build_with_config, BuildError};
+use libosbuild::core::executor::inputs::Content;
+use libosbuild::core::executor::plan;
+use libosbuild::core::id::HashAlgo;
+use libosbuild::core::monitor::Monitor;
+use libosbuild::manifest::description::v2::Validator;
+use libosbuild::manifest::{include, variables, Manifest};
+
+/// The version of the ABI, it changes whenever a function or a type changes.
+pub const OSBUILD_ABI_VERSION: u32 = 1;
+
+/// What a call did.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsbuildStatus {
+    Ok = 0,
+
+    /// The manifest or the options are invalid.
+    Invalid = 1,
+
+    /// The build failed.
+    Failed = 2,
+
+    /// Something else failed, such as loading the modules or a NULL argument.
+    Error = 3,
+}
+
+/// Callbacks for the progress of a build, all of them are optional. `userdata` is passed to
+/// each of them. Strings are only valid during the call.
+#[repr(C)]
+pub struct OsbuildMonitor {
+    pub userdata: *mut c_void,
+
+    /// A pipeline starts, with how many stages it runs.
+    pub begin: Option<extern "C" fn(userdata: *mut c_void, pipeline: *const c_char, stages: usize)>,
+
+    /// A stage starts.
+    pub stage: Option<extern "C" fn(userdata: *mut c_void, index: usize, name: *const c_char)>,
+
+    pub log: Option<extern "C" fn(userdata: *mut c_void, message: *const c_char)>,
+
+    /// A stage is done, with how long it ran in milliseconds.
+    pub result:
+        Option<extern "C" fn(userdata: *mut c_void, index: usize, success: bool, duration: u64)>,
+
+    /// The build is done.
+    pub finish: Option<extern "C" fn(userdata: *mut c_void, success: bool)>,
+}
+
+/// Forwards to the callbacks of an `OsbuildMonitor`.
+struct CallbackMonitor<'m> {
+    callbacks: &'m OsbuildMonitor,
+}
+
+/// `text` as a C string, NUL bytes in it can't be passed and are dropped.
+fn c_string(text: &str) -> CString {
+    CString::new(text.replace('\0', "")).unwrap_or_default()
+}
+
+impl Monitor for CallbackMonitor<'_> {
+    fn begin(&mut self, pipeline: &str, stages: &[String]) {
+        if let Some(begin) = self.callbacks.begin {
+            begin(
+                self.callbacks.userdata,
+                c_string(pipeline).as_ptr(),
+                stages.len(),
+            );
+        }
+    }
+
+    fn stage(&mut self, index: usize, name: &str) {
+        if let Some(stage) = self.callbacks.stage {
+            stage(self.callbacks.userdata, index, c_string(name).as_ptr());
+        }
+    }
+
+    fn log(&mut self, message: &str) {
+        if let Some(log) = self.callbacks.log {
+            log(self.callbacks.userdata, c_string(message).as_ptr());
+        }
+    }
+
+    fn result(&mut self, index: usize, success: bool, duration: std::time::Duration) {
+        if let Some(result) = self.callbacks.result {
+            result(
+                self.callbacks.userdata,
+                index,
+                success,
+                duration.as_millis() as u64,
+            );
+        }
+    }
+
+    fn finish(&mut self, success: bool) {
+        if let Some(finish) = self.callbacks.finish {
+            finish(self.callbacks.userdata, success);
+        }
+    }
+}
+
+/// Why a call failed, with the message for the caller.
+type Failure = (OsbuildStatus, String);
+
+fn invalid(message: impl ToString) -> Failure {
+    (OsbuildStatus::Invalid, message.to_string())
+}
+
+fn error(message: impl ToString) -> Failure {
+    (OsbuildStatus::Error, message.to_string())
+}
+
+/// The manifest in `text` with the sources it includes merged in, relative to the current
+/// directory, and its variables expanded.
+fn load_value(text: &str) -> Result<serde_json::Value, Failure> {
+    let mut manifest: serde_json::Value = serde_json::from_str(text).map_err(invalid)?;
+
+    include::resolve_sources(&mut manifest, Path::new(""))
+        .map_err(|err| invalid(format!("{:?}", err)))?;
+    variables::expand(&mut manifest).map_err(|err| invalid(format!("{:?}", err)))?;
+
+    Ok(manifest)
+}
+
+fn load_manifest(text: &str) -> Result<Manifest, Failure> {
+    serde_json::from_value(load_value(text)?).map_err(invalid)
+}
+
+fn validate(text: &str) -> Result<String, Failure> {
+    let result = Validator::against_format_schema()
+        .map_err(|err| error(format!("{:?}", err)))?
+        .validate(&load_value(text)?);
+
+    let diagnostics: Vec<serde_json::Value> = result
+        .diagnostics()
+        .map(|diagnostic| {
+            serde_json::json!({
+                "severity": diagnostic.severity.name(),
+                "rule": diagnostic.rule.id(),
+                "path": diagnostic.path.to_string(),
+                "message": diagnostic.message,
+            })
+        })
+        .collect();
+
+    let diagnostics = serde_json::Value::Array(diagnostics).to_string();
+
+    if result.is_valid() {
+        Ok(diagnostics)
+    } else {
+        Err((OsbuildStatus::Invalid, diagnostics))
+    }
+}
+
+fn plan(text: &str) -> Result<String, Failure> {
+    let plan = plan::plan(&load_manifest(text)?, &Content::new(), HashAlgo::default())
+        .map_err(|err| invalid(format!("{:?}", err)))?;
+
+    serde_json::to_string(&plan).map_err(error)
+}
+
+/// Build `text` with the configuration `options`, the JSON form of the configuration file.
+/// A store is required, workspaces go in its `tmp` directory unless configured otherwise.
+fn build(text: &str, options: Option<&str>, monitor: &mut dyn Monitor) -> Result<(), Failure> {
+    let config: Config = match options {
+        Some(options) => serde_json::from_str(options).map_err(invalid)?,
+        None => Config::default(),
+    };
+
+    build_with_config(
+        &load_manifest(text)?,
+        &BuildConfig::from_config(&config),
+        monitor,
+        None,
+    )
+    .map(|_| ())
+    .map_err(|err| match err {
+        BuildError::NoStore => invalid("building needs a store"),
+        BuildError::ExecutorError(err) => (OsbuildStatus::Failed, format!("{:?}", err)),
+        err => error(format!("{:?}", err)),
+    })
+}
+
+/// What `call` returns, or an error when it panics; unwinding out of a function of the ABI
+/// would abort the caller.
+fn guarded<T>(call: impl FnOnce() -> Result<T, Failure>) -> Result<T, Failure> {
+    panic::catch_unwind(AssertUnwindSafe(call)).unwrap_or_else(|panic| {
+        let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+            (Some(message), _) => message.to_string(),
+            (_, Some(message)) => message.clone(),
+            _ => "unknown".to_string(),
+        };
+
+        Err(error(format!("panicked: {}", message)))
+    })
+}
+
+/// The string at `text`, which may not be NULL.
+unsafe fn argument<'a>(text: *const c_char) -> Result<&'a str, Failure> {
+    if text.is_null() {
+        return Err(error("NULL argument"));
+    }
+
+    CStr::from_ptr(text).to_str().map_err(invalid)
+}
+
+/// Hand the outcome of a call to the caller; what it returned or why it failed goes to `out`,
+/// when it isn't NULL.
+unsafe fn status(result: Result<String, Failure>, out: *mut *mut c_char) -> OsbuildStatus {
+    let (status, text) = match result {
+        Ok(text) => (OsbuildStatus::Ok, text),
+        Err((status, message)) => (status, message),
+    };
+
+    if !out.is_null() {
+        *out = c_string(&text).into_raw();
+    }
+
+    status
+}
+
+#[no_mangle]
+pub extern "C" fn osbuild_abi_version() -> u32 {
+    OSBUILD_ABI_VERSION
+}
+
+/// Validate the manifest `manifest` against the format schemas. `diagnostics` is set to a JSON
+/// array with the `severity`, `rule`, `path`, and `message` of each diagnostic, or to why the
+/// manifest couldn't be validated. Returns `OSBUILD_STATUS_INVALID` when there are errors.
+///
+/// # Safety
+///
+/// `manifest` is a NUL-terminated string, `diagnostics` is NULL or points to a string pointer.
+#[no_mangle]
+pub unsafe extern "C" fn osbuild_validate(
+    manifest: *const c_char,
+    diagnostics: *mut *mut c_char,
+) -> OsbuildStatus {
+    status(
+        guarded(|| argument(manifest).and_then(validate)),
+        diagnostics,
+    )
+}
+
+/// Plan the manifest `manifest`. `plan` is set to the plan as JSON, as `osbuild plan --json`
+/// prints it, or to why the manifest couldn't be planned.
+///
+/// # Safety
+///
+/// `manifest` is a NUL-terminated string, `plan` is NULL or points to a string pointer.
+#[no_mangle]
+pub unsafe extern "C" fn osbuild_plan(
+    manifest: *const c_char,
+    plan: *mut *mut c_char,
+) -> OsbuildStatus {
+    status(guarded(|| argument(manifest).and_then(self::plan)), plan)
+}
+
+/// Build the manifest `manifest` with `options`, a JSON object with the settings of the
+/// configuration file such as `store` and `module-paths`. Progress is reported to `monitor`.
+/// `error` is set to why the build failed, or to an empty string.
+///
+/// # Safety
+///
+/// `manifest` and `options` are NUL-terminated strings, `options` and `monitor` may be NULL,
+/// `error` is NULL or points to a string pointer. The callbacks of `monitor` are called on the
+/// calling thread before the build returns.
+#[no_mangle]
+pub unsafe extern "C" fn osbuild_build(
+    manifest: *const c_char,
+    options: *const c_char,
+    monitor: *const OsbuildMonitor,
+    error: *mut *mut c_char,
+) -> OsbuildStatus {
+    let mut callbacks = monitor
+        .as_ref()
+        .map(|callbacks| CallbackMonitor { callbacks });
+    let mut null = libosbuild::core::monitor::NullMonitor {};
+    let monitor: &mut dyn Monitor = match &mut callbacks {
+        Some(callbacks) => callbacks,
+        None => &mut null,
+    };
+
+    let result = guarded(|| {
+        argument(manifest).and_then(|manifest| {
+            let options = match options.is_null() {
+                true => None,
+                false => Some(argument(options)?),
+            };
+
+            build(manifest, options, &mut *monitor)
+        })
+    });
+
+    monitor.finish(result.is_ok());
+
+    status(result.map(|_| String::new()), error)
+}
+
+/// Free a string returned by any of the other functions, NULL is ignored.
+///
+/// # Safety
+///
+/// `text` is NULL or a string returned by this library that wasn't freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn osbuild_string_free(text: *mut c_char) {
+    if !text.is_null() {
+        drop(CString::from_raw(text));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::ptr;
+
+    use libosbuild::core::store::ObjectStore;
+
+    const MANIFEST: &str = r#"{
+        "version": "2",
+        "pipelines": [
+            {"name": "tree", "stages": [{"type": "org.osbuild.touch"}]}
+        ]
+    }"#;
+
+    unsafe fn call(
+        function: unsafe extern "C" fn(*const c_char, *mut *mut c_char) -> OsbuildStatus,
+        manifest: &str,
+    ) -> (OsbuildStatus, String) {
+        let manifest = c_string(manifest);
+        let mut out = ptr::null_mut();
+
+        let status = function(manifest.as_ptr(), &mut out);
+        let text = CStr::from_ptr(out).to_string_lossy().to_string();
+
+        osbuild_string_free(out);
+
+        (status, text)
+    }
+
+    extern "C" fn record(userdata: *mut c_void, index: usize, name: *const c_char) {
+        let events = unsafe { &mut *(userdata as *mut Vec<String>) };
+        let name = unsafe { CStr::from_ptr(name) };
+
+        events.push(format!("stage {} {}", index, name.to_string_lossy()));
+    }
+
+    extern "C" fn finish(userdata: *mut c_void, success: bool) {
+        let events = unsafe { &mut *(userdata as *mut Vec<String>) };
+
+        events.push(format!("finish {}", success));
+    }
+
+    #[test]
+    fn manifests_validated_and_planned() {
+        unsafe {
+            assert_eq!(
+                call(osbuild_validate, MANIFEST),
+                (OsbuildStatus::Ok, "[]".to_string())
+            );
+
+            let (status, diagnostics) =
+                call(osbuild_validate, r#"{"version": "2", "pipelines": 1}"#);
+
+            assert_eq!(status, OsbuildStatus::Invalid);
+            assert!(diagnostics.contains("\"severity\":\"error\""));
+
+            let (status, plan) = call(osbuild_plan, MANIFEST);
+            let plan: serde_json::Value = serde_json::from_str(&plan).unwrap();
+
+            assert_eq!(status, OsbuildStatus::Ok);
+            assert_eq!(plan["stages"][0]["type"], "org.osbuild.touch");
+            assert_eq!(call(osbuild_plan, "{").0, OsbuildStatus::Invalid);
+            assert_eq!(
+                osbuild_plan(ptr::null(), ptr::null_mut()),
+                OsbuildStatus::Error
+            );
+        }
+    }
+
+    #[test]
+    fn panics_guarded() {
+        let result: Result<(), Failure> = guarded(|| panic!("out of bounds"));
+
+        assert_eq!(
+            result,
+            Err((OsbuildStatus::Error, "panicked: out of bounds".to_string()))
+        );
+        assert_eq!(guarded(|| Ok::<_, Failure>(1)), Ok(1));
+    }
+
+    #[test]
+    fn manifests_built() {
+        let directory = tempfile::tempdir().unwrap();
+        let stages = directory.path().join("modules/stages");
+        let store = directory.path().join("store");
+
+        fs::create_dir_all(&stages).unwrap();
+        fs::write(
+            stages.join("org.osbuild.touch"),
+            "#!/bin/sh\n[ \"$1\" = --schema ] && echo '{}' && exit 0\ntree=$(sed -n 's/.*\"tree\":\"\\([^\"]*\\)\".*/\\1/p')\ntouch \"$tree/built\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(
+            stages.join("org.osbuild.touch"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+
+        let options = c_string(
+            &serde_json::json!({
+                "store": store,
+                "module-paths": [directory.path().join("modules")]
+            })
+            .to_string(),
+        );
+
+        let mut events: Vec<String> = vec![];
+        let monitor = OsbuildMonitor {
+            userdata: &mut events as *mut Vec<String> as *mut c_void,
+            begin: None,
+            stage: Some(record),
+            log: None,
+            result: None,
+            finish: Some(finish),
+        };
+
+        let manifest = c_string(MANIFEST);
+        let mut error = ptr::null_mut();
+
+        let status =
+            unsafe { osbuild_build(manifest.as_ptr(), options.as_ptr(), &monitor, &mut error) };
+
+        unsafe { osbuild_string_free(error) };
+
+        assert_eq!(status, OsbuildStatus::Ok);
+        assert_eq!(events, ["stage 0 org.osbuild.touch", "finish true"]);
+
+        let objects = ObjectStore::new(&store);
+        let id = objects.ids().unwrap().pop().unwrap();
+
+        assert!(objects.tree_path(&id).unwrap().join("built").exists());
+
+        // without a store nothing is built
+        events.clear();
+
+        let status =
+            unsafe { osbuild_build(manifest.as_ptr(), ptr::null(), &monitor, ptr::null_mut()) };
+
+        assert_eq!(status, OsbuildStatus::Invalid);
+        assert_eq!(events, ["finish false"]);
+    }
+}
@@ -2,6 +2,6 @@
 mod test {
     #[test]
     fn dummy() {
-        assert!(true);
+        assert_eq!(1, 1);
     }
 }
@@ -1,20 +1,198 @@
+//! Python bindings of the manifest loader, the validator, and the planner, so that osbuild and
+//! composer can call into them from Python. Manifests are passed in as JSON text and results
+//! are returned as Python objects.
+use std::path::Path;
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+
+use libosbuild::core::executor;
+use libosbuild::core::executor::inputs::Content;
+use libosbuild::core::id::HashAlgo;
+use libosbuild::manifest::description::v2::Validator;
+use libosbuild::manifest::include;
+use libosbuild::manifest::source::Source;
+use libosbuild::manifest::variables;
+use libosbuild::manifest::Manifest;
+
+#[derive(Debug)]
+enum BindingError {
+    /// The manifest can't be loaded or planned, raised as a `ValueError`.
+    Manifest(String),
+
+    /// Something other than the manifest failed, raised as a `RuntimeError`.
+    Internal(String),
+}
+
+impl From<BindingError> for PyErr {
+    fn from(err: BindingError) -> Self {
+        match err {
+            BindingError::Manifest(message) => PyValueError::new_err(message),
+            BindingError::Internal(message) => PyRuntimeError::new_err(message),
+        }
+    }
+}
+
+/// The manifest in `text` with the sources it names in `sources-from` merged in, these are
+/// relative to `base`, and its variables expanded.
+fn load_value(text: &str, base: &Path) -> Result<serde_json::Value, BindingError> {
+    let source = Source::parse("manifest", text)
+        .map_err(|err| BindingError::Manifest(format!("{:?}", err)))?;
+
+    let mut manifest: serde_json::Value = serde_json::from_str(source.text())
+        .map_err(|err| BindingError::Manifest(err.to_string()))?;
+
+    include::resolve_sources(&mut manifest, base)
+        .map_err(|err| BindingError::Manifest(format!("{:?}", err)))?;
+    variables::expand(&mut manifest).map_err(|err| BindingError::Manifest(format!("{:?}", err)))?;
+
+    Ok(manifest)
+}
+
+fn load_manifest(text: &str, base: &Path) -> Result<Manifest, BindingError> {
+    serde_json::from_value(load_value(text, base)?)
+        .map_err(|err| BindingError::Manifest(err.to_string()))
+}
+
+/// The diagnostics of validating the manifest in `text` against the format schemas, errors
+/// first.
+fn diagnostics(text: &str, base: &Path) -> Result<Vec<serde_json::Value>, BindingError> {
+    let manifest = load_value(text, base)?;
+    let result = Validator::against_format_schema()
+        .map_err(|err| BindingError::Internal(format!("{:?}", err)))?
+        .validate(&manifest);
+
+    Ok(result
+        .diagnostics()
+        .map(|diagnostic| {
+            serde_json::json!({
+                "severity": diagnostic.severity.name(),
+                "rule": diagnostic.rule.id(),
+                "path": diagnostic.path.to_string(),
+                "message": diagnostic.message,
+            })
+        })
+        .collect())
+}
+
+/// The plan of the manifest in `text`, as `osbuild plan --json` prints it.
+fn plan_value(text: &str, base: &Path) -> Result<serde_json::Value, BindingError> {
+    let manifest = load_manifest(text, base)?;
+    let plan = executor::plan::plan(&manifest, &Content::new(), HashAlgo::default())
+        .map_err(|err| BindingError::Manifest(format!("{:?}", err)))?;
+
+    serde_json::to_value(plan).map_err(|err| BindingError::Internal(err.to_string()))
+}
+
+fn to_python(py: Python<'_>, value: &serde_json::Value) -> PyResult<PyObject> {
+    Ok(match value {
+        serde_json::Value::Null => py.None(),
+        serde_json::Value::Bool(value) => value.to_object(py),
+        serde_json::Value::Number(number) => match (number.as_i64(), number.as_u64()) {
+            (Some(number), _) => number.to_object(py),
+            (_, Some(number)) => number.to_object(py),
+            _ => number.as_f64().to_object(py),
+        },
+        serde_json::Value::String(value) => value.to_object(py),
+        serde_json::Value::Array(values) => {
+            let list = PyList::empty(py);
+
+            for value in values {
+                list.append(to_python(py, value)?)?;
+            }
+
+            list.to_object(py)
+        }
+        serde_json::Value::Object(values) => {
+            let dict = PyDict::new(py);
+
+            for (key, value) in values {
+                dict.set_item(key, to_python(py, value)?)?;
+            }
 
+            dict.to_object(py)
+        }
+    })
+}
+
+/// Load the manifest in the JSON text `text` and return it as a dict, with the sources it
+/// includes merged in. Those are relative to `base`, the current directory by default.
+#[pyfunction]
+fn load(py: Python<'_>, text: &str, base: Option<&str>) -> PyResult<PyObject> {
+    let manifest = load_value(text, Path::new(base.unwrap_or("")))?;
+
+    // only what would load is returned
+    serde_json::from_value::<Manifest>(manifest.clone())
+        .map_err(|err| BindingError::Manifest(err.to_string()))?;
+
+    to_python(py, &manifest)
+}
+
+/// Validate the manifest in the JSON text `text` against the format schemas. Returns a list
+/// of dicts with the `severity`, `rule`, `path`, and `message` of each diagnostic, the
+/// manifest is valid when none has the severity `error`.
+#[pyfunction]
+fn validate(py: Python<'_>, text: &str, base: Option<&str>) -> PyResult<PyObject> {
+    let diagnostics = diagnostics(text, Path::new(base.unwrap_or("")))?;
+
+    to_python(py, &serde_json::Value::Array(diagnostics))
+}
+
+/// Plan the manifest in the JSON text `text`; the stages it runs in order, with their ids.
 #[pyfunction]
-fn sum_as_string(a: usize, b: usize) -> PyResult<String> {
-    Ok((a + b).to_string())
+fn plan(py: Python<'_>, text: &str, base: Option<&str>) -> PyResult<PyObject> {
+    let plan = plan_value(text, Path::new(base.unwrap_or("")))?;
+
+    to_python(py, &plan)
 }
 
 #[pymodule]
 fn libosbuild_py(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(sum_as_string, m)?)?;
+    m.add_function(wrap_pyfunction!(load, m)?)?;
+    m.add_function(wrap_pyfunction!(validate, m)?)?;
+    m.add_function(wrap_pyfunction!(plan, m)?)?;
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    const MANIFEST: &str = r#"{
+        "version": "2",
+        "pipelines": [
+            {"name": "tree", "stages": [{"type": "org.osbuild.noop"}]}
+        ]
+    }"#;
+
+    #[test]
+    fn manifests_loaded() {
+        let manifest = load_manifest(MANIFEST, Path::new("")).unwrap();
+
+        assert_eq!(manifest.pipelines().len(), 1);
+        assert!(matches!(
+            load_value("{", Path::new("")),
+            Err(BindingError::Manifest(_))
+        ));
+    }
+
     #[test]
-    fn dummy() {
-        assert!(true);
+    fn manifests_validated() {
+        assert!(diagnostics(MANIFEST, Path::new("")).unwrap().is_empty());
+
+        let diagnostics =
+            diagnostics(r#"{"version": "2", "pipelines": 1}"#, Path::new("")).unwrap();
+
+        assert!(!diagnostics.is_empty());
+        assert_eq!(diagnostics[0]["severity"], "error");
+    }
+
+    #[test]
+    fn manifests_planned() {
+        let plan = plan_value(MANIFEST, Path::new("")).unwrap();
+
+        assert_eq!(plan["stages"][0]["pipeline"], "tree");
+        assert_eq!(plan["stages"][0]["type"], "org.osbuild.noop");
     }
 }
@@ -0,0 +1,144 @@
+//! Benchmarks of the hot paths of `libosbuild`, run with `cargo bench -p libosbuild`.
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use libosbuild::core::id::{self, HashAlgo};
+use libosbuild::manifest;
+use libosbuild::manifest::path::{Part, Path};
+use libosbuild::manifest::stream;
+use libosbuild::sandbox::communication::channel::protocol::message::encoding::{
+    Encoding, JSONEncoding,
+};
+use libosbuild::sandbox::communication::channel::protocol::message::Method;
+
+/// A version 2 manifest with `pipelines` pipelines of a few stages each, and a source item
+/// per stage.
+fn manifest(pipelines: usize) -> Vec<u8> {
+    let mut items = serde_json::Map::new();
+
+    let pipelines: Vec<serde_json::Value> = (0..pipelines)
+        .map(|index| {
+            let checksum = format!("sha256:{:064x}", index);
+
+            items.insert(
+                checksum.clone(),
+                serde_json::json!({"url": format!("https://example.com/{}.rpm", index)}),
+            );
+
+            serde_json::json!({
+                "name": format!("pipeline-{}", index),
+                "stages": [
+                    {"type": "org.osbuild.rpm", "inputs": {"packages": {"references": [checksum]}}},
+                    {"type": "org.osbuild.locale", "options": {"language": "en_US.UTF-8"}},
+                    {
+                        "type": "org.osbuild.copy",
+                        "devices": {"disk": {"type": "org.osbuild.loopback"}},
+                        "mounts": [{"name": "root", "type": "org.osbuild.ext4", "source": "disk"}]
+                    }
+                ]
+            })
+        })
+        .collect();
+
+    serde_json::to_vec(&serde_json::json!({
+        "version": "2",
+        "pipelines": pipelines,
+        "sources": {"org.osbuild.curl": {"items": items}}
+    }))
+    .unwrap()
+}
+
+fn manifest_parsing(c: &mut Criterion) {
+    let data = manifest(1000);
+    let mut group = c.benchmark_group("manifest");
+
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("pipeline_names", |b| {
+        b.iter(|| manifest::pipeline_names(black_box(&data)).unwrap())
+    });
+    group.bench_function("module_names", |b| {
+        b.iter(|| manifest::module_names(black_box(&data)).unwrap())
+    });
+    group.bench_function("stream_sources", |b| {
+        b.iter(|| stream::stream_sources(black_box(&data[..]), |_, _, _| {}).unwrap())
+    });
+    group.finish();
+}
+
+fn path_formatting(c: &mut Criterion) {
+    let path = Path::new(vec![
+        Part::Name("pipelines".to_string()),
+        Part::Index(12),
+        Part::Name("stages".to_string()),
+        Part::Index(3),
+        Part::Name("options".to_string()),
+        Part::Name("with spaces".to_string()),
+    ]);
+
+    c.bench_function("path/display", |b| b.iter(|| black_box(&path).to_string()));
+}
+
+fn message_encoding(c: &mut Criterion) {
+    let encoding = JSONEncoding {};
+    let method = Method::new("add", serde_json::json!({"name": "org.osbuild.rpm"}));
+    let encoded = String::from_utf8(encoding.encode(method.clone()).unwrap()).unwrap();
+
+    let mut group = c.benchmark_group("message");
+
+    group.bench_function("encode", |b| {
+        b.iter_batched(
+            || method.clone(),
+            |method| encoding.encode(method).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| encoding.decode::<Method>(black_box(&encoded)).unwrap())
+    });
+    group.finish();
+}
+
+fn object_hashing(c: &mut Criterion) {
+    let data = vec![0x5au8; 4 * 1024 * 1024];
+    let options = serde_json::json!({
+        "packages": (0..500).map(|index| format!("package-{}", index)).collect::<Vec<_>>(),
+        "language": "en_US.UTF-8",
+        "description": "Ünïcödé"
+    });
+
+    let mut group = c.benchmark_group("hashing");
+
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("sha256", |b| {
+        b.iter(|| HashAlgo::Sha256.hash_reader(black_box(&data[..])).unwrap())
+    });
+    #[cfg(feature = "blake3")]
+    group.bench_function("blake3", |b| {
+        b.iter(|| HashAlgo::Blake3.hash_reader(black_box(&data[..])).unwrap())
+    });
+    group.finish();
+
+    c.bench_function("hashing/canonical_json", |b| {
+        b.iter(|| id::canonical_json(black_box(&options)))
+    });
+    c.bench_function("hashing/stage_id", |b| {
+        b.iter(|| {
+            id::stage_id(
+                HashAlgo::Sha256,
+                "org.osbuild.rpm",
+                None,
+                None,
+                black_box(&options),
+                black_box(&serde_json::json!({})),
+            )
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    manifest_parsing,
+    path_formatting,
+    message_encoding,
+    object_hashing
+);
+criterion_main!(benches);
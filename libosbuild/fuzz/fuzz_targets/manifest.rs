@@ -0,0 +1,20 @@
+//! Manifests are read from users and from other services, none of the ways of reading them
+//! may panic on malformed input.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use libosbuild::manifest::description::v2::Validator;
+use libosbuild::manifest::{self, stream, Version};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = manifest::pipeline_names(data);
+    let _ = manifest::module_names(data);
+    let _ = stream::stream_sources(data, |_, _, _| {});
+
+    if let Ok(manifest) = serde_json::from_slice::<serde_json::Value>(data) {
+        if let Ok(Version::V2) = Version::detect(&manifest) {
+            let _ = Validator::new().validate(&manifest);
+        }
+    }
+});
@@ -0,0 +1,21 @@
+//! Messages are received from modules running in the build root, decoding whatever they
+//! send may not panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use libosbuild::sandbox::communication::channel::protocol::message::encoding::{
+    Encoding, JSONEncoding,
+};
+use libosbuild::sandbox::communication::channel::protocol::message::{
+    Exception, Method, Reply, Signal,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let encoding = JSONEncoding {};
+
+    let _ = encoding.decode_bytes::<Method>(data);
+    let _ = encoding.decode_bytes::<Reply>(data);
+    let _ = encoding.decode_bytes::<Signal>(data);
+    let _ = encoding.decode_bytes::<Exception>(data);
+});
@@ -0,0 +1,276 @@
+use std::io::{self, Write};
+
+use serde_json::{json, Value};
+
+/// Arguments of a command line whose values can't be known when it is defined, by id with
+/// where to get them from. Completion scripts run `<bin> complete <source> [manifest]` for
+/// them, with the last existing file on the command line as the manifest.
+pub type DynamicValues = &'static [(&'static str, &'static str)];
+
+fn dynamic_values(dynamic: DynamicValues, id: &str) -> Option<&'static str> {
+    dynamic
+        .iter()
+        .find(|(arg, _)| *arg == id)
+        .map(|(_, source)| *source)
+}
+
+fn describe_arg(arg: &clap::Arg, dynamic: DynamicValues) -> Value {
+    let possible_values: Vec<String> = arg
+        .get_value_parser()
+        .possible_values()
+        .map(|values| values.map(|value| value.get_name().to_string()).collect())
+        .unwrap_or_default();
+
+    json!({
+        "name": arg.get_id(),
+        "help": arg.get_help(),
+        "long": arg.get_long(),
+        "short": arg.get_short().map(String::from),
+        "positional": arg.is_positional(),
+        "required": arg.is_required_set(),
+        "takes_value": arg.is_takes_value_set(),
+        "multiple": arg.is_multiple_occurrences_set(),
+        "possible_values": possible_values,
+        "dynamic_values": dynamic_values(dynamic, arg.get_id()),
+    })
+}
+
+/// A machine readable description of a command and its subcommands, hidden arguments and
+/// subcommands are left out.
+pub fn describe(command: &clap::Command, dynamic: DynamicValues) -> Value {
+    json!({
+        "name": command.get_name(),
+        "about": command.get_about(),
+        "args": command
+            .get_arguments()
+            .filter(|arg| !arg.is_hide_set())
+            .map(|arg| describe_arg(arg, dynamic))
+            .collect::<Vec<_>>(),
+        "subcommands": command
+            .get_subcommands()
+            .filter(|subcommand| !subcommand.is_hide_set())
+            .map(|subcommand| describe(subcommand, dynamic))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// An argument with dynamic values; an option by its long flag, or a positional by the
+/// subcommands leading up to it.
+enum Dynamic {
+    Option(String, &'static str),
+    Positional(Vec<String>, &'static str),
+}
+
+fn find_dynamic(
+    command: &clap::Command,
+    dynamic: DynamicValues,
+    path: &mut Vec<String>,
+    found: &mut Vec<Dynamic>,
+) {
+    for arg in command.get_arguments().filter(|arg| !arg.is_hide_set()) {
+        let source = match dynamic_values(dynamic, arg.get_id()) {
+            Some(source) => source,
+            None => continue,
+        };
+
+        match arg.get_long() {
+            Some(long) => found.push(Dynamic::Option(long.to_string(), source)),
+            None if arg.is_positional() => found.push(Dynamic::Positional(path.clone(), source)),
+            None => {}
+        }
+    }
+
+    for subcommand in command.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        path.push(subcommand.get_name().to_string());
+        find_dynamic(subcommand, dynamic, path, found);
+        path.pop();
+    }
+}
+
+fn bash(bin: &str, found: &[Dynamic]) -> String {
+    let function = format!("_{}", bin);
+    let mut script = format!(
+        "\n{function}_dynamic() {{\n    \
+             local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\" \
+             manifest=\"\" word\n    \
+             for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do\n        \
+                 [[ -f \"${{word}}\" ]] && manifest=\"${{word}}\"\n    \
+             done\n"
+    );
+
+    for dynamic in found {
+        let (condition, source) = match dynamic {
+            Dynamic::Option(long, source) => (format!("\"${{prev}}\" == \"--{}\"", long), source),
+            Dynamic::Positional(path, source) => {
+                let mut condition = String::from("\"${cur}\" != -*");
+
+                for name in path {
+                    condition.push_str(&format!(
+                        " && \" ${{COMP_WORDS[*]:1:COMP_CWORD-1}} \" == *\" {} \"*",
+                        name
+                    ));
+                }
+
+                (condition, source)
+            }
+        };
+
+        script.push_str(&format!(
+            "    if [[ {condition} ]]; then\n        \
+                 COMPREPLY=($(compgen -W \"$({bin} complete {source} ${{manifest:+\"${{manifest}}\"}} \
+                 2>/dev/null)\" -- \"${{cur}}\"))\n        \
+                 return 0\n    \
+             fi\n"
+        ));
+    }
+
+    script.push_str(&format!(
+        "    {function} \"$@\"\n}}\n\n\
+         complete -F {function}_dynamic -o bashdefault -o default {bin}\n"
+    ));
+
+    script
+}
+
+fn fish(bin: &str, found: &[Dynamic]) -> String {
+    let function = format!("__{}_manifest", bin.replace('-', "_"));
+    let mut script = format!(
+        "\nfunction {function}\n    \
+             set -l manifest\n    \
+             for word in (commandline -opc)\n        \
+                 test -f \"$word\"; and set manifest \"$word\"\n    \
+             end\n    \
+             echo $manifest\n\
+         end\n"
+    );
+
+    for dynamic in found {
+        match dynamic {
+            Dynamic::Option(long, source) => script.push_str(&format!(
+                "complete -c {bin} -l {long} -f -a '({bin} complete {source} ({function}))'\n"
+            )),
+            Dynamic::Positional(path, source) => script.push_str(&format!(
+                "complete -c {bin} -n '__fish_seen_subcommand_from {}' -f -a '({bin} complete {source} ({function}))'\n",
+                path.last().map(String::as_str).unwrap_or("")
+            )),
+        }
+    }
+
+    script
+}
+
+/// Write a completion script for `shell` to `output`. The scripts for bash and fish also
+/// complete the arguments with dynamic values, the others only complete what the command
+/// line defines.
+pub fn completions(
+    shell: clap_complete::Shell,
+    command: &mut clap::Command,
+    bin: &str,
+    dynamic: DynamicValues,
+    output: &mut dyn Write,
+) -> io::Result<()> {
+    clap_complete::generate(shell, command, bin, output);
+
+    let mut found = vec![];
+    find_dynamic(command, dynamic, &mut vec![], &mut found);
+
+    if found.is_empty() {
+        return Ok(());
+    }
+
+    match shell {
+        clap_complete::Shell::Bash => output.write_all(bash(bin, &found).as_bytes()),
+        clap_complete::Shell::Fish => output.write_all(fish(bin, &found).as_bytes()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DYNAMIC: DynamicValues = &[("export", "pipelines"), ("name", "modules")];
+
+    fn command() -> clap::Command<'static> {
+        clap::Command::new("tool")
+            .arg(clap::arg!(--export <pipeline> "Pipeline to export").required(false))
+            .arg(
+                clap::arg!(--hidden <value> "Hidden")
+                    .required(false)
+                    .hide(true),
+            )
+            .subcommand(clap::Command::new("schema").subcommand(
+                clap::Command::new("show").arg(clap::arg!(<name> "Name of the module")),
+            ))
+    }
+
+    #[test]
+    fn command_described() {
+        let description = describe(&command(), DYNAMIC);
+        let args = description["args"].as_array().unwrap();
+
+        let export = args.iter().find(|arg| arg["name"] == "export").unwrap();
+
+        assert_eq!(export["dynamic_values"], "pipelines");
+        assert!(args.iter().all(|arg| arg["name"] != "hidden"));
+        assert_eq!(
+            description["subcommands"][0]["subcommands"][0]["args"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|arg| arg["name"] == "name")
+                .unwrap()["dynamic_values"],
+            "modules"
+        );
+    }
+
+    #[test]
+    fn completions_dynamic() {
+        let mut script = vec![];
+        completions(
+            clap_complete::Shell::Bash,
+            &mut command(),
+            "tool",
+            DYNAMIC,
+            &mut script,
+        )
+        .unwrap();
+
+        let script = String::from_utf8(script).unwrap();
+
+        assert!(script.contains("\"${prev}\" == \"--export\""));
+        assert!(script.contains("tool complete pipelines"));
+        assert!(script.contains("== *\" show \"*"));
+        assert!(script.ends_with("complete -F _tool_dynamic -o bashdefault -o default tool\n"));
+
+        let mut script = vec![];
+        completions(
+            clap_complete::Shell::Fish,
+            &mut command(),
+            "tool",
+            DYNAMIC,
+            &mut script,
+        )
+        .unwrap();
+
+        let script = String::from_utf8(script).unwrap();
+
+        assert!(script.contains(
+            "complete -c tool -l export -f -a '(tool complete pipelines (__tool_manifest))'"
+        ));
+        assert!(script.contains("__fish_seen_subcommand_from show"));
+
+        // without dynamic values the script is what clap generates
+        let mut script = vec![];
+        completions(
+            clap_complete::Shell::Bash,
+            &mut command(),
+            "tool",
+            &[],
+            &mut script,
+        )
+        .unwrap();
+
+        assert!(!String::from_utf8(script).unwrap().contains("_dynamic"));
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::module::util::tree;
+
+/// Where the cgroup v2 hierarchy is mounted, on its own or next to the v1 hierarchies as
+/// `unified` on hosts that still use those.
+pub const CGROUP_MOUNTS: &[&str] = &["/sys/fs/cgroup", "/sys/fs/cgroup/unified"];
+
+/// The cgroup v2 this process is in, as `/proc/self/cgroup` has it. `None` when there is
+/// no cgroup v2 hierarchy.
+pub fn own_cgroup() -> io::Result<Option<PathBuf>> {
+    let Some(mount) = CGROUP_MOUNTS
+        .iter()
+        .map(Path::new)
+        .find(|mount| mount.join("cgroup.controllers").exists())
+    else {
+        return Ok(None);
+    };
+
+    Ok(fs::read_to_string("/proc/self/cgroup")?
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| mount.join(path.trim_start_matches('/'))))
+}
+
+/// What building a pipeline cost; the resources its stages used as their cgroup accounts
+/// them, what its sources downloaded, and how much the store grew.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourceUsage {
+    /// How long the pipeline took to build, in milliseconds.
+    pub duration_ms: u64,
+
+    /// CPU time of the stages, in microseconds; `usage_usec` of `cpu.stat`.
+    pub cpu_usec: u64,
+
+    /// The most memory the stages used at once, in bytes; `memory.peak`.
+    pub memory_peak_bytes: u64,
+
+    /// Bytes the stages read from and wrote to block devices; `io.stat`.
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64,
+
+    /// Bytes the sources of the pipeline downloaded.
+    pub downloaded_bytes: u64,
+
+    /// How much the store grew by the pipeline, in bytes; negative when it shrank.
+    pub store_delta_bytes: i64,
+}
+
+impl ResourceUsage {
+    /// Add what `other` cost. Memory isn't summed, the peak is the larger of the two.
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.duration_ms += other.duration_ms;
+        self.cpu_usec += other.cpu_usec;
+        self.memory_peak_bytes = self.memory_peak_bytes.max(other.memory_peak_bytes);
+        self.io_read_bytes += other.io_read_bytes;
+        self.io_write_bytes += other.io_write_bytes;
+        self.downloaded_bytes += other.downloaded_bytes;
+        self.store_delta_bytes += other.store_delta_bytes;
+    }
+
+    /// The usage the cgroup v2 at `path` accounts. Files the kernel doesn't provide, such as
+    /// `memory.peak` before Linux 5.19, count as nothing.
+    pub fn from_cgroup(path: &Path) -> io::Result<Self> {
+        let read = |name: &str| match fs::read_to_string(path.join(name)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            result => result,
+        };
+
+        let mut usage = Self::default();
+
+        for line in read("cpu.stat")?.lines() {
+            if let Some(("usage_usec", value)) = line.split_once(' ') {
+                usage.cpu_usec = value.trim().parse().unwrap_or_default();
+            }
+        }
+
+        usage.memory_peak_bytes = read("memory.peak")?.trim().parse().unwrap_or_default();
+
+        // a line per device; `8:0 rbytes=1024 wbytes=512 rios=2 wios=1 ...`
+        for line in read("io.stat")?.lines() {
+            for field in line.split_whitespace().skip(1) {
+                match field.split_once('=') {
+                    Some(("rbytes", value)) => {
+                        usage.io_read_bytes += value.parse::<u64>().unwrap_or_default()
+                    }
+                    Some(("wbytes", value)) => {
+                        usage.io_write_bytes += value.parse::<u64>().unwrap_or_default()
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(usage)
+    }
+}
+
+/// The space the files below `path` take on disk, in bytes. Directories that don't exist
+/// take none, so a store can be measured before anything is committed to it.
+pub fn disk_usage(path: &Path) -> io::Result<u64> {
+    let mut total = 0;
+
+    match tree::walk(path, &mut |_, metadata| {
+        total += metadata.blocks() * 512;
+        Ok(())
+    }) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        result => result.map(|_| total),
+    }
+}
+
+/// `bytes` in the largest binary unit that keeps it above 1.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_delta(bytes: i64) -> String {
+    let sign = if bytes < 0 { "-" } else { "+" };
+
+    format!("{}{}", sign, format_bytes(bytes.unsigned_abs()))
+}
+
+/// A table of what each pipeline of a build cost and the total, for operators who want to
+/// know where the time of a build went.
+pub struct UsageTable<'u> {
+    pipelines: &'u BTreeMap<String, ResourceUsage>,
+}
+
+impl<'u> UsageTable<'u> {
+    pub fn new(pipelines: &'u BTreeMap<String, ResourceUsage>) -> Self {
+        Self { pipelines }
+    }
+}
+
+impl fmt::Display for UsageTable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut total = ResourceUsage::default();
+
+        for usage in self.pipelines.values() {
+            total.add(usage);
+        }
+
+        let rows: Vec<(&str, &ResourceUsage)> = self
+            .pipelines
+            .iter()
+            .map(|(name, usage)| (name.as_str(), usage))
+            .chain([("total", &total)])
+            .collect();
+
+        let width = rows
+            .iter()
+            .map(|(name, _)| name.len())
+            .chain(["pipeline".len()])
+            .max()
+            .unwrap_or_default();
+
+        writeln!(
+            f,
+            "{:<width$}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>11}",
+            "pipeline", "time", "cpu", "memory", "read", "written", "downloaded", "store",
+        )?;
+
+        for (index, (name, usage)) in rows.iter().enumerate() {
+            write!(
+                f,
+                "{:<width$}  {:>9.1}s  {:>9.1}s  {:>10}  {:>10}  {:>10}  {:>10}  {:>11}",
+                name,
+                usage.duration_ms as f64 / 1000.0,
+                usage.cpu_usec as f64 / 1_000_000.0,
+                format_bytes(usage.memory_peak_bytes),
+                format_bytes(usage.io_read_bytes),
+                format_bytes(usage.io_write_bytes),
+                format_bytes(usage.downloaded_bytes),
+                format_delta(usage.store_delta_bytes),
+            )?;
+
+            if index + 1 < rows.len() {
+                writeln!(f)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cgroups_read() {
+        let cgroup = tempfile::tempdir().unwrap();
+
+        fs::write(
+            cgroup.path().join("cpu.stat"),
+            "usage_usec 1500000\nuser_usec 1000000\nsystem_usec 500000\n",
+        )
+        .unwrap();
+        fs::write(
+            cgroup.path().join("io.stat"),
+            "8:0 rbytes=1024 wbytes=512 rios=2 wios=1 dbytes=0 dios=0\n\
+             253:0 rbytes=1024 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n",
+        )
+        .unwrap();
+
+        let usage = ResourceUsage::from_cgroup(cgroup.path()).unwrap();
+
+        assert_eq!(
+            usage,
+            ResourceUsage {
+                cpu_usec: 1_500_000,
+                io_read_bytes: 2048,
+                io_write_bytes: 512,
+                ..Default::default()
+            }
+        );
+
+        fs::write(cgroup.path().join("memory.peak"), "4096\n").unwrap();
+
+        let mut total = ResourceUsage::from_cgroup(cgroup.path()).unwrap();
+
+        total.add(&ResourceUsage {
+            memory_peak_bytes: 1024,
+            store_delta_bytes: -100,
+            ..usage
+        });
+
+        assert_eq!(total.cpu_usec, 3_000_000);
+        assert_eq!(total.memory_peak_bytes, 4096);
+        assert_eq!(total.store_delta_bytes, -100);
+    }
+
+    #[test]
+    fn disk_used() {
+        let directory = tempfile::tempdir().unwrap();
+
+        assert_eq!(disk_usage(&directory.path().join("missing")).unwrap(), 0);
+
+        let empty = disk_usage(directory.path()).unwrap();
+
+        fs::write(directory.path().join("data"), vec![1; 64 * 1024]).unwrap();
+
+        assert!(disk_usage(directory.path()).unwrap() >= empty + 64 * 1024);
+    }
+
+    #[test]
+    fn usage_tabled() {
+        let pipelines = BTreeMap::from([
+            (
+                "build".to_string(),
+                ResourceUsage {
+                    duration_ms: 61_500,
+                    cpu_usec: 90_000_000,
+                    memory_peak_bytes: 512 * 1024 * 1024,
+                    downloaded_bytes: 300 * 1024 * 1024,
+                    store_delta_bytes: 1024 * 1024 * 1024,
+                    ..Default::default()
+                },
+            ),
+            (
+                "os".to_string(),
+                ResourceUsage {
+                    duration_ms: 1_000,
+                    io_write_bytes: 1000,
+                    store_delta_bytes: -512 * 1024 * 1024,
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        assert_eq!(
+            UsageTable::new(&pipelines).to_string(),
+            "pipeline        time         cpu      memory        read     written  downloaded        store\n\
+             build          61.5s       90.0s   512.0 MiB         0 B         0 B   300.0 MiB     +1.0 GiB\n\
+             os              1.0s        0.0s         0 B         0 B      1000 B         0 B   -512.0 MiB\n\
+             total          62.5s       90.0s   512.0 MiB         0 B      1000 B   300.0 MiB   +512.0 MiB"
+        );
+    }
+}
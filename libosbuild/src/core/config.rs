@@ -0,0 +1,870 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::core::environment::{Environment, StageEnvironment};
+use crate::core::export::{self, ExportError, ExportOptions};
+use crate::core::id::HashAlgo;
+use crate::core::paths::Workspace;
+use crate::core::publish::{self, ArtifactPublisher};
+use crate::core::reproducible;
+use crate::core::secrets::{SecretError, Secrets};
+use crate::core::sources::backend::Backends;
+use crate::core::sources::download::Downloader;
+use crate::core::sources::limit::{LimitOptions, Limits};
+use crate::core::sources::manager::SourceManager;
+use crate::core::sources::offline::{self, MissingItem};
+use crate::core::sources::{self, proxy::ProxyConfig};
+use crate::manifest::{Manifest, Pipeline};
+use crate::module::capability::{Capability, Policy};
+use crate::module::output::{OutputLimits, DEFAULT_SCHEMA_TIMEOUT};
+use crate::module::Registry;
+
+/// The system wide configuration file.
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/osbuild/osbuild.toml";
+
+/// The per user configuration file, relative to the user's configuration directory.
+pub const USER_CONFIG_PATH: &str = "osbuild/config.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A configuration file could not be parsed, contains the path of the file.
+    ParseError(PathBuf, toml::de::Error),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// Settings read from configuration files. Everything is optional; a setting in a later
+/// file overrides the same setting in an earlier one and flags given on the command line
+/// override both.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Directory of the object store.
+    pub store: Option<PathBuf>,
+
+    /// Name of the monitor to use, see `core::monitor::available`.
+    pub monitor: Option<String>,
+
+    /// Directories to load modules from instead of the well-known ones.
+    pub module_paths: Option<Vec<PathBuf>>,
+
+    /// Proxy to use for fetching sources and metadata, e.g. `http://proxy.example.com:3128`.
+    pub proxy: Option<String>,
+
+    /// `SOURCE_DATE_EPOCH` for pipelines that don't set their own.
+    pub source_date_epoch: Option<u64>,
+
+    /// Directory the workspaces of builds are created in, see `core::paths::Workspace`.
+    pub workspace: Option<PathBuf>,
+
+    /// Build without a network, see `BuildConfig::offline`.
+    pub offline: Option<bool>,
+
+    /// Fail builds whose stages use the network or open paths they don't declare, see
+    /// `sandbox::hermetic`.
+    pub hermetic: Option<bool>,
+
+    /// Record what every stage changes in its tree, see `core::executor::changes`.
+    pub track_changes: Option<bool>,
+
+    /// Settings of the worker, see `core::worker::Worker`.
+    pub worker: Option<WorkerConfig>,
+
+    /// How long modules may run and how much they may write.
+    pub modules: Option<ModulesConfig>,
+
+    /// The environment variables stages are run with, e.g. `variables = { TZ = "UTC" }`.
+    pub environment: Option<StageEnvironment>,
+
+    /// The capabilities stages may be granted, e.g. `["devices"]`; all of them when unset.
+    pub capabilities: Option<Vec<Capability>>,
+
+    /// Run stages isolated from the host, granted only the capabilities they declare, see
+    /// `sandbox::isolation`.
+    pub isolate: Option<bool>,
+
+    /// Download rate limits and quota for sources.
+    pub limits: Option<LimitOptions>,
+
+    /// Algorithm to compute object ids with, `sha256` when unset.
+    pub hash_algo: Option<HashAlgo>,
+
+    /// What builds are isolated from the host with, e.g. `{ backend = "vm", cpus = 4 }`;
+    /// namespaces when unset.
+    pub sandbox: Option<Sandbox>,
+
+    /// Snapshot the trees of objects into staging trees on btrfs, see
+    /// `core::store::Backend`; they are copied when this is `false`.
+    pub snapshots: Option<bool>,
+}
+
+/// Limits on the modules a build runs, see `module::Registry::set_timeouts` and
+/// `module::Registry::set_output_limits`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ModulesConfig {
+    /// Seconds a module may run, modules run as long as they take when unset.
+    pub timeout: Option<u64>,
+
+    /// Seconds a module may take to print its schema, `DEFAULT_SCHEMA_TIMEOUT` when unset.
+    pub schema_timeout: Option<u64>,
+
+    /// Bytes a module may write to stdout.
+    pub stdout_limit: Option<usize>,
+
+    /// Bytes of what a module writes to stderr that are kept.
+    pub stderr_limit: Option<usize>,
+}
+
+/// How the worker runs the jobs it is sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WorkerConfig {
+    /// How many jobs run at the same time, one when unset.
+    pub jobs: Option<usize>,
+
+    /// How many jobs of a class run at the same time, e.g. `vm = 1`. Classes that aren't
+    /// listed are only limited by `jobs`.
+    pub classes: BTreeMap<String, usize>,
+
+    /// Directory queued jobs are kept in, so that they are run after a restart, along with
+    /// the last id handed out so ids aren't reused.
+    pub queue: Option<PathBuf>,
+
+    /// How many jobs that are done are remembered for their status and logs, those done
+    /// first are forgotten first; `core::worker::DEFAULT_FINISHED_JOBS` when unset.
+    pub finished_jobs: Option<usize>,
+
+    /// Directory with a store per tenant, see `core::worker::tenancy`. Jobs have to name
+    /// their tenant when it is set.
+    pub tenants: Option<PathBuf>,
+
+    /// How many bytes the store of a tenant may take, jobs of tenants over it are refused
+    /// and running ones are stopped once they go over it.
+    pub quota: Option<u64>,
+
+    /// The user the clients of each tenant run as by tenant, e.g. `alice = 1000`. Requests
+    /// sent over the socket are of the tenant of the user that sent them, whatever tenant
+    /// they name; users of no tenant have none.
+    pub tenant_users: BTreeMap<String, u32>,
+
+    /// The tenants whose jobs may be `shared` and build in the store all tenants share.
+    pub shared_tenants: Vec<String>,
+}
+
+impl Config {
+    pub fn parse(data: &str, path: &Path) -> Result<Self, ConfigError> {
+        toml::from_str(data).map_err(|err| ConfigError::ParseError(path.to_path_buf(), err))
+    }
+
+    /// Read a configuration file, a file that doesn't exist is an empty configuration.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(data) => Self::parse(&data, path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Override settings with those set in `other`.
+    pub fn merge(&mut self, other: Config) {
+        if other.store.is_some() {
+            self.store = other.store;
+        }
+
+        if other.monitor.is_some() {
+            self.monitor = other.monitor;
+        }
+
+        if other.module_paths.is_some() {
+            self.module_paths = other.module_paths;
+        }
+
+        if other.proxy.is_some() {
+            self.proxy = other.proxy;
+        }
+
+        if other.source_date_epoch.is_some() {
+            self.source_date_epoch = other.source_date_epoch;
+        }
+
+        if other.workspace.is_some() {
+            self.workspace = other.workspace;
+        }
+
+        if other.offline.is_some() {
+            self.offline = other.offline;
+        }
+
+        if other.hermetic.is_some() {
+            self.hermetic = other.hermetic;
+        }
+
+        if other.track_changes.is_some() {
+            self.track_changes = other.track_changes;
+        }
+
+        if other.worker.is_some() {
+            self.worker = other.worker;
+        }
+
+        if other.modules.is_some() {
+            self.modules = other.modules;
+        }
+
+        if other.environment.is_some() {
+            self.environment = other.environment;
+        }
+
+        if other.capabilities.is_some() {
+            self.capabilities = other.capabilities;
+        }
+
+        if other.isolate.is_some() {
+            self.isolate = other.isolate;
+        }
+
+        if other.limits.is_some() {
+            self.limits = other.limits;
+        }
+
+        if other.hash_algo.is_some() {
+            self.hash_algo = other.hash_algo;
+        }
+
+        if other.sandbox.is_some() {
+            self.sandbox = other.sandbox;
+        }
+
+        if other.snapshots.is_some() {
+            self.snapshots = other.snapshots;
+        }
+    }
+}
+
+/// The configuration files in the order they are layered, the user's configuration directory
+/// is `$XDG_CONFIG_HOME` or `$HOME/.config`.
+pub fn config_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(SYSTEM_CONFIG_PATH)];
+
+    let user = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    if let Some(user) = user {
+        paths.push(user.join(USER_CONFIG_PATH));
+    }
+
+    paths
+}
+
+/// Load and merge the configuration files in `paths`, later files override earlier ones.
+pub fn load_layered_from(paths: &[PathBuf]) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+
+    for path in paths {
+        config.merge(Config::load(path)?);
+    }
+
+    Ok(config)
+}
+
+/// Load the system configuration with the user's configuration layered on top.
+pub fn load_layered() -> Result<Config, ConfigError> {
+    load_layered_from(&config_paths())
+}
+
+/// What builds are isolated from the host with, configured by the name of its `backend`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum Sandbox {
+    /// Namespaces on the host, what osbuild always uses.
+    #[default]
+    Namespace,
+
+    /// A virtual machine per build, for services that build manifests they can't trust.
+    #[cfg(all(feature = "vm", target_os = "linux"))]
+    Vm(crate::sandbox::vm::VmConfig),
+}
+
+/// Configuration for a single build, shared by everything that runs as part of it.
+#[derive(Debug, Clone, Default)]
+pub struct BuildConfig {
+    /// Directory of the object store the build reads cached trees from and commits to.
+    pub store: Option<PathBuf>,
+
+    /// Directories to load modules from instead of the well-known ones.
+    pub module_paths: Option<Vec<PathBuf>>,
+
+    /// Where exported artifacts are written to, in a directory per pipeline.
+    pub output_directory: PathBuf,
+
+    /// The pipelines whose trees are exported.
+    pub exports: Vec<String>,
+
+    /// The pipelines whose trees are kept in the store; when there are none every tree is
+    /// kept.
+    pub checkpoints: Vec<String>,
+
+    /// What to write next to exported artifacts.
+    pub export: ExportOptions,
+
+    /// Proxy for all network sources.
+    pub proxy: ProxyConfig,
+
+    /// Proxy settings for single sources by module name, these override `proxy`.
+    pub source_proxies: BTreeMap<String, ProxyConfig>,
+
+    /// Download rate limits and quota for sources.
+    pub limits: LimitOptions,
+
+    /// Algorithm to compute object ids with.
+    pub hash_algo: HashAlgo,
+
+    /// The capabilities stages may be granted.
+    pub policy: Policy,
+
+    /// The environment variables stages are run with.
+    pub environment: StageEnvironment,
+
+    /// `SOURCE_DATE_EPOCH` for pipelines that don't set their own.
+    pub source_date_epoch: Option<u64>,
+
+    /// What the build is isolated from the host with.
+    pub sandbox: Sandbox,
+
+    /// Directory the workspace of the build is created in, `paths::default_root` if unset.
+    pub workspace_root: Option<PathBuf>,
+
+    /// Refuse to fetch anything over the network, for air-gapped and regulated build
+    /// environments. Every item of a network source has to be in the cache of sources.
+    pub offline: bool,
+
+    /// Where artifacts are uploaded to once they are exported, see `publish::publish`.
+    pub publishers: Vec<Arc<dyn ArtifactPublisher>>,
+
+    /// Fail stages that use the network or open paths they don't declare.
+    pub hermetic: bool,
+
+    /// Record what every stage changes in its tree.
+    pub track_changes: bool,
+
+    /// How long modules may run, as long as they take when unset.
+    pub module_timeout: Option<Duration>,
+
+    /// How long modules may take to print their schema, `DEFAULT_SCHEMA_TIMEOUT` when unset.
+    pub schema_timeout: Option<Duration>,
+
+    /// How much modules may write.
+    pub output_limits: OutputLimits,
+
+    /// Run stages isolated from the host, granted only the capabilities they declare.
+    pub isolate: bool,
+
+    /// The runner the stages of pipelines are run with by pipeline name, see
+    /// `runner::select`. Stages of other pipelines are run as they are.
+    pub runners: BTreeMap<String, String>,
+
+    /// Copy the trees of objects into staging trees even where the store could snapshot
+    /// them.
+    pub copy_trees: bool,
+}
+
+impl BuildConfig {
+    pub fn new(output_directory: PathBuf) -> Self {
+        Self {
+            output_directory,
+            ..Default::default()
+        }
+    }
+
+    /// A build with the settings of `config`, exporting nothing.
+    pub fn from_config(config: &Config) -> Self {
+        let modules = config.modules.clone().unwrap_or_default();
+        let defaults = OutputLimits::default();
+        let mut policy = Policy::allow_none();
+
+        match &config.capabilities {
+            Some(capabilities) => capabilities
+                .iter()
+                .for_each(|capability| policy.allow(*capability)),
+            None => policy = Policy::allow_all(),
+        }
+
+        Self {
+            store: config.store.clone(),
+            module_paths: config.module_paths.clone(),
+            proxy: config
+                .proxy
+                .as_deref()
+                .map(ProxyConfig::new)
+                .unwrap_or_default(),
+            limits: config.limits.clone().unwrap_or_default(),
+            hash_algo: config.hash_algo.unwrap_or_default(),
+            sandbox: config.sandbox.clone().unwrap_or_default(),
+            policy,
+            environment: config.environment.clone().unwrap_or_default(),
+            source_date_epoch: config.source_date_epoch,
+            workspace_root: config.workspace.clone(),
+            offline: config.offline == Some(true),
+            hermetic: config.hermetic == Some(true),
+            track_changes: config.track_changes == Some(true),
+            isolate: config.isolate == Some(true),
+            copy_trees: config.snapshots == Some(false),
+            module_timeout: modules.timeout.map(Duration::from_secs),
+            schema_timeout: modules.schema_timeout.map(Duration::from_secs),
+            output_limits: OutputLimits {
+                stdout: modules.stdout_limit.unwrap_or(defaults.stdout),
+                stderr: modules.stderr_limit.unwrap_or(defaults.stderr),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Apply the timeouts and output limits of modules to the modules in `registry`.
+    pub fn limit_modules(&self, registry: &mut Registry) {
+        registry.set_timeouts(
+            self.schema_timeout.unwrap_or(DEFAULT_SCHEMA_TIMEOUT),
+            self.module_timeout,
+        );
+        registry.set_output_limits(self.output_limits);
+    }
+
+    /// The directory the artifacts of `pipeline` are exported into.
+    pub fn export_directory(&self, pipeline: &str) -> PathBuf {
+        self.output_directory.join(pipeline)
+    }
+
+    /// Write the metadata configured in `export` next to artifacts that were exported into
+    /// the export directory of `pipeline`.
+    pub fn write_export_metadata(
+        &self,
+        pipeline: &str,
+        artifacts: &[PathBuf],
+    ) -> Result<Vec<PathBuf>, ExportError> {
+        export::write_metadata(&self.export_directory(pipeline), artifacts, &self.export)
+    }
+
+    /// Finish exporting artifacts into the export directory of `pipeline` as configured in
+    /// `export`, including fs-verity and immutability, see `export::finish`, then publish
+    /// them.
+    pub fn finish_export(
+        &self,
+        pipeline: &str,
+        artifacts: &[PathBuf],
+    ) -> Result<export::Exported, ExportError> {
+        let mut exported =
+            export::finish(&self.export_directory(pipeline), artifacts, &self.export)?;
+
+        exported.published = publish::publish(&self.publishers, artifacts)?;
+
+        Ok(exported)
+    }
+
+    /// Create the workspace of the build in the configured root.
+    pub fn workspace(&self) -> io::Result<Workspace> {
+        match &self.workspace_root {
+            Some(root) => Workspace::new(root),
+            None => Workspace::in_default_root(),
+        }
+    }
+
+    /// The proxy settings for the source module called `name`.
+    pub fn proxy_for(&self, name: &str) -> ProxyConfig {
+        match self.source_proxies.get(name) {
+            Some(proxy) => self.proxy.merged(proxy),
+            None => self.proxy.clone(),
+        }
+    }
+
+    /// The environment variables to run the source module called `name` with. Only sources
+    /// that fetch over the network get any.
+    pub fn source_environment(
+        &self,
+        name: &str,
+        secrets: &Secrets,
+    ) -> Result<Vec<(String, String)>, SecretError> {
+        if !sources::is_network_source(name) {
+            return Ok(vec![]);
+        }
+
+        self.proxy_for(name).environment(secrets)
+    }
+
+    /// The cache of sources at `cache`, fetching into it through the proxy of
+    /// `org.osbuild.curl` within the limits.
+    pub fn source_manager(
+        &self,
+        cache: &Path,
+        secrets: &Secrets,
+    ) -> Result<SourceManager, SecretError> {
+        let mut downloader =
+            Downloader::new("org.osbuild.curl", Arc::new(Limits::new(&self.limits)));
+
+        downloader.environment = self.source_environment("org.osbuild.curl", secrets)?;
+
+        Ok(SourceManager::new(
+            cache,
+            Backends::with_downloader(downloader),
+        ))
+    }
+
+    /// Check before anything runs that `manifest` can be built with the items in `cache`,
+    /// returns the items that are missing. Only offline builds need any to be there.
+    pub fn preflight(&self, manifest: &Manifest, cache: &Path) -> Result<(), Vec<MissingItem>> {
+        if !self.offline {
+            return Ok(());
+        }
+
+        let missing = offline::missing_items(manifest, cache);
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(missing)
+        }
+    }
+
+    /// The `SOURCE_DATE_EPOCH` of `pipeline`.
+    pub fn source_date_epoch_for(&self, pipeline: &Pipeline) -> Option<u64> {
+        reproducible::source_date_epoch(pipeline, self.source_date_epoch)
+    }
+
+    /// The environment to run the stages of `pipeline` with, from the variables of the host.
+    /// Proxy variables are added when `environment.proxy` is set, and `SOURCE_DATE_EPOCH`
+    /// when the pipeline has one.
+    pub fn stage_environment<I>(
+        &self,
+        pipeline: &Pipeline,
+        host: I,
+        secrets: &Secrets,
+    ) -> Result<Environment, SecretError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut environment = self.environment.resolve(host);
+
+        if self.environment.proxy {
+            environment.extend(self.proxy.environment(secrets)?);
+        }
+
+        if let Some(epoch) = self.source_date_epoch_for(pipeline) {
+            environment.insert(
+                reproducible::SOURCE_DATE_EPOCH.to_string(),
+                epoch.to_string(),
+            );
+        }
+
+        Ok(environment)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn source_proxies() {
+        let mut config = BuildConfig {
+            proxy: ProxyConfig::new("http://global:3128"),
+            ..Default::default()
+        };
+
+        config.source_proxies.insert(
+            "org.osbuild.skopeo".to_string(),
+            ProxyConfig::new("http://registry-proxy:3128"),
+        );
+
+        let secrets = Secrets::new();
+
+        assert!(config
+            .source_environment("org.osbuild.curl", &secrets)
+            .unwrap()
+            .contains(&("https_proxy".to_string(), "http://global:3128".to_string())));
+        assert!(config
+            .source_environment("org.osbuild.skopeo", &secrets)
+            .unwrap()
+            .contains(&(
+                "https_proxy".to_string(),
+                "http://registry-proxy:3128".to_string()
+            )));
+        assert!(config
+            .source_environment("org.osbuild.inline", &secrets)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn stage_environment() {
+        let mut config = BuildConfig {
+            proxy: ProxyConfig::new("http://global:3128"),
+            ..Default::default()
+        };
+
+        config.environment.set("TZ", "UTC");
+
+        let secrets = Secrets::new();
+        let host = || [("https_proxy".to_string(), "http://host:3128".to_string())];
+        let mut pipeline = Pipeline::new("os");
+
+        assert_eq!(
+            crate::core::environment::names(
+                &config
+                    .stage_environment(&pipeline, host(), &secrets)
+                    .unwrap()
+            ),
+            vec!["PATH", "TZ"]
+        );
+
+        config.environment.proxy = true;
+        config.source_date_epoch = Some(10);
+
+        let environment = config
+            .stage_environment(&pipeline, host(), &secrets)
+            .unwrap();
+
+        assert_eq!(environment["https_proxy"], "http://global:3128");
+        assert_eq!(environment["SOURCE_DATE_EPOCH"], "10");
+
+        pipeline.set_source_epoch(Some(5));
+
+        assert_eq!(
+            config
+                .stage_environment(&pipeline, host(), &secrets)
+                .unwrap()["SOURCE_DATE_EPOCH"],
+            "5"
+        );
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            "store = \"/var/cache/osbuild\"\nmonitor = \"jsonseq\"\nmodule-paths = [\"/usr/lib/osbuild\"]\n",
+            Path::new("osbuild.toml"),
+        )
+        .unwrap();
+
+        assert_eq!(config.store, Some(PathBuf::from("/var/cache/osbuild")));
+        assert_eq!(config.monitor.as_deref(), Some("jsonseq"));
+        assert_eq!(
+            config.module_paths,
+            Some(vec![PathBuf::from("/usr/lib/osbuild")])
+        );
+        assert_eq!(config.proxy, None);
+        assert_eq!(config.source_date_epoch, None);
+        assert_eq!(
+            Config::parse(
+                "source-date-epoch = 1700000000\n",
+                Path::new("osbuild.toml")
+            )
+            .unwrap()
+            .source_date_epoch,
+            Some(1700000000)
+        );
+
+        assert_eq!(
+            Config::parse(
+                "[worker]\njobs = 2\nclasses = { vm = 1 }\n",
+                Path::new("osbuild.toml")
+            )
+            .unwrap()
+            .worker,
+            Some(WorkerConfig {
+                jobs: Some(2),
+                classes: BTreeMap::from([("vm".to_string(), 1)]),
+                ..Default::default()
+            })
+        );
+
+        let config = Config::parse(
+            "[modules]\ntimeout = 600\nstdout-limit = 1024\n",
+            Path::new("osbuild.toml"),
+        )
+        .unwrap();
+        let build = BuildConfig::from_config(&config);
+
+        assert_eq!(build.module_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(build.schema_timeout, None);
+        assert_eq!(build.output_limits.stdout, 1024);
+        assert_eq!(build.output_limits.stderr, OutputLimits::default().stderr);
+
+        assert_eq!(
+            BuildConfig::from_config(
+                &Config::parse(
+                    "[environment]\nallowed = [\"LANG\"]\nproxy = true\n",
+                    Path::new("osbuild.toml"),
+                )
+                .unwrap()
+            )
+            .environment,
+            StageEnvironment {
+                allowed: vec!["LANG".to_string()],
+                proxy: true,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            BuildConfig::from_config(
+                &Config::parse(
+                    "[limits]\nrate = 1048576\nsource-rates = { \"org.osbuild.curl\" = 1024 }\n",
+                    Path::new("osbuild.toml"),
+                )
+                .unwrap()
+            )
+            .limits,
+            LimitOptions {
+                rate: Some(1048576),
+                source_rates: BTreeMap::from([("org.osbuild.curl".to_string(), 1024)]),
+                quota: None,
+            }
+        );
+
+        assert_eq!(
+            Config::parse("hash-algo = \"sha256\"\n", Path::new("osbuild.toml"))
+                .unwrap()
+                .hash_algo,
+            Some(HashAlgo::Sha256)
+        );
+        assert!(Config::parse("hash-algo = \"md5\"\n", Path::new("osbuild.toml")).is_err());
+
+        assert!(
+            BuildConfig::from_config(
+                &Config::parse("snapshots = false\n", Path::new("osbuild.toml")).unwrap()
+            )
+            .copy_trees
+        );
+
+        assert_eq!(
+            Config::parse(
+                "[sandbox]\nbackend = \"namespace\"\n",
+                Path::new("osbuild.toml")
+            )
+            .unwrap()
+            .sandbox,
+            Some(Sandbox::Namespace)
+        );
+
+        #[cfg(all(feature = "vm", target_os = "linux"))]
+        assert_eq!(
+            BuildConfig::from_config(
+                &Config::parse(
+                    "[sandbox]\nbackend = \"vm\"\ncpus = 4\nmemory-mb = 4096\n",
+                    Path::new("osbuild.toml"),
+                )
+                .unwrap()
+            )
+            .sandbox,
+            Sandbox::Vm(crate::sandbox::vm::VmConfig {
+                cpus: 4,
+                memory_mb: 4096,
+                ..Default::default()
+            })
+        );
+
+        assert!(matches!(
+            Config::parse("nope = 1\n", Path::new("osbuild.toml")),
+            Err(ConfigError::ParseError(..))
+        ));
+    }
+
+    #[test]
+    fn load_layered_configs() {
+        let directory = tempfile::tempdir().unwrap();
+        let system = directory.path().join("osbuild.toml");
+        let user = directory.path().join("config.toml");
+
+        fs::write(
+            &system,
+            "store = \"/system\"\nproxy = \"http://proxy:3128\"\n",
+        )
+        .unwrap();
+        fs::write(&user, "store = \"/user\"\nmonitor = \"null\"\n").unwrap();
+
+        let config =
+            load_layered_from(&[system, directory.path().join("missing.toml"), user]).unwrap();
+
+        assert_eq!(config.store, Some(PathBuf::from("/user")));
+        assert_eq!(config.monitor.as_deref(), Some("null"));
+        assert_eq!(config.proxy.as_deref(), Some("http://proxy:3128"));
+    }
+
+    #[test]
+    fn workspace_in_root() {
+        let directory = tempfile::tempdir().unwrap();
+
+        let config = Config::parse(
+            &format!("workspace = {:?}\n", directory.path().join("run")),
+            Path::new("osbuild.toml"),
+        )
+        .unwrap();
+
+        let build = BuildConfig {
+            workspace_root: config.workspace,
+            ..Default::default()
+        };
+
+        let workspace = build.workspace().unwrap();
+
+        assert!(workspace.path().starts_with(directory.path().join("run")));
+    }
+
+    #[test]
+    fn offline_preflight() {
+        let cache = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::new();
+
+        manifest.add_source(
+            "org.osbuild.curl",
+            serde_json::json!({"items": {"sha256:aa": "https://example.com/a"}}),
+        );
+
+        let mut config = BuildConfig::default();
+
+        assert!(config.preflight(&manifest, cache.path()).is_ok());
+
+        config.offline = true;
+
+        assert_eq!(
+            config.preflight(&manifest, cache.path()).unwrap_err()[0].checksum,
+            "sha256:aa"
+        );
+
+        fs::create_dir_all(cache.path().join("org.osbuild.curl")).unwrap();
+        fs::write(cache.path().join("org.osbuild.curl/sha256:aa"), "a").unwrap();
+
+        assert!(config.preflight(&manifest, cache.path()).is_ok());
+        assert_eq!(
+            Config::parse("offline = true\n", Path::new("osbuild.toml"))
+                .unwrap()
+                .offline,
+            Some(true)
+        );
+        assert_eq!(
+            Config::parse("hermetic = true\n", Path::new("osbuild.toml"))
+                .unwrap()
+                .hermetic,
+            Some(true)
+        );
+        assert_eq!(
+            Config::parse("track-changes = true\n", Path::new("osbuild.toml"))
+                .unwrap()
+                .track_changes,
+            Some(true)
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+/// The search path stages run with unless it is set explicitly.
+pub const DEFAULT_PATH: &str = "/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// The environment stages run with, resolved from a `StageEnvironment`.
+pub type Environment = BTreeMap<String, String>;
+
+/// Which environment variables stages are run with, everything else in the environment of
+/// osbuild is scrubbed. Variables are either set to a value here or passed through from the
+/// host when their name is allowed and they are set there.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StageEnvironment {
+    /// Names of host variables that are passed through.
+    pub allowed: Vec<String>,
+
+    /// Variables set to a value, these take precedence over host variables.
+    pub variables: BTreeMap<String, String>,
+
+    /// Whether stages get the proxy variables of the build's proxy settings.
+    pub proxy: bool,
+}
+
+impl StageEnvironment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass the host variable `name` through to stages.
+    pub fn allow(&mut self, name: &str) {
+        if !self.allowed.iter().any(|other| other == name) {
+            self.allowed.push(name.to_string());
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.variables.insert(name.to_string(), value.to_string());
+    }
+
+    /// The environment for stages given the variables of the host, `PATH` is always set.
+    pub fn resolve<I>(&self, host: I) -> Environment
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut environment = Environment::new();
+
+        environment.insert("PATH".to_string(), DEFAULT_PATH.to_string());
+
+        for (name, value) in host {
+            if self.allowed.contains(&name) {
+                environment.insert(name, value);
+            }
+        }
+
+        environment.extend(self.variables.clone());
+        environment
+    }
+}
+
+/// The names of the variables in `environment`, which is what builds record. Values are left
+/// out as proxy variables can contain credentials.
+pub fn names(environment: &Environment) -> Vec<String> {
+    environment.keys().cloned().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scrubbed_environment() {
+        let mut stage = StageEnvironment::new();
+        stage.allow("TZ");
+        stage.allow("TZ");
+        stage.set("SOURCE_DATE_EPOCH", "1700000000");
+        stage.set("TZ", "UTC");
+
+        let host = [
+            ("HOME", "/root"),
+            ("TZ", "Europe/Amsterdam"),
+            ("PATH", "/home/user/bin:/usr/bin"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+
+        let environment = stage.resolve(host);
+
+        assert_eq!(stage.allowed, vec!["TZ"]);
+        assert_eq!(names(&environment), vec!["PATH", "SOURCE_DATE_EPOCH", "TZ"]);
+        assert_eq!(environment["PATH"], DEFAULT_PATH);
+        assert_eq!(environment["TZ"], "UTC");
+
+        let mut stage = StageEnvironment::new();
+        stage.allow("PATH");
+        stage.allow("LANG");
+
+        let environment = stage.resolve([("PATH".to_string(), "/opt/bin".to_string())]);
+
+        assert_eq!(environment.len(), 1);
+        assert_eq!(environment["PATH"], "/opt/bin");
+    }
+}
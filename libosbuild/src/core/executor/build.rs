@@ -0,0 +1,504 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::core::accounting;
+use crate::core::config::BuildConfig;
+#[cfg(all(feature = "vm", target_os = "linux"))]
+use crate::core::config::Sandbox;
+use crate::core::environment;
+use crate::core::executor::inputs::PIPELINE_PREFIX;
+use crate::core::executor::modules::ModuleServices;
+use crate::core::executor::plan::PlannedStage;
+use crate::core::executor::{Executor, ExecutorError, Services};
+use crate::core::export::ExportError;
+use crate::core::journal::StoreLock;
+use crate::core::monitor::Monitor;
+use crate::core::paths::Workspace;
+use crate::core::result::BuildResult;
+use crate::core::secrets::{SecretError, Secrets};
+use crate::core::sources::offline::MissingItem;
+use crate::core::sources::prefetch::PrefetchError;
+use crate::core::store::{Backend, ObjectStore};
+use crate::manifest::description::validation::Severity;
+use crate::manifest::Manifest;
+use crate::module::cancel::CancellationToken;
+use crate::module::util::tree;
+use crate::module::{Registry, RegistryError};
+#[cfg(all(feature = "vm", target_os = "linux"))]
+use crate::sandbox::vm::{self, BuildRequest, VmConfig, VmError};
+
+/// Directory in the store workspaces are created in when no other is configured, so that
+/// trees are committed to the store without copying them.
+pub const WORKSPACE_DIR: &str = "tmp";
+
+/// Directory in the runtime of a workspace the audits of stages of hermetic builds are
+/// written to.
+pub const AUDIT_DIR: &str = "audit";
+
+/// How long a stage has to stop after the build is cancelled before it is killed.
+pub const CANCEL_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum BuildError {
+    /// There is no store configured to build into.
+    NoStore,
+
+    /// A hermetic build was asked for but stages can't be audited on this platform or
+    /// without the sandbox.
+    HermeticUnsupported,
+
+    /// Isolated stages were asked for but can't be isolated on this platform or without the
+    /// sandbox.
+    IsolationUnsupported,
+
+    /// An offline build is missing items of its network sources in the cache of sources.
+    MissingSources(Vec<MissingItem>),
+
+    /// A pipeline to export is not in the manifest.
+    UnknownExport(String),
+
+    /// Fetching the items of the network sources of an online build failed.
+    PrefetchError(PrefetchError),
+
+    RegistryError(RegistryError),
+    ExecutorError(ExecutorError),
+    ExportError(ExportError),
+    SecretError(SecretError),
+
+    #[cfg(all(feature = "vm", target_os = "linux"))]
+    VmError(VmError),
+
+    IOError(io::Error),
+}
+
+impl From<RegistryError> for BuildError {
+    fn from(err: RegistryError) -> Self {
+        Self::RegistryError(err)
+    }
+}
+
+impl From<ExecutorError> for BuildError {
+    fn from(err: ExecutorError) -> Self {
+        Self::ExecutorError(err)
+    }
+}
+
+impl From<ExportError> for BuildError {
+    fn from(err: ExportError) -> Self {
+        Self::ExportError(err)
+    }
+}
+
+impl From<PrefetchError> for BuildError {
+    fn from(err: PrefetchError) -> Self {
+        Self::PrefetchError(err)
+    }
+}
+
+impl From<SecretError> for BuildError {
+    fn from(err: SecretError) -> Self {
+        Self::SecretError(err)
+    }
+}
+
+#[cfg(all(feature = "vm", target_os = "linux"))]
+impl From<VmError> for BuildError {
+    fn from(err: VmError) -> Self {
+        Self::VmError(err)
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// Build `manifest` as `config` says; with the modules in its module paths, or the well-known
+/// ones, limited to its timeouts and output limits, into its store, in a workspace in its
+/// workspace directory or `WORKSPACE_DIR` of the store. Staging trees are snapshots where the
+/// store can make them, unless it asks for copies. Cancelling `cancellation` stops the
+/// running stage and skips those after it.
+///
+/// Items of network sources that aren't in the cache of sources of the store are fetched
+/// into it first, through its proxy and within its limits, see `BuildConfig::source_manager`;
+/// offline builds fail with the items that are missing instead.
+///
+/// Stages are only run when its policy allows the capabilities they need, isolated when it
+/// asks for that, by the runner selected for their pipeline, and with the environment of
+/// their pipeline, which has the `SOURCE_DATE_EPOCH` of the pipeline, see
+/// `BuildConfig::stage_environment`.
+///
+/// The trees of the pipelines it exports are copied into their export directories and
+/// finished and published with `BuildConfig::finish_export`. When it names checkpoints, the
+/// trees it committed of the other pipelines are removed from the store after that.
+///
+/// With a virtual machine as its sandbox the whole build runs in one, see `sandbox::vm`.
+///
+/// Returns the result of the build with the names of the variables stages ran with, the
+/// `SOURCE_DATE_EPOCH` of every pipeline that has one, the options of the stages that ran,
+/// with the defaults of their schemas, what they changed when changes are tracked, and what
+/// building each pipeline cost; the stages of a pipeline run in a cgroup of their own in the
+/// cgroup of the build, where cgroups can be created.
+pub fn build_with_config(
+    manifest: &Manifest,
+    config: &BuildConfig,
+    monitor: &mut dyn Monitor,
+    cancellation: Option<&CancellationToken>,
+) -> Result<BuildResult, BuildError> {
+    let root = config.store.as_deref().ok_or(BuildError::NoStore)?;
+
+    fs::create_dir_all(root)?;
+
+    // held until the build is done, so the store isn't recovered from under it
+    let _lock = StoreLock::shared(root)?;
+    let store = if config.copy_trees {
+        ObjectStore::with_backend(root, Backend::Copy)
+    } else {
+        ObjectStore::new(root)
+    };
+
+    if let Some(name) = config
+        .exports
+        .iter()
+        .find(|name| manifest.pipeline(name).is_none())
+    {
+        return Err(BuildError::UnknownExport(name.clone()));
+    }
+
+    config
+        .preflight(manifest, &store.sources_path())
+        .map_err(BuildError::MissingSources)?;
+
+    let secrets = Secrets::from_environment();
+
+    // items only their source module can fetch are left to it
+    let unsupported = if config.offline {
+        vec![]
+    } else {
+        config
+            .source_manager(&store.sources_path(), &secrets)?
+            .prefetch(manifest)?
+            .unsupported
+    };
+
+    #[cfg(all(feature = "vm", target_os = "linux"))]
+    if let Sandbox::Vm(vm) = &config.sandbox {
+        return build_in_vm(manifest, config, vm);
+    }
+
+    let mut registry = Registry::new_empty();
+
+    match &config.module_paths {
+        Some(paths) => paths
+            .iter()
+            .try_for_each(|path| registry.add_libdir(path))?,
+        None => registry.add_well_known()?,
+    }
+
+    config.limit_modules(&mut registry);
+
+    let workspace = Workspace::new(
+        &config
+            .workspace_root
+            .clone()
+            .unwrap_or_else(|| root.join(WORKSPACE_DIR)),
+    )?;
+
+    let mut services = ModuleServices::new(&registry);
+    let mut names = BTreeSet::new();
+    let mut epochs = BTreeMap::new();
+
+    for pipeline in manifest.pipelines() {
+        let host = env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        });
+        let variables = config.stage_environment(pipeline, host, &secrets)?;
+
+        names.extend(environment::names(&variables));
+        services.set_pipeline_environment(pipeline.name(), variables);
+
+        if let Some(runner) = config.runners.get(pipeline.name()) {
+            services.set_runner(pipeline.name(), runner);
+        }
+
+        if let Some(epoch) = config.source_date_epoch_for(pipeline) {
+            epochs.insert(pipeline.name().to_string(), epoch);
+        }
+    }
+
+    services.set_policy(config.policy.clone());
+
+    #[cfg(all(feature = "communication", unix))]
+    services.set_host_services(&workspace.socket("host")?);
+
+    #[cfg(target_os = "linux")]
+    if let (Some(cgroup), Some(name)) = (accounting::own_cgroup()?, workspace.path().file_name()) {
+        services.set_cgroups(&cgroup.join(name));
+    }
+
+    if config.isolate {
+        #[cfg(all(feature = "sandbox", target_os = "linux"))]
+        services.set_isolated(true);
+
+        #[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+        return Err(BuildError::IsolationUnsupported);
+    }
+
+    if config.hermetic {
+        #[cfg(all(feature = "sandbox", target_os = "linux"))]
+        services.set_hermetic(&workspace.runtime().join(AUDIT_DIR));
+
+        #[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+        return Err(BuildError::HermeticUnsupported);
+    }
+
+    if let Some(token) = cancellation {
+        services.set_cancellation(token.clone(), &workspace.socket("control")?, CANCEL_GRACE);
+    }
+
+    let mut executor = Executor::new(services, workspace.runtime());
+
+    executor.set_track_changes(config.track_changes);
+    executor.set_hash_algo(config.hash_algo);
+    executor.build(manifest, &store, &workspace, monitor)?;
+
+    let mut result = BuildResult {
+        environment: names.into_iter().collect(),
+        source_date_epochs: epochs,
+        effective_options: executor.effective_options().clone(),
+        changes: executor.changes().clone(),
+        ..BuildResult::success()
+    };
+
+    for (name, usage) in executor.usage() {
+        result.account(name, usage);
+    }
+
+    for item in &unsupported {
+        result.diagnose(
+            Severity::Warning,
+            format!(
+                "{} of {} can only be fetched by its source module",
+                item.checksum, item.source
+            ),
+        );
+    }
+
+    for name in &config.exports {
+        let Some((_, tree)) = executor
+            .content()
+            .tree(&format!("{}{}", PIPELINE_PREFIX, name))
+        else {
+            return Err(BuildError::UnknownExport(name.clone()));
+        };
+
+        let exported = config.finish_export(name, &export_tree(config, name, tree)?)?;
+
+        result.verity_digests.extend(exported.verity_digests());
+    }
+
+    if !config.checkpoints.is_empty() {
+        let kept: BTreeSet<&String> = config
+            .checkpoints
+            .iter()
+            .filter_map(|name| executor.committed().get(name))
+            .collect();
+
+        for id in executor.committed().values() {
+            if !kept.contains(id) && store.contains(id) {
+                store.remove(id).map_err(ExecutorError::from)?;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Build `manifest` in the virtual machine `vm` with the store and output directory of
+/// `config` shared with it, and finish the exports the guest copied into the output
+/// directory.
+#[cfg(all(feature = "vm", target_os = "linux"))]
+fn build_in_vm(
+    manifest: &Manifest,
+    config: &BuildConfig,
+    vm: &VmConfig,
+) -> Result<BuildResult, BuildError> {
+    let root = config.store.as_deref().ok_or(BuildError::NoStore)?;
+    let workspace = Workspace::new(
+        &config
+            .workspace_root
+            .clone()
+            .unwrap_or_else(|| root.join(WORKSPACE_DIR)),
+    )?;
+    let request = BuildRequest {
+        manifest: manifest.clone(),
+        exports: config.exports.clone(),
+    };
+
+    fs::create_dir_all(&config.output_directory)?;
+
+    let mut result = vm::build(
+        vm,
+        &request,
+        root,
+        &config.output_directory,
+        workspace.runtime(),
+    )?;
+
+    if result.is_success() {
+        for name in &config.exports {
+            let artifacts = artifacts(&config.export_directory(name))?;
+            let exported = config.finish_export(name, &artifacts)?;
+
+            result.verity_digests.extend(exported.verity_digests());
+        }
+    }
+
+    Ok(result)
+}
+
+/// Copy `tree`, the tree of the pipeline `name`, into its export directory. Returns its
+/// artifacts.
+fn export_tree(config: &BuildConfig, name: &str, tree: &Path) -> Result<Vec<PathBuf>, BuildError> {
+    let directory = config.export_directory(name);
+
+    fs::create_dir_all(&config.output_directory)?;
+    tree::copy_all(tree, &directory)?;
+
+    artifacts(&directory)
+}
+
+/// The artifacts in the export directory `directory`, the files at the top of it.
+fn artifacts(directory: &Path) -> Result<Vec<PathBuf>, BuildError> {
+    let mut artifacts = vec![];
+
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_file() {
+            artifacts.push(entry.path());
+        }
+    }
+
+    artifacts.sort();
+
+    Ok(artifacts)
+}
+
+impl<S: Services> Executor<S> {
+    /// Build the pipelines of `manifest` into `store` in the order of their plan. The objects
+    /// in the store and its cache of sources are the executor's content. A pipeline is built
+    /// in a staging tree of `workspace`, starting from the tree of its last cached stage as
+    /// the backend of `store` stages it, and is committed to the store under the id of its
+    /// last stage; the workspace has to be on the filesystem of the store. Stages are
+    /// reported to `monitor`, finishing it is up to the caller. What building a pipeline cost
+    /// is its `usage`; what its services account, how long it took, and how much its tree
+    /// takes in the store.
+    pub fn build(
+        &mut self,
+        manifest: &Manifest,
+        store: &ObjectStore,
+        workspace: &Workspace,
+        monitor: &mut dyn Monitor,
+    ) -> Result<(), ExecutorError> {
+        for id in store.ids()? {
+            let tree = store.tree_path(&id)?;
+            self.content_mut().add_tree(&id, &tree);
+        }
+
+        self.content_mut().set_sources(&store.sources_path());
+
+        let plan = self.plan(manifest)?;
+        let mut names: Vec<&str> = vec![];
+
+        for stage in &plan.stages {
+            if !names.contains(&stage.pipeline.as_str()) {
+                names.push(&stage.pipeline);
+            }
+        }
+
+        for name in names {
+            let Some(pipeline) = manifest.pipeline(name) else {
+                continue;
+            };
+
+            let planned: Vec<&PlannedStage> = plan
+                .stages
+                .iter()
+                .filter(|stage| stage.pipeline == name)
+                .collect();
+
+            let Some(last) = planned.last() else {
+                continue;
+            };
+
+            monitor.begin(
+                name,
+                &planned
+                    .iter()
+                    .map(|stage| stage.kind.clone())
+                    .collect::<Vec<_>>(),
+            );
+
+            if last.cached {
+                let committed = store.tree_path(&last.id)?;
+                self.commit(name, &last.id, &committed);
+                continue;
+            }
+
+            let work = workspace.tree(name)?;
+
+            let cached = planned.iter().rposition(|stage| stage.cached);
+
+            store.stage(cached.map(|cached| planned[cached].id.as_str()), &work)?;
+
+            let start = cached.map(|cached| cached + 1).unwrap_or(0);
+
+            let started = Instant::now();
+
+            self.services.begin_pipeline(name)?;
+
+            let ran = planned[start..].iter().try_for_each(|stage| {
+                monitor.stage(stage.index, &stage.kind);
+
+                let started = Instant::now();
+                let result = if self.track_changes {
+                    self.run_stage_tracked(&pipeline.stages()[stage.index], &work)
+                        .and_then(|(changes, result)| {
+                            self.changes.insert(stage.id.clone(), changes);
+                            result
+                        })
+                } else {
+                    self.run_stage(&pipeline.stages()[stage.index], &work)
+                };
+
+                monitor.result(stage.index, result.is_ok(), started.elapsed());
+
+                if let Some(options) = self.options.take() {
+                    self.effective_options.insert(stage.id.clone(), options);
+                }
+
+                result
+            });
+
+            let mut usage = self.services.end_pipeline(name)?;
+
+            ran?;
+
+            let committed = store.commit(&last.id, &work)?;
+            self.commit(name, &last.id, &committed);
+            self.committed.insert(name.to_string(), last.id.clone());
+
+            usage.duration_ms = started.elapsed().as_millis() as u64;
+            usage.store_delta_bytes = accounting::disk_usage(&committed)? as i64;
+            self.usage.insert(name.to_string(), usage);
+        }
+
+        Ok(())
+    }
+}
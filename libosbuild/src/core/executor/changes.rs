@@ -0,0 +1,498 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::module::util::tree;
+
+/// The extended attributes overlayfs marks opaque directories with; the `trusted` one, and
+/// the `user` one of overlays mounted with `userxattr` in user namespaces.
+pub const OPAQUE_XATTRS: &[&str] = &["trusted.overlay.opaque", "user.overlay.opaque"];
+
+/// What a stage changed in its tree, by path in the tree. A path is in one of the lists at
+/// most, what is below created and deleted directories is listed too.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChangeSet {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub created: Vec<PathBuf>,
+
+    /// Files whose content or metadata changed, and directories whose metadata did.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub modified: Vec<PathBuf>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<PathBuf>,
+}
+
+impl ChangeSet {
+    /// The changes in `upper`, the upper directory of an overlay, to `lower`, the tree below
+    /// it. Files in the upper directory were created or copied up and modified, whiteouts
+    /// are deleted files, and opaque directories replace the directory below them. Parent
+    /// directories that were only copied up aren't changes.
+    pub fn from_upper(upper: &Path, lower: &Path) -> io::Result<Self> {
+        let mut changes = Self::default();
+
+        changes.compare(upper, lower, Path::new("/"))?;
+
+        Ok(changes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.modified.is_empty() && self.deleted.is_empty()
+    }
+
+    fn compare(&mut self, upper: &Path, lower: &Path, path: &Path) -> io::Result<()> {
+        for entry in sorted_entries(upper)? {
+            let name = entry.file_name();
+            let metadata = entry.metadata()?;
+            let below = lower.join(&name);
+            let path = path.join(&name);
+
+            let existing = match fs::symlink_metadata(&below) {
+                Ok(metadata) => Some(metadata),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err),
+            };
+
+            if is_whiteout(&metadata) {
+                if existing.is_some() {
+                    self.delete_all(&below, &path)?;
+                }
+
+                continue;
+            }
+
+            match existing {
+                None if metadata.is_dir() => {
+                    self.created.push(path.clone());
+                    self.create_all(&entry.path(), &path)?;
+                }
+                None => self.created.push(path),
+                Some(existing) if metadata.is_dir() && existing.is_dir() => {
+                    if !same_metadata(&metadata, &existing) {
+                        self.modified.push(path.clone());
+                    }
+
+                    if is_opaque(&entry.path())? {
+                        // what was below and isn't in the upper directory anymore is gone
+                        for gone in sorted_entries(&below)? {
+                            if fs::symlink_metadata(entry.path().join(gone.file_name())).is_err() {
+                                self.delete_all(&gone.path(), &path.join(gone.file_name()))?;
+                            }
+                        }
+                    }
+
+                    self.compare(&entry.path(), &below, &path)?;
+                }
+                Some(_) if metadata.is_dir() => {
+                    self.modified.push(path.clone());
+                    self.create_all(&entry.path(), &path)?;
+                }
+                Some(_) => self.modified.push(path),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record what is below the created directory `upper` as created.
+    fn create_all(&mut self, upper: &Path, path: &Path) -> io::Result<()> {
+        for entry in sorted_entries(upper)? {
+            let path = path.join(entry.file_name());
+
+            if is_whiteout(&entry.metadata()?) {
+                continue;
+            }
+
+            self.created.push(path.clone());
+
+            if entry.file_type()?.is_dir() {
+                self.create_all(&entry.path(), &path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record `lower`, and what is below it, as deleted.
+    fn delete_all(&mut self, lower: &Path, path: &Path) -> io::Result<()> {
+        self.deleted.push(path.to_path_buf());
+
+        if fs::symlink_metadata(lower)?.is_dir() {
+            for entry in sorted_entries(lower)? {
+                self.delete_all(&entry.path(), &path.join(entry.file_name()))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Apply the upper directory of an overlay, `upper`, to the tree below it, `lower`; so that
+/// `lower` is what the overlay was. Whiteouts remove what they cover, opaque directories
+/// replace the directory below them, and everything else is copied over what is below it.
+/// Directories get the owners, permissions and times of those in the overlay.
+pub fn apply(upper: &Path, lower: &Path) -> io::Result<()> {
+    for entry in sorted_entries(upper)? {
+        let metadata = entry.metadata()?;
+        let below = lower.join(entry.file_name());
+
+        let existing = match fs::symlink_metadata(&below) {
+            Ok(existing) => Some(existing),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+
+        let replace = match &existing {
+            None => false,
+            Some(_) if is_whiteout(&metadata) => true,
+            Some(existing) if metadata.is_dir() && existing.is_dir() => is_opaque(&entry.path())?,
+            Some(_) => true,
+        };
+
+        if replace {
+            match existing {
+                Some(existing) if existing.is_dir() => fs::remove_dir_all(&below)?,
+                _ => fs::remove_file(&below)?,
+            }
+        }
+
+        if is_whiteout(&metadata) {
+            continue;
+        }
+
+        if !metadata.is_dir() {
+            tree::copy_all(&entry.path(), &below)?;
+            continue;
+        }
+
+        if !below.is_dir() {
+            fs::create_dir(&below)?;
+        }
+
+        apply(&entry.path(), &below)?;
+
+        // only root can give files away, other owners are a best effort
+        match std::os::unix::fs::lchown(&below, Some(metadata.uid()), Some(metadata.gid())) {
+            Err(err) if err.kind() != io::ErrorKind::PermissionDenied => return Err(err),
+            _ => {}
+        }
+
+        fs::set_permissions(&below, fs::Permissions::from_mode(metadata.mode()))?;
+
+        // applying what is below the directory changed its times, they are the overlay's
+        tree::set_times(&below, &metadata)?;
+    }
+
+    Ok(())
+}
+
+/// An overlay of a tree, a stage run on the merged directory changes only the upper one.
+/// The overlay's directories are kept in `root`.
+pub struct Overlay {
+    lower: PathBuf,
+    root: PathBuf,
+}
+
+impl Overlay {
+    /// An overlay of `lower` in `root`, which is created empty.
+    pub fn new(root: &Path, lower: &Path) -> io::Result<Self> {
+        let overlay = Self {
+            lower: lower.to_path_buf(),
+            root: root.to_path_buf(),
+        };
+
+        overlay.remove()?;
+
+        for path in [overlay.upper(), overlay.work(), overlay.merged()] {
+            fs::create_dir_all(path)?;
+        }
+
+        Ok(overlay)
+    }
+
+    pub fn upper(&self) -> PathBuf {
+        self.root.join("upper")
+    }
+
+    fn work(&self) -> PathBuf {
+        self.root.join("work")
+    }
+
+    pub fn merged(&self) -> PathBuf {
+        self.root.join("merged")
+    }
+
+    /// The options of the overlay; without redirects and metadata only copy ups, so the
+    /// upper directory has everything that changed in full.
+    fn options(&self) -> String {
+        format!(
+            "lowerdir={},upperdir={},workdir={},redirect_dir=off,metacopy=off",
+            self.lower.display(),
+            self.upper().display(),
+            self.work().display()
+        )
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn mount(&self) -> io::Result<()> {
+        let target = c_path(&self.merged())?;
+        let options = CString::new(self.options())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        // SAFETY: the strings outlive the call
+        if unsafe {
+            libc::mount(
+                c"overlay".as_ptr(),
+                target.as_ptr(),
+                c"overlay".as_ptr(),
+                0,
+                options.as_ptr().cast(),
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn mount(&self) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn umount(&self) -> io::Result<()> {
+        let target = c_path(&self.merged())?;
+
+        // SAFETY: the path outlives the call
+        if unsafe { libc::umount2(target.as_ptr(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn umount(&self) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Detach the overlay from the merged directory when it can't be unmounted because it is
+    /// busy, it is unmounted once it isn't.
+    #[cfg(target_os = "linux")]
+    pub fn detach(&self) -> io::Result<()> {
+        let target = c_path(&self.merged())?;
+
+        // SAFETY: the path outlives the call
+        if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn detach(&self) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Remove the directories of the overlay, it has to be unmounted.
+    pub fn remove(&self) -> io::Result<()> {
+        match fs::remove_dir_all(&self.root) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+fn sorted_entries(path: &Path) -> io::Result<Vec<fs::DirEntry>> {
+    let mut entries: Vec<fs::DirEntry> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    Ok(entries)
+}
+
+/// Whether `metadata` is of a whiteout, a character device with device number 0/0.
+fn is_whiteout(metadata: &fs::Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+/// Whether the directory `path` of an upper directory is opaque.
+fn is_opaque(path: &Path) -> io::Result<bool> {
+    let path = c_path(path)?;
+
+    for name in OPAQUE_XATTRS {
+        let name = CString::new(*name).expect("attribute names have no nul bytes");
+        let mut value = [0u8; 1];
+
+        // SAFETY: the strings and the buffer outlive the call, the buffer is as long as
+        // is passed
+        let length = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+
+        if length == 1 && value[0] == b'y' {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Whether the permissions and owners of two directories are the same; times change when
+/// directories are copied up.
+fn same_metadata(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    (a.mode(), a.uid(), a.gid()) == (b.mode(), b.uid(), b.gid())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A whiteout at `path`, `None` when making device nodes isn't permitted.
+    fn whiteout(path: &Path) -> Option<()> {
+        let path = c_path(path).unwrap();
+
+        // SAFETY: the path outlives the call
+        (unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR | 0o600, 0) } == 0).then_some(())
+    }
+
+    #[test]
+    fn changes_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let lower = dir.path().join("lower");
+        let upper = dir.path().join("upper");
+
+        for path in ["etc", "usr/share/doc", "var/cache"] {
+            fs::create_dir_all(lower.join(path)).unwrap();
+            fs::create_dir_all(upper.join(path)).unwrap();
+        }
+
+        fs::write(lower.join("etc/hostname"), "old").unwrap();
+        fs::write(lower.join("etc/hosts"), "").unwrap();
+
+        // copied up and modified, created, and a new directory
+        fs::write(upper.join("etc/hostname"), "new").unwrap();
+        fs::write(upper.join("etc/motd"), "hi").unwrap();
+        fs::create_dir_all(upper.join("opt/app")).unwrap();
+        fs::write(upper.join("opt/app/run"), "").unwrap();
+
+        fs::set_permissions(upper.join("var/cache"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::set_permissions(lower.join("var/cache"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let changes = ChangeSet::from_upper(&upper, &lower).unwrap();
+
+        assert_eq!(
+            changes.created,
+            ["/etc/motd", "/opt", "/opt/app", "/opt/app/run"].map(PathBuf::from)
+        );
+        assert_eq!(
+            changes.modified,
+            ["/etc/hostname", "/var/cache"].map(PathBuf::from)
+        );
+        assert!(changes.deleted.is_empty());
+
+        // a stage that didn't change anything leaves the upper directory empty
+        let empty = dir.path().join("empty");
+
+        fs::create_dir(&empty).unwrap();
+
+        assert!(ChangeSet::from_upper(&empty, &lower).unwrap().is_empty());
+
+        // creating whiteouts needs CAP_MKNOD
+        if whiteout(&upper.join("etc/hosts")).is_none() {
+            return;
+        }
+
+        fs::remove_dir_all(upper.join("usr/share")).unwrap();
+        whiteout(&upper.join("usr/share")).unwrap();
+
+        let changes = ChangeSet::from_upper(&upper, &lower).unwrap();
+
+        assert_eq!(
+            changes.deleted,
+            ["/etc/hosts", "/usr/share", "/usr/share/doc"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn changes_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let lower = dir.path().join("lower");
+        let upper = dir.path().join("upper");
+
+        fs::create_dir_all(lower.join("etc")).unwrap();
+        fs::create_dir_all(upper.join("etc")).unwrap();
+        fs::create_dir_all(upper.join("opt/app")).unwrap();
+
+        fs::write(lower.join("etc/hostname"), "old").unwrap();
+        fs::write(lower.join("etc/hosts"), "").unwrap();
+        fs::write(upper.join("etc/hostname"), "new").unwrap();
+        fs::write(upper.join("opt/app/run"), "").unwrap();
+
+        let past = fs::File::open(upper.join("opt/app")).unwrap();
+        past.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000))
+            .unwrap();
+
+        let deletes = whiteout(&upper.join("etc/hosts")).is_some();
+
+        apply(&upper, &lower).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(lower.join("etc/hostname")).unwrap(),
+            "new"
+        );
+        assert!(lower.join("opt/app/run").is_file());
+        assert_eq!(lower.join("etc/hosts").exists(), !deletes);
+
+        // directories have the times they had in the overlay
+        assert_eq!(
+            fs::metadata(lower.join("opt/app")).unwrap().mtime(),
+            fs::metadata(upper.join("opt/app")).unwrap().mtime()
+        );
+
+        // the tree is what the overlay was, applying again changes nothing
+        apply(&upper, &lower).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(lower.join("etc/hostname")).unwrap(),
+            "new"
+        );
+    }
+
+    #[test]
+    fn changes_serialized() {
+        let changes = ChangeSet {
+            created: vec![PathBuf::from("/etc/motd")],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            serde_json::to_value(&changes).unwrap(),
+            serde_json::json!({"created": ["/etc/motd"]})
+        );
+        assert_eq!(
+            serde_json::from_value::<ChangeSet>(serde_json::json!({"deleted": ["/etc/hosts"]}))
+                .unwrap()
+                .deleted,
+            [PathBuf::from("/etc/hosts")]
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::core::environment::{Environment, DEFAULT_PATH};
+use crate::core::executor::hooks::{ExecutorHooks, StageAction};
+use crate::core::executor::ExecutorError;
+use crate::manifest::Stage;
+use crate::module::capability::Capability;
+use crate::sandbox::isolation::{Isolation, BWRAP};
+
+/// The shell started at a breakpoint unless another one is set.
+pub const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// Where the tree is bound in the build root of a debug shell.
+pub const TREE_PATH: &str = "/run/osbuild/tree";
+
+/// When a breakpoint pauses the build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pause {
+    Before,
+
+    /// After the stage ran, or failed.
+    After,
+}
+
+/// Pauses the build before or after stages of a type and starts an interactive shell inside
+/// the build root, the build continues when the shell exits. The shell runs isolated like
+/// stages are, but with every capability.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    kind: String,
+    pause: Pause,
+    shell: PathBuf,
+
+    /// The build root to start the shell in, with the tree bound at `TREE_PATH`. Without one
+    /// the shell is started in the tree.
+    build_root: Option<PathBuf>,
+
+    hits: usize,
+}
+
+impl Breakpoint {
+    /// A breakpoint for stages of type `kind`.
+    pub fn new(kind: &str, pause: Pause) -> Self {
+        Self {
+            kind: kind.to_string(),
+            pause,
+            shell: PathBuf::from(DEFAULT_SHELL),
+            build_root: None,
+            hits: 0,
+        }
+    }
+
+    pub fn set_shell(&mut self, shell: &Path) {
+        self.shell = shell.to_path_buf();
+    }
+
+    pub fn set_build_root(&mut self, build_root: Option<&Path>) {
+        self.build_root = build_root.map(Path::to_path_buf);
+    }
+
+    /// How often the build was paused.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// The command starting the shell for `stage` building `tree`.
+    pub fn command(&self, stage: &Stage, tree: &Path) -> Command {
+        let mut environment = Environment::new();
+
+        environment.insert("PATH".to_string(), DEFAULT_PATH.to_string());
+        environment.insert("PS1".to_string(), format!("(osbuild {}) # ", stage.kind()));
+
+        if let Ok(term) = std::env::var("TERM") {
+            environment.insert("TERM".to_string(), term);
+        }
+
+        let mut isolation = Isolation::granting(&Capability::ALL);
+        isolation.set_environment(environment);
+
+        let mut command = Command::new(BWRAP);
+
+        match &self.build_root {
+            Some(build_root) => command
+                .args(isolation.args(build_root))
+                .arg("--bind")
+                .arg(tree)
+                .arg(TREE_PATH)
+                .args(["--chdir", TREE_PATH]),
+            None => command.args(isolation.args(tree)).args(["--chdir", "/"]),
+        };
+
+        command.arg("--").arg(&self.shell);
+        command
+    }
+
+    /// Start the shell and wait for it to exit, when `stage` is one to pause at.
+    fn pause(&mut self, pause: Pause, stage: &Stage, tree: &Path) {
+        if pause != self.pause || stage.kind() != self.kind {
+            return;
+        }
+
+        self.hits += 1;
+
+        // there is nothing to continue with but the build when the shell doesn't start
+        let _ = self.command(stage, tree).status();
+    }
+}
+
+impl ExecutorHooks for Breakpoint {
+    fn pre_stage(&mut self, stage: &Stage, tree: &Path) -> StageAction {
+        self.pause(Pause::Before, stage, tree);
+
+        StageAction::Run
+    }
+
+    fn post_stage(&mut self, stage: &Stage, tree: &Path) {
+        self.pause(Pause::After, stage, tree);
+    }
+
+    fn on_failure(&mut self, stage: &Stage, tree: &Path, _error: &ExecutorError) {
+        self.pause(Pause::After, stage, tree);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn shell_in_build_root() {
+        let stage = Stage::new("org.osbuild.rpm");
+        let mut breakpoint = Breakpoint::new("org.osbuild.rpm", Pause::Before);
+
+        let shell = args(&breakpoint.command(&stage, Path::new("/store/tree")));
+
+        assert_eq!(shell[..3], ["--bind", "/store/tree", "/"]);
+        assert_eq!(shell[shell.len() - 2..], ["--", DEFAULT_SHELL]);
+        assert!(shell
+            .windows(3)
+            .any(|args| args == ["--setenv", "PS1", "(osbuild org.osbuild.rpm) # "]));
+        assert!(shell.windows(2).any(|pair| pair == ["--cap-add", "ALL"]));
+
+        breakpoint.set_build_root(Some(Path::new("/store/build")));
+        breakpoint.set_shell(Path::new("/bin/bash"));
+
+        let shell = args(&breakpoint.command(&stage, Path::new("/store/tree")));
+
+        assert_eq!(shell[..3], ["--bind", "/store/build", "/"]);
+        assert!(shell
+            .windows(3)
+            .any(|args| args == ["--bind", "/store/tree", TREE_PATH]));
+        assert_eq!(shell[shell.len() - 3..], [TREE_PATH, "--", "/bin/bash"]);
+    }
+
+    #[test]
+    fn paused_at_stages() {
+        let mut breakpoint = Breakpoint::new("org.osbuild.rpm", Pause::After);
+
+        // a shell that exits right away, as if the user exited it
+        breakpoint.set_shell(Path::new("/bin/true"));
+
+        let tree = tempfile::tempdir().unwrap();
+        let rpm = Stage::new("org.osbuild.rpm");
+        let noop = Stage::new("org.osbuild.noop");
+
+        assert_eq!(breakpoint.pre_stage(&rpm, tree.path()), StageAction::Run);
+        breakpoint.post_stage(&noop, tree.path());
+        assert_eq!(breakpoint.hits(), 0);
+
+        breakpoint.post_stage(&rpm, tree.path());
+        assert_eq!(breakpoint.hits(), 1);
+
+        breakpoint.on_failure(
+            &rpm,
+            tree.path(),
+            &ExecutorError::InvalidStage(String::new()),
+        );
+        assert_eq!(breakpoint.hits(), 2);
+    }
+}
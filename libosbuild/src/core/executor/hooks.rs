@@ -0,0 +1,33 @@
+use std::path::Path;
+
+use crate::core::executor::ExecutorError;
+use crate::manifest::Stage;
+
+/// What the executor does with a stage after asking its hooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageAction {
+    Run,
+
+    /// Don't run the stage, a hook already produced its tree, e.g. from a cache.
+    Skip,
+}
+
+/// Callbacks for applications that embed the executor, for caching, notifications, or
+/// uploading artifacts. Every callback does nothing by default.
+pub trait ExecutorHooks {
+    /// Called before `stage` is set up and run on `tree`. When any hook returns
+    /// `StageAction::Skip` the stage isn't run, and the remaining hooks aren't asked.
+    fn pre_stage(&mut self, _stage: &Stage, _tree: &Path) -> StageAction {
+        StageAction::Run
+    }
+
+    /// Called after `stage` ran on `tree` and everything it used was torn down, also when
+    /// the stage was skipped.
+    fn post_stage(&mut self, _stage: &Stage, _tree: &Path) {}
+
+    /// Called when the tree of the pipeline `name` was committed with the id `id`.
+    fn on_commit(&mut self, _name: &str, _id: &str, _tree: &Path) {}
+
+    /// Called when `stage` failed on `tree`, or setting it up or tearing it down did.
+    fn on_failure(&mut self, _stage: &Stage, _tree: &Path, _error: &ExecutorError) {}
+}
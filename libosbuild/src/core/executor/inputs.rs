@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::core::executor::ExecutorError;
+use crate::manifest::{Input, Origin};
+
+/// Prefix of references to pipelines by name rather than by the id of their tree.
+pub const PIPELINE_PREFIX: &str = "name:";
+
+/// Where the content inputs refer to is found: the trees of pipelines that were built, by
+/// name and by id, and the cache of sources which has a directory per source module with the
+/// items of that source in it by checksum.
+#[derive(Debug, Clone, Default)]
+pub struct Content {
+    pipelines: BTreeMap<String, String>,
+    trees: BTreeMap<String, PathBuf>,
+    sources: Option<PathBuf>,
+}
+
+impl Content {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that the pipeline `name` was built into `tree`, which has the id `id`.
+    pub fn add_pipeline(&mut self, name: &str, id: &str, tree: &Path) {
+        self.pipelines.insert(name.to_string(), id.to_string());
+        self.trees.insert(id.to_string(), tree.to_path_buf());
+    }
+
+    /// Record that `tree` has the id `id`, for trees that aren't the tree of a pipeline such as
+    /// the objects in a store.
+    pub fn add_tree(&mut self, id: &str, tree: &Path) {
+        self.trees.insert(id.to_string(), tree.to_path_buf());
+    }
+
+    pub fn set_sources(&mut self, sources: &Path) {
+        self.sources = Some(sources.to_path_buf());
+    }
+
+    /// The id and tree of a pipeline reference.
+    pub fn tree(&self, reference: &str) -> Option<(&str, &Path)> {
+        let id = match reference.strip_prefix(PIPELINE_PREFIX) {
+            Some(name) => self.pipelines.get(name)?.as_str(),
+            None => reference,
+        };
+
+        self.trees
+            .get_key_value(id)
+            .map(|(id, tree)| (id.as_str(), tree.as_path()))
+    }
+
+    /// The path of a source item, looked for in the directories of all source modules in
+    /// name order. Checksums are `<algo>:<hex>`, anything else isn't an item and can't be a
+    /// path outside of the directories.
+    pub fn source_item(&self, checksum: &str) -> Option<PathBuf> {
+        if !is_checksum(checksum) {
+            return None;
+        }
+
+        let mut modules: Vec<PathBuf> = fs::read_dir(self.sources.as_ref()?)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .collect();
+
+        modules.sort();
+
+        modules
+            .into_iter()
+            .map(|module| module.join(checksum))
+            .find(|item| item.exists())
+    }
+}
+
+/// Whether `checksum` is the checksum of a source item, `<algo>:<hex>`.
+fn is_checksum(checksum: &str) -> bool {
+    match checksum.split_once(':') {
+        Some((algo, hex)) => {
+            !algo.is_empty()
+                && algo.chars().all(|c| c.is_ascii_alphanumeric())
+                && !hex.is_empty()
+                && hex.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
+
+/// A reference of an input with the content it refers to.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResolvedReference {
+    pub id: String,
+    pub path: PathBuf,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<serde_json::Value>,
+}
+
+/// Resolve the references of the input `name` to content. References to pipelines get the
+/// id of the pipeline's tree, references to sources keep their checksum.
+pub fn resolve(
+    name: &str,
+    input: &Input,
+    content: &Content,
+) -> Result<Vec<ResolvedReference>, ExecutorError> {
+    input
+        .references()
+        .entries()
+        .into_iter()
+        .map(|(reference, options)| {
+            let missing = || ExecutorError::MissingInput(name.to_string(), reference.to_string());
+
+            let (id, path) = match input.origin() {
+                Origin::Pipeline => content
+                    .tree(reference)
+                    .map(|(id, tree)| (id.to_string(), tree.to_path_buf()))
+                    .ok_or_else(missing)?,
+                Origin::Source => (
+                    reference.to_string(),
+                    content.source_item(reference).ok_or_else(missing)?,
+                ),
+            };
+
+            Ok(ResolvedReference {
+                id,
+                path,
+                options: options.cloned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::manifest::References;
+
+    #[test]
+    fn resolve_pipelines() {
+        let mut content = Content::new();
+        content.add_pipeline("build", "aa", Path::new("/store/objects/aa"));
+
+        let input = Input::new(
+            "org.osbuild.tree",
+            Origin::Pipeline,
+            References::Ids(vec!["name:build".to_string(), "aa".to_string()]),
+        );
+
+        let resolved = resolve("tree", &input, &content).unwrap();
+
+        assert_eq!(resolved.len(), 2);
+        assert_eq!(resolved[0].id, "aa");
+        assert_eq!(resolved[0].path, PathBuf::from("/store/objects/aa"));
+        assert_eq!(resolved[0], resolved[1]);
+
+        let input = Input::new(
+            "org.osbuild.tree",
+            Origin::Pipeline,
+            References::Ids(vec!["name:os".to_string()]),
+        );
+
+        assert!(matches!(
+            resolve("tree", &input, &content),
+            Err(ExecutorError::MissingInput(name, reference)) if name == "tree" && reference == "name:os"
+        ));
+        // trees that aren't of a pipeline can only be referred to by id
+        content.add_tree("bb", Path::new("/store/objects/bb"));
+
+        assert_eq!(
+            content.tree("bb").unwrap().1,
+            Path::new("/store/objects/bb")
+        );
+        assert!(content.tree("name:bb").is_none());
+    }
+
+    #[test]
+    fn resolve_sources() {
+        let directory = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(directory.path().join("org.osbuild.curl")).unwrap();
+        fs::create_dir_all(directory.path().join("org.osbuild.inline")).unwrap();
+        fs::write(directory.path().join("org.osbuild.inline/sha256:bb"), "b").unwrap();
+
+        let mut content = Content::new();
+        content.set_sources(directory.path());
+
+        let input = Input::new(
+            "org.osbuild.files",
+            Origin::Source,
+            References::Map(BTreeMap::from([(
+                "sha256:bb".to_string(),
+                serde_json::json!({"file": "b"}),
+            )])),
+        );
+
+        let resolved = resolve("files", &input, &content).unwrap();
+
+        assert_eq!(
+            resolved,
+            vec![ResolvedReference {
+                id: "sha256:bb".to_string(),
+                path: directory.path().join("org.osbuild.inline/sha256:bb"),
+                options: Some(serde_json::json!({"file": "b"})),
+            }]
+        );
+
+        // a source reference is never a pipeline
+        let input = Input::new(
+            "org.osbuild.files",
+            Origin::Source,
+            References::Ids(vec!["name:build".to_string()]),
+        );
+
+        assert!(resolve("files", &input, &content).is_err());
+
+        // references are checksums and not paths, not even ones to items that exist
+        fs::write(directory.path().join("secret"), "").unwrap();
+
+        for reference in [
+            "../secret",
+            "sha256:bb/../../secret",
+            "/etc/passwd",
+            "sha256:",
+            ":bb",
+            "sha256:xyz",
+            "",
+        ] {
+            assert!(content.source_item(reference).is_none(), "{}", reference);
+        }
+        assert!(resolve("files", &input, &Content::new()).is_err());
+    }
+}
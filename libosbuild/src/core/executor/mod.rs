@@ -0,0 +1,590 @@
+/// Building all pipelines of a manifest into a store.
+pub mod build;
+
+/// What stages change in their trees, recorded with overlays.
+pub mod changes;
+
+/// Callbacks for applications that embed the executor.
+pub mod hooks;
+
+/// Pausing builds at stages to debug them in a shell.
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+pub mod debug;
+
+/// Plans of what a build runs.
+pub mod plan;
+
+/// Resolving the references of inputs to the content they refer to.
+pub mod inputs;
+
+/// Services provided by running the input, device, mount, and stage modules of a registry.
+pub mod modules;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::accounting::ResourceUsage;
+use crate::core::executor::changes::{ChangeSet, Overlay};
+use crate::core::executor::hooks::{ExecutorHooks, StageAction};
+use crate::core::executor::inputs::{Content, ResolvedReference};
+use crate::core::executor::plan::Plan;
+use crate::core::id::HashAlgo;
+use crate::core::store::StoreError;
+use crate::manifest::{Device, Manifest, Origin, Stage};
+use crate::module::capability::Capability;
+use crate::module::util::tree::TreePath;
+
+#[cfg(test)]
+mod test;
+
+/// Where stages find the device nodes of the devices they use.
+pub const DEVICES_PATH: &str = "/dev";
+
+#[derive(Debug)]
+pub enum ExecutorError {
+    /// A module the stage uses is not in the registry.
+    MissingModule(String),
+
+    /// The devices or mounts of a stage don't fit together, such as a mount of a device the
+    /// stage doesn't declare or devices that are each other's parent.
+    InvalidStage(String),
+
+    /// A module needs capabilities the policy forbids, contains the ones it is denied.
+    CapabilityDenied(String, Vec<Capability>),
+
+    /// A module failed, with its exit code and what it wrote to stderr.
+    ModuleFailed(String, Option<i32>, String),
+
+    /// The content a reference of an input refers to isn't there, contains the name of the
+    /// input and the reference.
+    MissingInput(String, String),
+
+    /// A module wrote more to stdout than it may, contains the limit.
+    OutputTooLarge(String, usize),
+
+    /// A module didn't exit within its timeout, which is contained. It was killed.
+    Timeout(String, Duration),
+
+    /// A module was stopped, or not started, because the build was cancelled.
+    Cancelled(String),
+
+    /// A stage of a hermetic build used the network or opened paths it doesn't declare,
+    /// contains the accesses.
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    Unhermetic(String, Vec<crate::sandbox::hermetic::Access>),
+
+    /// A module replied with something that isn't what it should reply.
+    InvalidReply(String, serde_json::Error),
+
+    /// Reading a tree from, or committing one to, the store failed.
+    StoreError(StoreError),
+
+    IOError(io::Error),
+}
+
+impl From<StoreError> for ExecutorError {
+    fn from(err: StoreError) -> Self {
+        Self::StoreError(err)
+    }
+}
+
+impl From<io::Error> for ExecutorError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// The location of an opened device or mount, as passed to stages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathArgument {
+    pub path: PathBuf,
+}
+
+/// The location of a mapped input, with whatever its input module tells the stage about it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputArgument {
+    pub path: PathBuf,
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Paths {
+    pub devices: PathBuf,
+    pub inputs: PathBuf,
+    pub mounts: PathBuf,
+}
+
+/// The arguments a stage module is run with, stages read them with `module::stage`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StageArguments {
+    pub tree: PathBuf,
+    pub options: serde_json::Value,
+    pub paths: Paths,
+    pub inputs: BTreeMap<String, InputArgument>,
+    pub devices: BTreeMap<String, PathArgument>,
+    pub mounts: BTreeMap<String, PathArgument>,
+}
+
+/// Everything the executor does on the host goes through `Services`; mapping inputs, opening
+/// devices, mounting filesystems, and running stages. `modules::ModuleServices` runs the modules of a
+/// registry for this.
+pub trait Services {
+    /// Make the content of the resolved `references` available read-only in `target` with
+    /// the input module `kind`. Returns the data the module has for the stage.
+    fn map_input(
+        &mut self,
+        kind: &str,
+        origin: Origin,
+        references: &[ResolvedReference],
+        target: &Path,
+        options: Option<&serde_json::Value>,
+    ) -> Result<serde_json::Value, ExecutorError>;
+
+    fn unmap_input(&mut self, kind: &str, target: &Path) -> Result<(), ExecutorError>;
+
+    /// Open a device with the device module `kind`, on the device at `parent` if it is
+    /// stacked. Returns the path of the device node.
+    fn open_device(
+        &mut self,
+        kind: &str,
+        options: Option<&serde_json::Value>,
+        parent: Option<&Path>,
+    ) -> Result<PathBuf, ExecutorError>;
+
+    fn close_device(&mut self, kind: &str, path: &Path) -> Result<(), ExecutorError>;
+
+    /// Mount the device at `source`, if any, on `target` with the mount module `kind`.
+    fn mount(
+        &mut self,
+        kind: &str,
+        source: Option<&Path>,
+        target: &Path,
+        options: Option<&serde_json::Value>,
+    ) -> Result<(), ExecutorError>;
+
+    fn umount(&mut self, kind: &str, target: &Path) -> Result<(), ExecutorError>;
+
+    fn run_stage(&mut self, kind: &str, arguments: &StageArguments) -> Result<(), ExecutorError>;
+
+    /// Called by `Executor::build` before it runs the stages of the pipeline `name`, so the
+    /// stages can be run as their pipeline needs. Nothing is done by default.
+    fn begin_pipeline(&mut self, _name: &str) -> Result<(), ExecutorError> {
+        Ok(())
+    }
+
+    /// Called by `Executor::build` after it ran the stages of the pipeline `name`, whether
+    /// they failed or not. Returns what the stages used, nothing is accounted by default.
+    fn end_pipeline(&mut self, _name: &str) -> Result<ResourceUsage, ExecutorError> {
+        Ok(ResourceUsage::default())
+    }
+
+    /// The options a stage of type `kind` is run with for `options`, such as with the
+    /// defaults of its schema filled in. They are used as they are by default.
+    fn effective_options(
+        &mut self,
+        _kind: &str,
+        options: serde_json::Value,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        Ok(options)
+    }
+}
+
+/// The order to open devices in, parents before the devices on them. Devices that don't
+/// depend on each other are opened in name order.
+pub fn device_order(devices: &BTreeMap<String, Device>) -> Result<Vec<&str>, ExecutorError> {
+    let mut order: Vec<&str> = vec![];
+
+    for (name, device) in devices {
+        if let Some(parent) = device.parent() {
+            if !devices.contains_key(parent) {
+                return Err(ExecutorError::InvalidStage(format!(
+                    "device '{}' is on '{}' which is not a device of the stage",
+                    name, parent
+                )));
+            }
+        }
+    }
+
+    while order.len() < devices.len() {
+        let ready: Vec<&str> = devices
+            .iter()
+            .filter(|(name, _)| !order.contains(&name.as_str()))
+            .filter(|(_, device)| device.parent().is_none_or(|parent| order.contains(&parent)))
+            .map(|(name, _)| name.as_str())
+            .collect();
+
+        if ready.is_empty() {
+            return Err(ExecutorError::InvalidStage(
+                "the parents of the devices form a cycle".to_string(),
+            ));
+        }
+
+        order.extend(ready);
+    }
+
+    Ok(order)
+}
+
+/// `path` of a manifest, such as the target of a mount or the name of an input, normalized
+/// to a path below the directory it is joined onto, see `TreePath::relative`. Paths that
+/// would go above it are refused.
+fn below(path: &Path) -> Result<PathBuf, ExecutorError> {
+    TreePath::relative(path).map_err(|err| ExecutorError::InvalidStage(format!("{:?}", err)))
+}
+
+/// What was set up for a stage and is torn down after it, in the order it was set up; the
+/// input and mount targets with their module, and the devices by name with their module.
+#[derive(Default)]
+struct Setup {
+    inputs: Vec<(String, PathBuf)>,
+    devices: Vec<(String, String, PathBuf)>,
+    mounts: Vec<(String, PathBuf)>,
+}
+
+impl Setup {
+    fn device_path(&self, name: &str) -> Option<PathBuf> {
+        self.devices
+            .iter()
+            .find(|(other, _, _)| other == name)
+            .map(|(_, _, path)| path.clone())
+    }
+}
+
+/// Runs stages with their inputs, devices, and mounts set up. Inputs and mounts go below
+/// `runtime`, a directory that is private to this executor. Inputs are resolved against the
+/// executor's `Content`. Hooks are called in the order they were added.
+pub struct Executor<S: Services> {
+    services: S,
+    runtime: PathBuf,
+    content: Content,
+    hooks: Vec<Box<dyn ExecutorHooks>>,
+
+    /// The options the last stage that was run got.
+    options: Option<serde_json::Value>,
+
+    /// The options of the stages `build` ran, by stage id.
+    effective_options: BTreeMap<String, serde_json::Value>,
+
+    /// Whether `build` runs stages over an overlay to record what they change.
+    track_changes: bool,
+
+    /// What the stages `build` ran changed, by stage id, when changes are tracked.
+    changes: BTreeMap<String, ChangeSet>,
+
+    /// The algorithm the ids of stages are computed with.
+    hash_algo: HashAlgo,
+
+    /// What building each pipeline `build` built cost, by pipeline name.
+    usage: BTreeMap<String, ResourceUsage>,
+
+    /// The ids of the trees `build` committed to the store, by pipeline name.
+    committed: BTreeMap<String, String>,
+}
+
+impl<S: Services> Executor<S> {
+    pub fn new(services: S, runtime: &Path) -> Self {
+        Self {
+            services,
+            runtime: runtime.to_path_buf(),
+            content: Content::new(),
+            hooks: vec![],
+            options: None,
+            effective_options: BTreeMap::new(),
+            track_changes: false,
+            changes: BTreeMap::new(),
+            hash_algo: HashAlgo::default(),
+            usage: BTreeMap::new(),
+            committed: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_hooks(&mut self, hooks: Box<dyn ExecutorHooks>) {
+        self.hooks.push(hooks);
+    }
+
+    pub fn services(&self) -> &S {
+        &self.services
+    }
+
+    pub fn content(&self) -> &Content {
+        &self.content
+    }
+
+    /// The content inputs are resolved against, pipelines are added as they are built.
+    pub fn content_mut(&mut self) -> &mut Content {
+        &mut self.content
+    }
+
+    /// The options the last stage that was run got, as its `Services` made them effective.
+    /// Stages that hooks skip or that fail to be set up don't get any.
+    pub fn stage_options(&self) -> Option<&serde_json::Value> {
+        self.options.as_ref()
+    }
+
+    /// The options of every stage `build` ran, by stage id.
+    pub fn effective_options(&self) -> &BTreeMap<String, serde_json::Value> {
+        &self.effective_options
+    }
+
+    /// Have `build` run every stage over an overlay of its tree and record what it created,
+    /// modified, and deleted. Mounting the overlays needs privileges.
+    pub fn set_track_changes(&mut self, track: bool) {
+        self.track_changes = track;
+    }
+
+    /// What the stages `build` ran changed, by stage id, when changes are tracked.
+    pub fn changes(&self) -> &BTreeMap<String, ChangeSet> {
+        &self.changes
+    }
+
+    /// What building the pipelines `build` built cost, by pipeline name; pipelines that were
+    /// cached cost nothing and aren't in it.
+    pub fn usage(&self) -> &BTreeMap<String, ResourceUsage> {
+        &self.usage
+    }
+
+    /// The ids of the trees of the pipelines `build` built and committed to the store, by
+    /// pipeline name; pipelines that were cached aren't in it.
+    pub fn committed(&self) -> &BTreeMap<String, String> {
+        &self.committed
+    }
+
+    /// Commit `tree` as the tree of the pipeline `name` with the id `id`, so later stages can
+    /// use it as an input.
+    pub fn commit(&mut self, name: &str, id: &str, tree: &Path) {
+        self.content.add_pipeline(name, id, tree);
+
+        for hooks in &mut self.hooks {
+            hooks.on_commit(name, id, tree);
+        }
+    }
+
+    /// Compute the ids of stages with `algo` instead of the default algorithm.
+    pub fn set_hash_algo(&mut self, algo: HashAlgo) {
+        self.hash_algo = algo;
+    }
+
+    /// Plan the build of `manifest`, stages whose tree is in the executor's content are
+    /// cached. Ids are computed with the executor's algorithm.
+    pub fn plan(&self, manifest: &Manifest) -> Result<Plan, ExecutorError> {
+        plan::plan(manifest, &self.content, self.hash_algo)
+    }
+
+    /// The directory a stage's inputs are mapped below, each in a directory of its name.
+    pub fn inputs_path(&self) -> PathBuf {
+        self.runtime.join("inputs")
+    }
+
+    /// The directory a stage's filesystems are mounted below.
+    pub fn mounts_path(&self) -> PathBuf {
+        self.runtime.join("mounts")
+    }
+
+    /// Run `stage` on `tree`. Its inputs are mapped, its devices opened, and its filesystems
+    /// mounted first, their paths are passed to the stage, and they are torn down again in
+    /// reverse order afterwards; also when the stage or setting up fails. Stages a hook
+    /// skips aren't set up at all.
+    pub fn run_stage(&mut self, stage: &Stage, tree: &Path) -> Result<(), ExecutorError> {
+        let skip = self
+            .hooks
+            .iter_mut()
+            .any(|hooks| hooks.pre_stage(stage, tree) == StageAction::Skip);
+
+        self.options = None;
+
+        if !skip {
+            let mut setup = Setup::default();
+
+            let result = self.setup_and_run(stage, tree, &mut setup);
+            let teardown = self.teardown(&setup);
+
+            if let Err(err) = result.and(teardown) {
+                for hooks in &mut self.hooks {
+                    hooks.on_failure(stage, tree, &err);
+                }
+
+                return Err(err);
+            }
+        }
+
+        for hooks in &mut self.hooks {
+            hooks.post_stage(stage, tree);
+        }
+
+        Ok(())
+    }
+
+    /// Run `stage` as `run_stage` does, on an overlay of `tree`, and return what it changed.
+    /// The changes are applied to `tree` afterwards, also when the stage fails; what it
+    /// changed is returned along with the result of running it. The overlay is kept in the
+    /// runtime directory and removed afterwards, whether or not it could be unmounted.
+    pub fn run_stage_tracked(
+        &mut self,
+        stage: &Stage,
+        tree: &Path,
+    ) -> Result<(ChangeSet, Result<(), ExecutorError>), ExecutorError> {
+        let overlay = Overlay::new(&self.runtime.join("overlay"), tree)?;
+
+        if let Err(err) = overlay.mount() {
+            overlay.remove()?;
+            return Err(err.into());
+        }
+
+        let result = self.run_stage(stage, &overlay.merged());
+
+        if let Err(err) = overlay.umount() {
+            // what the stage left behind keeps the overlay busy, it goes once that is gone
+            overlay.detach()?;
+            overlay.remove()?;
+            return Err(err.into());
+        }
+
+        let applied = ChangeSet::from_upper(&overlay.upper(), tree)
+            .and_then(|changes| changes::apply(&overlay.upper(), tree).map(|_| changes));
+
+        overlay.remove()?;
+
+        Ok((applied?, result))
+    }
+
+    fn setup_and_run(
+        &mut self,
+        stage: &Stage,
+        tree: &Path,
+        setup: &mut Setup,
+    ) -> Result<(), ExecutorError> {
+        // resolve everything before mapping anything
+        let resolved = stage
+            .inputs()
+            .iter()
+            .map(|(name, input)| Ok((name, input, inputs::resolve(name, input, &self.content)?)))
+            .collect::<Result<Vec<_>, ExecutorError>>()?;
+
+        let options = self.services.effective_options(
+            stage.kind(),
+            stage
+                .options()
+                .cloned()
+                .unwrap_or_else(|| serde_json::json!({})),
+        )?;
+
+        self.options = Some(options.clone());
+
+        let mut arguments = StageArguments {
+            tree: tree.to_path_buf(),
+            options,
+            paths: Paths {
+                devices: PathBuf::from(DEVICES_PATH),
+                inputs: self.inputs_path(),
+                mounts: self.mounts_path(),
+            },
+            inputs: BTreeMap::new(),
+            devices: BTreeMap::new(),
+            mounts: BTreeMap::new(),
+        };
+
+        for (name, input, references) in resolved {
+            let target = match below(Path::new(name))? {
+                relative if relative.as_os_str().is_empty() => {
+                    return Err(ExecutorError::InvalidStage(format!(
+                        "input '{}' has no name",
+                        name
+                    )))
+                }
+                relative => self.inputs_path().join(relative),
+            };
+
+            std::fs::create_dir_all(&target)?;
+
+            let data = self.services.map_input(
+                input.kind(),
+                input.origin(),
+                &references,
+                &target,
+                input.options(),
+            )?;
+
+            setup
+                .inputs
+                .push((input.kind().to_string(), target.clone()));
+            arguments
+                .inputs
+                .insert(name.clone(), InputArgument { path: target, data });
+        }
+
+        for name in device_order(stage.devices())? {
+            let device = &stage.devices()[name];
+            let parent = device.parent().and_then(|parent| setup.device_path(parent));
+
+            let path =
+                self.services
+                    .open_device(device.kind(), device.options(), parent.as_deref())?;
+
+            setup
+                .devices
+                .push((name.to_string(), device.kind().to_string(), path.clone()));
+            arguments
+                .devices
+                .insert(name.to_string(), PathArgument { path });
+        }
+
+        let root = self.mounts_path();
+
+        for mount in stage.mounts() {
+            let source = match mount.source() {
+                Some(source) => Some(setup.device_path(source).ok_or_else(|| {
+                    ExecutorError::InvalidStage(format!(
+                        "mount '{}' is of '{}' which is not a device of the stage",
+                        mount.name(),
+                        source
+                    ))
+                })?),
+                None => None,
+            };
+
+            // joining an empty path would leave a trailing slash on the root
+            let target = match below(Path::new(mount.target()))? {
+                relative if relative.as_os_str().is_empty() => root.clone(),
+                relative => root.join(relative),
+            };
+
+            std::fs::create_dir_all(&target)?;
+
+            self.services
+                .mount(mount.kind(), source.as_deref(), &target, mount.options())?;
+
+            setup
+                .mounts
+                .push((mount.kind().to_string(), target.clone()));
+            arguments
+                .mounts
+                .insert(mount.name().to_string(), PathArgument { path: target });
+        }
+
+        self.services.run_stage(stage.kind(), &arguments)
+    }
+
+    /// Unmount, close, and unmap everything in reverse order. Tearing down continues past
+    /// failures, the first one is returned.
+    fn teardown(&mut self, setup: &Setup) -> Result<(), ExecutorError> {
+        let mut result = Ok(());
+
+        for (kind, target) in setup.mounts.iter().rev() {
+            result = result.and(self.services.umount(kind, target));
+        }
+
+        for (_, kind, path) in setup.devices.iter().rev() {
+            result = result.and(self.services.close_device(kind, path));
+        }
+
+        for (kind, target) in setup.inputs.iter().rev() {
+            result = result.and(self.services.unmap_input(kind, target));
+        }
+
+        result
+    }
+}
@@ -0,0 +1,737 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::accounting::ResourceUsage;
+use crate::core::environment::Environment;
+use crate::core::executor::inputs::ResolvedReference;
+use crate::core::executor::{ExecutorError, Services, StageArguments};
+use crate::manifest::description::defaults;
+use crate::manifest::Origin;
+use crate::module::cancel::CancellationToken;
+use crate::module::capability::Policy;
+use crate::module::output::Cancellation;
+use crate::module::{output, Kind, Module, Registry};
+#[cfg(feature = "communication")]
+use crate::sandbox::communication::channel::{config::Service, protocol::message::Signal, signals};
+#[cfg(all(feature = "communication", unix))]
+use crate::sandbox::communication::services::{HostServices, HostServicesSocket};
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+use crate::sandbox::hermetic::{Audit, Violation, STRACE};
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+use crate::sandbox::isolation::{Isolation, BWRAP};
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+use crate::sandbox::unprivileged;
+
+/// Services provided by running modules. Modules get their arguments as JSON on stdin and
+/// input and device modules reply with JSON on stdout:
+///
+/// - inputs are run as `<module> map` with `{"origin", "references", "target", "options"}`,
+///   where each reference is `{"id", "path", "options"}`, and reply `{"data"}`; they make the
+///   content at the paths available read-only in the target. They are run as
+///   `<module> unmap` with `{"target"}`,
+/// - devices are run as `<module> open` with `{"options", "parent"}` and reply `{"path"}`, and
+///   as `<module> close` with `{"path"}`,
+/// - mounts are run as `<module> mount` with `{"source", "target", "options"}` and as
+///   `<module> umount` with `{"target"}`,
+/// - stages are run with their `StageArguments`, by the runner of their pipeline when it has
+///   one.
+///
+/// A module fails when it exits with anything but 0, writes more to stdout than its output
+/// limits allow, or runs longer than its timeout. Stages that need capabilities the
+/// policy forbids are not run. Stages run with only the variables of their environment, if
+/// one is set; the environment of their pipeline or else the one of all stages.
+///
+/// When the build is cancelled a running stage is sent a cancel signal on its control socket
+/// and killed when it doesn't exit within the grace period, stages that would run after it
+/// aren't started.
+///
+/// In hermetic builds stages are run without the network and audited, see
+/// `sandbox::hermetic`, and fail when they try to use the network or open paths other than
+/// their tree, inputs, devices, and mounts.
+///
+/// With cgroups set the stages of each pipeline run in a cgroup of its own, which is what
+/// `end_pipeline` accounts.
+///
+/// Stages are served the clock and entropy of the host, see `communication::services`, on
+/// the socket of `Service::Host` when one is set.
+///
+/// Isolated stages are run with bwrap, see `sandbox::isolation`, and are granted only the
+/// capabilities they declare.
+pub struct ModuleServices<'r> {
+    registry: &'r Registry,
+    policy: Policy,
+    environment: Option<Environment>,
+
+    /// The environments of the stages of single pipelines by pipeline name, these take
+    /// precedence over `environment`.
+    pipeline_environments: BTreeMap<String, Environment>,
+
+    /// The runners the stages of single pipelines are run with by pipeline name.
+    runners: BTreeMap<String, String>,
+
+    /// The pipeline whose stages are run.
+    pipeline: Option<String>,
+
+    cancellation: Option<StageCancellation>,
+
+    /// The cgroup the cgroups of pipelines are created in, and the one of the pipeline whose
+    /// stages are run.
+    #[cfg(target_os = "linux")]
+    cgroups: Option<PathBuf>,
+
+    #[cfg(target_os = "linux")]
+    cgroup: Option<PathBuf>,
+
+    /// The socket stages are served `HostServices` on.
+    #[cfg(all(feature = "communication", unix))]
+    host_services: Option<PathBuf>,
+
+    /// The directory audits of stages are written to, in hermetic builds.
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    audits: Option<PathBuf>,
+
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    violations: Vec<Violation>,
+
+    /// Whether stages are run with bwrap.
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    isolated: bool,
+}
+
+/// How stages are stopped when the build is cancelled.
+struct StageCancellation {
+    token: CancellationToken,
+
+    /// The control socket of the running stage, the stage binds it.
+    control: PathBuf,
+    grace: Duration,
+}
+
+#[derive(Deserialize)]
+struct OpenReply {
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct MapReply {
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+impl<'r> ModuleServices<'r> {
+    pub fn new(registry: &'r Registry) -> Self {
+        Self {
+            registry,
+            policy: Policy::default(),
+            environment: None,
+            pipeline_environments: BTreeMap::new(),
+            runners: BTreeMap::new(),
+            pipeline: None,
+            cancellation: None,
+            #[cfg(target_os = "linux")]
+            cgroups: None,
+            #[cfg(target_os = "linux")]
+            cgroup: None,
+            #[cfg(all(feature = "communication", unix))]
+            host_services: None,
+            #[cfg(all(feature = "sandbox", target_os = "linux"))]
+            audits: None,
+            #[cfg(all(feature = "sandbox", target_os = "linux"))]
+            violations: vec![],
+            #[cfg(all(feature = "sandbox", target_os = "linux"))]
+            isolated: false,
+        }
+    }
+
+    /// Run stages isolated from the host with bwrap, granting each only the capabilities
+    /// its schema declares. Needs bwrap.
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    pub fn set_isolated(&mut self, isolated: bool) {
+        self.isolated = isolated;
+    }
+
+    /// Run stages without the network, audit them and fail those that aren't hermetic,
+    /// writing the audits to `audits`. Needs strace and unshare.
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    pub fn set_hermetic(&mut self, audits: &Path) {
+        self.audits = Some(audits.to_path_buf());
+    }
+
+    /// The violations of the stages that were audited.
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Run stages with `environment` and nothing else.
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = Some(environment);
+    }
+
+    /// Run the stages of the pipeline `name` with `environment` and nothing else.
+    pub fn set_pipeline_environment(&mut self, name: &str, environment: Environment) {
+        self.pipeline_environments
+            .insert(name.to_string(), environment);
+    }
+
+    /// Run the stages of the pipeline `name` with the runner module `runner`, as
+    /// `<runner> <stage>`.
+    pub fn set_runner(&mut self, name: &str, runner: &str) {
+        self.runners.insert(name.to_string(), runner.to_string());
+    }
+
+    /// The runner of the current pipeline as a wrapper of its stages, if it has one.
+    fn runner(&self) -> Result<Option<Vec<String>>, ExecutorError> {
+        let Some(name) = self
+            .pipeline
+            .as_ref()
+            .and_then(|pipeline| self.runners.get(pipeline))
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(vec![self
+            .module(Kind::Runner, name)?
+            .path()
+            .to_string()]))
+    }
+
+    /// The environment the stages of the current pipeline run with, if any.
+    fn stage_environment(&self) -> Option<&Environment> {
+        self.pipeline
+            .as_ref()
+            .and_then(|name| self.pipeline_environments.get(name))
+            .or(self.environment.as_ref())
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// Stop stages when `token` is cancelled. Stages get `control` as their control socket,
+    /// such as the `control` socket of the workspace of the build, and are killed when they
+    /// don't exit within `grace` of being asked to stop.
+    pub fn set_cancellation(&mut self, token: CancellationToken, control: &Path, grace: Duration) {
+        self.cancellation = Some(StageCancellation {
+            token,
+            control: control.to_path_buf(),
+            grace,
+        });
+    }
+
+    /// Run the stages of every pipeline in a cgroup of its own in the cgroup v2 `parent`,
+    /// which is created when it doesn't exist, to account what they use. Pipelines whose
+    /// cgroup can't be created, such as without delegation, aren't accounted.
+    #[cfg(target_os = "linux")]
+    pub fn set_cgroups(&mut self, parent: &Path) {
+        self.cgroups = Some(parent.to_path_buf());
+    }
+
+    /// Serve every stage its own `HostServices` on `socket`, such as the `host` socket of the
+    /// workspace of the build, while it runs.
+    #[cfg(all(feature = "communication", unix))]
+    pub fn set_host_services(&mut self, socket: &Path) {
+        self.host_services = Some(socket.to_path_buf());
+    }
+
+    /// Check `module` against the policy, there is nothing to check when it allows everything.
+    fn check_capabilities(&self, module: &Module) -> Result<(), ExecutorError> {
+        if self.policy.allows_all() {
+            return Ok(());
+        }
+
+        let forbidden = self.policy.forbidden(&module.capabilities().map_err(|err| {
+            ExecutorError::ModuleFailed(module.name().to_string(), None, format!("{:?}", err))
+        })?);
+
+        if forbidden.is_empty() {
+            Ok(())
+        } else {
+            Err(ExecutorError::CapabilityDenied(
+                module.name().to_string(),
+                forbidden,
+            ))
+        }
+    }
+
+    fn module(&self, kind: Kind, name: &str) -> Result<&'r Module, ExecutorError> {
+        self.registry
+            .by_name(name)
+            .filter(|module| module.kind() == kind)
+            .ok_or_else(|| ExecutorError::MissingModule(name.to_string()))
+    }
+
+    /// The process of `module` with `command`, if any, run by the programs in `wrappers` in
+    /// order, each with its arguments, such as bwrap or strace. With an `environment` the
+    /// module gets that instead of the environment of this process.
+    fn process(
+        module: &Module,
+        command: Option<&str>,
+        environment: Option<&Environment>,
+        wrappers: &[Vec<String>],
+    ) -> Command {
+        let mut argv: Vec<OsString> = wrappers.iter().flatten().map(OsString::from).collect();
+
+        argv.push(OsString::from(module.path()));
+
+        let mut process = Command::new(&argv[0]);
+
+        process.args(&argv[1..]).args(command);
+
+        if let Some(environment) = environment {
+            process.env_clear().envs(environment);
+        }
+
+        process
+    }
+
+    /// Run `module` with `command`, if any, and `input` on stdin, returns its stdout.
+    fn call<T: Serialize>(
+        &self,
+        module: &Module,
+        command: Option<&str>,
+        input: &T,
+        environment: Option<&Environment>,
+    ) -> Result<Vec<u8>, ExecutorError> {
+        self.run(
+            module,
+            &mut Self::process(module, command, environment, &[]),
+            input,
+            None,
+        )
+    }
+
+    /// Run `process` of `module` with `input` on stdin, stopping it when `cancellation` is
+    /// cancelled. Returns its stdout.
+    fn run<T: Serialize>(
+        &self,
+        module: &Module,
+        process: &mut Command,
+        input: &T,
+        cancellation: Option<&mut Cancellation>,
+    ) -> Result<Vec<u8>, ExecutorError> {
+        let limits = module.output_limits();
+        let output = output::run_cancellable(
+            process,
+            &serde_json::to_vec(input).map_err(std::io::Error::from)?,
+            limits,
+            module.timeout(),
+            cancellation,
+        )?;
+
+        if output.timed_out {
+            return Err(ExecutorError::Timeout(
+                module.name().to_string(),
+                module.timeout().unwrap_or_default(),
+            ));
+        }
+
+        if output.cancelled && !output.status.success() {
+            return Err(ExecutorError::Cancelled(module.name().to_string()));
+        }
+
+        if !output.status.success() {
+            return Err(ExecutorError::ModuleFailed(
+                module.name().to_string(),
+                output.status.code(),
+                output.stderr.to_string_lossy(),
+            ));
+        }
+
+        if output.stdout.is_truncated() {
+            return Err(ExecutorError::OutputTooLarge(
+                module.name().to_string(),
+                limits.stdout,
+            ));
+        }
+
+        Ok(output.stdout.data)
+    }
+
+    /// Run the `process` of the stage `module`, stopping it when the build is cancelled.
+    fn run_stage_process(
+        &self,
+        module: &Module,
+        process: &mut Command,
+        arguments: &StageArguments,
+    ) -> Result<(), ExecutorError> {
+        // the services stop when the stage is done
+        #[cfg(all(feature = "communication", unix))]
+        let _services = match &self.host_services {
+            Some(socket) => {
+                process.env(Service::Host.variable(), socket);
+
+                Some(HostServicesSocket::bind(socket, HostServices::default())?)
+            }
+            None => None,
+        };
+
+        #[cfg(target_os = "linux")]
+        if let Some(cgroup) = &self.cgroup {
+            use std::os::fd::{AsRawFd, OwnedFd};
+            use std::os::unix::process::CommandExt;
+
+            let procs = OwnedFd::from(
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(cgroup.join("cgroup.procs"))?,
+            );
+
+            // SAFETY: write(2) is async-signal-safe and the descriptor stays open until the
+            // process has been spawned; "0" is the process that writes it
+            unsafe {
+                process.pre_exec(move || {
+                    if libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+
+                    Ok(())
+                });
+            }
+        }
+
+        let Some(stage) = &self.cancellation else {
+            return self.run(module, process, arguments, None).map(|_| ());
+        };
+
+        if stage.token.is_cancelled() {
+            return Err(ExecutorError::Cancelled(module.name().to_string()));
+        }
+
+        // a stage that was killed can leave its socket behind
+        match fs::remove_file(&stage.control) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+
+        #[cfg(feature = "communication")]
+        process.env(Service::Control.variable(), &stage.control);
+
+        let token = stage.token.clone();
+        let mut cancellation = Cancellation {
+            token: stage.token.clone(),
+            grace: stage.grace,
+            // without a channel to the stage it can only be killed once its grace is over
+            notify: Box::new(move || {
+                #[cfg(feature = "communication")]
+                let _ = signals::notify(
+                    &stage.control,
+                    &Signal::cancel(&token.reason().unwrap_or_default()),
+                );
+                #[cfg(not(feature = "communication"))]
+                let _ = &token;
+            }),
+        };
+
+        self.run(module, process, arguments, Some(&mut cancellation))
+            .map(|_| ())
+    }
+}
+
+/// The name of the cgroup of the pipeline `name`, which can be anything.
+#[cfg(target_os = "linux")]
+fn cgroup_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    format!("pipeline-{}", name)
+}
+
+/// The cgroups of pipelines are gone by now, unless stages left processes behind.
+#[cfg(target_os = "linux")]
+impl Drop for ModuleServices<'_> {
+    fn drop(&mut self) {
+        if let Some(parent) = &self.cgroups {
+            let _ = fs::remove_dir(parent);
+        }
+    }
+}
+
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+impl ModuleServices<'_> {
+    /// bwrap with its arguments to run the stage `module` isolated, granted the capabilities
+    /// it declares and with the environment of its pipeline. The policy is checked before.
+    fn isolation(&self, module: &Module) -> Result<Vec<String>, ExecutorError> {
+        let capabilities = module.capabilities().map_err(|err| {
+            ExecutorError::ModuleFailed(module.name().to_string(), None, format!("{:?}", err))
+        })?;
+
+        let mut isolation = Isolation::granting(&capabilities);
+
+        isolation.set_unprivileged(unprivileged::in_user_namespace());
+
+        if let Some(environment) = self.stage_environment() {
+            isolation.set_environment(environment.clone());
+        }
+
+        Ok([
+            vec![BWRAP.to_string()],
+            isolation.args(Path::new("/")),
+            vec!["--".to_string()],
+        ]
+        .concat())
+    }
+
+    /// Check the audit of a stage at `log` against what it declares in its `arguments`, and
+    /// remove it. Violations come before whether the stage failed, a stage that can't reach
+    /// the network usually fails because of it.
+    fn audit(
+        &mut self,
+        module: &Module,
+        arguments: &StageArguments,
+        log: &Path,
+    ) -> Result<(), ExecutorError> {
+        // without an audit the stage didn't start, which is the error
+        let data = match fs::read_to_string(log) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        fs::remove_file(log)?;
+
+        let declared = [
+            arguments.tree.clone(),
+            arguments.paths.devices.clone(),
+            arguments.paths.inputs.clone(),
+            arguments.paths.mounts.clone(),
+            PathBuf::from(module.path()),
+        ]
+        .into_iter()
+        .chain(arguments.inputs.values().map(|input| input.path.clone()))
+        .chain(arguments.devices.values().map(|device| device.path.clone()))
+        .chain(arguments.mounts.values().map(|mount| mount.path.clone()));
+
+        let accesses = Audit::new(declared).check(&data);
+
+        if accesses.is_empty() {
+            return Ok(());
+        }
+
+        self.violations
+            .extend(accesses.iter().map(|access| Violation {
+                stage: module.name().to_string(),
+                access: access.clone(),
+            }));
+
+        Err(ExecutorError::Unhermetic(
+            module.name().to_string(),
+            accesses,
+        ))
+    }
+}
+
+impl Services for ModuleServices<'_> {
+    fn map_input(
+        &mut self,
+        kind: &str,
+        origin: Origin,
+        references: &[ResolvedReference],
+        target: &Path,
+        options: Option<&serde_json::Value>,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        let module = self.module(Kind::Input, kind)?;
+        let output = self.call(
+            module,
+            Some("map"),
+            &serde_json::json!({
+                "origin": origin,
+                "references": references,
+                "target": target,
+                "options": options,
+            }),
+            None,
+        )?;
+
+        serde_json::from_slice::<MapReply>(&output)
+            .map(|reply| reply.data)
+            .map_err(|err| ExecutorError::InvalidReply(kind.to_string(), err))
+    }
+
+    fn unmap_input(&mut self, kind: &str, target: &Path) -> Result<(), ExecutorError> {
+        let module = self.module(Kind::Input, kind)?;
+
+        self.call(
+            module,
+            Some("unmap"),
+            &serde_json::json!({ "target": target }),
+            None,
+        )
+        .map(|_| ())
+    }
+
+    fn open_device(
+        &mut self,
+        kind: &str,
+        options: Option<&serde_json::Value>,
+        parent: Option<&Path>,
+    ) -> Result<PathBuf, ExecutorError> {
+        let module = self.module(Kind::Device, kind)?;
+        let output = self.call(
+            module,
+            Some("open"),
+            &serde_json::json!({"options": options, "parent": parent}),
+            None,
+        )?;
+
+        serde_json::from_slice::<OpenReply>(&output)
+            .map(|reply| reply.path)
+            .map_err(|err| ExecutorError::InvalidReply(kind.to_string(), err))
+    }
+
+    fn close_device(&mut self, kind: &str, path: &Path) -> Result<(), ExecutorError> {
+        let module = self.module(Kind::Device, kind)?;
+
+        self.call(
+            module,
+            Some("close"),
+            &serde_json::json!({ "path": path }),
+            None,
+        )
+        .map(|_| ())
+    }
+
+    fn mount(
+        &mut self,
+        kind: &str,
+        source: Option<&Path>,
+        target: &Path,
+        options: Option<&serde_json::Value>,
+    ) -> Result<(), ExecutorError> {
+        let module = self.module(Kind::Mount, kind)?;
+
+        self.call(
+            module,
+            Some("mount"),
+            &serde_json::json!({"source": source, "target": target, "options": options}),
+            None,
+        )
+        .map(|_| ())
+    }
+
+    fn umount(&mut self, kind: &str, target: &Path) -> Result<(), ExecutorError> {
+        let module = self.module(Kind::Mount, kind)?;
+
+        self.call(
+            module,
+            Some("umount"),
+            &serde_json::json!({ "target": target }),
+            None,
+        )
+        .map(|_| ())
+    }
+
+    fn run_stage(&mut self, kind: &str, arguments: &StageArguments) -> Result<(), ExecutorError> {
+        let module = self.module(Kind::Stage, kind)?;
+
+        self.check_capabilities(module)?;
+
+        let runner = self.runner()?;
+
+        #[cfg(all(feature = "sandbox", target_os = "linux"))]
+        {
+            let mut wrappers = vec![];
+
+            if self.isolated {
+                wrappers.push(self.isolation(module)?);
+            }
+
+            if let Some(audits) = &self.audits {
+                fs::create_dir_all(audits)?;
+
+                let log = audits.join(kind);
+
+                // the network is unshared outside of bwrap, which drops what that takes
+                wrappers.insert(0, Audit::unshare());
+                wrappers.push([vec![STRACE.to_string()], Audit::args(&log)].concat());
+                wrappers.extend(runner);
+
+                let mut process = Self::process(module, None, self.stage_environment(), &wrappers);
+                let result = self.run_stage_process(module, &mut process, arguments);
+
+                return self.audit(module, arguments, &log).and(result);
+            }
+
+            wrappers.extend(runner);
+
+            let mut process = Self::process(module, None, self.stage_environment(), &wrappers);
+
+            self.run_stage_process(module, &mut process, arguments)
+        }
+
+        #[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+        {
+            let wrappers: Vec<Vec<String>> = runner.into_iter().collect();
+            let mut process = Self::process(module, None, self.stage_environment(), &wrappers);
+
+            self.run_stage_process(module, &mut process, arguments)
+        }
+    }
+
+    fn begin_pipeline(&mut self, name: &str) -> Result<(), ExecutorError> {
+        self.pipeline = Some(name.to_string());
+
+        #[cfg(target_os = "linux")]
+        if let Some(parent) = &self.cgroups {
+            let cgroup = parent.join(cgroup_name(name));
+
+            self.cgroup = match fs::create_dir_all(&cgroup) {
+                Ok(()) => Some(cgroup),
+                Err(err) => {
+                    log::warn!("not accounting pipeline '{}': {}", name, err);
+                    None
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    /// What the stages of the pipeline used as its cgroup accounts it, the cgroup is removed
+    /// after.
+    fn end_pipeline(&mut self, _name: &str) -> Result<ResourceUsage, ExecutorError> {
+        #[cfg(target_os = "linux")]
+        if let Some(cgroup) = self.cgroup.take() {
+            let usage = ResourceUsage::from_cgroup(&cgroup)?;
+
+            // stages that left processes behind keep it around
+            let _ = fs::remove_dir(&cgroup);
+
+            return Ok(usage);
+        }
+
+        Ok(ResourceUsage::default())
+    }
+
+    /// The options with the defaults the schema of the stage module declares filled in, the
+    /// module is asked for its schema once and it is cached after that.
+    fn effective_options(
+        &mut self,
+        kind: &str,
+        mut options: serde_json::Value,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        let module = self.module(Kind::Stage, kind)?;
+
+        // modules that don't describe their options, like the stages of osbuild-mod, have no
+        // defaults; whether options fit is up to validation
+        if let Ok(schema) = module.get_schema_json() {
+            defaults::apply(&schema, &mut options);
+        }
+
+        Ok(options)
+    }
+}
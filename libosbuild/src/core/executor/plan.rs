@@ -0,0 +1,500 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::core::executor::inputs::{Content, PIPELINE_PREFIX};
+use crate::core::executor::ExecutorError;
+use crate::core::id::{stage_id, HashAlgo, ObjectId};
+use crate::manifest::{Manifest, Origin, Pipeline, Stage};
+
+/// A stage as it will run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedStage {
+    pub pipeline: String,
+    pub index: usize,
+
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// The id of the tree the stage produces.
+    pub id: String,
+
+    /// The pipeline whose tree is the build root the stage runs in, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runner: Option<String>,
+
+    /// The ids of what each input refers to; trees of pipelines and items of sources.
+    pub inputs: BTreeMap<String, Vec<String>>,
+
+    /// The type of each device.
+    pub devices: BTreeMap<String, String>,
+
+    /// The type of each mount.
+    pub mounts: BTreeMap<String, String>,
+
+    /// Whether the tree the stage produces is already there, the stage doesn't have to run.
+    pub cached: bool,
+}
+
+/// What a build runs, in the order it runs it. Plans of the same manifest and content are
+/// the same, so plans of different revisions of a manifest can be diffed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Plan {
+    pub stages: Vec<PlannedStage>,
+}
+
+impl Plan {
+    /// The stages that have to run, those that aren't cached.
+    pub fn pending(&self) -> impl Iterator<Item = &PlannedStage> {
+        self.stages.iter().filter(|stage| !stage.cached)
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for stage in &self.stages {
+            write!(
+                f,
+                "{}[{}] {} {}",
+                stage.pipeline, stage.index, stage.kind, stage.id
+            )?;
+
+            if stage.cached {
+                write!(f, " (cached)")?;
+            }
+
+            writeln!(f)?;
+
+            if let Some(build) = &stage.build {
+                writeln!(f, "  build: {}", build)?;
+            }
+
+            if let Some(runner) = &stage.runner {
+                writeln!(f, "  runner: {}", runner)?;
+            }
+
+            for (name, ids) in &stage.inputs {
+                writeln!(f, "  input {}: {}", name, ids.join(", "))?;
+            }
+
+            for (name, kind) in &stage.devices {
+                writeln!(f, "  device {}: {}", name, kind)?;
+            }
+
+            for (name, kind) in &stage.mounts {
+                writeln!(f, "  mount {}: {}", name, kind)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The pipelines of `manifest` in an order where every pipeline comes after the pipelines it
+/// builds in or takes input from, and otherwise in manifest order.
+fn ordered(manifest: &Manifest) -> Result<Vec<&Pipeline>, ExecutorError> {
+    let dependencies = |pipeline: &Pipeline| -> Vec<String> {
+        let mut names: Vec<String> = pipeline
+            .build()
+            .and_then(|build| build.strip_prefix(PIPELINE_PREFIX))
+            .map(str::to_string)
+            .into_iter()
+            .collect();
+
+        for stage in pipeline.stages() {
+            for input in stage.inputs().values() {
+                if input.origin() != Origin::Pipeline {
+                    continue;
+                }
+
+                for (reference, _) in input.references().entries() {
+                    if let Some(name) = reference.strip_prefix(PIPELINE_PREFIX) {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+        }
+
+        names
+    };
+
+    let mut remaining: Vec<&Pipeline> = manifest.pipelines().iter().collect();
+    let mut ordered: Vec<&Pipeline> = vec![];
+
+    while !remaining.is_empty() {
+        let ready = remaining.iter().position(|pipeline| {
+            dependencies(pipeline).iter().all(|name| {
+                ordered.iter().any(|done| done.name() == name) || manifest.pipeline(name).is_none()
+            })
+        });
+
+        match ready {
+            Some(index) => ordered.push(remaining.remove(index)),
+            None => {
+                return Err(ExecutorError::InvalidStage(format!(
+                    "pipelines depend on each other: {}",
+                    remaining
+                        .iter()
+                        .map(|pipeline| pipeline.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )))
+            }
+        }
+    }
+
+    Ok(ordered)
+}
+
+/// What the tree of `stage` of `pipeline` depends on besides its base, build root and
+/// options, see `stage_id`; its inputs with what they refer to as `resolved`, its devices and
+/// mounts, and the source epoch of its pipeline. Identical stages of pipelines with different
+/// source epochs build different trees.
+fn dependencies(
+    pipeline: &Pipeline,
+    stage: &Stage,
+    resolved: &BTreeMap<String, Vec<String>>,
+) -> serde_json::Value {
+    let inputs: serde_json::Map<String, serde_json::Value> = stage
+        .inputs()
+        .iter()
+        .map(|(name, input)| {
+            (
+                name.clone(),
+                serde_json::json!({
+                    "type": input.kind(),
+                    "origin": input.origin(),
+                    "references": resolved.get(name),
+                    "options": input.options(),
+                }),
+            )
+        })
+        .collect();
+
+    let devices: serde_json::Map<String, serde_json::Value> = stage
+        .devices()
+        .iter()
+        .map(|(name, device)| {
+            (
+                name.clone(),
+                serde_json::json!({
+                    "type": device.kind(),
+                    "parent": device.parent(),
+                    "options": device.options(),
+                }),
+            )
+        })
+        .collect();
+
+    let mounts: Vec<serde_json::Value> = stage
+        .mounts()
+        .iter()
+        .map(|mount| {
+            serde_json::json!({
+                "name": mount.name(),
+                "type": mount.kind(),
+                "source": mount.source(),
+                "target": mount.target(),
+                "options": mount.options(),
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "inputs": inputs,
+        "devices": devices,
+        "mounts": mounts,
+        "source-epoch": pipeline.source_epoch(),
+    })
+}
+
+/// Plan the build of `manifest` with ids computed with `algo`. Stages whose tree `content`
+/// already has are cached. References to pipelines that aren't in the manifest have to be in
+/// `content`.
+pub fn plan(manifest: &Manifest, content: &Content, algo: HashAlgo) -> Result<Plan, ExecutorError> {
+    let mut ids: BTreeMap<&str, ObjectId> = BTreeMap::new();
+    let mut plan = Plan::default();
+
+    // the id of the tree a reference to a pipeline refers to
+    let pipeline_id = |ids: &BTreeMap<&str, ObjectId>, reference: &str| -> Option<String> {
+        match reference.strip_prefix(PIPELINE_PREFIX) {
+            Some(name) => ids
+                .get(name)
+                .map(ObjectId::to_string)
+                .or_else(|| content.tree(reference).map(|(id, _)| id.to_string())),
+            None => Some(reference.to_string()),
+        }
+    };
+
+    for pipeline in ordered(manifest)? {
+        let build = match pipeline.build() {
+            Some(build) => Some(
+                pipeline_id(&ids, build)
+                    .ok_or_else(|| {
+                        ExecutorError::InvalidStage(format!(
+                            "pipeline '{}' builds in unknown pipeline '{}'",
+                            pipeline.name(),
+                            build
+                        ))
+                    })?
+                    .parse::<ObjectId>()
+                    .map_err(|err| ExecutorError::InvalidStage(format!("{:?}", err)))?,
+            ),
+            None => None,
+        };
+
+        let mut base: Option<ObjectId> = None;
+
+        for (index, stage) in pipeline.stages().iter().enumerate() {
+            let mut inputs = BTreeMap::new();
+
+            for (name, input) in stage.inputs() {
+                let mut references = vec![];
+
+                for (reference, _) in input.references().entries() {
+                    references.push(match input.origin() {
+                        Origin::Pipeline => pipeline_id(&ids, reference).ok_or_else(|| {
+                            ExecutorError::MissingInput(name.clone(), reference.to_string())
+                        })?,
+                        Origin::Source => reference.to_string(),
+                    });
+                }
+
+                inputs.insert(name.clone(), references);
+            }
+
+            let id = stage_id(
+                algo,
+                stage.kind(),
+                base.as_ref(),
+                build.as_ref(),
+                stage.options().unwrap_or(&serde_json::json!({})),
+                &dependencies(pipeline, stage, &inputs),
+            );
+
+            plan.stages.push(PlannedStage {
+                pipeline: pipeline.name().to_string(),
+                index,
+                kind: stage.kind().to_string(),
+                id: id.to_string(),
+                build: pipeline.build().map(str::to_string),
+                runner: pipeline.runner().map(str::to_string),
+                inputs,
+                devices: stage
+                    .devices()
+                    .iter()
+                    .map(|(name, device)| (name.clone(), device.kind().to_string()))
+                    .collect(),
+                mounts: stage
+                    .mounts()
+                    .iter()
+                    .map(|mount| (mount.name().to_string(), mount.kind().to_string()))
+                    .collect(),
+                cached: content.tree(&id.to_string()).is_some(),
+            });
+
+            base = Some(id);
+        }
+
+        if let Some(id) = base {
+            ids.insert(pipeline.name(), id);
+        }
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+    use crate::manifest::{Device, Input, Mount, References};
+
+    fn manifest() -> Manifest {
+        let mut build = Pipeline::new("build");
+        build.add_stage(Stage::new("org.osbuild.rpm"));
+
+        let mut image = Pipeline::new("image");
+        image.set_build(Some("name:build"));
+
+        let mut copy = Stage::new("org.osbuild.copy");
+        copy.add_input(
+            "tree",
+            Input::new(
+                "org.osbuild.tree",
+                Origin::Pipeline,
+                References::Ids(vec!["name:os".to_string()]),
+            ),
+        );
+        copy.add_device("disk", Device::new("org.osbuild.loopback"));
+        copy.add_mount(Mount::new("root", "org.osbuild.ext4"));
+        image.add_stage(copy);
+
+        let mut os = Pipeline::new("os");
+        os.set_build(Some("name:build"));
+        os.set_runner(Some("org.osbuild.linux"));
+
+        let mut rpm = Stage::new("org.osbuild.rpm");
+        rpm.set_options(Some(serde_json::json!({"packages": ["bash"]})));
+        rpm.add_input(
+            "packages",
+            Input::new(
+                "org.osbuild.files",
+                Origin::Source,
+                References::Ids(vec!["sha256:aa".to_string()]),
+            ),
+        );
+        os.add_stage(rpm);
+        os.add_stage(Stage::new("org.osbuild.noop"));
+
+        let mut manifest = Manifest::new();
+        manifest.add_pipeline(build);
+        // image takes os as input before it is declared
+        manifest.add_pipeline(image);
+        manifest.add_pipeline(os);
+        manifest
+    }
+
+    #[test]
+    fn planned_in_order() {
+        let plan = plan(&manifest(), &Content::new(), HashAlgo::Sha256).unwrap();
+        let stages: Vec<(&str, usize)> = plan
+            .stages
+            .iter()
+            .map(|stage| (stage.pipeline.as_str(), stage.index))
+            .collect();
+
+        assert_eq!(
+            stages,
+            vec![("build", 0), ("os", 0), ("os", 1), ("image", 0)]
+        );
+
+        let copy = &plan.stages[3];
+
+        assert_eq!(copy.inputs["tree"], vec![plan.stages[2].id.clone()]);
+        assert_eq!(copy.devices["disk"], "org.osbuild.loopback");
+        assert_eq!(copy.mounts["root"], "org.osbuild.ext4");
+        assert_eq!(plan.stages[1].inputs["packages"], vec!["sha256:aa"]);
+        assert_eq!(plan.pending().count(), 4);
+
+        let text = plan.to_string();
+
+        assert!(text.starts_with(&format!("build[0] org.osbuild.rpm {}\n", plan.stages[0].id)));
+        assert!(text.contains("  build: name:build\n  runner: org.osbuild.linux\n"));
+        assert!(
+            text.contains("  device disk: org.osbuild.loopback\n  mount root: org.osbuild.ext4\n")
+        );
+    }
+
+    #[test]
+    fn planned_with_cache() {
+        let first = plan(&manifest(), &Content::new(), HashAlgo::Sha256).unwrap();
+
+        let mut content = Content::new();
+        content.add_pipeline("build", &first.stages[0].id, Path::new("/store/build"));
+
+        let cached = plan(&manifest(), &content, HashAlgo::Sha256).unwrap();
+
+        assert!(cached.stages[0].cached);
+        assert_eq!(cached.pending().count(), 3);
+        assert!(cached.to_string().contains(" (cached)\n"));
+
+        // changing the build root changes the ids of everything built in it
+        let mut changed = Manifest::new();
+        let mut build = Pipeline::new("build");
+        let mut rpm = Stage::new("org.osbuild.rpm");
+        rpm.set_options(Some(serde_json::json!({"packages": ["dnf"]})));
+        build.add_stage(rpm);
+        changed.add_pipeline(build);
+
+        for pipeline in &manifest().pipelines()[1..] {
+            changed.add_pipeline(pipeline.clone());
+        }
+
+        let changed = plan(&changed, &content, HashAlgo::Sha256).unwrap();
+
+        assert_eq!(changed.pending().count(), 4);
+        assert!(changed
+            .stages
+            .iter()
+            .zip(&first.stages)
+            .all(|(changed, first)| changed.id != first.id));
+    }
+
+    #[test]
+    fn planned_with_dependencies() {
+        let packages = |reference: &str| {
+            let mut manifest = Manifest::new();
+            let mut os = Pipeline::new("os");
+            let mut rpm = Stage::new("org.osbuild.rpm");
+
+            rpm.add_input(
+                "packages",
+                Input::new(
+                    "org.osbuild.files",
+                    Origin::Source,
+                    References::Ids(vec![reference.to_string()]),
+                ),
+            );
+            os.add_stage(rpm);
+            manifest.add_pipeline(os);
+            manifest
+        };
+
+        let first = plan(&packages("sha256:aa"), &Content::new(), HashAlgo::Sha256).unwrap();
+        let second = plan(&packages("sha256:bb"), &Content::new(), HashAlgo::Sha256).unwrap();
+
+        // stages that differ only in their inputs build different trees
+        assert_ne!(first.stages[0].id, second.stages[0].id);
+
+        // as do identical pipelines with different source epochs
+        let mut manifest = packages("sha256:aa");
+        let mut epoch = Pipeline::new("epoch");
+
+        epoch.add_stage(manifest.pipelines()[0].stages()[0].clone());
+        epoch.set_source_epoch(Some(1_700_000_000));
+        manifest.add_pipeline(epoch);
+
+        let epochs = plan(&manifest, &Content::new(), HashAlgo::Sha256).unwrap();
+
+        assert_eq!(epochs.stages[0].id, first.stages[0].id);
+        assert_ne!(epochs.stages[0].id, epochs.stages[1].id);
+    }
+
+    #[test]
+    fn planned_references() {
+        let mut manifest = manifest();
+        let mut other = Pipeline::new("other");
+        other.set_build(Some("name:elsewhere"));
+        other.add_stage(Stage::new("org.osbuild.noop"));
+        manifest.add_pipeline(other);
+
+        assert!(matches!(
+            plan(&manifest, &Content::new(), HashAlgo::Sha256),
+            Err(ExecutorError::InvalidStage(_))
+        ));
+
+        let mut content = Content::new();
+        content.add_pipeline("elsewhere", "bb", Path::new("/store/bb"));
+
+        assert!(plan(&manifest, &content, HashAlgo::Sha256).is_ok());
+
+        let mut cyclic = Manifest::new();
+
+        for (name, build) in [("a", "name:b"), ("b", "name:a")] {
+            let mut pipeline = Pipeline::new(name);
+            pipeline.set_build(Some(build));
+            cyclic.add_pipeline(pipeline);
+        }
+
+        assert!(matches!(
+            plan(&cyclic, &Content::new(), HashAlgo::Sha256),
+            Err(ExecutorError::InvalidStage(message)) if message.ends_with("a, b")
+        ));
+    }
+}
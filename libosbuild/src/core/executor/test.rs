@@ -0,0 +1,1334 @@
+use std::cell::RefCell;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::core::environment::StageEnvironment;
+use crate::core::executor::hooks::{ExecutorHooks, StageAction};
+use crate::core::executor::inputs::ResolvedReference;
+use crate::core::executor::modules::ModuleServices;
+use crate::core::executor::*;
+use crate::core::paths::Workspace;
+use crate::manifest::{Device, Input, Mount, Origin, References, Stage};
+use crate::module::capability::{Capability, Policy};
+use crate::module::output::OutputLimits;
+use crate::module::{Kind, Module, Registry};
+
+/// Services that record what they are asked to do, `fail` makes the named operation fail.
+#[derive(Default)]
+struct Recorder {
+    calls: Vec<String>,
+    arguments: Option<StageArguments>,
+    fail: Option<&'static str>,
+}
+
+impl Recorder {
+    fn record(&mut self, call: String, operation: &str) -> Result<(), ExecutorError> {
+        self.calls.push(call);
+
+        if self.fail == Some(operation) {
+            Err(ExecutorError::ModuleFailed(
+                operation.to_string(),
+                Some(1),
+                String::new(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Services for Recorder {
+    fn map_input(
+        &mut self,
+        kind: &str,
+        origin: Origin,
+        references: &[ResolvedReference],
+        target: &Path,
+        _options: Option<&serde_json::Value>,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        let ids: Vec<&str> = references.iter().map(|r| r.id.as_str()).collect();
+
+        self.record(
+            format!(
+                "map {} {} {} {}",
+                kind,
+                origin,
+                ids.join(","),
+                target.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            "map",
+        )?;
+
+        Ok(serde_json::json!({ "ids": ids }))
+    }
+
+    fn unmap_input(&mut self, kind: &str, target: &Path) -> Result<(), ExecutorError> {
+        self.record(
+            format!(
+                "unmap {} {}",
+                kind,
+                target.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            "unmap",
+        )
+    }
+
+    fn open_device(
+        &mut self,
+        kind: &str,
+        _options: Option<&serde_json::Value>,
+        parent: Option<&Path>,
+    ) -> Result<PathBuf, ExecutorError> {
+        let path = match parent {
+            Some(parent) => PathBuf::from(format!("{}p1", parent.display())),
+            None => PathBuf::from("/dev/loop0"),
+        };
+
+        self.record(format!("open {} {}", kind, path.display()), "open")?;
+
+        Ok(path)
+    }
+
+    fn close_device(&mut self, kind: &str, path: &Path) -> Result<(), ExecutorError> {
+        self.record(format!("close {} {}", kind, path.display()), "close")
+    }
+
+    fn mount(
+        &mut self,
+        kind: &str,
+        source: Option<&Path>,
+        target: &Path,
+        _options: Option<&serde_json::Value>,
+    ) -> Result<(), ExecutorError> {
+        self.record(
+            format!(
+                "mount {} {} {}",
+                kind,
+                source.unwrap_or(Path::new("-")).display(),
+                target.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            "mount",
+        )
+    }
+
+    fn umount(&mut self, kind: &str, target: &Path) -> Result<(), ExecutorError> {
+        self.record(
+            format!(
+                "umount {} {}",
+                kind,
+                target.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            "umount",
+        )
+    }
+
+    fn run_stage(&mut self, kind: &str, arguments: &StageArguments) -> Result<(), ExecutorError> {
+        self.arguments = Some(arguments.clone());
+        self.record(format!("run {}", kind), "run")
+    }
+}
+
+fn stage() -> Stage {
+    let mut stage = Stage::new("org.osbuild.copy");
+
+    stage.add_input(
+        "tree",
+        Input::new(
+            "org.osbuild.tree",
+            Origin::Pipeline,
+            References::Ids(vec!["name:build".to_string()]),
+        ),
+    );
+
+    let mut partition = Device::new("org.osbuild.partition");
+    partition.set_parent(Some("disk"));
+
+    stage.add_device("root", partition);
+    stage.add_device("disk", Device::new("org.osbuild.loopback"));
+
+    let mut root = Mount::new("root", "org.osbuild.ext4");
+    root.set_source(Some("root"));
+
+    let mut boot = Mount::new("boot", "org.osbuild.tmpfs");
+    boot.set_target(Some("/boot"));
+
+    stage.add_mount(root);
+    stage.add_mount(boot);
+
+    stage
+}
+
+#[test]
+fn devices_ordered_by_parent() {
+    let stage = stage();
+
+    assert_eq!(device_order(stage.devices()).unwrap(), vec!["disk", "root"]);
+
+    let mut cyclic = Stage::new("org.osbuild.copy");
+    let mut a = Device::new("org.osbuild.partition");
+    a.set_parent(Some("b"));
+    let mut b = Device::new("org.osbuild.partition");
+    b.set_parent(Some("a"));
+    cyclic.add_device("a", a);
+    cyclic.add_device("b", b);
+
+    assert!(matches!(
+        device_order(cyclic.devices()),
+        Err(ExecutorError::InvalidStage(_))
+    ));
+
+    let mut orphan = Stage::new("org.osbuild.copy");
+    let mut device = Device::new("org.osbuild.partition");
+    device.set_parent(Some("nope"));
+    orphan.add_device("a", device);
+
+    assert!(matches!(
+        device_order(orphan.devices()),
+        Err(ExecutorError::InvalidStage(_))
+    ));
+}
+
+/// An executor that has the `build` pipeline to use as input.
+fn with_build(recorder: Recorder, runtime: &Path) -> Executor<Recorder> {
+    let mut executor = Executor::new(recorder, runtime);
+
+    executor
+        .content_mut()
+        .add_pipeline("build", "aa", Path::new("/store/objects/aa"));
+
+    executor
+}
+
+#[test]
+fn stage_with_inputs_devices_and_mounts() {
+    let workspace = Workspace::new(&std::env::temp_dir()).unwrap();
+    let runtime = workspace.runtime();
+    let mut executor = with_build(Recorder::default(), runtime);
+
+    executor.run_stage(&stage(), Path::new("/tree")).unwrap();
+
+    assert_eq!(
+        executor.services().calls,
+        vec![
+            "map org.osbuild.tree org.osbuild.pipeline aa tree",
+            "open org.osbuild.loopback /dev/loop0",
+            "open org.osbuild.partition /dev/loop0p1",
+            "mount org.osbuild.ext4 /dev/loop0p1 mounts",
+            "mount org.osbuild.tmpfs - boot",
+            "run org.osbuild.copy",
+            "umount org.osbuild.tmpfs boot",
+            "umount org.osbuild.ext4 mounts",
+            "close org.osbuild.partition /dev/loop0p1",
+            "close org.osbuild.loopback /dev/loop0",
+            "unmap org.osbuild.tree tree",
+        ]
+    );
+
+    let arguments = executor.services().arguments.clone().unwrap();
+
+    assert_eq!(arguments.tree, PathBuf::from("/tree"));
+    assert_eq!(
+        arguments.devices["root"].path,
+        PathBuf::from("/dev/loop0p1")
+    );
+    assert_eq!(arguments.mounts["root"].path, runtime.join("mounts"));
+    assert_eq!(arguments.mounts["boot"].path, runtime.join("mounts/boot"));
+    assert_eq!(arguments.inputs["tree"].path, runtime.join("inputs/tree"));
+    assert_eq!(
+        arguments.inputs["tree"].data,
+        serde_json::json!({"ids": ["aa"]})
+    );
+    assert_eq!(arguments.options, serde_json::json!({}));
+}
+
+#[test]
+fn teardown_after_failure() {
+    let workspace = Workspace::new(&std::env::temp_dir()).unwrap();
+    let runtime = workspace.runtime();
+    let mut executor = with_build(
+        Recorder {
+            fail: Some("run"),
+            ..Default::default()
+        },
+        runtime,
+    );
+
+    assert!(matches!(
+        executor.run_stage(&stage(), Path::new("/tree")),
+        Err(ExecutorError::ModuleFailed(operation, _, _)) if operation == "run"
+    ));
+    assert_eq!(executor.services().calls.len(), 11);
+
+    let mut executor = with_build(
+        Recorder {
+            fail: Some("mount"),
+            ..Default::default()
+        },
+        runtime,
+    );
+
+    assert!(executor.run_stage(&stage(), Path::new("/tree")).is_err());
+    assert_eq!(
+        executor.services().calls[3..],
+        [
+            "mount org.osbuild.ext4 /dev/loop0p1 mounts",
+            "close org.osbuild.partition /dev/loop0p1",
+            "close org.osbuild.loopback /dev/loop0",
+            "unmap org.osbuild.tree tree",
+        ]
+    );
+}
+
+/// Hooks that record what they are called for, skipping the stages of `skip`.
+struct Events {
+    events: Rc<RefCell<Vec<String>>>,
+    skip: Option<&'static str>,
+}
+
+impl ExecutorHooks for Events {
+    fn pre_stage(&mut self, stage: &Stage, tree: &Path) -> StageAction {
+        self.events
+            .borrow_mut()
+            .push(format!("pre {} {}", stage.kind(), tree.display()));
+
+        if self.skip == Some(stage.kind()) {
+            StageAction::Skip
+        } else {
+            StageAction::Run
+        }
+    }
+
+    fn post_stage(&mut self, stage: &Stage, _tree: &Path) {
+        self.events
+            .borrow_mut()
+            .push(format!("post {}", stage.kind()));
+    }
+
+    fn on_commit(&mut self, name: &str, id: &str, tree: &Path) {
+        self.events
+            .borrow_mut()
+            .push(format!("commit {} {} {}", name, id, tree.display()));
+    }
+
+    fn on_failure(&mut self, stage: &Stage, _tree: &Path, error: &ExecutorError) {
+        self.events
+            .borrow_mut()
+            .push(format!("failure {} {:?}", stage.kind(), error));
+    }
+}
+
+#[test]
+fn hooks_called_around_stages() {
+    let workspace = Workspace::new(&std::env::temp_dir()).unwrap();
+    let runtime = workspace.runtime();
+    let events = Rc::new(RefCell::new(vec![]));
+    let mut executor = Executor::new(Recorder::default(), runtime);
+
+    executor.add_hooks(Box::new(Events {
+        events: events.clone(),
+        skip: None,
+    }));
+    executor.commit("build", "aa", Path::new("/store/objects/aa"));
+    executor.run_stage(&stage(), Path::new("/tree")).unwrap();
+
+    assert_eq!(
+        *events.borrow(),
+        vec![
+            "commit build aa /store/objects/aa",
+            "pre org.osbuild.copy /tree",
+            "post org.osbuild.copy",
+        ]
+    );
+    assert_eq!(executor.content().tree("name:build").unwrap().0, "aa");
+
+    // a stage that fails is reported but doesn't count as done
+    let mut executor = with_build(
+        Recorder {
+            fail: Some("run"),
+            ..Default::default()
+        },
+        runtime,
+    );
+
+    events.borrow_mut().clear();
+    executor.add_hooks(Box::new(Events {
+        events: events.clone(),
+        skip: None,
+    }));
+
+    assert!(executor.run_stage(&stage(), Path::new("/tree")).is_err());
+    assert_eq!(events.borrow().len(), 2);
+    assert!(events.borrow()[1].starts_with("failure org.osbuild.copy ModuleFailed"));
+}
+
+#[test]
+fn hooks_skip_stages() {
+    let workspace = Workspace::new(&std::env::temp_dir()).unwrap();
+    let runtime = workspace.runtime();
+    let events = Rc::new(RefCell::new(vec![]));
+    let mut executor = with_build(Recorder::default(), runtime);
+
+    executor.add_hooks(Box::new(Events {
+        events: events.clone(),
+        skip: Some("org.osbuild.copy"),
+    }));
+    executor.add_hooks(Box::new(Events {
+        events: events.clone(),
+        skip: None,
+    }));
+    executor.run_stage(&stage(), Path::new("/tree")).unwrap();
+
+    // the second hook isn't asked and nothing is set up
+    assert!(executor.services().calls.is_empty());
+    assert_eq!(
+        *events.borrow(),
+        vec![
+            "pre org.osbuild.copy /tree",
+            "post org.osbuild.copy",
+            "post org.osbuild.copy",
+        ]
+    );
+}
+
+#[test]
+fn inputs_resolved_before_setup() {
+    let workspace = Workspace::new(&std::env::temp_dir()).unwrap();
+    let runtime = workspace.runtime();
+
+    // without the build pipeline the tree input can't be resolved, nothing is set up
+    let mut executor = Executor::new(Recorder::default(), runtime);
+
+    assert!(matches!(
+        executor.run_stage(&stage(), Path::new("/tree")),
+        Err(ExecutorError::MissingInput(name, _)) if name == "tree"
+    ));
+    assert!(executor.services().calls.is_empty());
+}
+
+#[test]
+fn mount_of_unknown_device() {
+    let workspace = Workspace::new(&std::env::temp_dir()).unwrap();
+    let runtime = workspace.runtime();
+    let mut executor = Executor::new(Recorder::default(), runtime);
+
+    let mut stage = Stage::new("org.osbuild.copy");
+    let mut mount = Mount::new("root", "org.osbuild.ext4");
+    mount.set_source(Some("nope"));
+    stage.add_mount(mount);
+
+    assert!(matches!(
+        executor.run_stage(&stage, Path::new("/tree")),
+        Err(ExecutorError::InvalidStage(_))
+    ));
+    assert!(executor.services().calls.is_empty());
+}
+
+#[test]
+fn targets_kept_below_their_root() {
+    let workspace = Workspace::new(&std::env::temp_dir()).unwrap();
+    let runtime = workspace.runtime();
+    let mut executor = Executor::new(Recorder::default(), runtime);
+
+    let mut stage = Stage::new("org.osbuild.copy");
+    let mut boot = Mount::new("boot", "org.osbuild.tmpfs");
+    boot.set_target(Some("/boot/./efi/.."));
+    stage.add_mount(boot);
+
+    executor.run_stage(&stage, Path::new("/tree")).unwrap();
+
+    assert_eq!(
+        executor.services().arguments.clone().unwrap().mounts["boot"].path,
+        runtime.join("mounts/boot")
+    );
+
+    for target in ["/../etc", "boot/../../.."] {
+        let mut executor = Executor::new(Recorder::default(), runtime);
+        let mut stage = Stage::new("org.osbuild.copy");
+        let mut mount = Mount::new("root", "org.osbuild.tmpfs");
+        mount.set_target(Some(target));
+        stage.add_mount(mount);
+
+        assert!(matches!(
+            executor.run_stage(&stage, Path::new("/tree")),
+            Err(ExecutorError::InvalidStage(_))
+        ));
+        assert!(executor.services().calls.is_empty());
+    }
+
+    for name in ["..", "/"] {
+        let mut executor = with_build(Recorder::default(), runtime);
+        let mut stage = Stage::new("org.osbuild.copy");
+        stage.add_input(
+            name,
+            Input::new(
+                "org.osbuild.tree",
+                Origin::Pipeline,
+                References::Ids(vec!["name:build".to_string()]),
+            ),
+        );
+
+        assert!(matches!(
+            executor.run_stage(&stage, Path::new("/tree")),
+            Err(ExecutorError::InvalidStage(_))
+        ));
+        assert!(executor.services().calls.is_empty());
+    }
+}
+
+fn script(directory: &Path, name: &str, body: &str) -> Module {
+    let path = directory.join(name);
+
+    // stages are asked for their schema before they run, for the defaults of their options
+    let schema = if body.contains("--schema") {
+        ""
+    } else {
+        "[ \"$1\" = --schema ] && echo '{}' && exit 0\n"
+    };
+
+    fs::write(&path, format!("#!/bin/sh\n{}{}\n", schema, body)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    Module::new(
+        match name {
+            "org.osbuild.loopback" => Kind::Device,
+            "org.osbuild.tmpfs" => Kind::Mount,
+            "org.osbuild.files" => Kind::Input,
+            _ => Kind::Stage,
+        },
+        &path.to_string_lossy(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn modules_run_as_services() {
+    let directory = tempfile::tempdir().unwrap();
+    let log = directory.path().join("log");
+    let log = log.display();
+
+    let registry = Registry::new(vec![
+        script(
+            directory.path(),
+            "org.osbuild.loopback",
+            &format!(
+                "echo \"device $1 $(cat)\" >> {}\n[ \"$1\" = open ] && echo '{{\"path\": \"/dev/loop3\"}}'\nexit 0",
+                log
+            ),
+        ),
+        script(
+            directory.path(),
+            "org.osbuild.tmpfs",
+            &format!("echo \"mount $1 $(cat)\" >> {}", log),
+        ),
+        script(
+            directory.path(),
+            "org.osbuild.copy",
+            &format!("echo \"stage $(cat)\" >> {}", log),
+        ),
+        script(
+            directory.path(),
+            "org.osbuild.files",
+            &format!(
+                "echo \"input $1 $(cat)\" >> {}\n[ \"$1\" = map ] && echo '{{\"data\": {{\"files\": 1}}}}'\nexit 0",
+                log
+            ),
+        ),
+        script(directory.path(), "org.osbuild.false", "exit 3"),
+    ]);
+
+    let sources = directory.path().join("sources/org.osbuild.curl");
+    fs::create_dir_all(&sources).unwrap();
+    fs::write(sources.join("sha256:cc"), "c").unwrap();
+
+    let mut stage = Stage::new("org.osbuild.copy");
+    stage.add_input(
+        "files",
+        Input::new(
+            "org.osbuild.files",
+            Origin::Source,
+            References::Ids(vec!["sha256:cc".to_string()]),
+        ),
+    );
+    stage.add_device("disk", Device::new("org.osbuild.loopback"));
+
+    let mut mount = Mount::new("root", "org.osbuild.tmpfs");
+    mount.set_source(Some("disk"));
+    stage.add_mount(mount);
+
+    let runtime = directory.path().join("runtime");
+    let mut executor = Executor::new(ModuleServices::new(&registry), &runtime);
+    executor
+        .content_mut()
+        .set_sources(&directory.path().join("sources"));
+
+    executor.run_stage(&stage, Path::new("/tree")).unwrap();
+
+    let log = fs::read_to_string(directory.path().join("log")).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+
+    assert_eq!(lines.len(), 7);
+
+    let map: serde_json::Value =
+        serde_json::from_str(lines[0].strip_prefix("input map ").unwrap()).unwrap();
+
+    assert_eq!(map["origin"], "org.osbuild.source");
+    assert_eq!(map["references"][0]["id"], "sha256:cc");
+    assert_eq!(
+        map["references"][0]["path"],
+        sources.join("sha256:cc").to_string_lossy().as_ref()
+    );
+    assert_eq!(lines[1], r#"device open {"options":null,"parent":null}"#);
+    assert!(lines[2].starts_with(r#"mount mount {"options":null,"source":"/dev/loop3","#));
+
+    let arguments: serde_json::Value =
+        serde_json::from_str(lines[3].strip_prefix("stage ").unwrap()).unwrap();
+
+    assert_eq!(arguments["tree"], "/tree");
+    assert_eq!(
+        arguments["inputs"]["files"]["data"],
+        serde_json::json!({"files": 1})
+    );
+    assert_eq!(arguments["devices"]["disk"]["path"], "/dev/loop3");
+    assert_eq!(
+        arguments["mounts"]["root"]["path"],
+        runtime.join("mounts").to_string_lossy().as_ref()
+    );
+    assert!(lines[4].starts_with("mount umount"));
+    assert_eq!(lines[5], r#"device close {"path":"/dev/loop3"}"#);
+    assert!(lines[6].starts_with("input unmap"));
+
+    assert!(matches!(
+        executor.run_stage(&Stage::new("org.osbuild.false"), Path::new("/tree")),
+        Err(ExecutorError::ModuleFailed(name, Some(3), _)) if name == "org.osbuild.false"
+    ));
+    assert!(matches!(
+        executor.run_stage(&Stage::new("org.osbuild.nope"), Path::new("/tree")),
+        Err(ExecutorError::MissingModule(_))
+    ));
+}
+
+#[test]
+fn stages_denied_capabilities() {
+    let directory = tempfile::tempdir().unwrap();
+    let ran = directory.path().join("ran");
+
+    let registry = Registry::new(vec![script(
+        directory.path(),
+        "org.osbuild.curl",
+        &format!(
+            "[ \"$1\" = --schema ] && echo '{{\"capabilities\": [\"network\"]}}' && exit 0\ntouch {}",
+            ran.display()
+        ),
+    )]);
+
+    let mut services = ModuleServices::new(&registry);
+    let mut policy = Policy::allow_all();
+    policy.forbid(Capability::Network);
+    services.set_policy(policy);
+
+    let mut executor = Executor::new(services, &directory.path().join("runtime"));
+
+    assert!(matches!(
+        executor.run_stage(&Stage::new("org.osbuild.curl"), Path::new("/tree")),
+        Err(ExecutorError::CapabilityDenied(name, denied))
+            if name == "org.osbuild.curl" && denied == vec![Capability::Network]
+    ));
+    assert!(!ran.exists());
+
+    let mut executor = Executor::new(
+        ModuleServices::new(&registry),
+        &directory.path().join("runtime"),
+    );
+
+    executor
+        .run_stage(&Stage::new("org.osbuild.curl"), Path::new("/tree"))
+        .unwrap();
+    assert!(ran.exists());
+}
+
+#[test]
+fn stages_run_with_their_environment() {
+    let directory = tempfile::tempdir().unwrap();
+    let dump = directory.path().join("env");
+
+    let registry = Registry::new(vec![script(
+        directory.path(),
+        "org.osbuild.env",
+        &format!("env > {}", dump.display()),
+    )]);
+
+    let mut stage = StageEnvironment::new();
+    stage.set("SOURCE_DATE_EPOCH", "1700000000");
+
+    let mut services = ModuleServices::new(&registry);
+    services.set_environment(stage.resolve(std::env::vars()));
+
+    let mut executor = Executor::new(services, &directory.path().join("runtime"));
+
+    executor
+        .run_stage(&Stage::new("org.osbuild.env"), Path::new("/tree"))
+        .unwrap();
+
+    let mut names: Vec<String> = fs::read_to_string(&dump)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.split_once('=').map(|(name, _)| name.to_string()))
+        .collect();
+
+    // shells set a few variables of their own
+    names.retain(|name| !["PWD", "SHLVL", "_", "OLDPWD"].contains(&name.as_str()));
+
+    assert_eq!(names.len(), 2, "{:?}", names);
+    assert!(names.contains(&"SOURCE_DATE_EPOCH".to_string()));
+    assert!(names.contains(&"PATH".to_string()));
+}
+
+#[test]
+fn options_defaulted() {
+    let directory = tempfile::tempdir().unwrap();
+    let arguments = directory.path().join("arguments");
+
+    let registry = Registry::new(vec![script(
+        directory.path(),
+        "org.osbuild.mkdir",
+        &format!(
+            "[ \"$1\" = --schema ] && echo '{}' && exit 0\ncat > {}",
+            serde_json::json!({
+                "properties": {
+                    "paths": {"type": "array"},
+                    "exist_ok": {"type": "boolean", "default": false}
+                }
+            }),
+            arguments.display()
+        ),
+    )]);
+
+    let mut executor = Executor::new(
+        ModuleServices::new(&registry),
+        &directory.path().join("runtime"),
+    );
+
+    let mut stage = Stage::new("org.osbuild.mkdir");
+    stage.set_options(Some(serde_json::json!({"paths": ["/etc"]})));
+
+    executor.run_stage(&stage, Path::new("/tree")).unwrap();
+
+    let written: StageArguments = serde_json::from_slice(&fs::read(&arguments).unwrap()).unwrap();
+    let expected = serde_json::json!({"paths": ["/etc"], "exist_ok": false});
+
+    assert_eq!(written.options, expected);
+    assert_eq!(executor.stage_options(), Some(&expected));
+
+    // what the manifest says is left alone
+    assert_eq!(
+        stage.options(),
+        Some(&serde_json::json!({"paths": ["/etc"]}))
+    );
+}
+
+#[test]
+fn modules_output_limited() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut registry = Registry::new(vec![
+        script(
+            directory.path(),
+            "org.osbuild.chatty",
+            "head -c 4096 /dev/zero",
+        ),
+        script(
+            directory.path(),
+            "org.osbuild.fails",
+            "echo 'something went wrong' >&2\nexit 1",
+        ),
+    ]);
+
+    registry.set_output_limits(OutputLimits {
+        stdout: 1024,
+        stderr: 9,
+    });
+
+    let mut executor = Executor::new(
+        ModuleServices::new(&registry),
+        &directory.path().join("runtime"),
+    );
+
+    assert!(matches!(
+        executor.run_stage(&Stage::new("org.osbuild.chatty"), Path::new("/tree")),
+        Err(ExecutorError::OutputTooLarge(name, 1024)) if name == "org.osbuild.chatty"
+    ));
+    assert!(matches!(
+        executor.run_stage(&Stage::new("org.osbuild.fails"), Path::new("/tree")),
+        Err(ExecutorError::ModuleFailed(_, Some(1), stderr))
+            if stderr == "something\n[output truncated, 12 of 21 bytes left out]"
+    ));
+}
+
+#[test]
+fn modules_timed_out() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut registry = Registry::new(vec![script(
+        directory.path(),
+        "org.osbuild.hangs",
+        "sleep 60",
+    )]);
+
+    registry.set_timeouts(
+        std::time::Duration::from_secs(1),
+        Some(std::time::Duration::from_millis(100)),
+    );
+
+    let mut executor = Executor::new(
+        ModuleServices::new(&registry),
+        &directory.path().join("runtime"),
+    );
+
+    assert!(matches!(
+        executor.run_stage(&Stage::new("org.osbuild.hangs"), Path::new("/tree")),
+        Err(ExecutorError::Timeout(name, _)) if name == "org.osbuild.hangs"
+    ));
+}
+
+#[test]
+fn modules_cancelled() {
+    use crate::module::cancel::CancellationToken;
+    use std::time::{Duration, Instant};
+
+    let directory = tempfile::tempdir().unwrap();
+    let runs = directory.path().join("runs");
+    let mut registry = Registry::new(vec![script(
+        directory.path(),
+        "org.osbuild.hangs",
+        &format!(
+            "echo \"$OSBUILD_API_SOCKET_CONTROL\" >> {}\nsleep 60",
+            runs.display()
+        ),
+    )]);
+
+    registry.set_timeouts(Duration::from_secs(1), None);
+
+    let workspace = Workspace::new(directory.path()).unwrap();
+    let control = workspace.socket("control").unwrap();
+    let token = CancellationToken::new();
+    let mut services = ModuleServices::new(&registry);
+
+    services.set_cancellation(token.clone(), &control, Duration::from_millis(100));
+
+    let mut executor = Executor::new(services, workspace.runtime());
+    let start = Instant::now();
+
+    let cancel = {
+        let token = token.clone();
+
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            token.cancel("interrupted");
+        })
+    };
+
+    // the script doesn't listen for the cancel signal so it is killed after the grace period
+    assert!(matches!(
+        executor.run_stage(&Stage::new("org.osbuild.hangs"), Path::new("/tree")),
+        Err(ExecutorError::Cancelled(name)) if name == "org.osbuild.hangs"
+    ));
+    assert!(start.elapsed() < Duration::from_secs(30));
+
+    cancel.join().unwrap();
+
+    // once the build is cancelled stages aren't started
+    assert!(matches!(
+        executor.run_stage(&Stage::new("org.osbuild.hangs"), Path::new("/tree")),
+        Err(ExecutorError::Cancelled(_))
+    ));
+
+    #[cfg(feature = "communication")]
+    assert_eq!(
+        fs::read_to_string(&runs).unwrap(),
+        format!("{}\n", control.display())
+    );
+}
+
+#[test]
+fn manifests_built() {
+    use crate::core::monitor::LogMonitor;
+    use crate::core::store::ObjectStore;
+
+    let directory = tempfile::tempdir().unwrap();
+    let store = ObjectStore::new(&directory.path().join("store"));
+    let workspace = Workspace::new(&directory.path().join("store/tmp")).unwrap();
+
+    let manifest = |stages: &[&str]| -> Manifest {
+        serde_json::from_value(serde_json::json!({
+            "version": "2",
+            "pipelines": [{
+                "name": "tree",
+                "stages": stages
+                    .iter()
+                    .map(|kind| serde_json::json!({"type": kind}))
+                    .collect::<Vec<_>>()
+            }]
+        }))
+        .unwrap()
+    };
+
+    let build = |manifest: &Manifest| -> (Vec<String>, String) {
+        let mut executor = Executor::new(Recorder::default(), workspace.runtime());
+        let mut monitor = LogMonitor::new(vec![]);
+
+        executor
+            .build(manifest, &store, &workspace, &mut monitor)
+            .unwrap();
+
+        assert!(executor.content().tree("name:tree").is_some());
+
+        // the options of every stage that ran are kept
+        assert_eq!(
+            executor.effective_options().len(),
+            executor.services().calls.len()
+        );
+
+        (
+            executor.services().calls.clone(),
+            String::from_utf8(monitor.into_inner()).unwrap(),
+        )
+    };
+
+    let (calls, log) = build(&manifest(&["org.osbuild.a", "org.osbuild.b"]));
+    let id = store.ids().unwrap().pop().unwrap();
+
+    assert_eq!(calls, ["run org.osbuild.a", "run org.osbuild.b"]);
+    assert!(log.contains("org.osbuild.b"));
+    assert_eq!(store.ids().unwrap(), [id.as_str()]);
+
+    fs::write(store.tree_path(&id).unwrap().join("built"), "").unwrap();
+
+    // built trees are reused, also as the base of stages added after them
+    assert!(build(&manifest(&["org.osbuild.a", "org.osbuild.b"]))
+        .0
+        .is_empty());
+
+    let (calls, _) = build(&manifest(&[
+        "org.osbuild.a",
+        "org.osbuild.b",
+        "org.osbuild.c",
+    ]));
+
+    assert_eq!(calls, ["run org.osbuild.c"]);
+    assert_eq!(store.ids().unwrap().len(), 2);
+    assert!(store.ids().unwrap().iter().all(|id| store
+        .tree_path(id)
+        .unwrap()
+        .join("built")
+        .exists()));
+}
+
+#[test]
+fn manifests_built_with_config() {
+    use crate::core::config::BuildConfig;
+    use crate::core::executor::build::{build_with_config, BuildError};
+    use crate::core::monitor::LogMonitor;
+
+    let directory = tempfile::tempdir().unwrap();
+    let stages = directory.path().join("modules/stages");
+
+    fs::create_dir_all(&stages).unwrap();
+    script(
+        &stages,
+        "org.osbuild.disk",
+        "tree=$(sed -n 's/.*\"tree\":\"\\([^\"]*\\)\".*/\\1/p')\n\
+         echo disk > \"$tree/disk.raw\"\n\
+         mkdir \"$tree/etc\"",
+    );
+
+    let manifest: Manifest = serde_json::from_value(serde_json::json!({
+        "version": "2",
+        "pipelines": [{"name": "image", "stages": [{"type": "org.osbuild.disk"}]}]
+    }))
+    .unwrap();
+
+    let mut config = BuildConfig {
+        store: Some(directory.path().join("store")),
+        module_paths: Some(vec![directory.path().join("modules")]),
+        exports: vec!["image".to_string()],
+        ..BuildConfig::new(directory.path().join("output"))
+    };
+
+    config.export.checksums = true;
+
+    build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+
+    // the tree is exported, its files are the artifacts
+    let exported = directory.path().join("output/image");
+
+    assert_eq!(
+        fs::read_to_string(exported.join("disk.raw")).unwrap(),
+        "disk\n"
+    );
+    assert!(exported.join("etc").is_dir());
+    assert!(fs::read_to_string(exported.join("SHA256SUMS"))
+        .unwrap()
+        .ends_with("  disk.raw\n"));
+
+    config.exports = vec!["nope".to_string()];
+
+    assert!(matches!(
+        build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None),
+        Err(BuildError::UnknownExport(name)) if name == "nope"
+    ));
+}
+
+#[test]
+fn pipelines_built_with_their_environment() {
+    use crate::core::config::BuildConfig;
+    use crate::core::executor::build::build_with_config;
+    use crate::core::monitor::LogMonitor;
+
+    let directory = tempfile::tempdir().unwrap();
+    let stages = directory.path().join("modules/stages");
+
+    fs::create_dir_all(&stages).unwrap();
+    script(
+        &stages,
+        "org.osbuild.env",
+        "tree=$(sed -n 's/.*\"tree\":\"\\([^\"]*\\)\".*/\\1/p')\n\
+         env > \"$tree/env\"",
+    );
+
+    let manifest: Manifest = serde_json::from_value(serde_json::json!({
+        "version": "2",
+        "pipelines": [
+            {"name": "a", "source-epoch": 5, "stages": [{"type": "org.osbuild.env"}]},
+            {"name": "b", "stages": [{"type": "org.osbuild.env", "options": {"b": true}}]}
+        ]
+    }))
+    .unwrap();
+
+    let mut config = BuildConfig {
+        store: Some(directory.path().join("store")),
+        module_paths: Some(vec![directory.path().join("modules")]),
+        exports: vec!["a".to_string(), "b".to_string()],
+        ..BuildConfig::new(directory.path().join("output"))
+    };
+
+    config.environment.set("TZ", "UTC");
+
+    let result = build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+
+    assert_eq!(result.environment, ["PATH", "SOURCE_DATE_EPOCH", "TZ"]);
+    assert_eq!(
+        result.source_date_epochs,
+        BTreeMap::from([("a".to_string(), 5)])
+    );
+
+    let a = fs::read_to_string(directory.path().join("output/a/env")).unwrap();
+    let b = fs::read_to_string(directory.path().join("output/b/env")).unwrap();
+
+    assert!(a.contains("SOURCE_DATE_EPOCH=5\n") && a.contains("TZ=UTC\n"));
+    assert!(!b.contains("SOURCE_DATE_EPOCH") && b.contains("TZ=UTC\n"));
+    assert!(!a.contains("HOME="));
+
+    // the epoch of the configuration is for pipelines that don't set their own
+    config.source_date_epoch = Some(10);
+    fs::remove_dir_all(directory.path().join("output")).unwrap();
+    fs::remove_dir_all(directory.path().join("store")).unwrap();
+
+    let result = build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+
+    assert_eq!(result.source_date_epochs["a"], 5);
+    assert_eq!(result.source_date_epochs["b"], 10);
+    assert!(fs::read_to_string(directory.path().join("output/b/env"))
+        .unwrap()
+        .contains("SOURCE_DATE_EPOCH=10\n"));
+}
+
+#[test]
+fn stages_served_host_services() {
+    use crate::core::config::BuildConfig;
+    use crate::core::executor::build::build_with_config;
+    use crate::core::monitor::LogMonitor;
+
+    let directory = tempfile::tempdir().unwrap();
+    let stages = directory.path().join("modules/stages");
+
+    fs::create_dir_all(&stages).unwrap();
+    script(
+        &stages,
+        "org.osbuild.host",
+        "tree=$(sed -n 's/.*\"tree\":\"\\([^\"]*\\)\".*/\\1/p')\n\
+         [ -S \"$OSBUILD_API_SOCKET_HOST\" ] && touch \"$tree/served\"",
+    );
+
+    let manifest: Manifest = serde_json::from_value(serde_json::json!({
+        "version": "2",
+        "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.host"}]}]
+    }))
+    .unwrap();
+
+    let config = BuildConfig {
+        store: Some(directory.path().join("store")),
+        module_paths: Some(vec![directory.path().join("modules")]),
+        exports: vec!["os".to_string()],
+        ..BuildConfig::new(directory.path().join("output"))
+    };
+
+    build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+
+    assert!(directory.path().join("output/os/served").exists());
+}
+
+#[test]
+fn pipelines_accounted() {
+    use crate::core::config::BuildConfig;
+    use crate::core::executor::build::build_with_config;
+    use crate::core::monitor::LogMonitor;
+
+    let directory = tempfile::tempdir().unwrap();
+    let stages = directory.path().join("modules/stages");
+
+    fs::create_dir_all(&stages).unwrap();
+    script(
+        &stages,
+        "org.osbuild.write",
+        "tree=$(sed -n 's/.*\"tree\":\"\\([^\"]*\\)\".*/\\1/p')\n\
+         head -c 65536 /dev/zero > \"$tree/data\"",
+    );
+
+    let manifest: Manifest = serde_json::from_value(serde_json::json!({
+        "version": "2",
+        "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.write"}]}]
+    }))
+    .unwrap();
+
+    let config = BuildConfig {
+        store: Some(directory.path().join("store")),
+        module_paths: Some(vec![directory.path().join("modules")]),
+        ..BuildConfig::new(directory.path().join("output"))
+    };
+
+    let result = build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+
+    assert!(result.resources["os"].store_delta_bytes >= 65536);
+
+    // cached pipelines cost nothing
+    let result = build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+
+    assert!(result.resources.is_empty());
+}
+
+#[test]
+fn checkpoints_kept() {
+    use crate::core::config::BuildConfig;
+    use crate::core::executor::build::build_with_config;
+    use crate::core::monitor::LogMonitor;
+    use crate::core::store::ObjectStore;
+
+    let directory = tempfile::tempdir().unwrap();
+    let stages = directory.path().join("modules/stages");
+
+    fs::create_dir_all(&stages).unwrap();
+    script(
+        &stages,
+        "org.osbuild.write",
+        "tree=$(sed -n 's/.*\"tree\":\"\\([^\"]*\\)\".*/\\1/p')\n\
+         echo written > \"$tree/written\"",
+    );
+
+    let manifest: Manifest = serde_json::from_value(serde_json::json!({
+        "version": "2",
+        "pipelines": [
+            {"name": "build", "stages": [{"type": "org.osbuild.write"}]},
+            {"name": "os", "stages": [{"type": "org.osbuild.write", "options": {"os": true}}]}
+        ]
+    }))
+    .unwrap();
+
+    let mut config = BuildConfig {
+        store: Some(directory.path().join("store")),
+        module_paths: Some(vec![directory.path().join("modules")]),
+        exports: vec!["build".to_string()],
+        checkpoints: vec!["os".to_string()],
+        ..BuildConfig::new(directory.path().join("output"))
+    };
+
+    let store = ObjectStore::new(&directory.path().join("store"));
+
+    build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+
+    // only the checkpoint is kept, what isn't is still exported
+    let ids = store.ids().unwrap();
+
+    assert_eq!(ids.len(), 1);
+    assert_eq!(
+        fs::read_to_string(store.tree_path(&ids[0]).unwrap().join("written")).unwrap(),
+        "written\n"
+    );
+    assert!(directory.path().join("output/build/written").exists());
+
+    // the checkpoint is reused, and without checkpoints every tree is kept
+    config.checkpoints.clear();
+
+    let result = build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+
+    assert_eq!(result.resources.keys().collect::<Vec<_>>(), ["build"]);
+    assert_eq!(store.ids().unwrap().len(), 2);
+}
+
+#[test]
+fn sources_fetched_when_online() {
+    use crate::core::config::BuildConfig;
+    use crate::core::executor::build::{build_with_config, BuildError};
+    use crate::core::export;
+    use crate::core::monitor::LogMonitor;
+    use crate::core::sources::offline::MissingItem;
+    use crate::core::store::ObjectStore;
+
+    let directory = tempfile::tempdir().unwrap();
+    let file = directory.path().join("file");
+
+    fs::create_dir_all(directory.path().join("modules/stages")).unwrap();
+    fs::write(&file, "content").unwrap();
+
+    let checksum = format!("sha256:{}", export::sha256(&file).unwrap());
+    let manifest: Manifest = serde_json::from_value(serde_json::json!({
+        "version": "2",
+        "sources": {
+            "org.osbuild.curl": {"items": {&checksum: format!("file://{}", file.display())}}
+        }
+    }))
+    .unwrap();
+
+    let mut config = BuildConfig {
+        store: Some(directory.path().join("store")),
+        module_paths: Some(vec![directory.path().join("modules")]),
+        offline: true,
+        ..BuildConfig::new(directory.path().join("output"))
+    };
+
+    let cached = ObjectStore::new(&directory.path().join("store"))
+        .sources_path()
+        .join("org.osbuild.curl")
+        .join(&checksum);
+
+    assert!(matches!(
+        build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None),
+        Err(BuildError::MissingSources(missing)) if missing == [MissingItem {
+            source: "org.osbuild.curl".to_string(),
+            checksum: checksum.clone(),
+        }]
+    ));
+    assert!(!cached.exists());
+
+    config.offline = false;
+
+    build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+
+    assert_eq!(fs::read_to_string(&cached).unwrap(), "content");
+
+    // what was fetched is what an offline build finds
+    config.offline = true;
+
+    build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+}
+
+#[test]
+fn stages_run_by_their_runner() {
+    use crate::core::config::BuildConfig;
+    use crate::core::executor::build::{build_with_config, BuildError};
+    use crate::core::monitor::LogMonitor;
+
+    let directory = tempfile::tempdir().unwrap();
+    let stages = directory.path().join("modules/stages");
+    let runners = directory.path().join("modules/runners");
+    let log = directory.path().join("log");
+
+    fs::create_dir_all(&stages).unwrap();
+    fs::create_dir_all(&runners).unwrap();
+    script(&stages, "org.osbuild.noop", "cat > /dev/null");
+    script(
+        &runners,
+        "org.osbuild.linux",
+        &format!(
+            "echo \"$(basename \"$1\")\" >> {}\nexec \"$@\"",
+            log.display()
+        ),
+    );
+
+    let manifest: Manifest = serde_json::from_value(serde_json::json!({
+        "version": "2",
+        "pipelines": [
+            {"name": "build", "stages": [{"type": "org.osbuild.noop"}]},
+            {"name": "os", "stages": [{"type": "org.osbuild.noop", "options": {"os": true}}]}
+        ]
+    }))
+    .unwrap();
+
+    let mut config = BuildConfig {
+        store: Some(directory.path().join("store")),
+        module_paths: Some(vec![directory.path().join("modules")]),
+        runners: BTreeMap::from([("os".to_string(), "org.osbuild.linux".to_string())]),
+        ..BuildConfig::new(directory.path().join("output"))
+    };
+
+    build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None).unwrap();
+
+    // only the pipeline with a runner ran its stage through it
+    assert_eq!(fs::read_to_string(&log).unwrap(), "org.osbuild.noop\n");
+
+    config.runners = BTreeMap::from([("build".to_string(), "org.osbuild.fedora38".to_string())]);
+    fs::remove_dir_all(directory.path().join("store")).unwrap();
+
+    assert!(matches!(
+        build_with_config(&manifest, &config, &mut LogMonitor::new(vec![]), None),
+        Err(BuildError::ExecutorError(ExecutorError::MissingModule(name))) if name == "org.osbuild.fedora38"
+    ));
+}
+
+#[test]
+fn stages_denied_by_configured_policy() {
+    use crate::core::config::{BuildConfig, Config};
+    use crate::core::executor::build::{build_with_config, BuildError};
+    use crate::core::monitor::LogMonitor;
+
+    let directory = tempfile::tempdir().unwrap();
+    let stages = directory.path().join("modules/stages");
+    let ran = directory.path().join("ran");
+
+    fs::create_dir_all(&stages).unwrap();
+    script(
+        &stages,
+        "org.osbuild.curl",
+        &format!(
+            "[ \"$1\" = --schema ] && echo '{{\"capabilities\": [\"network\"]}}' && exit 0\ntouch {}",
+            ran.display()
+        ),
+    );
+
+    let manifest: Manifest = serde_json::from_value(serde_json::json!({
+        "version": "2",
+        "pipelines": [{"name": "tree", "stages": [{"type": "org.osbuild.curl"}]}]
+    }))
+    .unwrap();
+
+    let config = Config::parse(
+        &format!(
+            "store = {:?}\nmodule-paths = [{:?}]\ncapabilities = [\"devices\"]\n",
+            directory.path().join("store"),
+            directory.path().join("modules")
+        ),
+        Path::new("osbuild.toml"),
+    )
+    .unwrap();
+
+    assert!(matches!(
+        build_with_config(
+            &manifest,
+            &BuildConfig::from_config(&config),
+            &mut LogMonitor::new(vec![]),
+            None
+        ),
+        Err(BuildError::ExecutorError(ExecutorError::CapabilityDenied(name, denied)))
+            if name == "org.osbuild.curl" && denied == vec![Capability::Network]
+    ));
+    assert!(!ran.exists());
+}
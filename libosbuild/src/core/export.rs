@@ -0,0 +1,680 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::core::publish::{PublishError, Published};
+use crate::core::{reproducible, verity};
+
+/// The name of the checksum file written next to exported artifacts, in the format
+/// `sha256sum --check` understands.
+pub const CHECKSUM_FILENAME: &str = "SHA256SUMS";
+
+/// The name of the JSON manifest of exported artifacts.
+pub const CONTENTS_FILENAME: &str = "artifacts.json";
+
+#[derive(Debug)]
+pub enum ExportError {
+    /// An artifact isn't a regular file inside of the output directory.
+    InvalidArtifact(PathBuf),
+
+    /// Enabling fs-verity or immutability on an artifact failed, such as when the filesystem
+    /// of the output directory doesn't support it.
+    AttributeError(PathBuf, io::Error),
+
+    /// Publishing the artifacts failed, see `BuildConfig::publishers`.
+    PublishError(PublishError),
+
+    SerializeError(serde_json::Error),
+    IOError(io::Error),
+}
+
+impl From<PublishError> for ExportError {
+    fn from(err: PublishError) -> Self {
+        Self::PublishError(err)
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+impl From<serde_json::Error> for ExportError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::SerializeError(err)
+    }
+}
+
+/// Properties of the virtual machine described in an OVF descriptor.
+#[derive(Debug, Clone)]
+pub struct OvfOptions {
+    /// Name of the virtual system.
+    pub name: String,
+
+    pub cpus: u32,
+    pub memory_mb: u64,
+
+    /// The virtual size of the disk in bytes, defaults to the one in the header of the
+    /// artifact, see `DiskFormat::detect`.
+    pub capacity: Option<u64>,
+
+    /// The OVF operating system id, see the DMTF CIM_OperatingSystem schema; 101 is
+    /// 'Linux 64-Bit'.
+    pub os_id: u32,
+}
+
+impl Default for OvfOptions {
+    fn default() -> Self {
+        Self {
+            name: "osbuild".to_string(),
+            cpus: 1,
+            memory_mb: 1024,
+            capacity: None,
+            os_id: 101,
+        }
+    }
+}
+
+/// Metadata to write next to exported artifacts. Nothing is written by default.
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    /// Write a `SHA256SUMS` file.
+    pub checksums: bool,
+
+    /// Write an `artifacts.json` listing every artifact with its size and digest.
+    pub contents: bool,
+
+    /// Write an OVF descriptor for the (first) artifact.
+    pub ovf: Option<OvfOptions>,
+
+    /// Clamp the timestamps of artifacts and of the metadata written for them to this
+    /// `SOURCE_DATE_EPOCH`.
+    pub source_date_epoch: Option<u64>,
+
+    /// Enable fs-verity on artifacts and list their fs-verity digests.
+    pub verity: bool,
+
+    /// Make artifacts immutable once everything is written, see `verity::set_immutable`.
+    pub immutable: bool,
+}
+
+/// An exported artifact as listed in the contents manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Artifact {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+
+    /// The fs-verity digest, when fs-verity is enabled on the artifact.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verity: Option<String>,
+}
+
+impl Artifact {
+    /// Describe the artifact at `path`, its name is its filename.
+    pub fn from_path(path: &Path) -> Result<Self, ExportError> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| ExportError::InvalidArtifact(path.to_path_buf()))?
+            .to_string();
+
+        let metadata = fs::metadata(path)?;
+
+        if !metadata.is_file() {
+            return Err(ExportError::InvalidArtifact(path.to_path_buf()));
+        }
+
+        Ok(Self {
+            name,
+            size: metadata.len(),
+            sha256: sha256(path)?,
+            verity: None,
+        })
+    }
+}
+
+/// Hex encoded SHA256 digest of a file.
+pub fn sha256(path: &Path) -> Result<String, ExportError> {
+    hexdigest::<Sha256>(path)
+}
+
+/// Hex encoded digest of a file with the hash `D`.
+pub fn hexdigest<D: Digest>(path: &Path) -> Result<String, ExportError> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// The format of a disk image, as far as an OVF descriptor cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+    Vhd,
+    Vmdk,
+    /// A VMDK with compressed grains, the variant that's imported by VMware products.
+    VmdkStreamOptimized,
+}
+
+impl DiskFormat {
+    /// The URI of the format in the `ovf:format` attribute of a disk.
+    pub fn uri(&self) -> &'static str {
+        match self {
+            Self::Raw => "http://en.wikipedia.org/wiki/Byte",
+            Self::Qcow2 => "http://www.gnome.org/~markmc/qcow-image-format.html",
+            Self::Vhd => "http://go.microsoft.com/fwlink/?LinkId=137171",
+            Self::Vmdk => "http://www.vmware.com/interfaces/specifications/vmdk.html#sparse",
+            Self::VmdkStreamOptimized => {
+                "http://www.vmware.com/interfaces/specifications/vmdk.html#streamOptimized"
+            }
+        }
+    }
+
+    /// Detect the format of the image at `path` from its header and read its virtual size in
+    /// bytes. Anything that isn't recognized is a raw image, its virtual size is its size.
+    pub fn detect(path: &Path) -> Result<(Self, u64), ExportError> {
+        let mut file = fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut header = [0u8; 512];
+        let read = read_up_to(&mut file, &mut header)?;
+        let header = &header[..read];
+
+        let be = |offset: usize| u64::from_be_bytes(header[offset..offset + 8].try_into().unwrap());
+        let le = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+
+        if header.len() >= 32 && header.starts_with(b"QFI\xfb") {
+            return Ok((Self::Qcow2, be(24)));
+        }
+
+        if header.len() >= 80 && header.starts_with(b"KDMV") {
+            // the capacity is in sectors, grains are compressed in stream optimized images
+            let flags = u32::from_le_bytes(header[8..12].try_into().unwrap());
+            let compression = u16::from_le_bytes(header[77..79].try_into().unwrap());
+            let format = if flags & (1 << 16) != 0 || compression != 0 {
+                Self::VmdkStreamOptimized
+            } else {
+                Self::Vmdk
+            };
+
+            return Ok((format, le(12) * 512));
+        }
+
+        // a VHD has its footer at its end, and a copy of it at the start when it's dynamic
+        if size >= 512 {
+            let mut footer = [0u8; 512];
+            file.seek(SeekFrom::Start(size - 512))?;
+            file.read_exact(&mut footer)?;
+
+            if footer.starts_with(b"conectix") {
+                return Ok((
+                    Self::Vhd,
+                    u64::from_be_bytes(footer[48..56].try_into().unwrap()),
+                ));
+            }
+        }
+
+        Ok((Self::Raw, size))
+    }
+}
+
+fn read_up_to(file: &mut fs::File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+
+    while read < buffer.len() {
+        match file.read(&mut buffer[read..])? {
+            0 => break,
+            count => read += count,
+        }
+    }
+
+    Ok(read)
+}
+
+/// Render an OVF 1.0 descriptor for a single disk artifact in `format`, with the virtual size
+/// `capacity` unless the options override it.
+pub fn ovf_descriptor(
+    artifact: &Artifact,
+    format: DiskFormat,
+    capacity: u64,
+    options: &OvfOptions,
+) -> String {
+    let capacity = options.capacity.unwrap_or(capacity);
+    let name = xml_escape(&options.name);
+    let file = xml_escape(&artifact.name);
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Envelope xmlns="http://schemas.dmtf.org/ovf/envelope/1" xmlns:ovf="http://schemas.dmtf.org/ovf/envelope/1" xmlns:rasd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_ResourceAllocationSettingData" xmlns:vssd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_VirtualSystemSettingData">
+  <References>
+    <File ovf:href="{file}" ovf:id="file1" ovf:size="{size}"/>
+  </References>
+  <DiskSection>
+    <Info>Virtual disk information</Info>
+    <Disk ovf:capacity="{capacity}" ovf:capacityAllocationUnits="byte" ovf:diskId="vmdisk1" ovf:fileRef="file1" ovf:format="{format}"/>
+  </DiskSection>
+  <VirtualSystem ovf:id="{name}">
+    <Info>A virtual machine</Info>
+    <Name>{name}</Name>
+    <OperatingSystemSection ovf:id="{os_id}">
+      <Info>The kind of installed guest operating system</Info>
+    </OperatingSystemSection>
+    <VirtualHardwareSection>
+      <Info>Virtual hardware requirements</Info>
+      <Item>
+        <rasd:AllocationUnits>hertz * 10^6</rasd:AllocationUnits>
+        <rasd:ElementName>{cpus} virtual CPU(s)</rasd:ElementName>
+        <rasd:InstanceID>1</rasd:InstanceID>
+        <rasd:ResourceType>3</rasd:ResourceType>
+        <rasd:VirtualQuantity>{cpus}</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:AllocationUnits>byte * 2^20</rasd:AllocationUnits>
+        <rasd:ElementName>{memory}MB of memory</rasd:ElementName>
+        <rasd:InstanceID>2</rasd:InstanceID>
+        <rasd:ResourceType>4</rasd:ResourceType>
+        <rasd:VirtualQuantity>{memory}</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:AddressOnParent>0</rasd:AddressOnParent>
+        <rasd:ElementName>disk0</rasd:ElementName>
+        <rasd:HostResource>ovf:/disk/vmdisk1</rasd:HostResource>
+        <rasd:InstanceID>3</rasd:InstanceID>
+        <rasd:ResourceType>17</rasd:ResourceType>
+      </Item>
+    </VirtualHardwareSection>
+  </VirtualSystem>
+</Envelope>
+"#,
+        file = file,
+        size = artifact.size,
+        capacity = capacity,
+        format = format.uri(),
+        name = name,
+        os_id = options.os_id,
+        cpus = options.cpus,
+        memory = options.memory_mb,
+    )
+}
+
+/// What was done to exported artifacts.
+#[derive(Debug, Clone, Default)]
+pub struct Exported {
+    /// The artifacts, described when any metadata was asked for.
+    pub artifacts: Vec<Artifact>,
+
+    /// The metadata files that were written.
+    pub written: Vec<PathBuf>,
+
+    /// Where the artifacts were published to.
+    pub published: Vec<Published>,
+}
+
+impl Exported {
+    /// The fs-verity digests of the artifacts by name, for `BuildResult::verity_digests`.
+    pub fn verity_digests(&self) -> BTreeMap<String, String> {
+        self.artifacts
+            .iter()
+            .filter_map(|artifact| Some((artifact.name.clone(), artifact.verity.clone()?)))
+            .collect()
+    }
+}
+
+/// Write the metadata selected in `options` into `directory` for the given artifacts, which
+/// must be files in `directory`. Returns the paths of the files that were written.
+pub fn write_metadata(
+    directory: &Path,
+    artifacts: &[PathBuf],
+    options: &ExportOptions,
+) -> Result<Vec<PathBuf>, ExportError> {
+    Ok(finish(directory, artifacts, options)?.written)
+}
+
+/// Finish exporting the given artifacts, which must be files in `directory`: enable
+/// fs-verity on them, write the metadata selected in `options`, clamp their timestamps, and
+/// make them immutable, in that order as immutable files can't be touched.
+pub fn finish(
+    directory: &Path,
+    artifacts: &[PathBuf],
+    options: &ExportOptions,
+) -> Result<Exported, ExportError> {
+    let mut written = vec![];
+
+    if !options.checksums
+        && !options.contents
+        && options.ovf.is_none()
+        && options.source_date_epoch.is_none()
+        && !options.verity
+        && !options.immutable
+    {
+        return Ok(Exported::default());
+    }
+
+    let mut described = Vec::with_capacity(artifacts.len());
+
+    for path in artifacts {
+        if path.parent() != Some(directory) {
+            return Err(ExportError::InvalidArtifact(path.clone()));
+        }
+
+        let mut artifact = Artifact::from_path(path)?;
+
+        if options.verity {
+            verity::enable(path).map_err(|err| ExportError::AttributeError(path.clone(), err))?;
+            artifact.verity = Some(verity::digest(path)?);
+        }
+
+        described.push(artifact);
+    }
+
+    if options.checksums {
+        let path = directory.join(CHECKSUM_FILENAME);
+        let data: String = described
+            .iter()
+            .map(|artifact| format!("{}  {}\n", artifact.sha256, artifact.name))
+            .collect();
+
+        fs::write(&path, data)?;
+        written.push(path);
+    }
+
+    if options.contents {
+        let path = directory.join(CONTENTS_FILENAME);
+
+        fs::write(&path, serde_json::to_vec_pretty(&described)?)?;
+        written.push(path);
+    }
+
+    if let (Some(ovf), Some(artifact)) = (&options.ovf, described.first()) {
+        let stem = Path::new(&artifact.name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| artifact.name.clone());
+
+        let path = directory.join(format!("{}.ovf", stem));
+        let (format, capacity) = DiskFormat::detect(&artifacts[0])?;
+
+        fs::write(&path, ovf_descriptor(artifact, format, capacity, ovf))?;
+        written.push(path);
+    }
+
+    if let Some(epoch) = options.source_date_epoch {
+        for path in artifacts.iter().chain(&written) {
+            reproducible::clamp_timestamps(path, epoch)?;
+        }
+    }
+
+    if options.immutable {
+        for path in artifacts {
+            verity::set_immutable(path, true)
+                .map_err(|err| ExportError::AttributeError(path.clone(), err))?;
+        }
+    }
+
+    Ok(Exported {
+        artifacts: described,
+        written,
+        published: vec![],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_metadata_nothing() {
+        let directory = tempfile::tempdir().unwrap();
+        let artifact = directory.path().join("disk.raw");
+        fs::write(&artifact, b"abc").unwrap();
+
+        let written =
+            write_metadata(directory.path(), &[artifact], &ExportOptions::default()).unwrap();
+
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn write_metadata_all() {
+        let directory = tempfile::tempdir().unwrap();
+        let artifact = directory.path().join("disk.vmdk");
+        fs::write(&artifact, b"abc").unwrap();
+
+        let options = ExportOptions {
+            checksums: true,
+            contents: true,
+            ovf: Some(OvfOptions {
+                name: "my <vm>".to_string(),
+                capacity: Some(10 * 1024 * 1024 * 1024),
+                ..Default::default()
+            }),
+            source_date_epoch: None,
+            verity: false,
+            immutable: false,
+        };
+
+        let written = write_metadata(directory.path(), &[artifact], &options).unwrap();
+
+        assert_eq!(written.len(), 3);
+
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+        assert_eq!(
+            fs::read_to_string(directory.path().join(CHECKSUM_FILENAME)).unwrap(),
+            format!("{}  disk.vmdk\n", digest)
+        );
+
+        let contents: serde_json::Value =
+            serde_json::from_slice(&fs::read(directory.path().join(CONTENTS_FILENAME)).unwrap())
+                .unwrap();
+
+        assert_eq!(contents[0]["name"], "disk.vmdk");
+        assert_eq!(contents[0]["size"], 3);
+        assert_eq!(contents[0]["sha256"], digest);
+        assert!(contents[0].get("verity").is_none());
+
+        let ovf = fs::read_to_string(directory.path().join("disk.ovf")).unwrap();
+
+        assert!(ovf.contains(r#"ovf:href="disk.vmdk""#));
+        assert!(ovf.contains(r#"ovf:capacity="10737418240""#));
+        assert!(ovf.contains(r#"ovf:format="http://en.wikipedia.org/wiki/Byte""#));
+        assert!(ovf.contains("<Name>my &lt;vm&gt;</Name>"));
+    }
+
+    #[test]
+    fn disk_format_detected() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("disk");
+
+        let mut qcow2 = vec![0u8; 1024];
+        qcow2[..4].copy_from_slice(b"QFI\xfb");
+        qcow2[24..32].copy_from_slice(&(20u64 << 30).to_be_bytes());
+        fs::write(&path, &qcow2).unwrap();
+
+        assert_eq!(
+            DiskFormat::detect(&path).unwrap(),
+            (DiskFormat::Qcow2, 20 << 30)
+        );
+
+        let mut vmdk = vec![0u8; 1024];
+        vmdk[..4].copy_from_slice(b"KDMV");
+        vmdk[12..20].copy_from_slice(&(1u64 << 21).to_le_bytes());
+        fs::write(&path, &vmdk).unwrap();
+
+        assert_eq!(
+            DiskFormat::detect(&path).unwrap(),
+            (DiskFormat::Vmdk, 1 << 30)
+        );
+
+        vmdk[8..12].copy_from_slice(&(1u32 << 16).to_le_bytes());
+        vmdk[77..79].copy_from_slice(&1u16.to_le_bytes());
+        fs::write(&path, &vmdk).unwrap();
+
+        assert_eq!(
+            DiskFormat::detect(&path).unwrap(),
+            (DiskFormat::VmdkStreamOptimized, 1 << 30)
+        );
+
+        let mut vhd = vec![0u8; 2048];
+        vhd[1536..1544].copy_from_slice(b"conectix");
+        vhd[1536 + 48..1536 + 56].copy_from_slice(&(4u64 << 30).to_be_bytes());
+        fs::write(&path, &vhd).unwrap();
+
+        assert_eq!(
+            DiskFormat::detect(&path).unwrap(),
+            (DiskFormat::Vhd, 4 << 30)
+        );
+
+        fs::write(&path, b"abc").unwrap();
+
+        assert_eq!(DiskFormat::detect(&path).unwrap(), (DiskFormat::Raw, 3));
+    }
+
+    #[test]
+    fn ovf_descriptor_stream_optimized() {
+        let directory = tempfile::tempdir().unwrap();
+        let artifact = directory.path().join("disk.vmdk");
+
+        let mut vmdk = vec![0u8; 1024];
+        vmdk[..4].copy_from_slice(b"KDMV");
+        vmdk[8..12].copy_from_slice(&(1u32 << 16).to_le_bytes());
+        vmdk[12..20].copy_from_slice(&(1u64 << 21).to_le_bytes());
+        fs::write(&artifact, &vmdk).unwrap();
+
+        let options = ExportOptions {
+            ovf: Some(OvfOptions::default()),
+            ..Default::default()
+        };
+
+        write_metadata(directory.path(), &[artifact], &options).unwrap();
+
+        let ovf = fs::read_to_string(directory.path().join("disk.ovf")).unwrap();
+
+        assert!(ovf.contains(r#"ovf:size="1024""#));
+        assert!(ovf.contains(r#"ovf:capacity="1073741824""#));
+        assert!(ovf.contains("vmdk.html#streamOptimized"));
+    }
+
+    #[test]
+    fn write_metadata_clamped() {
+        let directory = tempfile::tempdir().unwrap();
+        let artifact = directory.path().join("disk.raw");
+        fs::write(&artifact, b"abc").unwrap();
+
+        let options = ExportOptions {
+            checksums: true,
+            source_date_epoch: Some(1700000000),
+            ..Default::default()
+        };
+
+        let written =
+            write_metadata(directory.path(), std::slice::from_ref(&artifact), &options).unwrap();
+        let epoch = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1700000000);
+
+        for path in [&artifact, &written[0]] {
+            assert_eq!(fs::metadata(path).unwrap().modified().unwrap(), epoch);
+        }
+
+        // clamping alone writes nothing
+        let options = ExportOptions {
+            source_date_epoch: Some(1700000000),
+            ..Default::default()
+        };
+
+        assert!(write_metadata(directory.path(), &[artifact], &options)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn finish_verity_immutable() {
+        let directory = tempfile::tempdir().unwrap();
+        let artifact = directory.path().join("disk.raw");
+        fs::write(&artifact, b"abc").unwrap();
+
+        let options = ExportOptions {
+            contents: true,
+            verity: true,
+            immutable: true,
+            ..Default::default()
+        };
+
+        // fs-verity and immutability depend on the filesystem and capabilities the tests
+        // run with
+        let exported = match finish(directory.path(), std::slice::from_ref(&artifact), &options) {
+            Ok(exported) => exported,
+            Err(ExportError::AttributeError(path, _)) => {
+                assert_eq!(path, artifact);
+                return;
+            }
+            Err(err) => panic!("{:?}", err),
+        };
+
+        let changed = fs::write(&artifact, b"def");
+        verity::set_immutable(&artifact, false).unwrap();
+
+        assert!(changed.is_err());
+
+        let digest = "700b6bd8510f0b4f9bac8b9cf0459151a1c4a99f467892bb4bd289a67df8e19c";
+
+        assert_eq!(
+            exported.verity_digests(),
+            BTreeMap::from([("disk.raw".to_string(), digest.to_string())])
+        );
+
+        let contents: serde_json::Value =
+            serde_json::from_slice(&fs::read(directory.path().join(CONTENTS_FILENAME)).unwrap())
+                .unwrap();
+
+        assert_eq!(contents[0]["verity"], digest);
+    }
+
+    #[test]
+    fn write_metadata_outside_directory() {
+        let directory = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let artifact = other.path().join("disk.raw");
+        fs::write(&artifact, b"abc").unwrap();
+
+        let options = ExportOptions {
+            checksums: true,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            write_metadata(directory.path(), &[artifact], &options),
+            Err(ExportError::InvalidArtifact(_))
+        ));
+    }
+}
@@ -0,0 +1,417 @@
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// File in an object's directory recording the algorithm its id was computed with.
+pub const ALGORITHM_FILE: &str = "hash-algorithm";
+
+#[derive(Debug)]
+pub enum IdError {
+    /// The algorithm is unknown or was not compiled in.
+    UnknownAlgorithm(String),
+
+    /// An id is not `<algorithm>:<hex>` or bare hex.
+    InvalidId(String),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for IdError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// The algorithm object ids are computed with. `Sha256` is what osbuild uses and the
+/// default, `Blake3` is much faster on large trees and available with the `blake3` feature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    #[default]
+    Sha256,
+
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl HashAlgo {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// Hash everything `reader` produces.
+    pub fn hash_reader<R: Read>(&self, mut reader: R) -> Result<ObjectId, IdError> {
+        let mut hasher = self.hasher();
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        loop {
+            let read = reader.read(&mut buffer)?;
+
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..read]);
+        }
+
+        Ok(hasher.finalize())
+    }
+
+    pub fn hash_file(&self, path: &Path) -> Result<ObjectId, IdError> {
+        self.hash_reader(fs::File::open(path)?)
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = IdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(Self::Sha256),
+            #[cfg(feature = "blake3")]
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(IdError::UnknownAlgorithm(s.to_string())),
+        }
+    }
+}
+
+/// A hash in progress.
+pub enum Hasher {
+    Sha256(Sha256),
+
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    #[inline]
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> ObjectId {
+        let (algo, digest): (HashAlgo, Vec<u8>) = match self {
+            Self::Sha256(hasher) => (HashAlgo::Sha256, hasher.finalize().to_vec()),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(hasher) => (HashAlgo::Blake3, hasher.finalize().as_bytes().to_vec()),
+        };
+
+        let mut hex = String::with_capacity(digest.len() * 2);
+
+        for byte in digest {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+
+        ObjectId { algo, digest: hex }
+    }
+}
+
+/// The id of an object in the store. Ids are shown as `<algorithm>:<hex>` except for sha256
+/// ids which are shown as bare hex, as osbuild does.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ObjectId {
+    pub algo: HashAlgo,
+    pub digest: String,
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.algo {
+            HashAlgo::Sha256 => write!(f, "{}", self.digest),
+            #[allow(unreachable_patterns)]
+            algo => write!(f, "{}:{}", algo, self.digest),
+        }
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = IdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algo, digest) = match s.split_once(':') {
+            Some((algo, digest)) => (algo.parse()?, digest),
+            None => (HashAlgo::Sha256, s),
+        };
+
+        if digest.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(IdError::InvalidId(s.to_string()));
+        }
+
+        Ok(Self {
+            algo,
+            digest: digest.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Serialize `value` the way Python's `json.dumps(value, sort_keys=True)` does, which is what
+/// osbuild hashes to compute ids. Keys are sorted, separators are `", "` and `": "`, and
+/// everything outside of ASCII is escaped.
+pub fn canonical_json(value: &serde_json::Value) -> String {
+    fn string(s: &str, out: &mut String) {
+        out.push('"');
+
+        // most strings need no escaping, copy the runs between escapes at once
+        let mut start = 0;
+
+        for (index, c) in s.char_indices() {
+            let escape = match c {
+                '"' => "\\\"",
+                '\\' => "\\\\",
+                '\n' => "\\n",
+                '\r' => "\\r",
+                '\t' => "\\t",
+                '\u{08}' => "\\b",
+                '\u{0c}' => "\\f",
+                c if (c as u32) < 0x20 || (c as u32) > 0x7e => "",
+                _ => continue,
+            };
+
+            out.push_str(&s[start..index]);
+            start = index + c.len_utf8();
+
+            if escape.is_empty() {
+                let mut units = [0u16; 2];
+
+                for unit in c.encode_utf16(&mut units) {
+                    let _ = write!(out, "\\u{:04x}", unit);
+                }
+            } else {
+                out.push_str(escape);
+            }
+        }
+
+        out.push_str(&s[start..]);
+        out.push('"');
+    }
+
+    fn write(value: &serde_json::Value, out: &mut String) {
+        match value {
+            serde_json::Value::String(s) => string(s, out),
+            serde_json::Value::Array(items) => {
+                out.push('[');
+
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        out.push_str(", ");
+                    }
+
+                    write(item, out);
+                }
+
+                out.push(']');
+            }
+            serde_json::Value::Object(map) => {
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+
+                out.push('{');
+
+                for (index, key) in keys.into_iter().enumerate() {
+                    if index > 0 {
+                        out.push_str(", ");
+                    }
+
+                    string(key, out);
+                    out.push_str(": ");
+                    write(&map[key], out);
+                }
+
+                out.push('}');
+            }
+            value => {
+                let _ = write!(out, "{}", value);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    write(value, &mut out);
+    out
+}
+
+/// The id of a stage: a hash over its type, the ids of the tree it builds on and of the
+/// build root it runs in, its options, and what else its tree depends on; its inputs as
+/// they resolve, its devices and mounts, and the source epoch of its pipeline.
+pub fn stage_id(
+    algo: HashAlgo,
+    kind: &str,
+    base: Option<&ObjectId>,
+    build: Option<&ObjectId>,
+    options: &serde_json::Value,
+    dependencies: &serde_json::Value,
+) -> ObjectId {
+    let id = |id: Option<&ObjectId>| match id {
+        Some(id) => serde_json::Value::String(id.to_string()),
+        None => serde_json::Value::Null,
+    };
+
+    let mut hasher = algo.hasher();
+
+    hasher.update(canonical_json(&kind.into()).as_bytes());
+    hasher.update(canonical_json(&id(build)).as_bytes());
+    hasher.update(canonical_json(&id(base)).as_bytes());
+    hasher.update(canonical_json(options).as_bytes());
+    hasher.update(canonical_json(dependencies).as_bytes());
+
+    hasher.finalize()
+}
+
+/// Record the algorithm an object's id was computed with in its directory.
+pub fn write_algorithm(object: &Path, algo: HashAlgo) -> Result<(), IdError> {
+    fs::write(object.join(ALGORITHM_FILE), format!("{}\n", algo))?;
+    Ok(())
+}
+
+/// The algorithm an object's id was computed with, objects that don't record one predate
+/// the choice and use sha256.
+pub fn read_algorithm(object: &Path) -> Result<HashAlgo, IdError> {
+    match fs::read_to_string(object.join(ALGORITHM_FILE)) {
+        Ok(data) => data.trim().parse(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashAlgo::Sha256),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn canonical_json_matches_python() {
+        let value = serde_json::json!({"b": [1, 2.5, null], "a": {"z": true, "y": "é\n"}});
+
+        // json.dumps(value, sort_keys=True)
+        assert_eq!(
+            canonical_json(&value),
+            r#"{"a": {"y": "\u00e9\n", "z": true}, "b": [1, 2.5, null]}"#
+        );
+        assert_eq!(canonical_json(&"😀".into()), r#""\ud83d\ude00""#);
+        assert_eq!(
+            canonical_json(&"a \"b\" \\ c\u{7f}".into()),
+            r#""a \"b\" \\ c\u007f""#
+        );
+    }
+
+    #[test]
+    fn object_ids() {
+        let id = HashAlgo::Sha256.hash_reader(&b"abc"[..]).unwrap();
+
+        assert_eq!(
+            id.to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(id.to_string().parse::<ObjectId>().unwrap(), id);
+        assert_eq!(
+            format!("sha256:{}", id.digest).parse::<ObjectId>().unwrap(),
+            id
+        );
+        assert!(matches!(
+            "md5:00".parse::<ObjectId>(),
+            Err(IdError::UnknownAlgorithm(_))
+        ));
+        assert!(matches!(
+            "xyz".parse::<ObjectId>(),
+            Err(IdError::InvalidId(_))
+        ));
+    }
+
+    #[test]
+    fn stage_ids_chain() {
+        let options = serde_json::json!({"packages": ["bash"]});
+        let none = serde_json::json!({});
+        let first = stage_id(
+            HashAlgo::Sha256,
+            "org.osbuild.rpm",
+            None,
+            None,
+            &options,
+            &none,
+        );
+        let second = stage_id(
+            HashAlgo::Sha256,
+            "org.osbuild.rpm",
+            Some(&first),
+            None,
+            &options,
+            &none,
+        );
+
+        assert_ne!(first, second);
+        assert_eq!(
+            first,
+            stage_id(
+                HashAlgo::Sha256,
+                "org.osbuild.rpm",
+                None,
+                None,
+                &options,
+                &none
+            )
+        );
+        assert_ne!(
+            first,
+            stage_id(
+                HashAlgo::Sha256,
+                "org.osbuild.rpm",
+                None,
+                None,
+                &options,
+                &serde_json::json!({"source-epoch": 0})
+            )
+        );
+    }
+
+    #[test]
+    fn algorithm_recorded() {
+        let directory = tempfile::tempdir().unwrap();
+
+        assert_eq!(read_algorithm(directory.path()).unwrap(), HashAlgo::Sha256);
+
+        write_algorithm(directory.path(), HashAlgo::default()).unwrap();
+        assert_eq!(read_algorithm(directory.path()).unwrap(), HashAlgo::Sha256);
+
+        fs::write(directory.path().join(ALGORITHM_FILE), "md5\n").unwrap();
+        assert!(read_algorithm(directory.path()).is_err());
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3_ids() {
+        let id = HashAlgo::Blake3.hash_reader(&b"abc"[..]).unwrap();
+
+        assert!(id.to_string().starts_with("blake3:6437b3ac"));
+        assert_eq!(id.to_string().parse::<ObjectId>().unwrap(), id);
+    }
+}
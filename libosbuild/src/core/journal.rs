@@ -0,0 +1,352 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// File in the store the journal is kept in.
+pub const JOURNAL_FILE: &str = "journal";
+
+/// File in the store builds lock; shared while they build in it, and exclusively to recover
+/// it, so staging trees of running builds aren't taken for ones that were left behind.
+pub const LOCK_FILE: &str = "lock";
+
+#[derive(Debug)]
+pub enum JournalError {
+    /// A line of the journal that isn't its last couldn't be parsed, contains the line number.
+    Corrupt(usize, String),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for JournalError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// What happened in the store, in the order it happened.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Entry {
+    /// A staging directory was created for an object that is being built.
+    Staging { path: PathBuf },
+
+    /// A stage of `pipeline` completed, `id` is the id of the tree it produced.
+    Stage { pipeline: String, id: String },
+
+    /// The staging directory was committed as the object `id`.
+    Commit { id: String, path: PathBuf },
+
+    /// The staging directory was removed without being committed.
+    Discard { path: PathBuf },
+}
+
+/// A write-ahead journal of the store. Entries are written, and synced to disk, before the
+/// store is changed so a build that crashed or lost power can be cleaned up after.
+pub struct Journal {
+    file: fs::File,
+}
+
+impl Journal {
+    /// Open the journal of `store` for appending, it is created if it doesn't exist.
+    pub fn open(store: &Path) -> Result<Self, JournalError> {
+        let file = fs::File::options()
+            .create(true)
+            .append(true)
+            .open(store.join(JOURNAL_FILE))?;
+
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, entry: &Entry) -> Result<(), JournalError> {
+        let mut line = serde_json::to_vec(entry).map_err(io::Error::from)?;
+        line.push(b'\n');
+
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+/// A lock on a store, see `LOCK_FILE`. It is released when dropped.
+pub struct StoreLock {
+    _file: fs::File,
+}
+
+impl StoreLock {
+    fn lock(store: &Path, operation: i32) -> io::Result<Option<Self>> {
+        use std::os::fd::AsRawFd;
+
+        let file = fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(store.join(LOCK_FILE))?;
+
+        // SAFETY: flock(2) on a descriptor we own
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } < 0 {
+            let err = io::Error::last_os_error();
+
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        Ok(Some(Self { _file: file }))
+    }
+
+    /// Share the lock of `store` with the other builds in it, waiting for a recovery of it
+    /// to finish.
+    pub fn shared(store: &Path) -> io::Result<Self> {
+        Ok(Self::lock(store, libc::LOCK_SH)?.expect("blocking locks are always taken"))
+    }
+
+    /// Lock `store` for nothing else to use it, `None` when something does.
+    pub fn exclusive(store: &Path) -> io::Result<Option<Self>> {
+        Self::lock(store, libc::LOCK_EX | libc::LOCK_NB)
+    }
+}
+
+/// The entries in the journal of `store`, none if there is no journal. A last line that
+/// doesn't parse was cut off by a crash while writing it, and is ignored.
+pub fn read(store: &Path) -> Result<Vec<Entry>, JournalError> {
+    let data = match fs::read_to_string(store.join(JOURNAL_FILE)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let lines: Vec<&str> = data.lines().filter(|line| !line.is_empty()).collect();
+    let mut entries = vec![];
+
+    for (index, line) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) if index + 1 == lines.len() && !data.ends_with('\n') => break,
+            Err(err) => return Err(JournalError::Corrupt(index + 1, err.to_string())),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// The state of the store the journal describes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// Staging directories that were neither committed nor discarded.
+    pub staging: Vec<PathBuf>,
+
+    /// Ids of the trees produced by the stages of each pipeline, in the order they completed.
+    pub completed: BTreeMap<String, Vec<String>>,
+
+    /// Ids of the objects that were committed.
+    pub committed: Vec<String>,
+}
+
+impl Recovery {
+    pub fn from_entries(entries: &[Entry]) -> Self {
+        let mut recovery = Self::default();
+
+        for entry in entries {
+            match entry {
+                Entry::Staging { path } => recovery.staging.push(path.clone()),
+                Entry::Stage { pipeline, id } => recovery
+                    .completed
+                    .entry(pipeline.clone())
+                    .or_default()
+                    .push(id.clone()),
+                Entry::Commit { id, path } => {
+                    recovery.staging.retain(|staging| staging != path);
+                    recovery.committed.push(id.clone());
+                }
+                Entry::Discard { path } => recovery.staging.retain(|staging| staging != path),
+            }
+        }
+
+        recovery
+    }
+
+    /// Whether a build was interrupted and left something behind.
+    pub fn is_clean(&self) -> bool {
+        self.staging.is_empty()
+    }
+}
+
+/// Recover `store` after a build that didn't finish: remove the staging directories it left
+/// behind and start a new journal. Directories outside of the store are never removed.
+/// Returns what the journal described so callers can report or resume from it. Nothing is
+/// recovered while other builds hold the lock of the store.
+pub fn recover(store: &Path) -> Result<Recovery, JournalError> {
+    if !store.exists() {
+        return Ok(Recovery::default());
+    }
+
+    let Some(_lock) = StoreLock::exclusive(store)? else {
+        return Ok(Recovery::default());
+    };
+
+    let recovery = Recovery::from_entries(&read(store)?);
+
+    for path in &recovery.staging {
+        if !path.starts_with(store) {
+            continue;
+        }
+
+        match fs::remove_dir_all(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+
+    match fs::remove_file(store.join(JOURNAL_FILE)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+
+    Ok(recovery)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn journal_roundtrip() {
+        let store = tempfile::tempdir().unwrap();
+
+        assert!(read(store.path()).unwrap().is_empty());
+
+        let entries = vec![
+            Entry::Staging {
+                path: store.path().join("stage/a"),
+            },
+            Entry::Stage {
+                pipeline: "os".to_string(),
+                id: "aa".to_string(),
+            },
+            Entry::Commit {
+                id: "aa".to_string(),
+                path: store.path().join("stage/a"),
+            },
+        ];
+
+        let mut journal = Journal::open(store.path()).unwrap();
+
+        for entry in &entries {
+            journal.record(entry).unwrap();
+        }
+
+        assert_eq!(read(store.path()).unwrap(), entries);
+
+        // a crash while writing cuts off the last line
+        let path = store.path().join(JOURNAL_FILE);
+        let mut data = fs::read_to_string(&path).unwrap();
+        data.push_str("{\"event\": \"stag");
+        fs::write(&path, &data).unwrap();
+
+        assert_eq!(read(store.path()).unwrap(), entries);
+
+        fs::write(&path, format!("nope\n{}", data)).unwrap();
+
+        assert!(matches!(
+            read(store.path()),
+            Err(JournalError::Corrupt(1, _))
+        ));
+    }
+
+    #[test]
+    fn recover_half_committed() {
+        let store = tempfile::tempdir().unwrap();
+        let committed = store.path().join("stage/a");
+        let discarded = store.path().join("stage/b");
+        let interrupted = store.path().join("stage/c");
+        let outside = tempfile::tempdir().unwrap();
+
+        for path in [&committed, &interrupted] {
+            fs::create_dir_all(path).unwrap();
+        }
+
+        let mut journal = Journal::open(store.path()).unwrap();
+
+        for entry in [
+            Entry::Staging {
+                path: committed.clone(),
+            },
+            Entry::Staging {
+                path: discarded.clone(),
+            },
+            Entry::Staging {
+                path: interrupted.clone(),
+            },
+            Entry::Staging {
+                path: outside.path().to_path_buf(),
+            },
+            Entry::Stage {
+                pipeline: "os".to_string(),
+                id: "aa".to_string(),
+            },
+            Entry::Stage {
+                pipeline: "os".to_string(),
+                id: "bb".to_string(),
+            },
+            Entry::Commit {
+                id: "aa".to_string(),
+                path: committed.clone(),
+            },
+            Entry::Discard { path: discarded },
+        ] {
+            journal.record(&entry).unwrap();
+        }
+
+        let recovery = recover(store.path()).unwrap();
+
+        assert!(!recovery.is_clean());
+        assert_eq!(
+            recovery.staging,
+            vec![interrupted.clone(), outside.path().to_path_buf()]
+        );
+        assert_eq!(recovery.completed["os"], vec!["aa", "bb"]);
+        assert_eq!(recovery.committed, vec!["aa"]);
+        assert!(committed.exists());
+        assert!(!interrupted.exists());
+        assert!(outside.path().exists());
+        assert!(!store.path().join(JOURNAL_FILE).exists());
+        assert!(recover(store.path()).unwrap().is_clean());
+    }
+
+    #[test]
+    fn recover_locked() {
+        let store = tempfile::tempdir().unwrap();
+        let building = store.path().join("stage/a");
+
+        fs::create_dir_all(&building).unwrap();
+
+        Journal::open(store.path())
+            .unwrap()
+            .record(&Entry::Staging {
+                path: building.clone(),
+            })
+            .unwrap();
+
+        // a running build holds the lock, its staging tree isn't left behind
+        let lock = StoreLock::shared(store.path()).unwrap();
+
+        assert!(StoreLock::exclusive(store.path()).unwrap().is_none());
+        assert!(recover(store.path()).unwrap().is_clean());
+        assert!(building.exists());
+
+        drop(lock);
+
+        assert_eq!(
+            recover(store.path()).unwrap().staging,
+            vec![building.clone()]
+        );
+        assert!(!building.exists());
+        assert!(recover(&store.path().join("missing")).unwrap().is_clean());
+    }
+}
@@ -1,3 +1,66 @@
+/// What builds cost; CPU, memory, and I/O of stages, downloads, and store growth.
+pub mod accounting;
+
+/// Configuration of a build.
+pub mod config;
+
+/// Exporting of artifacts and the metadata that accompanies them.
+pub mod export;
+
+/// The environment stages run with.
+pub mod environment;
+
+/// Running stages, with the devices and mounts they use.
+pub mod executor;
+
+/// Ids of objects and the algorithms they are computed with.
+pub mod id;
+
+/// The journal of the store, to clean up after builds that crashed.
+pub mod journal;
+
+/// Monitors report the progress of a build.
+pub mod monitor;
+
+/// Per-build directories on the host for runtime files, sockets, staging trees, and logs.
+pub mod paths;
+
+/// Uploading exported artifacts, such as to a bucket or a container registry.
+pub mod publish;
+
+/// Reproducible builds; `SOURCE_DATE_EPOCH` and the timestamps of what is exported.
+pub mod reproducible;
+
+/// The outcome of a build and how it failed.
+pub mod result;
+
+/// Runners set up the build root for stages, which runner is used depends on the host or is
+/// asked for by a pipeline.
+pub mod runner;
+
+/// Host side handling of sources, the modules that fetch the content builds use.
+pub mod sources;
+
+/// Secrets that sources need, such as credentials and client certificates, kept out of
+/// manifests.
+pub mod secrets;
+
+/// The object store built trees are committed to, and what is in them.
+pub mod store;
+
+/// Timings of the phases of a run.
+pub mod timing;
+
+/// fs-verity digests of files, and enabling fs-verity and immutability on them.
+pub mod verity;
+
+/// Building manifests for others, who control the worker over a socket.
+#[cfg(feature = "worker")]
+pub mod worker;
+
+pub use config::{BuildConfig, Sandbox};
+pub use result::{BuildResult, Failure, FailureKind};
+
 use crate::manifest::description::validation;
 use crate::manifest::path as manifest_path;
 
@@ -11,13 +74,20 @@ impl Schema {
         Self { name, data }
     }
 
+    /// The name of the thing this schema belongs to, if known.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn is_valid(self) -> bool {
         let mut result = validation::Result::new();
 
         if self.data.is_none() {
             result.add_error(validation::Error {
+                rule: validation::Rule::Schema,
                 message: "could not find schema information".to_string(),
                 path: manifest_path::Path(vec![]),
+                severity: validation::Severity::Error,
             });
         }
 
@@ -0,0 +1,193 @@
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::core::monitor::Monitor;
+
+/// The socket journald receives native messages on.
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// The `SYSLOG_IDENTIFIER` of the entries.
+pub const IDENTIFIER: &str = "osbuild";
+
+/// syslog priorities of the entries.
+const PRIORITY_ERROR: &str = "3";
+const PRIORITY_INFO: &str = "6";
+
+/// Encode `fields` in the native protocol of journald. Values with a newline in them are
+/// sent as their length followed by their bytes, others as `KEY=value` lines.
+pub fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut data = vec![];
+
+    for (key, value) in fields {
+        data.extend_from_slice(key.as_bytes());
+
+        if value.contains('\n') {
+            data.push(b'\n');
+            data.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            data.push(b'=');
+        }
+
+        data.extend_from_slice(value.as_bytes());
+        data.push(b'\n');
+    }
+
+    data
+}
+
+/// A monitor sending structured entries to the journal, with the pipeline and stage they are
+/// about in `OSBUILD_PIPELINE`, `OSBUILD_STAGE`, and `OSBUILD_STAGE_INDEX` so they can be
+/// filtered with `journalctl`. Entries that can't be sent are dropped.
+pub struct JournaldMonitor {
+    socket: UnixDatagram,
+    path: PathBuf,
+    pipeline: String,
+    stage: Option<(usize, String)>,
+}
+
+impl JournaldMonitor {
+    pub fn new() -> std::io::Result<Self> {
+        Self::with_socket(Path::new(JOURNAL_SOCKET))
+    }
+
+    /// A monitor sending to the journal socket at `path`.
+    pub fn with_socket(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            path: path.to_path_buf(),
+            pipeline: String::new(),
+            stage: None,
+        })
+    }
+
+    fn send(&self, message: &str, priority: &str, extra: &[(&str, &str)]) {
+        let index = self.stage.as_ref().map(|(index, _)| index.to_string());
+
+        let mut fields = vec![
+            ("MESSAGE", message),
+            ("PRIORITY", priority),
+            ("SYSLOG_IDENTIFIER", IDENTIFIER),
+        ];
+
+        if !self.pipeline.is_empty() {
+            fields.push(("OSBUILD_PIPELINE", &self.pipeline));
+        }
+
+        if let (Some((_, name)), Some(index)) = (&self.stage, &index) {
+            fields.push(("OSBUILD_STAGE", name));
+            fields.push(("OSBUILD_STAGE_INDEX", index));
+        }
+
+        fields.extend_from_slice(extra);
+
+        let _ = self.socket.send_to(&encode(&fields), &self.path);
+    }
+}
+
+impl Monitor for JournaldMonitor {
+    fn begin(&mut self, pipeline: &str, stages: &[String]) {
+        self.pipeline = pipeline.to_string();
+        self.stage = None;
+
+        self.send(
+            &format!("Pipeline {}", pipeline),
+            PRIORITY_INFO,
+            &[("OSBUILD_STAGES", &stages.len().to_string())],
+        );
+    }
+
+    fn stage(&mut self, index: usize, name: &str) {
+        self.stage = Some((index, name.to_string()));
+
+        self.send(&format!("Stage {}", name), PRIORITY_INFO, &[]);
+    }
+
+    fn log(&mut self, message: &str) {
+        self.send(message.trim_end_matches('\n'), PRIORITY_INFO, &[]);
+    }
+
+    fn result(&mut self, _index: usize, success: bool, duration: Duration) {
+        let name = self
+            .stage
+            .as_ref()
+            .map(|(_, name)| name.clone())
+            .unwrap_or_default();
+
+        self.send(
+            &format!(
+                "Stage {} {} after {:.2}s",
+                name,
+                if success { "finished" } else { "failed" },
+                duration.as_secs_f64()
+            ),
+            if success {
+                PRIORITY_INFO
+            } else {
+                PRIORITY_ERROR
+            },
+            &[
+                ("OSBUILD_SUCCESS", if success { "1" } else { "0" }),
+                ("OSBUILD_DURATION_USEC", &duration.as_micros().to_string()),
+            ],
+        );
+    }
+
+    fn finish(&mut self, success: bool) {
+        self.stage = None;
+
+        self.send(
+            &format!("Build {}", if success { "succeeded" } else { "failed" }),
+            if success {
+                PRIORITY_INFO
+            } else {
+                PRIORITY_ERROR
+            },
+            &[("OSBUILD_SUCCESS", if success { "1" } else { "0" })],
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fields_encoded() {
+        assert_eq!(
+            encode(&[("MESSAGE", "hello"), ("PRIORITY", "6")]),
+            b"MESSAGE=hello\nPRIORITY=6\n"
+        );
+        assert_eq!(
+            encode(&[("MESSAGE", "a\nb")]),
+            b"MESSAGE\n\x03\x00\x00\x00\x00\x00\x00\x00a\nb\n"
+        );
+    }
+
+    #[test]
+    fn entries_sent() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("journal");
+        let journal = UnixDatagram::bind(&path).unwrap();
+
+        let mut monitor = JournaldMonitor::with_socket(&path).unwrap();
+
+        monitor.begin("os", &["org.osbuild.rpm".to_string()]);
+        monitor.stage(0, "org.osbuild.rpm");
+        monitor.result(0, false, Duration::from_millis(1500));
+
+        let mut entries = vec![];
+        let mut buffer = vec![0; 4096];
+
+        for _ in 0..3 {
+            let size = journal.recv(&mut buffer).unwrap();
+            entries.push(String::from_utf8(buffer[..size].to_vec()).unwrap());
+        }
+
+        assert!(entries[0].starts_with("MESSAGE=Pipeline os\nPRIORITY=6\n"));
+        assert!(entries[1].contains("OSBUILD_PIPELINE=os\nOSBUILD_STAGE=org.osbuild.rpm\n"));
+        assert!(entries[2].contains("PRIORITY=3\n"));
+        assert!(entries[2].contains("OSBUILD_STAGE_INDEX=0\n"));
+        assert!(entries[2].contains("OSBUILD_DURATION_USEC=1500000\n"));
+    }
+}
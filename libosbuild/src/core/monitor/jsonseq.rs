@@ -0,0 +1,182 @@
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::core::monitor::Monitor;
+
+/// The record separator that starts every record, see RFC 7464.
+pub const RECORD_SEPARATOR: u8 = 0x1e;
+
+/// A monitor writing progress as a JSON text sequence (RFC 7464), in the same shape as
+/// osbuild's `JSONSeqMonitor`. Every record carries a `message`, a `context` saying where
+/// it came from, the overall `progress`, and a `timestamp`; records for finished stages
+/// and builds also carry a `result`.
+pub struct JsonSeqMonitor<W: Write> {
+    output: W,
+    pipeline: String,
+    stage: Option<(usize, String)>,
+    total: usize,
+    done: usize,
+}
+
+impl<W: Write> JsonSeqMonitor<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            pipeline: String::new(),
+            stage: None,
+            total: 0,
+            done: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    fn context(&self, origin: &str) -> Value {
+        let stage = self
+            .stage
+            .as_ref()
+            .map(|(index, name)| json!({"index": index, "name": name}));
+
+        json!({
+            "origin": origin,
+            "pipeline": {
+                "name": self.pipeline,
+                "stage": stage,
+            },
+        })
+    }
+
+    fn write(&mut self, message: &str, origin: &str, result: Option<Value>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let mut record = json!({
+            "message": message,
+            "context": self.context(origin),
+            "progress": {
+                "name": format!("pipeline: {}", self.pipeline),
+                "total": self.total,
+                "done": self.done,
+            },
+            "timestamp": timestamp,
+        });
+
+        if let Some(result) = result {
+            record["result"] = result;
+        }
+
+        let mut data = vec![RECORD_SEPARATOR];
+        data.extend(record.to_string().into_bytes());
+        data.push(b'\n');
+
+        // a record is written at once so readers never see half of one
+        let _ = self.output.write_all(&data);
+        let _ = self.output.flush();
+    }
+}
+
+impl<W: Write> Monitor for JsonSeqMonitor<W> {
+    fn begin(&mut self, pipeline: &str, stages: &[String]) {
+        self.pipeline = pipeline.to_string();
+        self.stage = None;
+        self.total = stages.len();
+        self.done = 0;
+
+        self.write(
+            &format!("Starting pipeline {}", pipeline),
+            "osbuild.monitor",
+            None,
+        );
+    }
+
+    fn stage(&mut self, index: usize, name: &str) {
+        self.stage = Some((index, name.to_string()));
+
+        self.write(
+            &format!("Starting module {}", name),
+            "osbuild.monitor",
+            None,
+        );
+    }
+
+    fn log(&mut self, message: &str) {
+        self.write(message, "org.osbuild", None);
+    }
+
+    fn result(&mut self, _index: usize, success: bool, duration: Duration) {
+        self.done += 1;
+
+        let name = self
+            .stage
+            .as_ref()
+            .map(|(_, name)| name.clone())
+            .unwrap_or_default();
+
+        self.write(
+            &format!("Finished module {}", name),
+            "osbuild.monitor",
+            Some(json!({
+                "name": name,
+                "success": success,
+                "duration": duration.as_secs_f64(),
+            })),
+        );
+    }
+
+    fn finish(&mut self, success: bool) {
+        self.stage = None;
+
+        self.write(
+            &format!("Build {}", if success { "succeeded" } else { "failed" }),
+            "osbuild.main",
+            Some(json!({ "success": success })),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn records(data: Vec<u8>) -> Vec<Value> {
+        data.split(|byte| *byte == RECORD_SEPARATOR)
+            .filter(|record| !record.is_empty())
+            .map(|record| serde_json::from_slice(record).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn jsonseq_records() {
+        let mut monitor = JsonSeqMonitor::new(vec![]);
+
+        monitor.begin("build", &["org.osbuild.rpm".to_string(), "b".to_string()]);
+        monitor.stage(0, "org.osbuild.rpm");
+        monitor.log("installing");
+        monitor.result(0, true, Duration::from_secs(2));
+        monitor.finish(false);
+
+        let data = monitor.into_inner();
+
+        assert_eq!(data[0], RECORD_SEPARATOR);
+        assert!(data.ends_with(b"\n"));
+
+        let records = records(data);
+
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0]["progress"]["total"], 2);
+        assert_eq!(records[2]["message"], "installing");
+        assert_eq!(
+            records[2]["context"]["pipeline"]["stage"]["name"],
+            "org.osbuild.rpm"
+        );
+        assert_eq!(records[3]["progress"]["done"], 1);
+        assert_eq!(records[3]["result"]["success"], true);
+        assert_eq!(records[4]["result"]["success"], false);
+    }
+}
@@ -0,0 +1,260 @@
+/// Progress as a JSON text sequence, for programs embedding `osbuild`.
+pub mod jsonseq;
+
+/// Structured entries in the journal of systemd, for builds running as a service.
+#[cfg(unix)]
+pub mod journald;
+
+/// An interactive terminal interface showing the pipelines, their stages, and the logs of the
+/// running stage.
+#[cfg(feature = "tui")]
+pub mod tui;
+
+#[cfg(unix)]
+use std::fs::File;
+use std::io;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum MonitorError {
+    /// The requested monitor does not exist or was not compiled in.
+    NoSuchMonitor(String),
+
+    /// The file descriptor given for the monitor isn't open, or it's one of stdin, stdout, and
+    /// stderr.
+    InvalidFd(i32),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for MonitorError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// Monitors receive progress of a build as it happens and report it somewhere; to a human on
+/// a terminal or to a program embedding `osbuild`. Events arrive in order: `begin` for each
+/// pipeline, then `stage`, zero or more `log`, and `result` for each stage, with `finish`
+/// once the whole build is done.
+pub trait Monitor {
+    /// A pipeline starts, `stages` are the names of the stages it will run.
+    fn begin(&mut self, pipeline: &str, stages: &[String]);
+
+    /// The stage at `index` in the current pipeline starts.
+    fn stage(&mut self, index: usize, name: &str);
+
+    /// Output of the running stage.
+    fn log(&mut self, message: &str);
+
+    /// The stage at `index` in the current pipeline is done.
+    fn result(&mut self, index: usize, success: bool, duration: Duration);
+
+    /// The build is done.
+    fn finish(&mut self, success: bool);
+}
+
+/// A monitor that does nothing.
+#[derive(Debug, Default)]
+pub struct NullMonitor {}
+
+impl Monitor for NullMonitor {
+    fn begin(&mut self, _pipeline: &str, _stages: &[String]) {}
+    fn stage(&mut self, _index: usize, _name: &str) {}
+    fn log(&mut self, _message: &str) {}
+    fn result(&mut self, _index: usize, _success: bool, _duration: Duration) {}
+    fn finish(&mut self, _success: bool) {}
+}
+
+/// A monitor that writes human readable progress to a stream, usually stdout.
+pub struct LogMonitor<W: Write> {
+    output: W,
+    pipeline: String,
+    total: usize,
+}
+
+impl<W: Write> LogMonitor<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            pipeline: String::new(),
+            total: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+// Monitors must not fail a build, write errors are ignored.
+impl<W: Write> Monitor for LogMonitor<W> {
+    fn begin(&mut self, pipeline: &str, stages: &[String]) {
+        self.pipeline = pipeline.to_string();
+        self.total = stages.len();
+
+        let _ = writeln!(self.output, "Pipeline {}", pipeline);
+    }
+
+    fn stage(&mut self, index: usize, name: &str) {
+        let _ = writeln!(
+            self.output,
+            "{} [{}/{}] {}",
+            self.pipeline,
+            index + 1,
+            self.total,
+            name
+        );
+    }
+
+    fn log(&mut self, message: &str) {
+        let _ = write!(self.output, "{}", message);
+
+        if !message.ends_with('\n') {
+            let _ = writeln!(self.output);
+        }
+    }
+
+    fn result(&mut self, _index: usize, success: bool, duration: Duration) {
+        let _ = writeln!(
+            self.output,
+            "{} after {:.2}s",
+            if success { "Finished" } else { "Failed" },
+            duration.as_secs_f64()
+        );
+    }
+
+    fn finish(&mut self, success: bool) {
+        let _ = writeln!(
+            self.output,
+            "Build {}",
+            if success { "succeeded" } else { "failed" }
+        );
+        let _ = self.output.flush();
+    }
+}
+
+/// The names of the monitors `make` knows about, in this build.
+pub fn available() -> Vec<&'static str> {
+    let mut names = vec!["null", "log", "jsonseq"];
+
+    if cfg!(unix) {
+        names.push("journald");
+    }
+
+    if cfg!(feature = "tui") {
+        names.push("tui");
+    }
+
+    names
+}
+
+/// Check that `fd` is open and not one of the standard streams, and duplicate it; the
+/// monitor owns the duplicate and closing it leaves `fd` alone.
+#[cfg(unix)]
+fn duplicate(fd: i32) -> Result<File, MonitorError> {
+    // SAFETY: fcntl doesn't touch memory, it fails with EBADF for a closed descriptor.
+    if fd <= 2 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(MonitorError::InvalidFd(fd));
+    }
+
+    // SAFETY: as above, the duplicate is a new descriptor that nothing else owns.
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3) } {
+        -1 => Err(io::Error::last_os_error().into()),
+        duplicate => Ok(unsafe { File::from_raw_fd(duplicate) }),
+    }
+}
+
+/// Create a monitor by name. Monitors that write a stream write to a duplicate of `fd` when
+/// given and to stdout otherwise. File descriptors can only be given on unix.
+pub fn make(name: &str, fd: Option<i32>) -> Result<Box<dyn Monitor>, MonitorError> {
+    let output = || -> Result<Box<dyn Write>, MonitorError> {
+        match fd {
+            #[cfg(unix)]
+            Some(fd) => Ok(Box::new(duplicate(fd)?)),
+            #[cfg(not(unix))]
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "monitor file descriptors are only supported on unix",
+            )
+            .into()),
+            None => Ok(Box::new(io::stdout())),
+        }
+    };
+
+    match name {
+        "null" => Ok(Box::new(NullMonitor::default())),
+        "log" => Ok(Box::new(LogMonitor::new(output()?))),
+        "jsonseq" => Ok(Box::new(jsonseq::JsonSeqMonitor::new(output()?))),
+        #[cfg(unix)]
+        "journald" => Ok(Box::new(journald::JournaldMonitor::new()?)),
+        #[cfg(feature = "tui")]
+        "tui" => Ok(Box::new(tui::TuiMonitor::new()?)),
+        _ => Err(MonitorError::NoSuchMonitor(name.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn log_monitor_output() {
+        let mut monitor = LogMonitor::new(vec![]);
+
+        monitor.begin("build", &["org.osbuild.rpm".to_string()]);
+        monitor.stage(0, "org.osbuild.rpm");
+        monitor.log("installing");
+        monitor.result(0, true, Duration::from_millis(1500));
+        monitor.finish(true);
+
+        assert_eq!(
+            String::from_utf8(monitor.into_inner()).unwrap(),
+            "Pipeline build\nbuild [1/1] org.osbuild.rpm\ninstalling\nFinished after 1.50s\nBuild succeeded\n"
+        );
+    }
+
+    #[test]
+    fn make_monitor() {
+        assert!(make("null", None).is_ok());
+        assert!(make("log", None).is_ok());
+        assert!(make("jsonseq", None).is_ok());
+        assert!(make("journald", None).is_ok());
+        assert!(matches!(
+            make("nope", None),
+            Err(MonitorError::NoSuchMonitor(_))
+        ));
+        assert!(available().contains(&"log"));
+    }
+
+    #[test]
+    fn make_monitor_fd() {
+        use std::io::{Read, Seek};
+        use std::os::unix::io::AsRawFd;
+
+        for fd in [0, 1, 2, -1, 1 << 20] {
+            assert!(matches!(
+                make("log", Some(fd)),
+                Err(MonitorError::InvalidFd(invalid)) if invalid == fd
+            ));
+        }
+
+        let mut file = tempfile::tempfile().unwrap();
+        let mut monitor = make("log", Some(file.as_raw_fd())).unwrap();
+
+        monitor.finish(true);
+        drop(monitor);
+
+        // the monitor closed its duplicate and not the descriptor it was given
+        file.write_all(b"done\n").unwrap();
+        file.seek(io::SeekFrom::Start(0)).unwrap();
+
+        let mut output = String::new();
+        file.read_to_string(&mut output).unwrap();
+
+        assert_eq!(output, "Build succeeded\ndone\n");
+    }
+}
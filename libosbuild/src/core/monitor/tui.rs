@@ -0,0 +1,398 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::crossterm::event::{self, Event, KeyCode};
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::crossterm::ExecutableCommand;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, List, ListItem, Paragraph};
+use ratatui::{Frame, Terminal};
+
+use crate::core::monitor::Monitor;
+
+/// How many lines of log output are kept for scrolling back.
+pub const LOG_LINES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pending,
+    Running(Instant),
+    Done(bool, Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct StageState {
+    pub name: String,
+    pub status: Status,
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineState {
+    pub name: String,
+    pub stages: Vec<StageState>,
+}
+
+/// Everything the interface shows, kept separate from the terminal so it can be inspected.
+#[derive(Debug, Default)]
+pub struct State {
+    pub pipelines: Vec<PipelineState>,
+    pub logs: VecDeque<String>,
+
+    /// How many lines from the end the log view is scrolled back, 0 follows new output.
+    pub scroll: usize,
+
+    pub finished: Option<bool>,
+}
+
+impl State {
+    pub fn begin(&mut self, pipeline: &str, stages: &[String]) {
+        self.pipelines.push(PipelineState {
+            name: pipeline.to_string(),
+            stages: stages
+                .iter()
+                .map(|name| StageState {
+                    name: name.clone(),
+                    status: Status::Pending,
+                })
+                .collect(),
+        });
+    }
+
+    fn current(&mut self, index: usize, name: &str) -> Option<&mut StageState> {
+        let pipeline = self.pipelines.last_mut()?;
+
+        // stages that weren't announced in `begin` are added as they come
+        while pipeline.stages.len() <= index {
+            pipeline.stages.push(StageState {
+                name: name.to_string(),
+                status: Status::Pending,
+            });
+        }
+
+        pipeline.stages.get_mut(index)
+    }
+
+    pub fn stage(&mut self, index: usize, name: &str) {
+        if let Some(stage) = self.current(index, name) {
+            stage.status = Status::Running(Instant::now());
+        }
+    }
+
+    pub fn result(&mut self, index: usize, success: bool, duration: Duration) {
+        if let Some(stage) = self.current(index, "") {
+            stage.status = Status::Done(success, duration);
+        }
+    }
+
+    pub fn log(&mut self, message: &str) {
+        for line in message.lines() {
+            if self.logs.len() == LOG_LINES {
+                self.logs.pop_front();
+            }
+
+            self.logs.push_back(line.to_string());
+
+            // keep the view where it was when scrolled back
+            if self.scroll > 0 {
+                self.scroll = (self.scroll + 1).min(self.logs.len());
+            }
+        }
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.scroll = (self.scroll + lines).min(self.logs.len().saturating_sub(1));
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.scroll = self.scroll.saturating_sub(lines);
+    }
+
+    /// The number of stages done and the total number of stages over all pipelines.
+    pub fn progress(&self) -> (usize, usize) {
+        let stages = self.pipelines.iter().flat_map(|p| p.stages.iter());
+
+        let done = stages
+            .clone()
+            .filter(|s| matches!(s.status, Status::Done(..)))
+            .count();
+
+        (done, stages.count())
+    }
+
+    /// The log lines visible in a view of `height` lines.
+    pub fn visible_logs(&self, height: usize) -> Vec<&str> {
+        let end = self.logs.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(height);
+
+        self.logs
+            .range(start..end)
+            .map(|line| line.as_str())
+            .collect()
+    }
+
+    fn tree(&self) -> Vec<ListItem<'_>> {
+        let mut items = vec![];
+
+        for pipeline in &self.pipelines {
+            items.push(ListItem::new(Line::from(pipeline.name.clone())));
+
+            for stage in &pipeline.stages {
+                let (marker, style, duration) = match stage.status {
+                    Status::Pending => (" ", Style::default().fg(Color::DarkGray), None),
+                    Status::Running(start) => (
+                        "*",
+                        Style::default().fg(Color::Yellow),
+                        Some(start.elapsed()),
+                    ),
+                    Status::Done(true, duration) => {
+                        ("✓", Style::default().fg(Color::Green), Some(duration))
+                    }
+                    Status::Done(false, duration) => {
+                        ("✗", Style::default().fg(Color::Red), Some(duration))
+                    }
+                };
+
+                let text = match duration {
+                    Some(duration) => format!(
+                        "  {} {} ({:.1}s)",
+                        marker,
+                        stage.name,
+                        duration.as_secs_f64()
+                    ),
+                    None => format!("  {} {}", marker, stage.name),
+                };
+
+                items.push(ListItem::new(Line::styled(text, style)));
+            }
+        }
+
+        items
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        let [gauge, body] =
+            Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(frame.area());
+        let [tree, logs] =
+            Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)])
+                .areas(body);
+
+        let (done, total) = self.progress();
+        let title = match self.finished {
+            Some(true) => "osbuild: done".to_string(),
+            Some(false) => "osbuild: failed".to_string(),
+            None => format!("osbuild: {}/{} stages", done, total),
+        };
+
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title(title))
+                .gauge_style(Style::default().fg(Color::Blue))
+                .ratio(if total == 0 {
+                    0.0
+                } else {
+                    done as f64 / total as f64
+                }),
+            gauge,
+        );
+
+        frame.render_widget(
+            List::new(self.tree()).block(Block::bordered().title("Pipelines")),
+            tree,
+        );
+
+        let height = logs.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = self
+            .visible_logs(height)
+            .into_iter()
+            .map(|line| Line::from(line.to_string()))
+            .collect();
+
+        let title = if self.scroll > 0 {
+            format!("Log (-{})", self.scroll)
+        } else {
+            "Log".to_string()
+        };
+
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            logs,
+        );
+    }
+}
+
+/// A monitor drawing the build state to the terminal. The terminal is restored when the
+/// monitor is dropped. Up/Down and PageUp/PageDown scroll the log, End follows it again.
+pub struct TuiMonitor<B: Backend> {
+    terminal: Terminal<B>,
+    state: State,
+    restore: bool,
+}
+
+impl TuiMonitor<CrosstermBackend<io::Stdout>> {
+    pub fn new() -> io::Result<Self> {
+        enable_raw_mode()?;
+        io::stdout().execute(EnterAlternateScreen)?;
+
+        Ok(Self {
+            terminal: Terminal::new(CrosstermBackend::new(io::stdout()))?,
+            state: State::default(),
+            restore: true,
+        })
+    }
+}
+
+impl<B: Backend> TuiMonitor<B> {
+    /// Use an arbitrary backend, the terminal is not put into raw mode.
+    pub fn with_backend(backend: B) -> io::Result<Self> {
+        Ok(Self {
+            terminal: Terminal::new(backend)?,
+            state: State::default(),
+            restore: false,
+        })
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn backend(&self) -> &B {
+        self.terminal.backend()
+    }
+
+    fn handle_input(&mut self) {
+        if !self.restore {
+            return;
+        }
+
+        while let Ok(true) = event::poll(Duration::ZERO) {
+            if let Ok(Event::Key(key)) = event::read() {
+                match key.code {
+                    KeyCode::Up => self.state.scroll_up(1),
+                    KeyCode::Down => self.state.scroll_down(1),
+                    KeyCode::PageUp => self.state.scroll_up(20),
+                    KeyCode::PageDown => self.state.scroll_down(20),
+                    KeyCode::End => self.state.scroll = 0,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn redraw(&mut self) {
+        self.handle_input();
+
+        let state = &self.state;
+        let _ = self.terminal.draw(|frame| state.draw(frame));
+    }
+}
+
+impl<B: Backend> Drop for TuiMonitor<B> {
+    fn drop(&mut self) {
+        if self.restore {
+            let _ = disable_raw_mode();
+            let _ = io::stdout().execute(LeaveAlternateScreen);
+        }
+    }
+}
+
+impl<B: Backend> Monitor for TuiMonitor<B> {
+    fn begin(&mut self, pipeline: &str, stages: &[String]) {
+        self.state.begin(pipeline, stages);
+        self.redraw();
+    }
+
+    fn stage(&mut self, index: usize, name: &str) {
+        self.state.stage(index, name);
+        self.redraw();
+    }
+
+    fn log(&mut self, message: &str) {
+        self.state.log(message);
+        self.redraw();
+    }
+
+    fn result(&mut self, index: usize, success: bool, duration: Duration) {
+        self.state.result(index, success, duration);
+        self.redraw();
+    }
+
+    fn finish(&mut self, success: bool) {
+        self.state.finished = Some(success);
+        self.redraw();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use ratatui::backend::TestBackend;
+
+    #[test]
+    fn state_progress() {
+        let mut state = State::default();
+
+        state.begin("build", &["a".to_string(), "b".to_string()]);
+        state.stage(0, "a");
+        state.result(0, true, Duration::from_secs(1));
+
+        assert_eq!(state.progress(), (1, 2));
+
+        // unannounced stages are added
+        state.stage(2, "c");
+
+        assert_eq!(state.progress(), (1, 3));
+        assert_eq!(state.pipelines[0].stages[2].name, "c");
+    }
+
+    #[test]
+    fn state_scrolling() {
+        let mut state = State::default();
+
+        state.log("1\n2\n3\n4\n5");
+
+        assert_eq!(state.visible_logs(2), vec!["4", "5"]);
+
+        state.scroll_up(2);
+        assert_eq!(state.visible_logs(2), vec!["2", "3"]);
+
+        // new output does not move a scrolled back view
+        state.log("6");
+        assert_eq!(state.visible_logs(2), vec!["2", "3"]);
+
+        state.scroll_down(100);
+        assert_eq!(state.visible_logs(2), vec!["5", "6"]);
+
+        state.scroll_up(100);
+        assert_eq!(state.visible_logs(2), vec!["1"]);
+    }
+
+    #[test]
+    fn monitor_draws() {
+        let mut monitor = TuiMonitor::with_backend(TestBackend::new(80, 12)).unwrap();
+
+        monitor.begin("build", &["org.osbuild.rpm".to_string()]);
+        monitor.stage(0, "org.osbuild.rpm");
+        monitor.log("installing bash");
+        monitor.result(0, true, Duration::from_secs(2));
+        monitor.finish(true);
+
+        let content: String = monitor
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+
+        assert!(content.contains("osbuild: done"));
+        assert!(content.contains("org.osbuild.rpm (2.0s)"));
+        assert!(content.contains("installing bash"));
+    }
+}
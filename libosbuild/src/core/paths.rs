@@ -0,0 +1,253 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Variable of the directory workspaces are created in when none is configured.
+pub const WORKSPACE_ROOT_VARIABLE: &str = "OSBUILD_WORKSPACE";
+
+/// Directory below the runtime directory of the user that workspaces are created in.
+pub const RUNTIME_DIRECTORY_NAME: &str = "osbuild";
+
+/// Longest path of a unix socket, `sun_path` of `struct sockaddr_un` without its terminator.
+pub const SOCKET_PATH_MAX: usize = 107;
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The directory workspaces are created in by default; `$OSBUILD_WORKSPACE`, the `osbuild`
+/// directory in `$XDG_RUNTIME_DIR`, or the temporary directory of the system.
+pub fn default_root() -> PathBuf {
+    if let Some(root) = env::var_os(WORKSPACE_ROOT_VARIABLE) {
+        return PathBuf::from(root);
+    }
+
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) => PathBuf::from(runtime).join(RUNTIME_DIRECTORY_NAME),
+        None => env::temp_dir(),
+    }
+}
+
+/// Names in a workspace are a single file name, so they can't point outside of it.
+fn check_name(name: &str) -> io::Result<()> {
+    let mut components = Path::new(name).components();
+
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not a name in a workspace", name),
+        )),
+    }
+}
+
+/// Remove `path` and everything below it, also directories that aren't writable such as
+/// those of trees that stages made read-only.
+pub fn remove_all(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            crate::module::util::tree::walk(path, &mut |path, metadata| {
+                if metadata.is_dir() {
+                    let mode = metadata.permissions().mode() | 0o700;
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+                }
+
+                Ok(())
+            })?;
+
+            fs::remove_dir_all(path)
+        }
+        result => result,
+    }
+}
+
+/// The private directory of a single build, for everything it needs on the host while it
+/// runs; the runtime directory of the executor, sockets, staging trees, and logs. Every
+/// workspace gets a directory of its own in the root so builds running at the same time
+/// don't collide, and it is removed with everything in it when the workspace is dropped.
+#[derive(Debug)]
+pub struct Workspace {
+    path: PathBuf,
+    runtime: PathBuf,
+    keep: bool,
+}
+
+impl Workspace {
+    /// Create a workspace in `root`, which is created when it doesn't exist.
+    pub fn new(root: &Path) -> io::Result<Self> {
+        fs::create_dir_all(root)?;
+
+        loop {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.subsec_nanos())
+                .unwrap_or_default();
+
+            let path = root.join(format!(
+                "build-{}-{}-{:08x}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed),
+                nanos
+            ));
+
+            match fs::create_dir(&path) {
+                Ok(()) => {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(0o700))?;
+
+                    let runtime = path.join("run");
+                    fs::create_dir(&runtime)?;
+
+                    return Ok(Self {
+                        path,
+                        runtime,
+                        keep: false,
+                    });
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Create a workspace in `default_root`.
+    pub fn in_default_root() -> io::Result<Self> {
+        Self::new(&default_root())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The runtime directory of the executor, inputs and mounts go below it.
+    pub fn runtime(&self) -> &Path {
+        &self.runtime
+    }
+
+    /// The path of the socket `name`. Sockets can't have long paths, a workspace in a root
+    /// that makes them too long is an error.
+    pub fn socket(&self, name: &str) -> io::Result<PathBuf> {
+        check_name(name)?;
+
+        let directory = self.path.join("sockets");
+        fs::create_dir_all(&directory)?;
+
+        let path = directory.join(format!("{}.sock", name));
+
+        if path.as_os_str().len() > SOCKET_PATH_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("socket path '{}' is too long", path.display()),
+            ));
+        }
+
+        Ok(path)
+    }
+
+    /// A new, empty, staging tree `name`. A tree of that name that is still there is removed.
+    pub fn tree(&self, name: &str) -> io::Result<PathBuf> {
+        check_name(name)?;
+
+        let directory = self.path.join("trees");
+        fs::create_dir_all(&directory)?;
+
+        let path = directory.join(name);
+
+        match remove_all(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+
+        fs::create_dir(&path)?;
+
+        Ok(path)
+    }
+
+    /// The path of the log `name`.
+    pub fn log(&self, name: &str) -> io::Result<PathBuf> {
+        check_name(name)?;
+
+        let directory = self.path.join("logs");
+        fs::create_dir_all(&directory)?;
+
+        Ok(directory.join(format!("{}.log", name)))
+    }
+
+    /// Keep the workspace when it is dropped, to look at what a build left behind. Returns
+    /// its path.
+    pub fn keep(&mut self) -> &Path {
+        self.keep = true;
+        &self.path
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = remove_all(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn workspaces_separate() {
+        let root = tempfile::tempdir().unwrap();
+
+        let a = Workspace::new(&root.path().join("run")).unwrap();
+        let b = Workspace::new(&root.path().join("run")).unwrap();
+
+        assert_ne!(a.path(), b.path());
+        assert!(a.runtime().is_dir());
+        assert_eq!(
+            fs::metadata(a.path()).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+
+        assert_eq!(a.socket("api").unwrap(), a.path().join("sockets/api.sock"));
+        assert_eq!(a.log("build").unwrap(), a.path().join("logs/build.log"));
+
+        for name in ["", "..", "a/b", "/etc"] {
+            assert!(a.socket(name).is_err(), "{}", name);
+            assert!(a.tree(name).is_err(), "{}", name);
+        }
+
+        let long = Workspace::new(&root.path().join("a".repeat(100))).unwrap();
+
+        assert!(long.socket("api").is_err());
+    }
+
+    #[test]
+    fn workspaces_removed() {
+        let root = tempfile::tempdir().unwrap();
+        let workspace = Workspace::new(root.path()).unwrap();
+        let path = workspace.path().to_path_buf();
+
+        let tree = workspace.tree("os").unwrap();
+        fs::create_dir(tree.join("etc")).unwrap();
+        fs::write(tree.join("etc/hostname"), "").unwrap();
+        fs::set_permissions(tree.join("etc"), fs::Permissions::from_mode(0o500)).unwrap();
+
+        // a tree is empty every time it is asked for
+        assert_eq!(workspace.tree("os").unwrap(), tree);
+        assert!(!tree.join("etc").exists());
+
+        fs::create_dir(tree.join("etc")).unwrap();
+        fs::set_permissions(tree.join("etc"), fs::Permissions::from_mode(0o500)).unwrap();
+
+        drop(workspace);
+
+        assert!(!path.exists());
+
+        let mut kept = Workspace::new(root.path()).unwrap();
+        let path = kept.keep().to_path_buf();
+
+        drop(kept);
+
+        assert!(path.is_dir());
+    }
+}
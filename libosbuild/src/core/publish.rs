@@ -0,0 +1,486 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+#[cfg(feature = "aws")]
+use std::io::Write;
+
+use serde::Serialize;
+
+#[cfg(feature = "aws")]
+use crate::core::secrets::{self, Secret};
+
+#[derive(Debug)]
+pub enum PublishError {
+    /// An artifact isn't a file with a name.
+    InvalidArtifact(PathBuf),
+
+    /// The publisher failed, contains its name, the artifact, the exit code of the tool it
+    /// runs, and its stderr.
+    Failed(String, String, Option<i32>, String),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for PublishError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// An artifact that was published.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Published {
+    pub publisher: String,
+    pub artifact: String,
+
+    /// Where the artifact can be found now, such as `s3://bucket/disk.raw`.
+    pub location: String,
+}
+
+/// Somewhere exported artifacts are uploaded to, such as a bucket or a container registry.
+/// Publishers run after everything is exported, in the order they are configured in.
+pub trait ArtifactPublisher: fmt::Debug + Send + Sync {
+    /// The name of the publisher, for errors and logs.
+    fn name(&self) -> &str;
+
+    /// Whether the publisher takes the artifact called `artifact`.
+    fn accepts(&self, _artifact: &str) -> bool {
+        true
+    }
+
+    /// Upload the file at `path` as `artifact`, returns where it went.
+    fn publish(&self, path: &Path, artifact: &str) -> Result<String, PublishError>;
+}
+
+/// Run `command` with `stdin`, failures are those of `publisher` for `artifact`.
+fn run(
+    mut command: Command,
+    stdin: &[u8],
+    publisher: &str,
+    artifact: &str,
+) -> Result<(), PublishError> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut input) = child.stdin.take() {
+        io::Write::write_all(&mut input, stdin)?;
+    }
+
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        return Err(PublishError::Failed(
+            publisher.to_string(),
+            artifact.to_string(),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Publish `artifacts` with every publisher that accepts them.
+pub fn publish(
+    publishers: &[Arc<dyn ArtifactPublisher>],
+    artifacts: &[PathBuf],
+) -> Result<Vec<Published>, PublishError> {
+    let mut published = vec![];
+
+    for publisher in publishers {
+        for path in artifacts {
+            let artifact = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| PublishError::InvalidArtifact(path.clone()))?;
+
+            if !publisher.accepts(artifact) {
+                continue;
+            }
+
+            published.push(Published {
+                publisher: publisher.name().to_string(),
+                artifact: artifact.to_string(),
+                location: publisher.publish(path, artifact)?,
+            });
+        }
+    }
+
+    Ok(published)
+}
+
+/// Pushes OCI archives, artifacts ending in `.tar`, to a container registry with skopeo.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    pub skopeo: PathBuf,
+
+    /// Where to push to, such as `quay.io/org/image:latest`.
+    pub reference: String,
+
+    /// The credentials for the registry, in the format of `containers-auth.json(5)`.
+    pub authfile: Option<PathBuf>,
+}
+
+impl Registry {
+    pub fn new(reference: &str) -> Self {
+        Self {
+            skopeo: PathBuf::from("skopeo"),
+            reference: reference.to_string(),
+            authfile: None,
+        }
+    }
+
+    /// The skopeo command that pushes the archive at `path`.
+    pub fn command(&self, path: &Path) -> Command {
+        let mut command = Command::new(&self.skopeo);
+
+        command.args(["copy", "--quiet"]);
+
+        if let Some(authfile) = &self.authfile {
+            command.arg("--authfile").arg(authfile);
+        }
+
+        command
+            .arg(format!("oci-archive:{}", path.display()))
+            .arg(format!("docker://{}", self.reference));
+
+        command
+    }
+}
+
+impl ArtifactPublisher for Registry {
+    fn name(&self) -> &str {
+        "registry"
+    }
+
+    fn accepts(&self, artifact: &str) -> bool {
+        artifact.ends_with(".tar")
+    }
+
+    fn publish(&self, path: &Path, artifact: &str) -> Result<String, PublishError> {
+        run(self.command(path), &[], self.name(), artifact)?;
+
+        Ok(format!("docker://{}", self.reference))
+    }
+}
+
+/// Uploads artifacts to an S3 bucket with curl, which signs the requests. The credentials
+/// are handed to curl on stdin so they don't show up in the arguments of the process.
+#[cfg(feature = "aws")]
+#[derive(Debug, Clone)]
+pub struct S3 {
+    pub curl: PathBuf,
+    pub bucket: String,
+    pub region: String,
+
+    /// Prepended to the names of artifacts, such as `images/`.
+    pub prefix: String,
+
+    /// The endpoint of the service, `https://s3.<region>.amazonaws.com` when unset. Buckets
+    /// are addressed by path, so other implementations of S3 work as well.
+    pub endpoint: Option<String>,
+
+    pub access_key: String,
+    pub secret_key: Secret,
+    pub session_token: Option<Secret>,
+}
+
+#[cfg(feature = "aws")]
+impl S3 {
+    pub fn new(bucket: &str, region: &str, access_key: &str, secret_key: Secret) -> Self {
+        Self {
+            curl: PathBuf::from("curl"),
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            prefix: String::new(),
+            endpoint: None,
+            access_key: access_key.to_string(),
+            secret_key,
+            session_token: None,
+        }
+    }
+
+    /// A publisher with the credentials in `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, and
+    /// `AWS_SESSION_TOKEN`, if they are set.
+    pub fn from_env(bucket: &str, region: &str) -> Option<Self> {
+        let access_key = std::env::var("AWS_ACCESS_KEY_ID").ok()?;
+        let secret_key = std::env::var("AWS_SECRET_ACCESS_KEY").ok()?;
+
+        Some(Self {
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok().map(Secret::new),
+            ..Self::new(bucket, region, &access_key, Secret::new(secret_key))
+        })
+    }
+
+    /// The URL of `artifact` in the bucket.
+    pub fn url(&self, artifact: &str) -> String {
+        let endpoint = self
+            .endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", self.region));
+
+        format!(
+            "{}/{}/{}{}",
+            endpoint.trim_end_matches('/'),
+            self.bucket,
+            self.prefix,
+            artifact
+        )
+    }
+
+    /// The curl command that uploads the file at `path` as `artifact`, it reads its
+    /// configuration from stdin, see `S3::config`.
+    pub fn command(&self, path: &Path, artifact: &str) -> Command {
+        let mut command = Command::new(&self.curl);
+
+        command
+            .args(["--silent", "--show-error", "--fail"])
+            .arg("--aws-sigv4")
+            .arg(format!("aws:amz:{}:s3", self.region))
+            .args(["--config", "-", "--upload-file"])
+            .arg(path)
+            .arg("--")
+            .arg(self.url(artifact));
+
+        command
+    }
+
+    /// The configuration curl reads from stdin, with the credentials.
+    pub fn config(&self) -> Vec<u8> {
+        fn quoted(value: &str) -> String {
+            value.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+
+        let mut config = vec![];
+
+        let _ = writeln!(
+            config,
+            "user = \"{}:{}\"",
+            quoted(&self.access_key),
+            quoted(self.secret_key.expose_str().unwrap_or_default())
+        );
+
+        if let Some(token) = &self.session_token {
+            let _ = writeln!(
+                config,
+                "header = \"x-amz-security-token: {}\"",
+                quoted(token.expose_str().unwrap_or_default())
+            );
+        }
+
+        config
+    }
+}
+
+#[cfg(feature = "aws")]
+impl ArtifactPublisher for S3 {
+    fn name(&self) -> &str {
+        "s3"
+    }
+
+    fn publish(&self, path: &Path, artifact: &str) -> Result<String, PublishError> {
+        let mut secrets = vec![&self.secret_key];
+        secrets.extend(&self.session_token);
+
+        run(
+            self.command(path, artifact),
+            &self.config(),
+            self.name(),
+            artifact,
+        )
+        .map_err(|err| match err {
+            PublishError::Failed(publisher, artifact, code, stderr) => PublishError::Failed(
+                publisher,
+                artifact,
+                code,
+                secrets::redact(&stderr, &secrets),
+            ),
+            err => err,
+        })?;
+
+        Ok(format!("s3://{}/{}{}", self.bucket, self.prefix, artifact))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+    use std::sync::Mutex;
+
+    use crate::core::BuildConfig;
+
+    /// Publishes by remembering what it was asked to publish.
+    #[derive(Debug, Default)]
+    struct Recorder {
+        published: Mutex<Vec<String>>,
+    }
+
+    impl ArtifactPublisher for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn accepts(&self, artifact: &str) -> bool {
+            artifact != "skipped"
+        }
+
+        fn publish(&self, _path: &Path, artifact: &str) -> Result<String, PublishError> {
+            self.published.lock().unwrap().push(artifact.to_string());
+
+            Ok(format!("recorded:{}", artifact))
+        }
+    }
+
+    #[test]
+    fn artifacts_published() {
+        let recorder = Arc::new(Recorder::default());
+        let publishers: Vec<Arc<dyn ArtifactPublisher>> = vec![recorder.clone()];
+
+        let published = publish(
+            &publishers,
+            &[
+                PathBuf::from("/out/disk.raw"),
+                PathBuf::from("/out/skipped"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            published,
+            [Published {
+                publisher: "recorder".to_string(),
+                artifact: "disk.raw".to_string(),
+                location: "recorded:disk.raw".to_string(),
+            }]
+        );
+        assert_eq!(*recorder.published.lock().unwrap(), ["disk.raw"]);
+    }
+
+    #[test]
+    fn registry_pushed() {
+        let registry = Registry {
+            authfile: Some(PathBuf::from("/run/auth.json")),
+            ..Registry::new("quay.io/org/image:latest")
+        };
+
+        assert!(registry.accepts("container.tar"));
+        assert!(!registry.accepts("disk.qcow2"));
+
+        let command = registry.command(Path::new("/out/container.tar"));
+
+        assert_eq!(
+            command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>(),
+            [
+                "copy",
+                "--quiet",
+                "--authfile",
+                "/run/auth.json",
+                "oci-archive:/out/container.tar",
+                "docker://quay.io/org/image:latest",
+            ]
+        );
+    }
+
+    #[test]
+    fn registry_failed() {
+        let directory = tempfile::tempdir().unwrap();
+        let artifact = directory.path().join("container.tar");
+
+        fs::write(&artifact, "").unwrap();
+
+        let registry = Registry {
+            skopeo: PathBuf::from("false"),
+            ..Registry::new("quay.io/org/image")
+        };
+
+        assert!(matches!(
+            registry.publish(&artifact, "container.tar"),
+            Err(PublishError::Failed(publisher, artifact, Some(1), _))
+                if publisher == "registry" && artifact == "container.tar"
+        ));
+    }
+
+    #[test]
+    fn exports_published() {
+        let directory = tempfile::tempdir().unwrap();
+        let artifact = directory.path().join("container/container.tar");
+
+        fs::create_dir(directory.path().join("container")).unwrap();
+        fs::write(&artifact, "").unwrap();
+
+        let config = BuildConfig {
+            publishers: vec![Arc::new(Registry {
+                skopeo: PathBuf::from("true"),
+                ..Registry::new("quay.io/org/image")
+            })],
+            ..BuildConfig::new(directory.path().to_path_buf())
+        };
+
+        assert_eq!(
+            config
+                .finish_export("container", &[artifact])
+                .unwrap()
+                .published,
+            [Published {
+                publisher: "registry".to_string(),
+                artifact: "container.tar".to_string(),
+                location: "docker://quay.io/org/image".to_string(),
+            }]
+        );
+    }
+
+    #[cfg(feature = "aws")]
+    #[test]
+    fn s3_uploaded() {
+        let s3 = S3 {
+            prefix: "images/".to_string(),
+            session_token: Some(Secret::new("token")),
+            ..S3::new("bucket", "eu-west-1", "AKID", Secret::new("se\"cret"))
+        };
+
+        assert_eq!(
+            s3.url("disk.raw"),
+            "https://s3.eu-west-1.amazonaws.com/bucket/images/disk.raw"
+        );
+
+        let args: Vec<String> = s3
+            .command(Path::new("/out/disk.raw"), "disk.raw")
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--aws-sigv4", "aws:amz:eu-west-1:s3"]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--upload-file", "/out/disk.raw"]));
+        assert!(!args.iter().any(|arg| arg.contains("cret")));
+
+        assert_eq!(
+            String::from_utf8(s3.config()).unwrap(),
+            "user = \"AKID:se\\\"cret\"\nheader = \"x-amz-security-token: token\"\n"
+        );
+
+        let minio = S3 {
+            endpoint: Some("http://localhost:9000/".to_string()),
+            ..S3::new("bucket", "us-east-1", "AKID", Secret::new("secret"))
+        };
+
+        assert_eq!(
+            minio.url("disk.raw"),
+            "http://localhost:9000/bucket/disk.raw"
+        );
+    }
+}
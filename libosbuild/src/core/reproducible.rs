@@ -0,0 +1,128 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::manifest::Pipeline;
+
+/// The variable tools read the time to use instead of the current time from, see
+/// <https://reproducible-builds.org/specs/source-date-epoch/>.
+pub const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// The `SOURCE_DATE_EPOCH` of a pipeline; the one the pipeline sets in the manifest, or the
+/// configured one.
+pub fn source_date_epoch(pipeline: &Pipeline, configured: Option<u64>) -> Option<u64> {
+    pipeline.source_epoch().or(configured)
+}
+
+/// Set the modification time of `path`, and of everything below it if it is a directory, to
+/// `epoch` where it is later. Symlinks are left alone as setting their time would set the
+/// time of what they point to. Returns how many timestamps were clamped.
+pub fn clamp_timestamps(path: &Path, epoch: u64) -> io::Result<usize> {
+    let limit = UNIX_EPOCH + Duration::from_secs(epoch);
+    let metadata = fs::symlink_metadata(path)?;
+
+    if metadata.file_type().is_symlink() {
+        return Ok(0);
+    }
+
+    let mut clamped = 0;
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            clamped += clamp_timestamps(&entry?.path(), epoch)?;
+        }
+    }
+
+    // clamp directories after their contents, changing those updates their time
+    if metadata.modified()? > limit {
+        set_modified(path, limit)?;
+        clamped += 1;
+    }
+
+    Ok(clamped)
+}
+
+fn set_modified(path: &Path, time: SystemTime) -> io::Result<()> {
+    let file = match fs::File::options().write(true).open(path) {
+        Ok(file) => file,
+        // directories and read-only files can't be opened for writing
+        Err(_) => fs::File::open(path)?,
+    };
+
+    file.set_modified(time)
+}
+
+/// Arguments for GNU tar to produce the same archive of the same tree: entries in name order,
+/// owned by root, and no timestamps later than `epoch`.
+pub fn tar_args(epoch: u64) -> Vec<String> {
+    vec![
+        "--sort=name".to_string(),
+        format!("--mtime=@{}", epoch),
+        "--clamp-mtime".to_string(),
+        "--owner=0".to_string(),
+        "--group=0".to_string(),
+        "--numeric-owner".to_string(),
+        "--pax-option=exthdr.name=%d/PaxHeaders/%f,delete=atime,delete=ctime".to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn epoch_of_pipeline() {
+        let mut pipeline = Pipeline::new("os");
+
+        assert_eq!(source_date_epoch(&pipeline, None), None);
+        assert_eq!(source_date_epoch(&pipeline, Some(10)), Some(10));
+
+        pipeline.set_source_epoch(Some(5));
+
+        assert_eq!(source_date_epoch(&pipeline, Some(10)), Some(5));
+    }
+
+    #[test]
+    fn clamped_timestamps() {
+        let directory = tempfile::tempdir().unwrap();
+        let tree = directory.path().join("tree");
+
+        fs::create_dir_all(tree.join("etc")).unwrap();
+        fs::write(tree.join("etc/hostname"), "localhost\n").unwrap();
+        fs::write(tree.join("old"), "").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(tree.join("old"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(100))
+            .unwrap();
+
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("/nonexistent", tree.join("link")).unwrap();
+
+        // the tree, etc, and hostname are newer than the epoch; old is older
+        assert_eq!(clamp_timestamps(&tree, 1000).unwrap(), 3);
+
+        for path in [&tree, &tree.join("etc"), &tree.join("etc/hostname")] {
+            assert_eq!(
+                fs::metadata(path).unwrap().modified().unwrap(),
+                UNIX_EPOCH + Duration::from_secs(1000)
+            );
+        }
+
+        assert_eq!(
+            fs::metadata(tree.join("old")).unwrap().modified().unwrap(),
+            UNIX_EPOCH + Duration::from_secs(100)
+        );
+        assert_eq!(clamp_timestamps(&tree, 1000).unwrap(), 0);
+    }
+
+    #[test]
+    fn reproducible_tar() {
+        let args = tar_args(1700000000);
+
+        assert!(args.contains(&"--mtime=@1700000000".to_string()));
+        assert!(args.contains(&"--clamp-mtime".to_string()));
+    }
+}
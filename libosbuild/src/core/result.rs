@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::accounting::ResourceUsage;
+use crate::core::executor::changes::ChangeSet;
+use crate::manifest::description::validation::Severity;
+
+/// Why a build failed. Every kind maps to a stable exit code of the `osbuild` binary so
+/// scripts can tell failures apart without looking at their output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    /// Something went wrong that isn't any of the other kinds.
+    Internal,
+
+    /// The manifest, or the options of one of its stages, did not validate.
+    Validation,
+
+    /// A module the manifest uses could not be found.
+    MissingModule,
+
+    /// A stage ran and failed.
+    StageFailure,
+
+    /// The build was interrupted before it finished.
+    Cancelled,
+}
+
+impl FailureKind {
+    /// The exit code of the `osbuild` binary for this kind of failure. Exit code 2 is left
+    /// for wrong command line usage.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Internal => 1,
+            Self::Validation => 3,
+            Self::MissingModule => 4,
+            Self::StageFailure => 5,
+            Self::Cancelled => 130,
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Internal => "internal error",
+            Self::Validation => "validation failed",
+            Self::MissingModule => "missing module",
+            Self::StageFailure => "stage failed",
+            Self::Cancelled => "cancelled",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(FailureKind::Internal, message)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+/// Something a build reports that doesn't fail it by itself, such as a deprecation or a
+/// condition a stage could work around.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.severity.name(), self.message)
+    }
+}
+
+/// The outcome of a build.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuildResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<Failure>,
+
+    /// Names of the environment variables stages were run with.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub environment: Vec<String>,
+
+    /// The `SOURCE_DATE_EPOCH` each pipeline was built with, by pipeline name; building
+    /// with the same ones gives the same artifacts.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub source_date_epochs: BTreeMap<String, u64>,
+
+    /// The fs-verity digests of exported artifacts by artifact name, when fs-verity was
+    /// enabled on them.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub verity_digests: BTreeMap<String, String>,
+
+    /// The options each stage that ran was run with, with the defaults of its schema filled
+    /// in, by stage id.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub effective_options: BTreeMap<String, serde_json::Value>,
+
+    /// What each stage that ran changed in its tree, by stage id, when changes were tracked.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub changes: BTreeMap<String, ChangeSet>,
+
+    /// What building each pipeline cost, by pipeline name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub resources: BTreeMap<String, ResourceUsage>,
+
+    /// The warnings and infos of the build, in the order they were reported.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl BuildResult {
+    pub fn success() -> Self {
+        Self::default()
+    }
+
+    pub fn failed(kind: FailureKind, message: impl Into<String>) -> Self {
+        Failure::new(kind, message).into()
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// Report `message` without failing the build, errors should be failures instead.
+    pub fn diagnose(&mut self, severity: Severity, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            message: message.into(),
+        });
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+    }
+
+    /// Fail a successful build that has warnings, for `--deny-warnings`. The first warning
+    /// is the message of the failure.
+    pub fn deny_warnings(&mut self) {
+        if self.failure.is_some() {
+            return;
+        }
+
+        let failure = self.warnings().next().map(|warning| {
+            Failure::new(
+                FailureKind::Validation,
+                format!("warnings denied: {}", warning.message),
+            )
+        });
+
+        self.failure = failure;
+    }
+
+    /// Account `usage` to `pipeline`, on top of what it already cost.
+    pub fn account(&mut self, pipeline: &str, usage: &ResourceUsage) {
+        self.resources
+            .entry(pipeline.to_string())
+            .or_default()
+            .add(usage);
+    }
+
+    /// What the whole build cost.
+    pub fn total_resources(&self) -> ResourceUsage {
+        let mut total = ResourceUsage::default();
+
+        for usage in self.resources.values() {
+            total.add(usage);
+        }
+
+        total
+    }
+
+    /// The exit code of the `osbuild` binary for this result.
+    pub fn exit_code(&self) -> i32 {
+        self.failure
+            .as_ref()
+            .map(|failure| failure.kind.exit_code())
+            .unwrap_or(0)
+    }
+}
+
+impl From<Failure> for BuildResult {
+    fn from(failure: Failure) -> Self {
+        Self {
+            failure: Some(failure),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<BuildResult> for Result<(), Failure> {
+    fn from(result: BuildResult) -> Self {
+        match result.failure {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(BuildResult::success().exit_code(), 0);
+        assert_eq!(
+            BuildResult::failed(FailureKind::Validation, "bad").exit_code(),
+            3
+        );
+        assert_eq!(
+            BuildResult::failed(FailureKind::Cancelled, "interrupted").exit_code(),
+            130
+        );
+
+        let kinds = [
+            FailureKind::Internal,
+            FailureKind::Validation,
+            FailureKind::MissingModule,
+            FailureKind::StageFailure,
+            FailureKind::Cancelled,
+        ];
+
+        // codes are distinct and never collide with success or usage errors
+        for (index, kind) in kinds.iter().enumerate() {
+            assert!(![0, 2].contains(&kind.exit_code()));
+            assert!(kinds[index + 1..]
+                .iter()
+                .all(|other| other.exit_code() != kind.exit_code()));
+        }
+    }
+
+    #[test]
+    fn result_serializes() {
+        assert_eq!(
+            serde_json::to_value(BuildResult::failed(
+                FailureKind::MissingModule,
+                "org.osbuild.nope"
+            ))
+            .unwrap(),
+            serde_json::json!({"failure": {"kind": "missing-module", "message": "org.osbuild.nope"}})
+        );
+        assert_eq!(
+            serde_json::to_value(BuildResult::success()).unwrap(),
+            serde_json::json!({})
+        );
+
+        let result = BuildResult {
+            environment: vec!["PATH".to_string(), "SOURCE_DATE_EPOCH".to_string()],
+            source_date_epochs: BTreeMap::from([("os".to_string(), 1700000000)]),
+            effective_options: BTreeMap::from([(
+                "abc".to_string(),
+                serde_json::json!({"exist_ok": false}),
+            )]),
+            ..BuildResult::success()
+        };
+
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({
+                "environment": ["PATH", "SOURCE_DATE_EPOCH"],
+                "source_date_epochs": {"os": 1700000000},
+                "effective_options": {"abc": {"exist_ok": false}}
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<BuildResult>(serde_json::to_value(&result).unwrap()).unwrap(),
+            result
+        );
+    }
+
+    #[test]
+    fn resources_accounted() {
+        let mut result = BuildResult::success();
+        let stage = ResourceUsage {
+            cpu_usec: 10,
+            memory_peak_bytes: 100,
+            ..Default::default()
+        };
+
+        result.account("os", &stage);
+        result.account("os", &stage);
+        result.account(
+            "image",
+            &ResourceUsage {
+                downloaded_bytes: 5,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(result.resources["os"].cpu_usec, 20);
+        assert_eq!(result.resources["os"].memory_peak_bytes, 100);
+        assert_eq!(
+            result.total_resources(),
+            ResourceUsage {
+                cpu_usec: 20,
+                memory_peak_bytes: 100,
+                downloaded_bytes: 5,
+                ..Default::default()
+            }
+        );
+
+        // results of builds that didn't account anything, or only some of it, still load
+        let loaded: BuildResult = serde_json::from_value(serde_json::json!({
+            "resources": {"os": {"cpu_usec": 20}},
+        }))
+        .unwrap();
+
+        assert_eq!(loaded.resources["os"].cpu_usec, 20);
+    }
+
+    #[test]
+    fn warnings_denied() {
+        let mut result = BuildResult::success();
+
+        result.diagnose(Severity::Info, "using a cached build root");
+        result.deny_warnings();
+
+        assert!(result.is_success());
+
+        result.diagnose(Severity::Warning, "org.osbuild.noop is deprecated");
+
+        assert_eq!(
+            serde_json::to_value(&result).unwrap()["diagnostics"][1],
+            serde_json::json!({"severity": "warning", "message": "org.osbuild.noop is deprecated"})
+        );
+        assert_eq!(
+            result.diagnostics[1].to_string(),
+            "warning: org.osbuild.noop is deprecated"
+        );
+
+        result.deny_warnings();
+
+        assert_eq!(result.exit_code(), 3);
+        assert_eq!(
+            result.failure.unwrap().message,
+            "warnings denied: org.osbuild.noop is deprecated"
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+/// Where the os-release of the host is read from, in order of preference, see os-release(5).
+pub const OS_RELEASE_PATHS: &[&str] = &["/etc/os-release", "/usr/lib/os-release"];
+
+/// The runner used when there is none for the host's distribution.
+pub const FALLBACK_RUNNER: &str = "org.osbuild.linux";
+
+const RUNNER_PREFIX: &str = "org.osbuild.";
+
+#[derive(Debug)]
+pub enum RunnerError {
+    /// A pipeline asked for a runner that is not available, with the runners that are.
+    NoSuchRunner(String, Vec<String>),
+
+    /// There is no runner for the host, nor a fallback.
+    NoHostRunner(String),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for RunnerError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// Parse the contents of an os-release file into its keys and (unquoted) values.
+pub fn parse_os_release(data: &str) -> BTreeMap<String, String> {
+    data.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .or_else(|| {
+                    value
+                        .strip_prefix('\'')
+                        .and_then(|value| value.strip_suffix('\''))
+                })
+                .unwrap_or(value);
+
+            (key.to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// The name of the runner for a distribution described by an os-release, such as
+/// `org.osbuild.fedora38` or `org.osbuild.rhel92`.
+pub fn runner_name(os_release: &BTreeMap<String, String>) -> String {
+    let id = os_release.get("ID").map(String::as_str).unwrap_or("linux");
+    let version = os_release
+        .get("VERSION_ID")
+        .map(|version| version.replace('.', ""))
+        .unwrap_or_default();
+
+    format!("{}{}{}", RUNNER_PREFIX, id, version)
+}
+
+/// The name of the runner for the host.
+pub fn host_runner_name() -> Result<String, RunnerError> {
+    for path in OS_RELEASE_PATHS {
+        match fs::read_to_string(path) {
+            Ok(data) => return Ok(runner_name(&parse_os_release(&data))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(FALLBACK_RUNNER.to_string())
+}
+
+/// Split a runner name into its distribution and version, `org.osbuild.fedora38` is
+/// `("fedora", Some(38))`.
+fn split(name: &str) -> (&str, Option<u32>) {
+    let name = name.strip_prefix(RUNNER_PREFIX).unwrap_or(name);
+    let distro = name.trim_end_matches(|c: char| c.is_ascii_digit());
+
+    (distro, name[distro.len()..].parse().ok())
+}
+
+/// Pick the runner for `host` from `available`: the runner of the host itself, or else the
+/// runner of the newest version of the host's distribution that isn't newer than the host,
+/// or else the generic linux runner.
+pub fn detect(host: &str, available: &[String]) -> Result<String, RunnerError> {
+    if available.iter().any(|name| name == host) {
+        return Ok(host.to_string());
+    }
+
+    let (distro, version) = split(host);
+
+    let candidate = available
+        .iter()
+        .filter_map(|name| match split(name) {
+            (other, Some(other_version)) if other == distro => Some((other_version, name)),
+            _ => None,
+        })
+        .filter(|(other_version, _)| version.is_none_or(|version| *other_version <= version))
+        .max_by_key(|(other_version, _)| *other_version);
+
+    match candidate {
+        Some((_, name)) => Ok(name.to_string()),
+        None if available.iter().any(|name| name == FALLBACK_RUNNER) => {
+            Ok(FALLBACK_RUNNER.to_string())
+        }
+        None => Err(RunnerError::NoHostRunner(host.to_string())),
+    }
+}
+
+/// The runner a pipeline runs with: the one it asks for, which has to be available, and
+/// otherwise the one detected for `host`.
+pub fn select(
+    requested: Option<&str>,
+    host: &str,
+    available: &[String],
+) -> Result<String, RunnerError> {
+    match requested {
+        Some(name) if available.iter().any(|other| other == name) => Ok(name.to_string()),
+        Some(name) => Err(RunnerError::NoSuchRunner(
+            name.to_string(),
+            available.to_vec(),
+        )),
+        None => detect(host, available),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn available() -> Vec<String> {
+        [
+            "org.osbuild.fedora30",
+            "org.osbuild.fedora38",
+            "org.osbuild.rhel82",
+            "org.osbuild.linux",
+        ]
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
+    }
+
+    #[test]
+    fn os_release() {
+        let os_release = parse_os_release(
+            "# comment\nNAME=\"Red Hat Enterprise Linux\"\nID=rhel\nVERSION_ID='9.2'\n",
+        );
+
+        assert_eq!(os_release["NAME"], "Red Hat Enterprise Linux");
+        assert_eq!(runner_name(&os_release), "org.osbuild.rhel92");
+        assert_eq!(runner_name(&BTreeMap::new()), "org.osbuild.linux");
+    }
+
+    #[test]
+    fn detect_host_runner() {
+        let available = available();
+
+        assert_eq!(
+            detect("org.osbuild.fedora38", &available).unwrap(),
+            "org.osbuild.fedora38"
+        );
+        assert_eq!(
+            detect("org.osbuild.fedora37", &available).unwrap(),
+            "org.osbuild.fedora30"
+        );
+        assert_eq!(
+            detect("org.osbuild.fedora40", &available).unwrap(),
+            "org.osbuild.fedora38"
+        );
+        assert_eq!(
+            detect("org.osbuild.rhel92", &available).unwrap(),
+            "org.osbuild.rhel82"
+        );
+        assert_eq!(
+            detect("org.osbuild.arch", &available).unwrap(),
+            "org.osbuild.linux"
+        );
+        assert!(matches!(
+            detect("org.osbuild.arch", &available[..2]),
+            Err(RunnerError::NoHostRunner(_))
+        ));
+    }
+
+    #[test]
+    fn requested_runner_overrides_detection() {
+        let available = available();
+
+        assert_eq!(
+            select(
+                Some("org.osbuild.fedora30"),
+                "org.osbuild.fedora38",
+                &available
+            )
+            .unwrap(),
+            "org.osbuild.fedora30"
+        );
+        assert_eq!(
+            select(None, "org.osbuild.fedora38", &available).unwrap(),
+            "org.osbuild.fedora38"
+        );
+
+        let err = select(
+            Some("org.osbuild.centos9"),
+            "org.osbuild.fedora38",
+            &available,
+        )
+        .unwrap_err();
+
+        assert!(
+            matches!(err, RunnerError::NoSuchRunner(name, available) if name == "org.osbuild.centos9" && available.len() == 4)
+        );
+    }
+}
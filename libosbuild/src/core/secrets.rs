@@ -0,0 +1,326 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Prefix of environment variables the `EnvProvider` reads secrets from.
+pub const ENV_PREFIX: &str = "OSBUILD_SECRET_";
+
+/// Environment variable systemd sets to the directory with the credentials of a service,
+/// see `LoadCredential=` in systemd.exec(5).
+pub const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+
+#[derive(Debug)]
+pub enum SecretError {
+    /// No provider has a secret by this name.
+    NotFound(String),
+
+    /// The name can't be used as a secret name, names may not contain path separators.
+    InvalidName(String),
+
+    /// The value of the secret by this name can't be used where it's asked for, such as
+    /// credentials that aren't UTF-8.
+    InvalidValue(String),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for SecretError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// The value of a secret. It doesn't implement `Display` and its `Debug` output doesn't
+/// contain the value, so it can't end up in logs by accident.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(value: impl Into<Vec<u8>>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The value as text with surrounding whitespace, such as a trailing newline in a file,
+    /// removed.
+    pub fn expose_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok().map(str::trim)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+/// A TLS client certificate and its private key, both PEM encoded.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub certificate: Secret,
+    pub key: Secret,
+}
+
+/// Somewhere secrets are kept. Sources ask providers for secrets by name, e.g.
+/// `registry.example.com` for a container registry, so the secrets themselves never have to
+/// be part of a manifest.
+pub trait Provider {
+    /// The secret called `name`, `None` when this provider doesn't have it.
+    fn get(&self, name: &str) -> Result<Option<Secret>, SecretError>;
+}
+
+fn check_name(name: &str) -> Result<(), SecretError> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(SecretError::InvalidName(name.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Secrets in environment variables, `registry.example.com` is read from
+/// `OSBUILD_SECRET_REGISTRY_EXAMPLE_COM`.
+#[derive(Debug, Default)]
+pub struct EnvProvider {}
+
+impl EnvProvider {
+    pub fn variable(name: &str) -> String {
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        format!("{}{}", ENV_PREFIX, name)
+    }
+}
+
+impl Provider for EnvProvider {
+    fn get(&self, name: &str) -> Result<Option<Secret>, SecretError> {
+        check_name(name)?;
+
+        Ok(env::var_os(Self::variable(name)).map(|value| Secret::new(value.into_encoded_bytes())))
+    }
+}
+
+/// Secrets in files in a directory, one file per secret named after the secret.
+#[derive(Debug)]
+pub struct FileProvider {
+    pub directory: PathBuf,
+}
+
+impl FileProvider {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+}
+
+impl Provider for FileProvider {
+    fn get(&self, name: &str) -> Result<Option<Secret>, SecretError> {
+        check_name(name)?;
+
+        match fs::read(self.directory.join(name)) {
+            Ok(value) => Ok(Some(Secret::new(value))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Credentials passed to the service by systemd, these are files in `$CREDENTIALS_DIRECTORY`.
+#[derive(Debug)]
+pub struct SystemdProvider {
+    files: Option<FileProvider>,
+}
+
+impl SystemdProvider {
+    /// A provider for the credentials of the current service, when not running as a service
+    /// with credentials it has no secrets.
+    pub fn new() -> Self {
+        Self {
+            files: env::var_os(CREDENTIALS_DIRECTORY)
+                .map(|directory| FileProvider::new(PathBuf::from(directory))),
+        }
+    }
+}
+
+impl Default for SystemdProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Provider for SystemdProvider {
+    fn get(&self, name: &str) -> Result<Option<Secret>, SecretError> {
+        match &self.files {
+            Some(files) => files.get(name),
+            None => {
+                check_name(name)?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// An ordered list of providers, a secret is taken from the first provider that has it.
+#[derive(Default)]
+pub struct Secrets {
+    providers: Vec<Box<dyn Provider>>,
+}
+
+impl Secrets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Credentials from systemd first, then the environment.
+    pub fn from_environment() -> Self {
+        let mut secrets = Self::new();
+
+        secrets.add(Box::new(SystemdProvider::new()));
+        secrets.add(Box::new(EnvProvider::default()));
+
+        secrets
+    }
+
+    pub fn add(&mut self, provider: Box<dyn Provider>) {
+        self.providers.push(provider);
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Secret>, SecretError> {
+        for provider in &self.providers {
+            if let Some(secret) = provider.get(name)? {
+                return Ok(Some(secret));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Like `get` but a missing secret is an error.
+    pub fn require(&self, name: &str) -> Result<Secret, SecretError> {
+        self.get(name)?
+            .ok_or_else(|| SecretError::NotFound(name.to_string()))
+    }
+
+    /// The value of an `Authorization` header for `name`; the secret is used as is.
+    pub fn auth_header(&self, name: &str) -> Result<Option<Secret>, SecretError> {
+        self.get(&format!("{}.auth", name))
+    }
+
+    /// The client certificate for `name`, made from the secrets `<name>.crt` and `<name>.key`.
+    /// Having only one of the two is an error.
+    pub fn client_certificate(&self, name: &str) -> Result<Option<ClientCertificate>, SecretError> {
+        let certificate = self.get(&format!("{}.crt", name))?;
+        let key = self.get(&format!("{}.key", name))?;
+
+        match (certificate, key) {
+            (Some(certificate), Some(key)) => Ok(Some(ClientCertificate { certificate, key })),
+            (None, None) => Ok(None),
+            (Some(_), None) => Err(SecretError::NotFound(format!("{}.key", name))),
+            (None, Some(_)) => Err(SecretError::NotFound(format!("{}.crt", name))),
+        }
+    }
+}
+
+/// Replace every occurrence of the secrets' values in `text`, for output that may contain them
+/// such as the stderr of a failed download.
+pub fn redact(text: &str, secrets: &[&Secret]) -> String {
+    let mut text = text.to_string();
+
+    for secret in secrets {
+        if let Some(value) = secret.expose_str().filter(|value| !value.is_empty()) {
+            text = text.replace(value, "<redacted>");
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secret_is_redacted() {
+        let secret = Secret::new("hunter2\n");
+
+        assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+        assert_eq!(secret.expose_str(), Some("hunter2"));
+        assert_eq!(
+            redact("curl: 401 for token hunter2", &[&secret]),
+            "curl: 401 for token <redacted>"
+        );
+    }
+
+    #[test]
+    fn env_provider() {
+        assert_eq!(
+            EnvProvider::variable("registry.example.com"),
+            "OSBUILD_SECRET_REGISTRY_EXAMPLE_COM"
+        );
+
+        env::set_var("OSBUILD_SECRET_LIBOSBUILD_TEST_ENV", "token");
+
+        let secret = EnvProvider::default().get("libosbuild-test-env").unwrap();
+        assert_eq!(secret, Some(Secret::new("token")));
+        assert_eq!(
+            EnvProvider::default()
+                .get("libosbuild-test-missing")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn file_providers_in_order() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+
+        fs::write(first.path().join("mirror.auth"), "Bearer first").unwrap();
+        fs::write(second.path().join("mirror.auth"), "Bearer second").unwrap();
+        fs::write(second.path().join("mirror.crt"), "cert").unwrap();
+        fs::write(second.path().join("mirror.key"), "key").unwrap();
+        fs::write(second.path().join("broken.crt"), "cert").unwrap();
+
+        let mut secrets = Secrets::new();
+        secrets.add(Box::new(FileProvider::new(first.path().to_path_buf())));
+        secrets.add(Box::new(FileProvider::new(second.path().to_path_buf())));
+
+        assert_eq!(
+            secrets.auth_header("mirror").unwrap(),
+            Some(Secret::new("Bearer first"))
+        );
+        assert_eq!(
+            secrets
+                .client_certificate("mirror")
+                .unwrap()
+                .unwrap()
+                .key
+                .expose(),
+            b"key"
+        );
+        assert!(secrets.client_certificate("other").unwrap().is_none());
+        assert!(matches!(
+            secrets.client_certificate("broken"),
+            Err(SecretError::NotFound(_))
+        ));
+        assert!(matches!(
+            secrets.require("nope"),
+            Err(SecretError::NotFound(_))
+        ));
+        assert!(matches!(
+            secrets.get("../etc/shadow"),
+            Err(SecretError::InvalidName(_))
+        ));
+    }
+}
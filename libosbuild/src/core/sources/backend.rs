@@ -0,0 +1,412 @@
+#[cfg(feature = "torrent")]
+use std::fs;
+#[cfg(feature = "torrent")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "torrent")]
+use std::process::{Command, Stdio};
+
+use crate::core::sources::download::{Download, DownloadError, Downloader};
+
+/// The URL schemes curl fetches for the built-in backend.
+pub const CURL_SCHEMES: &[&str] = &["http", "https", "ftp", "file"];
+
+#[derive(Debug)]
+pub enum BackendError {
+    /// No backend fetches URLs of the scheme, contains the scheme.
+    NoBackend(String),
+
+    /// The URL has no scheme.
+    InvalidUrl(String),
+
+    /// The backend failed, contains its name and the error.
+    Failed(String, DownloadError),
+
+    /// The build is offline and the item isn't in the cache, contains its URL.
+    Offline(String),
+}
+
+/// A way of fetching the items of sources, such as over HTTP or from peers. Backends are
+/// picked by the scheme of the URL of an item.
+pub trait SourceBackend: Send + Sync {
+    /// The name of the backend, for errors and logs.
+    fn name(&self) -> &str;
+
+    /// The URL schemes the backend fetches, such as `https` or `magnet`.
+    fn schemes(&self) -> Vec<String>;
+
+    /// Fetch the item at the URL of `download` to its destination, verifying its checksum.
+    /// A destination that already exists is left alone.
+    fn fetch(&self, download: &Download) -> Result<(), DownloadError>;
+}
+
+impl SourceBackend for Downloader {
+    fn name(&self) -> &str {
+        "curl"
+    }
+
+    fn schemes(&self) -> Vec<String> {
+        CURL_SCHEMES
+            .iter()
+            .map(|scheme| scheme.to_string())
+            .collect()
+    }
+
+    fn fetch(&self, download: &Download) -> Result<(), DownloadError> {
+        Downloader::fetch(self, download)
+    }
+}
+
+/// The scheme of `url`, lower case.
+pub fn scheme(url: &str) -> Option<String> {
+    let (scheme, _) = url.split_once(':')?;
+
+    (!scheme.is_empty()
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)))
+    .then(|| scheme.to_ascii_lowercase())
+}
+
+/// The backends sources fetch with, registered at runtime so transports that few builds
+/// need, such as BitTorrent, stay out of the core. A backend registered later takes the
+/// schemes of those before it.
+#[derive(Default)]
+pub struct Backends {
+    backends: Vec<Box<dyn SourceBackend>>,
+    offline: bool,
+}
+
+impl Backends {
+    /// A registry without any backends.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry with `downloader` as the backend for the schemes curl fetches.
+    pub fn with_downloader(downloader: Downloader) -> Self {
+        let mut backends = Self::new();
+        backends.register(Box::new(downloader));
+        backends
+    }
+
+    /// Refuse to fetch with any of the backends, for air-gapped builds. Items that are in
+    /// the cache already are still fine.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    pub fn register(&mut self, backend: Box<dyn SourceBackend>) {
+        self.backends.push(backend);
+    }
+
+    /// The names of the registered backends, in the order they were registered.
+    pub fn names(&self) -> Vec<&str> {
+        self.backends.iter().map(|backend| backend.name()).collect()
+    }
+
+    /// The backend that fetches `url`.
+    pub fn for_url(&self, url: &str) -> Result<&dyn SourceBackend, BackendError> {
+        let scheme = scheme(url).ok_or_else(|| BackendError::InvalidUrl(url.to_string()))?;
+
+        self.backends
+            .iter()
+            .rev()
+            .find(|backend| backend.schemes().contains(&scheme))
+            .map(|backend| backend.as_ref())
+            .ok_or(BackendError::NoBackend(scheme))
+    }
+
+    /// Fetch `download` with the backend for its URL.
+    pub fn fetch(&self, download: &Download) -> Result<(), BackendError> {
+        if self.offline && !download.destination.exists() {
+            return Err(BackendError::Offline(download.url.clone()));
+        }
+
+        let backend = self.for_url(&download.url)?;
+
+        backend
+            .fetch(download)
+            .map_err(|err| BackendError::Failed(backend.name().to_string(), err))
+    }
+}
+
+/// Fetches `ipfs://<cid>/<path>` through an HTTP gateway, for mirrors that distribute over
+/// IPFS without running a node on the build host. Experimental.
+#[cfg(feature = "ipfs")]
+pub struct IpfsGateway {
+    /// The gateway, such as `https://ipfs.io`.
+    pub gateway: String,
+    pub downloader: Downloader,
+}
+
+#[cfg(feature = "ipfs")]
+impl IpfsGateway {
+    pub fn new(gateway: &str, downloader: Downloader) -> Self {
+        Self {
+            gateway: gateway.to_string(),
+            downloader,
+        }
+    }
+
+    /// The URL of `url` on the gateway, `None` for URLs that aren't `ipfs://`.
+    pub fn gateway_url(&self, url: &str) -> Option<String> {
+        let path = url.strip_prefix("ipfs://")?;
+
+        Some(format!(
+            "{}/ipfs/{}",
+            self.gateway.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        ))
+    }
+}
+
+#[cfg(feature = "ipfs")]
+impl SourceBackend for IpfsGateway {
+    fn name(&self) -> &str {
+        "ipfs"
+    }
+
+    fn schemes(&self) -> Vec<String> {
+        vec!["ipfs".to_string()]
+    }
+
+    fn fetch(&self, download: &Download) -> Result<(), DownloadError> {
+        let url = self.gateway_url(&download.url).ok_or_else(|| {
+            DownloadError::Failed(None, format!("'{}' is not an ipfs url", download.url))
+        })?;
+
+        self.downloader.fetch(&Download {
+            url,
+            ..download.clone()
+        })
+    }
+}
+
+/// Fetches magnet links over BitTorrent with `aria2c`, for sharing
+/// large artifacts between build hosts in a network without a mirror. Only torrents of a
+/// single file are supported, and nothing is seeded once it is fetched. Experimental.
+#[cfg(feature = "torrent")]
+pub struct Torrent {
+    pub aria2c: PathBuf,
+
+    /// How long to wait for peers before giving up, in seconds.
+    pub timeout: u64,
+}
+
+#[cfg(feature = "torrent")]
+impl Default for Torrent {
+    fn default() -> Self {
+        Self {
+            aria2c: PathBuf::from("aria2c"),
+            timeout: 600,
+        }
+    }
+}
+
+#[cfg(feature = "torrent")]
+impl Torrent {
+    /// The aria2c command that fetches `download` to its partial path.
+    pub fn command(&self, download: &Download) -> Command {
+        let partial = download.partial_path();
+        let mut command = Command::new(&self.aria2c);
+
+        command
+            .args(["--quiet", "--seed-time=0", "--follow-torrent=mem"])
+            .arg(format!("--bt-stop-timeout={}", self.timeout))
+            .arg(format!(
+                "--dir={}",
+                partial.parent().unwrap_or(Path::new(".")).display()
+            ))
+            .arg(format!(
+                "--out={}",
+                partial.file_name().unwrap_or_default().to_string_lossy()
+            ))
+            .arg("--")
+            .arg(&download.url);
+
+        command
+    }
+}
+
+#[cfg(feature = "torrent")]
+impl SourceBackend for Torrent {
+    fn name(&self) -> &str {
+        "torrent"
+    }
+
+    fn schemes(&self) -> Vec<String> {
+        vec!["magnet".to_string()]
+    }
+
+    fn fetch(&self, download: &Download) -> Result<(), DownloadError> {
+        if download.destination.exists() {
+            return Ok(());
+        }
+
+        let output = self
+            .command(download)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()?;
+
+        if !output.status.success() {
+            let _ = fs::remove_file(download.partial_path());
+
+            return Err(DownloadError::Failed(
+                output.status.code(),
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        download.complete()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    use crate::core::sources::limit::Limits;
+
+    struct Recording {
+        name: &'static str,
+        schemes: &'static [&'static str],
+        fetched: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl SourceBackend for Recording {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn schemes(&self) -> Vec<String> {
+            self.schemes.iter().map(|s| s.to_string()).collect()
+        }
+
+        fn fetch(&self, download: &Download) -> Result<(), DownloadError> {
+            self.fetched
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", self.name, download.url));
+
+            fs::write(download.partial_path(), &download.url)?;
+
+            download.complete()
+        }
+    }
+
+    fn download(directory: &std::path::Path, url: &str, checksum: Option<&str>) -> Download {
+        Download {
+            url: url.to_string(),
+            destination: directory.join("out"),
+            checksum: checksum.map(String::from),
+        }
+    }
+
+    #[test]
+    fn schemes_parsed() {
+        assert_eq!(scheme("HTTPS://example.com").as_deref(), Some("https"));
+        assert_eq!(scheme("magnet:?xt=urn:btih:aa").as_deref(), Some("magnet"));
+        assert_eq!(scheme("git+ssh://host/repo").as_deref(), Some("git+ssh"));
+        assert_eq!(scheme("/path/to/file"), None);
+        assert_eq!(scheme(":nothing"), None);
+    }
+
+    #[test]
+    fn backends_registered() {
+        let directory = tempfile::tempdir().unwrap();
+        let fetched = Arc::new(Mutex::new(vec![]));
+
+        let mut backends = Backends::with_downloader(Downloader::new(
+            "org.osbuild.curl",
+            Arc::new(Limits::default()),
+        ));
+
+        assert!(matches!(
+            backends.fetch(&download(directory.path(), "ipfs://bafy/a", None)),
+            Err(BackendError::NoBackend(scheme)) if scheme == "ipfs"
+        ));
+        assert!(matches!(
+            backends.for_url("nothing"),
+            Err(BackendError::InvalidUrl(_))
+        ));
+
+        backends.register(Box::new(Recording {
+            name: "peers",
+            schemes: &["ipfs", "https"],
+            fetched: fetched.clone(),
+        }));
+
+        assert_eq!(backends.names(), ["curl", "peers"]);
+        assert_eq!(backends.for_url("file:///a").unwrap().name(), "curl");
+
+        // the backend registered later takes over https
+        backends
+            .fetch(&download(directory.path(), "https://example.com/a", None))
+            .unwrap();
+
+        assert_eq!(*fetched.lock().unwrap(), ["peers https://example.com/a"]);
+        assert_eq!(
+            fs::read_to_string(directory.path().join("out")).unwrap(),
+            "https://example.com/a"
+        );
+
+        fs::remove_file(directory.path().join("out")).unwrap();
+
+        assert!(matches!(
+            backends.fetch(&download(directory.path(), "ipfs://bafy/a", Some("sha256:00"))),
+            Err(BackendError::Failed(name, DownloadError::ChecksumMismatch(_))) if name == "peers"
+        ));
+        assert!(!directory.path().join("out.part").exists());
+    }
+
+    #[cfg(feature = "ipfs")]
+    #[test]
+    fn ipfs_through_gateway() {
+        let gateway = IpfsGateway::new(
+            "https://ipfs.example.com/",
+            Downloader::new("org.osbuild.curl", Arc::new(Limits::default())),
+        );
+
+        assert_eq!(
+            gateway
+                .gateway_url("ipfs://bafybeigdyrzt/boot.iso")
+                .as_deref(),
+            Some("https://ipfs.example.com/ipfs/bafybeigdyrzt/boot.iso")
+        );
+        assert_eq!(gateway.gateway_url("https://example.com"), None);
+    }
+
+    #[cfg(feature = "torrent")]
+    #[test]
+    fn torrent_fetched() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let aria2c = directory.path().join("aria2c");
+
+        // writes the url to the file named by --dir and --out
+        fs::write(
+            &aria2c,
+            "#!/bin/sh\nfor arg; do case $arg in --dir=*) dir=${arg#--dir=};; --out=*) out=${arg#--out=};; esac; url=$arg; done\nprintf %s \"$url\" > \"$dir/$out\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&aria2c, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let torrent = Torrent {
+            aria2c,
+            ..Torrent::default()
+        };
+        let magnet = "magnet:?xt=urn:btih:aa";
+        let download = download(directory.path(), magnet, None);
+
+        torrent.fetch(&download).unwrap();
+
+        assert_eq!(fs::read_to_string(&download.destination).unwrap(), magnet);
+        assert!(!download.partial_path().exists());
+    }
+}
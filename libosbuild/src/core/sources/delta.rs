@@ -0,0 +1,366 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::core::sources::download::{Download, DownloadError, Downloader};
+
+/// Suffix of the directory a delta download is assembled in, next to its destination.
+pub const DELTA_SUFFIX: &str = ".delta";
+
+/// The directory casync keeps the chunks of an index in by default, next to the index.
+pub const CASYNC_STORE: &str = "default.castr";
+
+/// How an artifact is split into chunks that can be fetched on their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaFormat {
+    /// zchunk files are their own index, `zckdl` fetches the chunks a seed doesn't have
+    /// with range requests.
+    Zchunk,
+
+    /// casync indexes a blob in a `.caibx` file, the chunks are in a store of their own.
+    Casync,
+}
+
+impl DeltaFormat {
+    /// The format of the index at `url`, by its extension.
+    pub fn detect(url: &str) -> Option<Self> {
+        let path = url.split(['?', '#']).next().unwrap_or_default();
+
+        if path.ends_with(".zck") {
+            Some(Self::Zchunk)
+        } else if path.ends_with(".caibx") {
+            Some(Self::Casync)
+        } else {
+            None
+        }
+    }
+}
+
+/// What a delta download needs; the index of the artifact and earlier versions of it whose
+/// chunks are reused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    pub format: DeltaFormat,
+    pub index: String,
+
+    /// Where casync finds the chunks, `default.castr` next to the index when not given.
+    pub store: Option<String>,
+
+    /// Files with chunks of the artifact, such as the version an earlier build downloaded.
+    pub seeds: Vec<PathBuf>,
+}
+
+impl Delta {
+    /// A delta download of the artifact indexed at `index`, `None` when the format of the
+    /// index isn't known.
+    pub fn new(index: &str, seeds: &[PathBuf]) -> Option<Self> {
+        Some(Self {
+            format: DeltaFormat::detect(index)?,
+            index: index.to_string(),
+            store: None,
+            seeds: seeds.to_vec(),
+        })
+    }
+
+    /// The store of casync, see `store`.
+    pub fn store(&self) -> String {
+        self.store.clone().unwrap_or_else(|| {
+            let base = self.index.rsplit_once('/').map_or("", |(base, _)| base);
+
+            format!("{}/{}", base, CASYNC_STORE)
+        })
+    }
+}
+
+/// How a file was fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fetched {
+    /// The destination already existed.
+    Existing,
+
+    /// Only the chunks the seeds didn't have were downloaded.
+    Delta,
+
+    /// The whole file was downloaded, because there were no seeds or fetching the delta
+    /// failed.
+    Full,
+}
+
+/// Fetches artifacts with `zckdl` or `casync` when an earlier version is at hand, so that
+/// repeated builds only download what changed of large artifacts such as installer images.
+/// Whenever that fails the whole file is downloaded instead. The delta tools download on
+/// their own, the rate limits and the quota only apply to full downloads.
+pub struct DeltaFetcher<'d> {
+    pub downloader: &'d Downloader,
+    pub zckdl: PathBuf,
+    pub casync: PathBuf,
+}
+
+impl<'d> DeltaFetcher<'d> {
+    pub fn new(downloader: &'d Downloader) -> Self {
+        Self {
+            downloader,
+            zckdl: PathBuf::from("zckdl"),
+            casync: PathBuf::from("casync"),
+        }
+    }
+
+    /// The command that assembles the artifact `delta` indexes in the directory `work` from
+    /// `seeds`. `zckdl` takes a single seed, the first is used.
+    pub fn command(&self, delta: &Delta, seeds: &[PathBuf], work: &Path) -> Command {
+        let mut command = match delta.format {
+            DeltaFormat::Zchunk => {
+                let mut command = Command::new(&self.zckdl);
+
+                command.arg("--fail-no-ranges");
+
+                if let Some(seed) = seeds.first() {
+                    command.arg(format!("--source={}", seed.display()));
+                }
+
+                command.arg("--").arg(&delta.index).current_dir(work);
+                command
+            }
+            DeltaFormat::Casync => {
+                let mut command = Command::new(&self.casync);
+
+                command.arg("extract");
+
+                for seed in seeds {
+                    command.arg(format!("--seed={}", seed.display()));
+                }
+
+                command
+                    .arg(format!("--store={}", delta.store()))
+                    .arg(&delta.index)
+                    .arg(work.join("artifact"));
+                command
+            }
+        };
+
+        command.envs(
+            self.downloader
+                .environment
+                .iter()
+                .map(|(key, value)| (key, value)),
+        );
+        command
+    }
+
+    fn attempt(
+        &self,
+        download: &Download,
+        delta: &Delta,
+        seeds: &[PathBuf],
+        work: &Path,
+    ) -> Result<(), DownloadError> {
+        fs::create_dir_all(work)?;
+
+        let output = self
+            .command(delta, seeds, work)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()?;
+
+        if !output.status.success() {
+            return Err(DownloadError::Failed(
+                output.status.code(),
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        // the tools name what they assemble themselves, it is the only file in `work`
+        let artifact = fs::read_dir(work)?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "nothing was fetched"))??
+            .path();
+
+        download.verify(&artifact)?;
+
+        fs::rename(&artifact, &download.destination)?;
+
+        Ok(())
+    }
+
+    /// Fetch `download` with `delta` from the seeds that exist, or the whole of it when
+    /// there are none or fetching the delta fails. A destination that already exists is
+    /// left alone.
+    pub fn fetch(&self, download: &Download, delta: &Delta) -> Result<Fetched, DownloadError> {
+        if download.destination.exists() {
+            return Ok(Fetched::Existing);
+        }
+
+        let seeds: Vec<PathBuf> = delta
+            .seeds
+            .iter()
+            .filter(|seed| seed.is_file())
+            .cloned()
+            .collect();
+
+        if !seeds.is_empty() && !self.downloader.offline {
+            let mut work = download.destination.as_os_str().to_os_string();
+            work.push(DELTA_SUFFIX);
+
+            let work = PathBuf::from(work);
+            let attempt = self.attempt(download, delta, &seeds, &work);
+
+            let _ = fs::remove_dir_all(&work);
+
+            if attempt.is_ok() {
+                return Ok(Fetched::Delta);
+            }
+        }
+
+        self.downloader.fetch(download)?;
+
+        Ok(Fetched::Full)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+
+    use crate::core::export;
+    use crate::core::sources::limit::Limits;
+
+    fn downloader() -> Downloader {
+        Downloader {
+            retries: 0,
+            ..Downloader::new("org.osbuild.curl", Arc::new(Limits::default()))
+        }
+    }
+
+    fn tool(directory: &Path, script: &str) -> PathBuf {
+        let path = directory.join("tool");
+
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn delta_formats() {
+        assert_eq!(
+            DeltaFormat::detect("https://example.com/repodata/primary.xml.zck"),
+            Some(DeltaFormat::Zchunk)
+        );
+        assert_eq!(
+            DeltaFormat::detect("https://example.com/boot.iso.caibx?token=1"),
+            Some(DeltaFormat::Casync)
+        );
+        assert_eq!(DeltaFormat::detect("https://example.com/boot.iso"), None);
+
+        let delta = Delta::new("https://example.com/images/boot.iso.caibx", &[]).unwrap();
+
+        assert_eq!(delta.store(), "https://example.com/images/default.castr");
+
+        let args: Vec<String> = DeltaFetcher::new(&downloader())
+            .command(
+                &delta,
+                &[PathBuf::from("/a.iso"), PathBuf::from("/b.iso")],
+                Path::new("/work"),
+            )
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+
+        assert_eq!(
+            args,
+            [
+                "extract",
+                "--seed=/a.iso",
+                "--seed=/b.iso",
+                "--store=https://example.com/images/default.castr",
+                "https://example.com/images/boot.iso.caibx",
+                "/work/artifact",
+            ]
+        );
+    }
+
+    #[test]
+    fn delta_fetched() {
+        let directory = tempfile::tempdir().unwrap();
+        let seed = directory.path().join("seed.zck");
+        fs::write(&seed, "hello, world").unwrap();
+
+        let download = Download {
+            url: "https://example.com/artifact.zck".to_string(),
+            destination: directory.path().join("out"),
+            checksum: Some(format!("sha256:{}", export::sha256(&seed).unwrap())),
+        };
+
+        let downloader = downloader();
+        let fetcher = DeltaFetcher {
+            zckdl: tool(
+                directory.path(),
+                "#!/bin/sh\nfor arg; do case $arg in --source=*) cp \"${arg#--source=}\" artifact.zck;; esac; done\n",
+            ),
+            ..DeltaFetcher::new(&downloader)
+        };
+        let delta = Delta::new(&download.url, &[directory.path().join("missing"), seed]).unwrap();
+
+        assert_eq!(fetcher.fetch(&download, &delta).unwrap(), Fetched::Delta);
+        assert_eq!(
+            fs::read_to_string(&download.destination).unwrap(),
+            "hello, world"
+        );
+        assert_eq!(fetcher.fetch(&download, &delta).unwrap(), Fetched::Existing);
+        assert!(!directory.path().join("out.delta").exists());
+    }
+
+    #[test]
+    fn delta_falls_back() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("artifact.zck");
+        let seed = directory.path().join("seed.zck");
+        fs::write(&source, "hello, world").unwrap();
+        fs::write(&seed, "hello, old world").unwrap();
+
+        let download = Download {
+            url: format!("file://{}", source.display()),
+            destination: directory.path().join("out"),
+            checksum: Some(format!("sha256:{}", export::sha256(&source).unwrap())),
+        };
+
+        let downloader = downloader();
+        let delta = Delta::new(&download.url, &[seed]).unwrap();
+
+        // the tool fails, is missing, or assembles something that doesn't verify
+        for zckdl in [
+            tool(
+                directory.path(),
+                "#!/bin/sh\necho 'no ranges' >&2\nexit 1\n",
+            ),
+            directory.path().join("missing"),
+            tool(directory.path(), "#!/bin/sh\necho broken > artifact.zck\n"),
+        ] {
+            let fetcher = DeltaFetcher {
+                zckdl,
+                ..DeltaFetcher::new(&downloader)
+            };
+
+            assert_eq!(fetcher.fetch(&download, &delta).unwrap(), Fetched::Full);
+            assert_eq!(
+                fs::read_to_string(&download.destination).unwrap(),
+                "hello, world"
+            );
+
+            fs::remove_file(&download.destination).unwrap();
+        }
+
+        // without seeds there is nothing to reuse
+        assert_eq!(
+            DeltaFetcher::new(&downloader)
+                .fetch(&download, &Delta::new(&download.url, &[]).unwrap())
+                .unwrap(),
+            Fetched::Full
+        );
+    }
+}
@@ -0,0 +1,414 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use crate::core::export::ExportError;
+use crate::core::sources;
+use crate::core::sources::limit::{LimitedReader, Limits};
+use crate::core::sources::mirror::{self, Mirror, MirrorError};
+
+/// Suffix of the file a download is written to until it is complete and verified.
+pub const PARTIAL_SUFFIX: &str = ".part";
+
+/// Exit code of curl when the server does not support resuming, see curl(1).
+pub const CURL_RANGE_ERROR: i32 = 33;
+
+#[derive(Debug)]
+pub enum DownloadError {
+    /// curl failed, contains its exit code and stderr.
+    Failed(Option<i32>, String),
+
+    /// The downloaded file doesn't match the expected checksum, contains the actual one.
+    ChecksumMismatch(String),
+
+    /// The checksum isn't of an algorithm in `sources::CHECKSUM_ALGORITHMS`.
+    UnsupportedChecksum(String),
+
+    /// The build is offline and the item isn't in the cache, contains its URL.
+    Offline(String),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+impl From<ExportError> for DownloadError {
+    fn from(err: ExportError) -> Self {
+        match err {
+            ExportError::IOError(err) => Self::IOError(err),
+            err => Self::IOError(io::Error::other(format!("{:?}", err))),
+        }
+    }
+}
+
+/// A single file to download.
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub url: String,
+    pub destination: PathBuf,
+
+    /// Expected checksum as `<algorithm>:<hex>`, see `sources::CHECKSUM_ALGORITHMS`, a partial download that fails to verify is
+    /// removed so the next attempt starts over.
+    pub checksum: Option<String>,
+}
+
+impl Download {
+    pub fn partial_path(&self) -> PathBuf {
+        let mut name = self.destination.as_os_str().to_os_string();
+        name.push(PARTIAL_SUFFIX);
+        PathBuf::from(name)
+    }
+
+    /// Check the file at `path` against the checksum, if there is one.
+    pub fn verify(&self, path: &Path) -> Result<(), DownloadError> {
+        let Some(checksum) = &self.checksum else {
+            return Ok(());
+        };
+
+        let actual = sources::checksum_of(path, checksum)?
+            .ok_or_else(|| DownloadError::UnsupportedChecksum(checksum.clone()))?;
+
+        if actual != *checksum {
+            return Err(DownloadError::ChecksumMismatch(actual));
+        }
+
+        Ok(())
+    }
+
+    /// Verify the file fetched to the partial path and move it to the destination, or
+    /// remove it when it doesn't verify.
+    pub fn complete(&self) -> Result<(), DownloadError> {
+        let partial = self.partial_path();
+
+        if let Err(err) = self.verify(&partial) {
+            fs::remove_file(&partial)?;
+            return Err(err);
+        }
+
+        fs::rename(&partial, &self.destination)?;
+
+        Ok(())
+    }
+}
+
+/// Downloads files with curl, continuing partial downloads left by an earlier attempt with
+/// a range request. Servers that don't support ranges get the whole file again.
+pub struct Downloader {
+    pub curl: PathBuf,
+    pub source: String,
+    pub limits: Arc<Limits>,
+
+    /// Environment for curl, such as proxy settings.
+    pub environment: Vec<(String, String)>,
+
+    /// How many more times to try after a failed attempt, each attempt resumes.
+    pub retries: usize,
+
+    /// Refuse to download anything, only destinations that exist are fine.
+    pub offline: bool,
+}
+
+impl Downloader {
+    pub fn new(source: &str, limits: Arc<Limits>) -> Self {
+        Self {
+            curl: PathBuf::from("curl"),
+            source: source.to_string(),
+            limits,
+            environment: vec![],
+            retries: 3,
+            offline: false,
+        }
+    }
+
+    /// The curl command to fetch `url` from `offset` onwards, the body is written to stdout.
+    pub fn command(&self, url: &str, offset: u64) -> Command {
+        let mut command = Command::new(&self.curl);
+
+        command
+            .args(["--silent", "--show-error", "--fail", "--location"])
+            .args(["--connect-timeout", "30"])
+            .envs(self.environment.iter().map(|(k, v)| (k, v)));
+
+        if offset > 0 {
+            command.args(["--continue-at", &offset.to_string()]);
+        }
+
+        command.arg("--output").arg("-").arg("--").arg(url);
+        command
+    }
+
+    fn attempt(&self, url: &str, partial: &Path, offset: u64) -> Result<(), DownloadError> {
+        let mut child = self
+            .command(url, offset)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(partial)?;
+
+        let stdout = child.stdout.take().unwrap();
+        let copied = io::copy(
+            &mut LimitedReader::new(stdout, &self.source, self.limits.clone()),
+            &mut file,
+        );
+
+        if copied.is_err() {
+            let _ = child.kill();
+        }
+
+        let output = child.wait_with_output()?;
+        copied?;
+
+        if !output.status.success() {
+            return Err(DownloadError::Failed(
+                output.status.code(),
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Download to the destination, resuming from a partial file if there is one. A
+    /// destination that already exists is left alone.
+    pub fn fetch(&self, download: &Download) -> Result<(), DownloadError> {
+        if download.destination.exists() {
+            return Ok(());
+        }
+
+        if self.offline {
+            return Err(DownloadError::Offline(download.url.clone()));
+        }
+
+        let partial = download.partial_path();
+        let mut attempts = 0;
+
+        loop {
+            let offset = fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+
+            match self.attempt(&download.url, &partial, offset) {
+                Ok(()) => break,
+                Err(DownloadError::Failed(Some(CURL_RANGE_ERROR), _)) if offset > 0 => {
+                    // the server can't resume, start over without counting it as an attempt
+                    fs::remove_file(&partial)?;
+                    continue;
+                }
+                Err(err) if attempts >= self.retries => return Err(err),
+                Err(DownloadError::Failed(..)) => attempts += 1,
+                Err(err) => return Err(err),
+            }
+        }
+
+        download.complete()
+    }
+
+    /// Download `path` from the first of `mirrors` that has it. Mirrors are tried in a
+    /// weighted random order by their preference, see `mirror::ordered`. A partial download
+    /// from a mirror that fails is removed before the next one is tried.
+    pub fn fetch_from_mirrors(
+        &self,
+        mirrors: &[Mirror],
+        path: &str,
+        destination: &Path,
+        checksum: Option<&str>,
+    ) -> Result<(), MirrorError> {
+        let mirrors = mirror::ordered(mirrors, &mut rand::thread_rng());
+
+        mirror::with_failover(&mirrors, |mirror| {
+            let download = Download {
+                url: mirror.join(path),
+                destination: destination.to_path_buf(),
+                checksum: checksum.map(String::from),
+            };
+
+            self.fetch(&download).inspect_err(|_| {
+                let _ = fs::remove_file(download.partial_path());
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::core::export;
+    use crate::core::sources::limit::LimitOptions;
+
+    fn downloader() -> Downloader {
+        Downloader {
+            retries: 0,
+            ..Downloader::new("org.osbuild.curl", Arc::new(Limits::default()))
+        }
+    }
+
+    #[test]
+    fn download_command() {
+        let args = |offset| -> Vec<String> {
+            downloader()
+                .command("https://example.com/a", offset)
+                .get_args()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect()
+        };
+
+        assert!(!args(0).contains(&"--continue-at".to_string()));
+        assert!(args(10).windows(2).any(|w| w == ["--continue-at", "10"]));
+        assert_eq!(args(0).last().unwrap(), "https://example.com/a");
+    }
+
+    #[test]
+    fn download_resumes_partial() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        fs::write(&source, "hello, world").unwrap();
+
+        let download = Download {
+            url: format!("file://{}", source.display()),
+            destination: directory.path().join("out"),
+            checksum: Some(format!("sha256:{}", export::sha256(&source).unwrap())),
+        };
+
+        // an earlier attempt got the first part
+        fs::write(download.partial_path(), "hello").unwrap();
+
+        downloader().fetch(&download).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&download.destination).unwrap(),
+            "hello, world"
+        );
+        assert!(!download.partial_path().exists());
+    }
+
+    #[test]
+    fn download_bad_checksum() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        fs::write(&source, "hello, world").unwrap();
+
+        let download = Download {
+            url: format!("file://{}", source.display()),
+            destination: directory.path().join("out"),
+            checksum: Some("sha256:00".to_string()),
+        };
+
+        assert!(matches!(
+            downloader().fetch(&download),
+            Err(DownloadError::ChecksumMismatch(_))
+        ));
+        assert!(!download.partial_path().exists());
+        assert!(!download.destination.exists());
+
+        let checksum = sources::checksum_of(&source, "sha512:").unwrap().unwrap();
+
+        assert!(download.verify(&source).is_err());
+        assert!(Download {
+            checksum: Some(checksum),
+            ..download.clone()
+        }
+        .verify(&source)
+        .is_ok());
+        assert!(matches!(
+            Download {
+                checksum: Some("md5:00".to_string()),
+                ..download
+            }
+            .verify(&source),
+            Err(DownloadError::UnsupportedChecksum(_))
+        ));
+    }
+
+    #[test]
+    fn download_mirror_failover() {
+        let directory = tempfile::tempdir().unwrap();
+        fs::create_dir(directory.path().join("good")).unwrap();
+        fs::write(directory.path().join("good/file"), "content").unwrap();
+
+        let mirrors = mirror::parse_mirrorlist(&format!(
+            "file://{0}/missing/\nfile://{0}/good/\n",
+            directory.path().display()
+        ));
+
+        let destination = directory.path().join("out");
+
+        downloader()
+            .fetch_from_mirrors(&mirrors, "file", &destination, None)
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(&destination).unwrap(), "content");
+        assert!(matches!(
+            downloader().fetch_from_mirrors(
+                &mirrors[..1],
+                "file",
+                &directory.path().join("other"),
+                None
+            ),
+            Err(MirrorError::AllFailed(_))
+        ));
+    }
+
+    #[test]
+    fn download_quota() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        fs::write(&source, "hello, world").unwrap();
+
+        let limits = Arc::new(Limits::new(&LimitOptions {
+            quota: Some(4),
+            ..Default::default()
+        }));
+
+        let download = Download {
+            url: format!("file://{}", source.display()),
+            destination: directory.path().join("out"),
+            checksum: None,
+        };
+
+        assert!(Downloader {
+            retries: 0,
+            ..Downloader::new("org.osbuild.curl", limits)
+        }
+        .fetch(&download)
+        .is_err());
+        assert!(!download.destination.exists());
+    }
+
+    #[test]
+    fn download_offline() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        fs::write(&source, "hello, world").unwrap();
+
+        let download = Download {
+            url: format!("file://{}", source.display()),
+            destination: directory.path().join("out"),
+            checksum: None,
+        };
+
+        let offline = Downloader {
+            offline: true,
+            ..downloader()
+        };
+
+        assert!(matches!(
+            offline.fetch(&download),
+            Err(DownloadError::Offline(url)) if url == download.url
+        ));
+        assert!(!download.partial_path().exists());
+
+        // what is in the cache already is fine
+        fs::write(&download.destination, "cached").unwrap();
+
+        offline.fetch(&download).unwrap();
+    }
+}
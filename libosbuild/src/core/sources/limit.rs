@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+#[derive(Debug)]
+pub enum LimitError {
+    /// Downloading would go over the quota, contains the quota in bytes.
+    QuotaExceeded(u64),
+}
+
+impl From<LimitError> for io::Error {
+    fn from(err: LimitError) -> Self {
+        io::Error::other(format!("{:?}", err))
+    }
+}
+
+/// Limits on how sources download, all optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LimitOptions {
+    /// Bytes per second over all sources together.
+    pub rate: Option<u64>,
+
+    /// Bytes per second for single sources by module name, on top of `rate`.
+    pub source_rates: BTreeMap<String, u64>,
+
+    /// Total number of bytes all sources together may download in a build.
+    pub quota: Option<u64>,
+}
+
+/// A token bucket allowing `rate` bytes per second with bursts of at most a second's worth.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` from the bucket at `now`, returns how long to wait before they may be
+    /// used. Tokens go negative so concurrent callers queue up behind each other.
+    pub fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = *state;
+
+        let refilled =
+            tokens + now.saturating_duration_since(last).as_secs_f64() * self.rate as f64;
+        let tokens = refilled.min(self.rate as f64) - bytes as f64;
+
+        *state = (tokens, now.max(last));
+
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate as f64)
+        }
+    }
+
+    /// Wait until `bytes` may be used.
+    pub fn acquire(&self, bytes: u64) {
+        let delay = self.reserve(bytes, Instant::now());
+
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// A maximum number of bytes, shared between everything that counts towards it.
+#[derive(Debug)]
+pub struct Quota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl Quota {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// Count `bytes` towards the quota, bytes that would go over it are not counted.
+    pub fn consume(&self, bytes: u64) -> Result<(), LimitError> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.limit)
+            })
+            .map(|_| ())
+            .map_err(|_| LimitError::QuotaExceeded(self.limit))
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+}
+
+/// The limits of a build, shared by all fetches that run concurrently.
+#[derive(Debug, Default)]
+pub struct Limits {
+    rate: Option<RateLimiter>,
+    source_rates: BTreeMap<String, RateLimiter>,
+    quota: Option<Quota>,
+}
+
+impl Limits {
+    pub fn new(options: &LimitOptions) -> Self {
+        Self {
+            rate: options.rate.map(RateLimiter::new),
+            source_rates: options
+                .source_rates
+                .iter()
+                .map(|(name, rate)| (name.clone(), RateLimiter::new(*rate)))
+                .collect(),
+            quota: options.quota.map(Quota::new),
+        }
+    }
+
+    /// Account for `bytes` downloaded by the source called `source`, waits as long as the
+    /// rate limits require. Fails when the quota is used up.
+    pub fn throttle(&self, source: &str, bytes: u64) -> Result<(), LimitError> {
+        if let Some(quota) = &self.quota {
+            quota.consume(bytes)?;
+        }
+
+        if let Some(rate) = self.source_rates.get(source) {
+            rate.acquire(bytes);
+        }
+
+        if let Some(rate) = &self.rate {
+            rate.acquire(bytes);
+        }
+
+        Ok(())
+    }
+
+    /// Bytes counted towards the quota so far.
+    pub fn downloaded(&self) -> Option<u64> {
+        self.quota.as_ref().map(Quota::used)
+    }
+}
+
+/// A reader that applies the limits to everything read through it, wrap the body of a
+/// download in it.
+pub struct LimitedReader<R: Read> {
+    inner: R,
+    source: String,
+    limits: Arc<Limits>,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, source: &str, limits: Arc<Limits>) -> Self {
+        Self {
+            inner,
+            source: source.to_string(),
+            limits,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        self.limits.throttle(&self.source, read as u64)?;
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_reserve() {
+        let limiter = RateLimiter::new(100);
+        let start = Instant::now();
+
+        // a full bucket allows a burst of a second's worth
+        assert_eq!(limiter.reserve(100, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(50, start), Duration::from_millis(500));
+
+        // callers queue up behind the debt
+        assert_eq!(limiter.reserve(50, start), Duration::from_secs(1));
+
+        assert_eq!(
+            limiter.reserve(0, start + Duration::from_secs(3)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn quota_shared() {
+        let quota = Arc::new(Quota::new(1000));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let quota = quota.clone();
+                thread::spawn(move || (0..10).filter(|_| quota.consume(50).is_ok()).count())
+            })
+            .collect();
+
+        let accepted: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+
+        assert_eq!(accepted, 20);
+        assert_eq!(quota.used(), 1000);
+        assert!(matches!(
+            quota.consume(1),
+            Err(LimitError::QuotaExceeded(1000))
+        ));
+    }
+
+    #[test]
+    fn limited_reader_quota() {
+        let limits = Arc::new(Limits::new(&LimitOptions {
+            quota: Some(4),
+            ..Default::default()
+        }));
+
+        let mut data = vec![];
+        let result = LimitedReader::new(&b"abcdefgh"[..], "org.osbuild.curl", limits.clone())
+            .read_to_end(&mut data);
+
+        assert!(result.is_err());
+        assert_eq!(limits.downloaded(), Some(0));
+
+        let mut data = vec![];
+        LimitedReader::new(&b"abc"[..], "org.osbuild.curl", limits.clone())
+            .read_to_end(&mut data)
+            .unwrap();
+
+        assert_eq!(data, b"abc");
+        assert_eq!(limits.downloaded(), Some(3));
+    }
+}
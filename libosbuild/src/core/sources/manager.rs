@@ -0,0 +1,366 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::export::ExportError;
+use crate::core::sources;
+use crate::core::sources::backend::Backends;
+use crate::core::sources::offline::{self, MissingItem};
+use crate::core::sources::prefetch::{self, PrefetchError, Prefetched};
+use crate::manifest::Manifest;
+
+/// The file in an exported directory of sources that lists its items.
+pub const INDEX_FILE: &str = "index.json";
+
+#[derive(Debug)]
+pub enum SourceError {
+    /// Items of network sources aren't in the cache, so they can't be exported.
+    Missing(Vec<MissingItem>),
+
+    /// The index of an exported directory can't be read.
+    InvalidIndex(serde_json::Error),
+
+    /// An item of an index has a name that isn't a single path component.
+    InvalidItem(String),
+
+    /// An imported item doesn't match its checksum, contains the checksum.
+    ChecksumMismatch(String),
+
+    /// An imported item has a checksum the host can't verify it with, contains the checksum.
+    UnsupportedChecksum(String),
+
+    /// Hashing an imported item failed.
+    Export(ExportError),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for SourceError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+impl From<ExportError> for SourceError {
+    fn from(err: ExportError) -> Self {
+        Self::Export(err)
+    }
+}
+
+/// An item in an exported directory of sources.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexedItem {
+    pub source: String,
+    pub checksum: String,
+
+    /// The size of the item in bytes.
+    pub size: u64,
+}
+
+/// The index of an exported directory of sources, which has the same layout as the cache;
+/// a directory per source module with the items of that source in it by checksum.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceIndex {
+    pub items: Vec<IndexedItem>,
+}
+
+impl SourceIndex {
+    /// The index of the exported directory `directory`.
+    pub fn load(directory: &Path) -> Result<Self, SourceError> {
+        let data = fs::read(directory.join(INDEX_FILE))?;
+
+        serde_json::from_slice(&data).map_err(SourceError::InvalidIndex)
+    }
+
+    /// The total size of the items.
+    pub fn size(&self) -> u64 {
+        self.items.iter().map(|item| item.size).sum()
+    }
+}
+
+/// Names of sources and items become paths, only single components are allowed.
+fn is_component(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+/// The cache of sources of a store, and the backends that fetch into it.
+pub struct SourceManager {
+    cache: PathBuf,
+    backends: Backends,
+}
+
+impl SourceManager {
+    pub fn new(cache: &Path, backends: Backends) -> Self {
+        Self {
+            cache: cache.to_path_buf(),
+            backends,
+        }
+    }
+
+    pub fn cache(&self) -> &Path {
+        &self.cache
+    }
+
+    /// The path of an item in the cache.
+    pub fn item_path(&self, source: &str, checksum: &str) -> PathBuf {
+        self.cache.join(source).join(checksum)
+    }
+
+    /// The items of network sources of `manifest` that aren't in the cache.
+    pub fn missing(&self, manifest: &Manifest) -> Vec<MissingItem> {
+        offline::missing_items(manifest, &self.cache)
+    }
+
+    /// Fetch the items of `manifest` that aren't in the cache, see `prefetch::prefetch`.
+    pub fn prefetch(&self, manifest: &Manifest) -> Result<Prefetched, PrefetchError> {
+        prefetch::prefetch(manifest, &self.cache, &self.backends)
+    }
+
+    /// Copy the items of `manifest` from the cache to `directory`, in the layout of the
+    /// cache with an index, so everything needed to build the manifest can be carried to a
+    /// machine without a network. Items of other sources, such as inline ones, are copied
+    /// when they are in the cache. Nothing is copied when items of network sources are
+    /// missing.
+    pub fn export_to(
+        &self,
+        manifest: &Manifest,
+        directory: &Path,
+    ) -> Result<SourceIndex, SourceError> {
+        let missing = self.missing(manifest);
+
+        if !missing.is_empty() {
+            return Err(SourceError::Missing(missing));
+        }
+
+        let mut index = SourceIndex::default();
+
+        for (name, source) in manifest.sources() {
+            let items = source
+                .get("items")
+                .and_then(|items| items.as_object())
+                .into_iter()
+                .flat_map(|items| items.keys());
+
+            for checksum in items {
+                if !is_component(name) || !is_component(checksum) {
+                    return Err(SourceError::InvalidItem(format!("{}/{}", name, checksum)));
+                }
+
+                let path = self.item_path(name, checksum);
+
+                if !path.exists() {
+                    continue;
+                }
+
+                fs::create_dir_all(directory.join(name))?;
+
+                let size = fs::copy(&path, directory.join(name).join(checksum))?;
+
+                index.items.push(IndexedItem {
+                    source: name.clone(),
+                    checksum: checksum.clone(),
+                    size,
+                });
+            }
+        }
+
+        fs::write(
+            directory.join(INDEX_FILE),
+            serde_json::to_vec_pretty(&index).map_err(io::Error::from)?,
+        )?;
+
+        Ok(index)
+    }
+
+    /// Copy the items of the exported directory `directory` into the cache. Items are
+    /// verified against their checksum first, items in the cache already are left alone.
+    /// Nothing is copied when an item has a checksum of an algorithm the host can't verify,
+    /// see `sources::CHECKSUM_ALGORITHMS`; items of sources whose checksums have no algorithm,
+    /// such as ostree commits, are copied as they are.
+    pub fn import_from(&self, directory: &Path) -> Result<SourceIndex, SourceError> {
+        let index = SourceIndex::load(directory)?;
+
+        for item in &index.items {
+            if !is_component(&item.source) || !is_component(&item.checksum) {
+                return Err(SourceError::InvalidItem(format!(
+                    "{}/{}",
+                    item.source, item.checksum
+                )));
+            }
+
+            if item.checksum.contains(':') && !sources::is_verifiable(&item.checksum) {
+                return Err(SourceError::UnsupportedChecksum(item.checksum.clone()));
+            }
+        }
+
+        for item in &index.items {
+            let destination = self.item_path(&item.source, &item.checksum);
+
+            if destination.exists() {
+                continue;
+            }
+
+            let path = directory.join(&item.source).join(&item.checksum);
+
+            if let Some(actual) = sources::checksum_of(&path, &item.checksum)? {
+                if actual != item.checksum {
+                    return Err(SourceError::ChecksumMismatch(item.checksum.clone()));
+                }
+            }
+
+            fs::create_dir_all(self.cache.join(&item.source))?;
+
+            // copy next to the destination first so a partial copy is never taken for the item
+            let mut partial = destination.as_os_str().to_os_string();
+            partial.push(".import");
+
+            fs::copy(&path, &partial)?;
+            fs::rename(&partial, &destination)?;
+        }
+
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::core::export;
+
+    fn manager(cache: &Path) -> SourceManager {
+        SourceManager::new(cache, Backends::new())
+    }
+
+    #[test]
+    fn sources_exported() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = directory.path().join("cache");
+        let exported = directory.path().join("exported");
+
+        let content = directory.path().join("content");
+        fs::write(&content, "content").unwrap();
+
+        let checksum = format!("sha256:{}", export::sha256(&content).unwrap());
+
+        let mut manifest = Manifest::new();
+
+        manifest.add_source(
+            "org.osbuild.curl",
+            serde_json::json!({"items": {&checksum: "https://example.com/content"}}),
+        );
+        manifest.add_source(
+            "org.osbuild.inline",
+            serde_json::json!({"items": {"sha256:dd": {"encoding": "base64", "data": ""}}}),
+        );
+
+        let source = manager(&cache);
+
+        assert!(matches!(
+            source.export_to(&manifest, &exported),
+            Err(SourceError::Missing(missing)) if missing.len() == 1
+        ));
+        assert!(!exported.join(INDEX_FILE).exists());
+
+        fs::create_dir_all(cache.join("org.osbuild.curl")).unwrap();
+        fs::copy(&content, source.item_path("org.osbuild.curl", &checksum)).unwrap();
+
+        fs::create_dir(&exported).unwrap();
+
+        let index = source.export_to(&manifest, &exported).unwrap();
+
+        assert_eq!(
+            index.items,
+            [IndexedItem {
+                source: "org.osbuild.curl".to_string(),
+                checksum: checksum.clone(),
+                size: 7,
+            }]
+        );
+        assert_eq!(SourceIndex::load(&exported).unwrap(), index);
+
+        // elsewhere the directory fills an empty cache
+        let elsewhere = manager(&directory.path().join("elsewhere"));
+
+        assert_eq!(elsewhere.missing(&manifest).len(), 1);
+        assert_eq!(elsewhere.import_from(&exported).unwrap().size(), 7);
+        assert!(elsewhere.missing(&manifest).is_empty());
+        assert_eq!(
+            fs::read_to_string(elsewhere.item_path("org.osbuild.curl", &checksum)).unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    fn imports_verified() {
+        let directory = tempfile::tempdir().unwrap();
+        let exported = directory.path().join("exported");
+
+        fs::create_dir_all(exported.join("org.osbuild.curl")).unwrap();
+        fs::write(exported.join("org.osbuild.curl/sha256:00"), "tampered").unwrap();
+
+        let write_index = |source: &str, checksum: &str| {
+            fs::write(
+                exported.join(INDEX_FILE),
+                serde_json::to_vec(&SourceIndex {
+                    items: vec![IndexedItem {
+                        source: source.to_string(),
+                        checksum: checksum.to_string(),
+                        size: 8,
+                    }],
+                })
+                .unwrap(),
+            )
+            .unwrap()
+        };
+
+        let source = manager(&directory.path().join("cache"));
+
+        write_index("org.osbuild.curl", "sha256:00");
+
+        assert!(matches!(
+            source.import_from(&exported),
+            Err(SourceError::ChecksumMismatch(_))
+        ));
+
+        write_index("..", "sha256:00");
+
+        assert!(matches!(
+            source.import_from(&exported),
+            Err(SourceError::InvalidItem(_))
+        ));
+        assert!(!source.item_path("org.osbuild.curl", "sha256:00").exists());
+
+        write_index("org.osbuild.curl", "md5:00");
+
+        assert!(matches!(
+            source.import_from(&exported),
+            Err(SourceError::UnsupportedChecksum(checksum)) if checksum == "md5:00"
+        ));
+
+        let checksum =
+            sources::checksum_of(&exported.join("org.osbuild.curl/sha256:00"), "sha512:")
+                .unwrap()
+                .unwrap();
+
+        fs::copy(
+            exported.join("org.osbuild.curl/sha256:00"),
+            exported.join("org.osbuild.curl").join(&checksum),
+        )
+        .unwrap();
+        write_index("org.osbuild.curl", &checksum);
+
+        source.import_from(&exported).unwrap();
+
+        assert!(source.item_path("org.osbuild.curl", &checksum).exists());
+
+        fs::write(exported.join(INDEX_FILE), "[]").unwrap();
+
+        assert!(matches!(
+            source.import_from(&exported),
+            Err(SourceError::InvalidIndex(_))
+        ));
+    }
+}
@@ -0,0 +1,424 @@
+use std::fmt;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use rand::Rng;
+
+/// The file metalinks for repositories describe, the mirror's base URL is the URL of this
+/// file with it removed.
+pub const REPOMD_PATH: &str = "repodata/repomd.xml";
+
+/// Weight of mirrors that don't state a preference.
+pub const DEFAULT_PREFERENCE: u32 = 100;
+
+#[derive(Debug)]
+pub enum MirrorError {
+    /// A metalink is not valid XML.
+    ParseError(String),
+
+    /// There are no mirrors to try.
+    NoMirrors,
+
+    /// Every mirror failed, contains the URL of each mirror with its error in the order they
+    /// were tried.
+    AllFailed(Vec<(String, String)>),
+
+    /// A metalink only has hashes of types that can't be checked, contains the types.
+    UnsupportedHashes(Vec<String>),
+}
+
+/// A mirror with its preference, higher is preferred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mirror {
+    pub url: String,
+    pub preference: u32,
+}
+
+impl Mirror {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            preference: DEFAULT_PREFERENCE,
+        }
+    }
+
+    /// The URL of `path` on this mirror.
+    pub fn join(&self, path: &str) -> String {
+        format!(
+            "{}/{}",
+            self.url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+}
+
+/// A file described by a metalink.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetalinkFile {
+    pub name: String,
+    pub size: Option<u64>,
+
+    /// Hashes of the file as `(type, hex)`, e.g. `("sha256", "...")`.
+    pub hashes: Vec<(String, String)>,
+
+    pub mirrors: Vec<Mirror>,
+}
+
+impl MetalinkFile {
+    /// The checksum downloads of the file are checked against, as `sha256:<hex>`. Files
+    /// without hashes have none, files with only hashes of other types can't be checked.
+    pub fn checksum(&self) -> Result<Option<String>, MirrorError> {
+        if self.hashes.is_empty() {
+            return Ok(None);
+        }
+
+        self.hashes
+            .iter()
+            .find(|(kind, _)| matches!(kind.as_str(), "sha256" | "sha-256"))
+            .map(|(_, hex)| Some(format!("sha256:{}", hex)))
+            .ok_or_else(|| {
+                MirrorError::UnsupportedHashes(
+                    self.hashes.iter().map(|(kind, _)| kind.clone()).collect(),
+                )
+            })
+    }
+
+    /// The base URLs of the mirrors of a repository's `repomd.xml`, mirrors that serve
+    /// something else are left out.
+    pub fn repository_mirrors(&self) -> Vec<Mirror> {
+        self.mirrors
+            .iter()
+            .filter_map(|mirror| {
+                mirror.url.strip_suffix(REPOMD_PATH).map(|base| Mirror {
+                    url: base.to_string(),
+                    preference: mirror.preference,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Parse a mirrorlist, one URL per line with `#` comments. All mirrors are preferred equally.
+pub fn parse_mirrorlist(data: &str) -> Vec<Mirror> {
+    data.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Mirror::new)
+        .collect()
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name)
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.to_string())
+}
+
+/// Parse a metalink, both version 3 (`preference`, as served by Fedora's mirror manager) and
+/// version 4 (RFC 5854, `priority`) are understood. Version 4 priorities are turned into
+/// preferences so that higher is always better.
+pub fn parse_metalink(data: &str) -> Result<Vec<MetalinkFile>, MirrorError> {
+    let mut reader = Reader::from_str(data);
+    reader.config_mut().trim_text(true);
+
+    let mut files = vec![];
+    let mut file: Option<MetalinkFile> = None;
+
+    // the element whose text is being read, with its attributes where they matter
+    let mut current: Option<(Vec<u8>, Option<String>, u32)> = None;
+
+    loop {
+        match reader
+            .read_event()
+            .map_err(|err| MirrorError::ParseError(err.to_string()))?
+        {
+            Event::Start(element) => {
+                let name = element.local_name().as_ref().to_vec();
+
+                match name.as_slice() {
+                    b"file" => {
+                        file = Some(MetalinkFile {
+                            name: attribute(&element, b"name").unwrap_or_default(),
+                            ..Default::default()
+                        })
+                    }
+                    b"url" => {
+                        let preference = match (
+                            attribute(&element, b"preference"),
+                            attribute(&element, b"priority"),
+                        ) {
+                            (Some(preference), _) => {
+                                preference.parse().unwrap_or(DEFAULT_PREFERENCE)
+                            }
+                            (None, Some(priority)) => {
+                                // priorities go from 1 (best) to 999999
+                                1_000_000u32.saturating_sub(priority.parse().unwrap_or(999_999))
+                            }
+                            (None, None) => DEFAULT_PREFERENCE,
+                        };
+
+                        current = Some((name, None, preference));
+                    }
+                    b"hash" => current = Some((name, attribute(&element, b"type"), 0)),
+                    b"size" => current = Some((name, None, 0)),
+                    _ => {}
+                }
+            }
+            Event::Text(text) => {
+                let text = text
+                    .unescape()
+                    .map_err(|err| MirrorError::ParseError(err.to_string()))?
+                    .to_string();
+
+                if let (Some(file), Some((name, kind, preference))) = (&mut file, &current) {
+                    match name.as_slice() {
+                        b"url" => file.mirrors.push(Mirror {
+                            url: text,
+                            preference: *preference,
+                        }),
+                        b"hash" => file
+                            .hashes
+                            .push((kind.clone().unwrap_or_default(), text.to_lowercase())),
+                        b"size" => file.size = text.parse().ok(),
+                        _ => {}
+                    }
+                }
+            }
+            Event::End(element) => {
+                if element.local_name().as_ref() == b"file" {
+                    files.extend(file.take());
+                }
+
+                current = None;
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(files)
+}
+
+/// Order mirrors for trying, a weighted shuffle where a mirror's chance to come before the
+/// others is its share of the total preference. Mirrors with a preference of 0 come last.
+pub fn ordered<R: Rng>(mirrors: &[Mirror], rng: &mut R) -> Vec<Mirror> {
+    let mut remaining: Vec<Mirror> = mirrors.to_vec();
+    let mut ordered = vec![];
+
+    while !remaining.is_empty() {
+        let total: u64 = remaining.iter().map(|m| m.preference as u64).sum();
+
+        let index = if total == 0 {
+            0
+        } else {
+            let mut pick = rng.gen_range(0..total);
+
+            remaining
+                .iter()
+                .position(|mirror| {
+                    if pick < mirror.preference as u64 {
+                        true
+                    } else {
+                        pick -= mirror.preference as u64;
+                        false
+                    }
+                })
+                .unwrap_or(0)
+        };
+
+        ordered.push(remaining.remove(index));
+    }
+
+    ordered
+}
+
+/// Call `f` with each mirror in turn until one succeeds.
+pub fn with_failover<T, E, F>(mirrors: &[Mirror], mut f: F) -> Result<T, MirrorError>
+where
+    E: fmt::Debug,
+    F: FnMut(&Mirror) -> Result<T, E>,
+{
+    if mirrors.is_empty() {
+        return Err(MirrorError::NoMirrors);
+    }
+
+    let mut errors = vec![];
+
+    for mirror in mirrors {
+        match f(mirror) {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                log::warn!("mirror {} failed, trying the next one", mirror.url);
+                errors.push((mirror.url.clone(), format!("{:?}", err)));
+            }
+        }
+    }
+
+    Err(MirrorError::AllFailed(errors))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const METALINK_V3: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<metalink version="3.0" xmlns="http://www.metalinker.org/" xmlns:mm0="http://fedorahosted.org/mirrormanager">
+ <files>
+  <file name="repomd.xml">
+   <mm0:timestamp>1700000000</mm0:timestamp>
+   <size>6012</size>
+   <verification>
+    <hash type="sha256">ABCDEF</hash>
+   </verification>
+   <resources maxconnections="1">
+    <url protocol="https" type="https" location="DE" preference="100">https://a.example.com/fedora/37/x86_64/os/repodata/repomd.xml</url>
+    <url protocol="http" type="http" location="US" preference="50">http://b.example.com/fedora/37/x86_64/os/repodata/repomd.xml</url>
+    <url protocol="rsync" type="rsync" location="US" preference="50">rsync://b.example.com/fedora/</url>
+   </resources>
+  </file>
+ </files>
+</metalink>"#;
+
+    const METALINK_V4: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="example.iso">
+    <size>14471447</size>
+    <hash type="sha-256">f0ad929cd259957e160ea442eb80986b5f01</hash>
+    <url location="de" priority="1">ftp://ftp.example.com/example.iso</url>
+    <url priority="2">http://example.com/example.iso</url>
+  </file>
+</metalink>"#;
+
+    #[test]
+    fn metalink_v3() {
+        let files = parse_metalink(METALINK_V3).unwrap();
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "repomd.xml");
+        assert_eq!(files[0].size, Some(6012));
+        assert_eq!(
+            files[0].hashes,
+            vec![("sha256".to_string(), "abcdef".to_string())]
+        );
+        assert_eq!(
+            files[0].checksum().unwrap().as_deref(),
+            Some("sha256:abcdef")
+        );
+        assert_eq!(
+            files[0].repository_mirrors(),
+            vec![
+                Mirror {
+                    url: "https://a.example.com/fedora/37/x86_64/os/".to_string(),
+                    preference: 100
+                },
+                Mirror {
+                    url: "http://b.example.com/fedora/37/x86_64/os/".to_string(),
+                    preference: 50
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn metalink_v4() {
+        let files = parse_metalink(METALINK_V4).unwrap();
+
+        assert_eq!(files[0].mirrors.len(), 2);
+        assert!(files[0].mirrors[0].preference > files[0].mirrors[1].preference);
+        assert!(files[0].checksum().unwrap().is_some());
+
+        let unchecked = MetalinkFile {
+            hashes: vec![("md5".to_string(), "00".to_string())],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            unchecked.checksum(),
+            Err(MirrorError::UnsupportedHashes(kinds)) if kinds == ["md5"]
+        ));
+        assert_eq!(MetalinkFile::default().checksum().unwrap(), None);
+        assert!(matches!(
+            parse_metalink("<metalink><file></metalink>"),
+            Err(MirrorError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn mirrorlist() {
+        let mirrors =
+            parse_mirrorlist("# comment\nhttps://a.example.com/\n\nhttps://b.example.com/\n");
+
+        assert_eq!(mirrors.len(), 2);
+        assert_eq!(
+            mirrors[1].join("/repodata/repomd.xml"),
+            "https://b.example.com/repodata/repomd.xml"
+        );
+    }
+
+    #[test]
+    fn weighted_order() {
+        let mirrors = vec![
+            Mirror {
+                url: "never-first".to_string(),
+                preference: 0,
+            },
+            Mirror {
+                url: "mostly-first".to_string(),
+                preference: 1000,
+            },
+            Mirror {
+                url: "rarely-first".to_string(),
+                preference: 1,
+            },
+        ];
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut first = 0;
+
+        for _ in 0..100 {
+            let ordered = ordered(&mirrors, &mut rng);
+
+            assert_eq!(ordered.len(), 3);
+            assert_eq!(ordered[2].url, "never-first");
+
+            if ordered[0].url == "mostly-first" {
+                first += 1;
+            }
+        }
+
+        assert!(first > 90);
+    }
+
+    #[test]
+    fn failover() {
+        let mirrors = parse_mirrorlist("https://a.example.com/\nhttps://b.example.com/\n");
+        let mut tried = vec![];
+
+        let result = with_failover(&mirrors, |mirror| {
+            tried.push(mirror.url.clone());
+
+            if mirror.url.contains("b.") {
+                Ok(mirror.join("file"))
+            } else {
+                Err("connection refused")
+            }
+        });
+
+        assert_eq!(result.unwrap(), "https://b.example.com/file");
+        assert_eq!(tried.len(), 2);
+
+        assert!(matches!(
+            with_failover(&mirrors, |_| Err::<(), _>("nope")),
+            Err(MirrorError::AllFailed(errors)) if errors.len() == 2
+        ));
+        assert!(matches!(
+            with_failover(&[], |_| Ok::<_, ()>(())),
+            Err(MirrorError::NoMirrors)
+        ));
+    }
+}
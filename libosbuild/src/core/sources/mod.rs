@@ -0,0 +1,103 @@
+/// Backends sources fetch with, picked by the scheme of the URL.
+pub mod backend;
+
+/// Fetching only the changed chunks of artifacts that earlier builds downloaded.
+pub mod delta;
+
+/// Downloading files with resuming of partial downloads.
+pub mod download;
+
+/// Rate limits and quotas for downloads.
+pub mod limit;
+
+/// The cache of sources; fetching into it, and carrying it to other machines.
+pub mod manager;
+
+/// Mirrorlists, metalinks, and failing over between mirrors.
+pub mod mirror;
+
+/// Air-gapped builds, which only use the items that are in the cache of sources.
+pub mod offline;
+
+/// Fetching the items of sources into the cache ahead of a build.
+pub mod prefetch;
+
+/// Proxies that sources fetch through.
+pub mod proxy;
+
+use std::path::Path;
+
+use sha2::{Sha256, Sha384, Sha512};
+
+use crate::core::export::{self, ExportError};
+
+/// Algorithms of the checksums of items that the host verifies items with. Source modules
+/// accept `md5` and `sha1` as well, items with those are refused rather than taken unchecked.
+pub const CHECKSUM_ALGORITHMS: &[&str] = &["sha256", "sha384", "sha512"];
+
+/// Source modules that fetch over the network and the names they are registered under.
+pub const NETWORK_SOURCES: &[&str] = &[
+    "org.osbuild.curl",
+    "org.osbuild.skopeo",
+    "org.osbuild.ostree",
+];
+
+/// Whether the source module called `name` fetches over the network.
+pub fn is_network_source(name: &str) -> bool {
+    NETWORK_SOURCES.contains(&name)
+}
+
+/// Whether the host can verify items with `checksum`, which is `<algorithm>:<hex>`.
+pub fn is_verifiable(checksum: &str) -> bool {
+    checksum
+        .split_once(':')
+        .is_some_and(|(algorithm, _)| CHECKSUM_ALGORITHMS.contains(&algorithm))
+}
+
+/// The checksum of the file at `path` with the algorithm of `checksum`, in the same form, or
+/// `None` when the host can't verify items with it.
+pub fn checksum_of(path: &Path, checksum: &str) -> Result<Option<String>, ExportError> {
+    let Some((algorithm, _)) = checksum.split_once(':') else {
+        return Ok(None);
+    };
+
+    let hex = match algorithm {
+        "sha256" => export::hexdigest::<Sha256>(path)?,
+        "sha384" => export::hexdigest::<Sha384>(path)?,
+        "sha512" => export::hexdigest::<Sha512>(path)?,
+        _ => return Ok(None),
+    };
+
+    Ok(Some(format!("{}:{}", algorithm, hex)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksums_verified() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file");
+
+        std::fs::write(&path, "abc").unwrap();
+
+        assert_eq!(
+            checksum_of(&path, "sha384:00").unwrap().unwrap(),
+            "sha384:cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed\
+             8086072ba1e7cc2358baeca134c825a7"
+        );
+        assert_eq!(
+            checksum_of(&path, "sha512:00").unwrap().unwrap(),
+            "sha512:ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        );
+
+        for checksum in ["md5:00", "sha1:00", "00"] {
+            assert!(!is_verifiable(checksum));
+            assert!(checksum_of(&path, checksum).unwrap().is_none());
+        }
+
+        assert!(is_verifiable("sha256:00"));
+    }
+}
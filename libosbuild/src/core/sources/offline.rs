@@ -0,0 +1,121 @@
+use std::fmt;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::core::sources;
+use crate::manifest::Manifest;
+
+/// An item of a network source that isn't in the cache of sources.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct MissingItem {
+    /// The name of the source module, such as `org.osbuild.curl`.
+    pub source: String,
+    pub checksum: String,
+}
+
+/// The items of the network sources of `manifest` that aren't in `cache`, which has a
+/// directory per source module with its items by checksum. These are what an offline build
+/// can't fetch; items of other sources, such as inline ones, are made without a network.
+pub fn missing_items(manifest: &Manifest, cache: &Path) -> Vec<MissingItem> {
+    manifest
+        .sources()
+        .iter()
+        .filter(|(name, _)| sources::is_network_source(name))
+        .flat_map(|(name, source)| {
+            source
+                .get("items")
+                .and_then(|items| items.as_object())
+                .into_iter()
+                .flat_map(|items| items.keys())
+                .filter(move |checksum| !cache.join(name).join(checksum).exists())
+                .map(move |checksum| MissingItem {
+                    source: name.clone(),
+                    checksum: checksum.clone(),
+                })
+        })
+        .collect()
+}
+
+/// What has to be put in the cache of sources before a manifest can be built offline, an
+/// item per line grouped by source.
+pub struct MissingReport<'m> {
+    missing: &'m [MissingItem],
+}
+
+impl<'m> MissingReport<'m> {
+    pub fn new(missing: &'m [MissingItem]) -> Self {
+        Self { missing }
+    }
+}
+
+impl fmt::Display for MissingReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} item(s) missing from the cache of sources",
+            self.missing.len()
+        )?;
+
+        let mut source = None;
+
+        for item in self.missing {
+            if source != Some(&item.source) {
+                write!(f, "\n{}:", item.source)?;
+                source = Some(&item.source);
+            }
+
+            write!(f, "\n  {}", item.checksum)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn missing_items_reported() {
+        let cache = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(cache.path().join("org.osbuild.curl")).unwrap();
+        fs::write(cache.path().join("org.osbuild.curl/sha256:aa"), "a").unwrap();
+
+        let manifest: Manifest = serde_json::from_value(serde_json::json!({
+            "version": "2",
+            "sources": {
+                "org.osbuild.curl": {"items": {"sha256:aa": "https://example.com/a", "sha256:bb": "https://example.com/b"}},
+                "org.osbuild.skopeo": {"items": {"sha256:cc": {"image": {"name": "fedora"}}}},
+                "org.osbuild.inline": {"items": {"sha256:dd": {"encoding": "base64", "data": ""}}}
+            }
+        }))
+        .unwrap();
+
+        let missing = missing_items(&manifest, cache.path());
+
+        assert_eq!(
+            missing,
+            [
+                MissingItem {
+                    source: "org.osbuild.curl".to_string(),
+                    checksum: "sha256:bb".to_string(),
+                },
+                MissingItem {
+                    source: "org.osbuild.skopeo".to_string(),
+                    checksum: "sha256:cc".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            MissingReport::new(&missing).to_string(),
+            "2 item(s) missing from the cache of sources\n\
+             org.osbuild.curl:\n  sha256:bb\n\
+             org.osbuild.skopeo:\n  sha256:cc"
+        );
+        assert!(missing_items(&Manifest::new(), cache.path()).is_empty());
+    }
+}
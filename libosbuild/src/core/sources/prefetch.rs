@@ -0,0 +1,264 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::core::sources;
+use crate::core::sources::backend::{BackendError, Backends};
+use crate::core::sources::download::Download;
+use crate::core::sources::offline::MissingItem;
+use crate::manifest::Manifest;
+
+/// Source modules whose items are URLs, the host fetches these without the module.
+pub const URL_SOURCES: &[&str] = &["org.osbuild.curl"];
+
+#[derive(Debug)]
+pub enum PrefetchError {
+    /// An item of a URL source has no URL, contains the source and the checksum.
+    InvalidItem(String, String),
+
+    /// An item of a URL source has a checksum the host can't verify it with, contains the
+    /// source and the checksum.
+    UnsupportedChecksum(String, String),
+
+    /// Fetching an item failed, contains its checksum.
+    Backend(String, BackendError),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for PrefetchError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// What prefetching the sources of a manifest did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prefetched {
+    /// How many items were downloaded.
+    pub fetched: usize,
+
+    /// How many items were in the cache already.
+    pub cached: usize,
+
+    /// Items of network sources the host can't fetch without their source module, such as
+    /// container images.
+    pub unsupported: Vec<MissingItem>,
+}
+
+/// The URL of an item of a URL source; the item is the URL or an object with a `url`.
+fn item_url(item: &serde_json::Value) -> Option<&str> {
+    match item {
+        serde_json::Value::String(url) => Some(url),
+        item => item.get("url")?.as_str(),
+    }
+}
+
+/// Fetch the items of the network sources of `manifest` into `cache` with `backends`,
+/// without running any stage, so that an online machine can prepare the cache for an
+/// offline build. Items in the cache already aren't fetched again. Nothing is fetched when
+/// an item of a URL source that isn't in the cache has a checksum the host can't verify,
+/// see `sources::CHECKSUM_ALGORITHMS`.
+pub fn prefetch(
+    manifest: &Manifest,
+    cache: &Path,
+    backends: &Backends,
+) -> Result<Prefetched, PrefetchError> {
+    for name in URL_SOURCES {
+        let items = manifest
+            .sources()
+            .get(*name)
+            .and_then(|source| source.get("items"))
+            .and_then(|items| items.as_object());
+
+        if let Some(checksum) = items
+            .into_iter()
+            .flat_map(|items| items.keys())
+            .filter(|checksum| !cache.join(name).join(checksum).exists())
+            .find(|checksum| !sources::is_verifiable(checksum))
+        {
+            return Err(PrefetchError::UnsupportedChecksum(
+                name.to_string(),
+                checksum.clone(),
+            ));
+        }
+    }
+
+    let mut prefetched = Prefetched::default();
+
+    for (name, source) in manifest.sources() {
+        if !sources::is_network_source(name) {
+            continue;
+        }
+
+        let Some(items) = source.get("items").and_then(|items| items.as_object()) else {
+            continue;
+        };
+
+        let directory = cache.join(name);
+
+        for (checksum, item) in items {
+            let destination = directory.join(checksum);
+
+            if destination.exists() {
+                prefetched.cached += 1;
+                continue;
+            }
+
+            if !URL_SOURCES.contains(&name.as_str()) {
+                prefetched.unsupported.push(MissingItem {
+                    source: name.clone(),
+                    checksum: checksum.clone(),
+                });
+                continue;
+            }
+
+            let url = item_url(item)
+                .ok_or_else(|| PrefetchError::InvalidItem(name.clone(), checksum.clone()))?;
+
+            fs::create_dir_all(&directory)?;
+
+            backends
+                .fetch(&Download {
+                    url: url.to_string(),
+                    destination,
+                    checksum: Some(checksum.clone()),
+                })
+                .map_err(|err| PrefetchError::Backend(checksum.clone(), err))?;
+
+            prefetched.fetched += 1;
+        }
+    }
+
+    Ok(prefetched)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::core::export;
+    use crate::core::sources::download::Downloader;
+    use crate::core::sources::limit::Limits;
+
+    fn backends() -> Backends {
+        Backends::with_downloader(Downloader {
+            retries: 0,
+            ..Downloader::new("org.osbuild.curl", Arc::new(Limits::default()))
+        })
+    }
+
+    #[test]
+    fn sources_prefetched() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = directory.path().join("cache");
+
+        let mut files = vec![];
+
+        for name in ["a", "b"] {
+            let path = directory.path().join(name);
+            fs::write(&path, name).unwrap();
+            files.push((
+                format!("sha256:{}", export::sha256(&path).unwrap()),
+                format!("file://{}", path.display()),
+            ));
+        }
+
+        let mut manifest = Manifest::new();
+
+        manifest.add_source(
+            "org.osbuild.curl",
+            serde_json::json!({"items": {
+                &files[0].0: &files[0].1,
+                &files[1].0: {"url": &files[1].1, "insecure": false}
+            }}),
+        );
+        manifest.add_source(
+            "org.osbuild.skopeo",
+            serde_json::json!({"items": {"sha256:cc": {"image": {"name": "fedora"}}}}),
+        );
+        manifest.add_source(
+            "org.osbuild.inline",
+            serde_json::json!({"items": {"sha256:dd": {"encoding": "base64", "data": ""}}}),
+        );
+
+        assert_eq!(
+            prefetch(&manifest, &cache, &backends()).unwrap(),
+            Prefetched {
+                fetched: 2,
+                cached: 0,
+                unsupported: vec![MissingItem {
+                    source: "org.osbuild.skopeo".to_string(),
+                    checksum: "sha256:cc".to_string(),
+                }],
+            }
+        );
+        assert_eq!(
+            fs::read_to_string(cache.join("org.osbuild.curl").join(&files[1].0)).unwrap(),
+            "b"
+        );
+
+        // the second time everything is cached, even what the host can't fetch itself
+        fs::create_dir_all(cache.join("org.osbuild.skopeo")).unwrap();
+        fs::write(cache.join("org.osbuild.skopeo/sha256:cc"), "c").unwrap();
+
+        assert_eq!(
+            prefetch(&manifest, &cache, &backends()).unwrap(),
+            Prefetched {
+                cached: 3,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn prefetch_failed() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::new();
+
+        manifest.add_source(
+            "org.osbuild.curl",
+            serde_json::json!({"items": {"sha256:aa": {"secrets": {}}}}),
+        );
+
+        assert!(matches!(
+            prefetch(&manifest, directory.path(), &backends()),
+            Err(PrefetchError::InvalidItem(_, checksum)) if checksum == "sha256:aa"
+        ));
+
+        manifest.add_source(
+            "org.osbuild.curl",
+            serde_json::json!({"items": {"sha256:aa": "file:///nonexistent"}}),
+        );
+
+        assert!(matches!(
+            prefetch(&manifest, directory.path(), &backends()),
+            Err(PrefetchError::Backend(_, BackendError::Failed(_, _)))
+        ));
+
+        // nothing is fetched when an item can't be verified
+        let file = directory.path().join("file");
+        fs::write(&file, "content").unwrap();
+
+        manifest.add_source(
+            "org.osbuild.curl",
+            serde_json::json!({"items": {
+                format!("sha256:{}", export::sha256(&file).unwrap()): format!("file://{}", file.display()),
+                "md5:9a0364b9e99bb480dd25e1f0284c8555": format!("file://{}", file.display())
+            }}),
+        );
+
+        assert!(matches!(
+            prefetch(&manifest, directory.path(), &backends()),
+            Err(PrefetchError::UnsupportedChecksum(_, checksum))
+                if checksum == "md5:9a0364b9e99bb480dd25e1f0284c8555"
+        ));
+        assert!(!directory
+            .path()
+            .join("org.osbuild.curl")
+            .join(format!("sha256:{}", export::sha256(&file).unwrap()))
+            .exists());
+    }
+}
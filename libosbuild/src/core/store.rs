@@ -0,0 +1,767 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::id::{self, HashAlgo, IdError, ObjectId};
+use crate::core::journal::{Entry, Journal, JournalError};
+use crate::core::paths;
+use crate::module::util::tree;
+
+/// Directory in the store objects are kept in, by their id.
+pub const OBJECTS_DIR: &str = "objects";
+
+/// Directory in the store the cache of sources is kept in, with a directory per source
+/// module that has the items of the source in it by checksum.
+pub const SOURCES_DIR: &str = "sources";
+
+/// Directory in an object its tree is kept in.
+pub const TREE_DIR: &str = "data/tree";
+
+/// Prefix of the directory in `OBJECTS_DIR` an imported tree is copied to before it is
+/// committed, it isn't a valid id so it is never taken for an object.
+pub const IMPORT_PREFIX: &str = ".import-";
+
+/// Prefix of the directory in `OBJECTS_DIR` an object is moved to before it is removed, see
+/// `IMPORT_PREFIX`.
+pub const REMOVE_PREFIX: &str = ".remove-";
+
+/// The program subvolumes are created and snapshotted with, see btrfs-subvolume(8).
+pub const BTRFS: &str = "btrfs";
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// There is no object with the id in the store.
+    NoSuchObject(String),
+
+    /// There already is an object with the id in the store.
+    ObjectExists(String),
+
+    /// A tree to import isn't a directory.
+    NotATree(PathBuf),
+
+    /// A staging tree couldn't be made a btrfs subvolume or snapshot, contains the tree and
+    /// what btrfs wrote to stderr.
+    SubvolumeFailed(PathBuf, String),
+
+    IdError(IdError),
+    JournalError(JournalError),
+    IOError(io::Error),
+}
+
+impl From<JournalError> for StoreError {
+    fn from(err: JournalError) -> Self {
+        Self::JournalError(err)
+    }
+}
+
+impl From<IdError> for StoreError {
+    fn from(err: IdError) -> Self {
+        Self::IdError(err)
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// How the store makes staging trees, and the trees of objects they start from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Staging trees are directories, the tree of an object is copied into them.
+    Copy,
+
+    /// Staging trees are btrfs subvolumes and the tree of an object is snapshotted into
+    /// them, which takes as long for large trees as for small ones. Committed trees stay
+    /// subvolumes, so later builds can snapshot them in turn.
+    Btrfs,
+}
+
+impl Backend {
+    /// The backend for a store at `root`; `Btrfs` when the store, or the directory it is
+    /// created in when it doesn't exist yet, is on btrfs.
+    pub fn detect(root: &Path) -> Self {
+        match root.ancestors().find(|path| path.exists()) {
+            Some(path) if is_btrfs(path) => Self::Btrfs,
+            _ => Self::Copy,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_btrfs(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+
+    // SAFETY: statfs is plain data that is valid zeroed
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: the path and stat outlive the call
+    (unsafe { libc::statfs(path.as_ptr(), &mut stat) } == 0)
+        && stat.f_type == libc::BTRFS_SUPER_MAGIC
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_btrfs(_path: &Path) -> bool {
+    false
+}
+
+/// The objects built trees are committed as, each in a directory named after its id.
+#[derive(Debug, Clone)]
+pub struct ObjectStore {
+    root: PathBuf,
+    backend: Backend,
+}
+
+impl ObjectStore {
+    /// The store at `root`, with the backend for the filesystem it is on.
+    pub fn new(root: &Path) -> Self {
+        Self::with_backend(root, Backend::detect(root))
+    }
+
+    pub fn with_backend(root: &Path, backend: Backend) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            backend,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// The cache of sources, see `SOURCES_DIR`.
+    pub fn sources_path(&self) -> PathBuf {
+        self.root.join(SOURCES_DIR)
+    }
+
+    /// The directory of the object `id`, ids that aren't valid object ids are refused so
+    /// they can't point outside of the store.
+    pub fn object_path(&self, id: &str) -> Result<PathBuf, StoreError> {
+        let id: ObjectId = id.parse()?;
+
+        Ok(self.root.join(OBJECTS_DIR).join(id.to_string()))
+    }
+
+    /// The tree of the object `id`.
+    pub fn tree_path(&self, id: &str) -> Result<PathBuf, StoreError> {
+        let tree = self.object_path(id)?.join(TREE_DIR);
+
+        if !tree.is_dir() {
+            return Err(StoreError::NoSuchObject(id.to_string()));
+        }
+
+        Ok(tree)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.tree_path(id).is_ok()
+    }
+
+    /// The ids of the objects in the store, in order.
+    pub fn ids(&self) -> Result<Vec<String>, StoreError> {
+        let mut ids = vec![];
+
+        let entries = match fs::read_dir(self.root.join(OBJECTS_DIR)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(ids),
+            Err(err) => return Err(err.into()),
+        };
+
+        for entry in entries {
+            let id = entry?.file_name().to_string_lossy().to_string();
+
+            if self.contains(&id) {
+                ids.push(id);
+            }
+        }
+
+        ids.sort();
+
+        Ok(ids)
+    }
+
+    /// Move `tree` into the store as the tree of the object `id`, `tree` has to be on the same
+    /// filesystem as the store. The algorithm of the id is recorded with the object and the
+    /// commit in the journal of the store. Returns where the tree is now.
+    pub fn commit(&self, id: &str, tree: &Path) -> Result<PathBuf, StoreError> {
+        let object = self.object_path(id)?;
+        let path = object.join(TREE_DIR);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        id::write_algorithm(&object, id.parse::<ObjectId>()?.algo)?;
+        fs::rename(tree, &path)?;
+
+        // a crash before this leaves nothing at the staging tree to recover
+        Journal::open(&self.root)?.record(&Entry::Commit {
+            id: id.to_string(),
+            path: tree.to_path_buf(),
+        })?;
+
+        Ok(path)
+    }
+
+    /// The algorithm the id of the object `id` was computed with, as it was recorded.
+    pub fn algorithm(&self, id: &str) -> Result<HashAlgo, StoreError> {
+        Ok(id::read_algorithm(&self.object_path(id)?)?)
+    }
+
+    /// Make `tree` a staging tree to build in; empty, or with the tree of the object `base`
+    /// in it. An empty directory at `tree` is replaced. With the `Btrfs` backend `tree` has
+    /// to be on the filesystem of the store, as it has to be to be committed; trees of
+    /// objects that aren't subvolumes, such as those committed by the `Copy` backend, are
+    /// copied, as they are without `BTRFS`. The tree is recorded in the journal of the store
+    /// before it is made, see `journal::recover`.
+    pub fn stage(&self, base: Option<&str>, tree: &Path) -> Result<(), StoreError> {
+        fs::create_dir_all(&self.root)?;
+
+        Journal::open(&self.root)?.record(&Entry::Staging {
+            path: tree.to_path_buf(),
+        })?;
+
+        match fs::remove_dir(tree) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+
+        match (self.backend, base) {
+            (Backend::Btrfs, None) => {
+                let created = btrfs(
+                    Command::new(BTRFS).args(["subvolume", "create"]).arg(tree),
+                    tree,
+                );
+
+                match created {
+                    Err(StoreError::IOError(err)) if err.kind() == io::ErrorKind::NotFound => {
+                        fs::create_dir(tree)?
+                    }
+                    result => result?,
+                }
+            }
+            (Backend::Btrfs, Some(base)) if is_subvolume(&self.tree_path(base)?) => {
+                let snapshot = btrfs(
+                    Command::new(BTRFS)
+                        .args(["subvolume", "snapshot"])
+                        .arg(self.tree_path(base)?)
+                        .arg(tree),
+                    tree,
+                );
+
+                match snapshot {
+                    Err(StoreError::IOError(err)) if err.kind() == io::ErrorKind::NotFound => {
+                        tree::copy_all(&self.tree_path(base)?, tree)?;
+                    }
+                    result => result?,
+                }
+            }
+            (_, None) => fs::create_dir(tree)?,
+            (_, Some(base)) => {
+                tree::copy_all(&self.tree_path(base)?, tree)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy `tree`, produced outside of the store such as by osbuild, into the store as the
+    /// tree of the object `id`, so that builds use it as if they had built the object. Objects
+    /// in the store already are never replaced. The copy is committed once it is complete, a
+    /// failed import leaves nothing behind. Returns where the tree is now.
+    pub fn import_tree(&self, tree: &Path, id: &str) -> Result<PathBuf, StoreError> {
+        let object = self.object_path(id)?;
+
+        if object.exists() {
+            return Err(StoreError::ObjectExists(id.to_string()));
+        }
+
+        if !tree.is_dir() {
+            return Err(StoreError::NotATree(tree.to_path_buf()));
+        }
+
+        let staging = self.root.join(OBJECTS_DIR).join(format!(
+            "{}{}",
+            IMPORT_PREFIX,
+            object.file_name().unwrap_or_default().to_string_lossy()
+        ));
+
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+
+        fs::create_dir_all(self.root.join(OBJECTS_DIR))?;
+
+        let committed = tree::copy_all(tree, &staging)
+            .map_err(StoreError::from)
+            .and_then(|_| self.commit(id, &staging));
+
+        if committed.is_err() {
+            let _ = fs::remove_dir_all(&staging);
+            let _ = fs::remove_dir_all(&object);
+        }
+
+        committed
+    }
+
+    /// Remove the object `id` from the store. It is moved out of the way first, so an object
+    /// is never left with half of its tree when removing it fails.
+    pub fn remove(&self, id: &str) -> Result<(), StoreError> {
+        let object = self.object_path(id)?;
+
+        if !self.contains(id) {
+            return Err(StoreError::NoSuchObject(id.to_string()));
+        }
+
+        let removing = self.root.join(OBJECTS_DIR).join(format!(
+            "{}{}",
+            REMOVE_PREFIX,
+            object.file_name().unwrap_or_default().to_string_lossy()
+        ));
+
+        fs::rename(&object, &removing)?;
+        paths::remove_all(&removing)?;
+
+        Ok(())
+    }
+
+    /// The files in the tree of the object `id`, hashed with the algorithm of its id.
+    pub fn index(&self, id: &str) -> Result<Index, StoreError> {
+        Index::with_algorithm(&self.tree_path(id)?, self.algorithm(id)?)
+    }
+
+    /// What changed from the tree of the object `a` to the tree of the object `b`.
+    pub fn diff(&self, a: &str, b: &str) -> Result<Diff, StoreError> {
+        Ok(Diff::between(&self.index(a)?, &self.index(b)?))
+    }
+}
+
+/// Whether `path` is the root of a btrfs subvolume, which has the first inode number that
+/// btrfs hands out.
+fn is_subvolume(path: &Path) -> bool {
+    const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+
+    is_btrfs(path)
+        && fs::metadata(path).is_ok_and(|metadata| metadata.ino() == BTRFS_FIRST_FREE_OBJECTID)
+}
+
+/// Run the btrfs `command` that makes `tree` a subvolume.
+fn btrfs(command: &mut Command, tree: &Path) -> Result<(), StoreError> {
+    let output = command.output()?;
+
+    if !output.status.success() {
+        return Err(StoreError::SubvolumeFailed(
+            tree.to_path_buf(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+
+    /// Devices, sockets, and pipes.
+    Other,
+}
+
+/// A file in a tree. Regular files have the digest of their content, symlinks their target.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// The path of the file inside the tree, starting with `/`.
+    pub path: String,
+
+    pub kind: FileKind,
+    pub size: u64,
+
+    /// The permission bits, including setuid, setgid, and sticky.
+    pub mode: u32,
+
+    pub uid: u32,
+    pub gid: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// The files of a tree, ordered by path.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Index {
+    pub entries: Vec<IndexEntry>,
+}
+
+impl Index {
+    /// Index the files in `tree`, the contents of regular files are hashed with the default
+    /// algorithm of object ids.
+    pub fn of(tree: &Path) -> Result<Self, StoreError> {
+        Self::with_algorithm(tree, HashAlgo::default())
+    }
+
+    /// Index the files in `tree`, hashing the contents of regular files with `algo`.
+    pub fn with_algorithm(tree: &Path, algo: HashAlgo) -> Result<Self, StoreError> {
+        let mut index = Self::default();
+
+        index.walk(tree, "", algo)?;
+
+        // `/etc-release` sorts before `/etc/hostname`, unlike the order they are walked in
+        index.entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(index)
+    }
+
+    fn walk(&mut self, directory: &Path, prefix: &str, algo: HashAlgo) -> Result<(), StoreError> {
+        let mut entries: Vec<fs::DirEntry> = fs::read_dir(directory)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let metadata = fs::symlink_metadata(&path)?;
+            let name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+            let file_type = metadata.file_type();
+
+            let kind = if file_type.is_symlink() {
+                FileKind::Symlink
+            } else if file_type.is_dir() {
+                FileKind::Directory
+            } else if file_type.is_file() {
+                FileKind::File
+            } else {
+                FileKind::Other
+            };
+
+            self.entries.push(IndexEntry {
+                path: name.clone(),
+                kind,
+                size: if kind == FileKind::Directory {
+                    0
+                } else {
+                    metadata.len()
+                },
+                mode: metadata.permissions().mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                digest: match kind {
+                    FileKind::File => Some(algo.hash_file(&path)?.to_string()),
+                    _ => None,
+                },
+                target: match kind {
+                    FileKind::Symlink => Some(fs::read_link(&path)?.to_string_lossy().to_string()),
+                    _ => None,
+                },
+            });
+
+            if kind == FileKind::Directory {
+                self.walk(&path, &name, algo)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, path: &str) -> Option<&IndexEntry> {
+        self.entries
+            .binary_search_by(|entry| entry.path.as_str().cmp(path))
+            .ok()
+            .map(|index| &self.entries[index])
+    }
+}
+
+/// The files added, removed, and changed between two trees, by path. Files change when their
+/// kind, content, permissions, owner, or target do.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl Diff {
+    pub fn between(a: &Index, b: &Index) -> Self {
+        let mut diff = Self::default();
+
+        for entry in &a.entries {
+            match b.get(&entry.path) {
+                None => diff.removed.push(entry.path.clone()),
+                Some(other) if other != entry => diff.changed.push(entry.path.clone()),
+                Some(_) => {}
+            }
+        }
+
+        for entry in &b.entries {
+            if a.get(&entry.path).is_none() {
+                diff.added.push(entry.path.clone());
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// One file per line, prefixed with `+` when added, `-` when removed, and `~` when changed.
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (prefix, paths) in [
+            ("+", &self.added),
+            ("-", &self.removed),
+            ("~", &self.changed),
+        ] {
+            for path in paths {
+                writeln!(f, "{} {}", prefix, path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::core::journal::{self, Recovery};
+
+    const A: &str = "aa";
+    const B: &str = "bb";
+
+    fn tree(store: &ObjectStore, id: &str) -> PathBuf {
+        let tree = store.object_path(id).unwrap().join(TREE_DIR);
+        fs::create_dir_all(tree.join("etc")).unwrap();
+        fs::write(tree.join("etc-release"), "").unwrap();
+        tree
+    }
+
+    #[test]
+    fn trees_committed() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = ObjectStore::new(&directory.path().join("store"));
+
+        assert!(store.ids().unwrap().is_empty());
+
+        let built = directory.path().join("built");
+        fs::create_dir(&built).unwrap();
+        fs::write(built.join("etc-release"), "").unwrap();
+
+        let committed = store.commit(B, &built).unwrap();
+
+        assert_eq!(committed, store.tree_path(B).unwrap());
+        assert!(committed.join("etc-release").is_file());
+        assert!(!built.exists());
+        assert_eq!(store.algorithm(B).unwrap(), HashAlgo::Sha256);
+        assert_eq!(
+            fs::read_to_string(store.object_path(B).unwrap().join(id::ALGORITHM_FILE)).unwrap(),
+            "sha256\n"
+        );
+
+        tree(&store, A);
+
+        assert_eq!(store.ids().unwrap(), [A, B]);
+        assert!(store.commit("../aa", &committed).is_err());
+    }
+
+    #[test]
+    fn trees_staged() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = ObjectStore::new(&directory.path().join("store"));
+
+        tree(&store, A);
+
+        // workspaces hand out empty staging trees
+        let staging = directory.path().join("staging");
+        fs::create_dir(&staging).unwrap();
+
+        store.stage(Some(A), &staging).unwrap();
+
+        assert!(staging.join("etc").is_dir());
+        assert!(staging.join("etc-release").is_file());
+
+        fs::write(staging.join("etc/hostname"), "staged\n").unwrap();
+
+        assert!(!store.tree_path(A).unwrap().join("etc/hostname").exists());
+
+        let empty = directory.path().join("empty");
+        store.stage(None, &empty).unwrap();
+
+        assert_eq!(fs::read_dir(&empty).unwrap().count(), 0);
+        assert!(matches!(
+            store.stage(Some(B), &directory.path().join("missing")),
+            Err(StoreError::NoSuchObject(_))
+        ));
+
+        store.commit(B, &staging).unwrap();
+
+        // what is staged and committed is journaled, so it can be recovered
+        let recovery = Recovery::from_entries(&journal::read(store.root()).unwrap());
+
+        assert_eq!(recovery.staging, [empty, directory.path().join("missing")]);
+        assert_eq!(recovery.committed, [B]);
+    }
+
+    #[test]
+    fn trees_copied_without_subvolumes() {
+        let directory = tempfile::tempdir().unwrap();
+        let copy = ObjectStore::with_backend(&directory.path().join("store"), Backend::Copy);
+
+        tree(&copy, A);
+
+        // trees committed without subvolumes are copied by the btrfs backend
+        let store = ObjectStore::with_backend(copy.root(), Backend::Btrfs);
+        let staging = directory.path().join("staging");
+
+        store.stage(Some(A), &staging).unwrap();
+
+        assert!(staging.join("etc-release").is_file());
+    }
+
+    #[test]
+    fn backends_detected() {
+        let directory = tempfile::tempdir().unwrap();
+        let missing = directory.path().join("not/created/yet");
+
+        // a store that doesn't exist yet is on the filesystem of where it is created
+        assert_eq!(Backend::detect(&missing), Backend::detect(directory.path()));
+        assert_eq!(
+            ObjectStore::with_backend(directory.path(), Backend::Copy).backend(),
+            Backend::Copy
+        );
+    }
+
+    #[test]
+    fn trees_imported() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = ObjectStore::new(&directory.path().join("store"));
+
+        let external = directory.path().join("external");
+        fs::create_dir_all(external.join("etc")).unwrap();
+        fs::write(external.join("etc/hostname"), "external\n").unwrap();
+        fs::set_permissions(external.join("etc"), fs::Permissions::from_mode(0o700)).unwrap();
+        std::os::unix::fs::symlink("etc/hostname", external.join("hostname")).unwrap();
+
+        let imported = store.import_tree(&external, A).unwrap();
+
+        assert_eq!(imported, store.tree_path(A).unwrap());
+        assert_eq!(store.ids().unwrap(), [A]);
+        assert_eq!(
+            fs::read_to_string(imported.join("hostname")).unwrap(),
+            "external\n"
+        );
+        assert_eq!(
+            fs::metadata(imported.join("etc")).unwrap().mode() & 0o777,
+            0o700
+        );
+        assert!(external.join("etc/hostname").is_file());
+
+        assert!(matches!(
+            store.import_tree(&external, A),
+            Err(StoreError::ObjectExists(_))
+        ));
+        assert!(matches!(
+            store.import_tree(&external.join("hostname"), B),
+            Err(StoreError::NotATree(_))
+        ));
+        assert!(matches!(
+            store.import_tree(&external, "../bb"),
+            Err(StoreError::IdError(_))
+        ));
+        assert!(!store.object_path(B).unwrap().exists());
+    }
+
+    #[test]
+    fn trees_indexed_and_diffed() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = ObjectStore::new(directory.path());
+
+        let a = tree(&store, A);
+        fs::write(a.join("etc/hostname"), "a\n").unwrap();
+        fs::write(a.join("etc/motd"), "hello\n").unwrap();
+        fs::write(a.join("removed"), "").unwrap();
+
+        let b = tree(&store, B);
+        fs::write(b.join("etc/hostname"), "b\n").unwrap();
+        fs::write(b.join("etc/motd"), "hello\n").unwrap();
+        fs::set_permissions(b.join("etc/motd"), fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink("/etc/motd", b.join("motd")).unwrap();
+
+        let index = store.index(A).unwrap();
+        let paths: Vec<&str> = index
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect();
+
+        assert_eq!(
+            paths,
+            [
+                "/etc",
+                "/etc-release",
+                "/etc/hostname",
+                "/etc/motd",
+                "/removed"
+            ]
+        );
+
+        let hostname = index.get("/etc/hostname").unwrap();
+
+        assert_eq!(hostname.kind, FileKind::File);
+        assert_eq!(hostname.size, 2);
+        assert_eq!(
+            hostname.digest.as_deref(),
+            Some("87428fc522803d31065e7bce3cf03fe475096631e5e07bbd7a0fde60c4cf25c7")
+        );
+        assert_eq!(
+            store
+                .index(B)
+                .unwrap()
+                .get("/motd")
+                .unwrap()
+                .target
+                .as_deref(),
+            Some("/etc/motd")
+        );
+
+        let diff = store.diff(A, B).unwrap();
+
+        assert_eq!(diff.added, ["/motd"]);
+        assert_eq!(diff.removed, ["/removed"]);
+        assert_eq!(diff.changed, ["/etc/hostname", "/etc/motd"]);
+        assert_eq!(
+            diff.to_string(),
+            "+ /motd\n- /removed\n~ /etc/hostname\n~ /etc/motd\n"
+        );
+        assert!(store.diff(A, A).unwrap().is_empty());
+
+        assert!(matches!(
+            store.index("cc"),
+            Err(StoreError::NoSuchObject(_))
+        ));
+        assert!(matches!(
+            store.index("../aa"),
+            Err(StoreError::IdError(IdError::InvalidId(_)))
+        ));
+    }
+}
@@ -0,0 +1,99 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// A named phase of a run and how long it took.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phase {
+    pub name: String,
+    pub duration: Duration,
+}
+
+/// The time spent in each phase of a run, in the order the phases ran. Phases with the same
+/// name are reported separately.
+#[derive(Debug, Default)]
+pub struct TimeReport {
+    phases: Vec<Phase>,
+}
+
+impl TimeReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `f` as the phase `name`.
+    pub fn time<T, F: FnOnce() -> T>(&mut self, name: &str, f: F) -> T {
+        let start = Instant::now();
+        let value = f();
+
+        self.record(name, start.elapsed());
+
+        value
+    }
+
+    pub fn record(&mut self, name: &str, duration: Duration) {
+        self.phases.push(Phase {
+            name: name.to_string(),
+            duration,
+        });
+    }
+
+    pub fn phases(&self) -> &[Phase] {
+        &self.phases
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|phase| phase.duration).sum()
+    }
+}
+
+impl fmt::Display for TimeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self
+            .phases
+            .iter()
+            .map(|phase| phase.name.len())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or_default();
+
+        for phase in &self.phases {
+            writeln!(
+                f,
+                "{:<width$}  {:>10.3}s",
+                phase.name,
+                phase.duration.as_secs_f64(),
+            )?;
+        }
+
+        write!(
+            f,
+            "{:<width$}  {:>10.3}s",
+            "total",
+            self.total().as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut report = TimeReport::new();
+
+        assert_eq!(report.time("parse", || 42), 42);
+        report.record("build", Duration::from_millis(1500));
+
+        assert_eq!(report.phases().len(), 2);
+        assert_eq!(report.phases()[1].name, "build");
+        assert!(report.total() >= Duration::from_millis(1500));
+
+        let text = report.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], "build       1.500s");
+        assert!(lines[2].starts_with("total "));
+    }
+}
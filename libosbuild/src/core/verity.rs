@@ -0,0 +1,204 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// The size of the blocks of the Merkle tree, the kernel requires it to equal the page size
+/// on older versions so 4096 is the only size that works everywhere.
+pub const BLOCK_SIZE: usize = 4096;
+
+const FS_VERITY_HASH_ALG_SHA256: u8 = 1;
+const FS_IMMUTABLE_FL: libc::c_long = 0x10;
+
+/// `struct fsverity_enable_arg` of `linux/fsverity.h`.
+#[repr(C)]
+struct EnableArg {
+    version: u32,
+    hash_algorithm: u32,
+    block_size: u32,
+    salt_size: u32,
+    salt_ptr: u64,
+    sig_size: u32,
+    reserved1: u32,
+    sig_ptr: u64,
+    reserved2: [u64; 11],
+}
+
+const FS_IOC_ENABLE_VERITY: libc::Ioctl = libc::_IOW::<EnableArg>('f' as u32, 133);
+
+fn hash_block(block: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    hasher.update(block);
+    hasher.update(&[0u8; BLOCK_SIZE][block.len()..]);
+    hasher.finalize().into()
+}
+
+/// The hex encoded fs-verity digest of a file, with SHA256, 4096 byte blocks, and no salt.
+/// This is the digest the kernel measures once fs-verity is enabled on the file, and what
+/// `fsverity digest` prints, but it is computed without the filesystem having to support
+/// fs-verity.
+pub fn digest(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut level = vec![];
+    let mut size = 0u64;
+    let mut block = vec![0u8; BLOCK_SIZE];
+
+    loop {
+        let mut read = 0;
+
+        while read < BLOCK_SIZE {
+            match file.read(&mut block[read..])? {
+                0 => break,
+                count => read += count,
+            }
+        }
+
+        if read == 0 {
+            break;
+        }
+
+        size += read as u64;
+        level.extend_from_slice(&hash_block(&block[..read]));
+    }
+
+    // hash the levels of the tree until one hash is left, the root; empty files have a root
+    // of zeroes
+    let mut root = [0u8; 32];
+
+    if !level.is_empty() {
+        while level.len() > 32 {
+            level = level.chunks(BLOCK_SIZE).flat_map(hash_block).collect();
+        }
+
+        root.copy_from_slice(&level);
+    }
+
+    // `struct fsverity_descriptor`
+    let mut descriptor = [0u8; 256];
+
+    descriptor[0] = 1;
+    descriptor[1] = FS_VERITY_HASH_ALG_SHA256;
+    descriptor[2] = BLOCK_SIZE.trailing_zeros() as u8;
+    descriptor[8..16].copy_from_slice(&size.to_le_bytes());
+    descriptor[16..48].copy_from_slice(&root);
+
+    Ok(Sha256::digest(descriptor)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Enable fs-verity on the file at `path`, after which its content can't change and is
+/// verified when read. Fails with `EOPNOTSUPP` or `ENOTTY` on filesystems that don't support
+/// it, and with `ETXTBSY` while the file is open for writing.
+pub fn enable(path: &Path) -> io::Result<()> {
+    let file = fs::File::open(path)?;
+    let arg = EnableArg {
+        version: 1,
+        hash_algorithm: FS_VERITY_HASH_ALG_SHA256 as u32,
+        block_size: BLOCK_SIZE as u32,
+        salt_size: 0,
+        salt_ptr: 0,
+        sig_size: 0,
+        reserved1: 0,
+        sig_ptr: 0,
+        reserved2: [0; 11],
+    };
+
+    // SAFETY: the argument lives for the duration of the call and is what the ioctl expects
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_ENABLE_VERITY, &arg) } < 0 {
+        let err = io::Error::last_os_error();
+
+        // enabling it on a file that already has it is fine
+        if err.raw_os_error() != Some(libc::EEXIST) {
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Set or clear the immutable attribute of the file at `path`, as `chattr +i` does. Files
+/// that are immutable can't be changed, renamed, or removed, not even by root, until the
+/// attribute is cleared. Requires `CAP_LINUX_IMMUTABLE`.
+pub fn set_immutable(path: &Path, immutable: bool) -> io::Result<()> {
+    let file = fs::File::open(path)?;
+    let mut flags: libc::c_long = 0;
+
+    // SAFETY: both ioctls read or write the one flags value
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if immutable {
+        flags |= FS_IMMUTABLE_FL;
+    } else {
+        flags &= !FS_IMMUTABLE_FL;
+    }
+
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn digests() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file");
+
+        // as printed by `fsverity digest`
+        for (data, expected) in [
+            (
+                vec![],
+                "3d248ca542a24fc62d1c43b916eae5016878e2533c88238480b26128a1f1af95",
+            ),
+            (
+                b"abc".to_vec(),
+                "700b6bd8510f0b4f9bac8b9cf0459151a1c4a99f467892bb4bd289a67df8e19c",
+            ),
+            (
+                vec![b'x'; 10000],
+                "56d299c86ab735388169327e546b464800d8cb6b3935303ed51b4f3142fc7362",
+            ),
+            // more blocks than the hashes that fit into one, so the tree has two levels
+            (
+                vec![b'x'; BLOCK_SIZE * 129 + 1],
+                "2ee3481b2e7c2a9b4317ce16cb027f5a2e5be6d1666414ae700c65c03e0c3fff",
+            ),
+        ] {
+            fs::write(&path, data).unwrap();
+
+            assert_eq!(digest(&path).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn immutable() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file");
+        fs::write(&path, "abc").unwrap();
+
+        // without `CAP_LINUX_IMMUTABLE`, or on filesystems without attributes, there is
+        // nothing to test
+        if set_immutable(&path, true).is_err() {
+            return;
+        }
+
+        let written = fs::write(&path, "def");
+
+        set_immutable(&path, false).unwrap();
+
+        assert!(written.is_err());
+        assert!(fs::write(&path, "def").is_ok());
+    }
+}
@@ -0,0 +1,235 @@
+use zbus::blocking::connection::Builder;
+use zbus::object_server::SignalEmitter;
+
+use crate::core::worker::{JobEvent, Worker, WorkerError, PROTOCOL_VERSION};
+use crate::manifest::Manifest;
+
+/// The well-known name the service owns on the bus.
+pub const BUS_NAME: &str = "org.osbuild.Worker1";
+
+/// The object the worker is served at.
+pub const OBJECT_PATH: &str = "/org/osbuild/Worker1";
+
+/// The interface of the worker.
+pub const INTERFACE: &str = "org.osbuild.Worker1";
+
+/// The errors of the methods, as `org.osbuild.Worker1.Error.<variant>`.
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "org.osbuild.Worker1.Error")]
+pub enum ServiceError {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+
+    /// The submitted manifest can't be read.
+    InvalidManifest(String),
+
+    /// There is no job with the id.
+    NoSuchJob(String),
+
+    /// The job is done already.
+    Finished(String),
+
+    /// The worker failed to queue the job.
+    Failed(String),
+}
+
+impl From<WorkerError> for ServiceError {
+    fn from(err: WorkerError) -> Self {
+        match err {
+            WorkerError::NoSuchJob(job) => Self::NoSuchJob(format!("no job {}", job)),
+            WorkerError::Finished(job) => Self::Finished(format!("job {} is done", job)),
+            WorkerError::IOError(err) => Self::Failed(err.to_string()),
+            err => Self::Failed(format!("{:?}", err)),
+        }
+    }
+}
+
+/// Which bus to serve the worker on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Session,
+    System,
+}
+
+struct Service {
+    worker: Worker,
+}
+
+/// The methods mirror those of the control protocol, see `Worker::handle`. Jobs are reported
+/// with the `JobChanged` and `JobLog` signals as they happen, so frontends don't poll.
+#[zbus::interface(name = "org.osbuild.Worker1")]
+impl Service {
+    /// The version of the control protocol the methods follow.
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        PROTOCOL_VERSION
+    }
+
+    /// Queue a build of the manifest, given as JSON. Returns the id of its job.
+    fn submit(&self, manifest: &str) -> Result<u64, ServiceError> {
+        let manifest: Manifest = serde_json::from_str(manifest)
+            .map_err(|err| ServiceError::InvalidManifest(err.to_string()))?;
+
+        Ok(self.worker.submit(manifest)?)
+    }
+
+    /// The state of a job and why it failed, if it did.
+    fn status(&self, job: u64) -> Result<(String, String), ServiceError> {
+        let status = self.worker.status(job)?;
+
+        Ok((
+            status.state.as_str().to_string(),
+            status.error.unwrap_or_default(),
+        ))
+    }
+
+    /// Cancel a job, returns its state.
+    fn cancel(&self, job: u64) -> Result<String, ServiceError> {
+        Ok(self.worker.cancel(job)?.state.as_str().to_string())
+    }
+
+    /// The lines of the log of a job from `offset`, the offset of the lines after them, and
+    /// whether the job is done.
+    fn logs(&self, job: u64, offset: u64) -> Result<(Vec<String>, u64, bool), ServiceError> {
+        let logs = self.worker.logs(job, offset as usize)?;
+
+        Ok((logs.lines, logs.next as u64, logs.done))
+    }
+
+    #[zbus(signal)]
+    async fn job_changed(emitter: &SignalEmitter<'_>, job: u64, state: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn job_log(emitter: &SignalEmitter<'_>, job: u64, line: &str) -> zbus::Result<()>;
+}
+
+/// Serve `worker` on the connection `builder` makes, and signal what happens to its jobs
+/// until the connection fails.
+pub fn serve(worker: Worker, builder: Builder<'_>) -> zbus::Result<()> {
+    let events = worker.subscribe();
+
+    let connection = builder.serve_at(OBJECT_PATH, Service { worker })?.build()?;
+    let interface = connection
+        .object_server()
+        .interface::<_, Service>(OBJECT_PATH)?;
+
+    let emitter = interface.signal_emitter();
+
+    for event in events {
+        zbus::block_on(async {
+            match event {
+                JobEvent::State(job, state) => {
+                    Service::job_changed(emitter, job, state.as_str()).await
+                }
+                JobEvent::Log(job, line) => Service::job_log(emitter, job, &line).await,
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Serve `worker` as `BUS_NAME` on `bus`.
+pub fn serve_bus(worker: Worker, bus: Bus) -> zbus::Result<()> {
+    let builder = match bus {
+        Bus::Session => Builder::session()?,
+        Bus::System => Builder::system()?,
+    };
+
+    serve(worker, builder.name(BUS_NAME)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use zbus::blocking::{Connection, MessageIterator};
+    use zbus::message::Type;
+
+    use crate::core::config::Config;
+    use crate::core::worker::JobState;
+
+    fn call<B>(connection: &Connection, method: &str, body: &B) -> zbus::Result<zbus::Message>
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        connection.call_method(None::<&str>, OBJECT_PATH, Some(INTERFACE), method, body)
+    }
+
+    #[test]
+    fn served_over_dbus() {
+        let (server, client) = UnixStream::pair().unwrap();
+
+        thread::spawn(move || {
+            serve(
+                Worker::new(Config::default()),
+                Builder::unix_stream(server)
+                    .server(zbus::Guid::generate())
+                    .unwrap()
+                    .p2p(),
+            )
+        });
+
+        let connection: Connection = Builder::unix_stream(client).p2p().build().unwrap();
+        let messages = MessageIterator::from(&connection);
+
+        let reply = call(&connection, "Status", &(7u64,));
+        assert_eq!(
+            reply.unwrap_err().to_string(),
+            "org.osbuild.Worker1.Error.NoSuchJob: no job 7"
+        );
+
+        let reply = call(&connection, "Submit", &("{\"version\": \"3\"}",));
+        assert!(reply
+            .unwrap_err()
+            .to_string()
+            .starts_with("org.osbuild.Worker1.Error.InvalidManifest"));
+
+        let job: u64 = call(
+            &connection,
+            "Submit",
+            &("{\"version\": \"2\", \"pipelines\": []}",),
+        )
+        .unwrap()
+        .body()
+        .deserialize()
+        .unwrap();
+
+        // without a store the build fails, after the job changed state for all to see
+        let mut states = vec![];
+
+        for message in messages {
+            let message = message.unwrap();
+            let header = message.header();
+
+            if header.message_type() != Type::Signal
+                || header.member().map(|member| member.as_str()) != Some("JobChanged")
+            {
+                continue;
+            }
+
+            let (id, state): (u64, String) = message.body().deserialize().unwrap();
+
+            assert_eq!(id, job);
+            states.push(state);
+
+            if states.len() == 3 {
+                break;
+            }
+        }
+
+        assert_eq!(states, ["queued", "running", "failed"]);
+
+        let (state, error): (String, String) = call(&connection, "Status", &(job,))
+            .unwrap()
+            .body()
+            .deserialize()
+            .unwrap();
+
+        assert_eq!(state, JobState::Failed.as_str());
+        assert!(error.contains("NoStore"));
+    }
+}
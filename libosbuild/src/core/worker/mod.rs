@@ -0,0 +1,1294 @@
+/// JSON-RPC 2.0 requests, responses, and error codes.
+pub mod rpc;
+
+/// Socket activation and notifications for running the worker as a systemd service.
+pub mod systemd;
+
+/// A store per tenant, for workers that build for more than one client.
+pub mod tenancy;
+
+/// The worker as a D-Bus service, for desktop frontends.
+#[cfg(feature = "dbus")]
+pub mod dbus;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::core::config::{BuildConfig, Config, WorkerConfig};
+use crate::core::executor::build::build_with_config;
+use crate::core::monitor::{LogMonitor, Monitor};
+use crate::core::result::BuildResult;
+use crate::manifest::Manifest;
+use crate::module::cancel::CancellationToken;
+use crate::module::util::atomic_write;
+use crate::sandbox::communication::channel::MAX_MESSAGE_SIZE;
+
+use rpc::{Request, Response, RpcError, JSONRPC_VERSION};
+
+/// The version of the control protocol, see `Worker::handle`. Methods and fields are only
+/// ever added to a version, anything else makes a new one.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How much of the log of a job a single `logs` response carries at most, so that the
+/// response fits in a message.
+pub const MAX_LOGS_SIZE: usize = MAX_MESSAGE_SIZE / 2;
+
+/// How many jobs that are done the worker remembers when `WorkerConfig` doesn't say.
+pub const DEFAULT_FINISHED_JOBS: usize = 1000;
+
+/// The file in the queue directory with the last id handed out, so that the ids of jobs that
+/// are done aren't handed out again after a restart.
+pub const LAST_ID_FILE: &str = "last-id";
+
+/// The largest message passed in a file, for requests and responses that don't fit in a
+/// datagram, such as submits of large manifests, see `send_message`.
+pub const MAX_PASSED_SIZE: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum WorkerError {
+    /// There is no job with the id.
+    NoSuchJob(u64),
+
+    /// The job is done already.
+    Finished(u64),
+
+    /// The worker has tenants and the job names none.
+    NoTenant,
+
+    /// The name of a tenant can't be a directory, see `tenancy::is_valid`.
+    InvalidTenant(String),
+
+    /// The store of a tenant is over the quota, contains the tenant and its usage in bytes.
+    QuotaExceeded(String, u64),
+
+    /// The job is `shared` but its tenant may not share, see `WorkerConfig::shared_tenants`.
+    SharingDenied(String),
+
+    /// Keeping a queued job failed.
+    IOError(io::Error),
+}
+
+impl From<io::Error> for WorkerError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+impl From<WorkerError> for RpcError {
+    fn from(err: WorkerError) -> Self {
+        match err {
+            WorkerError::NoSuchJob(job) => {
+                RpcError::new(RpcError::NO_SUCH_JOB, format!("no job {}", job))
+            }
+            WorkerError::Finished(job) => {
+                RpcError::new(RpcError::JOB_FINISHED, format!("job {} is done", job))
+            }
+            WorkerError::NoTenant => RpcError::new(RpcError::INVALID_PARAMS, "no tenant"),
+            WorkerError::InvalidTenant(tenant) => RpcError::new(
+                RpcError::INVALID_PARAMS,
+                format!("invalid tenant '{}'", tenant),
+            ),
+            WorkerError::QuotaExceeded(tenant, usage) => RpcError::new(
+                RpcError::QUOTA_EXCEEDED,
+                format!("the store of '{}' takes {} bytes", tenant, usage),
+            ),
+            WorkerError::SharingDenied(tenant) => RpcError::new(
+                RpcError::NOT_PERMITTED,
+                format!("'{}' may not share jobs", tenant),
+            ),
+            WorkerError::IOError(err) => RpcError::new(RpcError::INTERNAL_ERROR, err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_done(&self) -> bool {
+        matches!(self, Self::Succeeded | Self::Failed | Self::Cancelled)
+    }
+
+    /// The name of the state, as in the control protocol.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// The reply to `version`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    pub protocol: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubmitParams {
+    /// The manifest to build, as JSON.
+    pub manifest: serde_json::Value,
+
+    #[serde(flatten)]
+    pub options: JobOptions,
+}
+
+/// When a job runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobOptions {
+    /// Jobs with a higher priority run first, jobs with the same priority in the order they
+    /// were submitted.
+    #[serde(default)]
+    pub priority: i32,
+
+    /// The concurrency class of the job, such as `vm`, see `WorkerConfig::classes`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+
+    /// The tenant the job is built for, see `tenancy`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    /// Build in the store tenants share instead of that of the tenant, so that the
+    /// checkpoints of the job can be reused by other tenants and it can reuse theirs.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shared: bool,
+}
+
+/// The reply to `submit`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Submitted {
+    pub job: u64,
+}
+
+/// The parameters of `status` and `cancel`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobParams {
+    pub job: u64,
+
+    /// The tenant of the job, when the worker has tenants.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+/// The reply to `status` and `cancel`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub job: u64,
+    pub state: JobState,
+
+    /// Why the job failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// The result of the build, once the job built its manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<BuildResult>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogsParams {
+    pub job: u64,
+
+    /// The tenant of the job, when the worker has tenants.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    /// The first line to return, the `next` of the previous reply.
+    #[serde(default)]
+    pub offset: usize,
+}
+
+/// The reply to `logs`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Logs {
+    pub lines: Vec<String>,
+
+    /// The offset to ask for the lines after these with.
+    pub next: usize,
+
+    /// Whether the job is done, there are no lines after `next` when it is.
+    pub done: bool,
+}
+
+/// What happened to a job, see `Worker::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobEvent {
+    /// The job is in a new state.
+    State(u64, JobState),
+
+    /// The job logged a line.
+    Log(u64, String),
+}
+
+struct Job {
+    status: JobStatus,
+    options: JobOptions,
+    logs: Vec<String>,
+    manifest: Option<Manifest>,
+    cancellation: CancellationToken,
+}
+
+#[derive(Default)]
+struct Jobs {
+    jobs: BTreeMap<u64, Job>,
+    next: u64,
+    subscribers: Vec<mpsc::Sender<JobEvent>>,
+
+    /// How many jobs of each class are running.
+    running: BTreeMap<String, usize>,
+
+    /// The jobs that are done, in the order they were done.
+    finished: VecDeque<u64>,
+
+    /// Whether the worker was dropped, runners stop once their job is done.
+    stopped: bool,
+}
+
+impl Jobs {
+    /// Send `event` to the subscribers, forgetting those that went away.
+    fn emit(&mut self, event: JobEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// The queued job to run next; the one with the highest priority, submitted first, whose
+    /// class isn't at its limit.
+    fn next_job(&self, classes: &BTreeMap<String, usize>) -> Option<u64> {
+        self.jobs
+            .iter()
+            .filter(|(_, job)| job.status.state == JobState::Queued && job.manifest.is_some())
+            .filter(|(_, job)| match &job.options.class {
+                Some(class) => classes
+                    .get(class)
+                    .is_none_or(|limit| self.running.get(class).copied().unwrap_or(0) < *limit),
+                None => true,
+            })
+            .max_by_key(|(id, job)| (job.options.priority, std::cmp::Reverse(**id)))
+            .map(|(id, _)| *id)
+    }
+
+    /// Remember that `job` is done, forgetting the jobs done longest ago beyond `limit`.
+    fn finish(&mut self, job: u64, limit: usize) {
+        self.finished.push_back(job);
+
+        while self.finished.len() > limit {
+            if let Some(forgotten) = self.finished.pop_front() {
+                self.jobs.remove(&forgotten);
+            }
+        }
+    }
+}
+
+/// The jobs, and what the runners wait on for jobs to become runnable.
+struct Shared {
+    jobs: Mutex<Jobs>,
+    changed: Condvar,
+    settings: WorkerConfig,
+}
+
+type SharedJobs = Arc<Shared>;
+
+fn lock(shared: &SharedJobs) -> MutexGuard<'_, Jobs> {
+    shared.jobs.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// A job kept in the queue directory while it isn't done.
+#[derive(Debug, Serialize, Deserialize)]
+struct QueuedJob {
+    job: u64,
+
+    #[serde(flatten)]
+    options: JobOptions,
+
+    manifest: Manifest,
+}
+
+fn queued_path(queue: &Path, job: u64) -> PathBuf {
+    queue.join(format!("{}.json", job))
+}
+
+/// Keep `job` in `queue` with `atomic_write`, so a partial one is never read and a kept job
+/// survives a crash.
+fn keep(queue: &Path, job: &QueuedJob) -> io::Result<()> {
+    fs::create_dir_all(queue)?;
+
+    atomic_write(
+        &queued_path(queue, job.job),
+        serde_json::to_vec(job)?,
+        0o600,
+    )
+}
+
+/// Forget `job`, once it is done.
+fn forget(queue: Option<&Path>, job: u64) {
+    if let Some(queue) = queue {
+        if let Err(err) = fs::remove_file(queued_path(queue, job)) {
+            if err.kind() != io::ErrorKind::NotFound {
+                log::warn!("unable to remove job {} from the queue: {}", job, err);
+            }
+        }
+    }
+}
+
+/// Keep `id` as the last id handed out in `queue`.
+fn keep_last_id(queue: &Path, id: u64) -> io::Result<()> {
+    fs::create_dir_all(queue)?;
+
+    atomic_write(&queue.join(LAST_ID_FILE), id.to_string(), 0o600)
+}
+
+/// The last id handed out by a worker with `queue`, none when it can't be read.
+fn last_id(queue: &Path) -> u64 {
+    let path = queue.join(LAST_ID_FILE);
+
+    match fs::read_to_string(&path).map(|id| id.trim().parse()) {
+        Ok(Ok(id)) => id,
+        Ok(Err(err)) => {
+            log::warn!("unable to read '{}': {}", path.display(), err);
+            0
+        }
+        Err(err) => {
+            if err.kind() != io::ErrorKind::NotFound {
+                log::warn!("unable to read '{}': {}", path.display(), err);
+            }
+
+            0
+        }
+    }
+}
+
+/// The jobs kept in `queue`, jobs that can't be read are left there.
+fn restore(queue: &Path) -> Vec<QueuedJob> {
+    let Ok(entries) = fs::read_dir(queue) else {
+        return vec![];
+    };
+
+    let mut jobs = vec![];
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+
+        match fs::read(&path).map(|data| serde_json::from_slice::<QueuedJob>(&data)) {
+            Ok(Ok(job)) => jobs.push(job),
+            Ok(Err(err)) => log::warn!("unable to restore '{}': {}", path.display(), err),
+            Err(err) => log::warn!("unable to restore '{}': {}", path.display(), err),
+        }
+    }
+
+    jobs
+}
+
+/// Appends what a `LogMonitor` writes to the log of a job, line by line.
+struct JobLog {
+    shared: SharedJobs,
+    job: u64,
+    partial: Vec<u8>,
+}
+
+impl Write for JobLog {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(data);
+
+        while let Some(end) = self.partial.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]).to_string();
+
+            let mut jobs = lock(&self.shared);
+
+            if let Some(job) = jobs.jobs.get_mut(&self.job) {
+                job.logs.push(line.clone());
+                jobs.emit(JobEvent::Log(self.job, line));
+            }
+        }
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Wait for the next job to run and mark it as running, returns nothing once the worker is
+/// dropped.
+fn take(shared: &SharedJobs) -> Option<(u64, Manifest, JobOptions, CancellationToken)> {
+    let mut jobs = lock(shared);
+
+    loop {
+        if jobs.stopped {
+            return None;
+        }
+
+        if let Some(id) = jobs.next_job(&shared.settings.classes) {
+            let job = jobs.jobs.get_mut(&id)?;
+
+            let manifest = job.manifest.take()?;
+            let options = job.options.clone();
+            let cancellation = job.cancellation.clone();
+
+            job.status.state = JobState::Running;
+
+            if let Some(class) = options.class.clone() {
+                *jobs.running.entry(class).or_default() += 1;
+            }
+
+            jobs.emit(JobEvent::State(id, JobState::Running));
+
+            return Some((id, manifest, options, cancellation));
+        }
+
+        jobs = shared
+            .changed
+            .wait(jobs)
+            .unwrap_or_else(|err| err.into_inner());
+    }
+}
+
+/// The configuration of a job with `options`; with the store of its tenant, if it has one and
+/// the store is under the quota.
+fn job_config(
+    config: &Config,
+    settings: &WorkerConfig,
+    options: &JobOptions,
+) -> Result<Config, WorkerError> {
+    tenancy::check_quota(settings, options)?;
+
+    Ok(Config {
+        store: tenancy::job_store(config, settings, options)?,
+        ..config.clone()
+    })
+}
+
+/// How many jobs that are done a worker with `settings` remembers.
+fn finished_jobs(settings: &WorkerConfig) -> usize {
+    settings.finished_jobs.unwrap_or(DEFAULT_FINISHED_JOBS)
+}
+
+/// Run jobs as they become runnable, until the worker is dropped.
+fn run(config: Config, shared: SharedJobs) {
+    while let Some((id, manifest, options, cancellation)) = take(&shared) {
+        let mut monitor = LogMonitor::new(JobLog {
+            shared: shared.clone(),
+            job: id,
+            partial: vec![],
+        });
+
+        // the store of the tenant may have filled up while the job was queued, and is
+        // watched so the job can't fill it up much past the quota either
+        let mut exceeded = Ok(());
+
+        let result = match job_config(&config, &shared.settings, &options).and_then(|config| {
+            let watch = tenancy::watch_quota(
+                &shared.settings,
+                &options,
+                &cancellation,
+                tenancy::QUOTA_INTERVAL,
+            )?;
+
+            Ok((config, watch))
+        }) {
+            Ok((config, watch)) => {
+                let result = build_with_config(
+                    &manifest,
+                    &BuildConfig::from_config(&config),
+                    &mut monitor,
+                    Some(&cancellation),
+                )
+                .map_err(|err| format!("{:?}", err));
+
+                if let Some(watch) = watch {
+                    exceeded = watch.finish();
+                }
+
+                result
+            }
+            Err(err) => Err(format!("{:?}", err)),
+        };
+
+        monitor.finish(exceeded.is_ok() && result.as_ref().is_ok_and(BuildResult::is_success));
+
+        forget(shared.settings.queue.as_deref(), id);
+
+        let mut jobs = lock(&shared);
+
+        if let Some(job) = jobs.jobs.get_mut(&id) {
+            match (result, exceeded) {
+                // the job was stopped for going over the quota, whatever the build made of it
+                (_, Err(err)) => {
+                    job.status.state = JobState::Failed;
+                    job.status.error = Some(format!("{:?}", err));
+                }
+                (Ok(result), Ok(())) => {
+                    match &result.failure {
+                        None => job.status.state = JobState::Succeeded,
+                        Some(failure) => {
+                            job.status.state = JobState::Failed;
+                            job.status.error = Some(failure.to_string());
+                        }
+                    }
+
+                    job.status.result = Some(result);
+                }
+                (Err(_), Ok(())) if cancellation.is_cancelled() => {
+                    job.status.state = JobState::Cancelled
+                }
+                (Err(err), Ok(())) => {
+                    job.status.state = JobState::Failed;
+                    job.status.error = Some(err);
+                }
+            }
+
+            let state = job.status.state;
+            let class = job.options.class.clone();
+
+            if let Some(running) = class.and_then(|class| jobs.running.get_mut(&class)) {
+                *running -= 1;
+            }
+
+            jobs.emit(JobEvent::State(id, state));
+            jobs.finish(id, finished_jobs(&shared.settings));
+        }
+
+        // a slot of the class of the job is free
+        shared.changed.notify_all();
+    }
+}
+
+/// Builds the manifests it is sent with the configuration it was started with. Jobs run in
+/// order of their priority, as many at a time as `WorkerConfig` allows. It is controlled with
+/// JSON-RPC 2.0 requests, one per message as on the channels of modules, see
+/// `Worker::serve`.
+pub struct Worker {
+    shared: SharedJobs,
+}
+
+impl Worker {
+    /// A worker with the settings of `config.worker`. Jobs kept in its queue directory by an
+    /// earlier worker are queued again under their ids, including those that were running
+    /// when it stopped, and new jobs get ids after the last one it handed out.
+    pub fn new(config: Config) -> Self {
+        let settings = config.worker.clone().unwrap_or_default();
+
+        let mut jobs = Jobs {
+            next: settings.queue.as_deref().map(last_id).unwrap_or_default(),
+            ..Default::default()
+        };
+
+        for queued in settings.queue.as_deref().map(restore).unwrap_or_default() {
+            jobs.next = jobs.next.max(queued.job);
+            jobs.jobs.insert(
+                queued.job,
+                Job {
+                    status: JobStatus {
+                        job: queued.job,
+                        state: JobState::Queued,
+                        error: None,
+                        result: None,
+                    },
+                    options: queued.options,
+                    logs: vec![],
+                    manifest: Some(queued.manifest),
+                    cancellation: CancellationToken::new(),
+                },
+            );
+        }
+
+        let runners = settings.jobs.unwrap_or(1).max(1);
+
+        let shared = Arc::new(Shared {
+            jobs: Mutex::new(jobs),
+            changed: Condvar::new(),
+            settings,
+        });
+
+        for _ in 0..runners {
+            let config = config.clone();
+            let shared = shared.clone();
+
+            thread::spawn(move || run(config, shared));
+        }
+
+        Self { shared }
+    }
+
+    /// Queue a build of `manifest`, returns the id of its job.
+    pub fn submit(&self, manifest: Manifest) -> Result<u64, WorkerError> {
+        self.submit_with(manifest, JobOptions::default())
+    }
+
+    /// Queue a build of `manifest` with `options`, returns the id of its job. The job is
+    /// kept in the queue directory, if there is one, until it is done.
+    pub fn submit_with(&self, manifest: Manifest, options: JobOptions) -> Result<u64, WorkerError> {
+        tenancy::check_quota(&self.shared.settings, &options)?;
+        tenancy::check_shared(&self.shared.settings, &options)?;
+
+        // the id is taken before the job is kept, writing the job doesn't hold up the other
+        // jobs; ids of jobs that couldn't be kept aren't used. The last id is kept under the
+        // lock, so a smaller one never replaces it
+        let id = {
+            let mut jobs = lock(&self.shared);
+
+            if let Some(queue) = &self.shared.settings.queue {
+                keep_last_id(queue, jobs.next + 1)?;
+            }
+
+            jobs.next += 1;
+            jobs.next
+        };
+
+        if let Some(queue) = &self.shared.settings.queue {
+            keep(
+                queue,
+                &QueuedJob {
+                    job: id,
+                    options: options.clone(),
+                    manifest: manifest.clone(),
+                },
+            )?;
+        }
+
+        let mut jobs = lock(&self.shared);
+
+        jobs.jobs.insert(
+            id,
+            Job {
+                status: JobStatus {
+                    job: id,
+                    state: JobState::Queued,
+                    error: None,
+                    result: None,
+                },
+                options,
+                logs: vec![],
+                manifest: Some(manifest),
+                cancellation: CancellationToken::new(),
+            },
+        );
+
+        jobs.emit(JobEvent::State(id, JobState::Queued));
+
+        self.shared.changed.notify_all();
+
+        Ok(id)
+    }
+
+    /// Receive what happens to jobs from now on, for as long as the receiver is kept.
+    pub fn subscribe(&self) -> mpsc::Receiver<JobEvent> {
+        let (sender, receiver) = mpsc::channel();
+
+        lock(&self.shared).subscribers.push(sender);
+
+        receiver
+    }
+
+    pub fn status(&self, job: u64) -> Result<JobStatus, WorkerError> {
+        lock(&self.shared)
+            .jobs
+            .get(&job)
+            .map(|job| job.status.clone())
+            .ok_or(WorkerError::NoSuchJob(job))
+    }
+
+    /// Cancel `job`. Queued jobs are cancelled right away, running ones once their stage
+    /// stopped.
+    pub fn cancel(&self, job: u64) -> Result<JobStatus, WorkerError> {
+        let mut jobs = lock(&self.shared);
+        let entry = jobs.jobs.get_mut(&job).ok_or(WorkerError::NoSuchJob(job))?;
+
+        match entry.status.state {
+            JobState::Queued => {
+                entry.status.state = JobState::Cancelled;
+                entry.manifest = None;
+
+                let status = entry.status.clone();
+
+                forget(self.shared.settings.queue.as_deref(), job);
+
+                jobs.emit(JobEvent::State(job, JobState::Cancelled));
+                jobs.finish(job, finished_jobs(&self.shared.settings));
+
+                return Ok(status);
+            }
+            JobState::Running => entry.cancellation.cancel("cancelled by the worker"),
+            _ => return Err(WorkerError::Finished(job)),
+        }
+
+        Ok(entry.status.clone())
+    }
+
+    /// The lines of the log of `job` from `offset`, as many as fit in a response.
+    pub fn logs(&self, job: u64, offset: usize) -> Result<Logs, WorkerError> {
+        let jobs = lock(&self.shared);
+        let entry = jobs.jobs.get(&job).ok_or(WorkerError::NoSuchJob(job))?;
+
+        let mut lines = vec![];
+        let mut size = 0;
+
+        for line in entry.logs.iter().skip(offset) {
+            size += line.len();
+
+            if size > MAX_LOGS_SIZE && !lines.is_empty() {
+                break;
+            }
+
+            lines.push(line.clone());
+        }
+
+        let next = offset.min(entry.logs.len()) + lines.len();
+
+        Ok(Logs {
+            done: entry.status.state.is_done() && next == entry.logs.len(),
+            lines,
+            next,
+        })
+    }
+
+    /// Whether `tenant` may see `job`. When the worker has tenants they only see their own
+    /// jobs, the jobs of others don't exist to them.
+    pub fn visible(&self, job: u64, tenant: Option<&str>) -> Result<(), WorkerError> {
+        let jobs = lock(&self.shared);
+        let entry = jobs.jobs.get(&job).ok_or(WorkerError::NoSuchJob(job))?;
+
+        if self.shared.settings.tenants.is_some() && entry.options.tenant.as_deref() != tenant {
+            return Err(WorkerError::NoSuchJob(job));
+        }
+
+        Ok(())
+    }
+
+    /// The tenant of a request from `sender` that names `tenant`. Requests sent over the
+    /// socket are of the tenant of the user that sent them, whatever tenant they name.
+    fn tenant_of_sender(&self, sender: Sender, tenant: Option<String>) -> Option<String> {
+        match sender {
+            Sender::Peer(user) if self.shared.settings.tenants.is_some() => user
+                .and_then(|user| tenancy::tenant_of_user(&self.shared.settings, user))
+                .map(String::from),
+            _ => tenant,
+        }
+    }
+
+    fn call(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+        sender: Sender,
+    ) -> Result<serde_json::Value, RpcError> {
+        fn params_of<T: DeserializeOwned>(params: serde_json::Value) -> Result<T, RpcError> {
+            serde_json::from_value(params)
+                .map_err(|err| RpcError::new(RpcError::INVALID_PARAMS, err))
+        }
+
+        let result = match method {
+            "version" => serde_json::to_value(Version {
+                protocol: PROTOCOL_VERSION,
+            }),
+            "submit" => {
+                let mut params: SubmitParams = params_of(params)?;
+
+                params.options.tenant = self.tenant_of_sender(sender, params.options.tenant);
+
+                let manifest: Manifest = serde_json::from_value(params.manifest)
+                    .map_err(|err| RpcError::new(RpcError::INVALID_PARAMS, err))?;
+
+                serde_json::to_value(Submitted {
+                    job: self.submit_with(manifest, params.options)?,
+                })
+            }
+            "status" => {
+                let params: JobParams = params_of(params)?;
+
+                let tenant = self.tenant_of_sender(sender, params.tenant);
+
+                self.visible(params.job, tenant.as_deref())?;
+
+                serde_json::to_value(self.status(params.job)?)
+            }
+            "cancel" => {
+                let params: JobParams = params_of(params)?;
+
+                let tenant = self.tenant_of_sender(sender, params.tenant);
+
+                self.visible(params.job, tenant.as_deref())?;
+
+                serde_json::to_value(self.cancel(params.job)?)
+            }
+            "logs" => {
+                let params: LogsParams = params_of(params)?;
+
+                let tenant = self.tenant_of_sender(sender, params.tenant);
+
+                self.visible(params.job, tenant.as_deref())?;
+
+                serde_json::to_value(self.logs(params.job, params.offset)?)
+            }
+            method => {
+                return Err(RpcError::new(
+                    RpcError::METHOD_NOT_FOUND,
+                    format!("no method '{}'", method),
+                ))
+            }
+        };
+
+        result.map_err(|err| RpcError::new(RpcError::INTERNAL_ERROR, err))
+    }
+
+    /// Answer the JSON-RPC request in `message`. The methods are `version`, `submit`,
+    /// `status`, `cancel`, and `logs`; see `Version`, `SubmitParams`, `JobParams`, and
+    /// `LogsParams` for their parameters and replies. Returns nothing for notifications.
+    pub fn handle(&self, message: &[u8]) -> Option<Response> {
+        self.answer(message, Sender::Trusted)
+    }
+
+    /// Answer the JSON-RPC request in `message` that `user` sent over the socket, see
+    /// `handle`. When the worker has tenants the request is of the tenant of `user`, and of
+    /// none if it's not known who sent it.
+    pub fn handle_from(&self, message: &[u8], user: Option<u32>) -> Option<Response> {
+        self.answer(message, Sender::Peer(user))
+    }
+
+    fn answer(&self, message: &[u8], sender: Sender) -> Option<Response> {
+        let value: serde_json::Value = match serde_json::from_slice(message) {
+            Ok(value) => value,
+            Err(err) => {
+                return Some(Response::error(
+                    serde_json::Value::Null,
+                    RpcError::new(RpcError::PARSE_ERROR, err),
+                ))
+            }
+        };
+
+        let request = match serde_json::from_value::<Request>(value) {
+            Ok(request) if request.jsonrpc == JSONRPC_VERSION => request,
+            _ => {
+                return Some(Response::error(
+                    serde_json::Value::Null,
+                    RpcError::new(RpcError::INVALID_REQUEST, "not a JSON-RPC 2.0 request"),
+                ))
+            }
+        };
+
+        let result = self.call(&request.method, request.params, sender);
+        let id = request.id?;
+
+        Some(match result {
+            Ok(result) => Response::result(id, result),
+            Err(err) => Response::error(id, err),
+        })
+    }
+
+    /// Answer the requests sent to `socket` until receiving fails. Every request is a
+    /// message and is answered with one to the address it came from, so clients bind their
+    /// end, see `Client`; messages that don't fit in a datagram are passed in a file, see
+    /// `send_message`. Requests are of the user that sent them, see `handle_from`.
+    pub fn serve(&self, socket: &UnixDatagram) -> io::Result<()> {
+        let mut buffer = vec![0u8; MAX_MESSAGE_SIZE + 1];
+
+        pass_credentials(socket)?;
+
+        loop {
+            let received = receive_message(socket, &mut buffer)?;
+
+            let response = match &received.message {
+                Some(message) => self.handle_from(message, received.user),
+                None => Some(Response::error(
+                    serde_json::Value::Null,
+                    RpcError::new(RpcError::INVALID_REQUEST, "the request is too large"),
+                )),
+            };
+
+            if let (Some(response), Some(path)) = (response, &received.peer) {
+                let data = serde_json::to_vec(&response).map_err(io::Error::from)?;
+
+                // clients that went away don't stop the worker
+                let _ = send_message(socket, &data, Some(path));
+            }
+        }
+    }
+}
+
+/// Who sent a request; the worker itself, or a user over the socket.
+#[derive(Debug, Clone, Copy)]
+enum Sender {
+    Trusted,
+    Peer(Option<u32>),
+}
+
+/// Have the credentials of their sender come with the datagrams received on `socket`.
+#[cfg(target_os = "linux")]
+fn pass_credentials(socket: &UnixDatagram) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let enable: libc::c_int = 1;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PASSCRED,
+            (&enable as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pass_credentials(_socket: &UnixDatagram) -> io::Result<()> {
+    Ok(())
+}
+
+/// A message received on a socket of the worker, or of a client.
+struct Received {
+    /// The message, or `None` when it is larger than a datagram or `MAX_PASSED_SIZE`.
+    message: Option<Vec<u8>>,
+
+    /// The path the message came from, unnamed and abstract addresses have none.
+    peer: Option<PathBuf>,
+
+    /// The user that sent the message, from the credentials the kernel passes along.
+    user: Option<u32>,
+}
+
+/// Send `message` on `socket` to `peer`, or to the address it is connected to. A message
+/// that doesn't fit in a datagram is written to a memfd that is passed with an empty
+/// datagram instead, see `receive_message`.
+#[cfg(target_os = "linux")]
+fn send_message(socket: &UnixDatagram, message: &[u8], peer: Option<&Path>) -> io::Result<()> {
+    use std::mem;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+
+    if message.len() <= MAX_MESSAGE_SIZE {
+        match peer {
+            Some(path) => socket.send_to(message, path)?,
+            None => socket.send(message)?,
+        };
+
+        return Ok(());
+    }
+
+    // SAFETY: the name is a nul terminated string
+    let fd = unsafe { libc::memfd_create(c"osbuild-message".as_ptr(), libc::MFD_CLOEXEC) };
+
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: the memfd was just created and is owned by nothing else
+    let mut file = fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    file.write_all(message)?;
+
+    // SAFETY: an all-zero sockaddr_un is valid, an unnamed one
+    let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let mut length = 0;
+
+    if let Some(path) = peer {
+        let path = path.as_os_str().as_bytes();
+
+        if path.len() >= address.sun_path.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the path of the peer is too long",
+            ));
+        }
+
+        address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+        for (to, from) in address.sun_path.iter_mut().zip(path) {
+            *to = *from as libc::c_char;
+        }
+
+        length = mem::size_of::<libc::sa_family_t>() + path.len() + 1;
+    }
+
+    // u64s keep the control message aligned
+    let mut control = [0u64; 4];
+    let mut iov = libc::iovec {
+        iov_base: std::ptr::null_mut(),
+        iov_len: 0,
+    };
+
+    // SAFETY: an all-zero msghdr is valid, one without a name, data, or control messages
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+
+    if peer.is_some() {
+        header.msg_name = (&mut address as *mut libc::sockaddr_un).cast();
+        header.msg_namelen = length as libc::socklen_t;
+    }
+
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr().cast();
+
+    // SAFETY: CMSG_SPACE only computes a size
+    header.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as u32) } as _;
+
+    // SAFETY: the control buffer is large and aligned enough for a message with one fd, see
+    // `msg_controllen`, so the first header and its data are within it
+    unsafe {
+        let message = libc::CMSG_FIRSTHDR(&header);
+
+        (*message).cmsg_level = libc::SOL_SOCKET;
+        (*message).cmsg_type = libc::SCM_RIGHTS;
+        (*message).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::c_int>() as u32) as _;
+
+        std::ptr::write_unaligned(libc::CMSG_DATA(message).cast(), file.as_raw_fd());
+    }
+
+    // SAFETY: the header points to the address, iovec, and control buffer above, which
+    // outlive the call
+    if unsafe { libc::sendmsg(socket.as_raw_fd(), &header, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_message(socket: &UnixDatagram, message: &[u8], peer: Option<&Path>) -> io::Result<()> {
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the message is too large",
+        ));
+    }
+
+    match peer {
+        Some(path) => socket.send_to(message, path)?,
+        None => socket.send(message)?,
+    };
+
+    Ok(())
+}
+
+/// The message in the file `fd` passed with an empty datagram, when it is a regular file,
+/// so that a peer can't block the worker with a pipe, of at most `MAX_PASSED_SIZE`.
+#[cfg(target_os = "linux")]
+fn read_passed(fd: std::os::fd::OwnedFd) -> io::Result<Option<Vec<u8>>> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = fs::File::from(fd);
+
+    if !file.metadata()?.is_file() {
+        return Ok(None);
+    }
+
+    let mut message = vec![];
+
+    file.seek(SeekFrom::Start(0))?;
+    file.take(MAX_PASSED_SIZE + 1).read_to_end(&mut message)?;
+
+    Ok((message.len() as u64 <= MAX_PASSED_SIZE).then_some(message))
+}
+
+/// Receive a message on `socket`, with `buffer` for the datagram, which is one larger than
+/// `MAX_MESSAGE_SIZE` so larger ones are noticed. An empty datagram that passes a file has
+/// the message in that file, see `send_message`.
+#[cfg(target_os = "linux")]
+fn receive_message(socket: &UnixDatagram, buffer: &mut [u8]) -> io::Result<Received> {
+    use std::ffi::OsStr;
+    use std::mem;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+
+    // u64s keep the control messages aligned, there is room for the credentials and a few
+    // fds, the kernel closes those that don't fit
+    let mut control = [0u64; 16];
+
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+
+    header.msg_name = (&mut address as *mut libc::sockaddr_un).cast();
+    header.msg_namelen = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr().cast();
+    header.msg_controllen = mem::size_of_val(&control) as _;
+
+    let size = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, libc::MSG_CMSG_CLOEXEC) };
+
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut user = None;
+    let mut fds = vec![];
+    let mut message = unsafe { libc::CMSG_FIRSTHDR(&header) };
+
+    while !message.is_null() {
+        let (level, kind) = unsafe { ((*message).cmsg_level, (*message).cmsg_type) };
+
+        if level == libc::SOL_SOCKET && kind == libc::SCM_CREDENTIALS {
+            let credentials: libc::ucred =
+                unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(message).cast()) };
+
+            user = Some(credentials.uid);
+        }
+
+        if level == libc::SOL_SOCKET && kind == libc::SCM_RIGHTS {
+            // SAFETY: the data of the control message is within the control buffer, as the
+            // kernel wrote it, and holds as many fds as fit in its length
+            unsafe {
+                let data = libc::CMSG_DATA(message);
+                let count = ((*message).cmsg_len as usize - (data as usize - message as usize))
+                    / mem::size_of::<libc::c_int>();
+
+                for index in 0..count {
+                    let fd: libc::c_int =
+                        std::ptr::read_unaligned(data.cast::<libc::c_int>().add(index));
+
+                    // the fds were passed to this process, which owns them now
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+        }
+
+        message = unsafe { libc::CMSG_NXTHDR(&header, message) };
+    }
+
+    // unnamed and abstract addresses have no path
+    let length = (header.msg_namelen as usize)
+        .saturating_sub(mem::size_of::<libc::sa_family_t>())
+        .min(address.sun_path.len());
+    let path: Vec<u8> = address.sun_path[..length]
+        .iter()
+        .map(|c| *c as u8)
+        .take_while(|c| *c != 0)
+        .collect();
+    let peer = (!path.is_empty()).then(|| PathBuf::from(OsStr::from_bytes(&path)));
+
+    let size = size as usize;
+    let message = match fds.into_iter().next() {
+        Some(fd) if size == 0 => read_passed(fd)?,
+        _ => (size <= MAX_MESSAGE_SIZE).then(|| buffer[..size].to_vec()),
+    };
+
+    Ok(Received {
+        message,
+        peer,
+        user,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn receive_message(socket: &UnixDatagram, buffer: &mut [u8]) -> io::Result<Received> {
+    let (size, peer) = socket.recv_from(buffer)?;
+
+    Ok(Received {
+        message: (size <= MAX_MESSAGE_SIZE).then(|| buffer[..size].to_vec()),
+        peer: peer.as_pathname().map(Path::to_path_buf),
+        user: None,
+    })
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        lock(&self.shared).stopped = true;
+
+        self.shared.changed.notify_all();
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The worker answered with an error.
+    Rpc(RpcError),
+
+    /// The worker answered with something that isn't a response, or not the reply expected.
+    InvalidResponse(serde_json::Error),
+
+    /// The response of the worker is larger than `MAX_PASSED_SIZE`.
+    TooLarge,
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::InvalidResponse(err)
+    }
+}
+
+/// Calls the methods of a worker over its socket, see `Worker::serve`.
+pub struct Client {
+    socket: UnixDatagram,
+    next: u64,
+}
+
+impl Client {
+    /// A client of the worker at `worker`, that receives its responses at `path`.
+    pub fn connect(worker: &Path, path: &Path) -> Result<Self, ClientError> {
+        let socket = UnixDatagram::bind(path)?;
+
+        socket.connect(worker)?;
+
+        Ok(Self { socket, next: 0 })
+    }
+
+    pub fn call<P: Serialize, R: DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params: P,
+    ) -> Result<R, ClientError> {
+        self.next += 1;
+
+        let request = Request::new(self.next, method, serde_json::to_value(params)?);
+
+        send_message(&self.socket, &serde_json::to_vec(&request)?, None)?;
+
+        let mut buffer = vec![0u8; MAX_MESSAGE_SIZE + 1];
+        let received = receive_message(&self.socket, &mut buffer)?;
+        let response: Response =
+            serde_json::from_slice(&received.message.ok_or(ClientError::TooLarge)?)?;
+
+        match (response.result, response.error) {
+            (_, Some(error)) => Err(ClientError::Rpc(error)),
+            (result, None) => Ok(serde_json::from_value(result.unwrap_or_default())?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
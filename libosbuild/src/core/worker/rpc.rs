@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+
+/// The version of JSON-RPC requests and responses are in.
+pub const JSONRPC_VERSION: &str = "2.0";
+
+/// A JSON-RPC 2.0 request. Requests without an `id` are notifications, which aren't answered.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub method: String,
+
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub params: serde_json::Value,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+}
+
+impl Request {
+    pub fn new(id: u64, method: &str, params: serde_json::Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method: method.to_string(),
+            params,
+            id: Some(id.into()),
+        }
+    }
+}
+
+/// A JSON-RPC 2.0 response, with either a `result` or an `error`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+
+    /// The id of the request, null when it couldn't be read.
+    pub id: serde_json::Value,
+}
+
+impl Response {
+    pub fn result(id: serde_json::Value, result: serde_json::Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            result: Some(result),
+            error: None,
+            id,
+        }
+    }
+
+    pub fn error(id: serde_json::Value, error: RpcError) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            result: None,
+            error: Some(error),
+            id,
+        }
+    }
+}
+
+/// The error of a response. Codes from -32768 to -32000 are reserved by JSON-RPC, the codes
+/// of the worker are from -32099 to -32000 as the specification suggests for servers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl RpcError {
+    /// The request isn't JSON.
+    pub const PARSE_ERROR: i64 = -32700;
+
+    /// The request is JSON but not a request.
+    pub const INVALID_REQUEST: i64 = -32600;
+
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    pub const INTERNAL_ERROR: i64 = -32603;
+
+    /// There is no job with the id.
+    pub const NO_SUCH_JOB: i64 = -32001;
+
+    /// The job is done already, it can't be cancelled.
+    pub const JOB_FINISHED: i64 = -32002;
+
+    /// The store of the tenant of the job is over its quota.
+    pub const QUOTA_EXCEEDED: i64 = -32003;
+
+    /// The tenant of the job may not share it.
+    pub const NOT_PERMITTED: i64 = -32004;
+
+    pub fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
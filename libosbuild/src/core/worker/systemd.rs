@@ -0,0 +1,243 @@
+use std::io;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The first file descriptor passed by socket activation.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// How many file descriptors socket activation passed to the process `pid`, given the
+/// environment `var`. Descriptors passed to another process, such as a parent, don't count.
+pub fn listen_fds(var: impl Fn(&str) -> Option<String>, pid: u32) -> usize {
+    let for_us = var("LISTEN_PID")
+        .and_then(|value| value.parse::<u32>().ok())
+        .is_some_and(|value| value == pid);
+
+    if !for_us {
+        return 0;
+    }
+
+    var("LISTEN_FDS")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// The interval the service manager expects a watchdog notification in, given the
+/// environment `var`, for the process `pid`.
+pub fn watchdog_interval(var: impl Fn(&str) -> Option<String>, pid: u32) -> Option<Duration> {
+    if let Some(value) = var("WATCHDOG_PID") {
+        if value.parse::<u32>().ok()? != pid {
+            return None;
+        }
+    }
+
+    match var("WATCHDOG_USEC")?.parse().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// The datagram socket passed by socket activation, when there is one. The variables of
+/// socket activation are removed so processes started by the worker don't take it.
+pub fn activated_socket() -> io::Result<Option<UnixDatagram>> {
+    let count = listen_fds(env, std::process::id());
+
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    match count {
+        0 => return Ok(None),
+        1 => {}
+        count => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected a single socket, got {}", count),
+            ))
+        }
+    }
+
+    let fd = LISTEN_FDS_START;
+
+    let mut kind: libc::c_int = 0;
+    let mut size = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    // SAFETY: `kind` and `size` outlive the call and `size` is the size of `kind`
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut size,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if kind != libc::SOCK_DGRAM {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the passed socket isn't a datagram socket",
+        ));
+    }
+
+    // SAFETY: socket activation hands the descriptor to this process, and it is only taken
+    // once since the variables are removed above
+    let socket = unsafe { UnixDatagram::from_raw_fd(fd) };
+
+    // SAFETY: `fd` is open, it is owned by `socket`
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(Some(socket))
+}
+
+/// Notifications to the service manager, over `NOTIFY_SOCKET`.
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+}
+
+impl Notifier {
+    /// The notifier of the service manager at `path`, names starting with `@` are abstract.
+    pub fn new(path: &str) -> io::Result<Self> {
+        let address = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+
+                SocketAddr::from_abstract_name(name)?
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "abstract sockets are only supported on Linux",
+                ))
+            }
+            None => SocketAddr::from_pathname(path)?,
+        };
+
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            address,
+        })
+    }
+
+    /// The notifier of the service manager that started the process, if any.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        env("NOTIFY_SOCKET")
+            .filter(|path| !path.is_empty())
+            .map(|path| Self::new(&path))
+            .transpose()
+    }
+
+    /// Send `state`, newline separated `KEY=value` assignments such as `READY=1`.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.address)?;
+
+        Ok(())
+    }
+
+    pub fn ready(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("READY=1\nSTATUS={}", status))
+    }
+
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1")
+    }
+
+    /// Notify the watchdog at half of `interval` from a thread, for as long as the process
+    /// lives.
+    pub fn watchdog(self: Arc<Self>, interval: Duration) {
+        thread::spawn(move || loop {
+            let _ = self.notify("WATCHDOG=1");
+
+            thread::sleep(interval / 2);
+        });
+    }
+
+    /// Notify the watchdog from a thread when the service manager asked for it.
+    pub fn watchdog_from_env(self: Arc<Self>) {
+        if let Some(interval) = watchdog_interval(env, std::process::id()) {
+            self.watchdog(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let map: HashMap<String, String> = pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        move |name| map.get(name).cloned()
+    }
+
+    #[test]
+    fn fds_counted() {
+        assert_eq!(listen_fds(vars(&[]), 10), 0);
+        assert_eq!(
+            listen_fds(vars(&[("LISTEN_PID", "10"), ("LISTEN_FDS", "2")]), 10),
+            2
+        );
+        assert_eq!(
+            listen_fds(vars(&[("LISTEN_PID", "11"), ("LISTEN_FDS", "2")]), 10),
+            0
+        );
+        assert_eq!(listen_fds(vars(&[("LISTEN_FDS", "2")]), 10), 0);
+    }
+
+    #[test]
+    fn watchdog_configured() {
+        assert_eq!(watchdog_interval(vars(&[]), 10), None);
+        assert_eq!(
+            watchdog_interval(vars(&[("WATCHDOG_USEC", "2000000")]), 10),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            watchdog_interval(
+                vars(&[("WATCHDOG_USEC", "2000000"), ("WATCHDOG_PID", "11")]),
+                10
+            ),
+            None
+        );
+        assert_eq!(watchdog_interval(vars(&[("WATCHDOG_USEC", "0")]), 10), None);
+    }
+
+    #[test]
+    fn manager_notified() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("notify");
+        let manager = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Arc::new(Notifier::new(path.to_str().unwrap()).unwrap());
+
+        notifier.ready("Listening").unwrap();
+        notifier.clone().watchdog(Duration::from_secs(60));
+
+        let mut buffer = vec![0; 256];
+
+        let size = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"READY=1\nSTATUS=Listening");
+
+        let size = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"WATCHDOG=1");
+    }
+}
@@ -0,0 +1,289 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::core::accounting;
+use crate::core::config::{Config, WorkerConfig};
+use crate::core::worker::{JobOptions, WorkerError};
+use crate::module::cancel::CancellationToken;
+
+/// The directory of the store of a tenant, below `WorkerConfig::tenants`.
+pub const STORE_DIR: &str = "store";
+
+/// How often the store of a tenant is measured while a job of the tenant runs, see
+/// `watch_quota`.
+pub const QUOTA_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tenant names become directories; letters, digits, `-`, `_`, and `.` are allowed but a
+/// name can't start with a `.`.
+pub fn is_valid(tenant: &str) -> bool {
+    !tenant.is_empty()
+        && !tenant.starts_with('.')
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// The store of `tenant`. Its cache of sources is inside it, so neither checkpoints nor
+/// sources are reused across tenants.
+pub fn store_path(tenants: &Path, tenant: &str) -> PathBuf {
+    tenants.join(tenant).join(STORE_DIR)
+}
+
+/// The tenant of a job with `options`, when the worker has tenants.
+pub fn tenant_of<'a>(
+    settings: &WorkerConfig,
+    options: &'a JobOptions,
+) -> Result<Option<&'a str>, WorkerError> {
+    if settings.tenants.is_none() {
+        return Ok(None);
+    }
+
+    match options.tenant.as_deref() {
+        Some(tenant) if is_valid(tenant) => Ok(Some(tenant)),
+        Some(tenant) => Err(WorkerError::InvalidTenant(tenant.to_string())),
+        None => Err(WorkerError::NoTenant),
+    }
+}
+
+/// The tenant whose clients run as `user`, see `WorkerConfig::tenant_users`.
+pub fn tenant_of_user(settings: &WorkerConfig, user: u32) -> Option<&str> {
+    settings
+        .tenant_users
+        .iter()
+        .find(|(_, tenant_user)| **tenant_user == user)
+        .map(|(tenant, _)| tenant.as_str())
+}
+
+/// Refuse `shared` jobs of tenants that may not share, see `WorkerConfig::shared_tenants`.
+pub fn check_shared(settings: &WorkerConfig, options: &JobOptions) -> Result<(), WorkerError> {
+    match tenant_of(settings, options)? {
+        Some(tenant) if options.shared && !settings.shared_tenants.iter().any(|t| t == tenant) => {
+            Err(WorkerError::SharingDenied(tenant.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The store a job with `options` builds in. Jobs of a tenant build in the store of the
+/// tenant, unless they are `shared` and build in the store of `config` that all tenants that
+/// share use.
+pub fn job_store(
+    config: &Config,
+    settings: &WorkerConfig,
+    options: &JobOptions,
+) -> Result<Option<PathBuf>, WorkerError> {
+    match (tenant_of(settings, options)?, &settings.tenants) {
+        (Some(tenant), Some(tenants)) if !options.shared => Ok(Some(store_path(tenants, tenant))),
+        _ => Ok(config.store.clone()),
+    }
+}
+
+/// Refuse jobs of a tenant whose store is over `WorkerConfig::quota`. Shared jobs count
+/// against no tenant.
+pub fn check_quota(settings: &WorkerConfig, options: &JobOptions) -> Result<(), WorkerError> {
+    let (Some(tenant), Some(tenants), Some(quota)) = (
+        tenant_of(settings, options)?,
+        &settings.tenants,
+        settings.quota,
+    ) else {
+        return Ok(());
+    };
+
+    if options.shared {
+        return Ok(());
+    }
+
+    let usage = accounting::disk_usage(&store_path(tenants, tenant))?;
+
+    if usage > quota {
+        return Err(WorkerError::QuotaExceeded(tenant.to_string(), usage));
+    }
+
+    Ok(())
+}
+
+/// Watches the store of a tenant while a job of the tenant runs, see `watch_quota`.
+pub struct QuotaWatch {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<Result<(), WorkerError>>,
+}
+
+impl QuotaWatch {
+    /// Stop watching; fails with the usage of the store when it went over the quota.
+    pub fn finish(self) -> Result<(), WorkerError> {
+        drop(self.stop);
+
+        self.thread.join().unwrap_or(Ok(()))
+    }
+}
+
+/// Measure the store of the tenant of a job with `options` every `interval` while the job
+/// runs, and cancel `cancellation` once it is over `WorkerConfig::quota`, so a build can't
+/// grow the store past the quota by much. Jobs without a quota, such as shared ones, aren't
+/// watched.
+pub fn watch_quota(
+    settings: &WorkerConfig,
+    options: &JobOptions,
+    cancellation: &CancellationToken,
+    interval: Duration,
+) -> Result<Option<QuotaWatch>, WorkerError> {
+    let (Some(tenant), Some(tenants), Some(quota)) = (
+        tenant_of(settings, options)?,
+        &settings.tenants,
+        settings.quota,
+    ) else {
+        return Ok(None);
+    };
+
+    if options.shared {
+        return Ok(None);
+    }
+
+    let store = store_path(tenants, tenant);
+    let tenant = tenant.to_string();
+    let cancellation = cancellation.clone();
+    let (stop, stopped) = mpsc::channel();
+
+    let thread = thread::spawn(move || {
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            match accounting::disk_usage(&store) {
+                Ok(usage) if usage > quota => {
+                    cancellation.cancel(&format!("the store of '{}' is over its quota", tenant));
+
+                    return Err(WorkerError::QuotaExceeded(tenant, usage));
+                }
+                Ok(_) => {}
+                Err(err) => log::warn!("unable to measure '{}': {}", store.display(), err),
+            }
+        }
+
+        Ok(())
+    });
+
+    Ok(Some(QuotaWatch { stop, thread }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    fn tenant(name: &str) -> JobOptions {
+        JobOptions {
+            tenant: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tenants_validated() {
+        assert!(is_valid("alice"));
+        assert!(is_valid("team-a_1.prod"));
+        assert!(!is_valid(""));
+        assert!(!is_valid(".."));
+        assert!(!is_valid(".hidden"));
+        assert!(!is_valid("a/b"));
+    }
+
+    #[test]
+    fn stores_chosen() {
+        let config = Config {
+            store: Some(PathBuf::from("/var/cache/osbuild")),
+            ..Default::default()
+        };
+        let settings = WorkerConfig {
+            tenants: Some(PathBuf::from("/var/lib/osbuild/tenants")),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            job_store(&config, &settings, &tenant("alice")).unwrap(),
+            Some(PathBuf::from("/var/lib/osbuild/tenants/alice/store"))
+        );
+        assert_eq!(
+            job_store(
+                &config,
+                &settings,
+                &JobOptions {
+                    shared: true,
+                    ..tenant("alice")
+                }
+            )
+            .unwrap(),
+            config.store
+        );
+        assert!(matches!(
+            job_store(&config, &settings, &JobOptions::default()),
+            Err(WorkerError::NoTenant)
+        ));
+        assert!(matches!(
+            job_store(&config, &settings, &tenant("../bob")),
+            Err(WorkerError::InvalidTenant(_))
+        ));
+
+        // without tenants everything builds in the one store
+        assert_eq!(
+            job_store(&config, &WorkerConfig::default(), &tenant("alice")).unwrap(),
+            config.store
+        );
+    }
+
+    #[test]
+    fn tenants_of_users() {
+        let settings = WorkerConfig {
+            tenants: Some(PathBuf::from("/var/lib/osbuild/tenants")),
+            tenant_users: [("alice".to_string(), 1000), ("bob".to_string(), 1001)].into(),
+            shared_tenants: vec!["alice".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(tenant_of_user(&settings, 1001), Some("bob"));
+        assert_eq!(tenant_of_user(&settings, 0), None);
+
+        let shared = |tenant: &str| JobOptions {
+            shared: true,
+            ..self::tenant(tenant)
+        };
+
+        assert!(check_shared(&settings, &shared("alice")).is_ok());
+        assert!(matches!(
+            check_shared(&settings, &shared("bob")),
+            Err(WorkerError::SharingDenied(tenant)) if tenant == "bob"
+        ));
+        assert!(check_shared(&settings, &tenant("bob")).is_ok());
+    }
+
+    #[test]
+    fn quotas_enforced() {
+        let directory = tempfile::tempdir().unwrap();
+        let settings = WorkerConfig {
+            tenants: Some(directory.path().to_path_buf()),
+            quota: Some(1024),
+            ..Default::default()
+        };
+
+        assert!(check_quota(&settings, &tenant("alice")).is_ok());
+
+        let store = store_path(directory.path(), "alice");
+
+        fs::create_dir_all(&store).unwrap();
+        fs::write(store.join("object"), vec![1; 8192]).unwrap();
+
+        assert!(matches!(
+            check_quota(&settings, &tenant("alice")),
+            Err(WorkerError::QuotaExceeded(tenant, _)) if tenant == "alice"
+        ));
+        assert!(check_quota(&settings, &tenant("bob")).is_ok());
+        assert!(check_quota(
+            &settings,
+            &JobOptions {
+                shared: true,
+                ..tenant("alice")
+            }
+        )
+        .is_ok());
+    }
+}
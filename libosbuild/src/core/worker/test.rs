@@ -0,0 +1,757 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use super::*;
+
+use crate::core::store::ObjectStore;
+
+/// A configuration with a store and the stages `org.osbuild.touch`, which creates `built` in
+/// the tree, and `org.osbuild.sleep`, which takes half a second.
+fn config(directory: &Path) -> Config {
+    let stages = directory.join("modules/stages");
+
+    fs::create_dir_all(&stages).unwrap();
+
+    for (name, body) in [
+        (
+            "org.osbuild.touch",
+            "tree=$(sed -n 's/.*\"tree\":\"\\([^\"]*\\)\".*/\\1/p')\ntouch \"$tree/built\"",
+        ),
+        ("org.osbuild.sleep", "sleep 0.5"),
+    ] {
+        let path = stages.join(name);
+
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\n[ \"$1\" = --schema ] && echo '{{}}' && exit 0\n{}\n",
+                body
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    Config {
+        store: Some(directory.join("store")),
+        module_paths: Some(vec![directory.join("modules")]),
+        ..Default::default()
+    }
+}
+
+fn manifest(stages: &[&str]) -> serde_json::Value {
+    serde_json::json!({
+        "version": "2",
+        "pipelines": [{
+            "name": "tree",
+            "stages": stages
+                .iter()
+                .map(|kind| serde_json::json!({"type": kind}))
+                .collect::<Vec<_>>()
+        }]
+    })
+}
+
+fn submit(worker: &Worker, stages: &[&str], options: JobOptions) -> u64 {
+    worker
+        .submit_with(serde_json::from_value(manifest(stages)).unwrap(), options)
+        .unwrap()
+}
+
+fn options(priority: i32, class: &str) -> JobOptions {
+    JobOptions {
+        priority,
+        class: Some(class.to_string()),
+        ..Default::default()
+    }
+}
+
+/// The jobs in the order they started running.
+fn started(events: &mpsc::Receiver<JobEvent>, count: usize) -> Vec<u64> {
+    let mut started = vec![];
+
+    while started.len() < count {
+        if let JobEvent::State(job, JobState::Running) =
+            events.recv_timeout(Duration::from_secs(10)).unwrap()
+        {
+            started.push(job);
+        }
+    }
+
+    started
+}
+
+fn request(worker: &Worker, method: &str, params: serde_json::Value) -> Response {
+    worker
+        .handle(&serde_json::to_vec(&Request::new(1, method, params)).unwrap())
+        .unwrap()
+}
+
+/// How many jobs are kept in `queue`.
+fn queued(queue: &Path) -> usize {
+    fs::read_dir(queue)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .count()
+}
+
+fn wait(worker: &Worker, job: u64) -> JobStatus {
+    let started = Instant::now();
+
+    loop {
+        let status = worker.status(job).unwrap();
+
+        if status.state.is_done() || started.elapsed() > Duration::from_secs(10) {
+            return status;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn error_code(response: Response) -> i64 {
+    response.error.unwrap().code
+}
+
+#[test]
+fn requests_answered() {
+    let worker = Worker::new(Config::default());
+
+    assert_eq!(
+        request(&worker, "version", serde_json::Value::Null).result,
+        Some(serde_json::json!({"protocol": PROTOCOL_VERSION}))
+    );
+    assert_eq!(
+        error_code(worker.handle(b"{").unwrap()),
+        RpcError::PARSE_ERROR
+    );
+    assert_eq!(
+        error_code(
+            worker
+                .handle(br#"{"jsonrpc": "1.0", "method": "version", "id": 1}"#)
+                .unwrap()
+        ),
+        RpcError::INVALID_REQUEST
+    );
+    assert_eq!(
+        error_code(request(&worker, "build", serde_json::Value::Null)),
+        RpcError::METHOD_NOT_FOUND
+    );
+    assert_eq!(
+        error_code(request(
+            &worker,
+            "submit",
+            serde_json::json!({"manifest": {"version": "3"}})
+        )),
+        RpcError::INVALID_PARAMS
+    );
+    assert_eq!(
+        error_code(request(&worker, "status", serde_json::json!({"job": 7}))),
+        RpcError::NO_SUCH_JOB
+    );
+
+    // notifications are not answered
+    assert!(worker
+        .handle(br#"{"jsonrpc": "2.0", "method": "version"}"#)
+        .is_none());
+}
+
+#[test]
+fn jobs_built() {
+    let directory = tempfile::tempdir().unwrap();
+    let worker = Worker::new(config(directory.path()));
+
+    let submitted: Submitted = serde_json::from_value(
+        request(
+            &worker,
+            "submit",
+            serde_json::json!({"manifest": manifest(&["org.osbuild.touch"])}),
+        )
+        .result
+        .unwrap(),
+    )
+    .unwrap();
+
+    let status = wait(&worker, submitted.job);
+
+    assert_eq!(status.state, JobState::Succeeded);
+
+    // the result of the build is kept with the job
+    let result = status.result.unwrap();
+
+    assert!(result.is_success());
+    assert!(result.resources.contains_key("tree"));
+
+    let store = ObjectStore::new(&directory.path().join("store"));
+    let id = store.ids().unwrap().pop().unwrap();
+
+    assert!(store.tree_path(&id).unwrap().join("built").exists());
+
+    let logs = worker.logs(submitted.job, 0).unwrap();
+
+    assert!(logs.done);
+    assert_eq!(
+        logs.lines.first().map(String::as_str),
+        Some("Pipeline tree")
+    );
+    assert_eq!(
+        logs.lines.last().map(String::as_str),
+        Some("Build succeeded")
+    );
+    assert_eq!(
+        worker.logs(submitted.job, logs.next).unwrap().lines.len(),
+        0
+    );
+
+    assert_eq!(
+        error_code(request(
+            &worker,
+            "cancel",
+            serde_json::json!({"job": submitted.job})
+        )),
+        RpcError::JOB_FINISHED
+    );
+
+    // failures are reported with the job
+    let failed = submit(&worker, &["org.osbuild.missing"], JobOptions::default());
+    let status = wait(&worker, failed);
+
+    assert_eq!(status.state, JobState::Failed);
+    assert!(status.error.unwrap().contains("org.osbuild.missing"));
+}
+
+#[test]
+fn events_received() {
+    let directory = tempfile::tempdir().unwrap();
+    let worker = Worker::new(config(directory.path()));
+    let events = worker.subscribe();
+
+    let job = submit(&worker, &["org.osbuild.touch"], JobOptions::default());
+
+    let mut states = vec![];
+    let mut lines = 0;
+
+    while let Ok(event) = events.recv_timeout(Duration::from_secs(10)) {
+        match event {
+            JobEvent::State(id, state) => {
+                assert_eq!(id, job);
+                states.push(state);
+
+                if state.is_done() {
+                    break;
+                }
+            }
+            JobEvent::Log(_, _) => lines += 1,
+        }
+    }
+
+    assert_eq!(
+        states,
+        [JobState::Queued, JobState::Running, JobState::Succeeded]
+    );
+    assert_eq!(lines, worker.logs(job, 0).unwrap().lines.len());
+}
+
+#[test]
+fn jobs_prioritized() {
+    let directory = tempfile::tempdir().unwrap();
+    let worker = Worker::new(config(directory.path()));
+    let events = worker.subscribe();
+
+    // the others queue up behind the first
+    let first = submit(&worker, &["org.osbuild.sleep"], JobOptions::default());
+
+    assert_eq!(started(&events, 1), [first]);
+
+    let low = submit(&worker, &["org.osbuild.touch"], options(-1, "small"));
+    let normal = submit(&worker, &["org.osbuild.touch"], JobOptions::default());
+    let high = submit(&worker, &["org.osbuild.touch"], options(10, "small"));
+
+    assert_eq!(started(&events, 3), [high, normal, low]);
+}
+
+#[test]
+fn classes_limited() {
+    let directory = tempfile::tempdir().unwrap();
+    let worker = Worker::new(Config {
+        worker: Some(WorkerConfig {
+            jobs: Some(2),
+            classes: BTreeMap::from([("vm".to_string(), 1)]),
+            ..Default::default()
+        }),
+        ..config(directory.path())
+    });
+    let events = worker.subscribe();
+
+    let vm = submit(&worker, &["org.osbuild.sleep"], options(0, "vm"));
+    let second = submit(&worker, &["org.osbuild.sleep"], options(0, "vm"));
+    let other = submit(&worker, &["org.osbuild.sleep"], JobOptions::default());
+
+    // the second job of the class waits for the first, the job after it doesn't
+    assert_eq!(started(&events, 2), [vm, other]);
+    assert_eq!(worker.status(second).unwrap().state, JobState::Queued);
+    assert_eq!(started(&events, 1), [second]);
+}
+
+#[test]
+fn jobs_restored() {
+    let directory = tempfile::tempdir().unwrap();
+    let queue = directory.path().join("queue");
+
+    let settings = |classes: &[(&str, usize)]| WorkerConfig {
+        classes: classes
+            .iter()
+            .map(|(class, limit)| (class.to_string(), *limit))
+            .collect(),
+        queue: Some(queue.clone()),
+        ..Default::default()
+    };
+
+    // nothing of the class runs, so the jobs stay queued
+    let worker = Worker::new(Config {
+        worker: Some(settings(&[("held", 0)])),
+        ..config(directory.path())
+    });
+
+    let first = submit(&worker, &["org.osbuild.touch"], options(0, "held"));
+    let second = submit(&worker, &["org.osbuild.touch"], options(0, "held"));
+
+    worker.cancel(first).unwrap();
+
+    drop(worker);
+
+    assert_eq!(queued(&queue), 1);
+
+    let worker = Worker::new(Config {
+        worker: Some(settings(&[])),
+        ..config(directory.path())
+    });
+
+    assert!(matches!(
+        worker.status(first),
+        Err(WorkerError::NoSuchJob(_))
+    ));
+    assert_eq!(wait(&worker, second).state, JobState::Succeeded);
+    assert_eq!(queued(&queue), 0);
+
+    // ids continue after those of the restored jobs
+    let third = submit(&worker, &["org.osbuild.touch"], JobOptions::default());
+
+    assert_eq!(third, second + 1);
+    assert_eq!(wait(&worker, third).state, JobState::Succeeded);
+
+    drop(worker);
+
+    // and after those of jobs that are done, which aren't restored
+    let worker = Worker::new(Config {
+        worker: Some(settings(&[])),
+        ..config(directory.path())
+    });
+
+    assert_eq!(
+        submit(&worker, &["org.osbuild.touch"], JobOptions::default()),
+        third + 1
+    );
+}
+
+#[test]
+fn finished_jobs_forgotten() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let worker = Worker::new(Config {
+        worker: Some(WorkerConfig {
+            // nothing of the class runs, so jobs of it stay queued
+            classes: [("held".to_string(), 0)].into(),
+            finished_jobs: Some(1),
+            ..Default::default()
+        }),
+        ..config(directory.path())
+    });
+
+    let first = submit(&worker, &["org.osbuild.touch"], JobOptions::default());
+
+    assert_eq!(wait(&worker, first).state, JobState::Succeeded);
+
+    let second = submit(&worker, &["org.osbuild.touch"], JobOptions::default());
+
+    assert_eq!(wait(&worker, second).state, JobState::Succeeded);
+    assert!(matches!(
+        worker.status(first),
+        Err(WorkerError::NoSuchJob(_))
+    ));
+
+    // cancelled jobs are done as well
+    let third = submit(&worker, &["org.osbuild.touch"], options(0, "held"));
+
+    worker.cancel(third).unwrap();
+
+    assert!(matches!(
+        worker.status(second),
+        Err(WorkerError::NoSuchJob(_))
+    ));
+    assert_eq!(worker.status(third).unwrap().state, JobState::Cancelled);
+}
+
+#[test]
+fn tenants_isolated() {
+    let directory = tempfile::tempdir().unwrap();
+    let tenants = directory.path().join("tenants");
+
+    let worker = Worker::new(Config {
+        worker: Some(WorkerConfig {
+            tenants: Some(tenants.clone()),
+            quota: Some(0),
+            tenant_users: [("alice".to_string(), 1000), ("bob".to_string(), 1001)].into(),
+            shared_tenants: vec!["alice".to_string()],
+            ..Default::default()
+        }),
+        ..config(directory.path())
+    });
+
+    let submit_as = |tenant: Option<&str>, shared: bool| {
+        request(
+            &worker,
+            "submit",
+            serde_json::json!({
+                "manifest": manifest(&["org.osbuild.touch"]),
+                "tenant": tenant,
+                "shared": shared,
+            }),
+        )
+    };
+
+    assert_eq!(error_code(submit_as(None, false)), RpcError::INVALID_PARAMS);
+    assert_eq!(
+        error_code(submit_as(Some("../bob"), false)),
+        RpcError::INVALID_PARAMS
+    );
+
+    let job = |response: Response| -> u64 {
+        serde_json::from_value::<Submitted>(response.result.unwrap())
+            .unwrap()
+            .job
+    };
+
+    let alice = job(submit_as(Some("alice"), false));
+
+    assert_eq!(wait(&worker, alice).state, JobState::Succeeded);
+
+    // the checkpoints of alice are hers alone
+    let store = |path: PathBuf| ObjectStore::new(&path).ids().unwrap().len();
+
+    assert_eq!(store(tenancy::store_path(&tenants, "alice")), 1);
+    assert_eq!(store(tenancy::store_path(&tenants, "bob")), 0);
+    assert_eq!(store(directory.path().join("store")), 0);
+
+    // others don't see her jobs
+    assert_eq!(
+        error_code(request(
+            &worker,
+            "status",
+            serde_json::json!({"job": alice, "tenant": "bob"})
+        )),
+        RpcError::NO_SUCH_JOB
+    );
+    assert!(request(
+        &worker,
+        "logs",
+        serde_json::json!({"job": alice, "tenant": "alice"})
+    )
+    .result
+    .is_some());
+
+    // her store is over the quota now, though she can still build what she shares
+    assert_eq!(
+        error_code(submit_as(Some("alice"), false)),
+        RpcError::QUOTA_EXCEEDED
+    );
+
+    let shared = job(submit_as(Some("alice"), true));
+
+    assert_eq!(wait(&worker, shared).state, JobState::Succeeded);
+    assert_eq!(store(directory.path().join("store")), 1);
+
+    // bob may not share
+    assert_eq!(
+        error_code(submit_as(Some("bob"), true)),
+        RpcError::NOT_PERMITTED
+    );
+
+    // over the socket requests are of the tenant of the user that sent them
+    let request_from = |user: Option<u32>, method: &str, params: serde_json::Value| {
+        worker
+            .handle_from(
+                &serde_json::to_vec(&Request::new(1, method, params)).unwrap(),
+                user,
+            )
+            .unwrap()
+    };
+
+    assert_eq!(
+        error_code(request_from(
+            Some(1001),
+            "status",
+            serde_json::json!({"job": alice, "tenant": "alice"})
+        )),
+        RpcError::NO_SUCH_JOB
+    );
+    assert!(
+        request_from(Some(1000), "status", serde_json::json!({"job": alice}))
+            .result
+            .is_some()
+    );
+    assert_eq!(
+        error_code(request_from(
+            Some(1001),
+            "submit",
+            serde_json::json!({
+                "manifest": manifest(&["org.osbuild.touch"]),
+                "tenant": "alice",
+                "shared": true,
+            })
+        )),
+        RpcError::NOT_PERMITTED
+    );
+    assert_eq!(
+        error_code(request_from(
+            None,
+            "submit",
+            serde_json::json!({
+                "manifest": manifest(&["org.osbuild.touch"]),
+                "tenant": "alice",
+            })
+        )),
+        RpcError::INVALID_PARAMS
+    );
+}
+
+#[test]
+fn jobs_cancelled() {
+    let directory = tempfile::tempdir().unwrap();
+    let worker = Worker::new(config(directory.path()));
+
+    let running = submit(
+        &worker,
+        &["org.osbuild.sleep", "org.osbuild.touch"],
+        JobOptions::default(),
+    );
+    let queued = submit(&worker, &["org.osbuild.touch"], JobOptions::default());
+
+    while worker.status(running).unwrap().state == JobState::Queued {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(worker.cancel(queued).unwrap().state, JobState::Cancelled);
+    assert_eq!(worker.cancel(running).unwrap().state, JobState::Running);
+
+    // the stage that was running finishes, the one after it never starts
+    assert_eq!(wait(&worker, running).state, JobState::Cancelled);
+    assert_eq!(wait(&worker, queued).state, JobState::Cancelled);
+    assert!(ObjectStore::new(&directory.path().join("store"))
+        .ids()
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn served_over_sockets() {
+    let directory = tempfile::tempdir().unwrap();
+    let path: PathBuf = directory.path().join("worker");
+    let socket = UnixDatagram::bind(&path).unwrap();
+
+    thread::spawn(move || Worker::new(Config::default()).serve(&socket));
+
+    let mut client = Client::connect(&path, &directory.path().join("client")).unwrap();
+
+    let version: Version = client.call("version", serde_json::Value::Null).unwrap();
+
+    assert_eq!(version.protocol, PROTOCOL_VERSION);
+    assert!(matches!(
+        client.call::<_, JobStatus>(
+            "status",
+            JobParams {
+                job: 1,
+                tenant: None
+            }
+        ),
+        Err(ClientError::Rpc(RpcError {
+            code: RpcError::NO_SUCH_JOB,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn large_messages_passed() {
+    let directory = tempfile::tempdir().unwrap();
+    let path: PathBuf = directory.path().join("worker");
+    let socket = UnixDatagram::bind(&path).unwrap();
+    let worker = Worker::new(config(directory.path()));
+
+    thread::spawn(move || worker.serve(&socket));
+
+    let mut client = Client::connect(&path, &directory.path().join("client")).unwrap();
+
+    // neither the manifest nor the options of the stage in the result fit in a datagram
+    let padding = "x".repeat(2 * MAX_MESSAGE_SIZE);
+    let manifest = serde_json::json!({
+        "version": "2",
+        "pipelines": [{
+            "name": "tree",
+            "stages": [{"type": "org.osbuild.touch", "options": {"padding": &padding}}]
+        }]
+    });
+
+    let submitted: Submitted = client
+        .call("submit", serde_json::json!({"manifest": manifest}))
+        .unwrap();
+
+    let started = Instant::now();
+
+    let status = loop {
+        let status: JobStatus = client
+            .call(
+                "status",
+                JobParams {
+                    job: submitted.job,
+                    tenant: None,
+                },
+            )
+            .unwrap();
+
+        if status.state.is_done() || started.elapsed() > Duration::from_secs(10) {
+            break status;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    assert_eq!(status.state, JobState::Succeeded);
+
+    let options = serde_json::to_string(&status.result.unwrap().effective_options).unwrap();
+
+    assert!(options.contains(&padding));
+}
+
+#[test]
+fn quota_enforced_while_running() {
+    let directory = tempfile::tempdir().unwrap();
+    let config = config(directory.path());
+    let fill = directory.path().join("modules/stages/org.osbuild.fill");
+
+    // grows the tree past the quota, and takes long enough for that to be noticed
+    fs::write(
+        &fill,
+        "#!/bin/sh
+[ \"$1\" = --schema ] && echo '{}' && exit 0
+\
+         tree=$(sed -n 's/.*\"tree\":\"\\([^\"]*\\)\".*/\\1/p')
+\
+         head -c 1048576 /dev/zero > \"$tree/filled\"
+sleep 2
+",
+    )
+    .unwrap();
+    fs::set_permissions(&fill, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let worker = Worker::new(Config {
+        worker: Some(WorkerConfig {
+            tenants: Some(directory.path().join("tenants")),
+            quota: Some(512 * 1024),
+            ..Default::default()
+        }),
+        ..config
+    });
+
+    let job = submit(
+        &worker,
+        &["org.osbuild.fill", "org.osbuild.touch"],
+        JobOptions {
+            tenant: Some("alice".to_string()),
+            ..Default::default()
+        },
+    );
+
+    let status = wait(&worker, job);
+
+    assert_eq!(status.state, JobState::Failed);
+    assert!(status.error.unwrap().contains("QuotaExceeded"));
+
+    // the stage after the one that went over the quota never ran
+    assert!(ObjectStore::new(&tenancy::store_path(
+        &directory.path().join("tenants"),
+        "alice"
+    ))
+    .ids()
+    .unwrap()
+    .is_empty());
+}
+
+#[test]
+fn tenants_of_peers() {
+    let directory = tempfile::tempdir().unwrap();
+    let path: PathBuf = directory.path().join("worker");
+    let socket = UnixDatagram::bind(&path).unwrap();
+
+    let worker = Worker::new(Config {
+        worker: Some(WorkerConfig {
+            tenants: Some(directory.path().join("tenants")),
+            tenant_users: [("alice".to_string(), unsafe { libc::getuid() })].into(),
+            ..Default::default()
+        }),
+        ..config(directory.path())
+    });
+
+    thread::spawn(move || worker.serve(&socket));
+
+    let mut client = Client::connect(&path, &directory.path().join("client")).unwrap();
+
+    // requests sent before the worker passes credentials have none
+    let _: Version = client.call("version", serde_json::Value::Null).unwrap();
+
+    // whatever tenant the client names, its jobs are those of alice
+    let submitted: Submitted = client
+        .call(
+            "submit",
+            serde_json::json!({
+                "manifest": manifest(&["org.osbuild.touch"]),
+                "tenant": "bob",
+            }),
+        )
+        .unwrap();
+
+    let status: JobStatus = client
+        .call(
+            "status",
+            JobParams {
+                job: submitted.job,
+                tenant: Some("alice".to_string()),
+            },
+        )
+        .unwrap();
+
+    assert_eq!(status.job, submitted.job);
+    assert!(matches!(
+        client.call::<_, Submitted>(
+            "submit",
+            serde_json::json!({
+                "manifest": manifest(&["org.osbuild.touch"]),
+                "tenant": "alice",
+                "shared": true,
+            })
+        ),
+        Err(ClientError::Rpc(RpcError {
+            code: RpcError::NOT_PERMITTED,
+            ..
+        }))
+    ));
+}
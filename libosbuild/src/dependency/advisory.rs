@@ -0,0 +1,287 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dependency::solver::{Package, Solution};
+use crate::dependency::version::Evr;
+
+/// The kind of update an advisory is, as `updateinfo.xml` has it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdvisoryKind {
+    Security,
+    Bugfix,
+    Enhancement,
+    Newpackage,
+
+    /// Kinds repositories make up themselves.
+    #[serde(other)]
+    Other,
+}
+
+/// How severe the issues an advisory fixes are, from least to most severe. Advisories that
+/// don't say have `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Severity {
+    #[default]
+    None,
+    Low,
+    Moderate,
+    Important,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A package an advisory updates to, the update that fixes it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdvisoryPackage {
+    pub name: String,
+
+    #[serde(default)]
+    pub epoch: u32,
+
+    pub version: String,
+    pub release: String,
+    pub arch: String,
+}
+
+impl AdvisoryPackage {
+    pub fn evr(&self) -> Evr {
+        Evr::new(self.epoch, &self.version, &self.release)
+    }
+
+    /// Whether `package` is this one in another version; `noarch` is the same package on
+    /// every architecture.
+    fn updates(&self, package: &Package) -> bool {
+        self.name == package.name
+            && (self.arch == package.arch || self.arch == "noarch" || package.arch == "noarch")
+    }
+}
+
+/// An advisory from the `updateinfo` of a repository, such as `FEDORA-2024-1d3f5e7a9b`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Advisory {
+    pub id: String,
+
+    #[serde(rename = "type")]
+    pub kind: AdvisoryKind,
+
+    #[serde(default)]
+    pub severity: Severity,
+
+    #[serde(default)]
+    pub title: String,
+
+    /// The CVE ids of the issues the advisory fixes, `CVE-2024-3094`.
+    #[serde(default)]
+    pub cves: Vec<String>,
+
+    pub packages: Vec<AdvisoryPackage>,
+}
+
+/// Whether a resolved package is fixed by an advisory, or still affected by it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// The resolved package is the update of the advisory, or newer.
+    Fixed,
+
+    /// The resolved package is older than the update of the advisory.
+    Affected,
+}
+
+/// What an advisory means for one of the resolved packages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub advisory: String,
+    pub kind: AdvisoryKind,
+    pub severity: Severity,
+    pub cves: Vec<String>,
+
+    /// The resolved package, `name.arch`.
+    pub package: String,
+
+    pub status: Status,
+
+    /// The version that was resolved.
+    pub resolved: Evr,
+
+    /// The version of the advisory's update.
+    pub fixed_in: Evr,
+}
+
+/// Which resolved packages fix, or are affected by, the advisories of a solution; so image
+/// builds can be held back when they would ship known issues.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    /// The report for `solution`, in the order of its advisories and their packages.
+    /// Advisories for packages that weren't resolved aren't in it.
+    pub fn new(solution: &Solution) -> Self {
+        let mut findings = vec![];
+
+        for advisory in &solution.advisories {
+            for update in &advisory.packages {
+                for package in solution
+                    .packages
+                    .iter()
+                    .filter(|package| update.updates(package))
+                {
+                    let resolved = package.evr();
+                    let fixed_in = update.evr();
+
+                    findings.push(Finding {
+                        advisory: advisory.id.clone(),
+                        kind: advisory.kind,
+                        severity: advisory.severity,
+                        cves: advisory.cves.clone(),
+                        package: format!("{}.{}", package.name, package.arch),
+                        status: if resolved >= fixed_in {
+                            Status::Fixed
+                        } else {
+                            Status::Affected
+                        },
+                        resolved,
+                        fixed_in,
+                    });
+                }
+            }
+        }
+
+        Self { findings }
+    }
+
+    pub fn fixed(&self) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(|finding| finding.status == Status::Fixed)
+    }
+
+    /// The security issues the resolved packages are affected by of at least `severity`.
+    pub fn affected(&self, severity: Severity) -> impl Iterator<Item = &Finding> {
+        self.findings.iter().filter(move |finding| {
+            finding.status == Status::Affected
+                && finding.kind == AdvisoryKind::Security
+                && finding.severity >= severity
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn package(name: &str, version: &str, arch: &str) -> Package {
+        Package {
+            name: name.to_string(),
+            epoch: 0,
+            version: version.to_string(),
+            release: "1.fc40".to_string(),
+            arch: arch.to_string(),
+            repo_id: "updates".to_string(),
+            remote_location: None,
+            checksum: None,
+        }
+    }
+
+    fn solution() -> Solution {
+        serde_json::from_value(serde_json::json!({
+            "packages": [
+                {"name": "xz-libs", "version": "5.4.6", "release": "1.fc40", "arch": "x86_64"},
+                {"name": "openssl-libs", "epoch": 1, "version": "3.2.1", "release": "2.fc40", "arch": "x86_64"},
+                {"name": "tzdata", "version": "2024a", "release": "5.fc40", "arch": "noarch"}
+            ],
+            "advisories": [{
+                "id": "FEDORA-2024-7a3f",
+                "type": "security",
+                "severity": "Critical",
+                "cves": ["CVE-2024-3094"],
+                "packages": [{"name": "xz-libs", "version": "5.4.6", "release": "1.fc40", "arch": "x86_64"}]
+            }, {
+                "id": "FEDORA-2024-91c2",
+                "type": "security",
+                "severity": "Moderate",
+                "cves": ["CVE-2024-0727"],
+                "packages": [{"name": "openssl-libs", "epoch": 1, "version": "3.2.2", "release": "1.fc40", "arch": "x86_64"}]
+            }, {
+                "id": "FEDORA-2024-0b1d",
+                "type": "enhancement",
+                "packages": [
+                    {"name": "tzdata", "version": "2024b", "release": "1.fc40", "arch": "noarch"},
+                    {"name": "vim-minimal", "version": "9.1", "release": "1.fc40", "arch": "x86_64"}
+                ]
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn advisories_reported() {
+        let report = Report::new(&solution());
+
+        assert_eq!(
+            report
+                .findings
+                .iter()
+                .map(|finding| (
+                    finding.advisory.as_str(),
+                    finding.package.as_str(),
+                    finding.status
+                ))
+                .collect::<Vec<_>>(),
+            [
+                ("FEDORA-2024-7a3f", "xz-libs.x86_64", Status::Fixed),
+                ("FEDORA-2024-91c2", "openssl-libs.x86_64", Status::Affected),
+                ("FEDORA-2024-0b1d", "tzdata.noarch", Status::Affected),
+            ]
+        );
+
+        assert_eq!(report.fixed().count(), 1);
+        assert_eq!(
+            report.findings[1].fixed_in,
+            "1:3.2.2-1.fc40".parse::<Evr>().unwrap()
+        );
+
+        // only security issues count
+        let affected: Vec<&str> = report
+            .affected(Severity::Low)
+            .map(|finding| finding.advisory.as_str())
+            .collect();
+
+        assert_eq!(affected, ["FEDORA-2024-91c2"]);
+        assert_eq!(report.affected(Severity::Important).count(), 0);
+    }
+
+    #[test]
+    fn advisories_matched() {
+        let update = AdvisoryPackage {
+            name: "bash".to_string(),
+            epoch: 0,
+            version: "5.2".to_string(),
+            release: "1.fc40".to_string(),
+            arch: "x86_64".to_string(),
+        };
+
+        assert!(update.updates(&package("bash", "5.1", "x86_64")));
+        assert!(!update.updates(&package("bash", "5.1", "aarch64")));
+        assert!(!update.updates(&package("zsh", "5.1", "x86_64")));
+
+        let advisory: Advisory = serde_json::from_value(serde_json::json!({
+            "id": "CUSTOM-1",
+            "type": "hotfix",
+            "packages": []
+        }))
+        .unwrap();
+
+        assert_eq!(advisory.kind, AdvisoryKind::Other);
+        assert_eq!(advisory.severity, Severity::None);
+    }
+}
@@ -0,0 +1,326 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dependency::repository::Repository;
+use crate::dependency::solver::Package;
+
+/// The priority of repositories that don't set one, as dnf has it.
+pub const DEFAULT_PRIORITY: u32 = 99;
+
+/// What kind of problem keeps a request from being solved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProblemKind {
+    /// No package in any of the repositories matches a spec.
+    NoMatch,
+
+    /// A package requires something that nothing provides.
+    NothingProvides,
+
+    /// Packages conflict with each other, or their requirements can't be met together.
+    Conflict,
+
+    /// Problems the depsolver reports that aren't any of the other kinds.
+    #[serde(other)]
+    Other,
+}
+
+/// One problem of an unsolvable request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Problem {
+    pub kind: ProblemKind,
+
+    /// The spec of the request the problem is with, when it is known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spec: Option<String>,
+
+    /// The requirements that can't be met, as the depsolver words them, such as `nothing
+    /// provides libfoo.so.2 needed by bar-1.0-1.x86_64`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requirements: Vec<String>,
+
+    /// The packages the depsolver considered for the spec, for depsolvers that report them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<Package>,
+}
+
+/// Why a request couldn't be solved. Depsolvers that report their problems structured have
+/// them taken as they are, otherwise they are recovered from the messages of libsolv and dnf
+/// in the reason of the error.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnosis {
+    pub problems: Vec<Problem>,
+}
+
+impl Diagnosis {
+    /// Recover the problems from the `reason` of a depsolver's error. Each `Problem:` of
+    /// libsolv is a problem with the requirements below it, and each spec dnf couldn't match
+    /// is one as well. Lines that aren't understood are left out.
+    pub fn from_reason(reason: &str) -> Self {
+        let mut problems: Vec<Problem> = vec![];
+        let mut current: Option<Problem> = None;
+
+        for line in reason.lines().map(str::trim) {
+            if let Some(specs) = line
+                .strip_prefix("missing packages:")
+                .or_else(|| line.strip_prefix("No match for argument:"))
+            {
+                problems.extend(current.take());
+
+                for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                    problems.push(Problem {
+                        kind: ProblemKind::NoMatch,
+                        spec: Some(spec.to_string()),
+                        requirements: vec![],
+                        candidates: vec![],
+                    });
+                }
+
+                continue;
+            }
+
+            let Some(start) = line.find("Problem") else {
+                if let Some(problem) = &mut current {
+                    let requirement = line.trim_start_matches("- ");
+
+                    if !requirement.is_empty() {
+                        problem.add(requirement);
+                    }
+                }
+
+                continue;
+            };
+
+            // `Problem: ...` and `Problem 2: ...` start a problem; the text after the colon
+            // is its first requirement
+            let Some((number, requirement)) = line[start + "Problem".len()..].split_once(':')
+            else {
+                continue;
+            };
+
+            if !number.trim().chars().all(|c| c.is_ascii_digit()) {
+                continue;
+            }
+
+            problems.extend(current.take());
+
+            let mut problem = Problem {
+                kind: ProblemKind::Other,
+                spec: None,
+                requirements: vec![],
+                candidates: vec![],
+            };
+
+            problem.add(requirement.trim());
+            current = Some(problem);
+        }
+
+        problems.extend(current);
+
+        Self { problems }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Problem {
+    /// Add a requirement as libsolv words it, taking the kind and spec of the problem from
+    /// the first requirement that tells them.
+    fn add(&mut self, requirement: &str) {
+        if self.kind == ProblemKind::Other {
+            if requirement.starts_with("nothing provides ") {
+                self.kind = ProblemKind::NothingProvides;
+            } else if requirement.contains("conflicts with")
+                || requirement.contains("none of the providers can be installed")
+                || requirement.contains("conflicting requests")
+            {
+                self.kind = ProblemKind::Conflict;
+            }
+        }
+
+        if self.spec.is_none() {
+            // `package bar-1.0-1.x86_64 requires ...` and `... needed by bar-1.0-1.x86_64`
+            self.spec = requirement
+                .strip_prefix("package ")
+                .and_then(|rest| rest.split_whitespace().next())
+                .or_else(|| {
+                    requirement
+                        .split_once(" needed by ")
+                        .and_then(|(_, by)| by.split_whitespace().next())
+                })
+                .map(str::to_string);
+        }
+
+        self.requirements.push(requirement.to_string());
+    }
+}
+
+/// A diagnosis as text for people, with the candidates of each problem per repository in
+/// the order dnf prefers them; by priority, then newest first.
+pub struct Explanation<'d> {
+    diagnosis: &'d Diagnosis,
+    repositories: &'d [Repository],
+}
+
+impl<'d> Explanation<'d> {
+    pub fn new(diagnosis: &'d Diagnosis, repositories: &'d [Repository]) -> Self {
+        Self {
+            diagnosis,
+            repositories,
+        }
+    }
+
+    fn priority(&self, repo_id: &str) -> u32 {
+        self.repositories
+            .iter()
+            .find(|repository| repository.id == repo_id)
+            .and_then(|repository| repository.priority)
+            .unwrap_or(DEFAULT_PRIORITY)
+    }
+}
+
+impl fmt::Display for Explanation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, problem) in self.diagnosis.problems.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+
+            let subject = match &problem.spec {
+                Some(spec) => format!("'{}'", spec),
+                None => "the request".to_string(),
+            };
+
+            match problem.kind {
+                ProblemKind::NoMatch => writeln!(f, "no package matches {}", subject)?,
+                ProblemKind::NothingProvides => {
+                    writeln!(f, "{} requires something no package provides", subject)?
+                }
+                ProblemKind::Conflict => writeln!(f, "{} has requirements that conflict", subject)?,
+                ProblemKind::Other => writeln!(f, "{} can't be installed", subject)?,
+            }
+
+            for requirement in &problem.requirements {
+                writeln!(f, "  - {}", requirement)?;
+            }
+
+            let mut candidates: Vec<&Package> = problem.candidates.iter().collect();
+
+            candidates.sort_by(|a, b| {
+                (self.priority(&a.repo_id), &a.repo_id)
+                    .cmp(&(self.priority(&b.repo_id), &b.repo_id))
+                    .then_with(|| b.evr().cmp(&a.evr()))
+            });
+
+            let mut last_repo: Option<&str> = None;
+
+            for candidate in candidates {
+                if last_repo != Some(candidate.repo_id.as_str()) {
+                    writeln!(
+                        f,
+                        "  candidates in {} (priority {}):",
+                        candidate.repo_id,
+                        self.priority(&candidate.repo_id)
+                    )?;
+                    last_repo = Some(&candidate.repo_id);
+                }
+
+                writeln!(
+                    f,
+                    "    {}-{}.{}",
+                    candidate.name,
+                    candidate.evr(),
+                    candidate.arch
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const REASON: &str = "There was a problem depsolving ['vim-enhanced', 'nginx']: \n Problem 1: package vim-enhanced-9.1-1.x86_64 requires vim-common = 2:9.1-1, but none of the providers can be installed\n  - conflicting requests\n  - nothing provides libgpm.so.2()(64bit) needed by vim-common-2:9.1-1.x86_64\n Problem 2: nothing provides libpcre.so.1()(64bit) needed by nginx-1.24-1.x86_64";
+
+    #[test]
+    fn reasons_diagnosed() {
+        let diagnosis = Diagnosis::from_reason(REASON);
+
+        assert_eq!(diagnosis.problems.len(), 2);
+
+        assert_eq!(diagnosis.problems[0].kind, ProblemKind::Conflict);
+        assert_eq!(
+            diagnosis.problems[0].spec.as_deref(),
+            Some("vim-enhanced-9.1-1.x86_64")
+        );
+        assert_eq!(diagnosis.problems[0].requirements.len(), 3);
+
+        assert_eq!(diagnosis.problems[1].kind, ProblemKind::NothingProvides);
+        assert_eq!(
+            diagnosis.problems[1].spec.as_deref(),
+            Some("nginx-1.24-1.x86_64")
+        );
+
+        let diagnosis = Diagnosis::from_reason(
+            "Error occurred when marking packages for installation: Problems in request:\nmissing packages: foo, bar",
+        );
+
+        assert_eq!(
+            diagnosis
+                .problems
+                .iter()
+                .map(|problem| (problem.kind, problem.spec.as_deref()))
+                .collect::<Vec<_>>(),
+            [
+                (ProblemKind::NoMatch, Some("foo")),
+                (ProblemKind::NoMatch, Some("bar"))
+            ]
+        );
+
+        assert!(Diagnosis::from_reason("something else broke").is_empty());
+    }
+
+    #[test]
+    fn diagnoses_explained() {
+        let candidate = |version: &str, repo_id: &str| Package {
+            name: "nginx".to_string(),
+            epoch: 0,
+            version: version.to_string(),
+            release: "1".to_string(),
+            arch: "x86_64".to_string(),
+            repo_id: repo_id.to_string(),
+            remote_location: None,
+            checksum: None,
+        };
+
+        let diagnosis = Diagnosis {
+            problems: vec![Problem {
+                kind: ProblemKind::NothingProvides,
+                spec: Some("nginx".to_string()),
+                requirements: vec!["nothing provides libpcre.so.1 needed by nginx".to_string()],
+                candidates: vec![
+                    candidate("1.22", "fedora"),
+                    candidate("1.24", "fedora"),
+                    candidate("1.25", "copr"),
+                ],
+            }],
+        };
+
+        let repositories = [Repository {
+            id: "copr".to_string(),
+            priority: Some(10),
+            ..Default::default()
+        }];
+
+        assert_eq!(
+            Explanation::new(&diagnosis, &repositories).to_string(),
+            "'nginx' requires something no package provides\n  - nothing provides libpcre.so.1 needed by nginx\n  candidates in copr (priority 10):\n    nginx-1.25-1.x86_64\n  candidates in fedora (priority 99):\n    nginx-1.24-1.x86_64\n    nginx-1.22-1.x86_64\n"
+        );
+    }
+}
@@ -1,4 +1,19 @@
-pub mod solver {}
+/// Package repositories as configured in `.repo` files.
+pub mod repository;
+
+/// Resolving package specs into a full set of packages to install.
+pub mod solver;
+
+/// Why requests can't be solved, and explaining that to people.
+pub mod diagnosis;
+
+/// Security and other advisories of the packages of a solution, and which of them the
+/// resolved packages fix.
+pub mod advisory;
+
+/// Comparing the versions of packages; rpm EVRs as librpm orders them, and semantic
+/// versions.
+pub mod version;
 
 #[cfg(test)]
 mod test {
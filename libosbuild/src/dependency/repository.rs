@@ -0,0 +1,431 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "executor")]
+use crate::core::sources::download::{Download, DownloadError, Downloader};
+#[cfg(feature = "executor")]
+use crate::core::sources::mirror::{self, Mirror, MirrorError, REPOMD_PATH};
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    /// A line in a repository file could not be parsed.
+    ParseError(usize, String),
+
+    /// A repository has no `baseurl`, `metalink`, or `mirrorlist`.
+    NoSource(String),
+
+    /// Downloading the metalink or mirrorlist of a repository failed.
+    #[cfg(feature = "executor")]
+    DownloadError(DownloadError),
+
+    /// None of the mirrors of a repository had its metadata, or its metalink is invalid.
+    #[cfg(feature = "executor")]
+    MirrorError(MirrorError),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for RepositoryError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+#[cfg(feature = "executor")]
+impl From<DownloadError> for RepositoryError {
+    fn from(err: DownloadError) -> Self {
+        Self::DownloadError(err)
+    }
+}
+
+#[cfg(feature = "executor")]
+impl From<MirrorError> for RepositoryError {
+    fn from(err: MirrorError) -> Self {
+        Self::MirrorError(err)
+    }
+}
+
+/// A package repository, as configured in a yum/dnf `.repo` file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Repository {
+    pub id: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub baseurl: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metalink: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirrorlist: Option<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub gpgkeys: Vec<String>,
+
+    #[serde(default)]
+    pub check_gpg: bool,
+
+    #[serde(default = "default_true")]
+    pub sslverify: bool,
+
+    /// The priority of the repository, packages come from the repository with the lowest
+    /// priority that has them regardless of their versions. dnf defaults to 99.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub priority: Option<u32>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(feature = "executor")]
+impl Repository {
+    /// The mirrors of the repository with the checksum of its `repomd.xml`, if known. These
+    /// are its `baseurl`s, or else the mirrors its metalink or mirrorlist lists; which is
+    /// downloaded anew to `cache` each time. Like dnf, a mirrorlist can be a metalink too.
+    pub fn mirrors(
+        &self,
+        downloader: &Downloader,
+        cache: &Path,
+    ) -> Result<(Vec<Mirror>, Option<String>), RepositoryError> {
+        if !self.baseurl.is_empty() {
+            return Ok((
+                self.baseurl.iter().map(|url| Mirror::new(url)).collect(),
+                None,
+            ));
+        }
+
+        let (url, kind) = match (&self.metalink, &self.mirrorlist) {
+            (Some(url), _) => (url, "metalink"),
+            (None, Some(url)) => (url, "mirrorlist"),
+            (None, None) => return Err(RepositoryError::NoSource(self.id.clone())),
+        };
+
+        let download = Download {
+            url: url.clone(),
+            destination: cache.join(format!("{}.{}", self.id, kind)),
+            checksum: None,
+        };
+
+        match fs::remove_file(&download.destination) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+
+        downloader.fetch(&download)?;
+
+        let data = fs::read_to_string(&download.destination)?;
+
+        if !data.contains("<metalink") {
+            return Ok((mirror::parse_mirrorlist(&data), None));
+        }
+
+        let file = mirror::parse_metalink(&data)?
+            .into_iter()
+            .find(|file| file.name == "repomd.xml")
+            .ok_or(MirrorError::NoMirrors)?;
+
+        Ok((file.repository_mirrors(), file.checksum()?))
+    }
+
+    /// Download the `repomd.xml` of the repository to `destination` from the first of its
+    /// mirrors that has it, see `mirrors` and `Downloader::fetch_from_mirrors`. With a
+    /// metalink it is checked against the hash the metalink has for it.
+    pub fn fetch_repomd(
+        &self,
+        downloader: &Downloader,
+        cache: &Path,
+        destination: &Path,
+    ) -> Result<(), RepositoryError> {
+        let (mirrors, checksum) = self.mirrors(downloader, cache)?;
+
+        downloader.fetch_from_mirrors(&mirrors, REPOMD_PATH, destination, checksum.as_deref())?;
+
+        Ok(())
+    }
+}
+
+/// Variables that are substituted in repository files, such as `$basearch`.
+#[derive(Debug, Clone, Default)]
+pub struct Variables {
+    pub arch: String,
+    pub releasever: Option<String>,
+}
+
+impl Variables {
+    fn substitute(&self, value: &str) -> String {
+        let mut value = value
+            .replace("${basearch}", &self.arch)
+            .replace("$basearch", &self.arch)
+            .replace("${arch}", &self.arch)
+            .replace("$arch", &self.arch);
+
+        if let Some(releasever) = &self.releasever {
+            value = value
+                .replace("${releasever}", releasever)
+                .replace("$releasever", releasever);
+        }
+
+        value
+    }
+}
+
+fn parse_bool(value: &str) -> bool {
+    matches!(
+        value.to_ascii_lowercase().as_str(),
+        "1" | "yes" | "true" | "on"
+    )
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Parse the contents of a `.repo` file. Disabled repositories (`enabled=0`) are skipped and
+/// variables are substituted in all values.
+pub fn parse(data: &str, variables: &Variables) -> Result<Vec<Repository>, RepositoryError> {
+    let mut repositories: Vec<(Repository, bool)> = vec![];
+    let mut last_key: Option<String> = None;
+
+    for (number, raw) in data.lines().enumerate() {
+        let line = raw.trim();
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            repositories.push((
+                Repository {
+                    id: line[1..line.len() - 1].trim().to_string(),
+                    sslverify: true,
+                    ..Default::default()
+                },
+                true,
+            ));
+            last_key = None;
+            continue;
+        }
+
+        let (repository, enabled) = repositories
+            .last_mut()
+            .ok_or_else(|| RepositoryError::ParseError(number + 1, raw.to_string()))?;
+
+        // continuation lines of list values are indented
+        let (key, value) = match (raw.starts_with(char::is_whitespace), &last_key) {
+            (true, Some(key)) => (key.clone(), line.to_string()),
+            _ => {
+                let (key, value) = line
+                    .split_once('=')
+                    .ok_or_else(|| RepositoryError::ParseError(number + 1, raw.to_string()))?;
+
+                (key.trim().to_string(), value.trim().to_string())
+            }
+        };
+
+        let value = variables.substitute(&value);
+
+        match key.as_str() {
+            "name" => repository.name = Some(value),
+            "baseurl" => repository.baseurl.extend(parse_list(&value)),
+            "metalink" => repository.metalink = Some(value),
+            "mirrorlist" => repository.mirrorlist = Some(value),
+            "gpgkey" => repository.gpgkeys.extend(parse_list(&value)),
+            "gpgcheck" => repository.check_gpg = parse_bool(&value),
+            "sslverify" => repository.sslverify = parse_bool(&value),
+            "enabled" => *enabled = parse_bool(&value),
+            "priority" => {
+                repository.priority = Some(
+                    value
+                        .parse()
+                        .map_err(|_| RepositoryError::ParseError(number + 1, raw.to_string()))?,
+                )
+            }
+            _ => {}
+        }
+
+        last_key = Some(key);
+    }
+
+    let repositories: Vec<Repository> = repositories
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(repository, _)| repository)
+        .collect();
+
+    for repository in &repositories {
+        if repository.baseurl.is_empty()
+            && repository.metalink.is_none()
+            && repository.mirrorlist.is_none()
+        {
+            return Err(RepositoryError::NoSource(repository.id.clone()));
+        }
+    }
+
+    Ok(repositories)
+}
+
+/// Read and parse a `.repo` file.
+pub fn load(path: &Path, variables: &Variables) -> Result<Vec<Repository>, RepositoryError> {
+    parse(&fs::read_to_string(path)?, variables)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn variables() -> Variables {
+        Variables {
+            arch: "aarch64".to_string(),
+            releasever: Some("37".to_string()),
+        }
+    }
+
+    #[test]
+    fn parse_repository_file() {
+        let repositories = parse(
+            "[fedora]\nname=Fedora $releasever - $basearch\nmetalink=https://mirrors.fedoraproject.org/metalink?repo=fedora-$releasever&arch=$basearch\nenabled=1\ngpgcheck=1\ngpgkey=file:///a\n  file:///b\n\n[fedora-debuginfo]\nbaseurl=https://example.com/\nenabled=0\n\n[local]\nbaseurl=https://a.example.com/,https://b.example.com/\nsslverify=0\npriority=10\n",
+            &variables(),
+        )
+        .unwrap();
+
+        assert_eq!(repositories.len(), 2);
+
+        assert_eq!(repositories[0].id, "fedora");
+        assert_eq!(repositories[0].name.as_deref(), Some("Fedora 37 - aarch64"));
+        assert_eq!(
+            repositories[0].metalink.as_deref(),
+            Some("https://mirrors.fedoraproject.org/metalink?repo=fedora-37&arch=aarch64")
+        );
+        assert!(repositories[0].check_gpg);
+        assert_eq!(repositories[0].gpgkeys, vec!["file:///a", "file:///b"]);
+
+        assert_eq!(repositories[1].id, "local");
+        assert_eq!(repositories[1].baseurl.len(), 2);
+        assert!(!repositories[1].sslverify);
+        assert_eq!(repositories[0].priority, None);
+        assert_eq!(repositories[1].priority, Some(10));
+    }
+
+    #[test]
+    fn parse_repository_file_errors() {
+        assert!(matches!(
+            parse("baseurl=https://example.com/\n", &variables()),
+            Err(RepositoryError::ParseError(1, _))
+        ));
+        assert!(matches!(
+            parse("[empty]\nname=nothing\n", &variables()),
+            Err(RepositoryError::NoSource(_))
+        ));
+        assert!(matches!(
+            parse("[x]\nnot a key value\n", &variables()),
+            Err(RepositoryError::ParseError(2, _))
+        ));
+        assert!(matches!(
+            parse(
+                "[x]\nbaseurl=https://example.com/\npriority=high\n",
+                &variables()
+            ),
+            Err(RepositoryError::ParseError(3, _))
+        ));
+    }
+
+    #[cfg(feature = "executor")]
+    #[test]
+    fn repomd_fetched_from_mirrors() {
+        use std::sync::Arc;
+
+        use crate::core::export;
+        use crate::core::sources::limit::Limits;
+
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path();
+
+        fs::create_dir_all(root.join("good/repodata")).unwrap();
+        fs::write(root.join("good/repodata/repomd.xml"), "<repomd/>").unwrap();
+
+        let hash = export::sha256(&root.join("good/repodata/repomd.xml")).unwrap();
+        let metalink = |hash: &str| {
+            format!(
+                r#"<metalink version="3.0" xmlns="http://www.metalinker.org/">
+ <files>
+  <file name="repomd.xml">
+   <verification><hash type="sha256">{hash}</hash></verification>
+   <resources>
+    <url preference="100">file://{0}/missing/repodata/repomd.xml</url>
+    <url preference="100">file://{0}/good/repodata/repomd.xml</url>
+   </resources>
+  </file>
+ </files>
+</metalink>"#,
+                root.display()
+            )
+        };
+
+        fs::write(root.join("metalink"), metalink(&hash)).unwrap();
+        fs::write(root.join("bad-metalink"), metalink("00")).unwrap();
+        fs::write(
+            root.join("mirrorlist"),
+            format!("file://{0}/missing/\nfile://{0}/good/\n", root.display()),
+        )
+        .unwrap();
+
+        let downloader = Downloader {
+            retries: 0,
+            ..Downloader::new("org.osbuild.curl", Arc::new(Limits::default()))
+        };
+        let repository = |metalink: Option<&str>, mirrorlist: Option<&str>| Repository {
+            id: "fedora".to_string(),
+            metalink: metalink.map(|name| format!("file://{}/{}", root.display(), name)),
+            mirrorlist: mirrorlist.map(|name| format!("file://{}/{}", root.display(), name)),
+            ..Default::default()
+        };
+
+        for (index, repository) in [
+            repository(Some("metalink"), None),
+            repository(None, Some("mirrorlist")),
+            // mirrorlists can be metalinks
+            repository(None, Some("metalink")),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let destination = root.join(format!("repomd-{}.xml", index));
+
+            repository
+                .fetch_repomd(&downloader, root, &destination)
+                .unwrap();
+
+            assert_eq!(fs::read_to_string(&destination).unwrap(), "<repomd/>");
+        }
+
+        assert_eq!(
+            repository(Some("metalink"), None)
+                .mirrors(&downloader, root)
+                .unwrap()
+                .1,
+            Some(format!("sha256:{}", hash))
+        );
+
+        // what the metalink doesn't vouch for isn't taken from any mirror
+        assert!(matches!(
+            repository(Some("bad-metalink"), None).fetch_repomd(
+                &downloader,
+                root,
+                &root.join("bad.xml")
+            ),
+            Err(RepositoryError::MirrorError(MirrorError::AllFailed(errors))) if errors.len() == 2
+        ));
+        assert!(!root.join("bad.xml").exists());
+    }
+}
@@ -0,0 +1,671 @@
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::dependency::advisory::Advisory;
+use crate::dependency::diagnosis::{Diagnosis, Problem};
+use crate::dependency::repository::Repository;
+use crate::dependency::version::Evr;
+
+/// The depsolver shipped with osbuild, it speaks JSON on stdin and stdout.
+pub const DEFAULT_DEPSOLVER: &str = "/usr/libexec/osbuild-depsolve-dnf";
+
+#[derive(Debug)]
+pub enum SolverError {
+    /// The solver ran but could not resolve the request, contains the kind of error and
+    /// the reason as reported by the solver, and the problems behind it.
+    Unsolvable {
+        kind: String,
+        reason: String,
+        diagnosis: Diagnosis,
+    },
+
+    /// The solver exited unsuccessfully without a structured error.
+    Failed(String),
+
+    /// The request contradicts itself, such as by enabling two streams of a module.
+    InvalidRequest(String),
+
+    SerializeError(serde_json::Error),
+    IOError(io::Error),
+}
+
+impl From<io::Error> for SolverError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+impl From<serde_json::Error> for SolverError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::SerializeError(err)
+    }
+}
+
+/// What to solve for.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Request {
+    pub arch: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub releasever: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module_platform_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cachedir: Option<PathBuf>,
+
+    /// Proxy the solver fetches repository metadata through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+
+    pub repos: Vec<Repository>,
+
+    /// Package specs to install, these can be package names, globs, provides, or groups when
+    /// prefixed with `@`.
+    pub specs: Vec<String>,
+
+    /// Package specs to exclude from the transaction.
+    pub exclude: Vec<String>,
+
+    /// Comps groups to install by id, such as `core`; the same as a spec of `@core`.
+    pub groups: Vec<String>,
+
+    /// Module streams to enable before resolving, so packages come from them.
+    pub enable_modules: Vec<ModuleSpec>,
+
+    /// Modules to disable by name, so their packages come from outside any stream.
+    pub disable_modules: Vec<String>,
+
+    /// How weak dependencies and newer candidates are treated.
+    pub policy: Policy,
+}
+
+/// The policy a request is resolved with. Weak dependencies, the `Recommends` of packages,
+/// are a common cause of images growing; they can be left out entirely, or only some of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    /// Whether weak dependencies are installed, as dnf's `install_weak_deps`.
+    pub install_weak_deps: bool,
+
+    /// Package specs that are never installed as weak dependencies, but are when asked for
+    /// or required; as dnf's `exclude_from_weak`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude_from_weak: Vec<String>,
+
+    /// Whether the newest candidates have to be installed, failing the request when they
+    /// can't be; otherwise older candidates are taken to satisfy dependencies.
+    pub best: bool,
+}
+
+/// The defaults are dnf's defaults, except for `best` which depsolvers for images turn off.
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            install_weak_deps: true,
+            exclude_from_weak: vec![],
+            best: false,
+        }
+    }
+}
+
+impl Request {
+    /// The package specs to install; the specs, and the groups as `@` specs.
+    pub fn package_specs(&self) -> Vec<String> {
+        let mut specs = self.specs.clone();
+
+        for group in &self.groups {
+            let spec = format!("@{}", group);
+
+            if !specs.contains(&spec) {
+                specs.push(spec);
+            }
+        }
+
+        specs
+    }
+
+    /// Check that the modules of the request don't contradict each other; a module can only
+    /// have one stream enabled, and can't be enabled and disabled.
+    pub fn check_modules(&self) -> Result<(), SolverError> {
+        for (index, module) in self.enable_modules.iter().enumerate() {
+            if self.disable_modules.contains(&module.name) {
+                return Err(SolverError::InvalidRequest(format!(
+                    "module '{}' is both enabled and disabled",
+                    module.name
+                )));
+            }
+
+            if let Some(other) = self.enable_modules[..index]
+                .iter()
+                .find(|other| other.name == module.name && other.stream != module.stream)
+            {
+                return Err(SolverError::InvalidRequest(format!(
+                    "'{}' and '{}' are streams of the same module",
+                    other, module
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A module stream as dnf takes them, `nodejs:20` or `nodejs:20/minimal` with a profile.
+/// Without a stream the default stream of the module is meant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleSpec {
+    pub name: String,
+    pub stream: Option<String>,
+    pub profile: Option<String>,
+}
+
+impl FromStr for ModuleSpec {
+    type Err = SolverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rest, profile) = match s.split_once('/') {
+            Some((rest, profile)) => (rest, Some(profile.to_string())),
+            None => (s, None),
+        };
+
+        let (name, stream) = match rest.split_once(':') {
+            Some((name, stream)) => (name, Some(stream.to_string())),
+            None => (rest, None),
+        };
+
+        let empty = |part: &Option<String>| part.as_deref() == Some("");
+
+        if name.is_empty() || empty(&stream) || empty(&profile) {
+            return Err(SolverError::InvalidRequest(format!(
+                "'{}' is not a module spec",
+                s
+            )));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            stream,
+            profile,
+        })
+    }
+}
+
+impl fmt::Display for ModuleSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        if let Some(stream) = &self.stream {
+            write!(f, ":{}", stream)?;
+        }
+
+        if let Some(profile) = &self.profile {
+            write!(f, "/{}", profile)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Module specs are serialized as dnf takes them.
+impl Serialize for ModuleSpec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A package in the resolved set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Package {
+    pub name: String,
+
+    #[serde(default)]
+    pub epoch: u32,
+
+    pub version: String,
+    pub release: String,
+    pub arch: String,
+
+    #[serde(default)]
+    pub repo_id: String,
+
+    #[serde(default)]
+    pub remote_location: Option<String>,
+
+    #[serde(default)]
+    pub checksum: Option<String>,
+}
+
+impl Package {
+    pub fn evr(&self) -> Evr {
+        Evr::new(self.epoch, &self.version, &self.release)
+    }
+}
+
+/// What a request was resolved into.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Solution {
+    pub packages: Vec<Package>,
+
+    /// The advisories the `updateinfo` of the repositories has for the resolved packages,
+    /// for solvers that report them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub advisories: Vec<Advisory>,
+
+    /// The policy the packages were resolved with, so a locked set of packages says why
+    /// weak dependencies are, or aren't, in it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<Policy>,
+}
+
+/// Resolves a set of package specs into the full set of packages to install.
+pub trait Solver {
+    fn depsolve(&self, request: &Request) -> Result<Solution, SolverError>;
+}
+
+/// Solves by running osbuild's external depsolver executable.
+pub struct DnfJsonSolver {
+    pub path: PathBuf,
+}
+
+impl DnfJsonSolver {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn new_default() -> Self {
+        Self::new(PathBuf::from(DEFAULT_DEPSOLVER))
+    }
+
+    /// The JSON document the depsolver expects on stdin for `request`. Modules and weak
+    /// dependency excludes are only passed along when the request has them, for depsolvers
+    /// that don't know of them.
+    pub fn encode(request: &Request) -> serde_json::Value {
+        let mut value = serde_json::json!({
+            "command": "depsolve",
+            "arch": request.arch,
+            "releasever": request.releasever,
+            "module_platform_id": request.module_platform_id,
+            "cachedir": request.cachedir,
+            "arguments": {
+                "repos": request.repos,
+                "transactions": [{
+                    "package-specs": request.package_specs(),
+                    "exclude-specs": request.exclude,
+                    "repo-ids": request.repos.iter().map(|repo| &repo.id).collect::<Vec<_>>(),
+                    "install_weak_deps": request.policy.install_weak_deps,
+                }],
+                "best": request.policy.best,
+            },
+        });
+
+        let transaction = &mut value["arguments"]["transactions"][0];
+
+        if !request.enable_modules.is_empty() {
+            transaction["module-enable-specs"] = serde_json::json!(request.enable_modules);
+        }
+
+        if !request.disable_modules.is_empty() {
+            transaction["module-disable-specs"] = serde_json::json!(request.disable_modules);
+        }
+
+        if !request.policy.exclude_from_weak.is_empty() {
+            transaction["exclude-from-weak-specs"] =
+                serde_json::json!(request.policy.exclude_from_weak);
+        }
+
+        if let Some(proxy) = &request.proxy {
+            value["arguments"]["proxy"] = proxy.clone().into();
+        }
+
+        value
+    }
+
+    /// Decode the output of the depsolver. Both the current object format (with a `packages`
+    /// key, and `advisories` when it reports them) and the older bare list of packages are
+    /// understood. Errors are diagnosed from their `problems`, or from their reason for
+    /// depsolvers that don't report them.
+    pub fn decode(data: &[u8]) -> Result<Solution, SolverError> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Output {
+            Error {
+                kind: String,
+                reason: String,
+                #[serde(default)]
+                problems: Vec<Problem>,
+            },
+            Solution(Solution),
+            List(Vec<Package>),
+        }
+
+        match serde_json::from_slice(data)? {
+            Output::Error {
+                kind,
+                reason,
+                problems,
+            } => {
+                let diagnosis = if problems.is_empty() {
+                    Diagnosis::from_reason(&reason)
+                } else {
+                    Diagnosis { problems }
+                };
+
+                Err(SolverError::Unsolvable {
+                    kind,
+                    reason,
+                    diagnosis,
+                })
+            }
+            Output::Solution(solution) => Ok(solution),
+            Output::List(packages) => Ok(Solution {
+                packages,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+impl Solver for DnfJsonSolver {
+    fn depsolve(&self, request: &Request) -> Result<Solution, SolverError> {
+        request.check_modules()?;
+
+        let mut child = Command::new(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(&serde_json::to_vec(&Self::encode(request))?)?;
+        }
+
+        let output = child.wait_with_output()?;
+
+        match Self::decode(&output.stdout) {
+            Ok(solution) if output.status.success() => Ok(Solution {
+                policy: Some(request.policy.clone()),
+                ..solution
+            }),
+            Err(SolverError::SerializeError(_)) if !output.status.success() => Err(
+                SolverError::Failed(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+            ),
+            Ok(_) => Err(SolverError::Failed(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            )),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn request() -> Request {
+        Request {
+            arch: "aarch64".to_string(),
+            repos: vec![Repository {
+                id: "fedora".to_string(),
+                baseurl: vec!["https://example.com/".to_string()],
+                ..Default::default()
+            }],
+            specs: vec!["@core".to_string(), "vim-minimal".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn encode_request() {
+        let value = DnfJsonSolver::encode(&request());
+
+        assert_eq!(value["command"], "depsolve");
+        assert_eq!(value["arch"], "aarch64");
+        assert_eq!(
+            value["arguments"]["transactions"][0]["package-specs"],
+            serde_json::json!(["@core", "vim-minimal"])
+        );
+        assert_eq!(
+            value["arguments"]["transactions"][0]["repo-ids"],
+            serde_json::json!(["fedora"])
+        );
+        assert!(value["arguments"].get("proxy").is_none());
+
+        let value = DnfJsonSolver::encode(&Request {
+            proxy: Some("http://proxy:3128".to_string()),
+            ..request()
+        });
+
+        assert_eq!(value["arguments"]["proxy"], "http://proxy:3128");
+        assert!(value["arguments"]["transactions"][0]
+            .get("module-enable-specs")
+            .is_none());
+    }
+
+    #[test]
+    fn policy_encoded() {
+        let value = DnfJsonSolver::encode(&request());
+
+        assert_eq!(value["arguments"]["best"], false);
+        assert_eq!(
+            value["arguments"]["transactions"][0]["install_weak_deps"],
+            true
+        );
+        assert!(value["arguments"]["transactions"][0]
+            .get("exclude-from-weak-specs")
+            .is_none());
+
+        let value = DnfJsonSolver::encode(&Request {
+            policy: Policy {
+                install_weak_deps: false,
+                exclude_from_weak: vec!["langpacks-*".to_string()],
+                best: true,
+            },
+            ..request()
+        });
+
+        let transaction = &value["arguments"]["transactions"][0];
+
+        assert_eq!(value["arguments"]["best"], true);
+        assert_eq!(transaction["install_weak_deps"], false);
+        assert_eq!(
+            transaction["exclude-from-weak-specs"],
+            serde_json::json!(["langpacks-*"])
+        );
+
+        // a policy that isn't given in full is the default for the rest
+        assert_eq!(
+            serde_json::from_value::<Policy>(serde_json::json!({"best": true})).unwrap(),
+            Policy {
+                best: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn groups_and_modules_encoded() {
+        let value = DnfJsonSolver::encode(&Request {
+            groups: vec!["core".to_string(), "standard".to_string()],
+            enable_modules: vec![
+                "nodejs:20".parse().unwrap(),
+                "postgresql:16/server".parse().unwrap(),
+            ],
+            disable_modules: vec!["perl".to_string()],
+            ..request()
+        });
+
+        let transaction = &value["arguments"]["transactions"][0];
+
+        // groups already given as specs aren't repeated
+        assert_eq!(
+            transaction["package-specs"],
+            serde_json::json!(["@core", "vim-minimal", "@standard"])
+        );
+        assert_eq!(
+            transaction["module-enable-specs"],
+            serde_json::json!(["nodejs:20", "postgresql:16/server"])
+        );
+        assert_eq!(
+            transaction["module-disable-specs"],
+            serde_json::json!(["perl"])
+        );
+    }
+
+    #[test]
+    fn modules_checked() {
+        assert_eq!(
+            "postgresql:16/server".parse::<ModuleSpec>().unwrap(),
+            ModuleSpec {
+                name: "postgresql".to_string(),
+                stream: Some("16".to_string()),
+                profile: Some("server".to_string()),
+            }
+        );
+        assert_eq!(
+            "nodejs".parse::<ModuleSpec>().unwrap().to_string(),
+            "nodejs"
+        );
+
+        for invalid in ["", ":20", "nodejs:", "nodejs:20/"] {
+            assert!(matches!(
+                invalid.parse::<ModuleSpec>(),
+                Err(SolverError::InvalidRequest(_))
+            ));
+        }
+
+        for (enable, disable) in [
+            (vec!["nodejs:18", "nodejs:20"], vec![]),
+            (vec!["nodejs:20"], vec!["nodejs"]),
+        ] {
+            let request = Request {
+                enable_modules: enable.iter().map(|spec| spec.parse().unwrap()).collect(),
+                disable_modules: disable.iter().map(|name| name.to_string()).collect(),
+                ..request()
+            };
+
+            // the depsolver isn't run for contradicting requests
+            assert!(matches!(
+                DnfJsonSolver::new(PathBuf::from("/nonexistent")).depsolve(&request),
+                Err(SolverError::InvalidRequest(_))
+            ));
+        }
+
+        // a profile of the same stream is fine
+        assert!(Request {
+            enable_modules: vec![
+                "nodejs:20".parse().unwrap(),
+                "nodejs:20/minimal".parse().unwrap()
+            ],
+            ..request()
+        }
+        .check_modules()
+        .is_ok());
+    }
+
+    #[test]
+    fn decode_output() {
+        let package = r#"{"name": "bash", "epoch": 0, "version": "5.2", "release": "1", "arch": "aarch64", "repo_id": "fedora"}"#;
+
+        assert_eq!(
+            DnfJsonSolver::decode(format!(r#"{{"packages": [{}]}}"#, package).as_bytes())
+                .unwrap()
+                .packages[0]
+                .name,
+            "bash"
+        );
+        assert_eq!(
+            DnfJsonSolver::decode(format!("[{}]", package).as_bytes())
+                .unwrap()
+                .packages
+                .len(),
+            1
+        );
+
+        let solution = DnfJsonSolver::decode(
+            format!(
+                r#"{{"packages": [{}], "advisories": [{{"id": "FEDORA-2024-1", "type": "security", "severity": "Low", "packages": []}}]}}"#,
+                package
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(solution.advisories[0].id, "FEDORA-2024-1");
+        assert!(matches!(
+            DnfJsonSolver::decode(br#"{"kind": "MarkingErrors", "reason": "no package foo"}"#),
+            Err(SolverError::Unsolvable { diagnosis, .. }) if diagnosis.is_empty()
+        ));
+        assert!(matches!(
+            DnfJsonSolver::decode(br#"{"kind": "MarkingErrors", "reason": "Problems in request:\nmissing packages: foo"}"#),
+            Err(SolverError::Unsolvable { diagnosis, .. }) if diagnosis.problems[0].spec.as_deref() == Some("foo")
+        ));
+
+        let error = DnfJsonSolver::decode(
+            format!(
+                r#"{{"kind": "DepsolveError", "reason": "broken", "problems": [{{"kind": "conflict", "spec": "bash", "candidates": [{}]}}]}}"#,
+                package
+            )
+            .as_bytes(),
+        );
+
+        assert!(matches!(
+            error,
+            Err(SolverError::Unsolvable { diagnosis, .. }) if diagnosis.problems[0].candidates[0].repo_id == "fedora"
+        ));
+    }
+
+    #[test]
+    fn depsolve_executes_solver() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("depsolve");
+
+        fs::write(
+            &path,
+            "#!/bin/sh\ncat > /dev/null\necho '{\"packages\": [{\"name\": \"vim-minimal\", \"version\": \"9\", \"release\": \"1\", \"arch\": \"aarch64\"}]}'\n",
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let request = Request {
+            policy: Policy {
+                install_weak_deps: false,
+                ..Default::default()
+            },
+            ..request()
+        };
+        let solution = DnfJsonSolver::new(path).depsolve(&request).unwrap();
+
+        assert_eq!(solution.packages.len(), 1);
+        assert_eq!(solution.packages[0].name, "vim-minimal");
+        assert_eq!(solution.policy, Some(request.policy));
+    }
+
+    #[test]
+    fn depsolve_solver_fails() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("depsolve");
+
+        fs::write(
+            &path,
+            "#!/bin/sh\ncat > /dev/null\necho broken >&2\nexit 1\n",
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert!(matches!(
+            DnfJsonSolver::new(path).depsolve(&request()),
+            Err(SolverError::Failed(reason)) if reason == "broken"
+        ));
+    }
+}
@@ -0,0 +1,563 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+
+use crate::dependency::solver::Package;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionError {
+    /// An EVR has an epoch that isn't a number, or no version; contains it.
+    InvalidEvr(String),
+
+    /// A semantic version isn't `major.minor.patch` with optional pre-release and build
+    /// identifiers; contains it.
+    InvalidSemver(String),
+}
+
+/// Compare two version or release strings as rpm does, `rpmvercmp` of librpm. Both are split
+/// into runs of digits and runs of letters, anything else separates them. Runs of digits are
+/// compared as numbers and are newer than runs of letters, which are compared as bytes. A `~`
+/// sorts before anything, even the end of the string, so `1.0~rc1` is older than `1.0`; a
+/// `^` sorts after the end of the string but before anything else, so `1.0^git1` is newer
+/// than `1.0` and older than `1.0.1`.
+pub fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let separator = |c: &u8| !c.is_ascii_alphanumeric() && *c != b'~' && *c != b'^';
+
+    let mut one = a.as_bytes();
+    let mut two = b.as_bytes();
+
+    loop {
+        while one.first().is_some_and(separator) {
+            one = &one[1..];
+        }
+
+        while two.first().is_some_and(separator) {
+            two = &two[1..];
+        }
+
+        if one.first() == Some(&b'~') || two.first() == Some(&b'~') {
+            if one.first() != Some(&b'~') {
+                return Ordering::Greater;
+            }
+
+            if two.first() != Some(&b'~') {
+                return Ordering::Less;
+            }
+
+            one = &one[1..];
+            two = &two[1..];
+            continue;
+        }
+
+        if one.first() == Some(&b'^') || two.first() == Some(&b'^') {
+            match (one.first(), two.first()) {
+                (None, _) => return Ordering::Less,
+                (_, None) => return Ordering::Greater,
+                (Some(b'^'), Some(b'^')) => {}
+                (Some(_), Some(b'^')) => return Ordering::Greater,
+                _ => return Ordering::Less,
+            }
+
+            one = &one[1..];
+            two = &two[1..];
+            continue;
+        }
+
+        if one.is_empty() || two.is_empty() {
+            break;
+        }
+
+        let numeric = one[0].is_ascii_digit();
+        let run = |text: &[u8]| {
+            text.iter()
+                .take_while(|c| {
+                    if numeric {
+                        c.is_ascii_digit()
+                    } else {
+                        c.is_ascii_alphabetic()
+                    }
+                })
+                .count()
+        };
+
+        let (first, rest_one) = one.split_at(run(one));
+        let (second, rest_two) = two.split_at(run(two));
+
+        // runs of different kinds, numbers are newer
+        if second.is_empty() {
+            return if numeric {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+
+        let ordering = if numeric {
+            let first = trim_zeros(first);
+            let second = trim_zeros(second);
+
+            first.len().cmp(&second.len()).then(first.cmp(second))
+        } else {
+            first.cmp(second)
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+
+        one = rest_one;
+        two = rest_two;
+    }
+
+    // whichever has something left is newer
+    match (one.is_empty(), two.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (false, _) => Ordering::Greater,
+        (_, false) => Ordering::Less,
+    }
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|c| **c == b'0').count();
+
+    &digits[zeros..]
+}
+
+/// The epoch, version, and release of an rpm, `1:5.2.15-3.fc39`. They are ordered by epoch,
+/// then by version, and then by release, the latter two with `rpmvercmp`; `1.05` and `1.5`
+/// are equal.
+#[derive(Debug, Clone, Default)]
+pub struct Evr {
+    pub epoch: u32,
+    pub version: String,
+    pub release: String,
+}
+
+impl Evr {
+    pub fn new(epoch: u32, version: &str, release: &str) -> Self {
+        Self {
+            epoch,
+            version: version.to_string(),
+            release: release.to_string(),
+        }
+    }
+}
+
+impl Ord for Evr {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| rpmvercmp(&self.version, &other.version))
+            .then_with(|| rpmvercmp(&self.release, &other.release))
+    }
+}
+
+impl PartialOrd for Evr {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Evr {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Evr {}
+
+/// EVRs are parsed from `[epoch:]version[-release]`, the release is after the last `-`.
+impl FromStr for Evr {
+    type Err = VersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VersionError::InvalidEvr(s.to_string());
+
+        let (epoch, rest) = match s.split_once(':') {
+            Some((epoch, rest)) => (epoch.parse().map_err(|_| invalid())?, rest),
+            None => (0, s),
+        };
+
+        let (version, release) = rest.rsplit_once('-').unwrap_or((rest, ""));
+
+        if version.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self::new(epoch, version, release))
+    }
+}
+
+/// EVRs are displayed as rpm displays them, without an epoch of 0 or an empty release.
+impl fmt::Display for Evr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.epoch != 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+
+        write!(f, "{}", self.version)?;
+
+        if !self.release.is_empty() {
+            write!(f, "-{}", self.release)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// EVRs are serialized as they are displayed.
+impl Serialize for Evr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A pre-release identifier of a semantic version, numeric ones are older than others.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Identifier {
+    Numeric(u64),
+    Alphanumeric(String),
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Numeric(number) => write!(f, "{}", number),
+            Self::Alphanumeric(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+/// A semantic version, `1.2.3-rc.1+build.5`, ordered by the precedence of semver 2.0; a
+/// version with pre-release identifiers is older than the one without, and build metadata
+/// doesn't count. Versions that only differ in their build metadata are equal.
+#[derive(Debug, Clone)]
+pub struct Semver {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Vec<Identifier>,
+    pub build: Vec<String>,
+}
+
+impl Semver {
+    pub fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            pre: vec![],
+            build: vec![],
+        }
+    }
+}
+
+impl Ord for Semver {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self.pre.cmp(&other.pre),
+            })
+    }
+}
+
+impl PartialOrd for Semver {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Semver {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Semver {}
+
+impl FromStr for Semver {
+    type Err = VersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VersionError::InvalidSemver(s.to_string());
+
+        let identifiers = |text: &str| -> Result<Vec<String>, VersionError> {
+            text.split('.')
+                .map(|identifier| {
+                    if identifier.is_empty()
+                        || !identifier
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                    {
+                        Err(invalid())
+                    } else {
+                        Ok(identifier.to_string())
+                    }
+                })
+                .collect()
+        };
+
+        // numbers can't have leading zeros
+        let number = |text: &str| -> Result<u64, VersionError> {
+            if text.is_empty()
+                || !text.chars().all(|c| c.is_ascii_digit())
+                || (text.len() > 1 && text.starts_with('0'))
+            {
+                return Err(invalid());
+            }
+
+            text.parse().map_err(|_| invalid())
+        };
+
+        let (rest, build) = match s.split_once('+') {
+            Some((rest, build)) => (rest, identifiers(build)?),
+            None => (s, vec![]),
+        };
+
+        let (core, pre) = match rest.split_once('-') {
+            Some((core, pre)) => (core, identifiers(pre)?),
+            None => (rest, vec![]),
+        };
+
+        let pre = pre
+            .into_iter()
+            .map(|identifier| {
+                if identifier.chars().all(|c| c.is_ascii_digit()) {
+                    number(&identifier).map(Identifier::Numeric)
+                } else {
+                    Ok(Identifier::Alphanumeric(identifier))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let parts = core.split('.').collect::<Vec<_>>();
+
+        let [major, minor, patch] = parts[..] else {
+            return Err(invalid());
+        };
+
+        Ok(Self {
+            major: number(major)?,
+            minor: number(minor)?,
+            patch: number(patch)?,
+            pre,
+            build,
+        })
+    }
+}
+
+impl fmt::Display for Semver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+
+        let join = |identifiers: Vec<String>| identifiers.join(".");
+
+        if !self.pre.is_empty() {
+            write!(
+                f,
+                "-{}",
+                join(self.pre.iter().map(ToString::to_string).collect())
+            )?;
+        }
+
+        if !self.build.is_empty() {
+            write!(f, "+{}", join(self.build.clone()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A package that is older in one set of packages than in another, such as between the
+/// packages of two revisions of a manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Downgrade {
+    pub name: String,
+    pub arch: String,
+    pub from: Evr,
+    pub to: Evr,
+}
+
+/// The packages of `before` that `after` has older versions of, by name and architecture in
+/// the order of `after`. Packages either doesn't have are not compared.
+pub fn downgrades(before: &[Package], after: &[Package]) -> Vec<Downgrade> {
+    after
+        .iter()
+        .filter_map(|package| {
+            let previous = before
+                .iter()
+                .filter(|other| other.name == package.name && other.arch == package.arch)
+                .map(Package::evr)
+                .max()?;
+
+            let evr = package.evr();
+
+            (evr < previous).then(|| Downgrade {
+                name: package.name.clone(),
+                arch: package.arch.clone(),
+                from: previous,
+                to: evr,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn versions_compared() {
+        // from the tests of librpm
+        for (a, b, ordering) in [
+            ("1.0", "1.0", Ordering::Equal),
+            ("1.0", "2.0", Ordering::Less),
+            ("2.0.1", "2.0", Ordering::Greater),
+            ("2.0.1a", "2.0.1", Ordering::Greater),
+            ("5.5p1", "5.5p2", Ordering::Less),
+            ("5.5p10", "5.5p1", Ordering::Greater),
+            ("10xyz", "10.1xyz", Ordering::Less),
+            ("xyz10", "xyz10.1", Ordering::Less),
+            ("xyz.4", "8", Ordering::Less),
+            ("1b.fc17", "1.fc17", Ordering::Less),
+            ("1.0010", "1.9", Ordering::Greater),
+            ("1.05", "1.5", Ordering::Equal),
+            ("2a", "2.0", Ordering::Less),
+            ("1+2", "1_2", Ordering::Equal),
+            ("a", "B", Ordering::Greater),
+            ("1.0~rc1", "1.0", Ordering::Less),
+            ("1.0~rc1", "1.0~rc2", Ordering::Less),
+            ("1.0~rc1~git123", "1.0~rc1", Ordering::Less),
+            ("1.0^", "1.0", Ordering::Greater),
+            ("1.0^git1", "1.0^git2", Ordering::Less),
+            ("1.0^git1", "1.01", Ordering::Less),
+            ("1.0^20160101", "1.0.1", Ordering::Less),
+            ("1.0~rc1^git1", "1.0~rc1", Ordering::Greater),
+            ("1.0^git1~pre", "1.0^git1", Ordering::Less),
+        ] {
+            assert_eq!(rpmvercmp(a, b), ordering, "{} {}", a, b);
+            assert_eq!(rpmvercmp(b, a), ordering.reverse(), "{} {}", b, a);
+        }
+    }
+
+    #[test]
+    fn evrs_ordered() {
+        let evr = |text: &str| text.parse::<Evr>().unwrap();
+
+        assert_eq!(evr("1:5.2.15-3.fc39"), Evr::new(1, "5.2.15", "3.fc39"));
+        assert_eq!(evr("5.2-1-rc"), Evr::new(0, "5.2-1", "rc"));
+        assert_eq!(evr("1:5.2.15-3.fc39").to_string(), "1:5.2.15-3.fc39");
+        assert_eq!(evr("0:5.2").to_string(), "5.2");
+
+        assert!(evr("1:1.0-1") > evr("9.9-9"));
+        assert!(evr("1.10-1") > evr("1.9-2"));
+        assert!(evr("1.0-2.fc39") > evr("1.0-1.fc39"));
+        assert!(evr("1.0~beta-1") < evr("1.0-1"));
+        assert_eq!(evr("1.05-1"), evr("0:1.5-01"));
+
+        for invalid in ["x:1.0-1", ":1.0", "1:", "-1"] {
+            assert!(matches!(
+                invalid.parse::<Evr>(),
+                Err(VersionError::InvalidEvr(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn semvers_ordered() {
+        let semver = |text: &str| text.parse::<Semver>().unwrap();
+
+        // the precedence example of semver 2.0
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.1.0",
+            "2.0.0",
+        ];
+
+        for pair in ordered.windows(2) {
+            assert!(semver(pair[0]) < semver(pair[1]), "{:?}", pair);
+        }
+
+        assert_eq!(semver("1.0.0+build.1"), semver("1.0.0+build.2"));
+        assert_eq!(
+            semver("1.2.3-rc.1+build.5").to_string(),
+            "1.2.3-rc.1+build.5"
+        );
+
+        for invalid in [
+            "1.2",
+            "1.2.3.4",
+            "01.2.3",
+            "1.2.3-",
+            "1.2.3-rc..1",
+            "1.2.3-01",
+            "v1.2.3",
+        ] {
+            assert!(matches!(
+                invalid.parse::<Semver>(),
+                Err(VersionError::InvalidSemver(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn downgrades_found() {
+        let package = |name: &str, epoch: u32, version: &str| Package {
+            name: name.to_string(),
+            epoch,
+            version: version.to_string(),
+            release: "1.fc39".to_string(),
+            arch: "x86_64".to_string(),
+            repo_id: "fedora".to_string(),
+            remote_location: None,
+            checksum: None,
+        };
+
+        let before = [
+            package("bash", 0, "5.2.15"),
+            package("kernel", 0, "6.5.6"),
+            package("vim-minimal", 2, "9.0"),
+        ];
+        let after = [
+            package("bash", 0, "5.2.9"),
+            package("kernel", 0, "6.5.10"),
+            package("vim-minimal", 1, "9.1"),
+            package("zsh", 0, "5.9"),
+        ];
+
+        assert_eq!(
+            downgrades(&before, &after),
+            [
+                Downgrade {
+                    name: "bash".to_string(),
+                    arch: "x86_64".to_string(),
+                    from: Evr::new(0, "5.2.15", "1.fc39"),
+                    to: Evr::new(0, "5.2.9", "1.fc39"),
+                },
+                Downgrade {
+                    name: "vim-minimal".to_string(),
+                    arch: "x86_64".to_string(),
+                    from: Evr::new(2, "9.0", "1.fc39"),
+                    to: Evr::new(1, "9.1", "1.fc39"),
+                },
+            ]
+        );
+    }
+}
@@ -9,22 +9,33 @@
 // [osbuild's GitHub](https://github.com/osbuild/osbuild).
 
 /// Core tasks, providing all functionality of the main `osbuild` executable.
+#[cfg(feature = "executor")]
 pub mod core;
 
 /// Preprocessor tasks, providing all functionality of the `osbuild-mpp` executable.
+#[cfg(feature = "preprocessor")]
 pub mod preprocessor;
 
 /// Manifests describe builds of operating systems. They are usually exchanged as 'descriptions',
 /// which is a JSON serialized manifest. Schemas validate the manifest descriptions so we can make
 /// sure that a Manifest can be deserialized from a description.
+#[cfg(feature = "manifest")]
 pub mod manifest;
 
-/// Dependency tasks
+/// Dependency tasks, resolving package specs against repositories.
+#[cfg(feature = "solver")]
 pub mod dependency;
 
 /// Sandbox tasks
+#[cfg(any(feature = "communication", feature = "sandbox"))]
 pub mod sandbox;
 
 /// The work in osbuild is performed by modules, there are several types of modules. The `module`
 /// module provides primitives, traits, and helpers to implement your own modules.
+#[cfg(feature = "executor")]
 pub mod module;
+
+/// Machine readable descriptions and shell completions of command lines, shared by the
+/// executables.
+#[cfg(feature = "cli")]
+pub mod cli;
@@ -0,0 +1,158 @@
+/// How deep schemas are followed, through `$ref`s as well; schemas that refer to themselves
+/// stop here instead of recursing forever.
+const MAX_DEPTH: usize = 64;
+
+/// Fill in the defaults `schema` declares for what is missing from `value`. Properties of
+/// objects get the `default` of their schema when they are absent, and the properties and
+/// items that are present get their own defaults in turn. `$ref`s within the schema and the
+/// branches of `allOf` are followed; `oneOf` and `anyOf` are not, which of their branches
+/// applies isn't known without validating. Returns whether anything was filled in.
+pub fn apply(schema: &serde_json::Value, value: &mut serde_json::Value) -> bool {
+    apply_in(schema, schema, value, 0)
+}
+
+/// `schema` with its `$ref` followed, if it has one that points into `root`.
+fn resolve<'s>(
+    root: &'s serde_json::Value,
+    schema: &'s serde_json::Value,
+) -> &'s serde_json::Value {
+    let mut schema = schema;
+
+    for _ in 0..MAX_DEPTH {
+        let Some(target) = schema
+            .get("$ref")
+            .and_then(|reference| reference.as_str())
+            .and_then(|reference| reference.strip_prefix('#'))
+            .and_then(|pointer| root.pointer(pointer))
+        else {
+            break;
+        };
+
+        schema = target;
+    }
+
+    schema
+}
+
+fn apply_in(
+    root: &serde_json::Value,
+    schema: &serde_json::Value,
+    value: &mut serde_json::Value,
+    depth: usize,
+) -> bool {
+    if depth > MAX_DEPTH {
+        return false;
+    }
+
+    let schema = resolve(root, schema);
+    let mut applied = false;
+
+    if let Some(branches) = schema.get("allOf").and_then(|all| all.as_array()) {
+        for branch in branches {
+            applied |= apply_in(root, branch, value, depth + 1);
+        }
+    }
+
+    match value {
+        serde_json::Value::Object(object) => {
+            let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) else {
+                return applied;
+            };
+
+            for (name, property) in properties {
+                let property = resolve(root, property);
+
+                if !object.contains_key(name) {
+                    let Some(default) = property.get("default") else {
+                        continue;
+                    };
+
+                    object.insert(name.clone(), default.clone());
+                    applied = true;
+                }
+
+                if let Some(value) = object.get_mut(name) {
+                    applied |= apply_in(root, property, value, depth + 1);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            let Some(schema) = schema.get("items").filter(|items| items.is_object()) else {
+                return applied;
+            };
+
+            for item in items {
+                applied |= apply_in(root, schema, item, depth + 1);
+            }
+        }
+        _ => {}
+    }
+
+    applied
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn defaults_applied() {
+        let schema = serde_json::json!({
+            "definitions": {
+                "path": {
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string"},
+                        "mode": {"type": "integer", "default": 493},
+                    },
+                },
+            },
+            "properties": {
+                "paths": {"type": "array", "items": {"$ref": "#/definitions/path"}},
+                "exist_ok": {"type": "boolean", "default": false},
+                "config": {
+                    "type": "object",
+                    "default": {},
+                    "allOf": [{"properties": {"level": {"default": 3}}}],
+                },
+                "choice": {
+                    "oneOf": [{"properties": {"a": {"default": 1}}}],
+                },
+            },
+        });
+
+        let mut options = serde_json::json!({
+            "paths": [{"path": "/etc"}, {"path": "/var", "mode": 448}],
+            "choice": {},
+        });
+
+        assert!(apply(&schema, &mut options));
+        assert_eq!(
+            options,
+            serde_json::json!({
+                "paths": [{"path": "/etc", "mode": 493}, {"path": "/var", "mode": 448}],
+                "exist_ok": false,
+                "config": {"level": 3},
+                "choice": {},
+            })
+        );
+
+        // everything is there now
+        assert!(!apply(&schema, &mut options));
+    }
+
+    #[test]
+    fn recursion_bounded() {
+        let schema = serde_json::json!({
+            "definitions": {"loop": {"$ref": "#/definitions/loop"}},
+            "properties": {
+                "node": {"$ref": "#/definitions/node"},
+            },
+            "$ref": "#/definitions/loop",
+        });
+
+        let mut options = serde_json::json!({"node": {}});
+
+        assert!(!apply(&schema, &mut options));
+    }
+}
@@ -7,11 +7,50 @@ pub mod v1;
 /// Version 2 of manifest descriptions, this version is current.
 pub mod v2;
 
+/// The schemas of the manifest formats, embedded in the crate.
+pub mod schema;
+
+/// Filling in the defaults schemas declare for options.
+pub mod defaults;
+
 /// Validation for ManifestDescriptions.
 pub mod validation;
 
 #[derive(Debug)]
-pub enum ManifestDescriptionError {}
+pub enum ManifestDescriptionError {
+    /// The schema of the named module is not a valid JSON schema.
+    InvalidSchema(String, String),
+
+    /// The schemas of the manifest formats could not be read from a directory.
+    IOError(std::io::Error),
+
+    #[cfg(feature = "executor")]
+    ModuleError(crate::module::ModuleError),
+
+    /// The registry to validate against could not be loaded, such as an invalid snapshot.
+    #[cfg(feature = "executor")]
+    RegistryError(crate::module::RegistryError),
+}
+
+impl From<std::io::Error> for ManifestDescriptionError {
+    fn from(err: std::io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+#[cfg(feature = "executor")]
+impl From<crate::module::RegistryError> for ManifestDescriptionError {
+    fn from(err: crate::module::RegistryError) -> Self {
+        Self::RegistryError(err)
+    }
+}
+
+#[cfg(feature = "executor")]
+impl From<crate::module::ModuleError> for ManifestDescriptionError {
+    fn from(err: crate::module::ModuleError) -> Self {
+        Self::ModuleError(err)
+    }
+}
 
 #[cfg(test)]
 mod test {
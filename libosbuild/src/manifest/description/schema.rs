@@ -0,0 +1,113 @@
+use crate::manifest::Version;
+
+use std::path::Path;
+
+use crate::manifest::description::ManifestDescriptionError;
+
+/// The schema of version 1 manifests, as published by osbuild.
+pub const OSBUILD1: &str = include_str!("schemas/osbuild1.json");
+
+/// The schema of version 2 manifests, as published by osbuild.
+pub const OSBUILD2: &str = include_str!("schemas/osbuild2.json");
+
+/// The embedded schema of manifests of `version`.
+pub fn format_schema(version: Version) -> serde_json::Value {
+    let schema = match version {
+        Version::V1 => OSBUILD1,
+        Version::V2 => OSBUILD2,
+    };
+
+    // the bundled schemas are parsed by the tests below
+    serde_json::from_str(schema).expect("embedded manifest schema is valid JSON")
+}
+
+/// The schemas of the manifest formats to validate against.
+#[derive(Clone, Debug)]
+pub struct Schemas {
+    osbuild1: serde_json::Value,
+    osbuild2: serde_json::Value,
+}
+
+impl Schemas {
+    /// The schemas embedded in the crate.
+    pub fn embedded() -> Self {
+        Self {
+            osbuild1: format_schema(Version::V1),
+            osbuild2: format_schema(Version::V2),
+        }
+    }
+
+    /// The schemas `osbuild1.json` and `osbuild2.json` in `dir`, e.g.
+    /// `/usr/lib/osbuild/schemas` to validate against those of the installed osbuild.
+    pub fn from_dir(dir: &Path) -> Result<Self, ManifestDescriptionError> {
+        let read = |name: &str| {
+            let text = std::fs::read_to_string(dir.join(format!("{}.json", name)))?;
+
+            serde_json::from_str(&text).map_err(|err| {
+                ManifestDescriptionError::InvalidSchema(name.into(), err.to_string())
+            })
+        };
+
+        Ok(Self {
+            osbuild1: read("osbuild1")?,
+            osbuild2: read("osbuild2")?,
+        })
+    }
+
+    /// The schema of manifests of `version`.
+    pub fn get(&self, version: Version) -> &serde_json::Value {
+        match version {
+            Version::V1 => &self.osbuild1,
+            Version::V2 => &self.osbuild2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn schemas_embedded() {
+        for version in [Version::V1, Version::V2] {
+            let schema = format_schema(version);
+
+            assert_eq!(schema["title"], "OSBuild Manifest");
+            assert!(jsonschema::JSONSchema::compile(&schema).is_ok());
+        }
+
+        assert_eq!(
+            format_schema(Version::V2)["properties"]["version"]["enum"][0],
+            "2"
+        );
+    }
+
+    #[test]
+    fn schemas_read() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(matches!(
+            Schemas::from_dir(dir.path()),
+            Err(ManifestDescriptionError::IOError(_))
+        ));
+
+        std::fs::write(dir.path().join("osbuild1.json"), OSBUILD1).unwrap();
+        std::fs::write(
+            dir.path().join("osbuild2.json"),
+            r#"{"title": "Installed"}"#,
+        )
+        .unwrap();
+
+        let schemas = Schemas::from_dir(dir.path()).unwrap();
+
+        assert_eq!(schemas.get(Version::V1), &format_schema(Version::V1));
+        assert_eq!(schemas.get(Version::V2)["title"], "Installed");
+
+        std::fs::write(dir.path().join("osbuild2.json"), "{").unwrap();
+
+        assert!(matches!(
+            Schemas::from_dir(dir.path()),
+            Err(ManifestDescriptionError::InvalidSchema(name, _)) if name == "osbuild2"
+        ));
+    }
+}
@@ -1,3 +1,23 @@
+use std::collections::HashMap;
+#[cfg(feature = "executor")]
+use std::collections::HashSet;
+
+use jsonschema::paths::{JSONPointer, PathChunk};
+use jsonschema::JSONSchema;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::manifest::description::schema::Schemas;
+use crate::manifest::description::validation::Rule;
+use crate::manifest::description::ManifestDescriptionError;
+use crate::manifest::description::{defaults, validation};
+use crate::manifest::path::{Part, Path};
+use crate::manifest::Version;
+#[cfg(feature = "executor")]
+use crate::module::capability::{Capability, Policy};
+#[cfg(feature = "executor")]
+use crate::module::{Kind, Registry};
+
 pub struct ManifestDescription {}
 
 pub struct DeviceDescription {}
@@ -10,12 +30,1073 @@ pub struct StageDescription {}
 
 pub struct PipelineDescription {}
 
-pub struct Validator {}
+/// Validates the pipelines of a manifest and the options of their stages against the schemas
+/// of stage modules, and the runners pipelines ask for against the available runners. With the
+/// `parallel` feature pipelines are validated concurrently, errors are always reported in the
+/// order of the pipelines they occur in. With the `executor` feature stages needing
+/// capabilities the validator's policy forbids are invalid as well, and once the validator
+/// knows of input, device, or mount modules stages using other ones are too.
+#[derive(Default)]
+pub struct Validator {
+    schemas: HashMap<String, JSONSchema>,
+
+    /// The schemas of stages as they were added, for the defaults they declare.
+    documents: HashMap<String, serde_json::Value>,
+    runners: Vec<String>,
+
+    /// The schemas of the manifest formats, manifests are checked against the one of their
+    /// version before anything else.
+    formats: Vec<(Version, JSONSchema)>,
+
+    #[cfg(feature = "executor")]
+    capabilities: HashMap<String, Vec<Capability>>,
+
+    #[cfg(feature = "executor")]
+    policy: Policy,
+
+    /// The input, device, and mount modules stages may use, anything goes when not known.
+    #[cfg(feature = "executor")]
+    modules: Option<HashSet<(Kind, String)>>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A validator for the structure of manifests of either version, against the schemas of
+    /// the manifest formats embedded in the crate. Without module schemas stages of any type
+    /// are accepted, so manifests can be validated on hosts without osbuild installed.
+    pub fn against_format_schema() -> Result<Self, ManifestDescriptionError> {
+        Self::against_format_schemas(&Schemas::embedded())
+    }
+
+    /// A validator for the structure of manifests as `against_format_schema`, against
+    /// `schemas` instead, such as those of the installed osbuild.
+    pub fn against_format_schemas(schemas: &Schemas) -> Result<Self, ManifestDescriptionError> {
+        let mut validator = Self::new();
+
+        validator.set_format_schemas(schemas)?;
+
+        Ok(validator)
+    }
+
+    /// Check manifests against the embedded schemas of the manifest formats as well, before
+    /// the schemas of stages.
+    pub fn add_format_schemas(&mut self) -> Result<(), ManifestDescriptionError> {
+        self.set_format_schemas(&Schemas::embedded())
+    }
+
+    /// Check manifests against `schemas` of the manifest formats, before the schemas of
+    /// stages, replacing any format schemas added before.
+    pub fn set_format_schemas(
+        &mut self,
+        schemas: &Schemas,
+    ) -> Result<(), ManifestDescriptionError> {
+        self.formats.clear();
+
+        for version in [Version::V1, Version::V2] {
+            let compiled = JSONSchema::compile(schemas.get(version)).map_err(|err| {
+                ManifestDescriptionError::InvalidSchema(
+                    format!("osbuild{}", version),
+                    err.to_string(),
+                )
+            })?;
+
+            self.formats.push((version, compiled));
+        }
+
+        Ok(())
+    }
+
+    /// A validator for the schemas of all stages and the runners in `registry`.
+    #[cfg(feature = "executor")]
+    pub fn from_registry(registry: &Registry) -> Result<Self, ManifestDescriptionError> {
+        let mut validator = Self::new();
+
+        for module in registry.by_kind(Kind::Stage).unwrap_or_default() {
+            let schema = module.get_schema_json()?;
+
+            validator.add_schema(module.name(), &schema)?;
+            validator.add_capabilities(
+                module.name(),
+                &crate::module::capability::from_schema(&schema)?,
+            );
+        }
+
+        for module in registry.by_kind(Kind::Runner).unwrap_or_default() {
+            validator.add_runner(module.name());
+        }
+
+        for module in registry.modules() {
+            if matches!(module.kind(), Kind::Input | Kind::Device | Kind::Mount) {
+                validator.add_module(module.kind(), module.name());
+            }
+        }
+
+        Ok(validator)
+    }
+
+    /// A validator for the modules in a snapshot of a registry, as made by
+    /// `Registry::to_json` on another host. Manifests that validate only use modules that
+    /// host has, with options its modules accept.
+    #[cfg(feature = "executor")]
+    pub fn from_snapshot(snapshot: &serde_json::Value) -> Result<Self, ManifestDescriptionError> {
+        Self::from_registry(&Registry::from_json(snapshot)?)
+    }
+
+    /// Add an input, device, or mount module that stages may use.
+    #[cfg(feature = "executor")]
+    pub fn add_module(&mut self, kind: Kind, name: &str) {
+        self.modules
+            .get_or_insert_with(HashSet::new)
+            .insert((kind, name.to_string()));
+    }
+
+    /// Add a runner that pipelines may ask for.
+    pub fn add_runner(&mut self, name: &str) {
+        if let Err(index) = self
+            .runners
+            .binary_search_by(|other| other.as_str().cmp(name))
+        {
+            self.runners.insert(index, name.to_string());
+        }
+    }
+
+    /// Add the capabilities stages of type `name` need.
+    #[cfg(feature = "executor")]
+    pub fn add_capabilities(&mut self, name: &str, capabilities: &[Capability]) {
+        self.capabilities
+            .insert(name.to_string(), capabilities.to_vec());
+    }
+
+    /// Set the policy the capabilities of stages are checked against, everything is allowed
+    /// by default.
+    #[cfg(feature = "executor")]
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// Add the schema that options of stages of type `name` have to conform to.
+    pub fn add_schema(
+        &mut self,
+        name: &str,
+        schema: &serde_json::Value,
+    ) -> Result<(), ManifestDescriptionError> {
+        let compiled = JSONSchema::compile(schema).map_err(|err| {
+            ManifestDescriptionError::InvalidSchema(name.to_string(), err.to_string())
+        })?;
+
+        self.schemas.insert(name.to_string(), compiled);
+        self.documents.insert(name.to_string(), schema.clone());
+
+        Ok(())
+    }
+
+    /// The options stages of type `name` run with for `options`; with the defaults their
+    /// schema declares filled in where `options` leave something out. Options of stages
+    /// without a schema are what they are.
+    pub fn effective_options(
+        &self,
+        name: &str,
+        options: Option<&serde_json::Value>,
+    ) -> serde_json::Value {
+        let mut options = options
+            .cloned()
+            .unwrap_or_else(|| serde_json::Value::Object(Default::default()));
+
+        if let Some(schema) = self.documents.get(name) {
+            defaults::apply(schema, &mut options);
+        }
+
+        options
+    }
+
+    pub fn validate(&self, manifest: &serde_json::Value) -> validation::Result {
+        self.validate_cached(manifest).result()
+    }
+
+    /// Validate `manifest` like `validate` does, keeping the results of every pipeline and
+    /// stage so that the manifest can be `revalidate`d after an edit.
+    pub fn validate_cached(&self, manifest: &serde_json::Value) -> ValidationCache {
+        let (result, pipelines) = self.validate_manifest(manifest);
+
+        let pipelines = pipelines.map_or_else(Vec::new, |pipelines| {
+            #[cfg(feature = "parallel")]
+            let pipelines = pipelines
+                .par_iter()
+                .enumerate()
+                .map(|(index, pipeline)| self.validate_pipeline(index, pipeline))
+                .collect();
+
+            #[cfg(not(feature = "parallel"))]
+            let pipelines = pipelines
+                .iter()
+                .enumerate()
+                .map(|(index, pipeline)| self.validate_pipeline(index, pipeline))
+                .collect();
+
+            pipelines
+        });
+
+        ValidationCache {
+            manifest: result,
+            pipelines,
+        }
+    }
+
+    /// Validate `manifest` again after the value at `changed` was edited, added, or removed,
+    /// with `cache` holding the results of this validator from before the edit. Only the
+    /// stage or pipeline containing the edit is validated again, the results of others are
+    /// reused. Edits that add or remove pipelines or stages shift the paths of those after
+    /// them, so everything they contain is validated again. The checks of the manifest
+    /// itself, such as against format schemas, are always done.
+    pub fn revalidate(
+        &self,
+        cache: &mut ValidationCache,
+        manifest: &serde_json::Value,
+        changed: &Path,
+    ) -> validation::Result {
+        let (result, pipelines) = self.validate_manifest(manifest);
+
+        cache.manifest = result;
+
+        let Some(pipelines) =
+            pipelines.filter(|pipelines| pipelines.len() == cache.pipelines.len())
+        else {
+            *cache = self.validate_cached(manifest);
+            return cache.result();
+        };
+
+        match changed.as_slice() {
+            [Part::Name(key), Part::Index(index), rest @ ..]
+                if key == "pipelines" && *index < pipelines.len() =>
+            {
+                let cached = &mut cache.pipelines[*index];
+
+                match rest {
+                    [Part::Name(key), Part::Index(stage), ..] if key == "stages" => {
+                        self.revalidate_stage(cached, *index, &pipelines[*index], *stage)
+                    }
+                    _ => *cached = self.validate_pipeline(*index, &pipelines[*index]),
+                }
+            }
+            // the pipelines are validated on their own
+            [Part::Name(key), ..] if key != "pipelines" => {}
+            _ => *cache = self.validate_cached(manifest),
+        }
+
+        cache.result()
+    }
+
+    /// Validate the stage `stage` of the pipeline `index` again, and the fields of the
+    /// pipeline. The whole pipeline is validated when it has another number of stages.
+    fn revalidate_stage(
+        &self,
+        cached: &mut PipelineCache,
+        index: usize,
+        pipeline: &serde_json::Value,
+        stage: usize,
+    ) {
+        match self.validate_pipeline_fields(index, pipeline) {
+            (result, Some(stages))
+                if stage < stages.len() && stages.len() == cached.stages.len() =>
+            {
+                cached.pipeline = result;
+                cached.stages[stage] = self.validate_stage(index, stage, &stages[stage]);
+            }
+            _ => *cached = self.validate_pipeline(index, pipeline),
+        }
+    }
+
+    /// Check the manifest itself, returning its pipelines when they can be checked.
+    fn validate_manifest<'a>(
+        &self,
+        manifest: &'a serde_json::Value,
+    ) -> (validation::Result, Option<&'a Vec<serde_json::Value>>) {
+        let mut result = validation::Result::new();
+
+        if !self.formats.is_empty() {
+            let Ok(version) = Version::detect(manifest) else {
+                result.add_error(error(
+                    Rule::Format,
+                    "unknown manifest version",
+                    vec![Part::Name("version".to_string())],
+                ));
+
+                return (result, None);
+            };
+
+            if let Some((_, format)) = self.formats.iter().find(|(other, _)| *other == version) {
+                if let Err(errors) = format.validate(manifest) {
+                    for err in errors {
+                        result.add_error(error(
+                            Rule::Format,
+                            &err.to_string(),
+                            parts(&err.instance_path),
+                        ));
+                    }
+
+                    // the checks below would only repeat what the format schema found
+                    return (result, None);
+                }
+            }
+
+            // version 1 manifests have no pipelines to check further
+            if version == Version::V1 {
+                result.add_error(error(
+                    Rule::Deprecated,
+                    "version 1 manifests are deprecated, use version 2",
+                    vec![],
+                ));
+
+                return (result, None);
+            }
+        }
+
+        if manifest
+            .get("metadata")
+            .is_some_and(|metadata| !metadata.is_object())
+        {
+            result.add_error(error(
+                Rule::Structure,
+                "metadata must be an object",
+                vec![Part::Name("metadata".to_string())],
+            ));
+        }
+
+        match manifest.get("pipelines") {
+            Some(serde_json::Value::Array(pipelines)) => (result, Some(pipelines)),
+            _ => {
+                result.add_error(error(
+                    Rule::Structure,
+                    "pipelines must be an array",
+                    vec![Part::Name("pipelines".to_string())],
+                ));
+
+                (result, None)
+            }
+        }
+    }
+
+    fn validate_pipeline(&self, index: usize, pipeline: &serde_json::Value) -> PipelineCache {
+        let (result, stages) = self.validate_pipeline_fields(index, pipeline);
+
+        PipelineCache {
+            pipeline: result,
+            stages: stages
+                .into_iter()
+                .flatten()
+                .enumerate()
+                .map(|(stage_index, stage)| self.validate_stage(index, stage_index, stage))
+                .collect(),
+        }
+    }
+
+    /// Check the fields of a pipeline, returning its stages when they can be checked.
+    fn validate_pipeline_fields<'a>(
+        &self,
+        index: usize,
+        pipeline: &'a serde_json::Value,
+    ) -> (validation::Result, Option<&'a Vec<serde_json::Value>>) {
+        let mut result = validation::Result::new();
+
+        let at = |parts: &[Part]| {
+            let mut path = vec![Part::Name("pipelines".to_string()), Part::Index(index)];
+            path.extend_from_slice(parts);
+            path
+        };
+
+        if !pipeline.is_object() {
+            result.add_error(error(
+                Rule::Structure,
+                "pipeline must be an object",
+                at(&[]),
+            ));
+            return (result, None);
+        }
+
+        if !pipeline.get("name").is_some_and(|name| name.is_string()) {
+            result.add_error(error(
+                Rule::Structure,
+                "pipeline must have a name",
+                at(&[Part::Name("name".to_string())]),
+            ));
+        }
+
+        match pipeline.get("runner") {
+            Some(serde_json::Value::String(runner))
+                if self.runners.binary_search(runner).is_err() =>
+            {
+                let message = if self.runners.is_empty() {
+                    format!("unknown runner '{}', no runners are available", runner)
+                } else {
+                    format!(
+                        "unknown runner '{}', available runners are: {}",
+                        runner,
+                        self.runners.join(", ")
+                    )
+                };
+
+                result.add_error(error(
+                    Rule::UnknownRunner,
+                    &message,
+                    at(&[Part::Name("runner".to_string())]),
+                ));
+            }
+            None | Some(serde_json::Value::String(_)) => {}
+            Some(_) => result.add_error(error(
+                Rule::Structure,
+                "runner must be a string",
+                at(&[Part::Name("runner".to_string())]),
+            )),
+        }
+
+        match pipeline.get("stages") {
+            None => (result, None),
+            Some(serde_json::Value::Array(stages)) => (result, Some(stages)),
+            Some(_) => {
+                result.add_error(error(
+                    Rule::Structure,
+                    "stages must be an array",
+                    at(&[Part::Name("stages".to_string())]),
+                ));
+
+                (result, None)
+            }
+        }
+    }
+
+    fn validate_stage(
+        &self,
+        index: usize,
+        stage_index: usize,
+        stage: &serde_json::Value,
+    ) -> validation::Result {
+        let mut result = validation::Result::new();
+
+        let stage_at = |parts: &[Part]| {
+            let mut path = vec![
+                Part::Name("pipelines".to_string()),
+                Part::Index(index),
+                Part::Name("stages".to_string()),
+                Part::Index(stage_index),
+            ];
+            path.extend_from_slice(parts);
+            path
+        };
+
+        let kind = match stage.get("type").and_then(|kind| kind.as_str()) {
+            Some(kind) => kind,
+            None => {
+                result.add_error(error(
+                    Rule::Structure,
+                    "stage must have a type",
+                    stage_at(&[Part::Name("type".to_string())]),
+                ));
+
+                return result;
+            }
+        };
+
+        #[cfg(feature = "executor")]
+        self.validate_modules(stage, &stage_at, &mut result);
+
+        let schema = match self.schemas.get(kind) {
+            Some(schema) => schema,
+            // only the format is checked until schemas of stages are known
+            None if self.schemas.is_empty() && !self.formats.is_empty() => return result,
+            None => {
+                result.add_error(error(
+                    Rule::UnknownStage,
+                    &format!("unknown stage '{}'", kind),
+                    stage_at(&[Part::Name("type".to_string())]),
+                ));
+
+                return result;
+            }
+        };
+
+        #[cfg(feature = "executor")]
+        if let Some(capabilities) = self.capabilities.get(kind) {
+            let forbidden = self.policy.forbidden(capabilities);
+
+            if !forbidden.is_empty() {
+                let names: Vec<&str> = forbidden.iter().map(Capability::name).collect();
+
+                result.add_error(error(
+                    Rule::Capabilities,
+                    &format!(
+                        "stage '{}' needs capabilities the policy forbids: {}",
+                        kind,
+                        names.join(", ")
+                    ),
+                    stage_at(&[Part::Name("type".to_string())]),
+                ));
+            }
+        }
+
+        let empty = serde_json::Value::Object(Default::default());
+        let options = stage.get("options").unwrap_or(&empty);
+
+        if let Err(errors) = schema.validate(options) {
+            for err in errors {
+                let mut at = vec![Part::Name("options".to_string())];
+                at.extend(parts(&err.instance_path));
+
+                result.add_error(error(Rule::Options, &err.to_string(), stage_at(&at)));
+            }
+        }
+
+        result
+    }
+}
+
+impl Validator {
+    /// Check that the inputs, devices, and mounts of `stage` use known modules.
+    #[cfg(feature = "executor")]
+    fn validate_modules(
+        &self,
+        stage: &serde_json::Value,
+        at: &dyn Fn(&[Part]) -> Vec<Part>,
+        result: &mut validation::Result,
+    ) {
+        let Some(modules) = &self.modules else {
+            return;
+        };
+
+        for (kind, key) in [
+            (Kind::Input, "inputs"),
+            (Kind::Device, "devices"),
+            (Kind::Mount, "mounts"),
+        ] {
+            // inputs and devices are named, mounts are a list
+            let entries: Vec<(Part, &serde_json::Value)> = match stage.get(key) {
+                Some(serde_json::Value::Object(entries)) => entries
+                    .iter()
+                    .map(|(name, entry)| (Part::Name(name.clone()), entry))
+                    .collect(),
+                Some(serde_json::Value::Array(entries)) => entries
+                    .iter()
+                    .enumerate()
+                    .map(|(index, entry)| (Part::Index(index), entry))
+                    .collect(),
+                _ => continue,
+            };
+
+            for (part, entry) in entries {
+                let Some(name) = entry.get("type").and_then(|name| name.as_str()) else {
+                    continue;
+                };
+
+                if !modules.contains(&(kind, name.to_string())) {
+                    result.add_error(error(
+                        Rule::UnknownModule,
+                        &format!("unknown {} module '{}'", kind, name),
+                        at(&[
+                            Part::Name(key.to_string()),
+                            part,
+                            Part::Name("type".to_string()),
+                        ]),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// The results of validating a manifest, per pipeline and stage, see `Validator::revalidate`.
+#[derive(Debug, Default)]
+pub struct ValidationCache {
+    manifest: validation::Result,
+    pipelines: Vec<PipelineCache>,
+}
+
+#[derive(Debug, Default)]
+struct PipelineCache {
+    pipeline: validation::Result,
+    stages: Vec<validation::Result>,
+}
+
+impl ValidationCache {
+    /// All errors, in the order `Validator::validate` reports them.
+    pub fn result(&self) -> validation::Result {
+        let mut result = self.manifest.clone();
+
+        for pipeline in &self.pipelines {
+            result.merge(pipeline.pipeline.clone());
+
+            for stage in &pipeline.stages {
+                result.merge(stage.clone());
+            }
+        }
+
+        result
+    }
+}
+
+/// The parts of the path to where a schema didn't match.
+fn parts(pointer: &JSONPointer) -> Vec<Part> {
+    pointer
+        .iter()
+        .map(|chunk| match chunk {
+            PathChunk::Property(name) => Part::Name(name.to_string()),
+            PathChunk::Index(index) => Part::Index(*index),
+            PathChunk::Keyword(keyword) => Part::Name(keyword.to_string()),
+        })
+        .collect()
+}
+
+fn error(rule: Rule, message: &str, path: Vec<Part>) -> validation::Error {
+    validation::Error {
+        rule,
+        message: message.to_string(),
+        path: Path::new(path),
+        severity: rule.severity(),
+    }
+}
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    fn validator() -> Validator {
+        let mut validator = Validator::new();
+
+        validator
+            .add_schema(
+                "org.osbuild.rpm",
+                &serde_json::json!({
+                    "type": "object",
+                    "additionalProperties": false,
+                    "properties": {"packages": {"type": "array", "items": {"type": "string"}}}
+                }),
+            )
+            .unwrap();
+        validator
+            .add_schema("org.osbuild.noop", &serde_json::json!({}))
+            .unwrap();
+        validator.add_runner("org.osbuild.linux");
+        validator.add_runner("org.osbuild.fedora38");
+
+        validator
+    }
+
+    #[test]
+    fn valid_manifest() {
+        let manifest = serde_json::json!({
+            "version": "2",
+            "pipelines": [
+                {"name": "build", "runner": "org.osbuild.fedora38", "stages": [{"type": "org.osbuild.rpm", "options": {"packages": ["bash"]}}]},
+                {"name": "os", "stages": [{"type": "org.osbuild.noop"}]},
+                {"name": "empty"}
+            ]
+        });
+
+        assert!(validator().validate(&manifest).is_valid());
+    }
+
+    #[test]
+    fn errors_are_ordered() {
+        let pipelines: Vec<serde_json::Value> = (0..64)
+            .map(|index| {
+                serde_json::json!({
+                    "name": format!("pipeline-{}", index),
+                    "stages": [
+                        {"type": "org.osbuild.noop"},
+                        {"type": "org.osbuild.rpm", "options": {"packages": [index]}},
+                        {"type": "org.osbuild.unknown"},
+                    ]
+                })
+            })
+            .collect();
+
+        let result = validator().validate(&serde_json::json!({ "pipelines": pipelines }));
+        let ids: Vec<String> = result.errors().iter().map(|err| err.clone().id()).collect();
+
+        assert_eq!(ids.len(), 128);
+        assert_eq!(ids[0], ".pipelines[0].stages[1].options.packages[0]");
+        assert_eq!(ids[1], ".pipelines[0].stages[2].type");
+        assert_eq!(ids[127], ".pipelines[63].stages[2].type");
+    }
+
+    #[test]
+    fn unknown_runner() {
+        let result = validator().validate(&serde_json::json!({
+            "pipelines": [{"name": "build", "runner": "org.osbuild.centos9"}, {"name": "os", "runner": 9}]
+        }));
+
+        assert_eq!(result.errors().len(), 2);
+        assert_eq!(result.errors()[0].clone().id(), ".pipelines[0].runner");
+        assert_eq!(
+            result.errors()[0].message,
+            "unknown runner 'org.osbuild.centos9', available runners are: org.osbuild.fedora38, org.osbuild.linux"
+        );
+        assert_eq!(result.errors()[1].clone().id(), ".pipelines[1].runner");
+        assert_eq!(result.errors()[0].rule, Rule::UnknownRunner);
+        assert_eq!(result.errors()[1].rule, Rule::Structure);
+    }
+
+    #[test]
+    fn invalid_structure() {
+        let validator = validator();
+
+        assert_eq!(
+            validator.validate(&serde_json::json!({})).errors()[0]
+                .clone()
+                .id(),
+            ".pipelines"
+        );
+
+        let result = validator.validate(&serde_json::json!({
+            "pipelines": [1, {"stages": {}}, {"name": "os", "stages": [{}]}]
+        }));
+        let ids: Vec<String> = result.errors().iter().map(|err| err.clone().id()).collect();
+
+        assert_eq!(
+            ids,
+            vec![
+                ".pipelines[0]",
+                ".pipelines[1].name",
+                ".pipelines[1].stages",
+                ".pipelines[2].stages[0].type",
+            ]
+        );
+        assert_eq!(
+            validator
+                .validate(&serde_json::json!({"pipelines": [], "metadata": []}))
+                .errors()[0]
+                .clone()
+                .id(),
+            ".metadata"
+        );
+        assert!(matches!(
+            Validator::new().add_schema("org.osbuild.bad", &serde_json::json!({"type": 1})),
+            Err(ManifestDescriptionError::InvalidSchema(_, _))
+        ));
+    }
+
+    #[test]
+    fn revalidated_after_edits() {
+        let validator = validator();
+        let at = |parts: &[Part]| Path::new(parts.to_vec());
+        let name = |name: &str| Part::Name(name.to_string());
+
+        let mut manifest = serde_json::json!({
+            "pipelines": [
+                {"name": "build", "stages": [{"type": "org.osbuild.noop"}, {"type": "org.osbuild.rpm", "options": {"packages": [1]}}]},
+                {"name": "os", "stages": [{"type": "org.osbuild.noop"}]}
+            ]
+        });
+
+        let mut cache = validator.validate_cached(&manifest);
+
+        assert_eq!(cache.result().errors().len(), 1);
+
+        manifest["pipelines"][0]["stages"][1]["options"]["packages"][0] = "bash".into();
+
+        let changed = at(&[
+            name("pipelines"),
+            Part::Index(0),
+            name("stages"),
+            Part::Index(1),
+            name("options"),
+            name("packages"),
+            Part::Index(0),
+        ]);
+
+        assert!(validator
+            .revalidate(&mut cache, &manifest, &changed)
+            .is_valid());
+
+        // every kind of edit ends up with the errors a full validation finds
+        let edits: Vec<(Path, serde_json::Value)> = vec![
+            (
+                at(&[name("pipelines"), Part::Index(1), name("runner")]),
+                serde_json::json!({"name": "build", "stages": [{"type": "org.osbuild.noop"}, {"type": "org.osbuild.rpm"}]}),
+            ),
+            (
+                at(&[
+                    name("pipelines"),
+                    Part::Index(0),
+                    name("stages"),
+                    Part::Index(2),
+                ]),
+                serde_json::json!({"name": "build", "stages": [{"type": "org.osbuild.noop"}, {"type": "org.osbuild.rpm"}, {"type": "org.osbuild.unknown"}]}),
+            ),
+            (
+                at(&[name("pipelines"), Part::Index(0), name("stages")]),
+                serde_json::json!({"name": "build", "stages": {}}),
+            ),
+            (
+                at(&[
+                    name("pipelines"),
+                    Part::Index(0),
+                    name("stages"),
+                    Part::Index(0),
+                ]),
+                serde_json::json!({"name": "build", "stages": [{}]}),
+            ),
+        ];
+
+        for (changed, pipeline) in edits {
+            manifest["pipelines"][0] = pipeline;
+
+            let ids = |result: validation::Result| -> Vec<String> {
+                result.errors().iter().map(|err| err.clone().id()).collect()
+            };
+
+            assert_eq!(
+                ids(validator.revalidate(&mut cache, &manifest, &changed)),
+                ids(validator.validate(&manifest))
+            );
+        }
+
+        manifest["metadata"] = serde_json::json!([]);
+        manifest["pipelines"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({"name": "image"}));
+
+        let result = validator.revalidate(
+            &mut cache,
+            &manifest,
+            &at(&[name("pipelines"), Part::Index(2)]),
+        );
+
+        assert_eq!(result.errors()[0].clone().id(), ".metadata");
+        assert_eq!(
+            result.errors().len(),
+            validator.validate(&manifest).errors().len()
+        );
+
+        // the results of other pipelines are reused, even when another validator would find
+        // errors in them
+        let mut other = Validator::new();
+        other
+            .add_schema("org.osbuild.rpm", &serde_json::json!({}))
+            .unwrap();
+
+        manifest["pipelines"][0] =
+            serde_json::json!({"name": "build", "stages": [{"type": "org.osbuild.noop"}]});
+
+        let mut cache = validator.validate_cached(&manifest);
+        manifest["pipelines"][1]["name"] = "tree".into();
+
+        let result = other.revalidate(
+            &mut cache,
+            &manifest,
+            &at(&[name("pipelines"), Part::Index(1), name("name")]),
+        );
+        let ids: Vec<String> = result.errors().iter().map(|err| err.clone().id()).collect();
+
+        assert_eq!(ids, vec![".metadata", ".pipelines[1].stages[0].type"]);
+    }
+
+    #[test]
+    fn validated_against_format_schema() {
+        let offline = Validator::against_format_schema().unwrap();
+
+        assert!(offline
+            .validate(&serde_json::json!({
+                "version": "2",
+                "pipelines": [{
+                    "name": "os",
+                    "source-epoch": 0,
+                    "stages": [{
+                        "type": "org.osbuild.anything",
+                        "options": {"whatever": true},
+                        "inputs": {"tree": {"type": "org.osbuild.tree", "origin": "org.osbuild.pipeline", "references": ["name:build"]}},
+                        "mounts": [{"name": "root", "type": "org.osbuild.ext4", "source": "disk", "target": "/"}]
+                    }]
+                }],
+                "sources": {"org.osbuild.curl": {"items": {}}}
+            }))
+            .is_valid());
+
+        let result = offline.validate(&serde_json::json!({
+            "version": "2",
+            "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.noop", "options": []}]}]
+        }));
+
+        assert_eq!(result.errors().len(), 1);
+        assert_eq!(result.errors()[0].rule, Rule::Format);
+        assert_eq!(
+            result.errors()[0].clone().id(),
+            ".pipelines[0].stages[0].options"
+        );
+
+        assert!(offline
+            .validate(&serde_json::json!({
+                "pipeline": {"stages": [{"name": "org.osbuild.noop"}], "assembler": {"name": "org.osbuild.tar"}}
+            }))
+            .is_valid());
+        assert_eq!(
+            offline
+                .validate(&serde_json::json!({"pipeline": {"stages": [{}]}}))
+                .errors()[0]
+                .clone()
+                .id(),
+            ".pipeline.stages[0]"
+        );
+        assert_eq!(
+            offline
+                .validate(&serde_json::json!({"version": "3"}))
+                .errors()[0]
+                .clone()
+                .id(),
+            ".version"
+        );
+
+        // with schemas of stages, stages are checked against them as well
+        let mut validator = Validator::against_format_schema().unwrap();
+        validator
+            .add_schema("org.osbuild.noop", &serde_json::json!({}))
+            .unwrap();
+
+        let result = validator.validate(&serde_json::json!({
+            "version": "2",
+            "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.rpm"}]}]
+        }));
+
+        assert_eq!(
+            result.errors()[0].message,
+            "unknown stage 'org.osbuild.rpm'"
+        );
+    }
+
+    #[cfg(feature = "executor")]
     #[test]
-    fn dummy() {
-        assert_eq!(1, 1);
+    fn forbidden_capabilities() {
+        let mut validator = validator();
+        let schema = serde_json::json!({"capabilities": ["network"]});
+
+        validator.add_schema("org.osbuild.curl", &schema).unwrap();
+        validator.add_capabilities(
+            "org.osbuild.curl",
+            &crate::module::capability::from_schema(&schema).unwrap(),
+        );
+
+        let manifest = serde_json::json!({
+            "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.noop"}, {"type": "org.osbuild.curl"}]}]
+        });
+
+        assert!(validator.validate(&manifest).is_valid());
+
+        let mut policy = Policy::allow_all();
+        policy.forbid(Capability::Network);
+        validator.set_policy(policy);
+
+        let result = validator.validate(&manifest);
+
+        assert_eq!(result.errors().len(), 1);
+        assert_eq!(
+            result.errors()[0].clone().id(),
+            ".pipelines[0].stages[1].type"
+        );
+        assert_eq!(
+            result.errors()[0].message,
+            "stage 'org.osbuild.curl' needs capabilities the policy forbids: network"
+        );
+    }
+
+    #[cfg(feature = "executor")]
+    #[test]
+    fn validated_against_snapshot() {
+        let snapshot = serde_json::json!({
+            "version": 1,
+            "scanned": 1700000000,
+            "modules": [
+                {"name": "org.osbuild.noop", "kind": "stage", "path": "/usr/lib/osbuild/stages/org.osbuild.noop", "schema": {}},
+                {"name": "org.osbuild.tree", "kind": "input", "path": "/usr/lib/osbuild/inputs/org.osbuild.tree", "schema": {}},
+                {"name": "org.osbuild.ext4", "kind": "mount", "path": "/usr/lib/osbuild/mounts/org.osbuild.ext4", "schema": {}},
+                {"name": "org.osbuild.linux", "kind": "runner", "path": "/usr/lib/osbuild/runners/org.osbuild.linux", "schema": {}}
+            ]
+        });
+
+        let remote = Validator::from_snapshot(&snapshot).unwrap();
+        let manifest = serde_json::json!({
+            "pipelines": [{
+                "name": "os",
+                "runner": "org.osbuild.linux",
+                "stages": [{
+                    "type": "org.osbuild.noop",
+                    "inputs": {"tree": {"type": "org.osbuild.tree"}},
+                    "devices": {"disk": {"type": "org.osbuild.loopback"}},
+                    "mounts": [{"name": "root", "type": "org.osbuild.ext4"}]
+                }, {
+                    "type": "org.osbuild.rpm"
+                }]
+            }]
+        });
+
+        let result = remote.validate(&manifest);
+        let ids: Vec<String> = result.errors().iter().map(|err| err.clone().id()).collect();
+
+        assert_eq!(
+            ids,
+            vec![
+                ".pipelines[0].stages[0].devices.disk.type",
+                ".pipelines[0].stages[1].type",
+            ]
+        );
+        assert_eq!(
+            result.errors()[0].message,
+            "unknown device module 'org.osbuild.loopback'"
+        );
+
+        // without modules of the other kinds, their use isn't checked
+        assert!(validator().validate(&serde_json::json!({
+            "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.noop", "devices": {"disk": {"type": "org.osbuild.loopback"}}}]}]
+        })).is_valid());
+
+        assert!(matches!(
+            Validator::from_snapshot(&serde_json::json!({})),
+            Err(ManifestDescriptionError::RegistryError(_))
+        ));
+    }
+
+    #[test]
+    fn effective_options_defaulted() {
+        let mut validator = validator();
+
+        validator
+            .add_schema(
+                "org.osbuild.mkdir",
+                &serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "paths": {"type": "array"},
+                        "exist_ok": {"type": "boolean", "default": false}
+                    }
+                }),
+            )
+            .unwrap();
+
+        assert_eq!(
+            validator.effective_options(
+                "org.osbuild.mkdir",
+                Some(&serde_json::json!({"paths": ["/etc"]}))
+            ),
+            serde_json::json!({"paths": ["/etc"], "exist_ok": false})
+        );
+        assert_eq!(
+            validator.effective_options("org.osbuild.mkdir", None),
+            serde_json::json!({"exist_ok": false})
+        );
+
+        // options with defaults still validate, stages without a schema keep theirs
+        assert!(validator
+            .validate(&serde_json::json!({
+                "pipelines": [{"name": "os", "stages": [{
+                    "type": "org.osbuild.mkdir",
+                    "options": validator.effective_options("org.osbuild.mkdir", None)
+                }]}]
+            }))
+            .is_valid());
+        assert_eq!(
+            validator.effective_options("org.osbuild.noop", Some(&serde_json::json!({"a": 1}))),
+            serde_json::json!({"a": 1})
+        );
     }
 }
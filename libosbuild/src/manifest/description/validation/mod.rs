@@ -1,13 +1,137 @@
+use serde::{Deserialize, Serialize};
+
 use crate::manifest::path as manifest_path;
+use crate::manifest::source::Source;
+
+/// Exporting validation results as SARIF, for annotating manifests in CI systems.
+pub mod sarif;
 
 #[cfg(test)]
 pub mod test;
 
-/// Describes a single failed validation. Consists of a `message` describing the error and a `path`
-/// that points to the thing that caused the error.
+/// How serious a diagnostic is. Only errors make a manifest invalid or fail a build, warnings
+/// do so only when they are denied, and infos never do.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    #[default]
+    Error,
+}
+
+impl Severity {
+    /// The name of the severity in reports, such as `warning`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+
+    /// The `level` of the severity in SARIF logs.
+    pub fn sarif_level(&self) -> &'static str {
+        match self {
+            Self::Info => "note",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// The kind of check a validation failed, errors of one rule share an id in reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// The manifest doesn't match the schema of its format version.
+    Format,
+
+    /// A field of the manifest is missing or of the wrong type.
+    Structure,
+
+    UnknownRunner,
+    UnknownStage,
+
+    /// An input, device, or mount module that isn't available.
+    UnknownModule,
+
+    /// The options of a stage don't match the schema of the stage.
+    Options,
+
+    /// A stage needs capabilities the policy forbids.
+    Capabilities,
+
+    /// A module has no schema to validate against.
+    Schema,
+
+    /// The manifest uses something that still works but is going away.
+    Deprecated,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 9] = [
+        Rule::Format,
+        Rule::Structure,
+        Rule::UnknownRunner,
+        Rule::UnknownStage,
+        Rule::UnknownModule,
+        Rule::Options,
+        Rule::Capabilities,
+        Rule::Schema,
+        Rule::Deprecated,
+    ];
+
+    /// The id of the rule in reports, such as `unknown-stage`.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::Format => "format",
+            Self::Structure => "structure",
+            Self::UnknownRunner => "unknown-runner",
+            Self::UnknownStage => "unknown-stage",
+            Self::UnknownModule => "unknown-module",
+            Self::Options => "options",
+            Self::Capabilities => "capabilities",
+            Self::Schema => "schema",
+            Self::Deprecated => "deprecated",
+        }
+    }
+
+    /// The severity of what the rule finds, unless it is denied.
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::Deprecated => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Format => "The manifest doesn't match the schema of its format version.",
+            Self::Structure => "A field of the manifest is missing or of the wrong type.",
+            Self::UnknownRunner => "A pipeline asks for a runner that isn't available.",
+            Self::UnknownStage => "A stage has a type no stage module is available for.",
+            Self::UnknownModule => {
+                "A stage uses an input, device, or mount module that isn't available."
+            }
+            Self::Options => "The options of a stage don't match the schema of the stage.",
+            Self::Capabilities => "A stage needs capabilities the policy forbids.",
+            Self::Schema => "A module has no schema to validate against.",
+            Self::Deprecated => "The manifest uses something that still works but is going away.",
+        }
+    }
+}
+
+/// Describes a single failed validation. Consists of the `rule` that failed, a `message`
+/// describing the error and a `path` that points to the thing that caused the error. Not
+/// every failed validation is an error, its `severity` says how serious it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Error {
+    pub rule: Rule,
     pub message: String,
     pub path: manifest_path::Path,
+    pub severity: Severity,
 }
 
 impl Error {
@@ -16,20 +140,137 @@ impl Error {
     pub fn id(self) -> String {
         format!("{}", self.path)
     }
+
+    /// Render the error with the line of `source` it is about, the value at its path is
+    /// underlined. Errors about something missing underline what it is missing from.
+    ///
+    /// ```text
+    /// error: unknown stage 'org.osbuild.rpm'
+    ///  --> manifest.json:5:17
+    ///   |
+    /// 5 |       "type": "org.osbuild.rpm",
+    ///   |               ^^^^^^^^^^^^^^^^^
+    ///   = at .pipelines[0].stages[1].type
+    /// ```
+    pub fn render(&self, source: &Source) -> String {
+        let (_, span) = source.closest_span(&self.path);
+        let location = source.location(span.start);
+        let line = source.line(location.line).unwrap_or_default();
+
+        // underline up to the end of the first line of values spanning several
+        let start = source.text()[..span.start]
+            .rfind('\n')
+            .map_or(0, |index| index + 1);
+        let length = source.text()[span.start..span.end.max(span.start)]
+            .split('\n')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .count()
+            .max(1);
+
+        let number = location.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let indent: String = source.text()[start..span.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        format!(
+            "{}: {}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {}{}\n{} = at {}",
+            self.severity.name(),
+            self.message,
+            gutter,
+            source.name(),
+            location,
+            gutter,
+            number,
+            line,
+            gutter,
+            indent,
+            "^".repeat(length),
+            gutter,
+            self.path,
+        )
+    }
 }
 
+/// The errors of a validation, and apart from them the warnings and infos which don't make
+/// what was validated invalid.
+#[derive(Debug, Clone)]
 pub struct Result {
     errors: Vec<Error>,
+    warnings: Vec<Error>,
 }
 
 impl Result {
     pub fn new() -> Self {
-        Self { errors: vec![] }
+        Self {
+            errors: vec![],
+            warnings: vec![],
+        }
     }
 
-    /// Add a `Error` to the set of errors
+    /// Add a `Error` to the set of errors, or to the warnings when its severity is lower.
     pub fn add_error(&mut self, error: Error) {
-        self.errors.push(error);
+        match error.severity {
+            Severity::Error => self.errors.push(error),
+            Severity::Warning | Severity::Info => self.warnings.push(error),
+        }
+    }
+
+    /// Append the errors and warnings of `other` after those of this result.
+    pub fn merge(&mut self, other: Result) {
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+    }
+
+    pub fn errors(&self) -> &[Error] {
+        &self.errors
+    }
+
+    /// The warnings and infos.
+    pub fn warnings(&self) -> &[Error] {
+        &self.warnings
+    }
+
+    /// Every error, then every warning and info.
+    pub fn diagnostics(&self) -> impl Iterator<Item = &Error> {
+        self.errors.iter().chain(&self.warnings)
+    }
+
+    /// Make the warnings errors, for `--deny-warnings`. Infos stay what they are.
+    pub fn deny_warnings(&mut self) {
+        let (denied, kept) = self
+            .warnings
+            .drain(..)
+            .partition(|warning| warning.severity == Severity::Warning);
+
+        self.warnings = kept;
+
+        for mut warning in denied {
+            warning.severity = Severity::Error;
+            self.errors.push(warning);
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Render every error, then every warning and info, with the line of `source` it is
+    /// about, see `Error::render`.
+    pub fn render(&self, source: &Source) -> String {
+        self.diagnostics()
+            .map(|error| error.render(source))
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+impl Default for Result {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -0,0 +1,142 @@
+use serde_json::json;
+
+use crate::manifest::description::validation::{Result, Rule};
+use crate::manifest::source::Source;
+
+pub const SARIF_VERSION: &str = "2.1.0";
+
+pub const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+/// A SARIF log of validating one or more manifests, which code scanning services such as
+/// GitHub's read to annotate the lines of manifests that are invalid.
+#[derive(Debug, Default)]
+pub struct Sarif {
+    results: Vec<serde_json::Value>,
+}
+
+impl Sarif {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the errors and warnings of validating the manifest in `source`, the name of the
+    /// source is used as the uri of the manifest and should be relative to the root of the
+    /// repository.
+    pub fn add(&mut self, source: &Source, result: &Result) {
+        for error in result.diagnostics() {
+            let (_, span) = source.closest_span(&error.path);
+            let start = source.location(span.start);
+            let end = source.location(span.end);
+
+            self.results.push(json!({
+                "ruleId": error.rule.id(),
+                "ruleIndex": Rule::ALL.iter().position(|rule| *rule == error.rule),
+                "level": error.severity.sarif_level(),
+                "message": {"text": error.message},
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": {"uri": source.name()},
+                        "region": {
+                            "startLine": start.line,
+                            "startColumn": start.column,
+                            "endLine": end.line,
+                            "endColumn": end.column,
+                        },
+                    },
+                    "logicalLocations": [{"fullyQualifiedName": error.path.to_string()}],
+                }],
+            }));
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let rules: Vec<serde_json::Value> = Rule::ALL
+            .iter()
+            .map(|rule| {
+                json!({
+                    "id": rule.id(),
+                    "shortDescription": {"text": rule.description()},
+                    "defaultConfiguration": {"level": rule.severity().sarif_level()},
+                })
+            })
+            .collect();
+
+        json!({
+            "$schema": SARIF_SCHEMA,
+            "version": SARIF_VERSION,
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": "osbuild",
+                        "informationUri": "https://osbuild.org",
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "rules": rules,
+                    },
+                },
+                // columns are counted in characters, see `Source::location`
+                "columnKind": "unicodeCodePoints",
+                "results": self.results,
+            }],
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manifest::description::validation::{Error, Severity};
+    use crate::manifest::path::{Part, Path};
+
+    #[test]
+    fn errors_exported() {
+        let source = Source::parse(
+            "manifests/fedora.json",
+            "{\n  \"pipelines\": [\n    {\"name\": \"os\", \"runner\": \"org.osbuild.centos9\"}\n  ]\n}\n",
+        )
+        .unwrap();
+
+        let mut result = Result::new();
+
+        result.add_error(Error {
+            rule: Rule::UnknownRunner,
+            message: "unknown runner 'org.osbuild.centos9'".to_string(),
+            path: Path::new(vec![
+                Part::Name("pipelines".to_string()),
+                Part::Index(0),
+                Part::Name("runner".to_string()),
+            ]),
+            severity: Severity::Error,
+        });
+
+        let mut sarif = Sarif::new();
+        sarif.add(&source, &result);
+        sarif.add(&source, &Result::new());
+
+        let log = sarif.to_json();
+        let run = &log["runs"][0];
+
+        assert_eq!(log["version"], SARIF_VERSION);
+        assert_eq!(run["tool"]["driver"]["rules"].as_array().unwrap().len(), 9);
+        assert_eq!(run["results"].as_array().unwrap().len(), 1);
+
+        let exported = &run["results"][0];
+        let index = exported["ruleIndex"].as_u64().unwrap() as usize;
+
+        assert_eq!(exported["ruleId"], "unknown-runner");
+        assert_eq!(
+            run["tool"]["driver"]["rules"][index]["id"],
+            "unknown-runner"
+        );
+        assert_eq!(
+            exported["locations"][0]["physicalLocation"],
+            json!({
+                "artifactLocation": {"uri": "manifests/fedora.json"},
+                "region": {"startLine": 3, "startColumn": 30, "endLine": 3, "endColumn": 51}
+            })
+        );
+        assert_eq!(
+            exported["locations"][0]["logicalLocations"][0]["fullyQualifiedName"],
+            ".pipelines[0].runner"
+        );
+    }
+}
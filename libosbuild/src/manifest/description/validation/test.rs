@@ -1,39 +1,127 @@
+#[cfg(feature = "executor")]
 use crate::core::Schema;
 use crate::manifest::description::validation;
 use crate::manifest::path;
+use crate::manifest::source::Source;
 
 #[test]
 fn validation_result_no_error_valid() {
     let result = validation::Result::new();
     let valid: bool = result.into();
 
-    assert_eq!(valid, true);
+    assert!(valid);
 }
 
 #[test]
 fn validation_result_error_invalid() {
     let mut result = validation::Result::new();
     result.add_error(validation::Error {
+        rule: validation::Rule::Structure,
         message: "booboo".to_string(),
         path: path::Path(vec![]),
+        severity: validation::Severity::Error,
     });
     let valid: bool = result.into();
 
-    assert_eq!(valid, false);
+    assert!(!valid);
 }
 
+#[test]
+fn validation_error_rendered() {
+    let source = Source::parse(
+        "manifest.json",
+        "{\n  \"pipelines\": [\n    {\"name\": \"os\", \"stages\": [{\"type\": \"org.osbuild.rpm\"}]},\n    {}\n  ]\n}\n",
+    )
+    .unwrap();
+
+    let mut result = validation::Result::new();
+
+    result.add_error(validation::Error {
+        rule: validation::Rule::UnknownStage,
+        message: "unknown stage 'org.osbuild.rpm'".to_string(),
+        path: path::Path(vec![
+            path::Part::Name("pipelines".to_string()),
+            path::Part::Index(0),
+            path::Part::Name("stages".to_string()),
+            path::Part::Index(0),
+            path::Part::Name("type".to_string()),
+        ]),
+        severity: validation::Severity::Error,
+    });
+    result.add_error(validation::Error {
+        rule: validation::Rule::Structure,
+        message: "pipeline must have a name".to_string(),
+        path: path::Path(vec![
+            path::Part::Name("pipelines".to_string()),
+            path::Part::Index(1),
+            path::Part::Name("name".to_string()),
+        ]),
+        severity: validation::Severity::Error,
+    });
+
+    assert_eq!(
+        result.render(&source),
+        [
+            "error: unknown stage 'org.osbuild.rpm'",
+            " --> manifest.json:3:40",
+            "  |",
+            "3 |     {\"name\": \"os\", \"stages\": [{\"type\": \"org.osbuild.rpm\"}]},",
+            "  |                                        ^^^^^^^^^^^^^^^^^",
+            "  = at .pipelines[0].stages[0].type",
+            "",
+            "error: pipeline must have a name",
+            " --> manifest.json:4:5",
+            "  |",
+            "4 |     {}",
+            "  |     ^^",
+            "  = at .pipelines[1].name",
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn validation_warnings_denied() {
+    let mut result = validation::Result::new();
+
+    for (severity, message) in [
+        (validation::Severity::Warning, "deprecated"),
+        (validation::Severity::Info, "noted"),
+    ] {
+        result.add_error(validation::Error {
+            rule: validation::Rule::Deprecated,
+            message: message.to_string(),
+            path: path::Path(vec![]),
+            severity,
+        });
+    }
+
+    assert!(result.is_valid());
+    assert_eq!(result.warnings().len(), 2);
+    assert_eq!(result.diagnostics().count(), 2);
+
+    result.deny_warnings();
+
+    assert!(!result.is_valid());
+    assert_eq!(result.errors()[0].message, "deprecated");
+    assert_eq!(result.errors()[0].severity, validation::Severity::Error);
+    assert_eq!(result.warnings()[0].message, "noted");
+}
+
+#[cfg(feature = "executor")]
 #[test]
 fn schema_without_data_is_invalid() {
     let schema = Schema::new(Some("name".to_string()), None);
     let valid = schema.is_valid();
 
-    assert_eq!(valid, false);
+    assert!(!valid);
 }
 
+#[cfg(feature = "executor")]
 #[test]
 fn schema_with_data_is_valid() {
     let schema = Schema::new(Some("name".to_string()), Some("data".to_string()));
     let valid = schema.is_valid();
 
-    assert_eq!(valid, true);
+    assert!(valid);
 }
@@ -0,0 +1,265 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::manifest::variables::{self, VariableError};
+
+/// The key of a manifest that names the files its sources are in, a path or a list of paths
+/// relative to the manifest.
+pub const SOURCES_FROM: &str = "sources-from";
+
+#[derive(Debug)]
+pub enum IncludeError {
+    /// A file could not be read.
+    IOError(PathBuf, io::Error),
+
+    /// A file is not valid JSON.
+    ParseError(PathBuf, serde_json::Error),
+
+    /// `sources-from` isn't a path or a list of paths, or a file of sources isn't an object
+    /// of sources by module name.
+    Invalid(String),
+
+    /// Two files, or a file and the manifest, give a source different values; contains the
+    /// name of the source and the item or key they differ in.
+    Conflict(String, String),
+
+    /// The variables of the manifest could not be expanded.
+    VariableError(VariableError),
+}
+
+impl From<VariableError> for IncludeError {
+    fn from(err: VariableError) -> Self {
+        Self::VariableError(err)
+    }
+}
+
+/// Read the manifest at `path`, merge the sources its `sources-from` names into it, and
+/// expand its variables.
+pub fn load(path: &Path) -> Result<serde_json::Value, IncludeError> {
+    let data = fs::read(path).map_err(|err| IncludeError::IOError(path.to_path_buf(), err))?;
+    let mut manifest: serde_json::Value = serde_json::from_slice(&data)
+        .map_err(|err| IncludeError::ParseError(path.to_path_buf(), err))?;
+
+    resolve_sources(
+        &mut manifest,
+        path.parent().unwrap_or_else(|| Path::new("")),
+    )?;
+
+    variables::expand(&mut manifest)?;
+
+    Ok(manifest)
+}
+
+/// Merge the files of sources `sources-from` names, relative to `base`, into the sources of
+/// `manifest` and remove the key, so generated source lists can live apart from the pipelines
+/// written by hand. A file holds what the manifest would have as its `sources`. Items are
+/// merged by their id; an item, or an option of a source, that is given twice has to be
+/// given the same value both times. Returns the files that were merged.
+pub fn resolve_sources(
+    manifest: &mut serde_json::Value,
+    base: &Path,
+) -> Result<Vec<PathBuf>, IncludeError> {
+    let Some(object) = manifest.as_object_mut() else {
+        return Ok(vec![]);
+    };
+
+    let paths = match object.remove(SOURCES_FROM) {
+        None => return Ok(vec![]),
+        Some(serde_json::Value::String(path)) => vec![path],
+        Some(serde_json::Value::Array(paths)) => paths
+            .into_iter()
+            .map(|path| match path {
+                serde_json::Value::String(path) => Ok(path),
+                _ => Err(IncludeError::Invalid(format!(
+                    "{} must be a path or a list of paths",
+                    SOURCES_FROM
+                ))),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => {
+            return Err(IncludeError::Invalid(format!(
+                "{} must be a path or a list of paths",
+                SOURCES_FROM
+            )))
+        }
+    };
+
+    let sources = object
+        .entry("sources")
+        .or_insert_with(|| serde_json::json!({}))
+        .as_object_mut()
+        .ok_or_else(|| IncludeError::Invalid("sources must be an object".to_string()))?;
+
+    let mut merged = vec![];
+
+    for path in paths {
+        let path = base.join(path);
+        let data = fs::read(&path).map_err(|err| IncludeError::IOError(path.clone(), err))?;
+        let included: serde_json::Value = serde_json::from_slice(&data)
+            .map_err(|err| IncludeError::ParseError(path.clone(), err))?;
+
+        let serde_json::Value::Object(included) = included else {
+            return Err(IncludeError::Invalid(format!(
+                "'{}' must be an object of sources",
+                path.display()
+            )));
+        };
+
+        for (name, source) in included {
+            merge_source(sources, &name, source)?;
+        }
+
+        merged.push(path);
+    }
+
+    Ok(merged)
+}
+
+/// Merge `source` into the source `name` of `sources`.
+pub(crate) fn merge_source(
+    sources: &mut serde_json::Map<String, serde_json::Value>,
+    name: &str,
+    source: serde_json::Value,
+) -> Result<(), IncludeError> {
+    let invalid = || IncludeError::Invalid(format!("source '{}' must be an object", name));
+
+    let serde_json::Value::Object(source) = source else {
+        return Err(invalid());
+    };
+
+    let existing = sources
+        .entry(name)
+        .or_insert_with(|| serde_json::json!({}))
+        .as_object_mut()
+        .ok_or_else(invalid)?;
+
+    for (key, value) in source {
+        match (key.as_str(), existing.get_mut(&key), value) {
+            ("items", Some(serde_json::Value::Object(items)), serde_json::Value::Object(new)) => {
+                for (id, item) in new {
+                    match items.get(&id) {
+                        Some(other) if *other != item => {
+                            return Err(IncludeError::Conflict(name.to_string(), id))
+                        }
+                        _ => {
+                            items.insert(id, item);
+                        }
+                    }
+                }
+            }
+            (_, Some(other), value) if *other != value => {
+                return Err(IncludeError::Conflict(name.to_string(), key))
+            }
+            (_, _, value) => {
+                existing.insert(key, value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sources_included() {
+        let directory = tempfile::tempdir().unwrap();
+
+        fs::create_dir(directory.path().join("generated")).unwrap();
+        fs::write(
+            directory.path().join("generated/rpms.json"),
+            r#"{"org.osbuild.curl": {"items": {"sha256:aa": {"url": "https://example.com/a.rpm"}, "sha256:bb": "https://example.com/b.rpm"}}}"#,
+        )
+        .unwrap();
+        fs::write(
+            directory.path().join("containers.json"),
+            r#"{"org.osbuild.skopeo": {"items": {"sha256:cc": {"image": {"name": "fedora"}}}}}"#,
+        )
+        .unwrap();
+        fs::write(
+            directory.path().join("manifest.json"),
+            r#"{
+                "version": "2",
+                "sources-from": ["generated/rpms.json", "containers.json"],
+                "sources": {"org.osbuild.curl": {"items": {"sha256:bb": "https://example.com/b.rpm"}}}
+            }"#,
+        )
+        .unwrap();
+
+        let manifest = load(&directory.path().join("manifest.json")).unwrap();
+
+        assert_eq!(
+            manifest,
+            serde_json::json!({
+                "version": "2",
+                "sources": {
+                    "org.osbuild.curl": {"items": {
+                        "sha256:aa": {"url": "https://example.com/a.rpm"},
+                        "sha256:bb": "https://example.com/b.rpm"
+                    }},
+                    "org.osbuild.skopeo": {"items": {"sha256:cc": {"image": {"name": "fedora"}}}}
+                }
+            })
+        );
+
+        // manifests without the key are left as they are
+        let mut plain = serde_json::json!({"version": "2"});
+
+        assert!(resolve_sources(&mut plain, directory.path())
+            .unwrap()
+            .is_empty());
+        assert_eq!(plain, serde_json::json!({"version": "2"}));
+    }
+
+    #[test]
+    fn sources_conflicting() {
+        let directory = tempfile::tempdir().unwrap();
+
+        fs::write(
+            directory.path().join("sources.json"),
+            r#"{"org.osbuild.curl": {"items": {"sha256:aa": "https://example.com/a.rpm"}, "options": {"insecure": true}}}"#,
+        )
+        .unwrap();
+
+        let mut manifest = serde_json::json!({
+            "sources-from": "sources.json",
+            "sources": {"org.osbuild.curl": {"items": {"sha256:aa": "https://mirror.example.com/a.rpm"}}}
+        });
+
+        assert!(matches!(
+            resolve_sources(&mut manifest, directory.path()),
+            Err(IncludeError::Conflict(source, item)) if source == "org.osbuild.curl" && item == "sha256:aa"
+        ));
+
+        let mut manifest = serde_json::json!({
+            "sources-from": "sources.json",
+            "sources": {"org.osbuild.curl": {"options": {"insecure": false}}}
+        });
+
+        assert!(matches!(
+            resolve_sources(&mut manifest, directory.path()),
+            Err(IncludeError::Conflict(_, key)) if key == "options"
+        ));
+
+        for sources_from in [serde_json::json!(1), serde_json::json!([1])] {
+            assert!(matches!(
+                resolve_sources(
+                    &mut serde_json::json!({ "sources-from": sources_from }),
+                    directory.path()
+                ),
+                Err(IncludeError::Invalid(_))
+            ));
+        }
+
+        assert!(matches!(
+            resolve_sources(
+                &mut serde_json::json!({"sources-from": "missing.json"}),
+                directory.path()
+            ),
+            Err(IncludeError::IOError(_, _))
+        ));
+    }
+}
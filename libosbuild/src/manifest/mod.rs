@@ -1,21 +1,225 @@
 pub mod description;
 pub mod path;
 
+/// Manifests that keep their sources in files of their own.
+pub mod include;
+
+/// The manifest model, what a manifest description deserializes into.
+pub mod model;
+
+/// The text of manifests and where their values are written, for pointing errors at it.
+pub mod source;
+
+/// Reading large manifests without holding all of them in memory.
+pub mod stream;
+
+/// Constants manifests declare once and refer to in the options of their stages.
+pub mod variables;
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub use model::{
+    Device, Input, Manifest, Metadata, Mount, Origin, Pipeline, Producer, Reference, References,
+    Stage,
+};
+
 #[derive(Debug)]
 pub enum ManifestError {
+    /// The manifest is not valid JSON or not an object.
+    ParseError(serde_json::Error),
+
+    /// The manifest declares a version that isn't known.
+    UnknownVersion(String),
+}
+
+impl From<serde_json::Error> for ManifestError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::ParseError(err)
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Version {
     V1,
     V2,
 }
 
-pub struct Manifest {}
+impl Version {
+    /// The version of a manifest, manifests without a `version` key are version 1.
+    pub fn detect(manifest: &serde_json::Value) -> Result<Self, ManifestError> {
+        match manifest.get("version").and_then(|version| version.as_str()) {
+            None => Ok(Self::V1),
+            Some(version) => version.parse(),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::V1 => write!(f, "1"),
+            Self::V2 => write!(f, "2"),
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = ManifestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(Self::V1),
+            "2" => Ok(Self::V2),
+            version => Err(ManifestError::UnknownVersion(version.to_string())),
+        }
+    }
+}
+
+/// Versions are serialized as the strings manifests use, `"2"` for version 2.
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = String::deserialize(deserializer)?;
+
+        version
+            .parse()
+            .map_err(|_| serde::de::Error::custom(format!("unknown version '{}'", version)))
+    }
+}
+
+/// The names of the pipelines in a manifest, in the order they are declared. Version 1
+/// manifests don't name their pipelines so they have none.
+pub fn pipeline_names(data: &[u8]) -> Result<Vec<String>, ManifestError> {
+    let manifest: serde_json::Value = serde_json::from_slice(data)?;
+
+    Ok(match Version::detect(&manifest)? {
+        Version::V1 => vec![],
+        Version::V2 => manifest
+            .get("pipelines")
+            .and_then(|pipelines| pipelines.as_array())
+            .map(|pipelines| {
+                pipelines
+                    .iter()
+                    .filter_map(|pipeline| pipeline.get("name")?.as_str())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    })
+}
+
+/// The names of the modules a manifest uses for its stages, and their devices and mounts. Names
+/// are in the order they appear and can repeat. For version 1 manifests the stages and
+/// assemblers of all pipelines are returned.
+pub fn module_names(data: &[u8]) -> Result<Vec<String>, ManifestError> {
+    let manifest: serde_json::Value = serde_json::from_slice(data)?;
+    let mut names = vec![];
+
+    fn name_of(value: &serde_json::Value, key: &str) -> Option<String> {
+        value.get(key)?.as_str().map(String::from)
+    }
+
+    fn v1_pipeline(pipeline: &serde_json::Value, names: &mut Vec<String>) {
+        if let Some(build) = pipeline
+            .get("build")
+            .and_then(|build| build.get("pipeline"))
+        {
+            v1_pipeline(build, names);
+        }
+
+        for stage in pipeline
+            .get("stages")
+            .and_then(|stages| stages.as_array())
+            .into_iter()
+            .flatten()
+        {
+            names.extend(name_of(stage, "name"));
+        }
+
+        if let Some(assembler) = pipeline.get("assembler") {
+            names.extend(name_of(assembler, "name"));
+        }
+    }
+
+    match Version::detect(&manifest)? {
+        Version::V1 => {
+            if let Some(pipeline) = manifest.get("pipeline") {
+                v1_pipeline(pipeline, &mut names);
+            }
+        }
+        Version::V2 => {
+            let stages = manifest
+                .get("pipelines")
+                .and_then(|pipelines| pipelines.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|pipeline| pipeline.get("stages")?.as_array())
+                .flatten();
+
+            for stage in stages {
+                names.extend(name_of(stage, "type"));
+
+                if let Some(devices) = stage.get("devices").and_then(|d| d.as_object()) {
+                    names.extend(devices.values().filter_map(|d| name_of(d, "type")));
+                }
+
+                if let Some(mounts) = stage.get("mounts").and_then(|m| m.as_array()) {
+                    names.extend(mounts.iter().filter_map(|m| name_of(m, "type")));
+                }
+            }
+        }
+    }
+
+    Ok(names)
+}
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    #[test]
+    fn manifest_pipeline_names() {
+        assert_eq!(
+            pipeline_names(
+                br#"{"version": "2", "pipelines": [{"name": "build"}, {"name": "os"}, {"name": "image"}]}"#
+            )
+            .unwrap(),
+            vec!["build", "os", "image"]
+        );
+        assert!(pipeline_names(br#"{"pipeline": {}}"#).unwrap().is_empty());
+        assert!(matches!(
+            pipeline_names(br#"{"version": "3"}"#),
+            Err(ManifestError::UnknownVersion(_))
+        ));
+        assert!(matches!(
+            pipeline_names(b"nope"),
+            Err(ManifestError::ParseError(_))
+        ));
+    }
+
     #[test]
-    fn dummy() {
-        assert_eq!(1, 1);
+    fn manifest_module_names() {
+        assert_eq!(
+            module_names(
+                br#"{"version": "2", "pipelines": [{"name": "image", "stages": [{"type": "org.osbuild.copy", "devices": {"disk": {"type": "org.osbuild.loopback"}}, "mounts": [{"type": "org.osbuild.ext4"}]}]}]}"#
+            )
+            .unwrap(),
+            vec!["org.osbuild.copy", "org.osbuild.loopback", "org.osbuild.ext4"]
+        );
+        assert_eq!(
+            module_names(
+                br#"{"pipeline": {"build": {"pipeline": {"stages": [{"name": "org.osbuild.rpm"}]}}, "stages": [{"name": "org.osbuild.selinux"}], "assembler": {"name": "org.osbuild.qemu"}}}"#
+            )
+            .unwrap(),
+            vec!["org.osbuild.rpm", "org.osbuild.selinux", "org.osbuild.qemu"]
+        );
     }
 }
@@ -0,0 +1,638 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::Version;
+
+/// A version 2 manifest: pipelines of stages, and the sources the stages take their inputs
+/// from. Serializes to and deserializes from manifest descriptions, so it can be embedded in
+/// other types that are exchanged as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    version: Version,
+
+    #[serde(default)]
+    pipelines: Vec<Pipeline>,
+
+    /// Sources by module name, their items and options are left to the source modules.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    sources: BTreeMap<String, serde_json::Value>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self {
+            version: Version::V2,
+            pipelines: vec![],
+            sources: BTreeMap::new(),
+            metadata: None,
+        }
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn pipelines(&self) -> &[Pipeline] {
+        &self.pipelines
+    }
+
+    /// Find a pipeline by its name.
+    pub fn pipeline(&self, name: &str) -> Option<&Pipeline> {
+        self.pipelines.iter().find(|pipeline| pipeline.name == name)
+    }
+
+    pub fn add_pipeline(&mut self, pipeline: Pipeline) {
+        self.pipelines.push(pipeline);
+    }
+
+    pub fn sources(&self) -> &BTreeMap<String, serde_json::Value> {
+        &self.sources
+    }
+
+    pub fn add_source(&mut self, name: &str, source: serde_json::Value) {
+        self.sources.insert(name.to_string(), source);
+    }
+
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    pub fn set_metadata(&mut self, metadata: Option<Metadata>) {
+        self.metadata = metadata;
+    }
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The `metadata` of a manifest, information about the manifest that doesn't change what is
+/// built, such as the tool that produced it. Keys other than `producer` are kept as they are
+/// so that metadata recorded by other tools survives reading and writing a manifest.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    producer: Option<Producer>,
+
+    #[serde(flatten)]
+    other: BTreeMap<String, serde_json::Value>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn producer(&self) -> Option<&Producer> {
+        self.producer.as_ref()
+    }
+
+    pub fn set_producer(&mut self, producer: Option<Producer>) {
+        self.producer = producer;
+    }
+
+    /// A metadata key other than `producer`.
+    pub fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        self.other.get(key)
+    }
+
+    pub fn insert(&mut self, key: &str, value: serde_json::Value) {
+        self.other.insert(key.to_string(), value);
+    }
+}
+
+/// The tool that produced a manifest, e.g. `osbuild-composer`, and its version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Producer {
+    name: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+}
+
+impl Producer {
+    pub fn new(name: &str, version: Option<&str>) -> Self {
+        Self {
+            name: name.to_string(),
+            version: version.map(String::from),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+}
+
+/// A named pipeline, its stages run in order on the tree of the pipeline, in the build root
+/// made by the `build` pipeline.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Pipeline {
+    name: String,
+
+    /// The pipeline providing the build root, as `name:<pipeline>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    build: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    runner: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_epoch: Option<u64>,
+
+    #[serde(default)]
+    stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            build: None,
+            runner: None,
+            source_epoch: None,
+            stages: vec![],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn build(&self) -> Option<&str> {
+        self.build.as_deref()
+    }
+
+    pub fn set_build(&mut self, build: Option<&str>) {
+        self.build = build.map(String::from);
+    }
+
+    pub fn runner(&self) -> Option<&str> {
+        self.runner.as_deref()
+    }
+
+    pub fn set_runner(&mut self, runner: Option<&str>) {
+        self.runner = runner.map(String::from);
+    }
+
+    pub fn source_epoch(&self) -> Option<u64> {
+        self.source_epoch
+    }
+
+    pub fn set_source_epoch(&mut self, source_epoch: Option<u64>) {
+        self.source_epoch = source_epoch;
+    }
+
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    pub fn add_stage(&mut self, stage: Stage) {
+        self.stages.push(stage);
+    }
+}
+
+/// A stage of a pipeline, `kind` is the name of the stage module that runs it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stage {
+    #[serde(rename = "type")]
+    kind: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    options: Option<serde_json::Value>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    inputs: BTreeMap<String, Input>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    devices: BTreeMap<String, Device>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mounts: Vec<Mount>,
+}
+
+impl Stage {
+    pub fn new(kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            options: None,
+            inputs: BTreeMap::new(),
+            devices: BTreeMap::new(),
+            mounts: vec![],
+        }
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn options(&self) -> Option<&serde_json::Value> {
+        self.options.as_ref()
+    }
+
+    pub fn set_options(&mut self, options: Option<serde_json::Value>) {
+        self.options = options;
+    }
+
+    /// The inputs of the stage, by name.
+    pub fn inputs(&self) -> &BTreeMap<String, Input> {
+        &self.inputs
+    }
+
+    pub fn add_input(&mut self, name: &str, input: Input) {
+        self.inputs.insert(name.to_string(), input);
+    }
+
+    /// The devices the stage uses, by name.
+    pub fn devices(&self) -> &BTreeMap<String, Device> {
+        &self.devices
+    }
+
+    pub fn add_device(&mut self, name: &str, device: Device) {
+        self.devices.insert(name.to_string(), device);
+    }
+
+    /// The mounts of the stage, in the order they are mounted.
+    pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+
+    pub fn add_mount(&mut self, mount: Mount) {
+        self.mounts.push(mount);
+    }
+}
+
+/// Where the content an input refers to comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Origin {
+    /// The tree of another pipeline.
+    #[serde(rename = "org.osbuild.pipeline")]
+    Pipeline,
+
+    /// Items fetched by a source.
+    #[serde(rename = "org.osbuild.source")]
+    Source,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Pipeline => write!(f, "org.osbuild.pipeline"),
+            Self::Source => write!(f, "org.osbuild.source"),
+        }
+    }
+}
+
+/// A reference with the options it is given, see `References::Objects`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reference {
+    pub id: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<serde_json::Value>,
+}
+
+/// The references of an input, to pipelines as `name:<pipeline>` or by id, and to source
+/// items by checksum. Manifests write them in three ways which are all kept as written.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum References {
+    /// `["name:build", ...]`
+    Ids(Vec<String>),
+
+    /// `{"sha256:...": {options}, ...}`
+    Map(BTreeMap<String, serde_json::Value>),
+
+    /// `[{"id": "sha256:...", "options": {...}}, ...]`
+    Objects(Vec<Reference>),
+}
+
+impl Default for References {
+    fn default() -> Self {
+        Self::Ids(vec![])
+    }
+}
+
+impl References {
+    /// The references with their options, in the order they are written.
+    pub fn entries(&self) -> Vec<(&str, Option<&serde_json::Value>)> {
+        match self {
+            Self::Ids(ids) => ids.iter().map(|id| (id.as_str(), None)).collect(),
+            Self::Map(map) => map
+                .iter()
+                .map(|(id, options)| (id.as_str(), Some(options)))
+                .collect(),
+            Self::Objects(references) => references
+                .iter()
+                .map(|reference| (reference.id.as_str(), reference.options.as_ref()))
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Ids(ids) => ids.len(),
+            Self::Map(map) => map.len(),
+            Self::Objects(references) => references.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// An input of a stage, the content its references point to is made available to the stage
+/// by the input module `kind`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Input {
+    #[serde(rename = "type")]
+    kind: String,
+
+    origin: Origin,
+
+    #[serde(default)]
+    references: References,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    options: Option<serde_json::Value>,
+}
+
+impl Input {
+    pub fn new(kind: &str, origin: Origin, references: References) -> Self {
+        Self {
+            kind: kind.to_string(),
+            origin,
+            references,
+            options: None,
+        }
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn origin(&self) -> Origin {
+        self.origin
+    }
+
+    pub fn references(&self) -> &References {
+        &self.references
+    }
+
+    pub fn options(&self) -> Option<&serde_json::Value> {
+        self.options.as_ref()
+    }
+
+    pub fn set_options(&mut self, options: Option<serde_json::Value>) {
+        self.options = options;
+    }
+}
+
+/// A device a stage uses, opened by the device module `kind` before the stage runs. Devices
+/// can be stacked, e.g. a partition on a loopback device, by naming the device they are on as
+/// their `parent`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Device {
+    #[serde(rename = "type")]
+    kind: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    options: Option<serde_json::Value>,
+}
+
+impl Device {
+    pub fn new(kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            parent: None,
+            options: None,
+        }
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn parent(&self) -> Option<&str> {
+        self.parent.as_deref()
+    }
+
+    pub fn set_parent(&mut self, parent: Option<&str>) {
+        self.parent = parent.map(String::from);
+    }
+
+    pub fn options(&self) -> Option<&serde_json::Value> {
+        self.options.as_ref()
+    }
+
+    pub fn set_options(&mut self, options: Option<serde_json::Value>) {
+        self.options = options;
+    }
+}
+
+/// A filesystem a stage uses, mounted by the mount module `kind` from the device named by
+/// `source` at `target` below the stage's mount directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mount {
+    name: String,
+
+    #[serde(rename = "type")]
+    kind: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    options: Option<serde_json::Value>,
+}
+
+impl Mount {
+    pub fn new(name: &str, kind: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            source: None,
+            target: None,
+            options: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn set_source(&mut self, source: Option<&str>) {
+        self.source = source.map(String::from);
+    }
+
+    /// Where the mount goes, relative to the stage's mount directory; `/` when not given.
+    pub fn target(&self) -> &str {
+        self.target.as_deref().unwrap_or("/")
+    }
+
+    pub fn set_target(&mut self, target: Option<&str>) {
+        self.target = target.map(String::from);
+    }
+
+    pub fn options(&self) -> Option<&serde_json::Value> {
+        self.options.as_ref()
+    }
+
+    pub fn set_options(&mut self, options: Option<serde_json::Value>) {
+        self.options = options;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manifest_round_trip() {
+        let description = serde_json::json!({
+            "version": "2",
+            "pipelines": [
+                {"name": "build", "runner": "org.osbuild.fedora38", "stages": [{"type": "org.osbuild.rpm", "options": {"gpgkeys": []}}]},
+                {"name": "os", "build": "name:build", "source-epoch": 1700000000, "stages": [
+                    {
+                        "type": "org.osbuild.copy",
+                        "inputs": {
+                            "tree": {"type": "org.osbuild.tree", "origin": "org.osbuild.pipeline", "references": ["name:build"]},
+                            "files": {"type": "org.osbuild.files", "origin": "org.osbuild.source", "references": {"sha256:aa": {}}},
+                            "containers": {"type": "org.osbuild.containers", "origin": "org.osbuild.source", "references": [{"id": "sha256:bb", "options": {"name": "a"}}]}
+                        },
+                        "devices": {
+                            "disk": {"type": "org.osbuild.loopback", "options": {"filename": "disk.img"}},
+                            "root": {"type": "org.osbuild.luks2", "parent": "disk"}
+                        },
+                        "mounts": [{"name": "root", "type": "org.osbuild.ext4", "source": "root", "target": "/"}]
+                    }
+                ]}
+            ],
+            "sources": {"org.osbuild.curl": {"items": {}}}
+        });
+
+        let manifest: Manifest = serde_json::from_value(description.clone()).unwrap();
+
+        assert_eq!(manifest.version(), Version::V2);
+        assert_eq!(manifest.pipelines().len(), 2);
+        assert_eq!(manifest.pipeline("os").unwrap().build(), Some("name:build"));
+        assert_eq!(
+            manifest.pipeline("os").unwrap().source_epoch(),
+            Some(1700000000)
+        );
+        assert_eq!(
+            manifest.pipeline("build").unwrap().stages()[0].kind(),
+            "org.osbuild.rpm"
+        );
+        assert_eq!(serde_json::to_value(&manifest).unwrap(), description);
+
+        let stage = &manifest.pipeline("os").unwrap().stages()[0];
+
+        assert_eq!(stage.devices()["root"].parent(), Some("disk"));
+        assert_eq!(stage.mounts()[0].source(), Some("root"));
+        assert_eq!(stage.inputs()["tree"].origin(), Origin::Pipeline);
+        assert_eq!(
+            stage.inputs()["tree"].references().entries(),
+            vec![("name:build", None)]
+        );
+        assert_eq!(
+            stage.inputs()["containers"].references().entries(),
+            vec![("sha256:bb", Some(&serde_json::json!({"name": "a"})))]
+        );
+        assert_eq!(stage.inputs()["files"].references().len(), 1);
+
+        assert!(serde_json::from_value::<Input>(
+            serde_json::json!({"type": "org.osbuild.tree", "origin": "org.osbuild.nope"})
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn manifest_constructed() {
+        let mut stage = Stage::new("org.osbuild.locale");
+        stage.set_options(Some(serde_json::json!({"language": "en_US.UTF-8"})));
+
+        let mut pipeline = Pipeline::new("os");
+        pipeline.add_stage(stage);
+
+        let mut manifest = Manifest::new();
+        manifest.add_pipeline(pipeline);
+
+        assert_eq!(
+            serde_json::to_value(&manifest).unwrap(),
+            serde_json::json!({
+                "version": "2",
+                "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.locale", "options": {"language": "en_US.UTF-8"}}]}]
+            })
+        );
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let description = serde_json::json!({
+            "version": "2",
+            "pipelines": [],
+            "metadata": {
+                "producer": {"name": "osbuild-composer", "version": "94"},
+                "compose": {"id": "5b0a3cbb", "distro": "fedora-38"}
+            }
+        });
+
+        let manifest: Manifest = serde_json::from_value(description.clone()).unwrap();
+        let metadata = manifest.metadata().unwrap();
+
+        assert_eq!(metadata.producer().unwrap().name(), "osbuild-composer");
+        assert_eq!(metadata.producer().unwrap().version(), Some("94"));
+        assert_eq!(metadata.get("compose").unwrap()["distro"], "fedora-38");
+        assert_eq!(serde_json::to_value(&manifest).unwrap(), description);
+
+        let mut metadata = Metadata::new();
+        metadata.set_producer(Some(Producer::new("image-builder", None)));
+
+        let mut manifest = Manifest::new();
+        manifest.set_metadata(Some(metadata));
+
+        assert_eq!(
+            serde_json::to_value(&manifest).unwrap()["metadata"],
+            serde_json::json!({"producer": {"name": "image-builder"}})
+        );
+    }
+
+    #[test]
+    fn version_serialized_as_string() {
+        assert_eq!(serde_json::to_string(&Version::V2).unwrap(), r#""2""#);
+        assert_eq!(
+            serde_json::from_str::<Version>(r#""1""#).unwrap(),
+            Version::V1
+        );
+        assert!(serde_json::from_str::<Version>(r#""3""#).is_err());
+        assert!(serde_json::from_str::<Manifest>(r#"{"version": "3", "pipelines": []}"#).is_err());
+    }
+}
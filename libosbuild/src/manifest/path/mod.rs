@@ -7,11 +7,13 @@ use std::ops;
 #[cfg(test)]
 pub mod test;
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Part {
     Name(String),
     Index(usize),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Path(pub Vec<Part>);
 
 impl Path {
@@ -33,15 +35,15 @@ impl fmt::Display for Path {
         if self.is_empty() {
             write!(f, ".")
         } else {
-            self.iter().fold(Ok(()), |result, part| match part {
+            self.iter().try_fold((), |_, part| match part {
                 Part::Name(path) => {
                     if path.contains(' ') {
-                        result.and_then(|_| write!(f, ".'{}'", path))
+                        write!(f, ".'{}'", path)
                     } else {
-                        result.and_then(|_| write!(f, ".{}", path))
+                        write!(f, ".{}", path)
                     }
                 }
-                Part::Index(path) => result.and_then(|_| write!(f, "[{}]", path)),
+                Part::Index(path) => write!(f, "[{}]", path),
             })
         }
     }
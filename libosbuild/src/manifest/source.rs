@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::manifest::path::{Part, Path};
+use crate::manifest::ManifestError;
+
+/// Where a value is written in the text of a manifest, as byte offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A position in the text of a manifest, lines and columns count from 1. Columns count
+/// characters, not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The text of a manifest along with where each of its values is written, so that errors
+/// about a path in the manifest can point at the text it came from.
+#[derive(Debug, Clone)]
+pub struct Source {
+    name: String,
+    text: String,
+    spans: HashMap<Path, Span>,
+}
+
+impl Source {
+    /// Parse the manifest `text`, `name` is what it is called in messages such as its file
+    /// name.
+    pub fn parse(name: &str, text: &str) -> Result<Self, ManifestError> {
+        // let serde report what is wrong with invalid JSON, the scanner below only has to
+        // deal with valid JSON
+        serde_json::from_str::<serde::de::IgnoredAny>(text)?;
+
+        let mut scanner = Scanner {
+            text: text.as_bytes(),
+            offset: 0,
+            path: vec![],
+            spans: HashMap::new(),
+        };
+
+        scanner.value()?;
+
+        Ok(Self {
+            name: name.to_string(),
+            text: text.to_string(),
+            spans: scanner.spans,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Where the value at `path` is written.
+    pub fn span(&self, path: &Path) -> Option<Span> {
+        self.spans.get(path).copied()
+    }
+
+    /// Where the value at `path` is written, or the closest value containing it when it
+    /// doesn't exist, such as a missing key. Returns the path that was found as well.
+    pub fn closest_span(&self, path: &Path) -> (Path, Span) {
+        (0..=path.len())
+            .rev()
+            .find_map(|length| {
+                let prefix = Path::new(path[..length].to_vec());
+                self.span(&prefix).map(|span| (prefix, span))
+            })
+            .unwrap_or((
+                Path::new(vec![]),
+                Span {
+                    start: 0,
+                    end: self.text.len(),
+                },
+            ))
+    }
+
+    /// The path of the innermost value written around the byte `offset`, such as the value
+    /// a cursor is in.
+    pub fn path_at(&self, offset: usize) -> Option<Path> {
+        self.spans
+            .iter()
+            .filter(|(_, span)| span.start <= offset && offset <= span.end)
+            .min_by_key(|(path, span)| (span.end - span.start, std::cmp::Reverse(path.len())))
+            .map(|(path, _)| path.clone())
+    }
+
+    /// The line and column of the byte `offset`.
+    pub fn location(&self, offset: usize) -> Location {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+
+        Location {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /// The text of the line `line`, without its line ending.
+    pub fn line(&self, line: usize) -> Option<&str> {
+        self.text
+            .split('\n')
+            .nth(line.checked_sub(1)?)
+            .map(|text| text.strip_suffix('\r').unwrap_or(text))
+    }
+}
+
+/// Records the span of every value in a JSON text that is already known to be valid.
+struct Scanner<'a> {
+    text: &'a [u8],
+    offset: usize,
+    path: Vec<Part>,
+    spans: HashMap<Path, Span>,
+}
+
+impl<'a> Scanner<'a> {
+    fn whitespace(&mut self) {
+        while self
+            .text
+            .get(self.offset)
+            .is_some_and(|byte| byte.is_ascii_whitespace())
+        {
+            self.offset += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.whitespace();
+        self.text.get(self.offset).copied()
+    }
+
+    fn value(&mut self) -> Result<(), ManifestError> {
+        self.whitespace();
+
+        let start = self.offset;
+
+        match self.peek() {
+            Some(b'{') => self.object()?,
+            Some(b'[') => self.array()?,
+            Some(b'"') => {
+                self.string();
+            }
+            _ => {
+                while self
+                    .text
+                    .get(self.offset)
+                    .is_some_and(|byte| !b",]} \t\r\n".contains(byte))
+                {
+                    self.offset += 1;
+                }
+            }
+        }
+
+        self.spans.insert(
+            Path::new(self.path.clone()),
+            Span {
+                start,
+                end: self.offset,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Skip a string, returning its raw text between the quotes.
+    fn string(&mut self) -> &'a [u8] {
+        let start = self.offset + 1;
+
+        self.offset = start;
+
+        while let Some(byte) = self.text.get(self.offset) {
+            match byte {
+                b'\\' => self.offset += 2,
+                b'"' => break,
+                _ => self.offset += 1,
+            }
+        }
+
+        self.offset += 1;
+
+        &self.text[start..self.offset - 1]
+    }
+
+    fn object(&mut self) -> Result<(), ManifestError> {
+        self.offset += 1;
+
+        while self.peek() == Some(b'"') {
+            let raw = self.string();
+
+            // keys with escapes are written differently than they are named
+            let key = serde_json::from_slice::<String>(&[b"\"", raw, b"\""].concat())?;
+
+            self.peek();
+            self.offset += 1; // ':'
+
+            self.path.push(Part::Name(key));
+            self.value()?;
+            self.path.pop();
+
+            if self.peek() == Some(b',') {
+                self.offset += 1;
+            }
+        }
+
+        self.peek();
+        self.offset += 1; // '}'
+
+        Ok(())
+    }
+
+    fn array(&mut self) -> Result<(), ManifestError> {
+        self.offset += 1;
+
+        let mut index = 0;
+
+        while !matches!(self.peek(), Some(b']') | None) {
+            self.path.push(Part::Index(index));
+            self.value()?;
+            self.path.pop();
+
+            index += 1;
+
+            if self.peek() == Some(b',') {
+                self.offset += 1;
+            }
+        }
+
+        self.offset += 1; // ']'
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn path(parts: &[Part]) -> Path {
+        Path::new(parts.to_vec())
+    }
+
+    #[test]
+    fn spans_of_values() {
+        let text = "{\n  \"version\": \"2\",\n  \"pipelines\": [\n    {\"name\": \"os\", \"a\\u0020b\": [1, true]}\n  ]\n}\n";
+        let source = Source::parse("manifest.json", text).unwrap();
+
+        let span = source
+            .span(&path(&[Part::Name("version".to_string())]))
+            .unwrap();
+
+        assert_eq!(&text[span.start..span.end], "\"2\"");
+        assert_eq!(
+            source.location(span.start),
+            Location {
+                line: 2,
+                column: 14
+            }
+        );
+
+        let name = path(&[
+            Part::Name("pipelines".to_string()),
+            Part::Index(0),
+            Part::Name("name".to_string()),
+        ]);
+        let span = source.span(&name).unwrap();
+
+        assert_eq!(&text[span.start..span.end], "\"os\"");
+        assert_eq!(source.location(span.start).to_string(), "4:14");
+        assert_eq!(
+            source.line(4),
+            Some("    {\"name\": \"os\", \"a\\u0020b\": [1, true]}")
+        );
+
+        let span = source
+            .span(&path(&[
+                Part::Name("pipelines".to_string()),
+                Part::Index(0),
+                Part::Name("a b".to_string()),
+                Part::Index(1),
+            ]))
+            .unwrap();
+
+        assert_eq!(&text[span.start..span.end], "true");
+
+        let span = source.span(&path(&[])).unwrap();
+
+        assert_eq!(&text[span.start..span.end], text.trim_end());
+
+        // a missing key points at the object that lacks it
+        let (found, span) = source.closest_span(&path(&[
+            Part::Name("pipelines".to_string()),
+            Part::Index(0),
+            Part::Name("stages".to_string()),
+        ]));
+
+        assert_eq!(found.to_string(), ".pipelines[0]");
+        assert!(text[span.start..span.end].starts_with("{\"name\""));
+
+        let offset = text.find("\"os\"").unwrap() + 1;
+
+        assert_eq!(source.path_at(offset), Some(name));
+        assert_eq!(source.path_at(0), Some(path(&[])));
+
+        assert!(matches!(
+            Source::parse("manifest.json", "{\"version\": }"),
+            Err(ManifestError::ParseError(_))
+        ));
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde_json::value::RawValue;
+
+use crate::manifest::ManifestError;
+
+/// A manifest read with `stream_sources`, everything but the source items is kept as
+/// unparsed JSON.
+#[derive(Debug, Default)]
+pub struct StreamedManifest {
+    /// The top level fields other than `sources`, such as `version` and `pipelines`.
+    pub fields: BTreeMap<String, Box<RawValue>>,
+
+    /// The number of source items that were passed to the callback.
+    pub items: usize,
+}
+
+impl StreamedManifest {
+    /// Parse one of the top level fields.
+    pub fn field<T: serde::de::DeserializeOwned>(
+        &self,
+        name: &str,
+    ) -> Option<Result<T, ManifestError>> {
+        self.fields
+            .get(name)
+            .map(|raw| serde_json::from_str(raw.get()).map_err(ManifestError::from))
+    }
+}
+
+type Callback<'f> = dyn FnMut(&str, &str, &RawValue) + 'f;
+
+struct ManifestSeed<'a, 'f> {
+    callback: &'a mut Callback<'f>,
+    manifest: &'a mut StreamedManifest,
+}
+
+impl<'de> DeserializeSeed<'de> for ManifestSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ManifestSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a manifest object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "sources" {
+                map.next_value_seed(SourcesSeed {
+                    callback: &mut *self.callback,
+                    items: &mut self.manifest.items,
+                })?;
+            } else {
+                let value = map.next_value::<Box<RawValue>>()?;
+                self.manifest.fields.insert(key, value);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The `sources` object, keyed by source module.
+struct SourcesSeed<'a, 'f> {
+    callback: &'a mut Callback<'f>,
+    items: &'a mut usize,
+}
+
+impl<'de> DeserializeSeed<'de> for SourcesSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SourcesSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an object of sources")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(kind) = map.next_key::<String>()? {
+            map.next_value_seed(SourceSeed {
+                kind: &kind,
+                callback: &mut *self.callback,
+                items: &mut *self.items,
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A single source, its items are under `items` (or `urls` in version 1 manifests).
+struct SourceSeed<'a, 'f> {
+    kind: &'a str,
+    callback: &'a mut Callback<'f>,
+    items: &'a mut usize,
+}
+
+impl<'de> DeserializeSeed<'de> for SourceSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SourceSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a source object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "items" || key == "urls" {
+                map.next_value_seed(ItemsSeed {
+                    kind: self.kind,
+                    callback: &mut *self.callback,
+                    items: &mut *self.items,
+                })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct ItemsSeed<'a, 'f> {
+    kind: &'a str,
+    callback: &'a mut Callback<'f>,
+    items: &'a mut usize,
+}
+
+impl<'de> DeserializeSeed<'de> for ItemsSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ItemsSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an object of source items")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(id) = map.next_key::<String>()? {
+            let value = map.next_value::<Box<RawValue>>()?;
+
+            (self.callback)(self.kind, &id, &value);
+            *self.items += 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read a manifest without building a document of it in memory, calling `callback` with
+/// the source module, id, and unparsed value of every source item as it is read. Manifests
+/// with many thousands of sources can be validated this way with little memory; only a
+/// single item is held at a time. Options of sources are skipped. The reader is read in
+/// small pieces, wrap files in a `BufReader`.
+pub fn stream_sources<R, F>(reader: R, mut callback: F) -> Result<StreamedManifest, ManifestError>
+where
+    R: Read,
+    F: FnMut(&str, &str, &RawValue),
+{
+    let mut manifest = StreamedManifest::default();
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+
+    ManifestSeed {
+        callback: &mut callback,
+        manifest: &mut manifest,
+    }
+    .deserialize(&mut deserializer)?;
+
+    deserializer.end()?;
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stream_v2_sources() {
+        let data = br#"{
+            "version": "2",
+            "sources": {
+                "org.osbuild.curl": {
+                    "items": {
+                        "sha256:aa": "https://example.com/a.rpm",
+                        "sha256:bb": {"url": "https://example.com/b.rpm", "secrets": {"name": "rhsm"}}
+                    }
+                },
+                "org.osbuild.skopeo": {
+                    "options": {"ignored": true},
+                    "items": {"sha256:cc": {"image": {"name": "registry.example.com/a"}}}
+                }
+            },
+            "pipelines": [{"name": "os"}]
+        }"#;
+
+        let mut items = vec![];
+        let manifest = stream_sources(&data[..], |kind, id, value| {
+            items.push((kind.to_string(), id.to_string(), value.get().to_string()));
+        })
+        .unwrap();
+
+        assert_eq!(manifest.items, 3);
+        assert_eq!(items[0].0, "org.osbuild.curl");
+        assert_eq!(items[0].2, r#""https://example.com/a.rpm""#);
+        assert_eq!(items[2].1, "sha256:cc");
+
+        assert_eq!(manifest.field::<String>("version").unwrap().unwrap(), "2");
+        assert!(manifest.field::<String>("sources").is_none());
+        assert_eq!(
+            manifest
+                .field::<serde_json::Value>("pipelines")
+                .unwrap()
+                .unwrap()[0]["name"],
+            "os"
+        );
+    }
+
+    #[test]
+    fn stream_v1_sources() {
+        let data =
+            br#"{"pipeline": {}, "sources": {"org.osbuild.files": {"urls": {"sha256:aa": "https://example.com/a"}}}}"#;
+
+        let mut count = 0;
+        let manifest = stream_sources(&data[..], |kind, _, _| {
+            assert_eq!(kind, "org.osbuild.files");
+            count += 1;
+        })
+        .unwrap();
+
+        assert_eq!(count, 1);
+        assert!(manifest.fields.contains_key("pipeline"));
+    }
+
+    #[test]
+    fn stream_invalid() {
+        assert!(matches!(
+            stream_sources(&b"[]"[..], |_, _, _| {}),
+            Err(ManifestError::ParseError(_))
+        ));
+        assert!(matches!(
+            stream_sources(&br#"{"sources": {"a": {"items": []}}}"#[..], |_, _, _| {}),
+            Err(ManifestError::ParseError(_))
+        ));
+        assert!(matches!(
+            stream_sources(&b"{} trailing"[..], |_, _, _| {}),
+            Err(ManifestError::ParseError(_))
+        ));
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::manifest::path::{Part, Path};
+use crate::manifest::Version;
+
+/// The key of a manifest that declares its variables, an object of declarations by name.
+pub const VARIABLES: &str = "variables";
+
+#[derive(Debug)]
+pub enum VariableError {
+    /// `variables` isn't an object of declarations, a declaration isn't a type and a value,
+    /// or a reference isn't closed.
+    Invalid(String),
+
+    /// The value of a declaration isn't of its type, contains the name of the variable and
+    /// the type.
+    TypeMismatch(String, VariableType),
+
+    /// An option refers to a variable that isn't declared, contains its name and the option.
+    Undefined(String, Path),
+
+    /// An array or object is referred to inside a string, contains the name of the variable
+    /// and the option.
+    NotEmbeddable(String, Path),
+}
+
+/// The types variables are declared with, named as in JSON schemas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VariableType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    Array,
+    Object,
+}
+
+impl VariableType {
+    pub fn matches(&self, value: &serde_json::Value) -> bool {
+        match self {
+            Self::String => value.is_string(),
+            Self::Integer => value.is_i64() || value.is_u64(),
+            Self::Number => value.is_number(),
+            Self::Boolean => value.is_boolean(),
+            Self::Array => value.is_array(),
+            Self::Object => value.is_object(),
+        }
+    }
+
+    /// Whether values of the type can be referred to inside a string.
+    pub fn is_embeddable(&self) -> bool {
+        !matches!(self, Self::Array | Self::Object)
+    }
+}
+
+impl fmt::Display for VariableType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::String => "string",
+            Self::Integer => "integer",
+            Self::Number => "number",
+            Self::Boolean => "boolean",
+            Self::Array => "array",
+            Self::Object => "object",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// A variable as a manifest declares it, `{"type": "integer", "value": 4294967296}`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Variable {
+    #[serde(rename = "type")]
+    pub kind: VariableType,
+    pub value: serde_json::Value,
+}
+
+/// Expand the variables `manifest` declares in the options of its stages and remove the
+/// declarations, so constants such as the size of an image or a hostname are written once.
+/// A string that is only a reference, `"${size}"`, is replaced by the value of the variable
+/// and keeps its type; references inside a longer string, `"${hostname}.local"`, are
+/// replaced by the text of strings, numbers, and booleans. `$$` is a literal `$`. Values are
+/// checked against the type they are declared with, and references to variables that aren't
+/// declared are errors. Only version 2 manifests have variables.
+pub fn expand(manifest: &mut serde_json::Value) -> Result<(), VariableError> {
+    let Some(declarations) = manifest
+        .as_object_mut()
+        .and_then(|object| object.remove(VARIABLES))
+    else {
+        return Ok(());
+    };
+
+    if !matches!(Version::detect(manifest), Ok(Version::V2)) {
+        return Err(VariableError::Invalid(
+            "only version 2 manifests have variables".to_string(),
+        ));
+    }
+
+    let declarations: BTreeMap<String, Variable> = serde_json::from_value(declarations)
+        .map_err(|err| VariableError::Invalid(format!("{}: {}", VARIABLES, err)))?;
+
+    for (name, variable) in &declarations {
+        if !is_name(name) {
+            return Err(VariableError::Invalid(format!(
+                "'{}' is not a variable name",
+                name
+            )));
+        }
+
+        if !variable.kind.matches(&variable.value) {
+            return Err(VariableError::TypeMismatch(name.clone(), variable.kind));
+        }
+    }
+
+    let pipelines = manifest
+        .get_mut("pipelines")
+        .and_then(|pipelines| pipelines.as_array_mut())
+        .into_iter()
+        .flatten()
+        .enumerate();
+
+    for (index, pipeline) in pipelines {
+        let stages = pipeline
+            .get_mut("stages")
+            .and_then(|stages| stages.as_array_mut())
+            .into_iter()
+            .flatten()
+            .enumerate();
+
+        for (stage_index, stage) in stages {
+            let Some(options) = stage.get_mut("options") else {
+                continue;
+            };
+
+            let mut at = vec![
+                Part::Name("pipelines".to_string()),
+                Part::Index(index),
+                Part::Name("stages".to_string()),
+                Part::Index(stage_index),
+                Part::Name("options".to_string()),
+            ];
+
+            expand_value(options, &declarations, &mut at)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Names are those of identifiers, `image_size` and `hostname`.
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn expand_value(
+    value: &mut serde_json::Value,
+    declarations: &BTreeMap<String, Variable>,
+    at: &mut Vec<Part>,
+) -> Result<(), VariableError> {
+    match value {
+        serde_json::Value::String(text) => {
+            *value = expand_string(text, declarations, at)?;
+        }
+        serde_json::Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                at.push(Part::Index(index));
+                expand_value(item, declarations, at)?;
+                at.pop();
+            }
+        }
+        serde_json::Value::Object(object) => {
+            for (key, item) in object.iter_mut() {
+                at.push(Part::Name(key.clone()));
+                expand_value(item, declarations, at)?;
+                at.pop();
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn expand_string(
+    text: &str,
+    declarations: &BTreeMap<String, Variable>,
+    at: &[Part],
+) -> Result<serde_json::Value, VariableError> {
+    let lookup = |name: &str| {
+        declarations
+            .get(name)
+            .ok_or_else(|| VariableError::Undefined(name.to_string(), Path::new(at.to_vec())))
+    };
+
+    if let Some(name) = text
+        .strip_prefix("${")
+        .and_then(|rest| rest.strip_suffix('}'))
+        .filter(|name| is_name(name))
+    {
+        return Ok(lookup(name)?.value.clone());
+    }
+
+    let mut expanded = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+
+        let Some(reference) = rest.strip_prefix('{') else {
+            expanded.push('$');
+            continue;
+        };
+
+        let Some(end) = reference.find('}') else {
+            return Err(VariableError::Invalid(format!(
+                "unclosed reference in {}",
+                Path::new(at.to_vec())
+            )));
+        };
+
+        let name = &reference[..end];
+        let variable = lookup(name)?;
+
+        match &variable.value {
+            serde_json::Value::String(value) => expanded.push_str(value),
+            value if variable.kind.is_embeddable() => expanded.push_str(&value.to_string()),
+            _ => {
+                return Err(VariableError::NotEmbeddable(
+                    name.to_string(),
+                    Path::new(at.to_vec()),
+                ))
+            }
+        }
+
+        rest = &reference[end + 1..];
+    }
+
+    expanded.push_str(rest);
+
+    Ok(serde_json::Value::String(expanded))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn manifest(options: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "version": "2",
+            "variables": {
+                "size": {"type": "integer", "value": 4294967296u64},
+                "hostname": {"type": "string", "value": "builder"},
+                "packages": {"type": "array", "value": ["bash", "kernel"]}
+            },
+            "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.noop", "options": options}]}]
+        })
+    }
+
+    fn expanded(options: serde_json::Value) -> Result<serde_json::Value, VariableError> {
+        let mut manifest = manifest(options);
+
+        expand(&mut manifest)?;
+        assert!(manifest.get(VARIABLES).is_none());
+
+        Ok(manifest["pipelines"][0]["stages"][0]["options"].clone())
+    }
+
+    #[test]
+    fn variables_expanded() {
+        assert_eq!(
+            expanded(serde_json::json!({
+                "size": "${size}",
+                "hostname": "${hostname}.local",
+                "packages": "${packages}",
+                "label": "${hostname}-${size}",
+                "nested": [{"path": "/srv/${hostname}"}],
+                "price": "$$5 and $0"
+            }))
+            .unwrap(),
+            serde_json::json!({
+                "size": 4294967296u64,
+                "hostname": "builder.local",
+                "packages": ["bash", "kernel"],
+                "label": "builder-4294967296",
+                "nested": [{"path": "/srv/builder"}],
+                "price": "$5 and $0"
+            })
+        );
+
+        // manifests without variables are left as they are
+        let mut plain = serde_json::json!({"version": "2", "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.noop", "options": {"a": "${a}"}}]}]});
+        let original = plain.clone();
+
+        expand(&mut plain).unwrap();
+        assert_eq!(plain, original);
+    }
+
+    #[test]
+    fn variables_checked() {
+        assert!(matches!(
+            expanded(serde_json::json!({"nested": {"size": ["${nope}"]}})),
+            Err(VariableError::Undefined(name, at))
+                if name == "nope" && at.to_string() == ".pipelines[0].stages[0].options.nested.size[0]"
+        ));
+        assert!(matches!(
+            expanded(serde_json::json!({"packages": "install ${packages}"})),
+            Err(VariableError::NotEmbeddable(name, _)) if name == "packages"
+        ));
+        assert!(matches!(
+            expanded(serde_json::json!({"size": "${size"})),
+            Err(VariableError::Invalid(_))
+        ));
+
+        for (declarations, invalid) in [
+            (
+                serde_json::json!({"size": {"type": "integer", "value": "4G"}}),
+                false,
+            ),
+            (
+                serde_json::json!({"size": {"type": "string", "value": 4}}),
+                false,
+            ),
+            (serde_json::json!({"size": 4}), true),
+            (
+                serde_json::json!({"size": {"type": "bytes", "value": 4}}),
+                true,
+            ),
+            (
+                serde_json::json!({"not a name": {"type": "integer", "value": 4}}),
+                true,
+            ),
+        ] {
+            let mut manifest = serde_json::json!({"version": "2", "variables": declarations});
+
+            match expand(&mut manifest) {
+                Err(VariableError::TypeMismatch(name, _)) => assert!(!invalid && name == "size"),
+                Err(VariableError::Invalid(_)) => assert!(invalid),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        let mut v1 = serde_json::json!({"variables": {}, "pipeline": {}});
+
+        assert!(matches!(expand(&mut v1), Err(VariableError::Invalid(_))));
+    }
+}
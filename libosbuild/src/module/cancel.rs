@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default)]
+struct State {
+    cancelled: AtomicBool,
+    reason: Mutex<Option<String>>,
+}
+
+/// Whether work was asked to stop, and why. Clones share their state: one is handed to the
+/// code doing the work, which checks it where it can stop cleanly, and another to whoever
+/// may cancel it, such as a handler of Ctrl-C or of the cancel signal of the host.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    state: Arc<State>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the work to stop. Only the reason of the first cancellation is kept.
+    pub fn cancel(&self, reason: &str) {
+        let mut kept = self
+            .state
+            .reason
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+
+        if kept.is_none() {
+            *kept = Some(reason.to_string());
+        }
+
+        self.state.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Why the work was cancelled, if it was.
+    pub fn reason(&self) -> Option<String> {
+        self.state
+            .reason
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tokens_shared() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+
+        assert!(!clone.is_cancelled());
+        assert_eq!(clone.reason(), None);
+
+        std::thread::spawn(move || {
+            token.cancel("interrupted");
+            token.cancel("again");
+        })
+        .join()
+        .unwrap();
+
+        assert!(clone.is_cancelled());
+        assert_eq!(clone.reason().as_deref(), Some("interrupted"));
+        assert!(!CancellationToken::new().is_cancelled());
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::module::ModuleError;
+
+/// Key in the schema of a module listing the capabilities it needs.
+pub const CAPABILITIES_KEY: &str = "capabilities";
+
+/// Something a module needs beyond running in an isolated build root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    /// Access to the network.
+    Network,
+
+    /// Access to the device nodes of the host, such as loop devices.
+    Devices,
+
+    /// All capabilities of root, such as setting SELinux labels.
+    Privileged,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 3] = [
+        Capability::Network,
+        Capability::Devices,
+        Capability::Privileged,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Network => "network",
+            Self::Devices => "devices",
+            Self::Privileged => "privileged",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = ModuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|capability| capability.name() == s)
+            .ok_or_else(|| ModuleError::UnknownCapability(s.to_string()))
+    }
+}
+
+/// The capabilities a module declares in the `capabilities` list of its schema, a module that
+/// declares none needs none.
+pub fn from_schema(schema: &serde_json::Value) -> Result<Vec<Capability>, ModuleError> {
+    let names = match schema.get(CAPABILITIES_KEY) {
+        None => return Ok(vec![]),
+        Some(serde_json::Value::Array(names)) => names,
+        Some(other) => return Err(ModuleError::UnknownCapability(other.to_string())),
+    };
+
+    let mut capabilities = names
+        .iter()
+        .map(|name| match name.as_str() {
+            Some(name) => name.parse(),
+            None => Err(ModuleError::UnknownCapability(name.to_string())),
+        })
+        .collect::<Result<Vec<Capability>, ModuleError>>()?;
+
+    capabilities.sort();
+    capabilities.dedup();
+
+    Ok(capabilities)
+}
+
+/// The capabilities modules may be granted. The default policy allows everything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    allowed: BTreeSet<Capability>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+impl Policy {
+    pub fn allow_all() -> Self {
+        Self {
+            allowed: Capability::ALL.into_iter().collect(),
+        }
+    }
+
+    pub fn allow_none() -> Self {
+        Self {
+            allowed: BTreeSet::new(),
+        }
+    }
+
+    pub fn allow(&mut self, capability: Capability) {
+        self.allowed.insert(capability);
+    }
+
+    pub fn forbid(&mut self, capability: Capability) {
+        self.allowed.remove(&capability);
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.allowed.contains(&capability)
+    }
+
+    pub fn allows_all(&self) -> bool {
+        Capability::ALL
+            .iter()
+            .all(|capability| self.allows(*capability))
+    }
+
+    /// The capabilities in `needed` this policy doesn't allow.
+    pub fn forbidden(&self, needed: &[Capability]) -> Vec<Capability> {
+        needed
+            .iter()
+            .copied()
+            .filter(|capability| !self.allows(*capability))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capabilities_from_schema() {
+        assert_eq!(
+            from_schema(&serde_json::json!({"capabilities": ["privileged", "network", "network"]}))
+                .unwrap(),
+            vec![Capability::Network, Capability::Privileged]
+        );
+        assert!(from_schema(&serde_json::json!({"type": "object"}))
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            from_schema(&serde_json::json!({"capabilities": ["teleport"]})),
+            Err(ModuleError::UnknownCapability(name)) if name == "teleport"
+        ));
+        assert!(from_schema(&serde_json::json!({"capabilities": "network"})).is_err());
+    }
+
+    #[test]
+    fn policies() {
+        let needed = [Capability::Network, Capability::Devices];
+
+        assert!(Policy::default().forbidden(&needed).is_empty());
+        assert_eq!(Policy::allow_none().forbidden(&needed), needed);
+
+        let mut policy = Policy::allow_all();
+        policy.forbid(Capability::Network);
+
+        assert_eq!(policy.forbidden(&needed), vec![Capability::Network]);
+        assert!(!policy.allows_all());
+
+        policy.allow(Capability::Network);
+
+        assert!(policy.allows(Capability::Network));
+        assert!(policy.allows_all());
+    }
+}
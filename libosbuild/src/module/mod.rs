@@ -1,12 +1,49 @@
-use std::path::Path;
+/// Capabilities modules declare they need, and the policies that grant them.
+pub mod capability;
+
+/// Asking modules, and the work they do, to stop.
+pub mod cancel;
+
+/// Running modules with limits on how much output of theirs is kept.
+pub mod output;
+
+/// Snapshots of registries, to store them or send them to other hosts.
+pub mod snapshot;
+
+/// What stage binaries need to run as osbuild runs them; reading their arguments and
+/// options, resolving `tree://`, `input://`, and `mount://` locations, and logging.
+pub mod stage;
+
+/// Utilities shared between module implementations, these encapsulate logic that many stages
+/// need so it doesn't have to be duplicated.
+pub mod util;
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::module::capability::Capability;
+use crate::module::output::{OutputLimits, DEFAULT_SCHEMA_TIMEOUT};
 
 #[derive(Debug)]
 pub enum RegistryError {
     NoSuchPath,
     NotADirectory,
     ModuleError(ModuleError),
+
+    /// A snapshot isn't a valid snapshot of a registry.
+    InvalidSnapshot(serde_json::Error),
+
+    /// A snapshot has a version of the format that isn't supported, which is contained.
+    UnsupportedSnapshot(u32),
+
     IOError(std::io::Error),
 }
 
@@ -22,29 +59,175 @@ impl From<ModuleError> for RegistryError {
     }
 }
 
+/// The modules in a directory, in filename order. Hidden files and non-files are skipped.
+fn module_paths(path: &Path) -> Result<Vec<PathBuf>, RegistryError> {
+    if !path.exists() {
+        return Err(RegistryError::NoSuchPath);
+    }
+
+    if !path.is_dir() {
+        return Err(RegistryError::NotADirectory);
+    }
+
+    let mut paths = vec![];
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        if entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+
+    paths.sort();
+
+    Ok(paths)
+}
+
 /// A registry of all available modules to osbuild.
-pub struct Registry<'a> {
-    modules: Vec<Module<'a>>,
+pub struct Registry {
+    modules: Vec<Module>,
+
+    /// When the schemas of the modules were read, for registries made from a snapshot.
+    scanned: Option<u64>,
 }
 
-impl Registry<'_> {
+impl Registry {
     /// Create a new registry
-    pub fn new<'a>(modules: Vec<Module<'a>>) -> Registry<'a> {
-        Registry { modules }
+    pub fn new(modules: Vec<Module>) -> Registry {
+        Registry {
+            modules,
+            scanned: None,
+        }
     }
 
     /// Create a new empty registry
     pub fn new_empty() -> Self {
-        Self { modules: vec![] }
+        Self {
+            modules: vec![],
+            scanned: None,
+        }
     }
 
-    /// Add the 'well-known' locations where `osbuild` modules might be located.
+    /// Add the 'well-known' locations where `osbuild` modules might be located. Locations that
+    /// don't exist are skipped.
     /// XXX: decide if we actually want this or if we always want to be explicit and only load data
     /// from explicitly provided paths in the binaries.
     pub fn add_well_known(&mut self) -> Result<(), RegistryError> {
+        for (kind, path) in [
+            (Kind::Assembler, WELL_KNOWN_MODULE_PATH_ASSEMBLER),
+            (Kind::Device, WELL_KNOWN_MODULE_PATH_DEVICE),
+            (Kind::Input, WELL_KNOWN_MODULE_PATH_INPUT),
+            (Kind::Mount, WELL_KNOWN_MODULE_PATH_MOUNT),
+            (Kind::Runner, WELL_KNOWN_MODULE_PATH_RUNNER),
+            (Kind::Source, WELL_KNOWN_MODULE_PATH_SOURCE),
+            (Kind::Stage, WELL_KNOWN_MODULE_PATH_STAGE),
+        ] {
+            if Path::new(path).is_dir() {
+                self.add_directory(kind, Path::new(path))?;
+            }
+        }
+
         Ok(())
     }
 
+    /// Add all modules from an osbuild library directory, which contains a subdirectory per
+    /// kind of module (`stages`, `sources`, ...). Subdirectories that don't exist are skipped.
+    pub fn add_libdir(&mut self, libdir: &Path) -> Result<(), RegistryError> {
+        if !libdir.exists() {
+            return Err(RegistryError::NoSuchPath);
+        }
+
+        if !libdir.is_dir() {
+            return Err(RegistryError::NotADirectory);
+        }
+
+        for kind in Kind::ALL {
+            let path = libdir.join(kind.directory_name());
+
+            if path.is_dir() {
+                self.scan(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add all modules in a directory as modules of `kind`. Hidden files and non-files are
+    /// skipped, modules are added in filename order.
+    pub fn add_directory(&mut self, kind: Kind, path: &Path) -> Result<(), RegistryError> {
+        for path in module_paths(path)? {
+            self.modules
+                .push(Module::new(kind, &path.to_string_lossy())?);
+        }
+
+        Ok(())
+    }
+
+    /// Add all modules in a directory, inferring the kind of each module from the directory
+    /// or its schema. See `Module::infer_kind`.
+    pub fn scan(&mut self, path: &Path) -> Result<(), RegistryError> {
+        for path in module_paths(path)? {
+            self.modules.push(Module::infer(&path.to_string_lossy())?);
+        }
+
+        Ok(())
+    }
+
+    /// Limit the output of all modules in the registry.
+    pub fn set_output_limits(&mut self, limits: OutputLimits) {
+        for module in &mut self.modules {
+            module.set_output_limits(limits);
+        }
+    }
+
+    /// Give all modules in the registry `schema` to print their schema, and `run`, if any, to
+    /// do anything else.
+    pub fn set_timeouts(&mut self, schema: Duration, run: Option<Duration>) {
+        for module in &mut self.modules {
+            module.set_schema_timeout(schema);
+            module.set_timeout(run);
+        }
+    }
+
+    /// When the schemas of the modules were read, in seconds since the epoch. Only known for
+    /// registries made from a snapshot, other registries read schemas when they are asked for.
+    pub fn scanned(&self) -> Option<u64> {
+        self.scanned
+    }
+
+    /// All modules in the registry.
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    /// Write the schema of every module to `<directory>/<kind>/<name>.json`. Returns the paths
+    /// that were written, a module that fails to provide a valid schema fails the dump.
+    pub fn dump_schemas(&self, directory: &Path) -> Result<Vec<PathBuf>, RegistryError> {
+        let mut written = vec![];
+
+        for module in &self.modules {
+            let schema = module.get_schema_json()?;
+            let path = directory
+                .join(module.kind.directory_name())
+                .join(format!("{}.json", module.name));
+
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(
+                &path,
+                serde_json::to_vec_pretty(&schema).map_err(ModuleError::SchemaError)?,
+            )?;
+
+            written.push(path);
+        }
+
+        Ok(written)
+    }
+
     /// Find a module by its name.
     pub fn by_name(&self, name: &str) -> Option<&Module> {
         self.modules.iter().find(|&module| module.name == name)
@@ -62,8 +245,12 @@ impl Registry<'_> {
     }
 }
 
+/// Key in the schema of a module declaring its kind.
+pub const KIND_KEY: &str = "kind";
+
 /// Kind of a module.
-#[derive(Eq, PartialEq, Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Kind {
     Stage,
     Assembler,
@@ -74,15 +261,98 @@ pub enum Kind {
     Input,
 }
 
+impl Kind {
+    /// All kinds of modules.
+    pub const ALL: [Kind; 7] = [
+        Kind::Assembler,
+        Kind::Device,
+        Kind::Input,
+        Kind::Mount,
+        Kind::Runner,
+        Kind::Source,
+        Kind::Stage,
+    ];
+
+    /// An iterator over all kinds of modules, in the order of `ALL`.
+    pub fn all() -> impl Iterator<Item = Kind> {
+        Kind::ALL.into_iter()
+    }
+
+    /// The name of the kind, as modules declare it in their schema.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Stage => "stage",
+            Kind::Assembler => "assembler",
+            Kind::Source => "source",
+            Kind::Runner => "runner",
+            Kind::Mount => "mount",
+            Kind::Device => "device",
+            Kind::Input => "input",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Kind> {
+        Kind::all().find(|kind| kind.name() == name)
+    }
+
+    /// The kind of the modules in the directory with `name`, in an osbuild library directory.
+    pub fn from_directory_name(name: &str) -> Option<Kind> {
+        Kind::all().find(|kind| kind.directory_name() == name)
+    }
+
+    /// The kind of the module at `path`, from the name of the directory it is in.
+    pub fn from_path(path: &Path) -> Option<Kind> {
+        path.parent()
+            .and_then(|parent| parent.file_name())
+            .and_then(|name| Kind::from_directory_name(&name.to_string_lossy()))
+    }
+
+    /// The kind a module declares in the `kind` key of its schema.
+    pub fn from_schema(schema: &serde_json::Value) -> Option<Kind> {
+        schema
+            .get(KIND_KEY)
+            .and_then(|kind| kind.as_str())
+            .and_then(Kind::from_name)
+    }
+
+    /// The name of the directory modules of this kind are stored in, in an osbuild library
+    /// directory.
+    pub fn directory_name(&self) -> &'static str {
+        match self {
+            Kind::Stage => "stages",
+            Kind::Assembler => "assemblers",
+            Kind::Source => "sources",
+            Kind::Runner => "runners",
+            Kind::Mount => "mounts",
+            Kind::Device => "devices",
+            Kind::Input => "inputs",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Kind {
+    type Err = ModuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Kind::from_name(s).ok_or_else(|| ModuleError::UnknownKind(s.to_string()))
+    }
+}
+
 // The default paths where certain modules are located on a default install, note that
 // compatibility should be checked on these XXX
-const WELL_KNOWN_MODULE_PATH_ASSEMBLER: &str = "/usr/lib/osbuild/assemblers";
-const WELL_KNOWN_MODULE_PATH_DEVICE: &str = "/usr/lib/osbuild/devices";
-const WELL_KNOWN_MODULE_PATH_INPUT: &str = "/usr/lib/osbuild/inputs";
-const WELL_KNOWN_MODULE_PATH_MOUNT: &str = "/usr/lib/osbuild/mounts";
-const WELL_KNOWN_MODULE_PATH_RUNNER: &str = "/usr/lib/osbuild/runners";
-const WELL_KNOWN_MODULE_PATH_SOURCE: &str = "/usr/lib/osbuild/sources";
-const WELL_KNOWN_MODULE_PATH_STAGE: &str = "/usr/lib/osbuild/stages";
+pub const WELL_KNOWN_MODULE_PATH_ASSEMBLER: &str = "/usr/lib/osbuild/assemblers";
+pub const WELL_KNOWN_MODULE_PATH_DEVICE: &str = "/usr/lib/osbuild/devices";
+pub const WELL_KNOWN_MODULE_PATH_INPUT: &str = "/usr/lib/osbuild/inputs";
+pub const WELL_KNOWN_MODULE_PATH_MOUNT: &str = "/usr/lib/osbuild/mounts";
+pub const WELL_KNOWN_MODULE_PATH_RUNNER: &str = "/usr/lib/osbuild/runners";
+pub const WELL_KNOWN_MODULE_PATH_SOURCE: &str = "/usr/lib/osbuild/sources";
+pub const WELL_KNOWN_MODULE_PATH_STAGE: &str = "/usr/lib/osbuild/stages";
 
 /// Errors that happen during execution of a module.
 #[derive(Debug)]
@@ -97,6 +367,22 @@ pub enum ModuleError {
 
     /// The output of the module was not decodable as UTF-8.
     Utf8Error(std::str::Utf8Error),
+
+    /// The schema of the module is not valid JSON.
+    SchemaError(serde_json::Error),
+
+    /// The module declares a capability that doesn't exist.
+    UnknownCapability(String),
+
+    /// A kind of module with the contained name doesn't exist, or the kind of the module at
+    /// the contained path could not be inferred.
+    UnknownKind(String),
+
+    /// The module wrote more to stdout than its limit, which is contained.
+    OutputTooLarge(usize),
+
+    /// The module didn't exit within its timeout, which is contained. It was killed.
+    Timeout(Duration),
 }
 
 impl From<std::io::Error> for ModuleError {
@@ -112,23 +398,33 @@ impl From<std::str::Utf8Error> for ModuleError {
 }
 
 /// A module.
-pub struct Module<'a> {
+pub struct Module {
     /// The type of the module.
     kind: Kind,
 
     /// The path of the module
-    path: &'a str,
+    path: String,
 
     /// The name of the module, the filename part of the path.
-    name: &'a str,
+    name: String,
 
-    /// The schema of the module, this is initially `None` but once requested by `get_schema` the
+    /// The schema of the module, this is initially empty but once requested by `get_schema` the
     /// result will be cached in this field for faster retrieval.
-    schema: Option<String>,
+    schema: OnceLock<String>,
+
+    /// How much of the output of the module is kept when it is run.
+    limits: OutputLimits,
+
+    /// How long the module gets to print its schema.
+    schema_timeout: Duration,
+
+    /// How long the module gets to run for anything else, modules may run as long as they
+    /// take by default.
+    timeout: Option<Duration>,
 }
 
-impl Module<'_> {
-    fn new<'a>(kind: Kind, path: &'a str) -> Result<Module<'a>, ModuleError> {
+impl Module {
+    pub fn new(kind: Kind, path: &str) -> Result<Module, ModuleError> {
         let p = Path::new(path);
 
         if !p.exists() {
@@ -136,27 +432,111 @@ impl Module<'_> {
         } else {
             let f = p.file_name().ok_or(ModuleError::CantGetFilename)?;
 
-            Ok(Module {
-                kind,
-                path,
-                name: f.to_str().unwrap(),
-                schema: None,
-            })
+            Ok(Module::unchecked(kind, path, &f.to_string_lossy()))
+        }
+    }
+
+    /// A module that isn't checked to exist.
+    fn unchecked(kind: Kind, path: &str, name: &str) -> Module {
+        Module {
+            kind,
+            path: path.to_string(),
+            name: name.to_string(),
+            schema: OnceLock::new(),
+            limits: OutputLimits::default(),
+            schema_timeout: DEFAULT_SCHEMA_TIMEOUT,
+            timeout: None,
         }
     }
 
+    /// A module at `path` of the kind `infer_kind` finds.
+    pub fn infer(path: &str) -> Result<Module, ModuleError> {
+        // the kind is replaced as soon as it is known, asking for the schema needs a module
+        let mut module = Module::new(Kind::Stage, path)?;
+        module.kind = module.infer_kind()?;
+
+        Ok(module)
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// The kind of the module from the well-known directory it is in, or, for modules
+    /// elsewhere, the kind its schema declares.
+    pub fn infer_kind(&self) -> Result<Kind, ModuleError> {
+        if let Some(kind) = Kind::from_path(Path::new(&self.path)) {
+            return Ok(kind);
+        }
+
+        Kind::from_schema(&self.get_schema_json()?)
+            .ok_or_else(|| ModuleError::UnknownKind(self.path.clone()))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn output_limits(&self) -> OutputLimits {
+        self.limits
+    }
+
+    pub fn set_output_limits(&mut self, limits: OutputLimits) {
+        self.limits = limits;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_schema_timeout(&mut self, timeout: Duration) {
+        self.schema_timeout = timeout;
+    }
+
     /// Get the schema for this module by executing the module with the `--schema` argument,
-    /// results are cached.
-    fn get_schema(&self) -> Result<String, ModuleError> {
-        match self.schema.as_ref() {
-            Some(schema) => Ok(schema.to_string()),
-            None => {
-                let command = Command::new(self.path).args(["--schema"]).output()?;
-                let output = str::from_utf8(&command.stdout)?.to_string();
-
-                Ok(output)
-            }
+    /// results are cached. A module that doesn't print it within its schema timeout is
+    /// killed.
+    pub fn get_schema(&self) -> Result<String, ModuleError> {
+        if let Some(schema) = self.schema.get() {
+            return Ok(schema.to_string());
+        }
+
+        let output = output::run(
+            Command::new(&self.path).args(["--schema"]),
+            &[],
+            self.limits,
+            Some(self.schema_timeout),
+        )?;
+
+        if output.timed_out {
+            return Err(ModuleError::Timeout(self.schema_timeout));
         }
+
+        if output.stdout.is_truncated() {
+            return Err(ModuleError::OutputTooLarge(self.limits.stdout));
+        }
+
+        let schema = str::from_utf8(&output.stdout.data)?.to_string();
+
+        Ok(self.schema.get_or_init(|| schema).to_string())
+    }
+
+    /// Get the schema for this module parsed as JSON.
+    pub fn get_schema_json(&self) -> Result<serde_json::Value, ModuleError> {
+        serde_json::from_str(&self.get_schema()?).map_err(ModuleError::SchemaError)
+    }
+
+    /// The capabilities the module declares in its schema.
+    pub fn capabilities(&self) -> Result<Vec<Capability>, ModuleError> {
+        capability::from_schema(&self.get_schema_json()?)
     }
 }
 
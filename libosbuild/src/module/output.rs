@@ -0,0 +1,445 @@
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::module::cancel::CancellationToken;
+
+/// How much of stdout is kept by default; modules reply with JSON on stdout, which is small.
+pub const DEFAULT_STDOUT_LIMIT: usize = 16 * 1024 * 1024;
+
+/// How much of stderr is kept by default, stderr is only used to report why a module failed.
+pub const DEFAULT_STDERR_LIMIT: usize = 1024 * 1024;
+
+/// How much a module may write before it is cut off. A module that writes more to stdout
+/// fails as a truncated reply can't be parsed; stderr is truncated and marked as such.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputLimits {
+    pub stdout: usize,
+    pub stderr: usize,
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        Self {
+            stdout: DEFAULT_STDOUT_LIMIT,
+            stderr: DEFAULT_STDERR_LIMIT,
+        }
+    }
+}
+
+/// What was read from a stream, up to a limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Captured {
+    pub data: Vec<u8>,
+
+    /// How many bytes were written in total, more than `data` holds when it was truncated.
+    pub total: usize,
+}
+
+impl Captured {
+    pub fn is_truncated(&self) -> bool {
+        self.total > self.data.len()
+    }
+
+    /// The data as text, ending in a marker that says how much was left out if it was
+    /// truncated.
+    pub fn to_string_lossy(&self) -> String {
+        let mut text = String::from_utf8_lossy(&self.data).to_string();
+
+        if self.is_truncated() {
+            text.push_str(&format!(
+                "\n[output truncated, {} of {} bytes left out]",
+                self.total - self.data.len(),
+                self.total
+            ));
+        }
+
+        text
+    }
+}
+
+/// Read `reader` to its end, keeping at most `limit` bytes. The rest is read and dropped so
+/// whoever writes to it isn't blocked.
+pub fn read_limited<R: Read>(mut reader: R, limit: usize) -> io::Result<Captured> {
+    let mut captured = Captured::default();
+    let mut buffer = [0; 8192];
+
+    loop {
+        let count = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+
+        let keep = count.min(limit.saturating_sub(captured.data.len()));
+
+        captured.data.extend_from_slice(&buffer[..keep]);
+        captured.total += count;
+    }
+
+    Ok(captured)
+}
+
+/// How long a module gets to print its schema by default.
+pub const DEFAULT_SCHEMA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a module gets to stop after it was asked to by default.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Stopping a module when its token is cancelled. The module is asked to stop with `notify`
+/// and killed when it doesn't exit within `grace`.
+pub struct Cancellation<'c> {
+    pub token: CancellationToken,
+    pub grace: Duration,
+
+    /// Asks the module to stop, called once when the token is cancelled.
+    pub notify: Box<dyn FnMut() + 'c>,
+}
+
+/// Why a module that was waited for stopped, if it didn't just exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stopped {
+    Exited,
+    TimedOut,
+    Cancelled,
+}
+
+/// The exit status and limited output of a module.
+#[derive(Debug)]
+pub struct Output {
+    pub status: ExitStatus,
+    pub stdout: Captured,
+    pub stderr: Captured,
+
+    /// Whether the module was killed because it didn't exit in time.
+    pub timed_out: bool,
+
+    /// Whether the module was asked to stop, or killed, because it was cancelled.
+    pub cancelled: bool,
+}
+
+/// Kill everything in the process group of `child`, the child too if it still runs. The
+/// child has to lead its own process group, see `run_cancellable`, and must not be reaped
+/// yet, so that the group can't be another by now.
+fn kill_group(child: &Child) {
+    // SAFETY: kill(2) doesn't touch memory of this process; the group is that of the child,
+    // which isn't reaped, so its id can't have been reused
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+/// Whether `child` exited, without reaping it. With `block` this waits for it to exit.
+fn exited(child: &Child, block: bool) -> io::Result<bool> {
+    let flags = libc::WEXITED | libc::WNOWAIT | if block { 0 } else { libc::WNOHANG };
+
+    loop {
+        // SAFETY: an all-zero siginfo_t is valid, waitid(2) only writes to it
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+
+        // SAFETY: `info` outlives the call
+        if unsafe { libc::waitid(libc::P_PID, child.id(), &mut info, flags) } < 0 {
+            let err = io::Error::last_os_error();
+
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            return Err(err);
+        }
+
+        // SAFETY: waitid(2) filled in the pid of a child that exited, or left it zero
+        return Ok(unsafe { info.si_pid() } != 0);
+    }
+}
+
+/// Kill `child` along with everything it started, and reap it.
+fn kill(child: &mut Child) -> io::Result<ExitStatus> {
+    kill_group(child);
+
+    child.wait()
+}
+
+/// Wait for `child` to exit, for at most `timeout`. A child that takes longer, or that is
+/// cancelled and doesn't exit within the grace period, is killed; what it started is killed
+/// once it exits in any case, and it is reaped.
+fn wait_for(
+    child: &mut Child,
+    timeout: Option<Duration>,
+    mut cancellation: Option<&mut Cancellation>,
+) -> io::Result<(ExitStatus, Stopped)> {
+    if timeout.is_none() && cancellation.is_none() {
+        exited(child, true)?;
+
+        return Ok((kill(child)?, Stopped::Exited));
+    }
+
+    let start = Instant::now();
+    let mut notified: Option<Instant> = None;
+
+    loop {
+        if let Some(cancellation) = cancellation.as_deref_mut() {
+            match notified {
+                Some(at) if at.elapsed() > cancellation.grace => {
+                    return Ok((kill(child)?, Stopped::Cancelled));
+                }
+                None if cancellation.token.is_cancelled() => {
+                    (cancellation.notify)();
+                    notified = Some(Instant::now());
+                }
+                _ => {}
+            }
+        }
+
+        if exited(child, false)? {
+            let status = kill(child)?;
+
+            return Ok(match notified {
+                Some(_) => (status, Stopped::Cancelled),
+                None => (status, Stopped::Exited),
+            });
+        }
+
+        if timeout.is_some_and(|timeout| start.elapsed() > timeout) {
+            return Ok((kill(child)?, Stopped::TimedOut));
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Run `command` with `input` on stdin, keeping at most what `limits` allows of its output.
+/// Stdin is written while stdout and stderr are read so a module filling any doesn't block.
+/// The module runs in its own process group, which is killed when the module exits, so that
+/// nothing it left running keeps its output open, or when it takes longer than `timeout`.
+pub fn run(
+    command: &mut Command,
+    input: &[u8],
+    limits: OutputLimits,
+    timeout: Option<Duration>,
+) -> io::Result<Output> {
+    run_cancellable(command, input, limits, timeout, None)
+}
+
+/// Run `command` as `run` does, stopping it when `cancellation` is cancelled. As the module
+/// runs in its own process group, interrupting osbuild doesn't interrupt the module before
+/// it was asked to stop.
+pub fn run_cancellable(
+    command: &mut Command,
+    input: &[u8],
+    limits: OutputLimits,
+    timeout: Option<Duration>,
+    cancellation: Option<&mut Cancellation>,
+) -> io::Result<Output> {
+    command.process_group(0);
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = child
+        .stdout
+        .take()
+        .map(|stdout| thread::spawn(move || read_limited(stdout, limits.stdout)));
+
+    let stderr = child
+        .stderr
+        .take()
+        .map(|stderr| thread::spawn(move || read_limited(stderr, limits.stderr)));
+
+    let stdin = child.stdin.take().map(|mut stdin| {
+        let input = input.to_vec();
+
+        thread::spawn(move || match stdin.write_all(&input) {
+            // modules don't have to read their input
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            result => result,
+        })
+    });
+
+    let (status, stopped) = wait_for(&mut child, timeout, cancellation)?;
+
+    let stdout = match stdout {
+        Some(thread) => thread
+            .join()
+            .map_err(|_| io::Error::other("reading stdout panicked"))??,
+        None => Captured::default(),
+    };
+
+    let stderr = match stderr {
+        Some(thread) => thread
+            .join()
+            .map_err(|_| io::Error::other("reading stderr panicked"))??,
+        None => Captured::default(),
+    };
+
+    if let Some(thread) = stdin {
+        thread
+            .join()
+            .map_err(|_| io::Error::other("writing stdin panicked"))??;
+    }
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+        timed_out: stopped == Stopped::TimedOut,
+        cancelled: stopped == Stopped::Cancelled,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limited_reads() {
+        let captured = read_limited(&b"0123456789"[..], 4).unwrap();
+
+        assert_eq!(captured.data, b"0123");
+        assert_eq!(captured.total, 10);
+        assert!(captured.is_truncated());
+        assert_eq!(
+            captured.to_string_lossy(),
+            "0123\n[output truncated, 6 of 10 bytes left out]"
+        );
+
+        let captured = read_limited(&b"0123"[..], 4).unwrap();
+
+        assert!(!captured.is_truncated());
+        assert_eq!(captured.to_string_lossy(), "0123");
+    }
+
+    #[test]
+    fn limited_output() {
+        let output = run(
+            Command::new("sh").args(["-c", "head -c 100000 /dev/zero; cat >&2"]),
+            b"input",
+            OutputLimits {
+                stdout: 10,
+                stderr: 3,
+            },
+            None,
+        )
+        .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout.data.len(), 10);
+        assert_eq!(output.stdout.total, 100000);
+        assert_eq!(output.stderr.data, b"inp");
+        assert_eq!(output.stderr.total, 5);
+    }
+
+    #[test]
+    fn background_killed() {
+        let start = Instant::now();
+
+        // what the module leaves running would keep stdout open
+        for timeout in [None, Some(DEFAULT_SCHEMA_TIMEOUT)] {
+            let output = run(
+                Command::new("sh").args(["-c", "sleep 60 & echo {}"]),
+                b"",
+                OutputLimits::default(),
+                timeout,
+            )
+            .unwrap();
+
+            assert!(output.status.success());
+            assert!(!output.timed_out);
+            assert_eq!(output.stdout.data, b"{}\n");
+        }
+
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn timed_out() {
+        let start = Instant::now();
+        let output = run(
+            // the sleep started by the shell is killed too, or reading its output would hang
+            Command::new("sh").args(["-c", "echo started; sleep 60; echo done"]),
+            b"",
+            OutputLimits::default(),
+            Some(Duration::from_millis(200)),
+        )
+        .unwrap();
+
+        assert!(output.timed_out);
+        assert!(!output.status.success());
+        assert_eq!(output.stdout.data, b"started\n");
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        let output = run(
+            Command::new("true").arg("--schema"),
+            b"",
+            OutputLimits::default(),
+            Some(DEFAULT_SCHEMA_TIMEOUT),
+        )
+        .unwrap();
+
+        assert!(!output.timed_out);
+        assert!(output.status.success());
+    }
+
+    #[test]
+    fn cancelled() {
+        let token = CancellationToken::new();
+        let notified = std::cell::Cell::new(0);
+
+        let start = Instant::now();
+        let cancel = {
+            let token = token.clone();
+
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                token.cancel("interrupted");
+            })
+        };
+
+        // a module that doesn't stop when asked to is killed after the grace period
+        let output = run_cancellable(
+            Command::new("sh").args(["-c", "echo started; sleep 60"]),
+            b"",
+            OutputLimits::default(),
+            None,
+            Some(&mut Cancellation {
+                token: token.clone(),
+                grace: Duration::from_millis(200),
+                notify: Box::new(|| notified.set(notified.get() + 1)),
+            }),
+        )
+        .unwrap();
+
+        cancel.join().unwrap();
+
+        assert!(output.cancelled);
+        assert!(!output.timed_out);
+        assert!(!output.status.success());
+        assert_eq!(output.stdout.data, b"started\n");
+        assert_eq!(notified.get(), 1);
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        // one that exits in time isn't killed, it is asked to stop when it starts cancelled
+        let output = run_cancellable(
+            &mut Command::new("true"),
+            b"",
+            OutputLimits::default(),
+            None,
+            Some(&mut Cancellation {
+                token,
+                grace: Duration::from_secs(30),
+                notify: Box::new(|| {}),
+            }),
+        )
+        .unwrap();
+
+        assert!(output.cancelled);
+        assert!(output.status.success());
+    }
+}
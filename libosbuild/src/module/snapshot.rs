@@ -0,0 +1,140 @@
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::module::{Kind, Module, ModuleError, Registry, RegistryError};
+
+/// Version of the snapshot format, snapshots of other versions are refused.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// A module as it is recorded in a snapshot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModuleSnapshot {
+    pub name: String,
+    pub kind: Kind,
+    pub path: String,
+    pub schema: serde_json::Value,
+}
+
+/// The modules of a registry and their schemas, as they were when the registry was scanned.
+/// Snapshots can be stored to skip running every module for its schema on the next start, or
+/// sent to another host to validate manifests against the modules of this one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+
+    /// When the schemas were read, in seconds since the epoch.
+    pub scanned: u64,
+
+    pub modules: Vec<ModuleSnapshot>,
+}
+
+impl Registry {
+    /// A snapshot of the registry, every module is run for its schema. Snapshots of
+    /// registries made from a snapshot keep the time of the original.
+    pub fn snapshot(&self) -> Result<Snapshot, RegistryError> {
+        let mut modules = vec![];
+
+        for module in &self.modules {
+            modules.push(ModuleSnapshot {
+                name: module.name.clone(),
+                kind: module.kind,
+                path: module.path.clone(),
+                schema: module.get_schema_json()?,
+            });
+        }
+
+        Ok(Snapshot {
+            version: SNAPSHOT_VERSION,
+            scanned: self.scanned.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            modules,
+        })
+    }
+
+    /// A registry of the modules in `snapshot`. Their schemas are those in the snapshot, the
+    /// modules don't have to exist on this host.
+    pub fn from_snapshot(snapshot: Snapshot) -> Result<Self, RegistryError> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(RegistryError::UnsupportedSnapshot(snapshot.version));
+        }
+
+        let mut registry = Registry::new_empty();
+
+        for module in snapshot.modules {
+            registry.modules.push(Module {
+                schema: OnceLock::from(
+                    serde_json::to_string(&module.schema).map_err(ModuleError::SchemaError)?,
+                ),
+                ..Module::unchecked(module.kind, &module.path, &module.name)
+            });
+        }
+
+        registry.scanned = Some(snapshot.scanned);
+
+        Ok(registry)
+    }
+
+    pub fn to_json(&self) -> Result<serde_json::Value, RegistryError> {
+        serde_json::to_value(self.snapshot()?).map_err(RegistryError::InvalidSnapshot)
+    }
+
+    pub fn from_json(value: &serde_json::Value) -> Result<Self, RegistryError> {
+        Self::from_snapshot(Snapshot::deserialize(value).map_err(RegistryError::InvalidSnapshot)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn registry_roundtrip() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("org.osbuild.copy");
+
+        std::fs::write(&path, "#!/bin/sh\necho '{\"type\": \"object\"}'\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let registry = Registry::new(vec![
+            Module::new(Kind::Stage, &path.to_string_lossy()).unwrap()
+        ]);
+
+        let json = registry.to_json().unwrap();
+
+        assert_eq!(json["version"], SNAPSHOT_VERSION);
+        assert_eq!(json["modules"][0]["kind"], "stage");
+        assert_eq!(json["modules"][0]["schema"]["type"], "object");
+        assert_eq!(registry.scanned(), None);
+
+        // the schema comes from the snapshot, the module is gone
+        std::fs::remove_file(&path).unwrap();
+
+        let restored = Registry::from_json(&json).unwrap();
+        let module = restored.by_name("org.osbuild.copy").unwrap();
+
+        assert_eq!(module.kind(), Kind::Stage);
+        assert_eq!(module.get_schema_json().unwrap()["type"], "object");
+        assert_eq!(restored.scanned(), json["scanned"].as_u64());
+        assert_eq!(restored.to_json().unwrap(), json);
+
+        let mut newer = json.clone();
+        newer["version"] = serde_json::json!(SNAPSHOT_VERSION + 1);
+
+        assert!(matches!(
+            Registry::from_json(&newer),
+            Err(RegistryError::UnsupportedSnapshot(_))
+        ));
+        assert!(matches!(
+            Registry::from_json(&serde_json::json!({"modules": []})),
+            Err(RegistryError::InvalidSnapshot(_))
+        ));
+    }
+}
@@ -0,0 +1,441 @@
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+
+use crate::core::executor::{InputArgument, StageArguments};
+use crate::module::cancel::CancellationToken;
+use crate::module::util::iso::IsoError;
+use crate::module::util::oci::OciError;
+use crate::module::util::passwd::PasswdError;
+use crate::module::util::tree::{TreeError, TreePath};
+
+#[cfg(all(unix, feature = "communication"))]
+pub use crate::sandbox::communication::channel::LOG_SOCKET;
+#[cfg(all(unix, feature = "communication"))]
+use crate::sandbox::communication::channel::{
+    config::{ChannelConfig, Service},
+    protocol::message::{Signal, SignalKind},
+    protocol::JSONProtocol,
+    signals::Subscriptions,
+    transport::{TransportError, UnixDGRAMSocket},
+    Channel, CommandChannel,
+};
+
+/// The exit code of a stage that stopped because it was cancelled, as of a process that was
+/// interrupted.
+pub const CANCELLED_EXIT_CODE: u8 = 130;
+
+#[derive(Debug)]
+pub enum StageError {
+    /// The arguments the stage was run with aren't valid `StageArguments`.
+    InvalidArguments(serde_json::Error),
+
+    /// The options of the stage aren't what the stage takes.
+    InvalidOptions(serde_json::Error),
+
+    /// A location isn't a `tree://`, `input://`, or `mount://` URL.
+    InvalidLocation(String),
+
+    /// A location is in an input or mount the stage wasn't given.
+    NoSuchLocation(String),
+
+    /// The stage stopped because it was cancelled, contains why.
+    Cancelled(String),
+
+    TreeError(TreeError),
+    PasswdError(PasswdError),
+    OciError(OciError),
+    IsoError(IsoError),
+    IOError(io::Error),
+}
+
+impl From<TreeError> for StageError {
+    fn from(err: TreeError) -> Self {
+        Self::TreeError(err)
+    }
+}
+
+impl From<PasswdError> for StageError {
+    fn from(err: PasswdError) -> Self {
+        Self::PasswdError(err)
+    }
+}
+
+impl From<OciError> for StageError {
+    fn from(err: OciError) -> Self {
+        Self::OciError(err)
+    }
+}
+
+impl From<IsoError> for StageError {
+    fn from(err: IsoError) -> Self {
+        Self::IsoError(err)
+    }
+}
+
+impl From<io::Error> for StageError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// A path in the tree of a stage, in one of its inputs, or in one of its mounts, as written in
+/// the options of stages: `tree:///etc`, `input://tree/etc`, and `mount://root/etc`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Tree(PathBuf),
+    Input(String, PathBuf),
+    Mount(String, PathBuf),
+}
+
+impl Location {
+    /// A location as stages that take plain paths accept it; a URL, or an absolute path
+    /// which is a path in the tree.
+    pub fn from_option(option: &str) -> Result<Self, StageError> {
+        if option.starts_with('/') {
+            Ok(Self::Tree(PathBuf::from(option)))
+        } else {
+            option.parse()
+        }
+    }
+}
+
+impl FromStr for Location {
+    type Err = StageError;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let invalid = || StageError::InvalidLocation(url.to_string());
+        let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+
+        if scheme == "tree" {
+            return match rest.strip_prefix('/') {
+                Some(path) => Ok(Self::Tree(Path::new("/").join(path))),
+                None => Err(invalid()),
+            };
+        }
+
+        let (name, path) = rest.split_once('/').unwrap_or((rest, ""));
+
+        if name.is_empty() {
+            return Err(invalid());
+        }
+
+        let path = Path::new("/").join(path);
+
+        match scheme {
+            "input" => Ok(Self::Input(name.to_string(), path)),
+            "mount" => Ok(Self::Mount(name.to_string(), path)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// What a stage gets from osbuild; its arguments, a channel to log to, and whether it was
+/// asked to stop.
+pub struct Stage {
+    pub arguments: StageArguments,
+
+    cancellation: CancellationToken,
+
+    #[cfg(all(unix, feature = "communication"))]
+    channel: Option<CommandChannel>,
+}
+
+impl Stage {
+    /// A stage with arguments read from `reader`, which does not log to the host.
+    pub fn from_reader(reader: impl Read) -> Result<Self, StageError> {
+        Ok(Self {
+            arguments: serde_json::from_reader(reader).map_err(StageError::InvalidArguments)?,
+            cancellation: CancellationToken::new(),
+
+            #[cfg(all(unix, feature = "communication"))]
+            channel: None,
+        })
+    }
+
+    /// A stage of `tree` with `options` and the paths of devices, inputs, and mounts osbuild
+    /// runs stages with, but without any of them; for running stages in tests.
+    pub fn for_tree(tree: &Path, options: serde_json::Value) -> Self {
+        let arguments = serde_json::json!({
+            "tree": tree,
+            "options": options,
+            "paths": {"devices": "/dev", "inputs": "/run/osbuild/inputs", "mounts": "/run/osbuild/mounts"},
+            "inputs": {},
+            "devices": {},
+            "mounts": {},
+        });
+
+        Self::from_reader(arguments.to_string().as_bytes())
+            .expect("the arguments of a tree are stage arguments")
+    }
+
+    /// The stage with the directory `path` as its input `name`, which has no data.
+    pub fn with_input(mut self, name: &str, path: &Path) -> Self {
+        self.arguments.inputs.insert(
+            name.to_string(),
+            InputArgument {
+                path: path.to_path_buf(),
+                data: serde_json::json!({}),
+            },
+        );
+
+        self
+    }
+
+    /// The stage as osbuild runs it; arguments on stdin, and logging to the log socket when the
+    /// host provides it, `LOG_SOCKET` unless the environment moves it. When the host sets a
+    /// control socket the stage is cancelled by the cancel signals sent to it.
+    pub fn from_stdin() -> Result<Self, StageError> {
+        #[allow(unused_mut)]
+        let mut stage = Self::from_reader(io::stdin().lock())?;
+
+        #[cfg(all(unix, feature = "communication"))]
+        {
+            let config = ChannelConfig::from_env();
+
+            if config.socket(Service::Log).exists() {
+                stage.channel = CommandChannel::for_service(&config, Service::Log).ok();
+            }
+
+            if let Some(control) = config.configured(Service::Control) {
+                let _ = stage.listen(control);
+            }
+        }
+
+        Ok(stage)
+    }
+
+    /// Bind the control socket at `path` and cancel the stage when a cancel signal arrives on
+    /// it, signals are handled in a thread of their own.
+    #[cfg(all(unix, feature = "communication"))]
+    fn listen(&self, path: &Path) -> Result<(), StageError> {
+        let transport = UnixDGRAMSocket::bind(&path.to_string_lossy())
+            .map_err(|TransportError::IOError(err)| StageError::IOError(err))?;
+
+        let token = self.cancellation.clone();
+        let mut subscriptions = Subscriptions::new();
+
+        subscriptions.subscribe(SignalKind::Cancel, move |signal| {
+            let reason = signal
+                .value()
+                .and_then(|value| value["reason"].as_str())
+                .unwrap_or("cancelled by the host");
+
+            token.cancel(reason);
+        });
+
+        subscriptions.listen(CommandChannel {
+            transport: Box::new(transport),
+            protocol: Box::new(JSONProtocol {}),
+        });
+
+        Ok(())
+    }
+
+    /// The token that is cancelled when the stage is asked to stop, for work that checks it
+    /// in other threads.
+    pub fn cancellation(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Fail with `StageError::Cancelled` when the stage was asked to stop. Stages call this
+    /// where they can stop cleanly, such as between the items they work on; the host kills
+    /// those that don't stop within a grace period.
+    pub fn check_cancelled(&self) -> Result<(), StageError> {
+        match self.cancellation.reason() {
+            Some(reason) => Err(StageError::Cancelled(reason)),
+            None => Ok(()),
+        }
+    }
+
+    /// The options of the stage as the type the stage takes.
+    pub fn options<T: DeserializeOwned>(&self) -> Result<T, StageError> {
+        serde_json::from_value(self.arguments.options.clone()).map_err(StageError::InvalidOptions)
+    }
+
+    /// The tree the stage builds.
+    pub fn tree(&self) -> TreePath {
+        TreePath::new(&self.arguments.tree)
+    }
+
+    /// The path on the host of `location`, which can't leave the tree, input, or mount it is
+    /// in.
+    pub fn resolve(&self, location: &Location) -> Result<PathBuf, StageError> {
+        let (root, path) = match location {
+            Location::Tree(path) => (&self.arguments.tree, path),
+            Location::Input(name, path) => (
+                &self
+                    .arguments
+                    .inputs
+                    .get(name)
+                    .ok_or_else(|| StageError::NoSuchLocation(format!("input://{}", name)))?
+                    .path,
+                path,
+            ),
+            Location::Mount(name, path) => (
+                &self
+                    .arguments
+                    .mounts
+                    .get(name)
+                    .ok_or_else(|| StageError::NoSuchLocation(format!("mount://{}", name)))?
+                    .path,
+                path,
+            ),
+        };
+
+        Ok(TreePath::new(root).resolve(path)?)
+    }
+
+    /// Log `message` to stderr and, if connected, to the host. Logging to the host is a best
+    /// effort, a stage doesn't fail when its log can't be sent.
+    pub fn log(&mut self, message: &str) {
+        eprintln!("{}", message);
+
+        #[cfg(all(unix, feature = "communication"))]
+        if let Some(channel) = &mut self.channel {
+            let _ = channel.send(Signal::new(
+                serde_json::json!({ "message": message }),
+                vec![],
+            ));
+        }
+    }
+}
+
+/// Run the stage `main` as osbuild runs stages, for the `main` of stage binaries. Failures
+/// are written to stderr and exit with 1, stages that were cancelled exit with
+/// `CANCELLED_EXIT_CODE`.
+pub fn run<F: FnOnce(&mut Stage) -> Result<(), StageError>>(main: F) -> ExitCode {
+    match Stage::from_stdin().and_then(|mut stage| main(&mut stage)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(StageError::Cancelled(reason)) => {
+            eprintln!("stage cancelled: {}", reason);
+            ExitCode::from(CANCELLED_EXIT_CODE)
+        }
+        Err(err) => {
+            eprintln!("stage failed: {:?}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn locations() {
+        for (url, location) in [
+            ("tree:///", Location::Tree(PathBuf::from("/"))),
+            ("tree:///etc/ssh", Location::Tree(PathBuf::from("/etc/ssh"))),
+            (
+                "input://tree/usr",
+                Location::Input("tree".to_string(), PathBuf::from("/usr")),
+            ),
+            (
+                "mount://root",
+                Location::Mount("root".to_string(), PathBuf::from("/")),
+            ),
+        ] {
+            assert_eq!(url.parse::<Location>().unwrap(), location);
+        }
+
+        for url in ["/etc", "tree://etc", "input:///etc", "file:///etc"] {
+            assert!(matches!(
+                url.parse::<Location>(),
+                Err(StageError::InvalidLocation(_))
+            ));
+        }
+
+        assert_eq!(
+            Location::from_option("/etc").unwrap(),
+            Location::Tree(PathBuf::from("/etc"))
+        );
+        assert_eq!(
+            Location::from_option("mount://root/etc").unwrap(),
+            Location::Mount("root".to_string(), PathBuf::from("/etc"))
+        );
+        assert!(Location::from_option("etc").is_err());
+    }
+
+    #[test]
+    fn arguments_read() {
+        let tree = tempfile::tempdir().unwrap();
+        let input = tempfile::tempdir().unwrap();
+
+        fs::create_dir(input.path().join("etc")).unwrap();
+
+        let stage = Stage::for_tree(tree.path(), serde_json::json!({"paths": ["a"]}))
+            .with_input("tree", input.path());
+
+        #[derive(serde::Deserialize)]
+        struct Options {
+            paths: Vec<String>,
+        }
+
+        assert_eq!(stage.options::<Options>().unwrap().paths, ["a"]);
+        assert!(matches!(
+            stage.options::<Vec<String>>(),
+            Err(StageError::InvalidOptions(_))
+        ));
+
+        assert_eq!(
+            stage
+                .resolve(&"input://tree/etc/../etc".parse().unwrap())
+                .unwrap(),
+            input.path().join("etc")
+        );
+        assert_eq!(
+            stage.resolve(&"tree:///usr".parse().unwrap()).unwrap(),
+            tree.path().join("usr")
+        );
+        assert!(matches!(
+            stage.resolve(&"mount://root/".parse().unwrap()),
+            Err(StageError::NoSuchLocation(_))
+        ));
+        assert!(matches!(
+            stage.resolve(&"tree:///../etc".parse().unwrap()),
+            Err(StageError::TreeError(TreeError::Escape(_)))
+        ));
+
+        assert!(matches!(
+            Stage::from_reader("{}".as_bytes()),
+            Err(StageError::InvalidArguments(_))
+        ));
+    }
+
+    #[cfg(feature = "communication")]
+    #[test]
+    fn cancelled_by_host() {
+        use crate::sandbox::communication::channel::signals;
+        use std::time::{Duration, Instant};
+
+        let directory = tempfile::tempdir().unwrap();
+        let control = directory.path().join("control");
+
+        let stage = Stage::for_tree(directory.path(), serde_json::json!({}));
+
+        stage.listen(&control).unwrap();
+        assert!(stage.check_cancelled().is_ok());
+
+        signals::notify(&control, &Signal::progress(1, 2)).unwrap();
+        signals::notify(&control, &Signal::cancel("interrupted")).unwrap();
+
+        let start = Instant::now();
+
+        while !stage.cancellation().is_cancelled() {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(matches!(
+            stage.check_cancelled(),
+            Err(StageError::Cancelled(reason)) if reason == "interrupted"
+        ));
+    }
+}
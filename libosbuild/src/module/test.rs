@@ -6,6 +6,7 @@ fn registry_by_name() {
 
     let registry = Registry {
         modules: vec![module],
+        scanned: None,
     };
 
     let option = registry.by_name("sh");
@@ -39,6 +40,7 @@ fn registry_by_kind_no_result() {
 
     let registry = Registry {
         modules: vec![module],
+        scanned: None,
     };
 
     let option = registry.by_kind(Kind::Runner);
@@ -69,7 +71,259 @@ fn module_get_schema() {
     assert!(schema.is_ok());
 }
 
+#[test]
+fn module_get_schema_cached() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("org.osbuild.counted");
+    let count = directory.path().join("count");
+
+    std::fs::write(
+        &path,
+        format!("#!/bin/sh\necho x >> '{}'\necho '{{}}'\n", count.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let module = Module::new(Kind::Stage, &path.to_string_lossy()).unwrap();
+
+    assert_eq!(module.get_schema().unwrap(), "{}\n");
+    assert_eq!(module.get_schema().unwrap(), "{}\n");
+    assert!(module.get_schema_json().is_ok());
+    assert_eq!(std::fs::read_to_string(&count).unwrap(), "x\n");
+}
+
 #[test]
 fn module_get_schema_unparseable_path() {
     assert!(Module::new(Kind::Stage, "").is_err());
 }
+
+fn write_module(directory: &std::path::Path, name: &str, output: &str) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = directory.join(name);
+
+    std::fs::write(&path, format!("#!/bin/sh\necho '{}'\n", output)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn registry_add_libdir() {
+    let libdir = tempfile::tempdir().unwrap();
+    let stages = libdir.path().join("stages");
+    let sources = libdir.path().join("sources");
+
+    std::fs::create_dir_all(&stages).unwrap();
+    std::fs::create_dir_all(&sources).unwrap();
+
+    write_module(&stages, "org.osbuild.b", "{}");
+    write_module(&stages, "org.osbuild.a", "{}");
+    write_module(&stages, ".hidden", "{}");
+    write_module(&sources, "org.osbuild.curl", "{}");
+
+    let mut registry = Registry::new_empty();
+    registry.add_libdir(libdir.path()).unwrap();
+
+    let names: Vec<&str> = registry.modules().iter().map(|m| m.name()).collect();
+
+    assert_eq!(
+        names,
+        vec!["org.osbuild.curl", "org.osbuild.a", "org.osbuild.b"]
+    );
+    assert_eq!(registry.by_kind(Kind::Stage).unwrap().len(), 2);
+    assert_eq!(
+        registry.by_name("org.osbuild.curl").unwrap().kind(),
+        Kind::Source
+    );
+}
+
+#[test]
+fn registry_add_directory_missing() {
+    let mut registry = Registry::new_empty();
+
+    assert!(matches!(
+        registry.add_directory(Kind::Stage, std::path::Path::new("/nonexistent")),
+        Err(RegistryError::NoSuchPath)
+    ));
+    assert!(matches!(
+        registry.add_directory(Kind::Stage, std::path::Path::new("/bin/sh")),
+        Err(RegistryError::NotADirectory)
+    ));
+}
+
+#[test]
+fn registry_dump_schemas() {
+    let libdir = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let stages = libdir.path().join("stages");
+
+    std::fs::create_dir_all(&stages).unwrap();
+    write_module(&stages, "org.osbuild.a", r#"{"type": "object"}"#);
+
+    let mut registry = Registry::new_empty();
+    registry.add_libdir(libdir.path()).unwrap();
+
+    let written = registry.dump_schemas(out.path()).unwrap();
+
+    assert_eq!(written, vec![out.path().join("stages/org.osbuild.a.json")]);
+
+    let schema: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&written[0]).unwrap()).unwrap();
+
+    assert_eq!(schema["type"], "object");
+
+    write_module(&stages, "org.osbuild.b", "not json");
+
+    let mut registry = Registry::new_empty();
+    registry.add_libdir(libdir.path()).unwrap();
+
+    assert!(matches!(
+        registry.dump_schemas(out.path()),
+        Err(RegistryError::ModuleError(ModuleError::SchemaError(_)))
+    ));
+}
+
+#[test]
+fn module_get_schema_too_large() {
+    let directory = tempfile::tempdir().unwrap();
+
+    write_module(
+        directory.path(),
+        "org.osbuild.large",
+        "{\"type\": \"object\"}",
+    );
+
+    let path = directory.path().join("org.osbuild.large");
+
+    assert!(Module::new(Kind::Stage, &path.to_string_lossy())
+        .unwrap()
+        .get_schema_json()
+        .is_ok());
+
+    let mut module = Module::new(Kind::Stage, &path.to_string_lossy()).unwrap();
+
+    module.set_output_limits(output::OutputLimits {
+        stdout: 8,
+        stderr: 8,
+    });
+
+    assert!(matches!(
+        module.get_schema(),
+        Err(ModuleError::OutputTooLarge(8))
+    ));
+}
+
+#[test]
+fn module_get_schema_timeout() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("org.osbuild.hangs");
+
+    std::fs::write(&path, "#!/bin/sh\nsleep 60\n").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut registry = Registry::new_empty();
+    registry
+        .add_directory(Kind::Stage, directory.path())
+        .unwrap();
+    registry.set_timeouts(std::time::Duration::from_millis(100), None);
+
+    assert!(matches!(
+        registry.by_name("org.osbuild.hangs").unwrap().get_schema(),
+        Err(ModuleError::Timeout(timeout)) if timeout.as_millis() == 100
+    ));
+}
+
+#[test]
+fn kind_inferred() {
+    assert_eq!(
+        Kind::from_path(std::path::Path::new(
+            "/usr/lib/osbuild/devices/org.osbuild.loopback"
+        )),
+        Some(Kind::Device)
+    );
+    assert_eq!(
+        Kind::from_path(std::path::Path::new("/opt/modules/org.osbuild.loopback")),
+        None
+    );
+    assert_eq!(
+        Kind::from_schema(&serde_json::json!({"kind": "input"})),
+        Some(Kind::Input)
+    );
+    assert_eq!(
+        Kind::from_schema(&serde_json::json!({"kind": "inputs"})),
+        None
+    );
+
+    for kind in Kind::ALL {
+        assert_eq!(Kind::from_name(kind.name()), Some(kind));
+        assert_eq!(Kind::from_directory_name(kind.directory_name()), Some(kind));
+    }
+}
+
+#[test]
+fn registry_scan() {
+    let libdir = tempfile::tempdir().unwrap();
+    let mounts = libdir.path().join("mounts");
+    let other = libdir.path().join("other");
+
+    std::fs::create_dir_all(&mounts).unwrap();
+    std::fs::create_dir_all(&other).unwrap();
+
+    write_module(&mounts, "org.osbuild.ext4", "{}");
+    write_module(&other, "org.osbuild.files", "{\"kind\": \"input\"}");
+    write_module(&other, "org.osbuild.copy", "{\"kind\": \"stage\"}");
+
+    let mut registry = Registry::new_empty();
+    registry.scan(&mounts).unwrap();
+    registry.scan(&other).unwrap();
+
+    assert_eq!(
+        registry.by_name("org.osbuild.ext4").unwrap().kind(),
+        Kind::Mount
+    );
+    assert_eq!(
+        registry.by_name("org.osbuild.files").unwrap().kind(),
+        Kind::Input
+    );
+    assert_eq!(
+        registry.by_name("org.osbuild.copy").unwrap().kind(),
+        Kind::Stage
+    );
+
+    write_module(&other, "org.osbuild.unknown", "{}");
+
+    assert!(matches!(
+        Registry::new_empty().scan(&other),
+        Err(RegistryError::ModuleError(ModuleError::UnknownKind(path)))
+            if path.ends_with("org.osbuild.unknown")
+    ));
+}
+
+#[test]
+fn kind_names() {
+    assert_eq!(Kind::Device.to_string(), "device");
+    assert_eq!("input".parse::<Kind>().unwrap(), Kind::Input);
+    assert!(matches!(
+        "inputs".parse::<Kind>(),
+        Err(ModuleError::UnknownKind(name)) if name == "inputs"
+    ));
+    assert_eq!(
+        serde_json::to_value(Kind::all().collect::<Vec<_>>()).unwrap(),
+        serde_json::json!([
+            "assembler",
+            "device",
+            "input",
+            "mount",
+            "runner",
+            "source",
+            "stage"
+        ])
+    );
+    assert_eq!(
+        serde_json::from_value::<Kind>(serde_json::json!("stage")).unwrap(),
+        Kind::Stage
+    );
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A hidden file next to `path` to write its new contents to.
+pub fn temporary_path(path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not a file", path.display()),
+        )
+    })?;
+
+    let mut temporary = std::ffi::OsString::from(".");
+    temporary.push(name);
+    temporary.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    Ok(path.with_file_name(temporary))
+}
+
+fn write_temporary(path: &Path, contents: &[u8], mode: u32) -> io::Result<PathBuf> {
+    let temporary = temporary_path(path)?;
+
+    let result = (|| {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&temporary)?;
+
+        file.write_all(contents)?;
+
+        // the mode the file was created with is subject to the umask
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+        file.sync_all()
+    })();
+
+    match result {
+        Ok(()) => Ok(temporary),
+        Err(err) => {
+            let _ = fs::remove_file(&temporary);
+            Err(err)
+        }
+    }
+}
+
+/// The directory `path` is in, relative paths without one are in the current directory.
+fn directory_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Replace the file at `path` with `contents` so that, even when the machine crashes, it
+/// either has its old or its new contents and never a part of them. The contents are written
+/// to a temporary file next to `path` which is synced, renamed over `path`, and then the
+/// directory is synced so the rename is durable. The file gets `mode`.
+pub fn atomic_write(path: &Path, contents: impl AsRef<[u8]>, mode: u32) -> io::Result<()> {
+    atomic_write_all(&[(path, contents.as_ref(), mode)])
+}
+
+/// A hidden file next to `path` that keeps its old contents while files are replaced.
+fn backup_path(path: &Path) -> io::Result<PathBuf> {
+    let mut backup = temporary_path(path)?.into_os_string();
+    backup.push(".old");
+
+    Ok(PathBuf::from(backup))
+}
+
+/// Replace several files as `atomic_write` does; each file on its own is replaced atomically,
+/// the files together are not. All files are written before any is renamed into place, so
+/// when writing one of them fails none are replaced, and when replacing one of them fails the
+/// ones replaced before it get their old contents back. A crash between the renames can still
+/// leave some files replaced and others not. Every directory is synced once after all
+/// renames.
+pub fn atomic_write_all<P: AsRef<Path>, C: AsRef<[u8]>>(files: &[(P, C, u32)]) -> io::Result<()> {
+    let mut temporaries = Vec::with_capacity(files.len());
+
+    for (path, contents, mode) in files {
+        match write_temporary(path.as_ref(), contents.as_ref(), *mode) {
+            Ok(temporary) => temporaries.push((temporary, path.as_ref())),
+            Err(err) => {
+                for (temporary, _) in temporaries {
+                    let _ = fs::remove_file(temporary);
+                }
+
+                return Err(err);
+            }
+        }
+    }
+
+    let mut directories = BTreeSet::new();
+    let mut backups = Vec::with_capacity(temporaries.len());
+
+    for (index, (temporary, path)) in temporaries.iter().enumerate() {
+        // the old contents are linked to next to the file, to put back when a later one
+        // can't be replaced
+        let replaced = backup_path(path).and_then(|backup| {
+            match fs::hard_link(path, &backup) {
+                Ok(()) => backups.push(Some(backup)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => backups.push(None),
+                Err(err) => return Err(err),
+            }
+
+            fs::rename(temporary, path)
+        });
+
+        if let Err(err) = replaced {
+            for (temporary, _) in &temporaries[index..] {
+                let _ = fs::remove_file(temporary);
+            }
+
+            for ((_, path), backup) in temporaries.iter().zip(&backups).take(index) {
+                let _ = match backup {
+                    Some(backup) => fs::rename(backup, path),
+                    None => fs::remove_file(path),
+                };
+            }
+
+            if let Some(Some(backup)) = backups.get(index) {
+                let _ = fs::remove_file(backup);
+            }
+
+            return Err(err);
+        }
+
+        directories.insert(directory_of(path));
+    }
+
+    for backup in backups.iter().flatten() {
+        let _ = fs::remove_file(backup);
+    }
+
+    for directory in directories {
+        fs::File::open(directory)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn file_replaced() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("sshd_config");
+
+        fs::write(&path, "old").unwrap();
+        atomic_write(&path, "new", 0o600).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(entries(directory.path()), ["sshd_config"]);
+
+        assert!(atomic_write(Path::new("/"), "data", 0o644).is_err());
+    }
+
+    #[test]
+    fn files_replaced_together() {
+        let directory = tempfile::tempdir().unwrap();
+        let passwd = directory.path().join("passwd");
+        let group = directory.path().join("group");
+
+        atomic_write_all(&[(&passwd, "root\n", 0o644), (&group, "wheel\n", 0o644)]).unwrap();
+
+        assert_eq!(fs::read_to_string(&passwd).unwrap(), "root\n");
+        assert_eq!(fs::read_to_string(&group).unwrap(), "wheel\n");
+
+        // a file that can't be written leaves the others as they were
+        let missing = directory.path().join("missing/shadow");
+
+        assert!(
+            atomic_write_all(&[(&passwd, "nobody\n", 0o644), (&missing, "nobody\n", 0o000)])
+                .is_err()
+        );
+        assert_eq!(fs::read_to_string(&passwd).unwrap(), "root\n");
+        assert_eq!(entries(directory.path()), ["group", "passwd"]);
+
+        // a file that can't be renamed into place puts back those renamed before it
+        let occupied = directory.path().join("shadow");
+        fs::create_dir(&occupied).unwrap();
+        fs::write(occupied.join("entry"), "").unwrap();
+
+        let created = directory.path().join("gshadow");
+
+        assert!(atomic_write_all(&[
+            (&passwd, "nobody\n", 0o644),
+            (&created, "nobody\n", 0o000),
+            (&occupied, "nobody\n", 0o000),
+        ])
+        .is_err());
+        assert_eq!(fs::read_to_string(&passwd).unwrap(), "root\n");
+        assert_eq!(entries(directory.path()), ["group", "passwd", "shadow"]);
+    }
+}
@@ -0,0 +1,604 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::dependency::version::rpmvercmp;
+use crate::module::util::tree::{TreeError, TreePath};
+
+/// Where kernel modules, and on modern distributions the kernel images, are installed.
+pub const MODULES_PATH: &str = "usr/lib/modules";
+
+/// Where kernels and initrds are installed for the bootloader to find.
+pub const BOOT_PATH: &str = "boot";
+
+/// Where Boot Loader Specification entries are stored, relative to the root of the tree.
+pub const ENTRIES_PATH: &str = "boot/loader/entries";
+
+/// The location of the defaults `grub2-mkconfig` reads.
+pub const GRUB_DEFAULTS_PATH: &str = "etc/default/grub";
+
+#[derive(Debug)]
+pub enum BootError {
+    /// A boot loader entry could not be parsed.
+    ParseError(String),
+
+    /// A value contains characters that can't be represented in the output format.
+    InvalidValue(String),
+
+    TreeError(TreeError),
+    IOError(io::Error),
+}
+
+impl From<TreeError> for BootError {
+    fn from(err: TreeError) -> Self {
+        Self::TreeError(err)
+    }
+}
+
+impl From<io::Error> for BootError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// A kernel installed in a tree. Paths are as seen from inside the tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kernel {
+    pub version: String,
+    pub image: PathBuf,
+    pub initrd: Option<PathBuf>,
+}
+
+/// Enumerate the kernels installed in `tree`. Kernels are found through their module
+/// directories in `/usr/lib/modules` and through `vmlinuz-*` images in `/boot`; the image in
+/// `/boot` is preferred if both exist. The result is sorted by version as rpm compares them,
+/// so `6.10` comes after `6.9`.
+pub fn kernels(tree: &Path) -> Result<Vec<Kernel>, BootError> {
+    let mut versions = vec![];
+
+    if let Ok(entries) = fs::read_dir(tree.join(MODULES_PATH)) {
+        for entry in entries {
+            let entry = entry?;
+
+            if entry.path().join("vmlinuz").is_file() {
+                versions.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+    }
+
+    if let Ok(entries) = fs::read_dir(tree.join(BOOT_PATH)) {
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().to_string();
+
+            // skip the rescue kernel, it is not tied to an installed kernel package
+            if let Some(version) = name.strip_prefix("vmlinuz-") {
+                if !version.contains("-rescue-") {
+                    versions.push(version.to_string());
+                }
+            }
+        }
+    }
+
+    versions.sort_by(|a, b| rpmvercmp(a, b).then_with(|| a.cmp(b)));
+    versions.dedup();
+
+    Ok(versions
+        .into_iter()
+        .map(|version| {
+            let boot = Path::new(BOOT_PATH).join(format!("vmlinuz-{}", version));
+
+            let image = if tree.join(&boot).is_file() {
+                Path::new("/").join(boot)
+            } else {
+                Path::new("/")
+                    .join(MODULES_PATH)
+                    .join(&version)
+                    .join("vmlinuz")
+            };
+
+            let initrd = Path::new(BOOT_PATH).join(format!("initramfs-{}.img", version));
+            let initrd = tree
+                .join(&initrd)
+                .is_file()
+                .then(|| Path::new("/").join(initrd));
+
+            Kernel {
+                version,
+                image,
+                initrd,
+            }
+        })
+        .collect())
+}
+
+/// A Boot Loader Specification type #1 entry. Keys that aren't modeled explicitly are kept
+/// in `extra`, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootEntry {
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub machine_id: Option<String>,
+    pub linux: Option<String>,
+    pub initrd: Vec<String>,
+    pub options: Option<String>,
+    pub extra: Vec<(String, String)>,
+}
+
+impl BootEntry {
+    /// Create an entry for `kernel` the way `kernel-install` would.
+    pub fn for_kernel(kernel: &Kernel, machine_id: &str, title: &str, options: &str) -> Self {
+        Self {
+            title: Some(format!("{} ({})", title, kernel.version)),
+            version: Some(kernel.version.clone()),
+            machine_id: Some(machine_id.to_string()),
+            linux: Some(kernel.image.to_string_lossy().to_string()),
+            initrd: kernel
+                .initrd
+                .iter()
+                .map(|initrd| initrd.to_string_lossy().to_string())
+                .collect(),
+            options: (!options.is_empty()).then(|| options.to_string()),
+            extra: vec![],
+        }
+    }
+
+    /// The filename of this entry in the entries directory, `<machine-id>-<version>.conf`.
+    pub fn filename(&self) -> String {
+        match (&self.machine_id, &self.version) {
+            (Some(machine_id), Some(version)) => format!("{}-{}.conf", machine_id, version),
+            (None, Some(version)) => format!("{}.conf", version),
+            (Some(machine_id), None) => format!("{}.conf", machine_id),
+            (None, None) => "default.conf".to_string(),
+        }
+    }
+
+    /// Check that every value fits on the line of its key; a newline in a value would add
+    /// keys to the entry.
+    fn check(&self) -> Result<(), BootError> {
+        let values = [
+            &self.title,
+            &self.version,
+            &self.machine_id,
+            &self.linux,
+            &self.options,
+        ];
+
+        let values = values
+            .into_iter()
+            .flatten()
+            .chain(&self.initrd)
+            .chain(self.extra.iter().map(|(_, value)| value));
+
+        for value in values {
+            if value.chars().any(char::is_control) {
+                return Err(BootError::InvalidValue(value.clone()));
+            }
+        }
+
+        for (key, _) in &self.extra {
+            if key.is_empty() || key.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                return Err(BootError::InvalidValue(key.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write this entry into the entries directory of `tree`, returns the path written to.
+    /// Values with newlines or other control characters are refused.
+    pub fn write(&self, tree: &Path) -> Result<PathBuf, BootError> {
+        self.check()?;
+
+        let filename = self.filename();
+
+        if filename.contains('/') {
+            return Err(BootError::InvalidValue(filename));
+        }
+
+        Ok(TreePath::new(tree).write(
+            Path::new(ENTRIES_PATH).join(filename),
+            self.to_string(),
+            0o644,
+        )?)
+    }
+
+    /// Read all entries from the entries directory of `tree`, sorted by filename.
+    pub fn read_all(tree: &Path) -> Result<Vec<Self>, BootError> {
+        let mut paths = vec![];
+
+        match fs::read_dir(tree.join(ENTRIES_PATH)) {
+            Ok(entries) => {
+                for entry in entries {
+                    let path = entry?.path();
+
+                    if path
+                        .extension()
+                        .is_some_and(|extension| extension == "conf")
+                    {
+                        paths.push(path);
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        paths.sort();
+
+        paths
+            .into_iter()
+            .map(|path| fs::read_to_string(path)?.parse())
+            .collect()
+    }
+}
+
+impl FromStr for BootEntry {
+    type Err = BootError;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let mut entry = Self::default();
+
+        for line in data.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once(char::is_whitespace) {
+                Some((key, value)) => (key, value.trim().to_string()),
+                None => return Err(BootError::ParseError(line.to_string())),
+            };
+
+            match key {
+                "title" => entry.title = Some(value),
+                "version" => entry.version = Some(value),
+                "machine-id" => entry.machine_id = Some(value),
+                "linux" => entry.linux = Some(value),
+                "initrd" => entry.initrd.push(value),
+                "options" => entry.options = Some(value),
+                _ => entry.extra.push((key.to_string(), value)),
+            }
+        }
+
+        Ok(entry)
+    }
+}
+
+impl fmt::Display for BootEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let keys = [
+            ("title", &self.title),
+            ("version", &self.version),
+            ("machine-id", &self.machine_id),
+            ("linux", &self.linux),
+        ];
+
+        for (key, value) in keys {
+            if let Some(value) = value {
+                writeln!(f, "{} {}", key, value)?;
+            }
+        }
+
+        for initrd in &self.initrd {
+            writeln!(f, "initrd {}", initrd)?;
+        }
+
+        if let Some(options) = &self.options {
+            writeln!(f, "options {}", options)?;
+        }
+
+        for (key, value) in &self.extra {
+            writeln!(f, "{} {}", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Quote a value for a shell-sourced file such as `/etc/default/grub`.
+fn shell_quote(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "\\$")
+            .replace('`', "\\`")
+    )
+}
+
+/// The settings in `/etc/default/grub`, rendered in insertion order. Setting a key that is
+/// already present replaces its value in place.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GrubDefaults {
+    settings: Vec<(String, String)>,
+}
+
+impl GrubDefaults {
+    pub fn new() -> Self {
+        Self { settings: vec![] }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<&mut Self, BootError> {
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(BootError::InvalidValue(key.to_string()));
+        }
+
+        match self.settings.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.settings.push((key.to_string(), value.to_string())),
+        }
+
+        Ok(self)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.settings
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Write the defaults into `tree`.
+    pub fn write(&self, tree: &Path) -> Result<PathBuf, BootError> {
+        Ok(TreePath::new(tree).write(GRUB_DEFAULTS_PATH, self.to_string(), 0o644)?)
+    }
+}
+
+impl fmt::Display for GrubDefaults {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (key, value) in &self.settings {
+            writeln!(f, "{}={}", key, shell_quote(value))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Escape a word for grub's script lexer, which splits on whitespace and gives `;`, `|`,
+/// `&`, `<`, `>`, braces, quotes, and `#` meaning. With `variables` a `$` is kept, so
+/// options such as `$kernelopts` are still expanded by grub.
+fn grub_escape(word: &str, variables: bool) -> String {
+    let mut escaped = String::with_capacity(word.len());
+
+    for c in word.chars() {
+        if c.is_whitespace() || "\\'\";|&<>{}()#".contains(c) || (c == '$' && !variables) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Render a grub2 `menuentry` for a boot entry, for configurations that don't use the
+/// `blscfg` module. Paths are used as-is so they need to be relative to the grub root, and
+/// are escaped for grub; so are the options, word by word, except for the variables in them.
+pub fn grub_menuentry(entry: &BootEntry) -> Result<String, BootError> {
+    entry.check()?;
+
+    let title = entry.title.clone().unwrap_or_else(|| entry.filename());
+    let linux = entry
+        .linux
+        .as_ref()
+        .ok_or_else(|| BootError::InvalidValue("entry has no linux".to_string()))?;
+
+    // in single quotes nothing is special but the quote, which has to end them
+    let mut output = format!("menuentry '{}' {{\n", title.replace('\'', "'\\''"));
+
+    output.push_str(&format!("\tlinux {}", grub_escape(linux, false)));
+
+    for option in entry
+        .options
+        .iter()
+        .flat_map(|options| options.split_whitespace())
+    {
+        output.push_str(&format!(" {}", grub_escape(option, true)));
+    }
+
+    output.push('\n');
+
+    if !entry.initrd.is_empty() {
+        let initrd: Vec<String> = entry
+            .initrd
+            .iter()
+            .map(|initrd| grub_escape(initrd, false))
+            .collect();
+
+        output.push_str(&format!("\tinitrd {}\n", initrd.join(" ")));
+    }
+
+    output.push_str("}\n");
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn touch(path: PathBuf) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+
+    #[test]
+    fn kernels_in_tree() {
+        let tree = tempfile::tempdir().unwrap();
+
+        touch(
+            tree.path()
+                .join("usr/lib/modules/6.0.7-301.fc37.x86_64/vmlinuz"),
+        );
+        touch(
+            tree.path()
+                .join("usr/lib/modules/5.19.0-1.fc37.x86_64/vmlinuz"),
+        );
+        touch(tree.path().join("boot/vmlinuz-6.0.7-301.fc37.x86_64"));
+        touch(tree.path().join("boot/initramfs-6.0.7-301.fc37.x86_64.img"));
+        touch(tree.path().join("boot/vmlinuz-0-rescue-abcdef"));
+        touch(
+            tree.path()
+                .join("usr/lib/modules/6.10.0-1.fc41.x86_64/vmlinuz"),
+        );
+        touch(
+            tree.path()
+                .join("usr/lib/modules/6.9.0-1.fc41.x86_64/vmlinuz"),
+        );
+
+        let kernels = kernels(tree.path()).unwrap();
+
+        assert_eq!(
+            kernels,
+            vec![
+                Kernel {
+                    version: "5.19.0-1.fc37.x86_64".to_string(),
+                    image: PathBuf::from("/usr/lib/modules/5.19.0-1.fc37.x86_64/vmlinuz"),
+                    initrd: None,
+                },
+                Kernel {
+                    version: "6.0.7-301.fc37.x86_64".to_string(),
+                    image: PathBuf::from("/boot/vmlinuz-6.0.7-301.fc37.x86_64"),
+                    initrd: Some(PathBuf::from("/boot/initramfs-6.0.7-301.fc37.x86_64.img")),
+                },
+                Kernel {
+                    version: "6.9.0-1.fc41.x86_64".to_string(),
+                    image: PathBuf::from("/usr/lib/modules/6.9.0-1.fc41.x86_64/vmlinuz"),
+                    initrd: None,
+                },
+                Kernel {
+                    version: "6.10.0-1.fc41.x86_64".to_string(),
+                    image: PathBuf::from("/usr/lib/modules/6.10.0-1.fc41.x86_64/vmlinuz"),
+                    initrd: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn kernels_empty_tree() {
+        let tree = tempfile::tempdir().unwrap();
+
+        assert!(kernels(tree.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn boot_entry_roundtrip() {
+        let kernel = Kernel {
+            version: "6.0.7".to_string(),
+            image: PathBuf::from("/boot/vmlinuz-6.0.7"),
+            initrd: Some(PathBuf::from("/boot/initramfs-6.0.7.img")),
+        };
+
+        let mut entry = BootEntry::for_kernel(&kernel, "abc", "Fedora Linux", "root=UUID=1 ro");
+        entry
+            .extra
+            .push(("grub_users".to_string(), "$grub_users".to_string()));
+
+        let data = entry.to_string();
+
+        assert_eq!(
+            data,
+            "title Fedora Linux (6.0.7)\nversion 6.0.7\nmachine-id abc\nlinux /boot/vmlinuz-6.0.7\ninitrd /boot/initramfs-6.0.7.img\noptions root=UUID=1 ro\ngrub_users $grub_users\n"
+        );
+        assert_eq!(data.parse::<BootEntry>().unwrap(), entry);
+        assert_eq!(entry.filename(), "abc-6.0.7.conf");
+    }
+
+    #[test]
+    fn boot_entry_write_read() {
+        let tree = tempfile::tempdir().unwrap();
+
+        let entry = BootEntry {
+            version: Some("1".to_string()),
+            linux: Some("/vmlinuz".to_string()),
+            ..Default::default()
+        };
+
+        let path = entry.write(tree.path()).unwrap();
+
+        assert_eq!(path, tree.path().join("boot/loader/entries/1.conf"));
+        assert_eq!(BootEntry::read_all(tree.path()).unwrap(), vec![entry]);
+        assert!("nokeyvalue".parse::<BootEntry>().is_err());
+    }
+
+    #[test]
+    fn grub_defaults_render() {
+        let mut defaults = GrubDefaults::new();
+
+        defaults
+            .set("GRUB_TIMEOUT", "5")
+            .unwrap()
+            .set("GRUB_CMDLINE_LINUX", "console=ttyS0 \"quoted\" $x")
+            .unwrap()
+            .set("GRUB_TIMEOUT", "0")
+            .unwrap();
+
+        assert_eq!(
+            defaults.to_string(),
+            "GRUB_TIMEOUT=\"0\"\nGRUB_CMDLINE_LINUX=\"console=ttyS0 \\\"quoted\\\" \\$x\"\n"
+        );
+        assert!(defaults.set("NOT VALID", "").is_err());
+    }
+
+    #[test]
+    fn grub_menuentry_render() {
+        let entry = BootEntry {
+            title: Some("Fedora".to_string()),
+            linux: Some("/vmlinuz".to_string()),
+            initrd: vec!["/initrd".to_string()],
+            options: Some("ro".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            grub_menuentry(&entry).unwrap(),
+            "menuentry 'Fedora' {\n\tlinux /vmlinuz ro\n\tinitrd /initrd\n}\n"
+        );
+        assert!(grub_menuentry(&BootEntry::default()).is_err());
+
+        let entry = BootEntry {
+            title: Some("Fedora's".to_string()),
+            linux: Some("/vmlinuz; reboot".to_string()),
+            initrd: vec!["/initrd {x}".to_string()],
+            options: Some("$kernelopts root='/dev/vda' quiet".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            grub_menuentry(&entry).unwrap(),
+            "menuentry 'Fedora'\\''s' {\n\tlinux /vmlinuz\\;\\ reboot $kernelopts root=\\'/dev/vda\\' quiet\n\tinitrd /initrd\\ \\{x\\}\n}\n"
+        );
+    }
+
+    #[test]
+    fn boot_entry_values_checked() {
+        let tree = tempfile::tempdir().unwrap();
+        let entry = BootEntry {
+            version: Some("6.0".to_string()),
+            options: Some("ro\ninitrd /evil".to_string()),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            entry.write(tree.path()),
+            Err(BootError::InvalidValue(_))
+        ));
+        assert!(grub_menuentry(&BootEntry {
+            linux: Some("/vmlinuz".to_string()),
+            ..entry
+        })
+        .is_err());
+
+        let entry = BootEntry {
+            extra: vec![("grub users".to_string(), "$grub_users".to_string())],
+            ..Default::default()
+        };
+
+        assert!(entry.write(tree.path()).is_err());
+    }
+}
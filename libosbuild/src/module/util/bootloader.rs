@@ -0,0 +1,448 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::module::util::tree::{TreeError, TreePath};
+use crate::module::util::volume::{Teardown, VolumeError};
+
+/// What the host has that bootloaders need in the tree they are installed from.
+pub const HOST_MOUNTS: &[&str] = &["dev", "proc", "sys", "run"];
+
+#[derive(Debug)]
+pub enum BootloaderError {
+    /// A tool failed, contains its name, its exit code, and its stderr.
+    Failed(String, Option<i32>, String),
+
+    /// A mountpoint isn't an absolute path in the tree.
+    InvalidMountpoint(PathBuf),
+
+    /// Unmounting or detaching after the installation failed.
+    VolumeError(VolumeError),
+
+    TreeError(TreeError),
+    IOError(io::Error),
+}
+
+impl From<VolumeError> for BootloaderError {
+    fn from(err: VolumeError) -> Self {
+        Self::VolumeError(err)
+    }
+}
+
+impl From<TreeError> for BootloaderError {
+    fn from(err: TreeError) -> Self {
+        Self::TreeError(err)
+    }
+}
+
+impl From<io::Error> for BootloaderError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// Run `command` and return its stdout, failures are those of `tool`.
+fn run(mut command: Command, tool: &str) -> Result<String, BootloaderError> {
+    let output = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(BootloaderError::Failed(
+            tool.to_string(),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The bootloader to install, and how.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bootloader {
+    /// `grub2-install` for a `platform` such as `i386-pc`, which writes the boot code into
+    /// the MBR and the BIOS boot partition of the disk.
+    Grub2 {
+        platform: String,
+
+        /// Modules to build into the core image, such as `part_gpt` and `xfs`.
+        modules: Vec<String>,
+    },
+
+    /// `bootupctl backend install`, which installs the bootloaders of the tree for every
+    /// platform it has them for, as bootc and ostree deployments do.
+    Bootupd {
+        /// Install the static grub configuration bootupd ships rather than generating one.
+        static_configs: bool,
+    },
+}
+
+impl Bootloader {
+    /// The command, inside the tree, that installs the bootloader onto `device`.
+    pub fn arguments(&self, device: &Path) -> Vec<String> {
+        let device = device.to_string_lossy().to_string();
+
+        match self {
+            Self::Grub2 { platform, modules } => {
+                let mut arguments = vec![
+                    "grub2-install".to_string(),
+                    format!("--target={}", platform),
+                    "--boot-directory=/boot".to_string(),
+                ];
+
+                if !modules.is_empty() {
+                    arguments.push(format!("--modules={}", modules.join(" ")));
+                }
+
+                arguments.push(device);
+                arguments
+            }
+            Self::Bootupd { static_configs } => {
+                let mut arguments = vec!["bootupctl", "backend", "install"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect::<Vec<_>>();
+
+                if *static_configs {
+                    arguments.push("--with-static-configs".to_string());
+                }
+
+                arguments.extend(["--device".to_string(), device]);
+                arguments.extend(["--src-root", "/", "/"].map(String::from));
+                arguments
+            }
+        }
+    }
+}
+
+/// A partition of the disk and where it is mounted in the tree, such as 3 on `/` and 2 on
+/// `/boot/efi`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    pub partition: u32,
+    pub mountpoint: PathBuf,
+}
+
+/// Installs bootloaders into disk images. The image is attached to a loop device, its
+/// partitions are mounted into an empty directory in the order they are given, and the host
+/// mounts in `HOST_MOUNTS` are bound in; the bootloader is then installed with the tools of the
+/// tree, as the tree will boot it. Everything is unmounted and detached again in the reverse
+/// order, also when a step fails.
+#[derive(Debug, Clone)]
+pub struct Installer {
+    pub losetup: PathBuf,
+    pub mount: PathBuf,
+    pub umount: PathBuf,
+    pub chroot: PathBuf,
+}
+
+impl Installer {
+    pub fn new() -> Self {
+        Self {
+            losetup: PathBuf::from("losetup"),
+            mount: PathBuf::from("mount"),
+            umount: PathBuf::from("umount"),
+            chroot: PathBuf::from("chroot"),
+        }
+    }
+
+    /// Attach `image` to a loop device with its partitions scanned, pushing its detaching
+    /// onto `teardown`. Returns the loop device.
+    pub fn attach(
+        &self,
+        image: &Path,
+        teardown: &mut Teardown,
+    ) -> Result<PathBuf, BootloaderError> {
+        let mut command = Command::new(&self.losetup);
+        command.args(["--find", "--show", "--partscan"]).arg(image);
+
+        let device = PathBuf::from(run(command, "losetup")?);
+
+        let mut detach = Command::new(&self.losetup);
+        detach.arg("--detach").arg(&device);
+
+        teardown.push(detach, "losetup", "detach");
+
+        Ok(device)
+    }
+
+    /// Mount `source` on `target` with `arguments`, pushing the unmounting onto `teardown`.
+    fn mount_on(
+        &self,
+        arguments: &[&str],
+        source: &Path,
+        target: &Path,
+        teardown: &mut Teardown,
+    ) -> Result<(), BootloaderError> {
+        fs::create_dir_all(target)?;
+
+        let mut command = Command::new(&self.mount);
+        command.args(arguments).arg(source).arg(target);
+
+        run(command, "mount")?;
+
+        let mut umount = Command::new(&self.umount);
+        umount.arg(target);
+
+        teardown.push(umount, "umount", "unmount");
+
+        Ok(())
+    }
+
+    /// Install `bootloader` into the disk image at `image`, with `mounts` mounted in the
+    /// empty directory `root`.
+    pub fn install(
+        &self,
+        image: &Path,
+        root: &Path,
+        mounts: &[Mount],
+        bootloader: &Bootloader,
+    ) -> Result<(), BootloaderError> {
+        let mut teardown = Teardown::new();
+
+        let device = self.attach(image, &mut teardown)?;
+
+        for mount in mounts {
+            if !mount.mountpoint.is_absolute() {
+                return Err(BootloaderError::InvalidMountpoint(mount.mountpoint.clone()));
+            }
+
+            // later mounts are resolved in the filesystems of earlier ones
+            let target = TreePath::new(root).resolve(&mount.mountpoint)?;
+
+            let mut partition = device.clone().into_os_string();
+            partition.push(format!("p{}", mount.partition));
+
+            self.mount_on(&[], Path::new(&partition), &target, &mut teardown)?;
+        }
+
+        for host in HOST_MOUNTS {
+            self.mount_on(
+                &["--bind"],
+                &Path::new("/").join(host),
+                &root.join(host),
+                &mut teardown,
+            )?;
+        }
+
+        let mut command = Command::new(&self.chroot);
+        command.arg(root).args(bootloader.arguments(&device));
+
+        let tool = match bootloader {
+            Bootloader::Grub2 { .. } => "grub2-install",
+            Bootloader::Bootupd { .. } => "bootupctl",
+        };
+
+        run(command, tool)?;
+
+        Ok(teardown.run()?)
+    }
+}
+
+impl Default for Installer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A tool that logs its name and arguments to `log`, and fails when they contain `fail`.
+    /// `losetup` prints the loop device it attached.
+    fn tool(directory: &Path, name: &str, fail: &str) -> PathBuf {
+        let path = directory.join(name);
+
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\necho {name} \"$@\" >> {log}\ncase \"$*\" in *--show*) echo /dev/loop7;; esac\ncase \"$*\" in *{fail}*) exit 2;; esac\n",
+                name = name,
+                log = directory.join("log").display(),
+                fail = fail
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        path
+    }
+
+    fn installer(directory: &Path, fail: &str) -> Installer {
+        Installer {
+            losetup: tool(directory, "losetup", fail),
+            mount: tool(directory, "mount", fail),
+            umount: tool(directory, "umount", fail),
+            chroot: tool(directory, "chroot", fail),
+        }
+    }
+
+    fn log(directory: &Path, root: &Path) -> Vec<String> {
+        fs::read_to_string(directory.join("log"))
+            .unwrap()
+            .replace(&root.display().to_string(), "<root>")
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn bootloaders_invoked() {
+        let grub2 = Bootloader::Grub2 {
+            platform: "i386-pc".to_string(),
+            modules: vec!["part_gpt".to_string(), "xfs".to_string()],
+        };
+
+        assert_eq!(
+            grub2.arguments(Path::new("/dev/loop0")),
+            [
+                "grub2-install",
+                "--target=i386-pc",
+                "--boot-directory=/boot",
+                "--modules=part_gpt xfs",
+                "/dev/loop0"
+            ]
+        );
+
+        let bootupd = Bootloader::Bootupd {
+            static_configs: true,
+        };
+
+        assert_eq!(
+            bootupd.arguments(Path::new("/dev/loop0")),
+            [
+                "bootupctl",
+                "backend",
+                "install",
+                "--with-static-configs",
+                "--device",
+                "/dev/loop0",
+                "--src-root",
+                "/",
+                "/"
+            ]
+        );
+    }
+
+    #[test]
+    fn bootloaders_installed() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("root");
+
+        let mounts = [
+            Mount {
+                partition: 3,
+                mountpoint: PathBuf::from("/"),
+            },
+            Mount {
+                partition: 2,
+                mountpoint: PathBuf::from("/boot/efi"),
+            },
+        ];
+
+        installer(directory.path(), "never")
+            .install(
+                Path::new("/images/disk.raw"),
+                &root,
+                &mounts,
+                &Bootloader::Bootupd {
+                    static_configs: false,
+                },
+            )
+            .unwrap();
+
+        assert_eq!(
+            log(directory.path(), &root),
+            [
+                "losetup --find --show --partscan /images/disk.raw",
+                "mount /dev/loop7p3 <root>",
+                "mount /dev/loop7p2 <root>/boot/efi",
+                "mount --bind /dev <root>/dev",
+                "mount --bind /proc <root>/proc",
+                "mount --bind /sys <root>/sys",
+                "mount --bind /run <root>/run",
+                "chroot <root> bootupctl backend install --device /dev/loop7 --src-root / /",
+                "umount <root>/run",
+                "umount <root>/sys",
+                "umount <root>/proc",
+                "umount <root>/dev",
+                "umount <root>/boot/efi",
+                "umount <root>",
+                "losetup --detach /dev/loop7",
+            ]
+        );
+    }
+
+    #[test]
+    fn failures_torn_down() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("root");
+
+        let mounts = [Mount {
+            partition: 3,
+            mountpoint: PathBuf::from("/"),
+        }];
+
+        let err = installer(directory.path(), "/proc")
+            .install(
+                Path::new("/images/disk.raw"),
+                &root,
+                &mounts,
+                &Bootloader::Grub2 {
+                    platform: "i386-pc".to_string(),
+                    modules: vec![],
+                },
+            )
+            .unwrap_err();
+
+        assert!(matches!(err, BootloaderError::Failed(tool, Some(2), _) if tool == "mount"));
+        assert_eq!(
+            log(directory.path(), &root)[3..],
+            [
+                "mount --bind /proc <root>/proc",
+                "umount <root>/dev",
+                "umount <root>",
+                "losetup --detach /dev/loop7",
+            ]
+        );
+
+        let err = installer(directory.path(), "never")
+            .install(
+                Path::new("/images/disk.raw"),
+                &root,
+                &[Mount {
+                    partition: 2,
+                    mountpoint: PathBuf::from("boot"),
+                }],
+                &Bootloader::Bootupd {
+                    static_configs: false,
+                },
+            )
+            .unwrap_err();
+
+        assert!(matches!(err, BootloaderError::InvalidMountpoint(_)));
+
+        let err = installer(directory.path(), "never")
+            .install(
+                Path::new("/images/disk.raw"),
+                &root,
+                &[Mount {
+                    partition: 2,
+                    mountpoint: PathBuf::from("/../boot"),
+                }],
+                &Bootloader::Bootupd {
+                    static_configs: false,
+                },
+            )
+            .unwrap_err();
+
+        assert!(matches!(err, BootloaderError::TreeError(_)));
+    }
+}
@@ -0,0 +1,916 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use rand::Rng;
+use serde::{Deserialize, Deserializer};
+
+use crate::module::util::size::DataSize;
+use crate::module::util::tree;
+
+/// The size of the sectors of disk images, starts and sizes of partitions are in them.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Where partitions start when they don't say, and what they are aligned to; 1MiB.
+pub const ALIGNMENT: u64 = 2048;
+
+/// The number of entries of GPT partition arrays, which take 32 sectors.
+const GPT_ENTRIES: u64 = 128;
+const GPT_ENTRY_SIZE: u64 = 128;
+const GPT_ENTRY_SECTORS: u64 = GPT_ENTRIES * GPT_ENTRY_SIZE / SECTOR_SIZE;
+
+/// The type of partitions, the protective one, that covers GPT disks in their MBR.
+const MBR_PROTECTIVE: u8 = 0xee;
+
+#[derive(Debug)]
+pub enum DiskError {
+    /// A partition table can't be written as it is laid out, contains why.
+    InvalidLayout(String),
+
+    /// A GUID or partition type couldn't be parsed.
+    InvalidValue(String),
+
+    /// A tool failed, contains its name, its exit code, and its stderr.
+    Failed(String, Option<i32>, String),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for DiskError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// Run `command`, failures are those of `tool`.
+fn run(mut command: Command, tool: &str) -> Result<(), DiskError> {
+    let output = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(DiskError::Failed(
+            tool.to_string(),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// A GUID as GPT stores them; the first three fields little endian, the rest as written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    /// A random, version 4, GUID.
+    pub fn random() -> Self {
+        let mut bytes: [u8; 16] = rand::thread_rng().gen();
+
+        bytes[7] = (bytes[7] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        Self(bytes)
+    }
+
+    pub fn bytes(&self) -> [u8; 16] {
+        self.0
+    }
+}
+
+impl FromStr for Guid {
+    type Err = DiskError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || DiskError::InvalidValue(value.to_string());
+        let fields: Vec<&str> = value.split('-').collect();
+
+        if fields.iter().map(|field| field.len()).collect::<Vec<_>>() != [8, 4, 4, 4, 12] {
+            return Err(invalid());
+        }
+
+        let mut bytes = [0u8; 16];
+        let hex = fields.concat();
+
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2).ok_or_else(invalid)?, 16)
+                .map_err(|_| invalid())?;
+        }
+
+        bytes[..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+
+        b[10..]
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+/// The kind of partition table of a disk, named as sfdisk names them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Label {
+    /// A GUID partition table, with a protective MBR.
+    Gpt,
+
+    /// An MBR partition table, of up to four primary partitions.
+    Dos,
+}
+
+/// Sectors as options give them; integers are sectors, as `org.osbuild.sfdisk` takes them,
+/// and strings are a `DataSize` that has to be whole sectors, such as `"1 MiB"`.
+fn sectors<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Given {
+        Sectors(u64),
+        Size(String),
+    }
+
+    Ok(match Option::<Given>::deserialize(deserializer)? {
+        None => None,
+        Some(Given::Sectors(sectors)) => Some(sectors),
+        Some(Given::Size(text)) => {
+            let size: DataSize = text.parse().map_err(serde::de::Error::custom)?;
+
+            Some(size.exact_blocks(SECTOR_SIZE).ok_or_else(|| {
+                serde::de::Error::custom(format!("{} is not whole sectors", size))
+            })?)
+        }
+    })
+}
+
+/// A partition of a disk, as the `org.osbuild.sfdisk` stage takes them. Starts and sizes are
+/// in sectors, options can give them as sizes with units as well.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Partition {
+    /// Where the partition starts, after the previous one aligned to `ALIGNMENT` when unset.
+    #[serde(default, deserialize_with = "sectors")]
+    pub start: Option<u64>,
+
+    /// The size of the partition, the rest of the disk when unset; only the last partition
+    /// can leave it out.
+    #[serde(default, deserialize_with = "sectors")]
+    pub size: Option<u64>,
+
+    /// The type of the partition; a GUID on GPT disks, such as
+    /// `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`, and a hex byte on DOS disks, such as `83`.
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// The GUID of the partition on GPT disks, random when unset.
+    pub uuid: Option<String>,
+
+    /// The name of the partition on GPT disks.
+    pub name: Option<String>,
+
+    /// Mark the partition active on DOS disks, or legacy BIOS bootable on GPT disks.
+    #[serde(default)]
+    pub bootable: bool,
+
+    /// The bits of the GPT attributes to set, such as 60 for read-only.
+    #[serde(default)]
+    pub attrs: Vec<u8>,
+}
+
+/// The partition table of a disk, as the `org.osbuild.sfdisk` stage takes it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionTable {
+    pub label: Label,
+
+    /// The GUID of GPT disks, or the hex disk identifier of DOS disks such as `0x14fc63d2`;
+    /// random when unset.
+    pub uuid: Option<String>,
+
+    pub partitions: Vec<Partition>,
+}
+
+/// Where a partition was placed on a disk, in sectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Placement {
+    /// The offset of the partition in the image, in bytes.
+    pub fn offset(&self) -> u64 {
+        self.start * SECTOR_SIZE
+    }
+
+    /// The size of the partition, in bytes.
+    pub fn length(&self) -> u64 {
+        self.size * SECTOR_SIZE
+    }
+}
+
+/// The CRC-32 of `data`, as GPT headers and partition arrays are checked with.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= u32::from(*byte);
+
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+fn align(sector: u64) -> u64 {
+    sector.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+impl PartitionTable {
+    /// The sectors partitions can be in on a disk of `sectors` sectors, both inclusive. GPT
+    /// disks keep their partition arrays and headers at the start and the end.
+    pub fn usable(&self, sectors: u64) -> (u64, u64) {
+        match self.label {
+            Label::Gpt => (
+                2 + GPT_ENTRY_SECTORS,
+                sectors.saturating_sub(2 + GPT_ENTRY_SECTORS),
+            ),
+            Label::Dos => (1, sectors.saturating_sub(1)),
+        }
+    }
+
+    /// Where each partition goes on a disk of `sectors` sectors. Partitions have to be in
+    /// order, can't overlap, and have to be within the usable sectors.
+    pub fn place(&self, sectors: u64) -> Result<Vec<Placement>, DiskError> {
+        let (first, last) = self.usable(sectors);
+        let mut placements: Vec<Placement> = vec![];
+
+        if last < first {
+            return Err(DiskError::InvalidLayout(format!(
+                "the disk of {} sectors is too small for a partition table",
+                sectors
+            )));
+        }
+
+        if self.label == Label::Dos && self.partitions.len() > 4 {
+            return Err(DiskError::InvalidLayout(format!(
+                "{} partitions, DOS disks have at most 4",
+                self.partitions.len()
+            )));
+        }
+
+        for (index, partition) in self.partitions.iter().enumerate() {
+            let next = placements
+                .last()
+                .map(|previous| previous.start + previous.size)
+                .unwrap_or(first);
+
+            let start = partition.start.unwrap_or_else(|| align(next));
+
+            let size = match partition.size {
+                Some(size) => size,
+                None if index + 1 == self.partitions.len() => (last + 1).saturating_sub(start),
+                None => {
+                    return Err(DiskError::InvalidLayout(format!(
+                        "partition {} has no size and isn't the last",
+                        index + 1
+                    )))
+                }
+            };
+
+            if size == 0 || start < next || start + size - 1 > last {
+                return Err(DiskError::InvalidLayout(format!(
+                    "partition {} at sectors {}+{} overlaps another or is outside {}-{}",
+                    index + 1,
+                    start,
+                    size,
+                    first,
+                    last
+                )));
+            }
+
+            if self.label == Label::Dos
+                && (start > u64::from(u32::MAX) || size > u64::from(u32::MAX))
+            {
+                return Err(DiskError::InvalidLayout(format!(
+                    "partition {} is beyond 2TiB, which DOS disks can't address",
+                    index + 1
+                )));
+            }
+
+            placements.push(Placement { start, size });
+        }
+
+        Ok(placements)
+    }
+
+    /// Write the partition table to the disk image at `path`, which keeps its size. Returns
+    /// where the partitions were placed.
+    pub fn write(&self, path: &Path) -> Result<Vec<Placement>, DiskError> {
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let sectors = file.metadata()?.len() / SECTOR_SIZE;
+
+        let placements = self.place(sectors)?;
+
+        match self.label {
+            Label::Gpt => self.write_gpt(&mut file, sectors, &placements)?,
+            Label::Dos => self.write_dos(&mut file, &placements)?,
+        }
+
+        file.sync_all()?;
+
+        Ok(placements)
+    }
+
+    /// An MBR with the boot code area left empty.
+    fn mbr(signature: u32, entries: &[(bool, u8, u64, u64)]) -> [u8; SECTOR_SIZE as usize] {
+        let mut mbr = [0u8; SECTOR_SIZE as usize];
+
+        mbr[440..444].copy_from_slice(&signature.to_le_bytes());
+
+        for (index, (active, kind, start, size)) in entries.iter().enumerate() {
+            let entry = &mut mbr[446 + index * 16..462 + index * 16];
+
+            entry[0] = if *active { 0x80 } else { 0 };
+
+            // the CHS addresses are those of partitions beyond where CHS can address
+            entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+            entry[4] = *kind;
+            entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+            entry[8..12].copy_from_slice(&(*start as u32).to_le_bytes());
+            entry[12..16].copy_from_slice(&((*size).min(u64::from(u32::MAX)) as u32).to_le_bytes());
+        }
+
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+
+        mbr
+    }
+
+    fn write_dos(&self, file: &mut fs::File, placements: &[Placement]) -> Result<(), DiskError> {
+        let signature = match &self.uuid {
+            Some(uuid) => u32::from_str_radix(uuid.trim_start_matches("0x"), 16)
+                .map_err(|_| DiskError::InvalidValue(uuid.clone()))?,
+            None => rand::thread_rng().gen(),
+        };
+
+        let mut entries = vec![];
+
+        for (partition, placement) in self.partitions.iter().zip(placements) {
+            let kind = u8::from_str_radix(partition.kind.trim_start_matches("0x"), 16)
+                .map_err(|_| DiskError::InvalidValue(partition.kind.clone()))?;
+
+            entries.push((partition.bootable, kind, placement.start, placement.size));
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&Self::mbr(signature, &entries))?;
+
+        Ok(())
+    }
+
+    fn write_gpt(
+        &self,
+        file: &mut fs::File,
+        sectors: u64,
+        placements: &[Placement],
+    ) -> Result<(), DiskError> {
+        let disk = match &self.uuid {
+            Some(uuid) => uuid.parse()?,
+            None => Guid::random(),
+        };
+
+        let mut entries = vec![0u8; (GPT_ENTRIES * GPT_ENTRY_SIZE) as usize];
+
+        if self.partitions.len() as u64 > GPT_ENTRIES {
+            return Err(DiskError::InvalidLayout(format!(
+                "{} partitions, GPT disks have at most {}",
+                self.partitions.len(),
+                GPT_ENTRIES
+            )));
+        }
+
+        for (index, (partition, placement)) in self.partitions.iter().zip(placements).enumerate() {
+            let entry = &mut entries[index * GPT_ENTRY_SIZE as usize..][..GPT_ENTRY_SIZE as usize];
+
+            let uuid = match &partition.uuid {
+                Some(uuid) => uuid.parse()?,
+                None => Guid::random(),
+            };
+
+            let mut attributes = 0u64;
+
+            if partition.bootable {
+                attributes |= 1 << 2;
+            }
+
+            for bit in &partition.attrs {
+                if *bit >= 64 {
+                    return Err(DiskError::InvalidValue(format!("attribute bit {}", bit)));
+                }
+
+                attributes |= 1 << bit;
+            }
+
+            entry[..16].copy_from_slice(&partition.kind.parse::<Guid>()?.bytes());
+            entry[16..32].copy_from_slice(&uuid.bytes());
+            entry[32..40].copy_from_slice(&placement.start.to_le_bytes());
+            entry[40..48].copy_from_slice(&(placement.start + placement.size - 1).to_le_bytes());
+            entry[48..56].copy_from_slice(&attributes.to_le_bytes());
+
+            let name: Vec<u16> = partition
+                .name
+                .as_deref()
+                .unwrap_or("")
+                .encode_utf16()
+                .collect();
+
+            if name.len() > 36 {
+                return Err(DiskError::InvalidValue(
+                    partition.name.clone().unwrap_or_default(),
+                ));
+            }
+
+            for (index, unit) in name.iter().enumerate() {
+                entry[56 + index * 2..58 + index * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+
+        let (first, last) = self.usable(sectors);
+        let backup = sectors - 1;
+
+        let header = |current: u64, other: u64, array: u64| {
+            let mut header = [0u8; SECTOR_SIZE as usize];
+
+            header[..8].copy_from_slice(b"EFI PART");
+            header[8..12].copy_from_slice(&0x00010000u32.to_le_bytes());
+            header[12..16].copy_from_slice(&92u32.to_le_bytes());
+            header[24..32].copy_from_slice(&current.to_le_bytes());
+            header[32..40].copy_from_slice(&other.to_le_bytes());
+            header[40..48].copy_from_slice(&first.to_le_bytes());
+            header[48..56].copy_from_slice(&last.to_le_bytes());
+            header[56..72].copy_from_slice(&disk.bytes());
+            header[72..80].copy_from_slice(&array.to_le_bytes());
+            header[80..84].copy_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+            header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+            header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+
+            let checksum = crc32(&header[..92]);
+            header[16..20].copy_from_slice(&checksum.to_le_bytes());
+
+            header
+        };
+
+        let protective = Self::mbr(0, &[(false, MBR_PROTECTIVE, 1, sectors - 1)]);
+
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&protective)?;
+        file.write_all(&header(1, backup, 2))?;
+        file.write_all(&entries)?;
+
+        file.seek(SeekFrom::Start((backup - GPT_ENTRY_SECTORS) * SECTOR_SIZE))?;
+        file.write_all(&entries)?;
+        file.write_all(&header(backup, 1, backup - GPT_ENTRY_SECTORS))?;
+
+        Ok(())
+    }
+}
+
+/// Create a sparse disk image of `size` at `path`, rounded up to whole sectors.
+pub fn create_image(path: &Path, size: DataSize) -> io::Result<()> {
+    let length = size
+        .checked_align(DataSize::from_bytes(SECTOR_SIZE))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "image size too large"))?;
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+
+    file.set_len(length.bytes())
+}
+
+/// The filesystems partitions are made with, each by its own `mkfs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    Ext4,
+    Xfs,
+    Btrfs,
+    Vfat,
+}
+
+/// Makes a filesystem on a device, as the `org.osbuild.mkfs.*` stages do.
+#[derive(Debug, Clone)]
+pub struct Mkfs {
+    pub filesystem: Filesystem,
+
+    /// The mkfs to run, `mkfs.<filesystem>` when unset.
+    pub mkfs: Option<PathBuf>,
+
+    /// The UUID of the filesystem, for vfat its volume id such as `7B7795E7`.
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+impl Mkfs {
+    pub fn new(filesystem: Filesystem) -> Self {
+        Self {
+            filesystem,
+            mkfs: None,
+            uuid: None,
+            label: None,
+        }
+    }
+
+    /// The mkfs command that makes the filesystem on `device`.
+    pub fn command(&self, device: &Path) -> Command {
+        let tool = match self.filesystem {
+            Filesystem::Ext4 => "mkfs.ext4",
+            Filesystem::Xfs => "mkfs.xfs",
+            Filesystem::Btrfs => "mkfs.btrfs",
+            Filesystem::Vfat => "mkfs.fat",
+        };
+
+        let mut command = Command::new(self.mkfs.as_deref().unwrap_or(Path::new(tool)));
+
+        match self.filesystem {
+            Filesystem::Ext4 => {
+                command.arg("-F");
+
+                if let Some(uuid) = &self.uuid {
+                    command.arg("-U").arg(uuid);
+                }
+
+                if let Some(label) = &self.label {
+                    command.arg("-L").arg(label);
+                }
+            }
+            Filesystem::Xfs => {
+                command.arg("-f");
+
+                if let Some(uuid) = &self.uuid {
+                    command.arg("-m").arg(format!("uuid={}", uuid));
+                }
+
+                if let Some(label) = &self.label {
+                    command.arg("-L").arg(label);
+                }
+            }
+            Filesystem::Btrfs => {
+                command.arg("-f");
+
+                if let Some(uuid) = &self.uuid {
+                    command.arg("-U").arg(uuid);
+                }
+
+                if let Some(label) = &self.label {
+                    command.arg("-L").arg(label);
+                }
+            }
+            Filesystem::Vfat => {
+                // the device is the filesystem, it isn't partitioned itself
+                command.arg("-I");
+
+                if let Some(uuid) = &self.uuid {
+                    command.arg("-i").arg(uuid.replace('-', ""));
+                }
+
+                if let Some(label) = &self.label {
+                    command.arg("-n").arg(label);
+                }
+            }
+        }
+
+        command.arg(device);
+        command
+    }
+
+    /// Make the filesystem on `device`.
+    pub fn make(&self, device: &Path) -> Result<(), DiskError> {
+        let command = self.command(device);
+        let tool = command.get_program().to_string_lossy().to_string();
+
+        run(command, &tool)
+    }
+}
+
+/// Copy everything in `tree` into the filesystem mounted at `mountpoint`, as `cp -a` does.
+/// Returns how many files were copied.
+pub fn copy_tree(tree: &Path, mountpoint: &Path) -> io::Result<usize> {
+    let mut copied = 0;
+
+    for entry in fs::read_dir(tree)? {
+        let entry = entry?;
+
+        copied += tree::copy_all(&entry.path(), &mountpoint.join(entry.file_name()))?;
+    }
+
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn guids_parsed() {
+        let guid: Guid = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B".parse().unwrap();
+
+        assert_eq!(
+            guid.bytes()[..8],
+            [0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11]
+        );
+        assert_eq!(guid.to_string(), "C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+
+        assert!("C12A7328-F81F-11D2-BA4B".parse::<Guid>().is_err());
+        assert!("X12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+            .parse::<Guid>()
+            .is_err());
+
+        let random = Guid::random().to_string();
+
+        assert_eq!(random.parse::<Guid>().unwrap().to_string(), random);
+        assert_eq!(&random[14..15], "4");
+    }
+
+    #[test]
+    fn checksums_computed() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn partitions_placed() {
+        let table = PartitionTable {
+            label: Label::Gpt,
+            uuid: None,
+            partitions: vec![
+                Partition {
+                    size: Some(2048),
+                    kind: "21686148-6449-6E6F-744E-656564454649".to_string(),
+                    ..Default::default()
+                },
+                Partition {
+                    kind: "0FC63DAF-8483-4772-8E79-3D69D8477DE4".to_string(),
+                    ..Default::default()
+                },
+            ],
+        };
+
+        assert_eq!(
+            table.place(20480).unwrap(),
+            [
+                Placement {
+                    start: 2048,
+                    size: 2048
+                },
+                Placement {
+                    start: 4096,
+                    size: 20480 - 34 - 4096 + 1
+                },
+            ]
+        );
+
+        for partitions in [
+            // overlapping
+            vec![
+                Partition {
+                    start: Some(2048),
+                    size: Some(4096),
+                    ..Default::default()
+                },
+                Partition {
+                    start: Some(4096),
+                    size: Some(2048),
+                    ..Default::default()
+                },
+            ],
+            // in the backup partition array
+            vec![Partition {
+                start: Some(2048),
+                size: Some(20480),
+                ..Default::default()
+            }],
+            // without a size before another
+            vec![Partition::default(), Partition::default()],
+        ] {
+            let table = PartitionTable {
+                partitions,
+                ..table.clone()
+            };
+
+            assert!(matches!(
+                table.place(20480),
+                Err(DiskError::InvalidLayout(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn partitions_sized() {
+        let partition: Partition = serde_json::from_value(serde_json::json!({
+            "start": "1 MiB",
+            "size": 4096,
+            "type": "83"
+        }))
+        .unwrap();
+
+        assert_eq!(partition.start, Some(2048));
+        assert_eq!(partition.size, Some(4096));
+
+        let partition: Partition =
+            serde_json::from_value(serde_json::json!({"size": "2G", "type": "83"})).unwrap();
+
+        assert_eq!(partition.start, None);
+        assert_eq!(partition.size, Some(4 * 1024 * 1024));
+
+        for size in ["1000 B", "lots"] {
+            assert!(serde_json::from_value::<Partition>(
+                serde_json::json!({"size": size, "type": "83"})
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn gpt_written() {
+        let directory = tempfile::tempdir().unwrap();
+        let image = directory.path().join("disk.raw");
+
+        create_image(&image, DataSize::mib(10)).unwrap();
+
+        let table = PartitionTable {
+            label: Label::Gpt,
+            uuid: Some("D209C89E-EA5E-4FBD-B161-B461CCE297E0".to_string()),
+            partitions: vec![Partition {
+                kind: "C12A7328-F81F-11D2-BA4B-00A0C93EC93B".to_string(),
+                uuid: Some("68B2905B-DF3E-4FB3-80FA-49D1E773AA33".to_string()),
+                name: Some("EFI System".to_string()),
+                ..Default::default()
+            }],
+        };
+
+        let placements = table.write(&image).unwrap();
+        let data = fs::read(&image).unwrap();
+        let sectors = data.len() as u64 / SECTOR_SIZE;
+
+        assert_eq!(placements[0].offset(), 1024 * 1024);
+        assert_eq!(&data[510..512], [0x55, 0xaa]);
+        assert_eq!(data[446 + 4], MBR_PROTECTIVE);
+
+        for (current, array) in [(1, 2), (sectors - 1, sectors - 1 - GPT_ENTRY_SECTORS)] {
+            let header = &data[(current * SECTOR_SIZE) as usize..][..SECTOR_SIZE as usize];
+            let entries =
+                &data[(array * SECTOR_SIZE) as usize..][..(GPT_ENTRIES * GPT_ENTRY_SIZE) as usize];
+
+            assert_eq!(&header[..8], b"EFI PART");
+            assert_eq!(u64_at(header, 24), current);
+            assert_eq!(u64_at(header, 72), array);
+            assert_eq!(u32_at(header, 88), crc32(entries));
+
+            let mut copy = header[..92].to_vec();
+            copy[16..20].fill(0);
+
+            assert_eq!(u32_at(header, 16), crc32(&copy));
+
+            assert_eq!(u64_at(entries, 32), 2048);
+            assert_eq!(u64_at(entries, 40), sectors - 34);
+            assert_eq!(&entries[56..58], b"E\0");
+        }
+    }
+
+    #[test]
+    fn dos_written() {
+        let directory = tempfile::tempdir().unwrap();
+        let image = directory.path().join("disk.raw");
+
+        create_image(&image, DataSize::mib(4)).unwrap();
+
+        let table = PartitionTable {
+            label: Label::Dos,
+            uuid: Some("0x14fc63d2".to_string()),
+            partitions: vec![
+                Partition {
+                    size: Some(2048),
+                    kind: "ef".to_string(),
+                    bootable: true,
+                    ..Default::default()
+                },
+                Partition {
+                    kind: "83".to_string(),
+                    ..Default::default()
+                },
+            ],
+        };
+
+        table.write(&image).unwrap();
+
+        let data = fs::read(&image).unwrap();
+
+        assert_eq!(u32_at(&data, 440), 0x14fc63d2);
+        assert_eq!(data[446], 0x80);
+        assert_eq!(data[446 + 4], 0xef);
+        assert_eq!(u32_at(&data, 446 + 8), 2048);
+        assert_eq!(data[462 + 4], 0x83);
+        assert_eq!(u32_at(&data, 462 + 8), 4096);
+        assert_eq!(u32_at(&data, 462 + 12), 8192 - 1 - 4096 + 1);
+
+        let table = PartitionTable {
+            partitions: vec![Partition::default(); 5],
+            ..table
+        };
+
+        assert!(matches!(
+            table.write(&image),
+            Err(DiskError::InvalidLayout(_))
+        ));
+    }
+
+    #[test]
+    fn filesystems_made() {
+        let arguments = |mkfs: Mkfs| {
+            mkfs.command(Path::new("/dev/loop0p1"))
+                .get_args()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            arguments(Mkfs {
+                uuid: Some("6e4ff95f-f662-45ee-a82a-bdf44a2d0b75".to_string()),
+                label: Some("root".to_string()),
+                ..Mkfs::new(Filesystem::Xfs)
+            }),
+            [
+                "-f",
+                "-m",
+                "uuid=6e4ff95f-f662-45ee-a82a-bdf44a2d0b75",
+                "-L",
+                "root",
+                "/dev/loop0p1"
+            ]
+        );
+        assert_eq!(
+            arguments(Mkfs {
+                uuid: Some("7B77-95E7".to_string()),
+                label: Some("EFI-SYSTEM".to_string()),
+                ..Mkfs::new(Filesystem::Vfat)
+            }),
+            ["-I", "-i", "7B7795E7", "-n", "EFI-SYSTEM", "/dev/loop0p1"]
+        );
+
+        let failing = Mkfs {
+            mkfs: Some(PathBuf::from("false")),
+            ..Mkfs::new(Filesystem::Ext4)
+        };
+
+        assert!(matches!(
+            failing.make(Path::new("/dev/loop0p1")),
+            Err(DiskError::Failed(tool, Some(1), _)) if tool == "false"
+        ));
+    }
+
+    #[test]
+    fn trees_copied() {
+        let tree = tempfile::tempdir().unwrap();
+        let mountpoint = tempfile::tempdir().unwrap();
+
+        fs::create_dir(tree.path().join("etc")).unwrap();
+        fs::write(tree.path().join("etc/hostname"), "disk\n").unwrap();
+
+        assert_eq!(copy_tree(tree.path(), mountpoint.path()).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(mountpoint.path().join("etc/hostname")).unwrap(),
+            "disk\n"
+        );
+    }
+}
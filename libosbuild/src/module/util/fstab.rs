@@ -0,0 +1,510 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::module::util::tree::{TreeError, TreePath};
+
+pub const FSTAB_PATH: &str = "etc/fstab";
+pub const CRYPTTAB_PATH: &str = "etc/crypttab";
+
+#[derive(Debug)]
+pub enum FstabError {
+    /// A line could not be parsed, contains the 1-based line number and the line.
+    ParseError(usize, String),
+
+    /// A device could not be resolved to a stable identifier.
+    Unresolvable(String),
+
+    TreeError(TreeError),
+    IOError(io::Error),
+}
+
+impl From<TreeError> for FstabError {
+    fn from(err: TreeError) -> Self {
+        Self::TreeError(err)
+    }
+}
+
+impl From<io::Error> for FstabError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// Escape a field the way `getmntent(3)` expects; whitespace and backslashes are written as
+/// octal escapes.
+fn escape(field: &str) -> String {
+    let mut output = String::with_capacity(field.len());
+
+    for c in field.chars() {
+        match c {
+            ' ' => output.push_str("\\040"),
+            '\t' => output.push_str("\\011"),
+            '\n' => output.push_str("\\012"),
+            '\\' => output.push_str("\\134"),
+            c => output.push(c),
+        }
+    }
+
+    output
+}
+
+/// Reverse of `escape`; an escape is a backslash and exactly three octal digits of a byte,
+/// anything else is left alone. Escaped bytes that aren't UTF-8 are replaced.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let octal = bytes
+            .get(index + 1..index + 4)
+            .filter(|digits| digits.iter().all(|digit| (b'0'..=b'7').contains(digit)))
+            .map(|digits| {
+                digits
+                    .iter()
+                    .fold(0u32, |value, digit| value * 8 + u32::from(digit - b'0'))
+            })
+            .and_then(|value| u8::try_from(value).ok());
+
+        match octal {
+            Some(byte) if bytes[index] == b'\\' => {
+                output.push(byte);
+                index += 4;
+            }
+            _ => {
+                output.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// The source of a mount or the device of an encrypted volume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Uuid(String),
+    Label(String),
+    PartUuid(String),
+    PartLabel(String),
+
+    /// A path, or something that isn't a device at all such as `tmpfs` or `none`.
+    Path(String),
+}
+
+impl Source {
+    /// Is this a stable identifier that doesn't depend on device enumeration order?
+    pub fn is_stable(&self) -> bool {
+        !matches!(self, Self::Path(_))
+    }
+
+    /// Replace a path with a stable identifier known to `resolver`; UUIDs are preferred
+    /// over labels. Sources that already are stable are returned as-is.
+    pub fn resolve(&self, resolver: &dyn Resolver) -> Result<Self, FstabError> {
+        match self {
+            Self::Path(path) => resolver
+                .lookup(path)
+                .and_then(|device| device.stable_source())
+                .ok_or_else(|| FstabError::Unresolvable(path.clone())),
+            _ => Ok(self.clone()),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = FstabError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let source = match value.split_once('=') {
+            Some(("UUID", uuid)) => Self::Uuid(uuid.to_string()),
+            Some(("LABEL", label)) => Self::Label(label.to_string()),
+            Some(("PARTUUID", uuid)) => Self::PartUuid(uuid.to_string()),
+            Some(("PARTLABEL", label)) => Self::PartLabel(label.to_string()),
+            _ => Self::Path(value.to_string()),
+        };
+
+        Ok(source)
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Uuid(uuid) => write!(f, "UUID={}", uuid),
+            Self::Label(label) => write!(f, "LABEL={}", label),
+            Self::PartUuid(uuid) => write!(f, "PARTUUID={}", uuid),
+            Self::PartLabel(label) => write!(f, "PARTLABEL={}", label),
+            Self::Path(path) => write!(f, "{}", path),
+        }
+    }
+}
+
+/// What is known about a device; its path or name and its identifiers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Device {
+    pub name: String,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+    pub partuuid: Option<String>,
+    pub partlabel: Option<String>,
+}
+
+impl Device {
+    /// The most stable identifier for this device, if any.
+    pub fn stable_source(&self) -> Option<Source> {
+        self.uuid
+            .clone()
+            .map(Source::Uuid)
+            .or_else(|| self.label.clone().map(Source::Label))
+            .or_else(|| self.partuuid.clone().map(Source::PartUuid))
+            .or_else(|| self.partlabel.clone().map(Source::PartLabel))
+    }
+}
+
+/// Resolves device paths or names to what is known about the device. Whatever sets up
+/// devices for a build (and thus knows their identifiers) implements this.
+pub trait Resolver {
+    fn lookup(&self, name: &str) -> Option<&Device>;
+}
+
+/// A `Resolver` backed by a list of devices.
+#[derive(Debug, Clone, Default)]
+pub struct Devices(pub Vec<Device>);
+
+impl Resolver for Devices {
+    fn lookup(&self, name: &str) -> Option<&Device> {
+        self.0.iter().find(|device| device.name == name)
+    }
+}
+
+fn split_options(options: &str) -> Vec<String> {
+    options
+        .split(',')
+        .filter(|option| !option.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn join_options(options: &[String], default: &str) -> String {
+    if options.is_empty() {
+        default.to_string()
+    } else {
+        options.join(",")
+    }
+}
+
+/// A single line of `/etc/fstab`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FstabEntry {
+    pub source: Source,
+    pub target: String,
+    pub fstype: String,
+    pub options: Vec<String>,
+    pub freq: u32,
+    pub passno: u32,
+}
+
+impl FromStr for FstabEntry {
+    type Err = FstabError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let error = || FstabError::ParseError(0, line.to_string());
+
+        if !(3..=6).contains(&fields.len()) {
+            return Err(error());
+        }
+
+        Ok(Self {
+            source: unescape(fields[0]).parse()?,
+            target: unescape(fields[1]),
+            fstype: unescape(fields[2]),
+            options: fields.get(3).map(|o| split_options(o)).unwrap_or_default(),
+            freq: fields
+                .get(4)
+                .map_or(Ok(0), |f| f.parse())
+                .map_err(|_| error())?,
+            passno: fields
+                .get(5)
+                .map_or(Ok(0), |f| f.parse())
+                .map_err(|_| error())?,
+        })
+    }
+}
+
+impl fmt::Display for FstabEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {}",
+            escape(&self.source.to_string()),
+            escape(&self.target),
+            escape(&self.fstype),
+            join_options(&self.options, "defaults"),
+            self.freq,
+            self.passno
+        )
+    }
+}
+
+/// A single line of `/etc/crypttab`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrypttabEntry {
+    pub name: String,
+    pub device: Source,
+
+    /// The key file, `None` means the password is asked for interactively.
+    pub keyfile: Option<String>,
+
+    pub options: Vec<String>,
+}
+
+impl FromStr for CrypttabEntry {
+    type Err = FstabError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        if !(2..=4).contains(&fields.len()) {
+            return Err(FstabError::ParseError(0, line.to_string()));
+        }
+
+        Ok(Self {
+            name: unescape(fields[0]),
+            device: unescape(fields[1]).parse()?,
+            keyfile: fields
+                .get(2)
+                .filter(|keyfile| **keyfile != "none" && **keyfile != "-")
+                .map(|keyfile| unescape(keyfile)),
+            options: fields.get(3).map(|o| split_options(o)).unwrap_or_default(),
+        })
+    }
+}
+
+impl fmt::Display for CrypttabEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            escape(&self.name),
+            escape(&self.device.to_string()),
+            self.keyfile.as_deref().map_or("none".to_string(), escape)
+        )?;
+
+        if !self.options.is_empty() {
+            write!(f, " {}", self.options.join(","))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a table, skipping comments and empty lines. Errors carry the line number.
+pub fn parse<T: FromStr<Err = FstabError>>(data: &str) -> Result<Vec<T>, FstabError> {
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        })
+        .map(|(number, line)| {
+            line.parse().map_err(|err| match err {
+                FstabError::ParseError(_, line) => FstabError::ParseError(number + 1, line),
+                err => err,
+            })
+        })
+        .collect()
+}
+
+/// Render a table, one entry per line.
+pub fn render<T: fmt::Display>(entries: &[T]) -> String {
+    entries.iter().map(|entry| format!("{}\n", entry)).collect()
+}
+
+/// Write the fstab for `tree`, device paths are resolved to stable identifiers first. The
+/// sources of bind mounts are directories, and are left as they are.
+pub fn write_fstab(
+    tree: &Path,
+    entries: &[FstabEntry],
+    resolver: &dyn Resolver,
+) -> Result<PathBuf, FstabError> {
+    let mut resolved = Vec::with_capacity(entries.len());
+
+    for entry in entries {
+        let mut entry = entry.clone();
+
+        let bind = entry
+            .options
+            .iter()
+            .any(|option| option == "bind" || option == "rbind");
+
+        // only block devices are resolved, `tmpfs` and friends stay as they are
+        if let Source::Path(path) = &entry.source {
+            if !bind && (path.starts_with("/dev/") || resolver.lookup(path).is_some()) {
+                entry.source = entry.source.resolve(resolver)?;
+            }
+        }
+
+        resolved.push(entry);
+    }
+
+    write_table(tree, FSTAB_PATH, &resolved)
+}
+
+/// Write the crypttab for `tree`, device paths are resolved to stable identifiers first.
+pub fn write_crypttab(
+    tree: &Path,
+    entries: &[CrypttabEntry],
+    resolver: &dyn Resolver,
+) -> Result<PathBuf, FstabError> {
+    let mut resolved = Vec::with_capacity(entries.len());
+
+    for entry in entries {
+        let mut entry = entry.clone();
+        entry.device = entry.device.resolve(resolver)?;
+        resolved.push(entry);
+    }
+
+    write_table(tree, CRYPTTAB_PATH, &resolved)
+}
+
+fn write_table<T: fmt::Display>(
+    tree: &Path,
+    path: &str,
+    entries: &[T],
+) -> Result<PathBuf, FstabError> {
+    Ok(TreePath::new(tree).write(path, render(entries), 0o644)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    fn devices() -> Devices {
+        Devices(vec![
+            Device {
+                name: "/dev/vda3".to_string(),
+                uuid: Some("1234".to_string()),
+                label: Some("root".to_string()),
+                ..Default::default()
+            },
+            Device {
+                name: "boot".to_string(),
+                label: Some("boot".to_string()),
+                ..Default::default()
+            },
+            Device {
+                name: "/dev/vda9".to_string(),
+                ..Default::default()
+            },
+        ])
+    }
+
+    #[test]
+    fn escape_roundtrip() {
+        assert_eq!(escape("/mnt/my disk"), "/mnt/my\\040disk");
+        assert_eq!(unescape("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape("\\x"), "\\x");
+        assert_eq!(unescape("/mnt/caf\\303\\251"), "/mnt/caf\u{e9}");
+        assert_eq!(unescape("/mnt/déjà\\040vu"), "/mnt/déjà vu");
+        assert_eq!(unescape("\\+12\\01\\400"), "\\+12\\01\\400");
+        assert_eq!(unescape(&escape("a\\b\tc")), "a\\b\tc");
+    }
+
+    #[test]
+    fn fstab_parse_and_render() {
+        let entries: Vec<FstabEntry> = parse(
+            "# comment\n\nUUID=1234 / ext4 defaults,x-systemd.growfs 1 1\ntmpfs /tmp tmpfs\n/dev/sdb1 /mnt/my\\040disk xfs noauto 0 2\n",
+        )
+        .unwrap();
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].source, Source::Uuid("1234".to_string()));
+        assert_eq!(entries[1].options, Vec::<String>::new());
+        assert_eq!(entries[2].target, "/mnt/my disk");
+
+        assert_eq!(
+            render(&entries),
+            "UUID=1234 / ext4 defaults,x-systemd.growfs 1 1\ntmpfs /tmp tmpfs defaults 0 0\n/dev/sdb1 /mnt/my\\040disk xfs noauto 0 2\n"
+        );
+    }
+
+    #[test]
+    fn fstab_parse_error_line() {
+        assert!(matches!(
+            parse::<FstabEntry>("# comment\n/ /\n"),
+            Err(FstabError::ParseError(2, _))
+        ));
+        assert!(parse::<FstabEntry>("/ / ext4 defaults x 0\n").is_err());
+    }
+
+    #[test]
+    fn crypttab_parse_and_render() {
+        let entries: Vec<CrypttabEntry> =
+            parse("luks-1 UUID=abcd none discard\nswap /dev/vdb2 /dev/urandom swap,cipher=aes\nhome /dev/vdb3\n").unwrap();
+
+        assert_eq!(entries[0].keyfile, None);
+        assert_eq!(entries[1].keyfile.as_deref(), Some("/dev/urandom"));
+        assert_eq!(
+            render(&entries),
+            "luks-1 UUID=abcd none discard\nswap /dev/vdb2 /dev/urandom swap,cipher=aes\nhome /dev/vdb3 none\n"
+        );
+    }
+
+    #[test]
+    fn source_resolve() {
+        let devices = devices();
+
+        assert_eq!(
+            Source::Path("/dev/vda3".to_string())
+                .resolve(&devices)
+                .unwrap(),
+            Source::Uuid("1234".to_string())
+        );
+        assert_eq!(
+            Source::Path("boot".to_string()).resolve(&devices).unwrap(),
+            Source::Label("boot".to_string())
+        );
+        assert_eq!(
+            Source::Label("x".to_string()).resolve(&devices).unwrap(),
+            Source::Label("x".to_string())
+        );
+        assert!(matches!(
+            Source::Path("/dev/vda9".to_string()).resolve(&devices),
+            Err(FstabError::Unresolvable(_))
+        ));
+    }
+
+    #[test]
+    fn write_tables() {
+        let tree = tempfile::tempdir().unwrap();
+        let devices = devices();
+
+        let fstab = vec![
+            "/dev/vda3 / ext4".parse::<FstabEntry>().unwrap(),
+            "tmpfs /tmp tmpfs".parse::<FstabEntry>().unwrap(),
+            "/srv /var/srv none bind".parse::<FstabEntry>().unwrap(),
+        ];
+
+        write_fstab(tree.path(), &fstab, &devices).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(tree.path().join(FSTAB_PATH)).unwrap(),
+            "UUID=1234 / ext4 defaults 0 0\ntmpfs /tmp tmpfs defaults 0 0\n/srv /var/srv none bind 0 0\n"
+        );
+
+        let crypttab = vec!["root /dev/vda3".parse::<CrypttabEntry>().unwrap()];
+
+        write_crypttab(tree.path(), &crypttab, &devices).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(tree.path().join(CRYPTTAB_PATH)).unwrap(),
+            "root UUID=1234 none\n"
+        );
+    }
+}
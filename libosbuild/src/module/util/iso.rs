@@ -0,0 +1,309 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The GPT type of the EFI system partition, which the EFI boot image is appended as.
+pub const EFI_SYSTEM_PARTITION: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+
+/// The GPT type of basic data partitions, which the ISO 9660 filesystem is marked as.
+pub const BASIC_DATA_PARTITION: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+
+#[derive(Debug)]
+pub enum IsoError {
+    /// A tool failed, contains its name, its exit code, and its stderr.
+    Failed(String, Option<i32>, String),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for IsoError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// Run `command`, failures are those of `tool`.
+fn run(mut command: Command, tool: &str) -> Result<(), IsoError> {
+    let output = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(IsoError::Failed(
+            tool.to_string(),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The El Torito boot image BIOS firmware boots, paths are relative to the tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BiosBoot {
+    /// The boot image, such as `isolinux/isolinux.bin` or `images/eltorito.img`.
+    pub image: PathBuf,
+
+    /// The boot catalog xorrisofs writes, such as `isolinux/boot.cat`.
+    pub catalog: PathBuf,
+}
+
+/// Builds a bootable ISO of a tree with xorrisofs, as the `org.osbuild.xorrisofs` stage does.
+/// Media boot on BIOS through an El Torito image, on EFI through an EFI system partition
+/// image, or both.
+#[derive(Debug, Clone)]
+pub struct Xorrisofs {
+    pub xorrisofs: PathBuf,
+
+    /// The volume id, which installers find their media by, such as `Fedora-41-x86_64`.
+    pub volid: String,
+    pub sysid: Option<String>,
+
+    /// The ISO 9660 interchange level, 1 to 4.
+    pub isolevel: Option<u8>,
+
+    pub bios: Option<BiosBoot>,
+
+    /// The image of the EFI system partition, relative to the tree, such as
+    /// `images/efiboot.img`.
+    pub efi: Option<PathBuf>,
+
+    /// The MBR syslinux makes media bootable from USB sticks with, such as
+    /// `/usr/share/syslinux/isohdpfx.bin`.
+    pub isohybrid_mbr: Option<PathBuf>,
+
+    /// The MBR grub2 makes media bootable from USB sticks with, such as
+    /// `/usr/lib/grub/i386-pc/boot_hybrid.img`. The EFI image is appended to the media as a
+    /// partition of its own so firmware finds it through the partition table.
+    pub grub2_mbr: Option<PathBuf>,
+}
+
+impl Xorrisofs {
+    pub fn new(volid: &str) -> Self {
+        Self {
+            xorrisofs: PathBuf::from("xorrisofs"),
+            volid: volid.to_string(),
+            sysid: None,
+            isolevel: None,
+            bios: None,
+            efi: None,
+            isohybrid_mbr: None,
+            grub2_mbr: None,
+        }
+    }
+
+    /// The xorrisofs command that writes the media of `tree` to `path`.
+    pub fn command(&self, tree: &Path, path: &Path) -> Command {
+        let mut command = Command::new(&self.xorrisofs);
+
+        command.arg("-verbose");
+
+        if let Some(isolevel) = self.isolevel {
+            command.arg("-iso-level").arg(isolevel.to_string());
+        }
+
+        command.arg("-V").arg(&self.volid);
+
+        if let Some(sysid) = &self.sysid {
+            command.arg("-sysid").arg(sysid);
+        }
+
+        if let Some(mbr) = &self.isohybrid_mbr {
+            command.arg("-isohybrid-mbr").arg(mbr);
+        }
+
+        if let Some(mbr) = &self.grub2_mbr {
+            command.arg("--grub2-mbr").arg(mbr).args([
+                "-partition_offset",
+                "16",
+                "-appended_part_as_gpt",
+            ]);
+
+            if let Some(efi) = &self.efi {
+                command
+                    .args(["-append_partition", "2", EFI_SYSTEM_PARTITION])
+                    .arg(tree.join(efi));
+            }
+
+            command.args(["-iso_mbr_part_type", BASIC_DATA_PARTITION]);
+        }
+
+        if let Some(bios) = &self.bios {
+            command
+                .arg("-b")
+                .arg(&bios.image)
+                .arg("-c")
+                .arg(&bios.catalog)
+                .args(["-boot-load-size", "4", "-boot-info-table", "-no-emul-boot"]);
+
+            if self.grub2_mbr.is_some() {
+                command.arg("--grub2-boot-info");
+            }
+        }
+
+        if let Some(efi) = &self.efi {
+            command.args(["-eltorito-alt-boot", "-e"]);
+
+            // with a grub2 MBR the EFI image is the appended partition rather than a file
+            if self.grub2_mbr.is_some() {
+                command.arg("--interval:appended_partition_2:all::");
+            } else {
+                command.arg(efi);
+            }
+
+            command.args(["-no-emul-boot", "-isohybrid-gpt-basdat"]);
+        }
+
+        command.arg("-o").arg(path).arg(tree);
+        command
+    }
+
+    /// Write the media of `tree` to `path`.
+    pub fn build(&self, tree: &Path, path: &Path) -> Result<(), IsoError> {
+        run(self.command(tree, path), "xorrisofs")
+    }
+}
+
+/// Implants the MD5 checksum of media into them with `implantisomd5`, so installers can check
+/// their media with `checkisomd5` or `rd.live.check`.
+#[derive(Debug, Clone)]
+pub struct ImplantIsoMd5 {
+    pub implantisomd5: PathBuf,
+
+    /// Implant the checksum into media that already have one.
+    pub force: bool,
+
+    /// Leave out the checksum of the last 300 sectors, which are padding on older media.
+    pub supported_iso: bool,
+}
+
+impl ImplantIsoMd5 {
+    pub fn new() -> Self {
+        Self {
+            implantisomd5: PathBuf::from("implantisomd5"),
+            force: false,
+            supported_iso: false,
+        }
+    }
+
+    /// The implantisomd5 command that implants the checksum of the media at `path`.
+    pub fn command(&self, path: &Path) -> Command {
+        let mut command = Command::new(&self.implantisomd5);
+
+        if self.force {
+            command.arg("--force");
+        }
+
+        if self.supported_iso {
+            command.arg("--supported-iso");
+        }
+
+        command.arg(path);
+        command
+    }
+
+    /// Implant the checksum of the media at `path`.
+    pub fn implant(&self, path: &Path) -> Result<(), IsoError> {
+        run(self.command(path), "implantisomd5")
+    }
+}
+
+impl Default for ImplantIsoMd5 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn arguments(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn media_built() {
+        let xorrisofs = Xorrisofs {
+            isolevel: Some(3),
+            bios: Some(BiosBoot {
+                image: PathBuf::from("images/eltorito.img"),
+                catalog: PathBuf::from("boot.catalog"),
+            }),
+            efi: Some(PathBuf::from("images/efiboot.img")),
+            grub2_mbr: Some(PathBuf::from("/usr/lib/grub/i386-pc/boot_hybrid.img")),
+            ..Xorrisofs::new("Fedora-41-x86_64")
+        };
+
+        let args =
+            arguments(&xorrisofs.command(Path::new("/tree"), Path::new("/out/installer.iso")));
+
+        assert_eq!(
+            args[..5],
+            ["-verbose", "-iso-level", "3", "-V", "Fedora-41-x86_64"]
+        );
+        assert!(args.windows(5).any(|w| w
+            == [
+                "-append_partition",
+                "2",
+                EFI_SYSTEM_PARTITION,
+                "/tree/images/efiboot.img",
+                "-iso_mbr_part_type"
+            ]));
+        assert!(args.windows(3).any(|w| w
+            == [
+                "-e",
+                "--interval:appended_partition_2:all::",
+                "-no-emul-boot"
+            ]));
+        assert!(args.contains(&"--grub2-boot-info".to_string()));
+        assert_eq!(
+            args[args.len() - 3..],
+            ["-o", "/out/installer.iso", "/tree"]
+        );
+
+        // without a grub2 MBR the EFI image is a file in the tree
+        let xorrisofs = Xorrisofs {
+            efi: Some(PathBuf::from("images/efiboot.img")),
+            isohybrid_mbr: Some(PathBuf::from("/usr/share/syslinux/isohdpfx.bin")),
+            ..Xorrisofs::new("RHEL-9")
+        };
+
+        let args =
+            arguments(&xorrisofs.command(Path::new("/tree"), Path::new("/out/installer.iso")));
+
+        assert!(args
+            .windows(3)
+            .any(|w| w == ["-e", "images/efiboot.img", "-no-emul-boot"]));
+        assert!(!args.iter().any(|arg| arg == "-append_partition"));
+    }
+
+    #[test]
+    fn checksums_implanted() {
+        let implant = ImplantIsoMd5 {
+            force: true,
+            ..ImplantIsoMd5::new()
+        };
+
+        assert_eq!(
+            arguments(&implant.command(Path::new("/out/installer.iso"))),
+            ["--force", "/out/installer.iso"]
+        );
+
+        let failing = ImplantIsoMd5 {
+            implantisomd5: PathBuf::from("false"),
+            ..ImplantIsoMd5::new()
+        };
+
+        assert!(matches!(
+            failing.implant(Path::new("/out/installer.iso")),
+            Err(IsoError::Failed(tool, Some(1), _)) if tool == "implantisomd5"
+        ));
+    }
+}
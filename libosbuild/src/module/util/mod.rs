@@ -0,0 +1,62 @@
+/// Helpers for stages that manipulate systemd units inside a tree; enabling, disabling, and
+/// masking units by creating the right symlinks and writing drop-ins.
+#[cfg(unix)]
+pub mod systemd;
+
+/// Editing of the user and group databases (`/etc/passwd`, `/etc/group`, `/etc/shadow`) in a
+/// tree without needing a chroot or the shadow-utils binaries.
+#[cfg(unix)]
+pub mod passwd;
+
+/// Kernel and bootloader helpers; finding the kernels installed in a tree, writing Boot Loader
+/// Specification entries, and rendering grub2 configuration.
+pub mod boot;
+
+/// Querying the rpm database of a built tree for the installed packages.
+#[cfg(feature = "rpmdb")]
+pub mod rpmdb;
+
+/// Crash-safe writing of files; write a temporary file, sync it, and rename it into place.
+#[cfg(unix)]
+pub mod atomic;
+
+#[cfg(unix)]
+pub use atomic::{atomic_write, atomic_write_all};
+
+/// A small template engine for stages that generate configuration files from their options.
+pub mod template;
+
+/// Joining paths from manifests onto trees without them being able to escape the tree,
+/// which every helper that writes into a tree goes through.
+pub mod tree;
+
+/// Writing trees into tar archives in the pax format, byte for byte the same every time.
+#[cfg(unix)]
+pub mod tar;
+
+/// Container images; OCI image layouts and archives, and docker archives, of trees.
+#[cfg(unix)]
+pub mod oci;
+
+/// Bootable ISOs of trees for installer media, with xorrisofs and implantisomd5.
+pub mod iso;
+
+/// Sizes of disks, partitions, and filesystems in options; bytes, or numbers with units
+/// such as `2 GiB`.
+pub mod size;
+
+/// Raw disk images; writing GPT and DOS partition tables, making filesystems on partitions,
+/// and copying trees into them.
+pub mod disk;
+
+/// LUKS containers and LVM volume groups on block devices, and tearing them down again in
+/// the reverse of the order they were set up in.
+pub mod volume;
+
+/// Installing bootloaders into disk images, with grub2-install or bootupd run from the tree on
+/// its partitions mounted from a loop device.
+pub mod bootloader;
+
+/// Typed `/etc/fstab` and `/etc/crypttab` entries, with resolution of device paths to stable
+/// identifiers.
+pub mod fstab;
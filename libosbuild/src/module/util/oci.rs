@@ -0,0 +1,502 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::module::util::tar::{Header, TarWriter};
+
+pub const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const MEDIA_TYPE_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+
+/// The annotation that names the image in the index of a layout.
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// The version of the image layout specification layouts are written in.
+pub const LAYOUT_VERSION: &str = "1.0.0";
+
+#[derive(Debug)]
+pub enum OciError {
+    SerializeError(serde_json::Error),
+    IOError(io::Error),
+}
+
+impl From<io::Error> for OciError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+impl From<serde_json::Error> for OciError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::SerializeError(err)
+    }
+}
+
+/// What an image is archived as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// An OCI image layout in a tar archive, `oci-archive:` to skopeo and podman.
+    #[default]
+    Oci,
+
+    /// The format of `docker save`, `docker-archive:` to skopeo and podman.
+    Docker,
+}
+
+/// A reference to a blob of an image; its media type, digest, and size.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl Descriptor {
+    /// The hex of the digest, the name of the blob in a layout.
+    pub fn hex(&self) -> &str {
+        self.digest.strip_prefix("sha256:").unwrap_or(&self.digest)
+    }
+}
+
+/// Serialize a list as the object with the items as keys and empty objects as values, which
+/// is how the image configuration keeps sets such as `ExposedPorts`.
+fn as_set<S: Serializer>(items: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    let set: BTreeMap<&str, BTreeMap<(), ()>> = items
+        .iter()
+        .map(|item| (item.as_str(), BTreeMap::new()))
+        .collect();
+
+    set.serialize(serializer)
+}
+
+/// How containers of the image run by default, the `config` of the image configuration. It
+/// is read as the Python `org.osbuild.oci-archive` stage takes it, with lists for the sets.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct RuntimeConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "as_set"
+    )]
+    pub exposed_ports: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entrypoint: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cmd: Vec<String>,
+
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "as_set"
+    )]
+    pub volumes: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<String>,
+}
+
+/// What goes into an image besides the tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageOptions {
+    /// The architecture in the terms of Go, such as `amd64` or `arm64`.
+    pub architecture: String,
+
+    pub config: RuntimeConfig,
+
+    /// Annotations of the manifest.
+    pub annotations: BTreeMap<String, String>,
+
+    /// The name of the image, such as `quay.io/org/image:latest`.
+    pub reference: Option<String>,
+
+    /// Clamp the modification times in the layer to `SOURCE_DATE_EPOCH`, it is the creation
+    /// time of the image as well.
+    pub source_date_epoch: Option<u64>,
+}
+
+/// The time `epoch` in RFC 3339, in UTC.
+pub fn rfc3339(epoch: u64) -> String {
+    let days = (epoch / 86400) as i64;
+    let seconds = epoch % 86400;
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Passes writes through, hashing and counting them.
+struct Hashing<W: Write> {
+    out: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(data)?;
+
+        self.hasher.update(&data[..written]);
+        self.size += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+/// Write `data` as a blob of `layout`.
+fn write_blob(layout: &Path, media_type: &str, data: &[u8]) -> io::Result<Descriptor> {
+    let descriptor = Descriptor {
+        media_type: media_type.to_string(),
+        digest: digest(data),
+        size: data.len() as u64,
+        annotations: BTreeMap::new(),
+    };
+
+    fs::write(blob_path(layout, &descriptor), data)?;
+
+    Ok(descriptor)
+}
+
+/// The path of the blob of `descriptor` in `layout`.
+pub fn blob_path(layout: &Path, descriptor: &Descriptor) -> PathBuf {
+    layout.join("blobs/sha256").join(descriptor.hex())
+}
+
+/// An image of `tree` with a single layer, as an OCI image layout in the directory `layout`.
+/// The layer is an uncompressed tar archive of the tree, so its digest is its diff id too.
+/// Returns the descriptor of the manifest.
+pub fn write_layout(
+    tree: &Path,
+    layout: &Path,
+    options: &ImageOptions,
+) -> Result<Descriptor, OciError> {
+    let blobs = layout.join("blobs/sha256");
+    fs::create_dir_all(&blobs)?;
+
+    // the digest of the layer is its name, so it is written under another one first
+    let partial = blobs.join(".layer");
+
+    let mut tar = TarWriter::new(Hashing {
+        out: io::BufWriter::new(fs::File::create(&partial)?),
+        hasher: Sha256::new(),
+        size: 0,
+    })
+    .clamp_mtime(options.source_date_epoch);
+
+    tar.append_tree(tree)?;
+
+    let hashing = tar.finish()?;
+
+    let layer = Descriptor {
+        media_type: MEDIA_TYPE_LAYER.to_string(),
+        digest: format!("sha256:{:x}", hashing.hasher.finalize()),
+        size: hashing.size,
+        annotations: BTreeMap::new(),
+    };
+
+    fs::rename(&partial, blob_path(layout, &layer))?;
+
+    let mut config = serde_json::json!({
+        "architecture": options.architecture,
+        "os": "linux",
+        "config": options.config,
+        "rootfs": {"type": "layers", "diff_ids": [layer.digest]},
+        "history": [{"created_by": "osbuild"}],
+    });
+
+    if let Some(epoch) = options.source_date_epoch {
+        config["created"] = rfc3339(epoch).into();
+        config["history"][0]["created"] = rfc3339(epoch).into();
+    }
+
+    let config = write_blob(layout, MEDIA_TYPE_CONFIG, &serde_json::to_vec(&config)?)?;
+
+    let mut manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": MEDIA_TYPE_MANIFEST,
+        "config": config,
+        "layers": [layer],
+    });
+
+    if !options.annotations.is_empty() {
+        manifest["annotations"] = serde_json::to_value(&options.annotations)?;
+    }
+
+    let mut manifest = write_blob(layout, MEDIA_TYPE_MANIFEST, &serde_json::to_vec(&manifest)?)?;
+
+    if let Some(reference) = &options.reference {
+        manifest
+            .annotations
+            .insert(ANNOTATION_REF_NAME.to_string(), reference.clone());
+    }
+
+    fs::write(
+        layout.join("index.json"),
+        serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": MEDIA_TYPE_INDEX,
+            "manifests": [manifest],
+        }))?,
+    )?;
+
+    fs::write(
+        layout.join("oci-layout"),
+        serde_json::to_vec(&serde_json::json!({"imageLayoutVersion": LAYOUT_VERSION}))?,
+    )?;
+
+    Ok(manifest)
+}
+
+/// An image of `tree` as an archive in `format` at `path`, see `write_layout`. The layout is
+/// staged in a directory next to `path` which is removed again.
+pub fn write_archive(
+    tree: &Path,
+    path: &Path,
+    options: &ImageOptions,
+    format: ArchiveFormat,
+) -> Result<Descriptor, OciError> {
+    let mut staging = path.as_os_str().to_os_string();
+    staging.push(".layout");
+
+    let staging = PathBuf::from(staging);
+
+    let result = write_layout(tree, &staging, options)
+        .and_then(|manifest| archive_layout(&staging, path, &manifest, options, format));
+
+    fs::remove_dir_all(&staging)?;
+
+    result
+}
+
+fn archive_layout(
+    layout: &Path,
+    path: &Path,
+    manifest: &Descriptor,
+    options: &ImageOptions,
+    format: ArchiveFormat,
+) -> Result<Descriptor, OciError> {
+    let mut tar = TarWriter::new(io::BufWriter::new(fs::File::create(path)?))
+        .clamp_mtime(Some(options.source_date_epoch.unwrap_or(0)));
+
+    match format {
+        ArchiveFormat::Oci => {
+            tar.append_data("oci-layout", &fs::read(layout.join("oci-layout"))?)?;
+            tar.append_data("index.json", &fs::read(layout.join("index.json"))?)?;
+            tar.write_header(&Header::directory("blobs"))?;
+            tar.write_header(&Header::directory("blobs/sha256"))?;
+
+            let mut blobs: Vec<PathBuf> = fs::read_dir(layout.join("blobs/sha256"))?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<_, _>>()?;
+
+            blobs.sort();
+
+            for blob in blobs {
+                let name = blob.file_name().unwrap_or_default().to_string_lossy();
+                let size = fs::metadata(&blob)?.len();
+
+                tar.write_header(&Header::file(&format!("blobs/sha256/{}", name), size))?;
+                tar.write_data(fs::File::open(&blob)?, size)?;
+            }
+        }
+        ArchiveFormat::Docker => {
+            let image: serde_json::Value =
+                serde_json::from_slice(&fs::read(blob_path(layout, manifest))?)?;
+
+            let config: Descriptor = serde_json::from_value(image["config"].clone())?;
+            let layers: Vec<Descriptor> = serde_json::from_value(image["layers"].clone())?;
+
+            let config_name = format!("{}.json", config.hex());
+
+            tar.append_data(&config_name, &fs::read(blob_path(layout, &config))?)?;
+
+            let mut names = vec![];
+
+            for layer in &layers {
+                let name = format!("{}/layer.tar", layer.hex());
+
+                tar.write_header(&Header::directory(layer.hex()))?;
+                tar.write_header(&Header::file(&name, layer.size))?;
+                tar.write_data(fs::File::open(blob_path(layout, layer))?, layer.size)?;
+
+                names.push(name);
+            }
+
+            tar.append_data(
+                "manifest.json",
+                &serde_json::to_vec(&serde_json::json!([{
+                    "Config": config_name,
+                    "RepoTags": options.reference.iter().collect::<Vec<_>>(),
+                    "Layers": names,
+                }]))?,
+            )?;
+        }
+    }
+
+    tar.finish()?;
+
+    Ok(manifest.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::process::Command;
+
+    fn tree(directory: &Path) -> PathBuf {
+        let tree = directory.join("tree");
+
+        fs::create_dir_all(tree.join("etc")).unwrap();
+        fs::write(tree.join("etc/hostname"), "container\n").unwrap();
+
+        tree
+    }
+
+    fn options() -> ImageOptions {
+        ImageOptions {
+            architecture: "amd64".to_string(),
+            config: RuntimeConfig {
+                cmd: vec!["/bin/sh".to_string()],
+                exposed_ports: vec!["80/tcp".to_string()],
+                ..Default::default()
+            },
+            reference: Some("localhost/image:latest".to_string()),
+            source_date_epoch: Some(1700000000),
+            ..Default::default()
+        }
+    }
+
+    fn read_json(path: &Path) -> serde_json::Value {
+        serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn times_formatted() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1700000000), "2023-11-14T22:13:20Z");
+        assert_eq!(rfc3339(951782400), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn layouts_written() {
+        let directory = tempfile::tempdir().unwrap();
+        let layout = directory.path().join("layout");
+
+        let manifest = write_layout(&tree(directory.path()), &layout, &options()).unwrap();
+
+        let index = read_json(&layout.join("index.json"));
+
+        assert_eq!(index["manifests"][0]["digest"], manifest.digest);
+        assert_eq!(
+            index["manifests"][0]["annotations"][ANNOTATION_REF_NAME],
+            "localhost/image:latest"
+        );
+        assert_eq!(
+            read_json(&layout.join("oci-layout"))["imageLayoutVersion"],
+            LAYOUT_VERSION
+        );
+
+        // every blob is named after its digest
+        for entry in fs::read_dir(layout.join("blobs/sha256")).unwrap() {
+            let path = entry.unwrap().path();
+
+            assert_eq!(
+                digest(&fs::read(&path).unwrap()),
+                format!("sha256:{}", path.file_name().unwrap().to_string_lossy())
+            );
+        }
+
+        let image = read_json(&blob_path(&layout, &manifest));
+        let config: Descriptor = serde_json::from_value(image["config"].clone()).unwrap();
+        let config = read_json(&blob_path(&layout, &config));
+
+        assert_eq!(
+            config["rootfs"]["diff_ids"][0],
+            image["layers"][0]["digest"]
+        );
+        assert_eq!(config["created"], "2023-11-14T22:13:20Z");
+        assert_eq!(
+            config["config"],
+            serde_json::json!({"Cmd": ["/bin/sh"], "ExposedPorts": {"80/tcp": {}}})
+        );
+    }
+
+    #[test]
+    fn archives_written() {
+        let directory = tempfile::tempdir().unwrap();
+        let tree = tree(directory.path());
+
+        for (format, listed) in [
+            (ArchiveFormat::Oci, "index.json"),
+            (ArchiveFormat::Docker, "manifest.json"),
+        ] {
+            let path = directory.path().join("image.tar");
+            let manifest = write_archive(&tree, &path, &options(), format).unwrap();
+
+            // the same tree makes the same image
+            let again = directory.path().join("again.tar");
+
+            assert_eq!(
+                write_archive(&tree, &again, &options(), format).unwrap(),
+                manifest
+            );
+            assert_eq!(fs::read(&path).unwrap(), fs::read(&again).unwrap());
+            assert!(!directory.path().join("image.tar.layout").exists());
+
+            let output = Command::new("tar").arg("-tf").arg(&path).output().unwrap();
+            let names = String::from_utf8(output.stdout).unwrap();
+
+            assert!(names.lines().any(|name| name == listed), "{}", names);
+        }
+    }
+}
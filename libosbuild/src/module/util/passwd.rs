@@ -0,0 +1,770 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::module::util::atomic_write_all;
+use crate::module::util::tree::{TreeError, TreePath};
+
+/// The files in a tree that make up the user and group databases.
+pub const PASSWD_PATH: &str = "etc/passwd";
+pub const GROUP_PATH: &str = "etc/group";
+pub const SHADOW_PATH: &str = "etc/shadow";
+
+/// The first uid and gid handed out to regular users and groups by `useradd` on Fedora and
+/// derived distributions.
+pub const UID_MIN: u32 = 1000;
+pub const GID_MIN: u32 = 1000;
+
+#[derive(Debug)]
+pub enum PasswdError {
+    /// A line in one of the database files could not be parsed.
+    ParseError {
+        path: PathBuf,
+        line: usize,
+    },
+
+    /// Another process holds the lock for one of the database files.
+    Locked(PathBuf),
+
+    /// A field contains characters that can't be stored in the database (`:` or newlines).
+    InvalidField(String),
+
+    NoSuchUser(String),
+    NoSuchGroup(String),
+    UserExists(String),
+    GroupExists(String),
+
+    /// The databases disagree with each other, for example a user without a shadow entry or
+    /// a primary group that doesn't exist.
+    Inconsistent(String),
+
+    TreeError(TreeError),
+    IOError(io::Error),
+}
+
+impl From<TreeError> for PasswdError {
+    fn from(err: TreeError) -> Self {
+        Self::TreeError(err)
+    }
+}
+
+impl From<io::Error> for PasswdError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+fn check_field(value: &str) -> Result<(), PasswdError> {
+    if value.contains(':') || value.contains('\n') {
+        Err(PasswdError::InvalidField(value.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+fn parse_optional<T: FromStr>(value: &str) -> Result<Option<T>, ()> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        value.parse().map(Some).map_err(|_| ())
+    }
+}
+
+fn fmt_optional<T: fmt::Display>(value: &Option<T>) -> String {
+    value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// A single line of `/etc/passwd`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswdEntry {
+    pub name: String,
+    pub password: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub gecos: String,
+    pub home: String,
+    pub shell: String,
+}
+
+impl FromStr for PasswdEntry {
+    type Err = ();
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = line.split(':').collect();
+
+        if fields.len() != 7 {
+            return Err(());
+        }
+
+        Ok(Self {
+            name: fields[0].to_string(),
+            password: fields[1].to_string(),
+            uid: fields[2].parse().map_err(|_| ())?,
+            gid: fields[3].parse().map_err(|_| ())?,
+            gecos: fields[4].to_string(),
+            home: fields[5].to_string(),
+            shell: fields[6].to_string(),
+        })
+    }
+}
+
+impl fmt::Display for PasswdEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}:{}:{}:{}",
+            self.name, self.password, self.uid, self.gid, self.gecos, self.home, self.shell
+        )
+    }
+}
+
+/// A single line of `/etc/group`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupEntry {
+    pub name: String,
+    pub password: String,
+    pub gid: u32,
+    pub members: Vec<String>,
+}
+
+impl FromStr for GroupEntry {
+    type Err = ();
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = line.split(':').collect();
+
+        if fields.len() != 4 {
+            return Err(());
+        }
+
+        Ok(Self {
+            name: fields[0].to_string(),
+            password: fields[1].to_string(),
+            gid: fields[2].parse().map_err(|_| ())?,
+            members: fields[3]
+                .split(',')
+                .filter(|member| !member.is_empty())
+                .map(String::from)
+                .collect(),
+        })
+    }
+}
+
+impl fmt::Display for GroupEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.name,
+            self.password,
+            self.gid,
+            self.members.join(",")
+        )
+    }
+}
+
+/// A single line of `/etc/shadow`. Empty numeric fields are represented as `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShadowEntry {
+    pub name: String,
+    pub password: String,
+    pub last_change: Option<u64>,
+    pub min_age: Option<u64>,
+    pub max_age: Option<u64>,
+    pub warn_period: Option<u64>,
+    pub inactivity_period: Option<u64>,
+    pub expiration: Option<u64>,
+    pub reserved: String,
+}
+
+impl ShadowEntry {
+    /// A locked shadow entry for `name`, this is what `useradd` writes for users that are
+    /// created without a password.
+    pub fn locked(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            password: "!!".to_string(),
+            last_change: None,
+            min_age: None,
+            max_age: None,
+            warn_period: None,
+            inactivity_period: None,
+            expiration: None,
+            reserved: "".to_string(),
+        }
+    }
+}
+
+impl FromStr for ShadowEntry {
+    type Err = ();
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = line.split(':').collect();
+
+        if fields.len() != 9 {
+            return Err(());
+        }
+
+        Ok(Self {
+            name: fields[0].to_string(),
+            password: fields[1].to_string(),
+            last_change: parse_optional(fields[2])?,
+            min_age: parse_optional(fields[3])?,
+            max_age: parse_optional(fields[4])?,
+            warn_period: parse_optional(fields[5])?,
+            inactivity_period: parse_optional(fields[6])?,
+            expiration: parse_optional(fields[7])?,
+            reserved: fields[8].to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ShadowEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}:{}:{}:{}:{}:{}",
+            self.name,
+            self.password,
+            fmt_optional(&self.last_change),
+            fmt_optional(&self.min_age),
+            fmt_optional(&self.max_age),
+            fmt_optional(&self.warn_period),
+            fmt_optional(&self.inactivity_period),
+            fmt_optional(&self.expiration),
+            self.reserved,
+        )
+    }
+}
+
+/// The properties of a user to add, fields that are `None` are derived the way `useradd`
+/// would derive them.
+#[derive(Debug, Clone, Default)]
+pub struct User {
+    pub name: String,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub gecos: Option<String>,
+    pub home: Option<String>,
+    pub shell: Option<String>,
+
+    /// An already hashed password (`crypt(3)` format), a locked password is used if unset.
+    pub password: Option<String>,
+
+    /// Supplementary groups to add the user to.
+    pub groups: Vec<String>,
+}
+
+/// A lock on a single database file, following the shadow-utils convention of a `.lock`
+/// file next to the file being edited. The lock is released when dropped.
+struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    fn acquire(path: &Path) -> Result<Self, PasswdError> {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".lock");
+
+        let path = PathBuf::from(name);
+
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(_) => Ok(Self { path }),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                Err(PasswdError::Locked(path))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn read_entries<T: FromStr>(path: &Path) -> Result<Vec<T>, PasswdError> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(number, line)| {
+            line.parse().map_err(|_| PasswdError::ParseError {
+                path: path.to_path_buf(),
+                line: number + 1,
+            })
+        })
+        .collect()
+}
+
+/// The contents and mode to write `entries` to `path` with. The mode of an existing file is
+/// kept, new files get `mode`.
+fn render_entries<T: fmt::Display>(
+    path: PathBuf,
+    entries: &[T],
+    mode: u32,
+) -> (PathBuf, String, u32) {
+    let mode = match fs::metadata(&path) {
+        Ok(metadata) => metadata.permissions().mode(),
+        Err(_) => mode,
+    };
+
+    let mut data = String::new();
+
+    for entry in entries {
+        data.push_str(&entry.to_string());
+        data.push('\n');
+    }
+
+    (path, data, mode)
+}
+
+/// The user and group databases of a tree. Opening the database locks all files until it is
+/// dropped, changes are only written out by `commit`.
+pub struct Database {
+    tree: TreePath,
+
+    pub passwd: Vec<PasswdEntry>,
+    pub group: Vec<GroupEntry>,
+    pub shadow: Vec<ShadowEntry>,
+
+    _locks: Vec<Lock>,
+}
+
+impl Database {
+    /// Lock and read the databases in `tree`. Files that don't exist are treated as empty.
+    pub fn open(tree: &Path) -> Result<Self, PasswdError> {
+        let tree = TreePath::new(tree);
+
+        tree.create_dir_all("etc")?;
+
+        let mut locks = vec![];
+
+        for path in [PASSWD_PATH, GROUP_PATH, SHADOW_PATH] {
+            locks.push(Lock::acquire(&tree.resolve(path)?)?);
+        }
+
+        Ok(Self {
+            passwd: read_entries(&tree.resolve(PASSWD_PATH)?)?,
+            group: read_entries(&tree.resolve(GROUP_PATH)?)?,
+            shadow: read_entries(&tree.resolve(SHADOW_PATH)?)?,
+            tree,
+            _locks: locks,
+        })
+    }
+
+    pub fn user(&self, name: &str) -> Option<&PasswdEntry> {
+        self.passwd.iter().find(|entry| entry.name == name)
+    }
+
+    pub fn group(&self, name: &str) -> Option<&GroupEntry> {
+        self.group.iter().find(|entry| entry.name == name)
+    }
+
+    fn user_mut(&mut self, name: &str) -> Result<&mut PasswdEntry, PasswdError> {
+        self.passwd
+            .iter_mut()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| PasswdError::NoSuchUser(name.to_string()))
+    }
+
+    fn group_mut(&mut self, name: &str) -> Result<&mut GroupEntry, PasswdError> {
+        self.group
+            .iter_mut()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| PasswdError::NoSuchGroup(name.to_string()))
+    }
+
+    /// The lowest uid at or above `min` that isn't in use.
+    pub fn next_uid(&self, min: u32) -> u32 {
+        (min..)
+            .find(|uid| !self.passwd.iter().any(|entry| entry.uid == *uid))
+            .unwrap_or(min)
+    }
+
+    /// The lowest gid at or above `min` that isn't in use.
+    pub fn next_gid(&self, min: u32) -> u32 {
+        (min..)
+            .find(|gid| !self.group.iter().any(|entry| entry.gid == *gid))
+            .unwrap_or(min)
+    }
+
+    /// Add a group, picking the next free gid if none is given. Returns the gid.
+    pub fn add_group(&mut self, name: &str, gid: Option<u32>) -> Result<u32, PasswdError> {
+        check_field(name)?;
+
+        if self.group(name).is_some() {
+            return Err(PasswdError::GroupExists(name.to_string()));
+        }
+
+        let gid = gid.unwrap_or_else(|| self.next_gid(GID_MIN));
+
+        self.group.push(GroupEntry {
+            name: name.to_string(),
+            password: "x".to_string(),
+            gid,
+            members: vec![],
+        });
+
+        Ok(gid)
+    }
+
+    /// Add a user. Like `useradd` a user private group with the same name is created if no
+    /// gid is given, and a locked shadow entry is written if no password is given.
+    pub fn add_user(&mut self, user: &User) -> Result<u32, PasswdError> {
+        check_field(&user.name)?;
+
+        for field in [&user.gecos, &user.home, &user.shell, &user.password]
+            .into_iter()
+            .flatten()
+        {
+            check_field(field)?;
+        }
+
+        if self.user(&user.name).is_some() {
+            return Err(PasswdError::UserExists(user.name.clone()));
+        }
+
+        for group in &user.groups {
+            if self.group(group).is_none() {
+                return Err(PasswdError::NoSuchGroup(group.clone()));
+            }
+        }
+
+        let uid = user.uid.unwrap_or_else(|| self.next_uid(UID_MIN));
+
+        let gid = match user.gid {
+            Some(gid) => gid,
+            None => match self.group(&user.name) {
+                Some(group) => group.gid,
+                None => {
+                    let gid = if self.group.iter().any(|entry| entry.gid == uid) {
+                        None
+                    } else {
+                        Some(uid)
+                    };
+
+                    self.add_group(&user.name, gid)?
+                }
+            },
+        };
+
+        self.passwd.push(PasswdEntry {
+            name: user.name.clone(),
+            password: "x".to_string(),
+            uid,
+            gid,
+            gecos: user.gecos.clone().unwrap_or_default(),
+            home: user
+                .home
+                .clone()
+                .unwrap_or_else(|| format!("/home/{}", user.name)),
+            shell: user
+                .shell
+                .clone()
+                .unwrap_or_else(|| "/bin/bash".to_string()),
+        });
+
+        let mut shadow = ShadowEntry::locked(&user.name);
+
+        if let Some(password) = &user.password {
+            shadow.password = password.clone();
+        }
+
+        self.shadow.retain(|entry| entry.name != user.name);
+        self.shadow.push(shadow);
+
+        for group in &user.groups {
+            self.add_member(group, &user.name)?;
+        }
+
+        Ok(uid)
+    }
+
+    pub fn set_uid(&mut self, name: &str, uid: u32) -> Result<(), PasswdError> {
+        self.user_mut(name)?.uid = uid;
+        Ok(())
+    }
+
+    /// Set the primary group of a user by gid.
+    pub fn set_gid(&mut self, name: &str, gid: u32) -> Result<(), PasswdError> {
+        self.user_mut(name)?.gid = gid;
+        Ok(())
+    }
+
+    pub fn set_group_gid(&mut self, name: &str, gid: u32) -> Result<(), PasswdError> {
+        self.group_mut(name)?.gid = gid;
+        Ok(())
+    }
+
+    /// Set an already hashed password for a user.
+    pub fn set_password(&mut self, name: &str, hash: &str) -> Result<(), PasswdError> {
+        check_field(hash)?;
+
+        if self.user(name).is_none() {
+            return Err(PasswdError::NoSuchUser(name.to_string()));
+        }
+
+        match self.shadow.iter_mut().find(|entry| entry.name == name) {
+            Some(entry) => entry.password = hash.to_string(),
+            None => {
+                let mut entry = ShadowEntry::locked(name);
+                entry.password = hash.to_string();
+                self.shadow.push(entry);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a user as a supplementary member of a group, adding an existing member is a no-op.
+    pub fn add_member(&mut self, group: &str, user: &str) -> Result<(), PasswdError> {
+        if self.user(user).is_none() {
+            return Err(PasswdError::NoSuchUser(user.to_string()));
+        }
+
+        let entry = self.group_mut(group)?;
+
+        if !entry.members.iter().any(|member| member == user) {
+            entry.members.push(user.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Check the databases for consistency: names must be unique, every user needs an
+    /// existing primary group and a shadow entry, and group members must exist.
+    pub fn check(&self) -> Result<(), PasswdError> {
+        for (index, entry) in self.passwd.iter().enumerate() {
+            if self.passwd[..index].iter().any(|e| e.name == entry.name) {
+                return Err(PasswdError::UserExists(entry.name.clone()));
+            }
+
+            if !self.group.iter().any(|group| group.gid == entry.gid) {
+                return Err(PasswdError::Inconsistent(format!(
+                    "primary group {} of user '{}' does not exist",
+                    entry.gid, entry.name
+                )));
+            }
+
+            if entry.password == "x" && !self.shadow.iter().any(|s| s.name == entry.name) {
+                return Err(PasswdError::Inconsistent(format!(
+                    "user '{}' has no shadow entry",
+                    entry.name
+                )));
+            }
+        }
+
+        for (index, entry) in self.group.iter().enumerate() {
+            if self.group[..index].iter().any(|e| e.name == entry.name) {
+                return Err(PasswdError::GroupExists(entry.name.clone()));
+            }
+
+            for member in &entry.members {
+                if self.user(member).is_none() {
+                    return Err(PasswdError::Inconsistent(format!(
+                        "member '{}' of group '{}' does not exist",
+                        member, entry.name
+                    )));
+                }
+            }
+        }
+
+        for entry in &self.shadow {
+            if self.user(&entry.name).is_none() {
+                return Err(PasswdError::Inconsistent(format!(
+                    "shadow entry for '{}' has no user",
+                    entry.name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the databases and write them back to the tree. Each database is replaced
+    /// atomically, and when replacing one fails those replaced before it are put back, but a
+    /// crash while they are replaced can leave some of them new and others old, see
+    /// `atomic_write_all`.
+    pub fn commit(&self) -> Result<(), PasswdError> {
+        self.check()?;
+
+        // all databases are written before any is replaced, so a database that can't be
+        // written leaves them all as they were
+        atomic_write_all(&[
+            render_entries(self.tree.resolve(PASSWD_PATH)?, &self.passwd, 0o644),
+            render_entries(self.tree.resolve(GROUP_PATH)?, &self.group, 0o644),
+            render_entries(self.tree.resolve(SHADOW_PATH)?, &self.shadow, 0o000),
+        ])?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tree() -> tempfile::TempDir {
+        let tree = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(tree.path().join("etc")).unwrap();
+        fs::write(
+            tree.path().join(PASSWD_PATH),
+            "root:x:0:0:root:/root:/bin/bash\n",
+        )
+        .unwrap();
+        fs::write(tree.path().join(GROUP_PATH), "root:x:0:\nwheel:x:10:\n").unwrap();
+        fs::write(
+            tree.path().join(SHADOW_PATH),
+            "root:!locked::0:99999:7:::\n",
+        )
+        .unwrap();
+
+        tree
+    }
+
+    #[test]
+    fn entry_roundtrip() {
+        for line in [
+            "root:x:0:0:root:/root:/bin/bash",
+            "nobody:x:65534:65534:Kernel Overflow User:/:/sbin/nologin",
+        ] {
+            assert_eq!(line.parse::<PasswdEntry>().unwrap().to_string(), line);
+        }
+
+        for line in ["wheel:x:10:", "wheel:x:10:alice,bob"] {
+            assert_eq!(line.parse::<GroupEntry>().unwrap().to_string(), line);
+        }
+
+        for line in [
+            "root:!locked::0:99999:7:::",
+            "alice:$6$salt$hash:19000::::::",
+        ] {
+            assert_eq!(line.parse::<ShadowEntry>().unwrap().to_string(), line);
+        }
+
+        assert!("root:x:zero:0:root:/root:/bin/bash"
+            .parse::<PasswdEntry>()
+            .is_err());
+        assert!("root:x:0".parse::<GroupEntry>().is_err());
+    }
+
+    #[test]
+    fn database_add_user() {
+        let tree = tree();
+
+        {
+            let mut database = Database::open(tree.path()).unwrap();
+
+            let uid = database
+                .add_user(&User {
+                    name: "alice".to_string(),
+                    password: Some("$6$salt$hash".to_string()),
+                    groups: vec!["wheel".to_string()],
+                    ..Default::default()
+                })
+                .unwrap();
+
+            assert_eq!(uid, 1000);
+            assert_eq!(database.group("alice").unwrap().gid, 1000);
+            assert_eq!(database.group("wheel").unwrap().members, vec!["alice"]);
+
+            database.commit().unwrap();
+        }
+
+        assert_eq!(
+            fs::read_to_string(tree.path().join(PASSWD_PATH)).unwrap(),
+            "root:x:0:0:root:/root:/bin/bash\nalice:x:1000:1000::/home/alice:/bin/bash\n"
+        );
+        assert!(fs::read_to_string(tree.path().join(SHADOW_PATH))
+            .unwrap()
+            .contains("alice:$6$salt$hash:"));
+
+        // locks are released on drop
+        assert!(Database::open(tree.path()).is_ok());
+    }
+
+    #[test]
+    fn database_locked() {
+        let tree = tree();
+        let _database = Database::open(tree.path()).unwrap();
+
+        assert!(matches!(
+            Database::open(tree.path()),
+            Err(PasswdError::Locked(_))
+        ));
+    }
+
+    #[test]
+    fn database_duplicate_and_missing() {
+        let tree = tree();
+        let mut database = Database::open(tree.path()).unwrap();
+
+        let user = User {
+            name: "root".to_string(),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            database.add_user(&user),
+            Err(PasswdError::UserExists(_))
+        ));
+        assert!(matches!(
+            database.add_user(&User {
+                name: "bob".to_string(),
+                groups: vec!["nope".to_string()],
+                ..Default::default()
+            }),
+            Err(PasswdError::NoSuchGroup(_))
+        ));
+        assert!(matches!(
+            database.set_password("nope", "x"),
+            Err(PasswdError::NoSuchUser(_))
+        ));
+        assert!(matches!(
+            database.set_password("root", "a:b"),
+            Err(PasswdError::InvalidField(_))
+        ));
+    }
+
+    #[test]
+    fn database_set_ids() {
+        let tree = tree();
+        let mut database = Database::open(tree.path()).unwrap();
+
+        database
+            .add_user(&User {
+                name: "bob".to_string(),
+                uid: Some(2000),
+                ..Default::default()
+            })
+            .unwrap();
+
+        database.set_uid("bob", 2001).unwrap();
+        database.set_group_gid("bob", 2002).unwrap();
+
+        // the primary gid of bob now points to nothing
+        assert!(matches!(
+            database.check(),
+            Err(PasswdError::Inconsistent(_))
+        ));
+
+        database.set_gid("bob", 2002).unwrap();
+        assert!(database.check().is_ok());
+        assert_eq!(database.user("bob").unwrap().uid, 2001);
+    }
+}
@@ -0,0 +1,337 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Locations of the sqlite rpm database in a tree, newer distributions moved it out of
+/// `/var`.
+pub const RPMDB_PATHS: [&str; 2] = [
+    "usr/lib/sysimage/rpm/rpmdb.sqlite",
+    "var/lib/rpm/rpmdb.sqlite",
+];
+
+// Header tags we are interested in, see `rpmtag.h`.
+const TAG_NAME: i32 = 1000;
+const TAG_VERSION: i32 = 1001;
+const TAG_RELEASE: i32 = 1002;
+const TAG_EPOCH: i32 = 1003;
+const TAG_LICENSE: i32 = 1014;
+const TAG_ARCH: i32 = 1022;
+const TAG_SOURCERPM: i32 = 1044;
+
+// Header data types, see `rpmtag.h`.
+const TYPE_INT32: u32 = 4;
+const TYPE_STRING: u32 = 6;
+const TYPE_I18NSTRING: u32 = 9;
+
+#[derive(Debug)]
+pub enum RpmdbError {
+    /// None of the known rpm database locations exist in the tree.
+    NoDatabase(PathBuf),
+
+    /// A header blob in the database is truncated or otherwise malformed.
+    InvalidHeader(String),
+
+    /// A required tag is missing from a header.
+    MissingTag(i32),
+
+    SqliteError(rusqlite::Error),
+    IOError(io::Error),
+}
+
+impl From<rusqlite::Error> for RpmdbError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::SqliteError(err)
+    }
+}
+
+impl From<io::Error> for RpmdbError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// An installed package, identified by its NEVRA with some additional metadata that is
+/// useful for SBOMs.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub epoch: Option<u32>,
+    pub version: String,
+    pub release: String,
+
+    /// `gpg-pubkey` pseudo-packages have no architecture.
+    pub arch: Option<String>,
+
+    pub license: Option<String>,
+    pub sourcerpm: Option<String>,
+}
+
+impl fmt::Display for Package {
+    /// Format as NEVRA, the epoch is left out when unset, as `rpm -qa` does.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-", self.name)?;
+
+        if let Some(epoch) = self.epoch {
+            write!(f, "{}:", epoch)?;
+        }
+
+        write!(f, "{}-{}", self.version, self.release)?;
+
+        if let Some(arch) = &self.arch {
+            write!(f, ".{}", arch)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A parsed rpm header as stored in the database; an index of tag entries pointing into a
+/// data store.
+struct Header<'a> {
+    index: Vec<(i32, u32, usize, u32)>,
+    data: &'a [u8],
+}
+
+fn read_u32(blob: &[u8], offset: usize) -> Result<u32, RpmdbError> {
+    blob.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| RpmdbError::InvalidHeader(format!("truncated at offset {}", offset)))
+}
+
+impl<'a> Header<'a> {
+    fn parse(blob: &'a [u8]) -> Result<Self, RpmdbError> {
+        let count = read_u32(blob, 0)? as usize;
+        let size = read_u32(blob, 4)? as usize;
+
+        let start = 8 + count * 16;
+
+        let data = blob
+            .get(start..start + size)
+            .ok_or_else(|| RpmdbError::InvalidHeader("data store out of bounds".to_string()))?;
+
+        let mut index = Vec::with_capacity(count);
+
+        for entry in 0..count {
+            let offset = 8 + entry * 16;
+
+            index.push((
+                read_u32(blob, offset)? as i32,
+                read_u32(blob, offset + 4)?,
+                read_u32(blob, offset + 8)? as usize,
+                read_u32(blob, offset + 12)?,
+            ));
+        }
+
+        Ok(Self { index, data })
+    }
+
+    fn string(&self, tag: i32) -> Result<Option<String>, RpmdbError> {
+        let entry = self.index.iter().find(|(t, _, _, _)| *t == tag);
+
+        match entry {
+            None => Ok(None),
+            Some((_, kind, offset, _)) if *kind == TYPE_STRING || *kind == TYPE_I18NSTRING => {
+                let rest = self.data.get(*offset..).ok_or_else(|| {
+                    RpmdbError::InvalidHeader(format!("tag {} out of bounds", tag))
+                })?;
+
+                let end = rest.iter().position(|byte| *byte == 0).ok_or_else(|| {
+                    RpmdbError::InvalidHeader(format!("tag {} is not terminated", tag))
+                })?;
+
+                Ok(Some(String::from_utf8_lossy(&rest[..end]).to_string()))
+            }
+            Some(_) => Err(RpmdbError::InvalidHeader(format!(
+                "tag {} is not a string",
+                tag
+            ))),
+        }
+    }
+
+    fn int32(&self, tag: i32) -> Result<Option<u32>, RpmdbError> {
+        match self.index.iter().find(|(t, _, _, _)| *t == tag) {
+            None => Ok(None),
+            Some((_, kind, offset, _)) if *kind == TYPE_INT32 => {
+                Ok(Some(read_u32(self.data, *offset)?))
+            }
+            Some(_) => Err(RpmdbError::InvalidHeader(format!(
+                "tag {} is not an int32",
+                tag
+            ))),
+        }
+    }
+}
+
+impl Package {
+    /// Decode a package from a header blob as stored in the `Packages` table.
+    pub fn from_header(blob: &[u8]) -> Result<Self, RpmdbError> {
+        let header = Header::parse(blob)?;
+
+        Ok(Self {
+            name: header
+                .string(TAG_NAME)?
+                .ok_or(RpmdbError::MissingTag(TAG_NAME))?,
+            epoch: header.int32(TAG_EPOCH)?,
+            version: header
+                .string(TAG_VERSION)?
+                .ok_or(RpmdbError::MissingTag(TAG_VERSION))?,
+            release: header
+                .string(TAG_RELEASE)?
+                .ok_or(RpmdbError::MissingTag(TAG_RELEASE))?,
+            arch: header.string(TAG_ARCH)?,
+            license: header.string(TAG_LICENSE)?,
+            sourcerpm: header.string(TAG_SOURCERPM)?,
+        })
+    }
+}
+
+/// Find the rpm database in `tree`.
+pub fn find(tree: &Path) -> Result<PathBuf, RpmdbError> {
+    RPMDB_PATHS
+        .iter()
+        .map(|path| tree.join(path))
+        .find(|path| path.is_file())
+        .ok_or_else(|| RpmdbError::NoDatabase(tree.to_path_buf()))
+}
+
+/// Read all installed packages from the rpm database in `tree`, sorted by NEVRA. The
+/// database is opened read-only so the tree is not modified.
+pub fn packages(tree: &Path) -> Result<Vec<Package>, RpmdbError> {
+    let connection = rusqlite::Connection::open_with_flags(
+        find(tree)?,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+
+    let mut statement = connection.prepare("SELECT blob FROM Packages")?;
+    let mut packages = statement
+        .query_map([], |row| row.get::<_, Vec<u8>>(0))?
+        .map(|blob| Package::from_header(&blob?))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    packages.sort();
+
+    Ok(packages)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    /// Build a header blob with string tags and an optional epoch.
+    fn header(strings: &[(i32, &str)], epoch: Option<u32>) -> Vec<u8> {
+        let mut index = vec![];
+        let mut data = vec![];
+
+        if let Some(epoch) = epoch {
+            index.push((TAG_EPOCH, TYPE_INT32, data.len() as u32));
+            data.extend_from_slice(&epoch.to_be_bytes());
+        }
+
+        for (tag, value) in strings {
+            index.push((*tag, TYPE_STRING, data.len() as u32));
+            data.extend_from_slice(value.as_bytes());
+            data.push(0);
+        }
+
+        let mut blob = vec![];
+        blob.extend_from_slice(&(index.len() as u32).to_be_bytes());
+        blob.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+        for (tag, kind, offset) in index {
+            blob.extend_from_slice(&tag.to_be_bytes());
+            blob.extend_from_slice(&kind.to_be_bytes());
+            blob.extend_from_slice(&offset.to_be_bytes());
+            blob.extend_from_slice(&1u32.to_be_bytes());
+        }
+
+        blob.extend_from_slice(&data);
+        blob
+    }
+
+    #[test]
+    fn package_from_header() {
+        let blob = header(
+            &[
+                (TAG_NAME, "bash"),
+                (TAG_VERSION, "5.2.15"),
+                (TAG_RELEASE, "1.fc37"),
+                (TAG_ARCH, "x86_64"),
+            ],
+            Some(1),
+        );
+
+        let package = Package::from_header(&blob).unwrap();
+
+        assert_eq!(package.to_string(), "bash-1:5.2.15-1.fc37.x86_64");
+        assert_eq!(package.license, None);
+    }
+
+    #[test]
+    fn package_from_header_invalid() {
+        assert!(Package::from_header(&[0, 0]).is_err());
+        assert!(matches!(
+            Package::from_header(&header(&[(TAG_NAME, "bash")], None)),
+            Err(RpmdbError::MissingTag(TAG_VERSION))
+        ));
+
+        let mut truncated = header(&[(TAG_NAME, "bash")], None);
+        truncated.truncate(truncated.len() - 2);
+
+        assert!(Package::from_header(&truncated).is_err());
+    }
+
+    #[test]
+    fn packages_from_tree() {
+        let tree = tempfile::tempdir().unwrap();
+        let path = tree.path().join(RPMDB_PATHS[0]);
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        connection
+            .execute(
+                "CREATE TABLE Packages (hnum INTEGER PRIMARY KEY AUTOINCREMENT, blob BLOB NOT NULL)",
+                [],
+            )
+            .unwrap();
+
+        for (name, arch) in [("zsh", Some("x86_64")), ("gpg-pubkey", None)] {
+            let mut tags = vec![(TAG_NAME, name), (TAG_VERSION, "1"), (TAG_RELEASE, "2")];
+
+            if let Some(arch) = arch {
+                tags.push((TAG_ARCH, arch));
+            }
+
+            connection
+                .execute(
+                    "INSERT INTO Packages (blob) VALUES (?1)",
+                    [header(&tags, None)],
+                )
+                .unwrap();
+        }
+
+        drop(connection);
+
+        let names: Vec<String> = packages(tree.path())
+            .unwrap()
+            .iter()
+            .map(|package| package.to_string())
+            .collect();
+
+        assert_eq!(names, vec!["gpg-pubkey-1-2", "zsh-1-2.x86_64"]);
+    }
+
+    #[test]
+    fn packages_no_database() {
+        let tree = tempfile::tempdir().unwrap();
+
+        assert!(matches!(
+            packages(tree.path()),
+            Err(RpmdbError::NoDatabase(_))
+        ));
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SizeError {
+    /// A size isn't a number of bytes with an optional unit, contains it.
+    Invalid(String),
+
+    /// A size, or arithmetic on sizes, doesn't fit in 64 bits of bytes.
+    Overflow(String),
+}
+
+impl fmt::Display for SizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Invalid(size) => write!(f, "'{}' is not a size", size),
+            Self::Overflow(size) => write!(f, "{} is too large", size),
+        }
+    }
+}
+
+/// The binary units sizes are displayed in, largest first; `K`, `M`, `G`, and `T` are these
+/// as well, as in lvm and sfdisk.
+const BINARY_UNITS: &[(&str, u64)] = &[
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+];
+
+/// The multiplier of `unit`, units are case insensitive.
+fn multiplier(unit: &str) -> Option<u64> {
+    Some(match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => return None,
+    })
+}
+
+/// A number of bytes, as options of manifests give sizes of disks, partitions, and
+/// filesystems. Sizes are parsed from bytes, `4096`, or from a number and a unit, `2 GiB`,
+/// `512M`, or `1.5G`; `KiB` and `K` are 1024 bytes, `kB` is 1000. Fractions have to come to
+/// whole bytes. In options they are either integers of bytes or strings to parse, and are
+/// serialized as integers of bytes. Arithmetic on sizes is checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DataSize(u64);
+
+impl DataSize {
+    pub const ZERO: Self = Self(0);
+
+    pub const fn from_bytes(bytes: u64) -> Self {
+        Self(bytes)
+    }
+
+    pub const fn kib(count: u64) -> Self {
+        Self(count << 10)
+    }
+
+    pub const fn mib(count: u64) -> Self {
+        Self(count << 20)
+    }
+
+    pub const fn gib(count: u64) -> Self {
+        Self(count << 30)
+    }
+
+    pub const fn bytes(&self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    pub fn checked_mul(self, factor: u64) -> Option<Self> {
+        self.0.checked_mul(factor).map(Self)
+    }
+
+    /// The size rounded up to a multiple of `alignment`, which can't be zero.
+    pub fn checked_align(self, alignment: Self) -> Option<Self> {
+        self.0.checked_next_multiple_of(alignment.0).map(Self)
+    }
+
+    /// The number of blocks of `block_size` bytes the size takes, counting a partial block
+    /// as a whole one.
+    pub fn blocks(&self, block_size: u64) -> u64 {
+        self.0.div_ceil(block_size)
+    }
+
+    /// The number of blocks of `block_size` bytes the size is, when it is whole blocks.
+    pub fn exact_blocks(&self, block_size: u64) -> Option<u64> {
+        (block_size > 0 && self.0.is_multiple_of(block_size)).then(|| self.0 / block_size)
+    }
+}
+
+impl From<u64> for DataSize {
+    fn from(bytes: u64) -> Self {
+        Self(bytes)
+    }
+}
+
+impl FromStr for DataSize {
+    type Err = SizeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SizeError::Invalid(s.to_string());
+
+        let text = s.trim();
+        let split = text
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+
+        let multiplier = multiplier(unit.trim_start()).ok_or_else(invalid)?;
+
+        let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+
+        if whole.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        let overflow = || SizeError::Overflow(s.to_string());
+
+        let bytes = whole
+            .parse::<u128>()
+            .map_err(|_| overflow())?
+            .checked_mul(u128::from(multiplier))
+            .ok_or_else(overflow)?;
+
+        let bytes = match fraction.trim_end_matches('0') {
+            "" => bytes,
+            fraction => {
+                let scale = 10u128
+                    .checked_pow(fraction.len() as u32)
+                    .ok_or_else(invalid)?;
+                let part =
+                    fraction.parse::<u128>().map_err(|_| invalid())? * u128::from(multiplier);
+
+                if !part.is_multiple_of(scale) {
+                    return Err(invalid());
+                }
+
+                bytes + part / scale
+            }
+        };
+
+        u64::try_from(bytes).map(Self).map_err(|_| overflow())
+    }
+}
+
+/// Sizes are displayed in the largest binary unit they are a whole number of, `2 GiB`, or in
+/// bytes, `4097 B`; they parse back to the same size.
+impl fmt::Display for DataSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (unit, multiplier) in BINARY_UNITS {
+            if self.0 >= *multiplier && self.0.is_multiple_of(*multiplier) {
+                return write!(f, "{} {}", self.0 / multiplier, unit);
+            }
+        }
+
+        write!(f, "{} B", self.0)
+    }
+}
+
+impl Serialize for DataSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for DataSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Given {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Given::deserialize(deserializer)? {
+            Given::Bytes(bytes) => Ok(Self(bytes)),
+            Given::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sizes_parsed() {
+        for (text, bytes) in [
+            ("4096", 4096),
+            ("4096B", 4096),
+            ("2 GiB", 2 << 30),
+            ("512M", 512 << 20),
+            ("512mib", 512 << 20),
+            ("1.5G", 3 << 29),
+            ("1.50 KiB", 1536),
+            ("10 kB", 10_000),
+            ("4GB", 4_000_000_000),
+            (" 1 T ", 1 << 40),
+        ] {
+            assert_eq!(text.parse::<DataSize>(), Ok(DataSize(bytes)), "{}", text);
+        }
+
+        for text in [
+            "", "G", "-1", "1.2.3 G", "1.1 B", "0.0001 K", "2 GiBs", "1 PiB",
+        ] {
+            assert_eq!(
+                text.parse::<DataSize>(),
+                Err(SizeError::Invalid(text.to_string())),
+                "{}",
+                text
+            );
+        }
+
+        for text in [
+            "16777216 TiB",
+            "99999999999999999999999999999999999999999 G",
+        ] {
+            assert!(matches!(
+                text.parse::<DataSize>(),
+                Err(SizeError::Overflow(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn sizes_displayed() {
+        for (size, text) in [
+            (DataSize::gib(2), "2 GiB"),
+            (DataSize::mib(1536), "1536 MiB"),
+            (DataSize::from_bytes(4097), "4097 B"),
+            (DataSize::ZERO, "0 B"),
+        ] {
+            assert_eq!(size.to_string(), text);
+            assert_eq!(text.parse::<DataSize>().unwrap(), size);
+        }
+    }
+
+    #[test]
+    fn sizes_deserialized() {
+        #[derive(Debug, PartialEq, Deserialize, Serialize)]
+        struct Options {
+            size: DataSize,
+        }
+
+        for value in [
+            serde_json::json!({"size": 1073741824}),
+            serde_json::json!({"size": "1 GiB"}),
+            serde_json::json!({"size": "1024M"}),
+        ] {
+            let options: Options = serde_json::from_value(value).unwrap();
+
+            assert_eq!(options.size, DataSize::gib(1));
+            assert_eq!(
+                serde_json::to_value(&options).unwrap(),
+                serde_json::json!({"size": 1073741824})
+            );
+        }
+
+        assert!(serde_json::from_value::<Options>(serde_json::json!({"size": "big"})).is_err());
+        assert!(serde_json::from_value::<Options>(serde_json::json!({"size": -1})).is_err());
+    }
+
+    #[test]
+    fn arithmetic_checked() {
+        let size = DataSize::mib(3);
+
+        assert_eq!(size.checked_add(DataSize::mib(1)), Some(DataSize::mib(4)));
+        assert_eq!(size.checked_sub(DataSize::mib(4)), None);
+        assert_eq!(size.checked_mul(2), Some(DataSize::mib(6)));
+        assert_eq!(DataSize::from_bytes(u64::MAX).checked_add(size), None);
+        assert_eq!(DataSize::from_bytes(u64::MAX).checked_mul(2), None);
+
+        assert_eq!(
+            DataSize::from_bytes(1).checked_align(DataSize::mib(1)),
+            Some(DataSize::mib(1))
+        );
+        assert_eq!(size.checked_align(DataSize::ZERO), None);
+        assert_eq!(
+            DataSize::from_bytes(u64::MAX).checked_align(DataSize::mib(1)),
+            None
+        );
+
+        assert_eq!(DataSize::from_bytes(513).blocks(512), 2);
+        assert_eq!(DataSize::from_bytes(513).exact_blocks(512), None);
+        assert_eq!(DataSize::mib(1).exact_blocks(512), Some(2048));
+    }
+}
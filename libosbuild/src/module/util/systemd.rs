@@ -0,0 +1,611 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use crate::module::util::tree::{TreeError, TreePath};
+
+/// Directories in a tree where unit files are looked up, in order of precedence.
+pub const UNIT_SEARCH_PATHS: [&str; 3] = [
+    "etc/systemd/system",
+    "usr/lib/systemd/system",
+    "lib/systemd/system",
+];
+
+/// The directory administrator configuration (enablement symlinks, masks, drop-ins) goes in.
+pub const UNIT_CONFIG_PATH: &str = "etc/systemd/system";
+
+#[derive(Debug)]
+pub enum SystemdError {
+    /// The unit name is empty, contains a path separator, or has no unit type suffix.
+    InvalidUnitName(String),
+
+    /// The unit could not be found in any of the search paths of the tree.
+    NoSuchUnit(String),
+
+    /// The unit file could not be parsed.
+    ParseError(String),
+
+    TreeError(TreeError),
+    IOError(io::Error),
+}
+
+impl From<TreeError> for SystemdError {
+    fn from(err: TreeError) -> Self {
+        Self::TreeError(err)
+    }
+}
+
+impl From<io::Error> for SystemdError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// Check that a unit name is something we can safely put into a path.
+fn check_unit_name(name: &str) -> Result<(), SystemdError> {
+    let valid = !name.is_empty()
+        && !name.contains('/')
+        && !name.starts_with('.')
+        && name
+            .rsplit_once('.')
+            .is_some_and(|(base, suffix)| !base.is_empty() && !suffix.is_empty());
+
+    if valid {
+        Ok(())
+    } else {
+        Err(SystemdError::InvalidUnitName(name.to_string()))
+    }
+}
+
+/// Split an instantiated unit name (`getty@tty1.service`) into the name of its template
+/// (`getty@.service`) so the unit file can be found. Returns `None` for non-template units.
+fn template_name(name: &str) -> Option<String> {
+    let (prefix, rest) = name.split_once('@')?;
+    let (instance, suffix) = rest.rsplit_once('.')?;
+
+    (!instance.is_empty()).then(|| format!("{}@.{}", prefix, suffix))
+}
+
+/// A unit file as an ordered set of sections with ordered key/value pairs. Keys can occur
+/// more than once in a section, as they can in systemd unit files.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UnitFile {
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl UnitFile {
+    pub fn new() -> Self {
+        Self { sections: vec![] }
+    }
+
+    /// Append a `key=value` pair to `section`, creating the section if it does not exist yet.
+    pub fn add(&mut self, section: &str, key: &str, value: &str) -> &mut Self {
+        let index = match self.sections.iter().position(|(name, _)| name == section) {
+            Some(index) => index,
+            None => {
+                self.sections.push((section.to_string(), vec![]));
+                self.sections.len() - 1
+            }
+        };
+
+        self.sections[index]
+            .1
+            .push((key.to_string(), value.to_string()));
+
+        self
+    }
+
+    /// Get all values for `key` in `section`, in the order they appear.
+    pub fn get(&self, section: &str, key: &str) -> Vec<&str> {
+        self.sections
+            .iter()
+            .filter(|(name, _)| name == section)
+            .flat_map(|(_, entries)| entries.iter())
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// Get all whitespace separated words for `key` in `section`, this is how lists such as
+    /// `WantedBy=` are expressed.
+    pub fn get_list(&self, section: &str, key: &str) -> Vec<&str> {
+        self.get(section, key)
+            .into_iter()
+            .flat_map(|value| value.split_whitespace())
+            .collect()
+    }
+
+    /// Parse the contents of a unit file. Comments, empty lines, and line continuations are
+    /// handled; quoting and specifiers are left as-is in the values.
+    pub fn parse(data: &str) -> Result<Self, SystemdError> {
+        let mut unit = Self::new();
+        let mut section: Option<String> = None;
+        let mut pending = String::new();
+
+        for (number, raw) in data.lines().enumerate() {
+            if pending.is_empty() {
+                let trimmed = raw.trim();
+
+                if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+                    continue;
+                }
+
+                if trimmed.starts_with('[') {
+                    if !trimmed.ends_with(']') {
+                        return Err(SystemdError::ParseError(format!(
+                            "unterminated section header on line {}",
+                            number + 1
+                        )));
+                    }
+
+                    section = Some(trimmed[1..trimmed.len() - 1].to_string());
+                    continue;
+                }
+            }
+
+            match raw.trim_end().strip_suffix('\\') {
+                Some(partial) => {
+                    pending.push_str(partial);
+                    pending.push(' ');
+                    continue;
+                }
+                None => pending.push_str(raw.trim_end()),
+            }
+
+            let line = std::mem::take(&mut pending);
+
+            let name = section.as_ref().ok_or_else(|| {
+                SystemdError::ParseError(format!(
+                    "assignment outside of a section on line {}",
+                    number + 1
+                ))
+            })?;
+
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                SystemdError::ParseError(format!("missing '=' on line {}", number + 1))
+            })?;
+
+            unit.add(name, key.trim(), value.trim());
+        }
+
+        Ok(unit)
+    }
+}
+
+impl fmt::Display for UnitFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, (name, entries)) in self.sections.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+
+            writeln!(f, "[{}]", name)?;
+
+            for (key, value) in entries {
+                writeln!(f, "{}={}", key, value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A unit inside of a tree, the entry point for enabling, disabling, masking, and writing
+/// drop-ins without needing `systemctl` or a chroot.
+pub struct Unit<'a> {
+    tree: &'a Path,
+    name: String,
+}
+
+impl Unit<'_> {
+    pub fn new<'a>(tree: &'a Path, name: &str) -> Result<Unit<'a>, SystemdError> {
+        check_unit_name(name)?;
+
+        Ok(Unit {
+            tree,
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The path on the host of `path` in the configuration directory. Only its parent is
+    /// resolved, the last component is a link or drop-in that is replaced rather than
+    /// followed.
+    fn config_path(&self, path: impl AsRef<Path>) -> Result<PathBuf, SystemdError> {
+        let path = Path::new(UNIT_CONFIG_PATH).join(path);
+        let tree = TreePath::new(self.tree);
+
+        Ok(match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => tree.resolve(parent)?.join(name),
+            _ => tree.resolve(path)?,
+        })
+    }
+
+    /// Find the unit file for this unit in the tree, template units are resolved to their
+    /// template. Returns the path inside the tree, and the path as seen from inside the tree.
+    pub fn find(&self) -> Result<(PathBuf, PathBuf), SystemdError> {
+        let mut names = vec![self.name.clone()];
+
+        if let Some(template) = template_name(&self.name) {
+            names.push(template);
+        }
+
+        let tree = TreePath::new(self.tree);
+
+        for name in &names {
+            for search in UNIT_SEARCH_PATHS {
+                let inside = Path::new("/").join(search).join(name);
+                let link = tree.resolve(search)?.join(name);
+
+                // Masked units are symlinks to /dev/null and are not a unit file.
+                if fs::read_link(&link).is_ok_and(|target| target == Path::new("/dev/null")) {
+                    continue;
+                }
+
+                // the kernel follows symlinks to unit files inside of the tree, never to
+                // the unit files of the host
+                let is_file = tree
+                    .open(&inside)
+                    .and_then(|file| Ok(file.metadata()?))
+                    .is_ok_and(|metadata| metadata.is_file());
+
+                if is_file {
+                    return Ok((tree.resolve(&inside)?, inside));
+                }
+            }
+        }
+
+        Err(SystemdError::NoSuchUnit(self.name.clone()))
+    }
+
+    /// Load and parse the unit file for this unit.
+    pub fn load(&self) -> Result<UnitFile, SystemdError> {
+        let (_, inside) = self.find()?;
+        let mut data = String::new();
+
+        TreePath::new(self.tree)
+            .open(inside)?
+            .read_to_string(&mut data)?;
+
+        UnitFile::parse(&data)
+    }
+
+    /// Compute the symlinks `enable` would create as pairs of (link inside the tree, target).
+    fn install_links(&self) -> Result<Vec<(PathBuf, PathBuf)>, SystemdError> {
+        let unit = self.load()?;
+        let (_, target) = self.find()?;
+
+        let mut links = vec![];
+
+        for (key, suffix) in [("WantedBy", "wants"), ("RequiredBy", "requires")] {
+            for dependent in unit.get_list("Install", key) {
+                check_unit_name(dependent)?;
+
+                links.push((
+                    self.config_path(
+                        Path::new(&format!("{}.{}", dependent, suffix)).join(&self.name),
+                    )?,
+                    target.clone(),
+                ));
+            }
+        }
+
+        for alias in unit.get_list("Install", "Alias") {
+            check_unit_name(alias)?;
+            links.push((self.config_path(alias)?, target.clone()));
+        }
+
+        Ok(links)
+    }
+
+    /// Enable the unit by creating the symlinks described by its `[Install]` section. Returns
+    /// the links that were created, a unit without an `[Install]` section creates none.
+    pub fn enable(&self) -> Result<Vec<PathBuf>, SystemdError> {
+        let mut created = vec![];
+
+        for (link, target) in self.install_links()? {
+            if let Some(parent) = link.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            if fs::symlink_metadata(&link).is_ok() {
+                fs::remove_file(&link)?;
+            }
+
+            symlink(&target, &link)?;
+            created.push(link);
+        }
+
+        Ok(created)
+    }
+
+    /// Disable the unit by removing the symlinks described by its `[Install]` section. Returns
+    /// the links that were removed.
+    pub fn disable(&self) -> Result<Vec<PathBuf>, SystemdError> {
+        let mut removed = vec![];
+
+        for (link, _) in self.install_links()? {
+            if fs::symlink_metadata(&link).is_ok() {
+                fs::remove_file(&link)?;
+                removed.push(link);
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Mask the unit by symlinking it to `/dev/null` in the configuration directory.
+    pub fn mask(&self) -> Result<(), SystemdError> {
+        let link = self.config_path(&self.name)?;
+
+        if let Some(parent) = link.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        if fs::symlink_metadata(&link).is_ok() {
+            fs::remove_file(&link)?;
+        }
+
+        symlink("/dev/null", &link)?;
+
+        Ok(())
+    }
+
+    /// Unmask the unit, only the `/dev/null` symlink is removed; a unit file in the
+    /// configuration directory is left alone.
+    pub fn unmask(&self) -> Result<bool, SystemdError> {
+        let link = self.config_path(&self.name)?;
+
+        match fs::read_link(&link) {
+            Ok(target) if target == Path::new("/dev/null") => {
+                fs::remove_file(&link)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Is the unit masked in the tree?
+    pub fn is_masked(&self) -> bool {
+        self.config_path(&self.name)
+            .and_then(|link| Ok(fs::read_link(link)?))
+            .is_ok_and(|target| target == Path::new("/dev/null"))
+    }
+
+    /// Write a drop-in named `name` for this unit, `name` gets a `.conf` suffix if it does not
+    /// have one already. Returns the path of the drop-in inside the tree.
+    pub fn drop_in(&self, name: &str, unit: &UnitFile) -> Result<PathBuf, SystemdError> {
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return Err(SystemdError::InvalidUnitName(name.to_string()));
+        }
+
+        let directory = Path::new(UNIT_CONFIG_PATH).join(format!("{}.d", self.name));
+        let filename = if name.ends_with(".conf") {
+            name.to_string()
+        } else {
+            format!("{}.conf", name)
+        };
+
+        Ok(TreePath::new(self.tree).write(directory.join(filename), unit.to_string(), 0o644)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_unit(tree: &Path, name: &str, data: &str) {
+        let directory = tree.join("usr/lib/systemd/system");
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join(name), data).unwrap();
+    }
+
+    #[test]
+    fn unit_name_validation() {
+        let tree = Path::new("/");
+
+        assert!(Unit::new(tree, "sshd.service").is_ok());
+        assert!(Unit::new(tree, "getty@tty1.service").is_ok());
+        assert!(Unit::new(tree, "").is_err());
+        assert!(Unit::new(tree, "sshd").is_err());
+        assert!(Unit::new(tree, "../sshd.service").is_err());
+        assert!(Unit::new(tree, ".service").is_err());
+    }
+
+    #[test]
+    fn unit_file_parse_and_render() {
+        let unit = UnitFile::parse(
+            "# comment\n[Unit]\nDescription=Foo\n\n[Install]\nWantedBy=multi-user.target \\\n  graphical.target\nAlias=bar.service\n",
+        )
+        .unwrap();
+
+        assert_eq!(unit.get("Unit", "Description"), vec!["Foo"]);
+        assert_eq!(
+            unit.get_list("Install", "WantedBy"),
+            vec!["multi-user.target", "graphical.target"]
+        );
+
+        let mut rendered = UnitFile::new();
+        rendered
+            .add("Service", "ExecStart", "")
+            .add("Service", "ExecStart", "/usr/bin/foo")
+            .add("Install", "WantedBy", "multi-user.target");
+
+        assert_eq!(
+            rendered.to_string(),
+            "[Service]\nExecStart=\nExecStart=/usr/bin/foo\n\n[Install]\nWantedBy=multi-user.target\n"
+        );
+        assert_eq!(UnitFile::parse(&rendered.to_string()).unwrap(), rendered);
+    }
+
+    #[test]
+    fn unit_file_parse_errors() {
+        assert!(UnitFile::parse("Foo=bar\n").is_err());
+        assert!(UnitFile::parse("[Unit\n").is_err());
+        assert!(UnitFile::parse("[Unit]\nFoo\n").is_err());
+    }
+
+    #[test]
+    fn unit_enable_disable() {
+        let tree = tempfile::tempdir().unwrap();
+        write_unit(
+            tree.path(),
+            "foo.service",
+            "[Install]\nWantedBy=multi-user.target\nAlias=bar.service\n",
+        );
+
+        let unit = Unit::new(tree.path(), "foo.service").unwrap();
+        let links = unit.enable().unwrap();
+
+        assert_eq!(links.len(), 2);
+
+        let wants = tree
+            .path()
+            .join("etc/systemd/system/multi-user.target.wants/foo.service");
+
+        assert_eq!(
+            fs::read_link(&wants).unwrap(),
+            Path::new("/usr/lib/systemd/system/foo.service")
+        );
+        assert!(fs::symlink_metadata(tree.path().join("etc/systemd/system/bar.service")).is_ok());
+
+        // enabling twice is fine
+        assert!(unit.enable().is_ok());
+
+        assert_eq!(unit.disable().unwrap().len(), 2);
+        assert!(fs::symlink_metadata(&wants).is_err());
+    }
+
+    #[test]
+    fn unit_enable_template_instance() {
+        let tree = tempfile::tempdir().unwrap();
+        write_unit(
+            tree.path(),
+            "getty@.service",
+            "[Install]\nWantedBy=getty.target\n",
+        );
+
+        let unit = Unit::new(tree.path(), "getty@tty1.service").unwrap();
+        unit.enable().unwrap();
+
+        assert_eq!(
+            fs::read_link(
+                tree.path()
+                    .join("etc/systemd/system/getty.target.wants/getty@tty1.service")
+            )
+            .unwrap(),
+            Path::new("/usr/lib/systemd/system/getty@.service")
+        );
+    }
+
+    #[test]
+    fn unit_enable_missing() {
+        let tree = tempfile::tempdir().unwrap();
+        let unit = Unit::new(tree.path(), "foo.service").unwrap();
+
+        assert!(matches!(unit.enable(), Err(SystemdError::NoSuchUnit(_))));
+    }
+
+    #[test]
+    fn unit_mask_unmask() {
+        let tree = tempfile::tempdir().unwrap();
+        let unit = Unit::new(tree.path(), "foo.service").unwrap();
+
+        assert!(!unit.is_masked());
+        unit.mask().unwrap();
+        assert!(unit.is_masked());
+        assert!(unit.unmask().unwrap());
+        assert!(!unit.is_masked());
+        assert!(!unit.unmask().unwrap());
+    }
+
+    #[test]
+    fn unit_config_symlinked_outside() {
+        let tree = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(tree.path().join("etc")).unwrap();
+        symlink(outside.path(), tree.path().join("etc/systemd")).unwrap();
+
+        let unit = Unit::new(tree.path(), "foo.service").unwrap();
+
+        unit.mask().unwrap();
+
+        // the symlink points at the same path inside of the tree
+        let inside = tree
+            .path()
+            .join(outside.path().strip_prefix("/").unwrap())
+            .join("system/foo.service");
+
+        assert!(unit.is_masked());
+        assert!(fs::symlink_metadata(inside).is_ok());
+        assert!(fs::read_dir(outside.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn unit_file_symlinked_outside() {
+        let tree = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let host = outside.path().join("foo.service");
+
+        fs::write(&host, "[Install]\nWantedBy=multi-user.target\n").unwrap();
+        fs::create_dir_all(tree.path().join("usr/lib/systemd/system")).unwrap();
+        symlink(
+            &host,
+            tree.path().join("usr/lib/systemd/system/foo.service"),
+        )
+        .unwrap();
+
+        // the unit file of the host is not the unit file of the tree
+        let unit = Unit::new(tree.path(), "foo.service").unwrap();
+
+        assert!(matches!(unit.find(), Err(SystemdError::NoSuchUnit(_))));
+
+        // absolute symlinks to unit files of the tree are followed inside of it
+        write_unit(
+            tree.path(),
+            "bar.service",
+            "[Install]\nWantedBy=multi-user.target\n",
+        );
+        fs::create_dir_all(tree.path().join("etc/systemd/system")).unwrap();
+        symlink(
+            "/usr/lib/systemd/system/bar.service",
+            tree.path().join("etc/systemd/system/baz.service"),
+        )
+        .unwrap();
+
+        let unit = Unit::new(tree.path(), "baz.service").unwrap();
+
+        assert_eq!(
+            unit.load().unwrap().get_list("Install", "WantedBy"),
+            vec!["multi-user.target"]
+        );
+    }
+
+    #[test]
+    fn unit_drop_in() {
+        let tree = tempfile::tempdir().unwrap();
+        let unit = Unit::new(tree.path(), "foo.service").unwrap();
+
+        let mut drop_in = UnitFile::new();
+        drop_in.add("Service", "Restart", "always");
+
+        let path = unit.drop_in("10-restart", &drop_in).unwrap();
+
+        assert_eq!(
+            path,
+            tree.path()
+                .join("etc/systemd/system/foo.service.d/10-restart.conf")
+        );
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            "[Service]\nRestart=always\n"
+        );
+        assert!(unit.drop_in("../evil", &drop_in).is_err());
+    }
+}
@@ -0,0 +1,424 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+use crate::module::util::tree;
+
+/// The size of the blocks of a tar archive, headers take one and data is padded to them.
+pub const BLOCK_SIZE: usize = 512;
+
+/// The largest value that fits the octal size field of a header, larger ones go in a pax
+/// extended header.
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+/// The largest value that fits the octal owner fields of a header.
+const MAX_OCTAL_ID: u64 = 0o7777777;
+
+/// The kinds of entries, as the typeflag of their header.
+pub const REGULAR: u8 = b'0';
+pub const SYMLINK: u8 = b'2';
+pub const CHAR_DEVICE: u8 = b'3';
+pub const BLOCK_DEVICE: u8 = b'4';
+pub const DIRECTORY: u8 = b'5';
+pub const FIFO: u8 = b'6';
+pub const PAX_HEADER: u8 = b'x';
+
+/// An entry of an archive, without its data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub name: String,
+    pub kind: u8,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    pub size: u64,
+    pub mtime: u64,
+
+    /// The target of symlinks.
+    pub link: String,
+
+    /// The device numbers of device nodes.
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl Header {
+    /// A regular file of `size` bytes, owned by root.
+    pub fn file(name: &str, size: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: REGULAR,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            size,
+            mtime: 0,
+            link: String::new(),
+            major: 0,
+            minor: 0,
+        }
+    }
+
+    /// A directory owned by root, its name gets a trailing `/`.
+    pub fn directory(name: &str) -> Self {
+        Self {
+            name: format!("{}/", name.trim_end_matches('/')),
+            kind: DIRECTORY,
+            mode: 0o755,
+            size: 0,
+            ..Self::file(name, 0)
+        }
+    }
+}
+
+/// Write `value` as a NUL terminated octal number filling `field`, values that don't fit are
+/// written as 0 and carried in a pax header instead.
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let text = format!("{:0width$o}", value, width = width);
+
+    if text.len() == width {
+        field[..width].copy_from_slice(text.as_bytes());
+    } else {
+        field[..width].fill(b'0');
+    }
+
+    field[width] = 0;
+}
+
+/// A pax record, `<length> <key>=<value>\n` where the length counts itself.
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + 1;
+
+    while length != rest + length.to_string().len() {
+        length = rest + length.to_string().len();
+    }
+
+    format!("{} {}={}\n", length, key, value).into_bytes()
+}
+
+/// The 512 byte ustar header of `header`; fields that don't fit are cut short.
+fn ustar(header: &Header, name: &str) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+
+    let name = name.as_bytes();
+    let link = header.link.as_bytes();
+
+    block[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+    octal(&mut block[100..108], u64::from(header.mode & 0o7777));
+    octal(&mut block[108..116], header.uid.min(MAX_OCTAL_ID + 1));
+    octal(&mut block[116..124], header.gid.min(MAX_OCTAL_ID + 1));
+    octal(&mut block[124..136], header.size);
+    octal(&mut block[136..148], header.mtime.min(MAX_OCTAL_SIZE + 1));
+    block[156] = header.kind;
+    block[157..157 + link.len().min(100)].copy_from_slice(&link[..link.len().min(100)]);
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    octal(&mut block[329..337], u64::from(header.major));
+    octal(&mut block[337..345], u64::from(header.minor));
+
+    // the checksum is computed with its own field as spaces
+    block[148..156].fill(b' ');
+
+    let checksum: u32 = block.iter().map(|byte| u32::from(*byte)).sum();
+
+    octal(&mut block[148..155], u64::from(checksum));
+    block[155] = b' ';
+
+    block
+}
+
+/// Writes tar archives in the pax format, entry by entry. Owners are numeric and names of
+/// users and groups are left out, so that the same tree always makes the same archive.
+pub struct TarWriter<W: Write> {
+    out: W,
+
+    /// Later modification times are clamped to this, see `SOURCE_DATE_EPOCH`.
+    clamp: Option<u64>,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, clamp: None }
+    }
+
+    /// Clamp the modification times of entries to `epoch`.
+    pub fn clamp_mtime(mut self, epoch: Option<u64>) -> Self {
+        self.clamp = epoch;
+        self
+    }
+
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let rest = (size % BLOCK_SIZE as u64) as usize;
+
+        if rest != 0 {
+            self.out.write_all(&[0; BLOCK_SIZE][rest..])?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the header of an entry, its data has to follow with `write_data`. Values that
+    /// don't fit the ustar header, such as names longer than 100 bytes, go in a pax header
+    /// before it.
+    pub fn write_header(&mut self, header: &Header) -> io::Result<()> {
+        let mut header = header.clone();
+
+        if let Some(clamp) = self.clamp {
+            header.mtime = header.mtime.min(clamp);
+        }
+
+        let mut pax = vec![];
+
+        if header.name.len() > 100 {
+            pax.extend(pax_record("path", &header.name));
+        }
+
+        if header.link.len() > 100 {
+            pax.extend(pax_record("linkpath", &header.link));
+        }
+
+        if header.size > MAX_OCTAL_SIZE {
+            pax.extend(pax_record("size", &header.size.to_string()));
+        }
+
+        if header.uid > MAX_OCTAL_ID {
+            pax.extend(pax_record("uid", &header.uid.to_string()));
+        }
+
+        if header.gid > MAX_OCTAL_ID {
+            pax.extend(pax_record("gid", &header.gid.to_string()));
+        }
+
+        if header.mtime > MAX_OCTAL_SIZE {
+            pax.extend(pax_record("mtime", &header.mtime.to_string()));
+        }
+
+        if !pax.is_empty() {
+            let extended = Header {
+                kind: PAX_HEADER,
+                size: pax.len() as u64,
+                link: String::new(),
+                ..header.clone()
+            };
+
+            self.out.write_all(&ustar(&extended, "././@PaxHeader"))?;
+            self.out.write_all(&pax)?;
+            self.pad(pax.len() as u64)?;
+        }
+
+        self.out.write_all(&ustar(&header, &header.name))
+    }
+
+    /// Write the `size` bytes of data of the entry whose header was written last.
+    pub fn write_data(&mut self, data: impl Read, size: u64) -> io::Result<()> {
+        let copied = io::copy(&mut data.take(size), &mut self.out)?;
+
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the data of an entry is shorter than its header says",
+            ));
+        }
+
+        self.pad(size)
+    }
+
+    /// Append a regular file called `name` with `data`, owned by root.
+    pub fn append_data(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.write_header(&Header::file(name, data.len() as u64))?;
+        self.write_data(data, data.len() as u64)
+    }
+
+    /// Append the file at `path` as `name`, as what it is; a regular file, directory,
+    /// symlink, device node, or fifo. Sockets are skipped. Returns whether it was appended.
+    pub fn append_path(&mut self, name: &str, path: &Path) -> io::Result<bool> {
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+
+        let mut header = Header {
+            name: name.to_string(),
+            kind: REGULAR,
+            mode: metadata.mode(),
+            uid: u64::from(metadata.uid()),
+            gid: u64::from(metadata.gid()),
+            size: 0,
+            mtime: metadata.mtime().max(0) as u64,
+            link: String::new(),
+            major: 0,
+            minor: 0,
+        };
+
+        if file_type.is_dir() {
+            header.kind = DIRECTORY;
+            header.name = format!("{}/", name.trim_end_matches('/'));
+        } else if file_type.is_symlink() {
+            header.kind = SYMLINK;
+            header.link = fs::read_link(path)?.to_string_lossy().to_string();
+        } else if file_type.is_char_device() || file_type.is_block_device() {
+            let device = metadata.rdev();
+
+            header.kind = if file_type.is_char_device() {
+                CHAR_DEVICE
+            } else {
+                BLOCK_DEVICE
+            };
+            header.major = libc::major(device);
+            header.minor = libc::minor(device);
+        } else if file_type.is_fifo() {
+            header.kind = FIFO;
+        } else if file_type.is_file() {
+            header.size = metadata.len();
+        } else {
+            return Ok(false);
+        }
+
+        self.write_header(&header)?;
+
+        if header.kind == REGULAR {
+            self.write_data(fs::File::open(path)?, header.size)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Append everything below `root`, in filename order, with names relative to it. Returns
+    /// how many entries were appended.
+    pub fn append_tree(&mut self, root: &Path) -> io::Result<usize> {
+        let mut appended = 0;
+
+        tree::walk(root, &mut |path, _| {
+            let Ok(relative) = path.strip_prefix(root) else {
+                return Ok(());
+            };
+
+            if relative.as_os_str().is_empty() {
+                return Ok(());
+            }
+
+            if self.append_path(&relative.to_string_lossy(), path)? {
+                appended += 1;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(appended)
+    }
+
+    /// End the archive with two empty blocks and return what it was written to.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0; BLOCK_SIZE * 2])?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+    use std::process::Command;
+
+    #[test]
+    fn headers_written() {
+        let block = ustar(&Header::file("etc/hostname", 8), "etc/hostname");
+
+        assert_eq!(&block[..12], b"etc/hostname");
+        assert_eq!(&block[124..136], b"00000000010\0");
+        assert_eq!(&block[257..265], b"ustar\x0000");
+
+        // the checksum is that of the header with spaces in its place
+        let mut copy = block;
+        copy[148..156].fill(b' ');
+
+        let sum: u32 = copy.iter().map(|byte| u32::from(*byte)).sum();
+
+        assert_eq!(&block[148..156], format!("{:06o}\0 ", sum).as_bytes());
+    }
+
+    #[test]
+    fn records_counted() {
+        assert_eq!(pax_record("path", "a"), b"9 path=a\n");
+
+        for size in 0..300 {
+            let record = pax_record("path", &"a".repeat(size));
+            let length: usize = std::str::from_utf8(&record)
+                .unwrap()
+                .split(' ')
+                .next()
+                .unwrap()
+                .parse()
+                .unwrap();
+
+            assert_eq!(length, record.len());
+        }
+    }
+
+    #[test]
+    fn trees_archived() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("tree");
+        let long = format!("usr/share/{}", "x".repeat(120));
+
+        fs::create_dir_all(root.join(&long)).unwrap();
+        fs::write(root.join(&long).join("file"), "data").unwrap();
+        fs::create_dir(root.join("etc")).unwrap();
+        fs::write(root.join("etc/hostname"), "builder\n").unwrap();
+        symlink("../usr/share", root.join("etc/share")).unwrap();
+
+        let archive = |path: &Path| {
+            let mut tar = TarWriter::new(fs::File::create(path).unwrap()).clamp_mtime(Some(0));
+
+            let appended = tar.append_tree(&root).unwrap();
+            tar.finish().unwrap();
+
+            appended
+        };
+
+        let first = directory.path().join("first.tar");
+        let second = directory.path().join("second.tar");
+
+        assert_eq!(archive(&first), 7);
+        archive(&second);
+
+        // the same tree makes the same archive
+        assert_eq!(fs::read(&first).unwrap(), fs::read(&second).unwrap());
+        assert_eq!(fs::metadata(&first).unwrap().len() % BLOCK_SIZE as u64, 0);
+
+        let extracted = directory.path().join("extracted");
+        fs::create_dir(&extracted).unwrap();
+
+        let status = Command::new("tar")
+            .arg("-xf")
+            .arg(&first)
+            .arg("-C")
+            .arg(&extracted)
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+        assert_eq!(
+            fs::read_to_string(extracted.join(&long).join("file")).unwrap(),
+            "data"
+        );
+        assert_eq!(
+            fs::read_to_string(extracted.join("etc/hostname")).unwrap(),
+            "builder\n"
+        );
+        assert_eq!(
+            fs::read_link(extracted.join("etc/share")).unwrap(),
+            Path::new("../usr/share")
+        );
+        assert_eq!(
+            fs::metadata(extracted.join("etc/hostname"))
+                .unwrap()
+                .mtime(),
+            0
+        );
+    }
+}
@@ -0,0 +1,930 @@
+use std::collections::VecDeque;
+
+use serde_json::Value;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    /// The template can't be parsed, contains the 1-based line number and what is wrong.
+    SyntaxError(usize, String),
+
+    /// A variable that isn't in the context was rendered, contains the line and the name.
+    Undefined(usize, String),
+
+    /// A value can't be used the way it is, such as rendering a list without `join` or
+    /// looping over a string.
+    TypeError(usize, String),
+}
+
+/// The part of a template a piece of text came from.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Text(String),
+    Expression(usize, String),
+    Block(usize, String),
+}
+
+/// Split a template into text, `{{ expressions }}`, and `{% blocks %}`; `{# comments #}`
+/// are dropped. A `-` at the inside of a delimiter trims the whitespace on that side.
+fn tokenize(text: &str) -> Result<Vec<Token>, TemplateError> {
+    let mut tokens = vec![];
+    let mut rest = text;
+    let mut line = 1;
+    let mut trim_next = false;
+
+    loop {
+        let start = ["{{", "{%", "{#"]
+            .iter()
+            .filter_map(|open| rest.find(open))
+            .min();
+
+        let (before, after) = match start {
+            Some(start) => rest.split_at(start),
+            None => (rest, ""),
+        };
+
+        let mut before = before.to_string();
+
+        if trim_next {
+            before = before.trim_start().to_string();
+        }
+
+        if after.is_empty() {
+            tokens.push(Token::Text(before));
+            break;
+        }
+
+        line += rest[..rest.len() - after.len()].matches('\n').count();
+
+        let close = match &after[..2] {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+
+        let end = after
+            .find(close)
+            .ok_or_else(|| TemplateError::SyntaxError(line, format!("missing '{}'", close)))?;
+
+        let mut inner = &after[2..end];
+
+        if let Some(trimmed) = inner.strip_prefix('-') {
+            before = before.trim_end().to_string();
+            inner = trimmed;
+        }
+
+        trim_next = false;
+
+        if let Some(trimmed) = inner.strip_suffix('-') {
+            trim_next = true;
+            inner = trimmed;
+        }
+
+        tokens.push(Token::Text(before));
+
+        let consumed = end + 2;
+
+        match close {
+            "}}" => tokens.push(Token::Expression(line, inner.trim().to_string())),
+            "%}" => tokens.push(Token::Block(line, inner.trim().to_string())),
+            _ => {}
+        }
+
+        line += after[..consumed].matches('\n').count();
+        rest = &after[consumed..];
+    }
+
+    Ok(tokens
+        .into_iter()
+        .filter(|token| *token != Token::Text(String::new()))
+        .collect())
+}
+
+/// A token of an expression.
+#[derive(Debug, Clone, PartialEq)]
+enum Word {
+    Name(String),
+    Literal(Value),
+    Symbol(&'static str),
+}
+
+fn words(line: usize, text: &str) -> Result<VecDeque<Word>, TemplateError> {
+    let mut words = VecDeque::new();
+    let chars: Vec<char> = text.chars().collect();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let c = chars[index];
+
+        if c.is_whitespace() {
+            index += 1;
+        } else if c == '"' || c == '\'' {
+            let mut value = String::new();
+
+            index += 1;
+
+            loop {
+                match chars.get(index) {
+                    None => {
+                        return Err(TemplateError::SyntaxError(
+                            line,
+                            "unterminated string".to_string(),
+                        ))
+                    }
+                    Some('\\') => {
+                        match chars.get(index + 1) {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some(other) => value.push(*other),
+                            None => {}
+                        }
+
+                        index += 2;
+                    }
+                    Some(quote) if *quote == c => {
+                        index += 1;
+                        break;
+                    }
+                    Some(other) => {
+                        value.push(*other);
+                        index += 1;
+                    }
+                }
+            }
+
+            words.push_back(Word::Literal(Value::String(value)));
+        } else if c.is_ascii_digit() {
+            let start = index;
+
+            while chars
+                .get(index)
+                .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+            {
+                index += 1;
+            }
+
+            let number: String = chars[start..index].iter().collect();
+            let value = match number.parse::<i64>() {
+                Ok(number) => Value::from(number),
+                Err(_) => number.parse::<f64>().map(Value::from).map_err(|_| {
+                    TemplateError::SyntaxError(line, format!("invalid number '{}'", number))
+                })?,
+            };
+
+            words.push_back(Word::Literal(value));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = index;
+
+            while chars
+                .get(index)
+                .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+            {
+                index += 1;
+            }
+
+            let name: String = chars[start..index].iter().collect();
+
+            words.push_back(match name.as_str() {
+                "true" => Word::Literal(Value::Bool(true)),
+                "false" => Word::Literal(Value::Bool(false)),
+                "none" => Word::Literal(Value::Null),
+                _ => Word::Name(name),
+            });
+        } else {
+            let symbol = ["==", "!=", ".", "[", "]", "(", ")", ",", "|"]
+                .into_iter()
+                .find(|symbol| {
+                    chars[index..]
+                        .iter()
+                        .take(symbol.len())
+                        .copied()
+                        .eq(symbol.chars())
+                })
+                .ok_or_else(|| TemplateError::SyntaxError(line, format!("unexpected '{}'", c)))?;
+
+            index += symbol.len();
+            words.push_back(Word::Symbol(symbol));
+        }
+    }
+
+    Ok(words)
+}
+
+#[derive(Debug, Clone)]
+enum Key {
+    Name(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone)]
+enum Expression {
+    Literal(Value),
+    Variable(String, Vec<Key>),
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Equal(Box<Expression>, Box<Expression>, bool),
+    Filter(Box<Expression>, String, Vec<Expression>),
+}
+
+struct ExpressionParser {
+    line: usize,
+    words: VecDeque<Word>,
+}
+
+impl ExpressionParser {
+    fn error(&self, message: impl Into<String>) -> TemplateError {
+        TemplateError::SyntaxError(self.line, message.into())
+    }
+
+    fn eat(&mut self, word: &Word) -> bool {
+        if self.words.front() == Some(word) {
+            self.words.pop_front();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), TemplateError> {
+        if self.eat(&Word::Symbol(symbol)) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected '{}'", symbol)))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, TemplateError> {
+        match self.words.pop_front() {
+            Some(Word::Name(name)) => Ok(name),
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        self.eat(&Word::Name(keyword.to_string()))
+    }
+
+    fn finish(&self) -> Result<(), TemplateError> {
+        match self.words.front() {
+            None => Ok(()),
+            Some(word) => Err(self.error(format!("unexpected {:?}", word))),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expression, TemplateError> {
+        let mut left = self.and()?;
+
+        while self.keyword("or") {
+            left = Expression::Or(Box::new(left), Box::new(self.and()?));
+        }
+
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expression, TemplateError> {
+        let mut left = self.not()?;
+
+        while self.keyword("and") {
+            left = Expression::And(Box::new(left), Box::new(self.not()?));
+        }
+
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expression, TemplateError> {
+        if self.keyword("not") {
+            return Ok(Expression::Not(Box::new(self.not()?)));
+        }
+
+        let left = self.filtered()?;
+
+        for (symbol, equal) in [("==", true), ("!=", false)] {
+            if self.eat(&Word::Symbol(symbol)) {
+                return Ok(Expression::Equal(
+                    Box::new(left),
+                    Box::new(self.filtered()?),
+                    equal,
+                ));
+            }
+        }
+
+        Ok(left)
+    }
+
+    fn filtered(&mut self) -> Result<Expression, TemplateError> {
+        let mut expression = self.primary()?;
+
+        while self.eat(&Word::Symbol("|")) {
+            let name = self.name()?;
+            let mut arguments = vec![];
+
+            if self.eat(&Word::Symbol("(")) {
+                while !self.eat(&Word::Symbol(")")) {
+                    arguments.push(self.or()?);
+
+                    if !self.eat(&Word::Symbol(",")) {
+                        self.expect(")")?;
+                        break;
+                    }
+                }
+            }
+
+            expression = Expression::Filter(Box::new(expression), name, arguments);
+        }
+
+        Ok(expression)
+    }
+
+    fn primary(&mut self) -> Result<Expression, TemplateError> {
+        match self.words.pop_front() {
+            Some(Word::Literal(value)) => Ok(Expression::Literal(value)),
+            Some(Word::Symbol("(")) => {
+                let expression = self.or()?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            Some(Word::Name(name)) => {
+                let mut keys = vec![];
+
+                loop {
+                    if self.eat(&Word::Symbol(".")) {
+                        keys.push(Key::Name(self.name()?));
+                    } else if self.eat(&Word::Symbol("[")) {
+                        keys.push(match self.words.pop_front() {
+                            Some(Word::Literal(Value::String(name))) => Key::Name(name),
+                            Some(Word::Literal(Value::Number(number))) => Key::Index(
+                                number.as_u64().ok_or_else(|| self.error("invalid index"))?
+                                    as usize,
+                            ),
+                            _ => return Err(self.error("expected a string or an index")),
+                        });
+                        self.expect("]")?;
+                    } else {
+                        break;
+                    }
+                }
+
+                Ok(Expression::Variable(name, keys))
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+}
+
+fn expression(line: usize, text: &str) -> Result<Expression, TemplateError> {
+    let mut parser = ExpressionParser {
+        line,
+        words: words(line, text)?,
+    };
+
+    let expression = parser.or()?;
+    parser.finish()?;
+
+    Ok(expression)
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Output(usize, Expression),
+    If(Vec<(usize, Expression, Vec<Node>)>, Vec<Node>),
+    For {
+        line: usize,
+        names: Vec<String>,
+        iterable: Expression,
+        body: Vec<Node>,
+    },
+}
+
+/// The block a list of nodes ended at, and its line.
+type End = (usize, String);
+
+/// Parse nodes until one of the blocks in `ends`, which is returned.
+fn nodes(
+    tokens: &mut VecDeque<Token>,
+    ends: &[&str],
+) -> Result<(Vec<Node>, Option<End>), TemplateError> {
+    let mut nodes = vec![];
+
+    while let Some(token) = tokens.pop_front() {
+        match token {
+            Token::Text(text) => nodes.push(Node::Text(text)),
+            Token::Expression(line, text) => {
+                nodes.push(Node::Output(line, expression(line, &text)?))
+            }
+            Token::Block(line, text) => {
+                let (keyword, rest) = text.split_once(char::is_whitespace).unwrap_or((&text, ""));
+
+                if ends.contains(&keyword) {
+                    return Ok((nodes, Some((line, text))));
+                }
+
+                match keyword {
+                    "if" => {
+                        let mut branches = vec![];
+                        let mut condition = (line, expression(line, rest)?);
+                        let mut otherwise = vec![];
+
+                        loop {
+                            let (body, end) =
+                                nodes_until(tokens, line, &["elif", "else", "endif"])?;
+                            branches.push((condition.0, condition.1, body));
+
+                            let (end_line, end) = end;
+                            let (keyword, rest) =
+                                end.split_once(char::is_whitespace).unwrap_or((&end, ""));
+
+                            match keyword {
+                                "elif" => condition = (end_line, expression(end_line, rest)?),
+                                "else" => {
+                                    otherwise = nodes_until(tokens, line, &["endif"])?.0;
+                                    break;
+                                }
+                                _ => break,
+                            }
+                        }
+
+                        nodes.push(Node::If(branches, otherwise));
+                    }
+                    "for" => {
+                        let (names, iterable) = rest.split_once(" in ").ok_or_else(|| {
+                            TemplateError::SyntaxError(
+                                line,
+                                "expected 'for name in value'".to_string(),
+                            )
+                        })?;
+
+                        let names: Vec<String> = names
+                            .split(',')
+                            .map(|name| name.trim().to_string())
+                            .collect();
+
+                        if names.iter().any(|name| {
+                            name.is_empty()
+                                || !name.chars().all(|c| c.is_alphanumeric() || c == '_')
+                        }) {
+                            return Err(TemplateError::SyntaxError(
+                                line,
+                                format!("invalid loop variables '{}'", names.join(", ")),
+                            ));
+                        }
+
+                        nodes.push(Node::For {
+                            line,
+                            names,
+                            iterable: expression(line, iterable)?,
+                            body: nodes_until(tokens, line, &["endfor"])?.0,
+                        });
+                    }
+                    _ => {
+                        return Err(TemplateError::SyntaxError(
+                            line,
+                            format!("unexpected block '{}'", keyword),
+                        ))
+                    }
+                }
+            }
+        }
+    }
+
+    Ok((nodes, None))
+}
+
+/// Parse nodes until one of the blocks in `ends`, which must be there.
+fn nodes_until(
+    tokens: &mut VecDeque<Token>,
+    line: usize,
+    ends: &[&str],
+) -> Result<(Vec<Node>, End), TemplateError> {
+    match nodes(tokens, ends)? {
+        (nodes, Some(end)) => Ok((nodes, end)),
+        (_, None) => Err(TemplateError::SyntaxError(
+            line,
+            format!("missing '{{% {} %}}'", ends[ends.len() - 1]),
+        )),
+    }
+}
+
+fn truthy(value: &Option<Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(value)) => *value,
+        Some(Value::Number(number)) => number.as_f64() != Some(0.0),
+        Some(Value::String(value)) => !value.is_empty(),
+        Some(Value::Array(values)) => !values.is_empty(),
+        Some(Value::Object(values)) => !values.is_empty(),
+    }
+}
+
+/// How a value is written into the output; lists and objects have to be written with
+/// `join` or `json` instead.
+fn display(line: usize, value: &Value) -> Result<String, TemplateError> {
+    match value {
+        Value::Null => Ok(String::new()),
+        Value::String(value) => Ok(value.clone()),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        Value::Array(_) | Value::Object(_) => Err(TemplateError::TypeError(
+            line,
+            "lists and objects can only be rendered with 'join' or 'json'".to_string(),
+        )),
+    }
+}
+
+struct Renderer<'a> {
+    context: &'a Value,
+    scopes: Vec<Vec<(String, Value)>>,
+}
+
+impl Renderer<'_> {
+    fn variable(&self, name: &str, keys: &[Key]) -> Option<Value> {
+        let mut value = self
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter())
+            .find(|(local, _)| local == name)
+            .map(|(_, value)| value)
+            .or_else(|| self.context.get(name))?;
+
+        for key in keys {
+            value = match key {
+                Key::Name(name) => value.get(name)?,
+                Key::Index(index) => value.get(index)?,
+            };
+        }
+
+        Some(value.clone())
+    }
+
+    fn evaluate(
+        &self,
+        line: usize,
+        expression: &Expression,
+    ) -> Result<Option<Value>, TemplateError> {
+        Ok(match expression {
+            Expression::Literal(value) => Some(value.clone()),
+            Expression::Variable(name, keys) => self.variable(name, keys),
+            Expression::Not(inner) => Some(Value::Bool(!truthy(&self.evaluate(line, inner)?))),
+            Expression::And(left, right) => Some(Value::Bool(
+                truthy(&self.evaluate(line, left)?) && truthy(&self.evaluate(line, right)?),
+            )),
+            Expression::Or(left, right) => Some(Value::Bool(
+                truthy(&self.evaluate(line, left)?) || truthy(&self.evaluate(line, right)?),
+            )),
+            Expression::Equal(left, right, equal) => Some(Value::Bool(
+                (self.evaluate(line, left)? == self.evaluate(line, right)?) == *equal,
+            )),
+            Expression::Filter(inner, name, arguments) => {
+                let value = self.evaluate(line, inner)?;
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| self.evaluate(line, argument))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Some(filter(line, name, value, arguments, inner)?)
+            }
+        })
+    }
+
+    fn defined(&self, line: usize, expression: &Expression) -> Result<Value, TemplateError> {
+        self.evaluate(line, expression)?
+            .ok_or_else(|| TemplateError::Undefined(line, name_of(expression)))
+    }
+
+    fn render(&mut self, nodes: &[Node], output: &mut String) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => output.push_str(text),
+                Node::Output(line, expression) => {
+                    output.push_str(&display(*line, &self.defined(*line, expression)?)?)
+                }
+                Node::If(branches, otherwise) => {
+                    let mut body = otherwise;
+
+                    for (line, condition, branch) in branches {
+                        if truthy(&self.evaluate(*line, condition)?) {
+                            body = branch;
+                            break;
+                        }
+                    }
+
+                    self.render(body, output)?;
+                }
+                Node::For {
+                    line,
+                    names,
+                    iterable,
+                    body,
+                } => {
+                    let items = match self.defined(*line, iterable)? {
+                        Value::Array(items) => items,
+                        Value::Null => vec![],
+                        _ => {
+                            return Err(TemplateError::TypeError(
+                                *line,
+                                format!("'{}' is not a list", name_of(iterable)),
+                            ))
+                        }
+                    };
+
+                    let length = items.len();
+
+                    for (index, item) in items.into_iter().enumerate() {
+                        let mut scope = vec![(
+                            "loop".to_string(),
+                            serde_json::json!({
+                                "index": index + 1,
+                                "index0": index,
+                                "first": index == 0,
+                                "last": index + 1 == length,
+                                "length": length,
+                            }),
+                        )];
+
+                        match (names.as_slice(), item) {
+                            ([name], item) => scope.push((name.clone(), item)),
+                            (names, Value::Array(values)) if values.len() == names.len() => {
+                                scope.extend(names.iter().cloned().zip(values))
+                            }
+                            _ => {
+                                return Err(TemplateError::TypeError(
+                                    *line,
+                                    format!("can't unpack into {} names", names.len()),
+                                ))
+                            }
+                        }
+
+                        self.scopes.push(scope);
+                        let rendered = self.render(body, output);
+                        self.scopes.pop();
+
+                        rendered?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The name of a variable for messages.
+fn name_of(expression: &Expression) -> String {
+    match expression {
+        Expression::Variable(name, keys) => {
+            let mut name = name.clone();
+
+            for key in keys {
+                match key {
+                    Key::Name(key) => {
+                        name.push('.');
+                        name.push_str(key);
+                    }
+                    Key::Index(index) => name.push_str(&format!("[{}]", index)),
+                }
+            }
+
+            name
+        }
+        Expression::Filter(inner, _, _) => name_of(inner),
+        _ => "expression".to_string(),
+    }
+}
+
+/// Apply the filter `name`. `default` is the only filter that takes undefined values.
+fn filter(
+    line: usize,
+    name: &str,
+    value: Option<Value>,
+    arguments: Vec<Option<Value>>,
+    expression: &Expression,
+) -> Result<Value, TemplateError> {
+    let argument = |index: usize| arguments.get(index).cloned().flatten();
+
+    if name == "default" {
+        return Ok(match value {
+            None | Some(Value::Null) => argument(0).unwrap_or(Value::Null),
+            Some(value) => value,
+        });
+    }
+
+    let value = value.ok_or_else(|| TemplateError::Undefined(line, name_of(expression)))?;
+    let type_error =
+        |message: &str| TemplateError::TypeError(line, format!("'{}' {}", name, message));
+
+    Ok(match name {
+        "join" => {
+            let separator = match argument(0) {
+                Some(Value::String(separator)) => separator,
+                None => String::new(),
+                Some(_) => return Err(type_error("takes a string separator")),
+            };
+
+            match value {
+                Value::Array(values) => Value::String(
+                    values
+                        .iter()
+                        .map(|value| display(line, value))
+                        .collect::<Result<Vec<_>, _>>()?
+                        .join(&separator),
+                ),
+                _ => return Err(type_error("needs a list")),
+            }
+        }
+        "lower" | "upper" => match value {
+            Value::String(value) if name == "lower" => Value::String(value.to_lowercase()),
+            Value::String(value) => Value::String(value.to_uppercase()),
+            _ => return Err(type_error("needs a string")),
+        },
+        "length" => match value {
+            Value::String(value) => Value::from(value.chars().count()),
+            Value::Array(values) => Value::from(values.len()),
+            Value::Object(values) => Value::from(values.len()),
+            _ => return Err(type_error("needs a string, list, or object")),
+        },
+        "items" => match value {
+            Value::Object(values) => Value::Array(
+                values
+                    .into_iter()
+                    .map(|(key, value)| Value::Array(vec![Value::String(key), value]))
+                    .collect(),
+            ),
+            _ => return Err(type_error("needs an object")),
+        },
+        "json" => Value::String(value.to_string()),
+        _ => {
+            return Err(TemplateError::SyntaxError(
+                line,
+                format!("unknown filter '{}'", name),
+            ))
+        }
+    })
+}
+
+/// A parsed template. The syntax is a small subset of Jinja: `{{ value | filter }}` renders
+/// a value, `{% if %}`/`{% elif %}`/`{% else %}`/`{% endif %}` and `{% for x in xs %}`/
+/// `{% endfor %}` are blocks, and `{# ... #}` are comments. Values come only from the
+/// context; templates can't read files, run anything, or call into the host.
+///
+/// The filters are `default(value)`, `join(separator)`, `lower`, `upper`, `length`, `items`
+/// to loop over the keys and values of an object, and `json`. Rendering a variable that
+/// isn't in the context is an error unless it has a `default`.
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Self, TemplateError> {
+        let mut tokens: VecDeque<Token> = tokenize(text)?.into();
+
+        match nodes(&mut tokens, &[])? {
+            (nodes, None) => Ok(Self { nodes }),
+            (_, Some((line, block))) => Err(TemplateError::SyntaxError(
+                line,
+                format!("unexpected block '{}'", block),
+            )),
+        }
+    }
+
+    /// Render the template with the values of `context`, usually the options of a stage.
+    pub fn render(&self, context: &Value) -> Result<String, TemplateError> {
+        let mut output = String::new();
+        let mut renderer = Renderer {
+            context,
+            scopes: vec![],
+        };
+
+        renderer.render(&self.nodes, &mut output)?;
+
+        Ok(output)
+    }
+}
+
+/// Parse and render `text` with the values of `context`.
+pub fn render(text: &str, context: &Value) -> Result<String, TemplateError> {
+    Template::parse(text)?.render(context)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn values_rendered() {
+        let context = json!({
+            "servers": [{"host": "0.pool.ntp.org", "iburst": true}, {"host": "1.pool.ntp.org"}],
+            "makestep": {"threshold": 1.0, "limit": 3},
+            "name": "Chrony",
+            "ciphers": ["aes256-gcm@openssh.com", "chacha20-poly1305@openssh.com"],
+        });
+
+        assert_eq!(
+            render(
+                "{# generated -#}\n{% for server in servers -%}\nserver {{ server.host }}{% if server.iburst %} iburst{% endif %}\n{% endfor -%}\nmakestep {{ makestep.threshold }} {{ makestep['limit'] }}\n",
+                &context
+            )
+            .unwrap(),
+            "server 0.pool.ntp.org iburst\nserver 1.pool.ntp.org\nmakestep 1.0 3\n"
+        );
+        assert_eq!(
+            render(
+                "Ciphers {{ ciphers | join(',') }}\n{{ name | lower }} {{ ciphers | length }} {{ ciphers[1] | upper }}",
+                &context
+            )
+            .unwrap(),
+            "Ciphers aes256-gcm@openssh.com,chacha20-poly1305@openssh.com\nchrony 2 CHACHA20-POLY1305@OPENSSH.COM"
+        );
+        assert_eq!(
+            render(
+                "{{ port | default(22) }} {{ makestep | json }} {{ missing.key | default('x') }}",
+                &context
+            )
+            .unwrap(),
+            "22 {\"limit\":3,\"threshold\":1.0} x"
+        );
+    }
+
+    #[test]
+    fn blocks_rendered() {
+        let context = json!({
+            "options": {"PermitRootLogin": "no", "PasswordAuthentication": "yes"},
+            "mode": "server",
+            "interfaces": ["eth0", "eth1", "eth2"],
+        });
+
+        assert_eq!(
+            render(
+                "{% for key, value in options | items -%}\n{{ key }} {{ value }}\n{% endfor %}",
+                &context
+            )
+            .unwrap(),
+            "PasswordAuthentication yes\nPermitRootLogin no\n"
+        );
+        assert_eq!(
+            render(
+                "{% if mode == 'client' %}c{% elif mode == 'server' and not missing %}s{% else %}?{% endif %}",
+                &context
+            )
+            .unwrap(),
+            "s"
+        );
+        assert_eq!(
+            render(
+                "{%- for name in interfaces -%}\n  {{ name }}{% if not loop.last %},{% endif %}\n{%- endfor %}",
+                &context
+            )
+            .unwrap(),
+            "eth0,eth1,eth2"
+        );
+        assert_eq!(
+            render(
+                "{% for x in none %}x{% endfor %}{% if missing %}x{% endif %}",
+                &context
+            )
+            .unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let context = json!({"list": [1, 2], "name": "x"});
+
+        assert_eq!(
+            render("a\n{{ missing }}", &context),
+            Err(TemplateError::Undefined(2, "missing".to_string()))
+        );
+        assert_eq!(
+            render("a\n\n{{ list.key | upper }}", &context),
+            Err(TemplateError::Undefined(3, "list.key".to_string()))
+        );
+        assert!(matches!(
+            render("{{ list }}", &context),
+            Err(TemplateError::TypeError(1, _))
+        ));
+        assert!(matches!(
+            render("{% for x in name %}{% endfor %}", &context),
+            Err(TemplateError::TypeError(1, _))
+        ));
+
+        for text in [
+            "{{ name",
+            "{% if name %}",
+            "{% endif %}",
+            "{% while name %}",
+            "{{ name | nope }}",
+            "{{ name name }}",
+            "{{ 'open }}",
+            "{% for in list %}{% endfor %}",
+        ] {
+            assert!(
+                matches!(render(text, &context), Err(TemplateError::SyntaxError(..))),
+                "{}",
+                text
+            );
+        }
+    }
+}
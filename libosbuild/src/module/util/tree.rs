@@ -0,0 +1,608 @@
+use std::collections::VecDeque;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path, PathBuf};
+
+use crate::module::util::atomic::temporary_path;
+
+/// How many symlinks are followed resolving a single path, as `MAXSYMLINKS` in the kernel.
+pub const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug)]
+pub enum TreeError {
+    /// A path has more `..` components than it can go up, so it would leave the tree.
+    Escape(PathBuf),
+
+    /// Resolving a path followed more than `MAX_SYMLINKS` symlinks.
+    TooManyLinks(PathBuf),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for TreeError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// `struct open_how` of `linux/openat2.h`, the one in `libc` can't be constructed.
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+/// The root of a tree that paths from manifests, or from files inside of the tree, are
+/// joined onto. Paths can't leave the tree; absolute paths are relative to its root, `..`
+/// can't go above it, and symlinks are resolved as if the tree was the root of the
+/// filesystem, so a symlink to `/etc` in the tree points at the `etc` of the tree and not
+/// at the one of the host.
+#[derive(Debug, Clone)]
+pub struct TreePath {
+    root: PathBuf,
+}
+
+impl TreePath {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Normalize `path` to a path relative to the root of the tree without looking at the
+    /// tree. `..` is applied before symlinks are, and paths that go above the root are
+    /// refused instead of being clamped to it, as those are mistakes or attempts to escape.
+    pub fn relative(path: &Path) -> Result<PathBuf, TreeError> {
+        let mut relative = PathBuf::new();
+
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::ParentDir => {
+                    if !relative.pop() {
+                        return Err(TreeError::Escape(path.to_path_buf()));
+                    }
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+
+        Ok(relative)
+    }
+
+    /// The path on the host of `path` in the tree, with the symlinks in it resolved inside
+    /// of the tree. Components that don't exist yet are joined as they are, so this can be
+    /// used for files that are about to be created.
+    ///
+    /// The result can go stale when the tree changes before it is used; use `open`, which
+    /// lets the kernel resolve the path, where that matters.
+    pub fn resolve(&self, path: impl AsRef<Path>) -> Result<PathBuf, TreeError> {
+        let path = path.as_ref();
+        let mut pending: VecDeque<OsString> = Self::relative(path)?
+            .iter()
+            .map(|name| name.to_os_string())
+            .collect();
+        let mut resolved = self.root.clone();
+        let mut depth = 0;
+        let mut links = 0;
+
+        while let Some(name) = pending.pop_front() {
+            // only symlinks in the tree have `..` left, those stop at the root like they
+            // would for a process chrooted into the tree
+            if name == ".." {
+                if depth > 0 {
+                    resolved.pop();
+                    depth -= 1;
+                }
+
+                continue;
+            }
+
+            let candidate = resolved.join(&name);
+
+            match fs::symlink_metadata(&candidate) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    links += 1;
+
+                    if links > MAX_SYMLINKS {
+                        return Err(TreeError::TooManyLinks(path.to_path_buf()));
+                    }
+
+                    let target = fs::read_link(&candidate)?;
+
+                    if target.is_absolute() {
+                        resolved = self.root.clone();
+                        depth = 0;
+                    }
+
+                    for component in target.components().rev() {
+                        match component {
+                            Component::Normal(name) => pending.push_front(name.to_os_string()),
+                            Component::ParentDir => pending.push_front("..".into()),
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {
+                    resolved = candidate;
+                    depth += 1;
+                }
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// Open `path` in the tree for reading, the kernel resolves it inside of the tree with
+    /// `openat2(2)` and `RESOLVE_IN_ROOT`, so symlinks swapped in while the path is resolved
+    /// can't lead out of the tree.
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<fs::File, TreeError> {
+        self.open_in_root(path.as_ref(), libc::O_RDONLY | libc::O_CLOEXEC)
+    }
+
+    /// Open `path` in the tree with `flags` as `open` does.
+    fn open_in_root(&self, path: &Path, flags: libc::c_int) -> Result<fs::File, TreeError> {
+        let root = fs::File::open(&self.root)?;
+        let relative = Self::relative(path)?;
+        let relative = if relative.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            &relative
+        };
+        let relative = c_path(relative)?;
+
+        let how = OpenHow {
+            flags: flags as u64,
+            mode: 0,
+            resolve: libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS,
+        };
+
+        // SAFETY: the path and `how` outlive the call, and `how` is the size passed
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                root.as_raw_fd(),
+                relative.as_ptr(),
+                &how,
+                std::mem::size_of::<OpenHow>(),
+            )
+        };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // SAFETY: the descriptor was just opened and nothing else owns it
+        Ok(unsafe { fs::File::from_raw_fd(fd as libc::c_int) })
+    }
+
+    /// Create the directory `path` in the tree along with its parents. Returns the path of
+    /// the directory on the host.
+    pub fn create_dir_all(&self, path: impl AsRef<Path>) -> Result<PathBuf, TreeError> {
+        let resolved = self.resolve(path)?;
+
+        fs::create_dir_all(&resolved)?;
+
+        Ok(resolved)
+    }
+
+    /// Write `data` to the file `path` in the tree, creating it and its parent directories
+    /// if needed, with `mode`. The file is replaced as `atomic_write` does, in its directory
+    /// as opened with `open`, so the data can't end up outside of the tree when symlinks
+    /// are swapped in while it is written. A symlink at `path` is replaced rather than
+    /// followed. Returns the path of the file on the host.
+    pub fn write(
+        &self,
+        path: impl AsRef<Path>,
+        data: impl AsRef<[u8]>,
+        mode: u32,
+    ) -> Result<PathBuf, TreeError> {
+        let relative = Self::relative(path.as_ref())?;
+        let name = relative.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the root of a tree is not a file",
+            )
+        })?;
+        let parent = relative.parent().unwrap_or(Path::new(""));
+
+        let path = self.create_dir_all(parent)?.join(name);
+        let directory =
+            self.open_in_root(parent, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC)?;
+
+        let temporary = c_path(&temporary_path(Path::new(name))?)?;
+        let name = c_path(Path::new(name))?;
+
+        // SAFETY: the name outlives the call
+        let fd = unsafe {
+            libc::openat(
+                directory.as_raw_fd(),
+                temporary.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                mode as libc::c_uint,
+            )
+        };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // SAFETY: the descriptor was just opened and nothing else owns it
+        let mut file = unsafe { fs::File::from_raw_fd(fd) };
+
+        let written = (|| {
+            file.write_all(data.as_ref())?;
+
+            // the mode the file was created with is subject to the umask
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+            file.sync_all()?;
+
+            // SAFETY: the names outlive the call
+            if unsafe {
+                libc::renameat(
+                    directory.as_raw_fd(),
+                    temporary.as_ptr(),
+                    directory.as_raw_fd(),
+                    name.as_ptr(),
+                )
+            } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        })();
+
+        if let Err(err) = written {
+            // SAFETY: the name outlives the call
+            unsafe { libc::unlinkat(directory.as_raw_fd(), temporary.as_ptr(), 0) };
+
+            return Err(err.into());
+        }
+
+        directory.sync_all()?;
+
+        Ok(path)
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Copy `from` to `to` as `cp -a` does; directories recursively, symlinks as symlinks, and
+/// keeping permissions, modification times, and, when permitted, owners. Other kinds of
+/// files such as devices are skipped. Returns how many files were copied.
+///
+/// What is at `to` is never followed; files and symlinks there are removed before they are
+/// replaced, and directories are only copied into when they are directories and not
+/// symlinks to them, so a symlink in the tree that is copied into, such as an absolute one
+/// that points at the host, can't redirect the copy.
+pub fn copy_all(from: &Path, to: &Path) -> io::Result<usize> {
+    let metadata = fs::symlink_metadata(from)?;
+    let file_type = metadata.file_type();
+    let mut copied = 1;
+
+    let existing = match fs::symlink_metadata(to) {
+        Ok(existing) => Some(existing),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+
+    // directories are copied into, anything else that is in the way is replaced
+    match existing {
+        Some(existing) if existing.is_dir() && file_type.is_dir() => {}
+        Some(existing) if existing.is_dir() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("'{}' is a directory", to.display()),
+            ))
+        }
+        Some(_) if file_type.is_dir() || file_type.is_file() || file_type.is_symlink() => {
+            fs::remove_file(to)?
+        }
+        _ => {}
+    }
+
+    if file_type.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    } else if file_type.is_dir() {
+        if !to.is_dir() {
+            fs::create_dir(to)?;
+        }
+
+        let mut entries: Vec<fs::DirEntry> = fs::read_dir(from)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            copied += copy_all(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else if file_type.is_file() {
+        let mut source = fs::File::open(from)?;
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(to)?;
+
+        io::copy(&mut source, &mut file)?;
+    } else {
+        return Ok(0);
+    }
+
+    // only root can give files away, other owners are a best effort
+    match std::os::unix::fs::lchown(to, Some(metadata.uid()), Some(metadata.gid())) {
+        Err(err) if err.kind() != io::ErrorKind::PermissionDenied => return Err(err),
+        _ => {}
+    }
+
+    // setting permissions on a symlink would set them on what it points to
+    if !file_type.is_symlink() {
+        fs::set_permissions(to, metadata.permissions())?;
+    }
+
+    set_times(to, &metadata)?;
+
+    Ok(copied)
+}
+
+/// Call `visit` with `path` and, when it is a directory, everything below it, in filename
+/// order with directories before what is in them. Symlinks are visited but not followed.
+/// Returns how many files were visited.
+pub fn walk<F: FnMut(&Path, &fs::Metadata) -> io::Result<()>>(
+    path: &Path,
+    visit: &mut F,
+) -> io::Result<usize> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut visited = 1;
+
+    visit(path, &metadata)?;
+
+    if metadata.is_dir() {
+        let mut entries: Vec<fs::DirEntry> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            visited += walk(&entry.path(), visit)?;
+        }
+    }
+
+    Ok(visited)
+}
+
+/// Set the access and modification times of `path`, not of what it points to when it is a
+/// symlink, to those in `metadata`.
+pub fn set_times(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    let path = c_path(path)?;
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime() as libc::time_t,
+            tv_nsec: metadata.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime() as libc::time_t,
+            tv_nsec: metadata.mtime_nsec() as _,
+        },
+    ];
+
+    // SAFETY: the path and times outlive the call
+    if unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Read;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn relative_paths() {
+        for (path, relative) in [
+            ("/etc/passwd", "etc/passwd"),
+            ("etc/./passwd", "etc/passwd"),
+            ("/etc/../usr//lib/", "usr/lib"),
+            ("/", ""),
+        ] {
+            assert_eq!(
+                TreePath::relative(Path::new(path)).unwrap(),
+                Path::new(relative)
+            );
+        }
+
+        for path in ["..", "/../etc", "etc/../../passwd"] {
+            assert!(matches!(
+                TreePath::relative(Path::new(path)),
+                Err(TreeError::Escape(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn symlinks_resolved_in_tree() {
+        let tree = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let root = tree.path();
+
+        fs::create_dir_all(root.join("usr/etc")).unwrap();
+        symlink("/usr/etc", root.join("etc")).unwrap();
+        symlink("../../../../../..", root.join("usr/up")).unwrap();
+        symlink(outside.path(), root.join("host")).unwrap();
+        symlink("loop", root.join("loop")).unwrap();
+
+        let tree = TreePath::new(root);
+
+        assert_eq!(
+            tree.resolve("/etc/passwd").unwrap(),
+            root.join("usr/etc/passwd")
+        );
+        assert_eq!(tree.resolve("usr/up/etc").unwrap(), root.join("usr/etc"));
+        assert_eq!(
+            tree.resolve("host/file").unwrap(),
+            root.join(outside.path().strip_prefix("/").unwrap())
+                .join("file")
+        );
+        assert!(matches!(
+            tree.resolve("loop/file"),
+            Err(TreeError::TooManyLinks(_))
+        ));
+        assert!(matches!(tree.resolve("../etc"), Err(TreeError::Escape(_))));
+
+        // writes through symlinks that point outside of the tree stay inside of it
+        let written = tree.write("/host/file", "data", 0o644).unwrap();
+
+        assert!(written.starts_with(root));
+        assert!(!outside.path().join("file").exists());
+        assert_eq!(fs::read_to_string(&written).unwrap(), "data");
+
+        tree.write("etc/passwd", "root:x:0:0::/root:/bin/bash\n", 0o644)
+            .unwrap();
+
+        let mut data = String::new();
+        tree.open("/etc/passwd")
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+
+        assert_eq!(data, "root:x:0:0::/root:/bin/bash\n");
+        assert!(root.join("usr/etc/passwd").is_file());
+
+        // files get the mode they are written with, whatever the umask
+        let shadow = tree.write("etc/shadow", "root:!::0:::::\n", 0o600).unwrap();
+
+        assert_eq!(fs::metadata(&shadow).unwrap().mode() & 0o7777, 0o600);
+
+        // a symlink at the path is replaced, what it points to is left alone
+        symlink(outside.path().join("motd"), root.join("usr/etc/motd")).unwrap();
+        tree.write("etc/motd", "hi\n", 0o644).unwrap();
+
+        assert!(!outside.path().join("motd").exists());
+        assert!(root.join("usr/etc/motd").is_file());
+    }
+
+    #[test]
+    fn trees_copied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let from = tempfile::tempdir().unwrap();
+        let to = tempfile::tempdir().unwrap();
+        let epoch = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1700000000);
+
+        fs::create_dir_all(from.path().join("etc/ssh")).unwrap();
+        fs::write(from.path().join("etc/ssh/sshd_config"), "Port 22\n").unwrap();
+        fs::set_permissions(
+            from.path().join("etc/ssh/sshd_config"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        fs::File::open(from.path().join("etc/ssh/sshd_config"))
+            .unwrap()
+            .set_modified(epoch)
+            .unwrap();
+        symlink("ssh/sshd_config", from.path().join("etc/link")).unwrap();
+
+        let target = to.path().join("copy");
+
+        assert_eq!(copy_all(&from.path().join("etc"), &target).unwrap(), 4);
+
+        let metadata = fs::metadata(target.join("ssh/sshd_config")).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(target.join("ssh/sshd_config")).unwrap(),
+            "Port 22\n"
+        );
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(metadata.modified().unwrap(), epoch);
+        assert_eq!(
+            fs::read_link(target.join("link")).unwrap(),
+            Path::new("ssh/sshd_config")
+        );
+    }
+
+    #[test]
+    fn trees_copied_over_symlinks() {
+        let from = tempfile::tempdir().unwrap();
+        let to = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(from.path().join("etc/ssh")).unwrap();
+        fs::write(from.path().join("etc/localtime"), "TZif").unwrap();
+        fs::write(from.path().join("etc/ssh/sshd_config"), "Port 22\n").unwrap();
+
+        // an absolute symlink means the host outside of the tree
+        fs::create_dir_all(to.path().join("etc")).unwrap();
+        symlink("/usr/share/zoneinfo/UTC", to.path().join("etc/localtime")).unwrap();
+        symlink(outside.path(), to.path().join("etc/ssh")).unwrap();
+
+        copy_all(&from.path().join("etc"), &to.path().join("etc")).unwrap();
+
+        assert!(!fs::symlink_metadata(to.path().join("etc/localtime"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(
+            fs::read_to_string(to.path().join("etc/localtime")).unwrap(),
+            "TZif"
+        );
+        assert!(fs::symlink_metadata(to.path().join("etc/ssh"))
+            .unwrap()
+            .is_dir());
+        assert!(to.path().join("etc/ssh/sshd_config").is_file());
+        assert!(!outside.path().join("sshd_config").exists());
+    }
+
+    #[test]
+    fn trees_walked() {
+        let root = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(root.path().join("etc/ssh")).unwrap();
+        fs::write(root.path().join("etc/ssh/sshd_config"), "").unwrap();
+        fs::write(root.path().join("etc/hostname"), "").unwrap();
+        symlink("/", root.path().join("etc/root")).unwrap();
+
+        let mut visited = vec![];
+
+        let count = walk(&root.path().join("etc"), &mut |path, _| {
+            visited.push(path.strip_prefix(root.path()).unwrap().to_path_buf());
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(count, 5);
+        assert_eq!(
+            visited,
+            [
+                "etc",
+                "etc/hostname",
+                "etc/root",
+                "etc/ssh",
+                "etc/ssh/sshd_config"
+            ]
+            .map(PathBuf::from)
+        );
+    }
+}
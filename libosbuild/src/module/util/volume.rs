@@ -0,0 +1,566 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::core::secrets::Secret;
+
+/// Where device mapper devices, such as opened LUKS containers and logical volumes, appear.
+pub const MAPPER_PATH: &str = "/dev/mapper";
+
+#[derive(Debug)]
+pub enum VolumeError {
+    /// A tool failed, contains its name, what it was asked to do, its exit code, and its
+    /// stderr.
+    Failed(String, String, Option<i32>, String),
+
+    /// Undoing the setup failed, contains the failures of the steps that failed; the other
+    /// steps were still run.
+    TeardownFailed(Vec<VolumeError>),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for VolumeError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// Run `command` with `stdin`, failures are those of `tool` doing `action`.
+fn run(mut command: Command, stdin: &[u8], tool: &str, action: &str) -> Result<(), VolumeError> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut input) = child.stdin.take() {
+        input.write_all(stdin)?;
+    }
+
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        return Err(VolumeError::Failed(
+            tool.to_string(),
+            action.to_string(),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// A step that undoes part of a setup.
+#[derive(Debug)]
+struct Step {
+    tool: String,
+    action: String,
+    command: Command,
+}
+
+/// The steps that undo a setup, such as closing a LUKS container and deactivating a volume
+/// group on it. They run in the reverse of the order they were pushed in, so what was set up
+/// last is torn down first. A teardown that is dropped runs its steps, so a setup that fails
+/// half way undoes what it did.
+#[derive(Debug, Default)]
+pub struct Teardown {
+    steps: Vec<Step>,
+}
+
+impl Teardown {
+    pub fn new() -> Self {
+        Self { steps: vec![] }
+    }
+
+    /// Undo with `command`, the action of `tool`, before everything pushed so far.
+    pub fn push(&mut self, command: Command, tool: &str, action: &str) {
+        self.steps.push(Step {
+            tool: tool.to_string(),
+            action: action.to_string(),
+            command,
+        });
+    }
+
+    /// Run all steps, also those after one that failed, and fail with the failures.
+    pub fn run(mut self) -> Result<(), VolumeError> {
+        let failures = self.run_steps();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(VolumeError::TeardownFailed(failures))
+        }
+    }
+
+    fn run_steps(&mut self) -> Vec<VolumeError> {
+        let mut failures = vec![];
+
+        while let Some(step) = self.steps.pop() {
+            if let Err(err) = run(step.command, &[], &step.tool, &step.action) {
+                failures.push(err);
+            }
+        }
+
+        failures
+    }
+}
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        for err in self.run_steps() {
+            log::warn!("teardown failed: {:?}", err);
+        }
+    }
+}
+
+/// The key LUKS containers are formatted and opened with, it is handed to cryptsetup on
+/// stdin when it is a passphrase.
+#[derive(Debug, Clone)]
+pub enum Key {
+    File(PathBuf),
+    Passphrase(Secret),
+}
+
+impl Key {
+    fn arguments(&self, command: &mut Command) {
+        match self {
+            Self::File(path) => command.arg("--key-file").arg(path),
+            Self::Passphrase(_) => command.args(["--key-file", "-"]),
+        };
+    }
+
+    fn stdin(&self) -> &[u8] {
+        match self {
+            Self::File(_) => &[],
+            Self::Passphrase(secret) => secret.expose(),
+        }
+    }
+}
+
+/// A LUKS2 container, as the `org.osbuild.luks2` device and `org.osbuild.luks2.format` stage
+/// take it.
+#[derive(Debug, Clone)]
+pub struct Luks {
+    pub cryptsetup: PathBuf,
+    pub key: Key,
+
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+    pub subsystem: Option<String>,
+    pub cipher: Option<String>,
+
+    /// The key derivation function, such as `argon2i` or `pbkdf2`. Builds use `pbkdf2` with a
+    /// low iteration count, the key is replaced when the image is first booted.
+    pub pbkdf: Option<String>,
+    pub pbkdf_iterations: Option<u32>,
+
+    pub sector_size: Option<u32>,
+}
+
+impl Luks {
+    pub fn new(key: Key) -> Self {
+        Self {
+            cryptsetup: PathBuf::from("cryptsetup"),
+            key,
+            uuid: None,
+            label: None,
+            subsystem: None,
+            cipher: None,
+            pbkdf: None,
+            pbkdf_iterations: None,
+            sector_size: None,
+        }
+    }
+
+    /// The cryptsetup command that formats `device` as a container.
+    pub fn format_command(&self, device: &Path) -> Command {
+        let mut command = Command::new(&self.cryptsetup);
+
+        command.args(["--batch-mode", "luksFormat", "--type", "luks2"]);
+        self.key.arguments(&mut command);
+
+        for (option, value) in [
+            ("--uuid", &self.uuid),
+            ("--label", &self.label),
+            ("--subsystem", &self.subsystem),
+            ("--cipher", &self.cipher),
+            ("--pbkdf", &self.pbkdf),
+        ] {
+            if let Some(value) = value {
+                command.arg(option).arg(value);
+            }
+        }
+
+        if let Some(iterations) = self.pbkdf_iterations {
+            command
+                .arg("--pbkdf-force-iterations")
+                .arg(iterations.to_string());
+        }
+
+        if let Some(sector_size) = self.sector_size {
+            command.arg("--sector-size").arg(sector_size.to_string());
+        }
+
+        command.arg(device);
+        command
+    }
+
+    /// The cryptsetup command that opens the container on `device` as `name`.
+    pub fn open_command(&self, device: &Path, name: &str) -> Command {
+        let mut command = Command::new(&self.cryptsetup);
+
+        command.args(["open", "--type", "luks2"]);
+        self.key.arguments(&mut command);
+        command.arg(device).arg(name);
+
+        command
+    }
+
+    /// The cryptsetup command that closes the container opened as `name`.
+    pub fn close_command(&self, name: &str) -> Command {
+        let mut command = Command::new(&self.cryptsetup);
+
+        command.arg("close").arg(name);
+        command
+    }
+
+    /// Format `device` as a container.
+    pub fn format(&self, device: &Path) -> Result<(), VolumeError> {
+        run(
+            self.format_command(device),
+            self.key.stdin(),
+            "cryptsetup",
+            "luksFormat",
+        )
+    }
+
+    /// Open the container on `device` as `name`, pushing its closing onto `teardown`.
+    /// Returns the path of the opened container.
+    pub fn open(
+        &self,
+        device: &Path,
+        name: &str,
+        teardown: &mut Teardown,
+    ) -> Result<PathBuf, VolumeError> {
+        run(
+            self.open_command(device, name),
+            self.key.stdin(),
+            "cryptsetup",
+            "open",
+        )?;
+
+        teardown.push(self.close_command(name), "cryptsetup", "close");
+
+        Ok(Path::new(MAPPER_PATH).join(name))
+    }
+}
+
+/// A logical volume, as the `org.osbuild.lvm2.create` stage takes them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogicalVolume {
+    pub name: String,
+
+    /// The size in the units of lvcreate, such as `2G` or `100%FREE` when it ends in `%...`.
+    pub size: String,
+}
+
+/// A volume group on a single physical volume, with logical volumes in it.
+#[derive(Debug, Clone)]
+pub struct Lvm {
+    /// The `lvm` binary, its commands are run as `lvm <command>`.
+    pub lvm: PathBuf,
+
+    pub group: String,
+    pub volumes: Vec<LogicalVolume>,
+}
+
+impl Lvm {
+    pub fn new(group: &str) -> Self {
+        Self {
+            lvm: PathBuf::from("lvm"),
+            group: group.to_string(),
+            volumes: vec![],
+        }
+    }
+
+    fn command(&self, action: &str) -> Command {
+        let mut command = Command::new(&self.lvm);
+
+        command.arg(action);
+        command
+    }
+
+    /// The lvcreate command of `volume`, it is created without being wiped or activated so
+    /// no udev rules of the host run for it.
+    pub fn lvcreate_command(&self, volume: &LogicalVolume) -> Command {
+        let mut command = self.command("lvcreate");
+
+        command.args(["--yes", "--activate", "n", "--zero", "n"]);
+
+        if volume.size.contains('%') {
+            command.arg("--extents").arg(&volume.size);
+        } else {
+            command.arg("--size").arg(&volume.size);
+        }
+
+        command.arg("--name").arg(&volume.name).arg(&self.group);
+        command
+    }
+
+    /// The vgchange command that activates, or deactivates, the volume group.
+    pub fn vgchange_command(&self, activate: bool) -> Command {
+        let mut command = self.command("vgchange");
+
+        command
+            .arg("--activate")
+            .arg(if activate { "y" } else { "n" })
+            .arg(&self.group);
+        command
+    }
+
+    /// Create the volume group and its logical volumes on `device`. The logical volumes are
+    /// created inactive, so there is nothing to tear down until the group is activated.
+    pub fn create(&self, device: &Path) -> Result<(), VolumeError> {
+        let mut pvcreate = self.command("pvcreate");
+        pvcreate.arg("--yes").arg(device);
+
+        run(pvcreate, &[], "lvm", "pvcreate")?;
+
+        let mut vgcreate = self.command("vgcreate");
+        vgcreate.arg("--yes").arg(&self.group).arg(device);
+
+        run(vgcreate, &[], "lvm", "vgcreate")?;
+
+        for volume in &self.volumes {
+            run(self.lvcreate_command(volume), &[], "lvm", "lvcreate")?;
+        }
+
+        Ok(())
+    }
+
+    /// Activate the volume group, pushing its deactivation onto `teardown`. Returns the paths
+    /// of the logical volumes.
+    pub fn activate(&self, teardown: &mut Teardown) -> Result<Vec<PathBuf>, VolumeError> {
+        run(self.vgchange_command(true), &[], "lvm", "vgchange")?;
+
+        teardown.push(self.vgchange_command(false), "lvm", "vgchange");
+
+        Ok(self
+            .volumes
+            .iter()
+            .map(|volume| Path::new("/dev").join(&self.group).join(&volume.name))
+            .collect())
+    }
+}
+
+/// Set up encrypted LVM on `device`; a LUKS container opened as `name`
+/// with a volume group in it, whose logical volumes are activated. Returns the paths of the
+/// logical volumes and the teardown that closes everything again. When a step fails what was
+/// set up before it is torn down.
+pub fn setup_encrypted_lvm(
+    device: &Path,
+    name: &str,
+    luks: &Luks,
+    lvm: &Lvm,
+) -> Result<(Vec<PathBuf>, Teardown), VolumeError> {
+    let mut teardown = Teardown::new();
+
+    luks.format(device)?;
+
+    let container = luks.open(device, name, &mut teardown)?;
+
+    lvm.create(&container)?;
+
+    let volumes = lvm.activate(&mut teardown)?;
+
+    Ok((volumes, teardown))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn arguments(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    /// A tool that logs its name, arguments, and stdin to `log` and fails when its arguments
+    /// contain `fail`.
+    fn tool(directory: &Path, name: &str, fail: &str) -> PathBuf {
+        let path = directory.join(name);
+        let log = directory.join("log");
+
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\necho {name} \"$@\" $(cat) >> {log}\ncase \"$*\" in *{fail}*) exit 3;; esac\n",
+                name = name,
+                log = log.display(),
+                fail = fail
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn containers_formatted() {
+        let luks = Luks {
+            uuid: Some("b8f4a2a3-3c1a-4d5e-9e3b-6f7a8b9c0d1e".to_string()),
+            pbkdf: Some("pbkdf2".to_string()),
+            pbkdf_iterations: Some(1000),
+            ..Luks::new(Key::Passphrase(Secret::new("password")))
+        };
+
+        let args = arguments(&luks.format_command(Path::new("/dev/loop0p3")));
+
+        assert_eq!(
+            args,
+            [
+                "--batch-mode",
+                "luksFormat",
+                "--type",
+                "luks2",
+                "--key-file",
+                "-",
+                "--uuid",
+                "b8f4a2a3-3c1a-4d5e-9e3b-6f7a8b9c0d1e",
+                "--pbkdf",
+                "pbkdf2",
+                "--pbkdf-force-iterations",
+                "1000",
+                "/dev/loop0p3"
+            ]
+        );
+        assert!(!args.iter().any(|arg| arg.contains("password")));
+
+        let luks = Luks::new(Key::File(PathBuf::from("/run/keyfile")));
+
+        assert_eq!(
+            arguments(&luks.open_command(Path::new("/dev/loop0p3"), "luks-root")),
+            [
+                "open",
+                "--type",
+                "luks2",
+                "--key-file",
+                "/run/keyfile",
+                "/dev/loop0p3",
+                "luks-root"
+            ]
+        );
+    }
+
+    #[test]
+    fn volumes_created() {
+        let lvm = Lvm::new("rootvg");
+
+        assert_eq!(
+            arguments(&lvm.lvcreate_command(&LogicalVolume {
+                name: "root".to_string(),
+                size: "100%FREE".to_string(),
+            })),
+            [
+                "lvcreate",
+                "--yes",
+                "--activate",
+                "n",
+                "--zero",
+                "n",
+                "--extents",
+                "100%FREE",
+                "--name",
+                "root",
+                "rootvg"
+            ]
+        );
+    }
+
+    #[test]
+    fn torn_down_in_reverse() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = directory.path().join("log");
+
+        let luks = Luks {
+            cryptsetup: tool(directory.path(), "cryptsetup", "never"),
+            ..Luks::new(Key::Passphrase(Secret::new("password")))
+        };
+
+        let lvm = Lvm {
+            lvm: tool(directory.path(), "lvm", "lvcreate"),
+            volumes: vec![LogicalVolume {
+                name: "root".to_string(),
+                size: "2G".to_string(),
+            }],
+            ..Lvm::new("rootvg")
+        };
+
+        let err =
+            setup_encrypted_lvm(Path::new("/dev/loop0p3"), "luks-root", &luks, &lvm).unwrap_err();
+
+        assert!(matches!(
+            err,
+            VolumeError::Failed(tool, action, Some(3), _) if tool == "lvm" && action == "lvcreate"
+        ));
+
+        let steps: Vec<String> = fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| line.split(' ').take(2).collect::<Vec<_>>().join(" "))
+            .collect();
+
+        assert_eq!(
+            steps,
+            [
+                "cryptsetup --batch-mode",
+                "cryptsetup open",
+                "lvm pvcreate",
+                "lvm vgcreate",
+                "lvm lvcreate",
+                "cryptsetup close",
+            ]
+        );
+
+        // the passphrase went to stdin
+        assert!(fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .ends_with("/dev/loop0p3 password"));
+    }
+
+    #[test]
+    fn teardown_continued() {
+        let directory = tempfile::tempdir().unwrap();
+        let fails = tool(directory.path(), "failing", "");
+        let log = directory.path().join("log");
+
+        let mut teardown = Teardown::new();
+
+        for step in ["first", "second"] {
+            let mut command = Command::new(&fails);
+            command.arg(step);
+
+            teardown.push(command, "failing", step);
+        }
+
+        assert!(matches!(
+            teardown.run(),
+            Err(VolumeError::TeardownFailed(failures)) if failures.len() == 2
+        ));
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "failing second\nfailing first\n"
+        );
+    }
+}
@@ -1,10 +1,849 @@
+/// What the preprocessor recorded of the directives it evaluated.
+pub mod trace;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::dependency::repository::Repository;
+use crate::dependency::solver::{Request, Solution, Solver, SolverError};
+use crate::manifest::include::{self, IncludeError};
+use crate::manifest::path::{Part, Path as Location};
+
+use self::trace::{Event, Trace};
+
+/// The key of a manifest that defines variables for its format directives.
+pub const VARS: &str = "mpp-vars";
+
+/// The key of an item of `pipelines` that is replaced by the pipelines of another manifest.
+pub const IMPORT_PIPELINES: &str = "mpp-import-pipelines";
+
+/// The key of an object that resolves package specs into the `references` of the object.
+pub const DEPSOLVE: &str = "mpp-depsolve";
+
+/// The keys of objects that are replaced by a string with variables substituted, and by that
+/// string as an integer or as JSON.
+pub const FORMATS: &[&str] = &["mpp-format-string", "mpp-format-int", "mpp-format-json"];
+
+/// The source downloads of resolved packages are added to.
+const CURL: &str = "org.osbuild.curl";
+
 #[derive(Debug)]
-pub enum PreprocessorError {}
+pub enum PreprocessorError {
+    /// A file could not be read.
+    IOError(PathBuf, io::Error),
+
+    /// A file is not valid JSON.
+    ParseError(PathBuf, serde_json::Error),
+
+    /// A directive isn't written as it should be, contains where it is and why.
+    Invalid(String, String),
+
+    /// A format directive refers to a variable that isn't defined, contains its name and
+    /// where the directive is.
+    Undefined(String, String),
+
+    /// A file imports itself through the files it imports, contains the file.
+    ImportCycle(PathBuf),
+
+    /// A manifest depsolves but the preprocessor has no solver, contains where.
+    NoSolver(String),
+
+    /// The sources of an imported manifest, or of resolved packages, conflict with those of
+    /// the manifest.
+    IncludeError(IncludeError),
+
+    SolverError(SolverError),
+}
+
+impl From<IncludeError> for PreprocessorError {
+    fn from(err: IncludeError) -> Self {
+        Self::IncludeError(err)
+    }
+}
+
+impl From<SolverError> for PreprocessorError {
+    fn from(err: SolverError) -> Self {
+        Self::SolverError(err)
+    }
+}
+
+/// The arguments of `mpp-import-pipelines`, a manifest relative to the importing one and the
+/// names of the pipelines to import; all of them when not given.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Import {
+    path: PathBuf,
+
+    #[serde(default)]
+    ids: Option<Vec<String>>,
+}
+
+/// The arguments of `mpp-depsolve`.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Depsolve {
+    architecture: String,
+
+    #[serde(default)]
+    releasever: Option<String>,
+
+    #[serde(default)]
+    module_platform_id: Option<String>,
+
+    repos: Vec<Repository>,
+    packages: Vec<String>,
+
+    #[serde(default)]
+    excludes: Vec<String>,
+}
+
+/// Resolves the `mpp-*` directives of manifests into manifests osbuild can build, recording
+/// every directive it evaluates in a trace. Identical depsolves are only done once.
+///
+/// The output is the same for the same templates and repositories, so manifests can be
+/// reviewed by their diffs; objects are sorted by key, and packages are in a fixed order
+/// whatever order the solver gives them in. `to_string` writes manifests the same way.
+#[derive(Default)]
+pub struct Preprocessor {
+    solver: Option<Box<dyn Solver>>,
+    cache: HashMap<String, Solution>,
+    trace: Trace,
+
+    /// The files being loaded, to find imports that go around in circles.
+    loading: Vec<PathBuf>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve `mpp-depsolve` with `solver`; without one manifests that depsolve fail.
+    pub fn with_solver(mut self, solver: Box<dyn Solver>) -> Self {
+        self.solver = Some(solver);
+        self
+    }
+
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// Read the manifest at `path` and resolve its directives.
+    pub fn load(&mut self, path: &Path) -> Result<serde_json::Value, PreprocessorError> {
+        self.load_with(path, &serde_json::Map::new())
+    }
+
+    /// Resolve the directives of `manifest`, which is read from `file`; imports are relative
+    /// to it.
+    pub fn process(
+        &mut self,
+        manifest: serde_json::Value,
+        file: &Path,
+    ) -> Result<serde_json::Value, PreprocessorError> {
+        self.process_with(manifest, file, &serde_json::Map::new())
+    }
+
+    fn load_with(
+        &mut self,
+        path: &Path,
+        inherited: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<serde_json::Value, PreprocessorError> {
+        let canonical = fs::canonicalize(path)
+            .map_err(|err| PreprocessorError::IOError(path.to_path_buf(), err))?;
+
+        if self.loading.contains(&canonical) {
+            return Err(PreprocessorError::ImportCycle(path.to_path_buf()));
+        }
+
+        let data =
+            fs::read(path).map_err(|err| PreprocessorError::IOError(path.to_path_buf(), err))?;
+        let manifest = serde_json::from_slice(&data)
+            .map_err(|err| PreprocessorError::ParseError(path.to_path_buf(), err))?;
+
+        self.loading.push(canonical);
+        let result = self.process_with(manifest, path, inherited);
+        self.loading.pop();
+
+        result
+    }
+
+    fn process_with(
+        &mut self,
+        mut manifest: serde_json::Value,
+        file: &Path,
+        inherited: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<serde_json::Value, PreprocessorError> {
+        let mut vars = inherited.clone();
+
+        if let Some(defined) = manifest
+            .as_object_mut()
+            .and_then(|object| object.remove(VARS))
+        {
+            let serde_json::Value::Object(defined) = defined else {
+                return Err(PreprocessorError::Invalid(
+                    format!(".{}", VARS),
+                    "must be an object".to_string(),
+                ));
+            };
+
+            self.trace.record(
+                file.to_path_buf(),
+                format!(".{}", VARS),
+                Event::Define {
+                    names: {
+                        let mut names: Vec<String> = defined.keys().cloned().collect();
+                        names.sort();
+                        names
+                    },
+                },
+            );
+
+            vars.extend(defined);
+        }
+
+        self.import_pipelines(&mut manifest, file, &vars)?;
+
+        let mut downloads = BTreeMap::new();
+
+        self.resolve(&mut manifest, file, &vars, &mut vec![], &mut downloads)?;
+
+        if !downloads.is_empty() {
+            include::merge_source(
+                sources(&mut manifest)?,
+                CURL,
+                serde_json::json!({ "items": downloads }),
+            )?;
+        }
+
+        Ok(sorted(manifest))
+    }
+
+    /// Replace the imports of the pipelines of `manifest` by the pipelines they import, and
+    /// merge the sources of the imported manifests into its sources.
+    fn import_pipelines(
+        &mut self,
+        manifest: &mut serde_json::Value,
+        file: &Path,
+        vars: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(), PreprocessorError> {
+        let Some(pipelines) = manifest
+            .get_mut("pipelines")
+            .and_then(|pipelines| pipelines.as_array_mut())
+        else {
+            return Ok(());
+        };
+
+        let base = file.parent().unwrap_or_else(|| Path::new(""));
+        let mut spliced = vec![];
+        let mut imported_sources = vec![];
+
+        for (index, pipeline) in std::mem::take(pipelines).into_iter().enumerate() {
+            let Some(import) = pipeline.get(IMPORT_PIPELINES) else {
+                spliced.push(pipeline);
+                continue;
+            };
+
+            let at = format!(".pipelines[{}].{}", index, IMPORT_PIPELINES);
+            let import: Import = serde_json::from_value(import.clone())
+                .map_err(|err| PreprocessorError::Invalid(at.clone(), err.to_string()))?;
+
+            let mut imported = self.load_with(&base.join(&import.path), vars)?;
+
+            let mut pipelines = match imported.get_mut("pipelines").map(serde_json::Value::take) {
+                Some(serde_json::Value::Array(pipelines)) => pipelines,
+                _ => vec![],
+            };
+
+            if let Some(ids) = &import.ids {
+                pipelines.retain(|pipeline| ids.iter().any(|id| name(pipeline) == Some(id)));
+
+                if let Some(missing) = ids
+                    .iter()
+                    .find(|id| !pipelines.iter().any(|p| name(p) == Some(id)))
+                {
+                    return Err(PreprocessorError::Invalid(
+                        at,
+                        format!("'{}' has no pipeline '{}'", import.path.display(), missing),
+                    ));
+                }
+            }
+
+            self.trace.record(
+                file.to_path_buf(),
+                at,
+                Event::Import {
+                    path: import.path,
+                    pipelines: pipelines
+                        .iter()
+                        .filter_map(name)
+                        .map(str::to_string)
+                        .collect(),
+                },
+            );
+
+            if let Some(serde_json::Value::Object(sources)) =
+                imported.get_mut("sources").map(serde_json::Value::take)
+            {
+                imported_sources.push(sources);
+            }
+
+            spliced.extend(pipelines);
+        }
+
+        *pipelines = spliced;
+
+        for imported in imported_sources {
+            let sources = sources(manifest)?;
+
+            for (name, source) in imported {
+                include::merge_source(sources, &name, source)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve the format directives and depsolves in `value`, which is at `at` in `file`.
+    /// Depsolves are resolved after the directives in their arguments.
+    fn resolve(
+        &mut self,
+        value: &mut serde_json::Value,
+        file: &Path,
+        vars: &serde_json::Map<String, serde_json::Value>,
+        at: &mut Vec<Part>,
+        downloads: &mut BTreeMap<String, String>,
+    ) -> Result<(), PreprocessorError> {
+        if let Some((kind, template)) = format_directive(value, at)? {
+            let (formatted, variables) = format(kind, template, vars, at)?;
+
+            self.trace.record(
+                file.to_path_buf(),
+                Location::new(at.clone()).to_string(),
+                Event::Format {
+                    kind: kind.to_string(),
+                    variables,
+                    value: formatted.clone(),
+                },
+            );
+
+            *value = formatted;
+
+            return Ok(());
+        }
+
+        match value {
+            serde_json::Value::Array(items) => {
+                for (index, item) in items.iter_mut().enumerate() {
+                    at.push(Part::Index(index));
+                    self.resolve(item, file, vars, at, downloads)?;
+                    at.pop();
+                }
+            }
+            serde_json::Value::Object(object) => {
+                for (key, item) in object.iter_mut() {
+                    at.push(Part::Name(key.clone()));
+                    self.resolve(item, file, vars, at, downloads)?;
+                    at.pop();
+                }
+
+                if let Some(arguments) = object.remove(DEPSOLVE) {
+                    let references = self.depsolve(arguments, file, at, downloads)?;
+
+                    object.insert("references".to_string(), references);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Resolve the arguments of a depsolve into the checksums of the packages, adding their
+    /// downloads to `downloads`.
+    fn depsolve(
+        &mut self,
+        arguments: serde_json::Value,
+        file: &Path,
+        at: &[Part],
+        downloads: &mut BTreeMap<String, String>,
+    ) -> Result<serde_json::Value, PreprocessorError> {
+        let location = Location::new(at.to_vec()).to_string();
+        let invalid = |reason: String| PreprocessorError::Invalid(location.clone(), reason);
+
+        let arguments: Depsolve =
+            serde_json::from_value(arguments).map_err(|err| invalid(err.to_string()))?;
+
+        let request = Request {
+            arch: arguments.architecture,
+            releasever: arguments.releasever,
+            module_platform_id: arguments.module_platform_id,
+            repos: arguments.repos,
+            specs: arguments.packages,
+            exclude: arguments.excludes,
+            ..Default::default()
+        };
+
+        let key = serde_json::to_string(&request).map_err(|err| invalid(err.to_string()))?;
+        let cached = self.cache.contains_key(&key);
+
+        if !cached {
+            let solver = self
+                .solver
+                .as_ref()
+                .ok_or_else(|| PreprocessorError::NoSolver(location.clone()))?;
+
+            let mut solution = solver.depsolve(&request)?;
+
+            solution.packages.sort_by(|a, b| {
+                (&a.name, &a.arch, a.evr(), &a.repo_id).cmp(&(
+                    &b.name,
+                    &b.arch,
+                    b.evr(),
+                    &b.repo_id,
+                ))
+            });
+
+            self.cache.insert(key.clone(), solution);
+        }
+
+        let solution = &self.cache[&key];
+        let mut references = vec![];
+
+        for package in &solution.packages {
+            let (Some(checksum), Some(url)) = (&package.checksum, &package.remote_location) else {
+                return Err(invalid(format!(
+                    "package '{}' has no checksum or location",
+                    package.name
+                )));
+            };
+
+            downloads.insert(checksum.clone(), url.clone());
+            references.push(serde_json::Value::String(checksum.clone()));
+        }
+
+        self.trace.record(
+            file.to_path_buf(),
+            location,
+            Event::Depsolve {
+                specs: request.specs,
+                packages: references.len(),
+                cached,
+            },
+        );
+
+        Ok(serde_json::Value::Array(references))
+    }
+}
+
+/// Write `manifest` as the preprocessor does; pretty printed, with a newline at the end.
+pub fn to_string(manifest: &serde_json::Value) -> Result<String, serde_json::Error> {
+    Ok(serde_json::to_string_pretty(&sorted(manifest.clone()))? + "\n")
+}
+
+/// `value` with the keys of its objects sorted; they are already unless `serde_json` keeps
+/// the order of insertion, which another crate in the build can turn on.
+fn sorted(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Array(items) => items.into_iter().map(sorted).collect(),
+        serde_json::Value::Object(object) => {
+            let mut entries: Vec<(String, serde_json::Value)> = object.into_iter().collect();
+
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            serde_json::Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, sorted(value)))
+                    .collect(),
+            )
+        }
+        value => value,
+    }
+}
+
+fn name(pipeline: &serde_json::Value) -> Option<&str> {
+    pipeline.get("name").and_then(|name| name.as_str())
+}
+
+/// The sources of `manifest`, added when it has none.
+fn sources(
+    manifest: &mut serde_json::Value,
+) -> Result<&mut serde_json::Map<String, serde_json::Value>, PreprocessorError> {
+    manifest
+        .as_object_mut()
+        .ok_or_else(|| {
+            PreprocessorError::Invalid(".".to_string(), "must be an object".to_string())
+        })?
+        .entry("sources")
+        .or_insert_with(|| serde_json::json!({}))
+        .as_object_mut()
+        .ok_or_else(|| {
+            PreprocessorError::Invalid(".sources".to_string(), "must be an object".to_string())
+        })
+}
+
+/// The kind and template of `value` when it is a format directive, an object of only one of
+/// the format keys with a string.
+fn format_directive<'v>(
+    value: &'v serde_json::Value,
+    at: &[Part],
+) -> Result<Option<(&'static str, &'v str)>, PreprocessorError> {
+    let Some(object) = value.as_object().filter(|object| object.len() == 1) else {
+        return Ok(None);
+    };
+
+    let Some(kind) = FORMATS.iter().find(|kind| object.contains_key(**kind)) else {
+        return Ok(None);
+    };
+
+    match object[*kind].as_str() {
+        Some(template) => Ok(Some((kind, template))),
+        None => Err(PreprocessorError::Invalid(
+            Location::new(at.to_vec()).to_string(),
+            format!("{} must be a string", kind),
+        )),
+    }
+}
+
+/// Substitute the variables `template` refers to, `{name}`; `{{` and `}}` are literal braces.
+/// Strings are substituted as they are and other values as JSON. Returns the value of the
+/// kind of directive and the variables that were referred to.
+fn format(
+    kind: &str,
+    template: &str,
+    vars: &serde_json::Map<String, serde_json::Value>,
+    at: &[Part],
+) -> Result<(serde_json::Value, Vec<String>), PreprocessorError> {
+    let location = || Location::new(at.to_vec()).to_string();
+
+    let mut text = String::new();
+    let mut names: Vec<String> = vec![];
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        text.push_str(&rest[..start]);
+
+        let brace = &rest[start..start + 1];
+        let after = &rest[start + 1..];
+
+        if let Some(after) = after.strip_prefix(brace) {
+            text.push_str(brace);
+            rest = after;
+            continue;
+        }
+
+        let end = match (brace, after.find('}')) {
+            ("{", Some(end)) => end,
+            _ => {
+                return Err(PreprocessorError::Invalid(
+                    location(),
+                    format!("unbalanced braces in '{}'", template),
+                ))
+            }
+        };
+
+        let name = after[..end].trim();
+        let value = vars
+            .get(name)
+            .ok_or_else(|| PreprocessorError::Undefined(name.to_string(), location()))?;
+
+        match value {
+            serde_json::Value::String(value) => text.push_str(value),
+            value => text.push_str(&value.to_string()),
+        }
+
+        if !names.iter().any(|other| other == name) {
+            names.push(name.to_string());
+        }
+
+        rest = &after[end + 1..];
+    }
+
+    text.push_str(rest);
+
+    let value = match kind {
+        "mpp-format-int" => text
+            .trim()
+            .parse::<i64>()
+            .map(serde_json::Value::from)
+            .map_err(|_| {
+                PreprocessorError::Invalid(location(), format!("'{}' is not an integer", text))
+            })?,
+        "mpp-format-json" => serde_json::from_str(&text).map_err(|err| {
+            PreprocessorError::Invalid(location(), format!("'{}' is not JSON: {}", text, err))
+        })?,
+        _ => serde_json::Value::String(text),
+    };
+
+    Ok((value, names))
+}
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::dependency::solver::Package;
+
+    /// Resolves every spec into a package of the same name, counting the requests. The
+    /// packages are in the order of the specs, or reversed.
+    struct FakeSolver {
+        calls: Rc<Cell<usize>>,
+        reversed: bool,
+    }
+
+    impl Solver for FakeSolver {
+        fn depsolve(&self, request: &Request) -> Result<Solution, SolverError> {
+            self.calls.set(self.calls.get() + 1);
+
+            let mut packages: Vec<Package> = request
+                .specs
+                .iter()
+                .map(|spec| Package {
+                    name: spec.clone(),
+                    epoch: 0,
+                    version: "1".to_string(),
+                    release: "1".to_string(),
+                    arch: request.arch.clone(),
+                    repo_id: "fedora".to_string(),
+                    remote_location: Some(format!("https://example.com/{}.rpm", spec)),
+                    checksum: Some(format!("sha256:{}", spec)),
+                })
+                .collect();
+
+            if self.reversed {
+                packages.reverse();
+            }
+
+            Ok(Solution {
+                packages,
+                ..Default::default()
+            })
+        }
+    }
+
+    fn write(directory: &Path, name: &str, value: serde_json::Value) -> PathBuf {
+        let path = directory.join(name);
+
+        fs::write(&path, serde_json::to_vec(&value).unwrap()).unwrap();
+
+        path
+    }
+
+    fn rpm_stage() -> serde_json::Value {
+        serde_json::json!({
+            "type": "org.osbuild.rpm",
+            "inputs": {"packages": {
+                "type": "org.osbuild.files",
+                "origin": "org.osbuild.source",
+                "mpp-depsolve": {
+                    "architecture": {"mpp-format-string": "{arch}"},
+                    "repos": [{"id": "fedora", "baseurl": ["https://example.com/"]}],
+                    "packages": ["bash", "kernel"]
+                }
+            }}
+        })
+    }
+
     #[test]
-    fn dummy() {
-        assert_eq!(1, 1);
+    fn manifests_preprocessed() {
+        let directory = tempfile::tempdir().unwrap();
+
+        write(
+            directory.path(),
+            "build.json",
+            serde_json::json!({
+                "version": "2",
+                "pipelines": [
+                    {"name": "build", "stages": [rpm_stage()]},
+                    {"name": "unused"}
+                ],
+                "sources": {"org.osbuild.curl": {"items": {"sha256:extra": "https://example.com/extra.rpm"}}}
+            }),
+        );
+
+        let path = write(
+            directory.path(),
+            "manifest.json",
+            serde_json::json!({
+                "version": "2",
+                "mpp-vars": {"arch": "x86_64", "size": 4096},
+                "pipelines": [
+                    {"mpp-import-pipelines": {"path": "build.json", "ids": ["build"]}},
+                    {"name": "os", "build": "name:build", "stages": [
+                        rpm_stage(),
+                        {"type": "org.osbuild.truncate", "options": {
+                            "filename": {"mpp-format-string": "disk-{arch}.img"},
+                            "size": {"mpp-format-int": "{size}"},
+                            "label": {"mpp-format-string": "{{{arch}}}"}
+                        }}
+                    ]}
+                ]
+            }),
+        );
+
+        let calls = Rc::new(Cell::new(0));
+        let mut preprocessor = Preprocessor::new().with_solver(Box::new(FakeSolver {
+            calls: calls.clone(),
+            reversed: false,
+        }));
+
+        let manifest = preprocessor.load(&path).unwrap();
+
+        assert!(manifest.get(VARS).is_none());
+        assert_eq!(manifest["pipelines"].as_array().unwrap().len(), 2);
+        assert_eq!(manifest["pipelines"][0]["name"], "build");
+        assert_eq!(
+            manifest["pipelines"][1]["stages"][0]["inputs"]["packages"],
+            serde_json::json!({
+                "type": "org.osbuild.files",
+                "origin": "org.osbuild.source",
+                "references": ["sha256:bash", "sha256:kernel"]
+            })
+        );
+        assert_eq!(
+            manifest["pipelines"][1]["stages"][1]["options"],
+            serde_json::json!({"filename": "disk-x86_64.img", "size": 4096, "label": "{x86_64}"})
+        );
+        assert_eq!(
+            manifest["sources"][CURL]["items"],
+            serde_json::json!({
+                "sha256:bash": "https://example.com/bash.rpm",
+                "sha256:extra": "https://example.com/extra.rpm",
+                "sha256:kernel": "https://example.com/kernel.rpm"
+            })
+        );
+
+        // the imported manifest inherits the variables and the two identical depsolves are
+        // done once
+        assert_eq!(calls.get(), 1);
+
+        let trace = preprocessor.trace();
+
+        assert_eq!(trace.cache_hits(), 1);
+        assert_eq!(
+            trace
+                .entries
+                .iter()
+                .map(|entry| (
+                    entry.file.file_name().unwrap().to_str().unwrap(),
+                    entry.at.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            [
+                ("manifest.json", ".mpp-vars"),
+                (
+                    "build.json",
+                    ".pipelines[0].stages[0].inputs.packages.mpp-depsolve.architecture"
+                ),
+                ("build.json", ".pipelines[0].stages[0].inputs.packages"),
+                ("manifest.json", ".pipelines[0].mpp-import-pipelines"),
+                (
+                    "manifest.json",
+                    ".pipelines[1].stages[0].inputs.packages.mpp-depsolve.architecture"
+                ),
+                ("manifest.json", ".pipelines[1].stages[0].inputs.packages"),
+                ("manifest.json", ".pipelines[1].stages[1].options.filename"),
+                ("manifest.json", ".pipelines[1].stages[1].options.label"),
+                ("manifest.json", ".pipelines[1].stages[1].options.size"),
+            ]
+        );
+        assert!(trace
+            .to_string()
+            .contains("mpp-depsolve resolved bash, kernel into 2 packages (cached)"));
+    }
+
+    #[test]
+    fn output_deterministic() {
+        let directory = tempfile::tempdir().unwrap();
+
+        write(
+            directory.path(),
+            "build.json",
+            serde_json::json!({"version": "2", "pipelines": [{"name": "build", "stages": [rpm_stage()]}]}),
+        );
+
+        let path = write(
+            directory.path(),
+            "manifest.json",
+            serde_json::json!({
+                "version": "2",
+                "mpp-vars": {"zone": "UTC", "arch": "aarch64"},
+                "pipelines": [
+                    {"mpp-import-pipelines": {"path": "build.json"}},
+                    {"name": "os", "stages": [rpm_stage()]}
+                ]
+            }),
+        );
+
+        let run = |reversed: bool| {
+            let mut preprocessor = Preprocessor::new().with_solver(Box::new(FakeSolver {
+                calls: Rc::new(Cell::new(0)),
+                reversed,
+            }));
+
+            let manifest = preprocessor.load(&path).unwrap();
+
+            (
+                to_string(&manifest).unwrap(),
+                preprocessor.trace().to_string(),
+            )
+        };
+
+        let (first, first_trace) = run(false);
+        let (second, second_trace) = run(true);
+
+        // the solver giving the packages in another order doesn't change a byte
+        assert_eq!(first.as_bytes(), second.as_bytes());
+        assert_eq!(first_trace.as_bytes(), second_trace.as_bytes());
+        assert!(first.ends_with("}\n"));
+        assert!(first_trace.contains("mpp-vars defined arch, zone"));
+    }
+
+    #[test]
+    fn directives_checked() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("manifest.json");
+
+        let process = |manifest: serde_json::Value| Preprocessor::new().process(manifest, &file);
+
+        assert!(matches!(
+            process(serde_json::json!({"pipelines": [{"name": {"mpp-format-string": "{nope}"}}]})),
+            Err(PreprocessorError::Undefined(name, at)) if name == "nope" && at == ".pipelines[0].name"
+        ));
+        assert!(matches!(
+            process(serde_json::json!({"mpp-vars": {"a": "b"}, "x": {"mpp-format-int": "{a}"}})),
+            Err(PreprocessorError::Invalid(at, _)) if at == ".x"
+        ));
+        assert!(matches!(
+            process(serde_json::json!({"x": {"mpp-format-string": "{"}})),
+            Err(PreprocessorError::Invalid(_, _))
+        ));
+        assert!(matches!(
+            process(serde_json::json!({"mpp-vars": {"arch": "x86_64"}, "stage": rpm_stage()})),
+            Err(PreprocessorError::NoSolver(at)) if at == ".stage.inputs.packages"
+        ));
+
+        write(
+            directory.path(),
+            "a.json",
+            serde_json::json!({"pipelines": [{"mpp-import-pipelines": {"path": "b.json"}}]}),
+        );
+        let path = write(
+            directory.path(),
+            "b.json",
+            serde_json::json!({"pipelines": [{"mpp-import-pipelines": {"path": "a.json"}}]}),
+        );
+
+        assert!(matches!(
+            Preprocessor::new().load(&path),
+            Err(PreprocessorError::ImportCycle(_))
+        ));
     }
 }
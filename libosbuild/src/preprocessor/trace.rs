@@ -0,0 +1,120 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+/// A directive the preprocessor evaluated, and what it did.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "directive")]
+pub enum Event {
+    /// `mpp-vars` defined variables; those a file imports with inherit the variables of the
+    /// file that imports it.
+    #[serde(rename = "mpp-vars")]
+    Define { names: Vec<String> },
+
+    /// `mpp-import-pipelines` spliced the pipelines of another manifest in.
+    #[serde(rename = "mpp-import-pipelines")]
+    Import {
+        path: PathBuf,
+        pipelines: Vec<String>,
+    },
+
+    /// One of the `mpp-format-*` directives substituted variables into a value.
+    #[serde(rename = "mpp-format")]
+    Format {
+        kind: String,
+        variables: Vec<String>,
+        value: serde_json::Value,
+    },
+
+    /// `mpp-depsolve` resolved package specs, or took the packages of an identical request
+    /// that was resolved before.
+    #[serde(rename = "mpp-depsolve")]
+    Depsolve {
+        specs: Vec<String>,
+        packages: usize,
+        cached: bool,
+    },
+}
+
+/// An event, with the file and the place in it of the directive.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Entry {
+    pub file: PathBuf,
+    pub at: String,
+
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Every directive the preprocessor evaluated, in the order it did; so authors of templates
+/// can tell why the manifest it resolved to looks the way it does.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Trace {
+    pub entries: Vec<Entry>,
+}
+
+impl Trace {
+    pub fn record(&mut self, file: PathBuf, at: String, event: Event) {
+        self.entries.push(Entry { file, at, event });
+    }
+
+    /// The number of depsolves that were answered from the cache.
+    pub fn cache_hits(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| matches!(entry.event, Event::Depsolve { cached: true, .. }))
+            .count()
+    }
+}
+
+/// The trace as one line per directive, `file:path directive what it did`.
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            write!(f, "{}:{} ", entry.file.display(), entry.at)?;
+
+            match &entry.event {
+                Event::Define { names } => {
+                    writeln!(f, "mpp-vars defined {}", names.join(", "))?;
+                }
+                Event::Import { path, pipelines } => {
+                    writeln!(
+                        f,
+                        "mpp-import-pipelines imported {} from {}",
+                        pipelines.join(", "),
+                        path.display()
+                    )?;
+                }
+                Event::Format {
+                    kind,
+                    variables,
+                    value,
+                } => {
+                    writeln!(
+                        f,
+                        "{} substituted {} into {}",
+                        kind,
+                        variables.join(", "),
+                        value
+                    )?;
+                }
+                Event::Depsolve {
+                    specs,
+                    packages,
+                    cached,
+                } => {
+                    writeln!(
+                        f,
+                        "mpp-depsolve resolved {} into {} packages{}",
+                        specs.join(", "),
+                        packages,
+                        if *cached { " (cached)" } else { "" }
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The directory the sockets of the services of the host are in, in the sandbox.
+pub const API_DIRECTORY: &str = "/run/osbuild/api";
+
+/// Variable that moves all sockets to another directory, for when osbuild keeps its
+/// runtime directory elsewhere such as below `$XDG_RUNTIME_DIR` for rootless builds.
+pub const API_DIRECTORY_VARIABLE: &str = "OSBUILD_API_DIRECTORY";
+
+/// Prefix of the variables that set the socket of a single service, the name of the
+/// service in upper case follows it; `OSBUILD_API_SOCKET_LOG`.
+pub const SOCKET_VARIABLE_PREFIX: &str = "OSBUILD_API_SOCKET_";
+
+/// The services of the host that modules talk to, each has a socket of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Service {
+    /// Where modules send their log and progress.
+    Log,
+
+    /// The osbuild API; arguments, exceptions, and metadata of modules.
+    Api,
+
+    /// Fetching the items of sources.
+    Sources,
+
+    /// Signals of the host to a module, such as to cancel it. Unlike the others the module
+    /// binds this socket, and only when the host sets it.
+    Control,
+
+    /// The clock and entropy of the host, see `communication::services`. A stream socket,
+    /// only there when the host sets it.
+    Host,
+}
+
+impl Service {
+    pub const ALL: [Service; 5] = [
+        Service::Log,
+        Service::Api,
+        Service::Sources,
+        Service::Control,
+        Service::Host,
+    ];
+
+    /// The name of the socket of the service in the API directory, as osbuild names them.
+    pub fn socket_name(&self) -> &'static str {
+        match self {
+            Service::Log => "log",
+            Service::Api => "osbuild",
+            Service::Sources => "sources",
+            Service::Control => "control",
+            Service::Host => "host",
+        }
+    }
+
+    /// The variable that sets the socket of the service.
+    pub fn variable(&self) -> String {
+        format!(
+            "{}{}",
+            SOCKET_VARIABLE_PREFIX,
+            self.to_string().to_uppercase()
+        )
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Service::Log => write!(f, "log"),
+            Service::Api => write!(f, "api"),
+            Service::Sources => write!(f, "sources"),
+            Service::Control => write!(f, "control"),
+            Service::Host => write!(f, "host"),
+        }
+    }
+}
+
+/// Where the sockets of the services are. Every socket is in the API directory under its
+/// well-known name unless it is set on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelConfig {
+    directory: PathBuf,
+    sockets: BTreeMap<Service, PathBuf>,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self::new(Path::new(API_DIRECTORY))
+    }
+}
+
+impl ChannelConfig {
+    /// Sockets in `directory`.
+    pub fn new(directory: &Path) -> Self {
+        Self {
+            directory: directory.to_path_buf(),
+            sockets: BTreeMap::new(),
+        }
+    }
+
+    /// The configuration set by the environment of this process, see `from_variables`.
+    pub fn from_env() -> Self {
+        Self::from_variables(|name| env::var_os(name).map(PathBuf::from))
+    }
+
+    /// The configuration set by the variables `lookup` finds; the API directory from
+    /// `API_DIRECTORY_VARIABLE` and the socket of each service from its `variable`. Empty
+    /// variables are ignored.
+    pub fn from_variables<F: Fn(&str) -> Option<PathBuf>>(lookup: F) -> Self {
+        let lookup = |name: &str| lookup(name).filter(|path| !path.as_os_str().is_empty());
+
+        let mut config = match lookup(API_DIRECTORY_VARIABLE) {
+            Some(directory) => Self::new(&directory),
+            None => Self::default(),
+        };
+
+        for service in Service::ALL {
+            if let Some(path) = lookup(&service.variable()) {
+                config.set_socket(service, &path);
+            }
+        }
+
+        config
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Use `path` as the socket of `service` instead of the one in the API directory.
+    pub fn set_socket(&mut self, service: Service, path: &Path) {
+        self.sockets.insert(service, path.to_path_buf());
+    }
+
+    /// The socket of `service` when it was set on its own.
+    pub fn configured(&self, service: Service) -> Option<&Path> {
+        self.sockets.get(&service).map(PathBuf::as_path)
+    }
+
+    /// The socket of `service`.
+    pub fn socket(&self, service: Service) -> PathBuf {
+        self.sockets
+            .get(&service)
+            .cloned()
+            .unwrap_or_else(|| self.directory.join(service.socket_name()))
+    }
+
+    /// The variables that give modules this configuration through `from_env`, only what
+    /// differs from the default is set.
+    pub fn environment(&self) -> Vec<(String, String)> {
+        let mut environment = vec![];
+
+        if self.directory != Path::new(API_DIRECTORY) {
+            environment.push((
+                API_DIRECTORY_VARIABLE.to_string(),
+                self.directory.to_string_lossy().to_string(),
+            ));
+        }
+
+        for (service, path) in &self.sockets {
+            environment.push((service.variable(), path.to_string_lossy().to_string()));
+        }
+
+        environment
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_sockets() {
+        let config = ChannelConfig::from_variables(|_| None);
+
+        assert_eq!(config, ChannelConfig::default());
+        assert_eq!(
+            config.socket(Service::Log),
+            PathBuf::from("/run/osbuild/api/log")
+        );
+        assert_eq!(
+            config.socket(Service::Api),
+            PathBuf::from("/run/osbuild/api/osbuild")
+        );
+        assert_eq!(config.configured(Service::Control), None);
+        assert!(config.environment().is_empty());
+    }
+
+    #[test]
+    fn relocated_sockets() {
+        let variables = BTreeMap::from([
+            ("OSBUILD_API_DIRECTORY", "/run/user/1000/osbuild/api"),
+            ("OSBUILD_API_SOCKET_SOURCES", "/run/sources.sock"),
+            ("OSBUILD_API_SOCKET_LOG", ""),
+            ("OSBUILD_API_SOCKET_CONTROL", "/run/control.sock"),
+        ]);
+
+        let config = ChannelConfig::from_variables(|name| variables.get(name).map(PathBuf::from));
+
+        assert_eq!(
+            config.socket(Service::Log),
+            PathBuf::from("/run/user/1000/osbuild/api/log")
+        );
+        assert_eq!(
+            config.socket(Service::Sources),
+            PathBuf::from("/run/sources.sock")
+        );
+        assert_eq!(
+            config.configured(Service::Control),
+            Some(Path::new("/run/control.sock"))
+        );
+
+        // what the host sets is what modules find
+        let environment: BTreeMap<String, String> = config.environment().into_iter().collect();
+
+        assert_eq!(
+            ChannelConfig::from_variables(|name| environment.get(name).map(PathBuf::from)),
+            config
+        );
+    }
+}
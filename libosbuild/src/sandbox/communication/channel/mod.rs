@@ -7,6 +7,13 @@ pub mod transport;
 /// objects expected.
 pub mod protocol;
 
+/// Where the sockets of the services of the host are, osbuild can move them.
+pub mod config;
+
+/// Handlers for signals that arrive while a module does its work.
+pub mod signals;
+
+#[cfg(unix)]
 use transport::Transport;
 
 use protocol::message::encoding::*;
@@ -17,11 +24,26 @@ use serde::Serialize;
 
 use std::str;
 
+/// The socket in the sandbox that modules log to unless it is moved, see `config::ChannelConfig`.
+pub const LOG_SOCKET: &str = "/run/osbuild/api/log";
+
+/// The largest message that can be received, larger messages are an error.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
 #[derive(Debug)]
 pub enum ChannelError {
     Transport(transport::TransportError),
     Protocol(protocol::ProtocolError),
     Encoding(protocol::message::encoding::EncodingError),
+
+    /// The peer sent a message that is not valid UTF-8.
+    InvalidUtf8(str::Utf8Error),
+
+    /// The peer sent a message larger than `MAX_MESSAGE_SIZE`, it was cut off.
+    Truncated,
+
+    /// The peer closed the channel, or sent an empty message which no message is.
+    Closed,
 }
 
 impl From<transport::TransportError> for ChannelError {
@@ -78,16 +100,39 @@ pub struct CommandChannel {
     pub protocol: Box<dyn protocol::Protocol>,
 }
 
-impl Channel for CommandChannel {
-    fn new_default() -> Result<Self, ChannelError> {
+#[cfg(unix)]
+impl CommandChannel {
+    /// A channel to the socket of `service` in `config`.
+    pub fn for_service(
+        config: &config::ChannelConfig,
+        service: config::Service,
+    ) -> Result<Self, ChannelError> {
         Ok(Self {
             transport: Box::new(transport::UnixDGRAMSocket::new(
-                "/run/osbuild/api/log".to_string(),
+                config.socket(service).to_string_lossy().to_string(),
                 None,
             )?),
             protocol: Box::new(protocol::JSONProtocol {}),
         })
     }
+}
+
+impl Channel for CommandChannel {
+    /// The log socket as the environment configures it, see `config::ChannelConfig::from_env`.
+    #[cfg(unix)]
+    fn new_default() -> Result<Self, ChannelError> {
+        Self::for_service(&config::ChannelConfig::from_env(), config::Service::Log)
+    }
+
+    /// The default channel is a unix socket, elsewhere a transport has to be chosen.
+    #[cfg(not(unix))]
+    fn new_default() -> Result<Self, ChannelError> {
+        Err(transport::TransportError::IOError(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the default channel needs unix sockets",
+        ))
+        .into())
+    }
 
     fn send<T: Message + Serialize>(&mut self, object: T) -> Result<usize, ChannelError> {
         let enc = JSONEncoding {};
@@ -99,27 +144,29 @@ impl Channel for CommandChannel {
         let enc = JSONEncoding {};
 
         // XXX let the protocol handle this, it knows boundaries for encoded messages
-        let mut dat = vec![0u8; 1024];
+        // one byte more than the largest message so that truncation can be noticed
+        let mut dat = vec![0u8; MAX_MESSAGE_SIZE + 1];
+        let size = self.transport.recv(&mut dat)?;
+
+        if size == 0 {
+            return Err(ChannelError::Closed);
+        }
 
-        self.transport.recv(&mut dat)?;
+        if size > MAX_MESSAGE_SIZE {
+            return Err(ChannelError::Truncated);
+        }
 
-        Ok(enc.decode::<T>(str::from_utf8(&dat).unwrap())?)
+        let text = str::from_utf8(&dat[..size]).map_err(ChannelError::InvalidUtf8)?;
+
+        Ok(enc.decode::<T>(text)?)
     }
 
     fn send_and_recv<T0: Message + Serialize, T1: Message + DeserializeOwned>(
         &mut self,
         object: T0,
     ) -> Result<T1, ChannelError> {
-        let enc = JSONEncoding {};
-
-        self.transport.send_all(&enc.encode(object)?)?;
-
-        // XXX let the protocol handle this, it knows boundaries for encoded messages
-        let mut dat = vec![0u8; 1024];
-
-        self.transport.recv(&mut dat)?;
-
-        Ok(enc.decode::<T1>(str::from_utf8(&dat).unwrap())?)
+        self.send(object)?;
+        self.recv()
     }
 
     fn open(&mut self, _path: &str) -> Result<(), ChannelError> {
@@ -141,21 +188,17 @@ mod test {
 
     #[test]
     fn command_channel_send() {
-        let path = "/tmp/channel";
-        let sock = UnixDatagram::bind(path.to_string()).unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("channel");
+        let path = path.to_str().unwrap();
+        let sock = UnixDatagram::bind(path).unwrap();
 
         let mut channel = CommandChannel {
             transport: Box::new(transport::UnixDGRAMSocket::new(path.to_string(), None).unwrap()),
             protocol: Box::new(protocol::JSONProtocol {}),
         };
 
-        let method = Method {
-            r#type: MessageType::Method,
-            method: "test".to_string(),
-            data: MethodData {
-                name: "name".to_string(),
-            },
-        };
+        let method = Method::new("test", serde_json::json!([]));
 
         let size = channel.send(method).unwrap();
         let mut buffer = vec![0; size];
@@ -166,9 +209,74 @@ mod test {
         // confirm the message wasn't erroneously translated or is a literal fine?
         assert_eq!(
             buffer,
-            b"{\"type\":\"Method\",\"method\":\"test\",\"data\":{\"name\":\"name\"}}"
+            b"{\"type\": \"method\", \"data\": {\"name\": \"test\", \"args\": []}}"
         );
 
         remove_file(path).unwrap();
     }
+
+    /// A channel and the socket of its peer, in a temporary directory.
+    fn channel_pair(directory: &std::path::Path) -> (CommandChannel, UnixDatagram) {
+        let peer_path = directory.join("peer");
+        let own_path = directory.join("own");
+
+        let peer = UnixDatagram::bind(&peer_path).unwrap();
+        let channel = CommandChannel {
+            transport: Box::new(
+                transport::UnixDGRAMSocket::new(
+                    peer_path.to_string_lossy().to_string(),
+                    Some(own_path.to_string_lossy().to_string()),
+                )
+                .unwrap(),
+            ),
+            protocol: Box::new(protocol::JSONProtocol {}),
+        };
+
+        peer.connect(own_path).unwrap();
+
+        (channel, peer)
+    }
+
+    #[test]
+    fn command_channel_recv() {
+        let directory = tempfile::tempdir().unwrap();
+        let (mut channel, peer) = channel_pair(directory.path());
+
+        peer.send(b"{\"type\": \"method\", \"data\": {\"name\": \"test\"}}")
+            .unwrap();
+
+        let method: Method = channel.recv().unwrap();
+        assert_eq!(method.data.name, "test");
+        assert_eq!(method.data.args, serde_json::json!([]));
+    }
+
+    #[test]
+    fn command_channel_recv_misbehaving_peer() {
+        let directory = tempfile::tempdir().unwrap();
+        let (mut channel, peer) = channel_pair(directory.path());
+
+        peer.send(&[0xff, 0xfe, 0xfd]).unwrap();
+        assert!(matches!(
+            channel.recv::<Method>(),
+            Err(ChannelError::InvalidUtf8(_))
+        ));
+
+        peer.send(&vec![b' '; MAX_MESSAGE_SIZE + 10]).unwrap();
+        assert!(matches!(
+            channel.recv::<Method>(),
+            Err(ChannelError::Truncated)
+        ));
+
+        peer.send(b"{}").unwrap();
+        assert!(matches!(
+            channel.recv::<Method>(),
+            Err(ChannelError::Encoding(_))
+        ));
+
+        peer.send(b"").unwrap();
+        assert!(matches!(
+            channel.recv::<Method>(),
+            Err(ChannelError::Closed)
+        ));
+    }
 }
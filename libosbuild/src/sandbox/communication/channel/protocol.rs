@@ -1,7 +1,7 @@
 #[derive(Debug)]
 pub enum ProtocolError {}
 
-pub trait Protocol {
+pub trait Protocol: Send {
     fn new() -> Result<Self, ProtocolError>
     where
         Self: Sized;
@@ -17,11 +17,13 @@ impl Protocol for JSONProtocol {
 }
 
 /// Message types that exist in the protocols. Some of these messages can only be sent
-/// over certain types of transports).
+/// over certain types of transports). Messages are those of osbuild's Python implementation,
+/// see `osbuild.host.ServiceProtocol`.
 pub mod message {
     use serde::{Deserialize, Serialize};
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
     pub enum MessageType {
         Method,
         Reply,
@@ -34,53 +36,195 @@ pub mod message {
 
     pub trait Message {}
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    fn no_arguments() -> serde_json::Value {
+        serde_json::Value::Array(vec![])
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
     pub struct MethodData {
         pub name: String,
+
+        /// A list or an object, methods without arguments get an empty list.
+        #[serde(default = "no_arguments")]
+        pub args: serde_json::Value,
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
     pub struct Method {
         pub r#type: MessageType,
-        pub method: String,
         pub data: MethodData,
     }
 
+    impl Method {
+        pub fn new(name: &str, args: serde_json::Value) -> Self {
+            Self {
+                r#type: MessageType::Method,
+                data: MethodData {
+                    name: name.to_string(),
+                    args,
+                },
+            }
+        }
+    }
+
     impl Message for Method {}
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    pub struct ReplyData {}
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct ReplyData {
+        /// Indices of the file descriptors sent along with the message.
+        #[serde(default)]
+        pub fds: Vec<usize>,
+
+        #[serde(default)]
+        pub reply: serde_json::Value,
+    }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
     pub struct Reply {
-        r#type: MessageType,
-        data: ReplyData,
+        pub r#type: MessageType,
+        pub data: ReplyData,
+    }
+
+    impl Reply {
+        pub fn new(reply: serde_json::Value, fds: Vec<usize>) -> Self {
+            Self {
+                r#type: MessageType::Reply,
+                data: ReplyData { fds, reply },
+            }
+        }
     }
 
     impl Message for Reply {}
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    pub struct SignalData {}
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct SignalData {
+        #[serde(default)]
+        pub reply: serde_json::Value,
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+        /// Indices of the file descriptors sent along with the message, only sent when
+        /// there are any.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub fds: Vec<usize>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
     pub struct Signal {
-        r#type: MessageType,
-        data: SignalData,
+        pub r#type: MessageType,
+        pub data: SignalData,
+    }
+
+    impl Signal {
+        pub fn new(reply: serde_json::Value, fds: Vec<usize>) -> Self {
+            Self {
+                r#type: MessageType::Signal,
+                data: SignalData { reply, fds },
+            }
+        }
     }
 
     impl Message for Signal {}
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    /// The kinds of signals, a signal is of the kind whose key is in its reply object.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub enum SignalKind {
+        /// A log message of a module; `{"message": "...", "origin": "..."}`.
+        Log,
+
+        /// How far a module is; `{"progress": {"done": 3, "total": 10}}`.
+        Progress,
+
+        /// The host asks a module to stop what it is doing; `{"cancel": {"reason": "..."}}`.
+        Cancel,
+
+        /// The configuration of a module changed and is to be read again;
+        /// `{"reload": {...}}`.
+        Reload,
+    }
+
+    impl SignalKind {
+        pub const ALL: [SignalKind; 4] = [
+            SignalKind::Log,
+            SignalKind::Progress,
+            SignalKind::Cancel,
+            SignalKind::Reload,
+        ];
+
+        /// The key of the kind in the reply of a signal.
+        pub fn key(&self) -> &'static str {
+            match self {
+                SignalKind::Log => "message",
+                SignalKind::Progress => "progress",
+                SignalKind::Cancel => "cancel",
+                SignalKind::Reload => "reload",
+            }
+        }
+    }
+
+    impl Signal {
+        /// A signal of `kind` that carries `value`.
+        pub fn of_kind(kind: SignalKind, value: serde_json::Value) -> Self {
+            let mut reply = serde_json::Map::new();
+
+            reply.insert(kind.key().to_string(), value);
+
+            Self::new(serde_json::Value::Object(reply), vec![])
+        }
+
+        pub fn progress(done: u64, total: u64) -> Self {
+            Self::of_kind(
+                SignalKind::Progress,
+                serde_json::json!({"done": done, "total": total}),
+            )
+        }
+
+        pub fn cancel(reason: &str) -> Self {
+            Self::of_kind(SignalKind::Cancel, serde_json::json!({ "reason": reason }))
+        }
+
+        pub fn reload(config: serde_json::Value) -> Self {
+            Self::of_kind(SignalKind::Reload, config)
+        }
+
+        /// The kind of the signal, signals of other kinds or without an object as their
+        /// reply have none.
+        pub fn kind(&self) -> Option<SignalKind> {
+            let reply = self.data.reply.as_object()?;
+
+            SignalKind::ALL
+                .into_iter()
+                .find(|kind| reply.contains_key(kind.key()))
+        }
+
+        /// What the signal carries for its kind.
+        pub fn value(&self) -> Option<&serde_json::Value> {
+            self.data.reply.get(self.kind()?.key())
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
     pub struct ExceptionData {
-        name: String,
-        value: String,
-        backtrace: String,
+        pub name: String,
+        pub value: String,
+        pub backtrace: String,
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
     pub struct Exception {
-        r#type: MessageType,
-        data: ExceptionData,
+        pub r#type: MessageType,
+        pub data: ExceptionData,
+    }
+
+    impl Exception {
+        pub fn new(name: &str, value: &str, backtrace: &str) -> Self {
+            Self {
+                r#type: MessageType::Exception,
+                data: ExceptionData {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                    backtrace: backtrace.to_string(),
+                },
+            }
+        }
     }
 
     impl Message for Exception {}
@@ -88,10 +232,15 @@ pub mod message {
     pub mod encoding {
         use super::*;
         use serde::de::DeserializeOwned;
+        use std::io;
+        use std::str;
 
         #[derive(Debug)]
         pub enum EncodingError {
             ParseError(serde_json::Error),
+
+            /// The received bytes are not valid UTF-8.
+            Utf8Error(str::Utf8Error),
         }
 
         impl From<serde_json::Error> for EncodingError {
@@ -100,16 +249,99 @@ pub mod message {
             }
         }
 
+        impl From<str::Utf8Error> for EncodingError {
+            fn from(err: str::Utf8Error) -> Self {
+                Self::Utf8Error(err)
+            }
+        }
+
         pub trait Encoding {
             fn encode<T: Serialize>(&self, object: T) -> Result<Vec<u8>, EncodingError>;
             fn decode<T: DeserializeOwned>(&self, data: &str) -> Result<T, EncodingError>;
+
+            /// Decode bytes as received from a transport, these can be anything the peer
+            /// sent so invalid input is an error.
+            fn decode_bytes<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, EncodingError> {
+                self.decode(str::from_utf8(data)?)
+            }
+        }
+
+        /// Formats JSON like Python's `json.dumps` does by default: `", "` and `": "` as
+        /// separators and everything outside of printable ASCII escaped.
+        struct PythonFormatter;
+
+        impl serde_json::ser::Formatter for PythonFormatter {
+            fn begin_array_value<W: ?Sized + io::Write>(
+                &mut self,
+                writer: &mut W,
+                first: bool,
+            ) -> io::Result<()> {
+                if first {
+                    Ok(())
+                } else {
+                    writer.write_all(b", ")
+                }
+            }
+
+            fn begin_object_key<W: ?Sized + io::Write>(
+                &mut self,
+                writer: &mut W,
+                first: bool,
+            ) -> io::Result<()> {
+                if first {
+                    Ok(())
+                } else {
+                    writer.write_all(b", ")
+                }
+            }
+
+            fn begin_object_value<W: ?Sized + io::Write>(
+                &mut self,
+                writer: &mut W,
+            ) -> io::Result<()> {
+                writer.write_all(b": ")
+            }
+
+            fn write_string_fragment<W: ?Sized + io::Write>(
+                &mut self,
+                writer: &mut W,
+                fragment: &str,
+            ) -> io::Result<()> {
+                let mut start = 0;
+
+                for (index, c) in fragment.char_indices() {
+                    if (' '..='~').contains(&c) {
+                        continue;
+                    }
+
+                    writer.write_all(&fragment.as_bytes()[start..index])?;
+
+                    // characters outside of the basic plane are escaped as surrogate pairs
+                    for unit in c.encode_utf16(&mut [0; 2]) {
+                        write!(writer, "\\u{:04x}", unit)?;
+                    }
+
+                    start = index + c.len_utf8();
+                }
+
+                writer.write_all(&fragment.as_bytes()[start..])
+            }
         }
 
+        /// Encodes messages as the Python implementation does, byte for byte as long as the
+        /// keys of objects in messages are sorted; objects that are values, such as the
+        /// arguments of methods, don't keep the order of their keys.
         pub struct JSONEncoding {}
 
         impl Encoding for JSONEncoding {
             fn encode<T: Serialize>(&self, object: T) -> Result<Vec<u8>, EncodingError> {
-                Ok(serde_json::to_vec(&object)?)
+                let mut data = vec![];
+                let mut serializer =
+                    serde_json::Serializer::with_formatter(&mut data, PythonFormatter);
+
+                object.serialize(&mut serializer)?;
+
+                Ok(data)
             }
 
             fn decode<T: DeserializeOwned>(&self, data: &str) -> Result<T, EncodingError> {
@@ -125,10 +357,7 @@ pub mod message {
             #[test]
             fn test_encode_reply() {
                 let encoding = JSONEncoding {};
-                let reply = Reply {
-                    r#type: MessageType::Reply,
-                    data: ReplyData {},
-                };
+                let reply = Reply::new(serde_json::json!({"ok": true}), vec![0]);
 
                 assert!(encoding
                     .decode::<Reply>(str::from_utf8(&encoding.encode(reply).unwrap()).unwrap())
@@ -138,13 +367,7 @@ pub mod message {
             #[test]
             fn test_encode_method() {
                 let encoding = JSONEncoding {};
-                let method = Method {
-                    r#type: MessageType::Method,
-                    method: "test".to_string(),
-                    data: MethodData {
-                        name: "name".to_string(),
-                    },
-                };
+                let method = Method::new("name", serde_json::json!(["arg"]));
 
                 assert!(encoding
                     .decode::<Method>(str::from_utf8(&encoding.encode(method).unwrap()).unwrap())
@@ -154,27 +377,65 @@ pub mod message {
             #[test]
             fn test_encode_signal() {
                 let encoding = JSONEncoding {};
-                let signal = Signal {
-                    r#type: MessageType::Signal,
-                    data: SignalData {},
-                };
+                let signal = Signal::new(serde_json::json!("progress"), vec![]);
 
                 assert!(encoding
                     .decode::<Signal>(str::from_utf8(&encoding.encode(signal).unwrap()).unwrap())
                     .is_ok());
             }
 
+            #[test]
+            fn test_signal_kinds() {
+                let encoding = JSONEncoding {};
+
+                for (signal, kind) in [
+                    (Signal::progress(3, 10), SignalKind::Progress),
+                    (Signal::cancel("timeout"), SignalKind::Cancel),
+                    (
+                        Signal::reload(serde_json::json!({"debug": true})),
+                        SignalKind::Reload,
+                    ),
+                ] {
+                    let decoded = encoding
+                        .decode_bytes::<Signal>(&encoding.encode(&signal).unwrap())
+                        .unwrap();
+
+                    assert_eq!(decoded, signal);
+                    assert_eq!(decoded.kind(), Some(kind));
+                }
+
+                assert_eq!(
+                    Signal::progress(3, 10).value(),
+                    Some(&serde_json::json!({"done": 3, "total": 10}))
+                );
+                assert_eq!(
+                    Signal::new(serde_json::json!("progress"), vec![]).kind(),
+                    None
+                );
+                assert_eq!(
+                    Signal::new(serde_json::json!({"other": 1}), vec![]).kind(),
+                    None
+                );
+            }
+
+            #[test]
+            fn test_decode_garbage() {
+                let encoding = JSONEncoding {};
+
+                assert!(matches!(
+                    encoding.decode_bytes::<Method>(&[0xff, 0xfe, 0x00]),
+                    Err(EncodingError::Utf8Error(_))
+                ));
+                assert!(matches!(
+                    encoding.decode_bytes::<Method>(b"{\"type\": \"Method\"}"),
+                    Err(EncodingError::ParseError(_))
+                ));
+            }
+
             #[test]
             fn test_encode_exception() {
                 let encoding = JSONEncoding {};
-                let exception = Exception {
-                    r#type: MessageType::Exception,
-                    data: ExceptionData {
-                        name: "foo".to_string(),
-                        value: "foo".to_string(),
-                        backtrace: "foo".to_string(),
-                    },
-                };
+                let exception = Exception::new("foo", "foo", "foo");
 
                 assert!(encoding
                     .decode::<Exception>(
@@ -185,3 +446,7 @@ pub mod message {
         }
     }
 }
+
+/// Messages of the Python implementation the messages here have to be compatible with.
+#[cfg(test)]
+mod compat;
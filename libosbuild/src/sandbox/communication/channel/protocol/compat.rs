@@ -0,0 +1,113 @@
+//! Messages as osbuild's Python implementation sends them, one per line. They were produced
+//! by `json.dumps` of the messages `osbuild.host.ServiceProtocol` encodes. Objects in them
+//! have sorted keys, the order of keys of objects that are values isn't kept by decoding.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::sandbox::communication::channel::protocol::message::encoding::*;
+use crate::sandbox::communication::channel::protocol::message::*;
+
+const METHODS: &str = include_str!("compat/methods.jsonl");
+const REPLIES: &str = include_str!("compat/replies.jsonl");
+const SIGNALS: &str = include_str!("compat/signals.jsonl");
+const EXCEPTIONS: &str = include_str!("compat/exceptions.jsonl");
+
+/// Decode every message in `corpus` as a `T` and check that encoding it gives the same bytes.
+fn roundtrip<T: Serialize + DeserializeOwned>(corpus: &str) -> Vec<T> {
+    let encoding = JSONEncoding {};
+    let mut messages = vec![];
+
+    for line in corpus.lines() {
+        let message: T = encoding.decode(line).unwrap();
+
+        assert_eq!(
+            String::from_utf8(encoding.encode(&message).unwrap()).unwrap(),
+            line
+        );
+
+        messages.push(message);
+    }
+
+    assert!(!messages.is_empty());
+
+    messages
+}
+
+#[test]
+fn methods() {
+    let methods = roundtrip::<Method>(METHODS);
+
+    assert!(methods
+        .iter()
+        .all(|method| method.r#type == MessageType::Method));
+    assert_eq!(methods[0].data.name, "add");
+    assert_eq!(methods[2].data.args, serde_json::json!([]));
+    assert_eq!(
+        methods[0],
+        Method::new(
+            "add",
+            serde_json::json!({"desc": "a meta-data object", "name": "org.osbuild.rpm"})
+        )
+    );
+}
+
+#[test]
+fn replies() {
+    let replies = roundtrip::<Reply>(REPLIES);
+
+    assert_eq!(replies[0], Reply::new(serde_json::Value::Null, vec![]));
+    assert_eq!(replies[1].data.reply["path"], "/dev/loop0");
+    assert_eq!(replies[4].data.fds, vec![0, 1]);
+    assert_eq!(replies[4].data.reply[3], "über");
+}
+
+#[test]
+fn signals() {
+    let signals = roundtrip::<Signal>(SIGNALS);
+
+    // file descriptors are only sent along when there are any
+    assert!(signals[0].data.fds.is_empty());
+    assert_eq!(signals[0].kind(), Some(SignalKind::Log));
+    assert_eq!(signals[1].kind(), Some(SignalKind::Progress));
+    assert_eq!(signals[2].kind(), None);
+    assert_eq!(signals[2].data.fds, vec![0]);
+    assert_eq!(
+        signals[3],
+        Signal::new(
+            serde_json::json!("a rocket \u{1F680} \u{7f}\t\"quoted\""),
+            vec![]
+        )
+    );
+}
+
+#[test]
+fn exceptions() {
+    let exceptions = roundtrip::<Exception>(EXCEPTIONS);
+
+    assert_eq!(exceptions[0].data.name, "ValueError");
+    assert!(exceptions[0]
+        .data
+        .backtrace
+        .ends_with("raise ValueError(msg)\n"));
+    assert_eq!(
+        exceptions[1],
+        Exception::new(
+            "FileNotFoundError",
+            "[Errno 2] No such file or directory: '/run/osbuild/tree/étc'",
+            ""
+        )
+    );
+}
+
+#[test]
+fn wrong_type() {
+    let encoding = JSONEncoding {};
+
+    assert!(encoding
+        .decode::<Method>(REPLIES.lines().next().unwrap())
+        .is_err());
+    assert!(encoding
+        .decode::<Exception>(METHODS.lines().next().unwrap())
+        .is_err());
+}
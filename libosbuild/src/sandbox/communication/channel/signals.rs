@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::Path;
+use std::thread;
+
+#[cfg(unix)]
+use super::protocol::message::encoding::{Encoding, JSONEncoding};
+use super::protocol::message::{MessageType, Signal, SignalKind};
+use super::{Channel, ChannelError, CommandChannel};
+
+type Handler = Box<dyn FnMut(&Signal) + Send>;
+
+/// Handlers for the kinds of signals a module wants to know about. Signals are dispatched
+/// to every handler of their kind in the order the handlers were subscribed.
+#[derive(Default)]
+pub struct Subscriptions {
+    handlers: BTreeMap<SignalKind, Vec<Handler>>,
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `handler` for every signal of `kind`.
+    pub fn subscribe<F: FnMut(&Signal) + Send + 'static>(&mut self, kind: SignalKind, handler: F) {
+        self.handlers
+            .entry(kind)
+            .or_default()
+            .push(Box::new(handler));
+    }
+
+    /// Call the handlers of the kind of `signal`, returns how many there were.
+    pub fn dispatch(&mut self, signal: &Signal) -> usize {
+        match signal.kind().and_then(|kind| self.handlers.get_mut(&kind)) {
+            Some(handlers) => {
+                for handler in handlers.iter_mut() {
+                    handler(signal);
+                }
+
+                handlers.len()
+            }
+            None => 0,
+        }
+    }
+
+    /// Receive a message on `channel` and dispatch it when it is a signal. Messages that
+    /// aren't signals or can't be decoded are ignored, failing to receive is an error.
+    pub fn dispatch_next(&mut self, channel: &mut CommandChannel) -> Result<usize, ChannelError> {
+        match channel.recv::<Signal>() {
+            Ok(signal) if signal.r#type == MessageType::Signal => Ok(self.dispatch(&signal)),
+            Ok(_)
+            | Err(ChannelError::Encoding(_))
+            | Err(ChannelError::InvalidUtf8(_))
+            | Err(ChannelError::Truncated) => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Dispatch the signals that arrive on `channel` in a thread of their own while the
+    /// module does its work. The thread ends when receiving fails, such as when the host
+    /// closes the channel, and returns why.
+    pub fn listen(mut self, mut channel: CommandChannel) -> thread::JoinHandle<ChannelError> {
+        thread::spawn(move || loop {
+            if let Err(err) = self.dispatch_next(&mut channel) {
+                return err;
+            }
+        })
+    }
+}
+
+/// Send `signal` to the socket at `path`, without a channel as no reply is expected. This is
+/// how the host signals a module on its control socket.
+#[cfg(unix)]
+pub fn notify(path: &Path, signal: &Signal) -> Result<(), ChannelError> {
+    let data = JSONEncoding {}.encode(signal)?;
+    let socket = UnixDatagram::unbound().map_err(super::transport::TransportError::from)?;
+
+    socket
+        .send_to(&data, path)
+        .map_err(super::transport::TransportError::from)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::protocol::message::Method;
+    use super::super::{protocol, transport};
+    use super::*;
+
+    use std::sync::mpsc;
+
+    fn memory_pair() -> (CommandChannel, CommandChannel) {
+        let (a, b) = transport::MemoryTransport::pair();
+
+        (
+            CommandChannel {
+                transport: Box::new(a),
+                protocol: Box::new(protocol::JSONProtocol {}),
+            },
+            CommandChannel {
+                transport: Box::new(b),
+                protocol: Box::new(protocol::JSONProtocol {}),
+            },
+        )
+    }
+
+    #[test]
+    fn signals_dispatched() {
+        let (sender, receiver) = mpsc::channel();
+        let mut subscriptions = Subscriptions::new();
+
+        for name in ["first", "second"] {
+            let sender = sender.clone();
+
+            subscriptions.subscribe(SignalKind::Progress, move |signal| {
+                sender
+                    .send((name, signal.value().unwrap()["done"].clone()))
+                    .unwrap()
+            });
+        }
+
+        assert_eq!(subscriptions.dispatch(&Signal::progress(1, 2)), 2);
+        assert_eq!(subscriptions.dispatch(&Signal::cancel("")), 0);
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            [("first", 1.into()), ("second", 1.into())]
+        );
+    }
+
+    #[test]
+    fn signals_listened_for() {
+        let (mut host, module) = memory_pair();
+        let (sender, receiver) = mpsc::channel();
+        let mut subscriptions = Subscriptions::new();
+
+        subscriptions.subscribe(SignalKind::Cancel, move |signal| {
+            sender.send(signal.clone()).unwrap()
+        });
+        subscriptions.subscribe(SignalKind::Reload, |_| panic!("no reload was sent"));
+
+        let listener = subscriptions.listen(module);
+
+        // anything that isn't a signal is ignored
+        host.send(Method::new("cancel", serde_json::json!([])))
+            .unwrap();
+        host.transport.send(b"garbage").unwrap();
+        host.send(Signal::progress(1, 2)).unwrap();
+        host.send(Signal::cancel("stopped")).unwrap();
+
+        assert_eq!(receiver.recv().unwrap(), Signal::cancel("stopped"));
+
+        host.close().unwrap();
+
+        assert!(matches!(listener.join().unwrap(), ChannelError::Closed));
+    }
+
+    #[test]
+    fn signals_notified() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("control");
+
+        assert!(matches!(
+            notify(&path, &Signal::cancel("")),
+            Err(ChannelError::Transport(_))
+        ));
+
+        let mut channel = CommandChannel {
+            transport: Box::new(transport::UnixDGRAMSocket::bind(path.to_str().unwrap()).unwrap()),
+            protocol: Box::new(protocol::JSONProtocol {}),
+        };
+
+        notify(&path, &Signal::reload(serde_json::json!({}))).unwrap();
+
+        assert_eq!(
+            channel.recv::<Signal>().unwrap(),
+            Signal::reload(serde_json::json!({}))
+        );
+    }
+}
@@ -1,5 +1,9 @@
-use std::net::Shutdown;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
 use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::sync::mpsc;
 
 #[derive(Debug)]
 pub enum TransportError {
@@ -12,7 +16,8 @@ impl From<std::io::Error> for TransportError {
     }
 }
 
-pub trait Transport {
+/// Transports are `Send` so a channel can be moved to a thread that listens on it.
+pub trait Transport: Send {
     fn new(dst: String, src: Option<String>) -> Result<Self, TransportError>
     where
         Self: Sized;
@@ -26,13 +31,25 @@ pub trait Transport {
 
 /// A UnixDGRAMSocket Transport to send data back and forth over a SOCK_DGRAM, AF_UNIX
 /// socket.
+#[cfg(unix)]
 pub struct UnixDGRAMSocket {
     socket: UnixDatagram,
 }
 
+#[cfg(unix)]
+impl UnixDGRAMSocket {
+    /// A socket bound to `path` that receives from whoever sends to it, it can't send.
+    pub fn bind(path: &str) -> Result<Self, TransportError> {
+        Ok(Self {
+            socket: UnixDatagram::bind(path)?,
+        })
+    }
+}
+
+#[cfg(unix)]
 impl Transport for UnixDGRAMSocket {
     fn new(dst: String, src: Option<String>) -> Result<Self, TransportError> {
-        let socket = UnixDatagram::bind(src.unwrap_or_else(|| "".to_string()))?;
+        let socket = UnixDatagram::bind(src.unwrap_or_default())?;
 
         let instance = Self { socket };
 
@@ -68,10 +85,20 @@ impl Transport for UnixDGRAMSocket {
 
 /// A UnixSTREAMSocket Transport to send data back and forth over a SOCK_STREAM, AF_UNIX
 /// socket.
+#[cfg(unix)]
 pub struct UnixSTREAMSocket {
     socket: UnixStream,
 }
 
+/// The end of a connection a listener accepted.
+#[cfg(unix)]
+impl From<UnixStream> for UnixSTREAMSocket {
+    fn from(socket: UnixStream) -> Self {
+        Self { socket }
+    }
+}
+
+#[cfg(unix)]
 impl Transport for UnixSTREAMSocket {
     fn new(dst: String, _src: Option<String>) -> Result<Self, TransportError> {
         Ok(Self {
@@ -85,22 +112,298 @@ impl Transport for UnixSTREAMSocket {
         Ok(())
     }
 
-    fn recv(&self, _buf: &mut [u8]) -> Result<usize, TransportError> {
-        Ok(1)
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        Ok((&self.socket).read(buf)?)
     }
 
-    fn send(&self, _buf: &[u8]) -> Result<usize, TransportError> {
-        Ok(1)
+    fn send(&self, buf: &[u8]) -> Result<usize, TransportError> {
+        Ok((&self.socket).write(buf)?)
     }
 
     fn send_all(&self, buf: &[u8]) -> Result<usize, TransportError> {
-        let mut sent = 0;
+        (&self.socket).write_all(buf)?;
 
-        while sent < buf.len() {
-            sent += self.send(buf)?;
+        Ok(buf.len())
+    }
+}
+
+/// A TCPSocket Transport to send data back and forth over a TCP connection, for platforms
+/// without unix sockets. The destination is a `host:port` address.
+pub struct TCPSocket {
+    socket: TcpStream,
+}
+
+impl Transport for TCPSocket {
+    fn new(dst: String, _src: Option<String>) -> Result<Self, TransportError> {
+        Ok(Self {
+            socket: TcpStream::connect(dst)?,
+        })
+    }
+
+    fn close(&mut self) -> Result<(), TransportError> {
+        self.socket.shutdown(Shutdown::Both)?;
+
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        Ok((&self.socket).read(buf)?)
+    }
+
+    fn send(&self, buf: &[u8]) -> Result<usize, TransportError> {
+        Ok((&self.socket).write(buf)?)
+    }
+
+    fn send_all(&self, buf: &[u8]) -> Result<usize, TransportError> {
+        (&self.socket).write_all(buf)?;
+
+        Ok(buf.len())
+    }
+}
+
+/// A MemoryTransport passes messages between two ends in the same process, each `send` is
+/// received whole by one `recv` on the other end, as with a SOCK_DGRAM socket. Ends are made
+/// in connected pairs with `MemoryTransport::pair`.
+pub struct MemoryTransport {
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    receiver: mpsc::Receiver<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (Self, Self) {
+        let (a_sender, a_receiver) = mpsc::channel();
+        let (b_sender, b_receiver) = mpsc::channel();
+
+        (
+            Self {
+                sender: Some(a_sender),
+                receiver: b_receiver,
+            },
+            Self {
+                sender: Some(b_sender),
+                receiver: a_receiver,
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    /// Memory transports have no addresses to connect to, use `MemoryTransport::pair`.
+    fn new(_dst: String, _src: Option<String>) -> Result<Self, TransportError> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "memory transports are created with MemoryTransport::pair",
+        )
+        .into())
+    }
+
+    fn close(&mut self) -> Result<(), TransportError> {
+        self.sender = None;
+
+        Ok(())
+    }
+
+    /// Receive a message, blocking until one arrives. Messages longer than `buf` are cut
+    /// off. Once the other end is closed 0 is returned.
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        match self.receiver.recv() {
+            Ok(message) => {
+                let size = message.len().min(buf.len());
+
+                buf[..size].copy_from_slice(&message[..size]);
+
+                Ok(size)
+            }
+            Err(mpsc::RecvError) => Ok(0),
         }
+    }
 
-        Ok(sent)
+    fn send(&self, buf: &[u8]) -> Result<usize, TransportError> {
+        let closed = || io::Error::from(io::ErrorKind::BrokenPipe);
+
+        self.sender
+            .as_ref()
+            .ok_or_else(closed)?
+            .send(buf.to_vec())
+            .map_err(|_| closed())?;
+
+        Ok(buf.len())
+    }
+
+    fn send_all(&self, buf: &[u8]) -> Result<usize, TransportError> {
+        self.send(buf)
+    }
+}
+
+/// The context id of the host, as seen from a virtual machine.
+#[cfg(all(feature = "vm", target_os = "linux"))]
+pub const VMADDR_CID_HOST: u32 = 2;
+
+/// A VSockSTREAMSocket Transport to send data back and forth between a virtual machine and
+/// its host over a SOCK_STREAM, AF_VSOCK socket. Addresses are `<cid>:<port>`.
+#[cfg(all(feature = "vm", target_os = "linux"))]
+pub struct VSockSTREAMSocket {
+    socket: std::fs::File,
+}
+
+#[cfg(all(feature = "vm", target_os = "linux"))]
+impl VSockSTREAMSocket {
+    /// Parse a `<cid>:<port>` address.
+    pub fn parse_address(address: &str) -> Result<(u32, u32), TransportError> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}' is not a <cid>:<port> vsock address", address),
+            )
+        };
+
+        let (cid, port) = address.split_once(':').ok_or_else(invalid)?;
+
+        Ok((
+            cid.parse().map_err(|_| invalid())?,
+            port.parse().map_err(|_| invalid())?,
+        ))
+    }
+
+    fn address(cid: u32, port: u32) -> libc::sockaddr_vm {
+        // SAFETY: sockaddr_vm is plain data for which all zeroes is a valid value
+        let mut address: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+
+        address.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        address.svm_cid = cid;
+        address.svm_port = port;
+
+        address
+    }
+
+    fn socket() -> Result<std::os::fd::OwnedFd, TransportError> {
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: socket(2) has no memory safety requirements
+        let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // SAFETY: fd is a newly created socket that nothing else owns
+        Ok(unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) })
+    }
+
+    /// Wait on `port` for a connection from any virtual machine, this is the host's end.
+    pub fn accept(port: u32) -> Result<Self, TransportError> {
+        VSockListener::bind(port)?.accept()
+    }
+}
+
+/// The host's end of `VSockSTREAMSocket`, listening on a port for virtual machines to
+/// connect to.
+#[cfg(all(feature = "vm", target_os = "linux"))]
+pub struct VSockListener {
+    listener: std::os::fd::OwnedFd,
+}
+
+#[cfg(all(feature = "vm", target_os = "linux"))]
+impl VSockListener {
+    /// Listen on `port` for connections from any virtual machine.
+    pub fn bind(port: u32) -> Result<Self, TransportError> {
+        use std::os::fd::AsRawFd;
+
+        let listener = VSockSTREAMSocket::socket()?;
+        let address = VSockSTREAMSocket::address(libc::VMADDR_CID_ANY, port);
+
+        // SAFETY: address is a valid sockaddr_vm and its size is passed along
+        let bound = unsafe {
+            libc::bind(
+                listener.as_raw_fd(),
+                &address as *const libc::sockaddr_vm as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+
+        // SAFETY: listen(2) on a socket we own
+        if bound < 0 || unsafe { libc::listen(listener.as_raw_fd(), 1) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Self { listener })
+    }
+
+    /// Wait for a virtual machine to connect.
+    pub fn accept(&self) -> Result<VSockSTREAMSocket, TransportError> {
+        use std::os::fd::{AsRawFd, FromRawFd};
+
+        // SAFETY: accept4(2) on a socket we own, without asking for the peer's address
+        let fd = unsafe {
+            libc::accept4(
+                self.listener.as_raw_fd(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            )
+        };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // SAFETY: fd is a newly accepted connection that nothing else owns
+        Ok(VSockSTREAMSocket {
+            socket: unsafe { std::fs::File::from_raw_fd(fd) },
+        })
+    }
+}
+
+#[cfg(all(feature = "vm", target_os = "linux"))]
+impl Transport for VSockSTREAMSocket {
+    fn new(dst: String, _src: Option<String>) -> Result<Self, TransportError> {
+        use std::os::fd::AsRawFd;
+
+        let (cid, port) = Self::parse_address(&dst)?;
+        let socket = Self::socket()?;
+        let address = Self::address(cid, port);
+
+        // SAFETY: address is a valid sockaddr_vm and its size is passed along
+        let connected = unsafe {
+            libc::connect(
+                socket.as_raw_fd(),
+                &address as *const libc::sockaddr_vm as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+
+        if connected < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Self {
+            socket: socket.into(),
+        })
+    }
+
+    fn close(&mut self) -> Result<(), TransportError> {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: shutdown(2) on a socket we own
+        if unsafe { libc::shutdown(self.socket.as_raw_fd(), libc::SHUT_RDWR) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        Ok((&self.socket).read(buf)?)
+    }
+
+    fn send(&self, buf: &[u8]) -> Result<usize, TransportError> {
+        Ok((&self.socket).write(buf)?)
+    }
+
+    fn send_all(&self, buf: &[u8]) -> Result<usize, TransportError> {
+        (&self.socket).write_all(buf)?;
+
+        Ok(buf.len())
     }
 }
 
@@ -116,7 +419,7 @@ mod test {
     use rand::distributions::Alphanumeric;
     use rand::{thread_rng, Rng};
 
-    fn with_path<T>(test: T) -> ()
+    fn with_path<T>(test: T)
     where
         T: FnOnce(&str) + panic::UnwindSafe,
     {
@@ -190,4 +493,52 @@ mod test {
             assert!(UnixSTREAMSocket::new(path.to_string(), None).is_err());
         })
     }
+
+    #[test]
+    fn tcpsocket_send_recv() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let transport = TCPSocket::new(listener.local_addr().unwrap().to_string(), None).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+
+        transport.send_all(b"foo").unwrap();
+
+        let mut buffer = vec![0; 3];
+        peer.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, b"foo");
+
+        peer.write_all(b"bar").unwrap();
+        assert_eq!(transport.recv(&mut buffer).unwrap(), 3);
+        assert_eq!(buffer, b"bar");
+    }
+
+    #[test]
+    fn memorytransport_pair() {
+        let (mut a, b) = MemoryTransport::pair();
+
+        a.send_all(b"foo").unwrap();
+        a.send_all(b"quux").unwrap();
+
+        let mut buffer = vec![0; 3];
+        assert_eq!(b.recv(&mut buffer).unwrap(), 3);
+        assert_eq!(buffer, b"foo");
+        assert_eq!(b.recv(&mut buffer).unwrap(), 3);
+        assert_eq!(buffer, b"quu");
+
+        a.close().unwrap();
+        assert_eq!(b.recv(&mut buffer).unwrap(), 0);
+        assert!(a.send(b"foo").is_err());
+        assert!(MemoryTransport::new("memory".to_string(), None).is_err());
+    }
+
+    #[cfg(all(feature = "vm", target_os = "linux"))]
+    #[test]
+    fn vsocksocket_addresses() {
+        assert_eq!(
+            VSockSTREAMSocket::parse_address("2:1024").unwrap(),
+            (VMADDR_CID_HOST, 1024)
+        );
+        assert!(VSockSTREAMSocket::parse_address("2").is_err());
+        assert!(VSockSTREAMSocket::parse_address("host:1024").is_err());
+        assert!(VSockSTREAMSocket::new("host:1024".to_string(), None).is_err());
+    }
 }
@@ -2,3 +2,6 @@
 /// machine through a transport. The `channel` module provides abstractions for an `osbuild`
 /// module to talk to the host system.
 pub mod channel;
+
+/// Services the host provides to modules over channels, such as a clock and entropy.
+pub mod services;
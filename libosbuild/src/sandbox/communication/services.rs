@@ -0,0 +1,439 @@
+use std::fs::{self, File};
+use std::io;
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::Arc;
+#[cfg(unix)]
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::sandbox::communication::channel::protocol::message::{
+    Exception, ExceptionData, Message, MessageType, Method, Reply, ReplyData,
+};
+#[cfg(unix)]
+use crate::sandbox::communication::channel::{protocol::JSONProtocol, transport::UnixSTREAMSocket};
+use crate::sandbox::communication::channel::{Channel, ChannelError, CommandChannel};
+
+/// The method that replies with the host's monotonic clock, `{"nanoseconds": ...}`.
+pub const CLOCK_METHOD: &str = "clock.monotonic";
+
+/// The method that replies with `{"data": ...}`, `size` bytes of entropy as hex.
+pub const ENTROPY_METHOD: &str = "entropy.read";
+
+/// The most entropy a single call gets, enough to seed the random number generator of a
+/// module or for a few keys.
+pub const MAX_ENTROPY_REQUEST: usize = 512;
+
+/// How much entropy a module gets in total by default.
+pub const DEFAULT_ENTROPY_BUDGET: usize = 64 * 1024;
+
+/// How often a `HostServicesSocket` checks whether it was dropped while nobody connects.
+#[cfg(unix)]
+pub const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum ServiceError {
+    /// A method that no service provides.
+    UnknownMethod(String),
+
+    /// The arguments of a method aren't what it takes.
+    InvalidArguments(serde_json::Error),
+
+    /// More entropy was asked for than is left of the budget, contains what is left.
+    EntropyExhausted(usize),
+
+    /// The host replied with an exception, contains its name and value.
+    Exception(String, String),
+
+    /// The host replied with something that isn't what the method replies.
+    InvalidReply(serde_json::Error),
+
+    Channel(ChannelError),
+    IOError(io::Error),
+}
+
+impl ServiceError {
+    /// The name of the exception the error is sent to modules as.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::UnknownMethod(_) => "UnknownMethod",
+            Self::InvalidArguments(_) => "InvalidArguments",
+            Self::EntropyExhausted(_) => "EntropyExhausted",
+            Self::Exception(_, _) => "Exception",
+            Self::InvalidReply(_) => "InvalidReply",
+            Self::Channel(_) => "ChannelError",
+            Self::IOError(_) => "IOError",
+        }
+    }
+}
+
+impl From<ChannelError> for ServiceError {
+    fn from(err: ChannelError) -> Self {
+        Self::Channel(err)
+    }
+}
+
+impl From<io::Error> for ServiceError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EntropyArguments {
+    size: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ClockReply {
+    nanoseconds: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EntropyReply {
+    data: String,
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..data.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(data.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+/// Services of the host for modules in sandboxes without devices or a network, where the
+/// clock can't be trusted to be monotonic and the entropy pool can be empty; stages that
+/// generate keys would otherwise block until it fills. Each module gets its own services, so
+/// its entropy is bound by the budget no matter how often it asks.
+#[derive(Debug)]
+pub struct HostServices {
+    start: Instant,
+    budget: usize,
+}
+
+impl Default for HostServices {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENTROPY_BUDGET)
+    }
+}
+
+impl HostServices {
+    /// Services that hand out at most `budget` bytes of entropy.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            budget,
+        }
+    }
+
+    /// How much entropy is left to hand out.
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Nanoseconds since the services started, the clock never goes back.
+    fn clock(&self) -> ClockReply {
+        ClockReply {
+            nanoseconds: self.start.elapsed().as_nanos() as u64,
+        }
+    }
+
+    /// `size` bytes of entropy from the host's `/dev/urandom`.
+    fn entropy(&mut self, size: usize) -> Result<EntropyReply, ServiceError> {
+        if size > MAX_ENTROPY_REQUEST.min(self.budget) {
+            return Err(ServiceError::EntropyExhausted(
+                MAX_ENTROPY_REQUEST.min(self.budget),
+            ));
+        }
+
+        let mut data = vec![0; size];
+
+        File::open("/dev/urandom")?.read_exact(&mut data)?;
+
+        self.budget -= size;
+
+        Ok(EntropyReply {
+            data: to_hex(&data),
+        })
+    }
+
+    /// The reply to `method`.
+    pub fn call(&mut self, method: &Method) -> Result<serde_json::Value, ServiceError> {
+        let reply = match method.data.name.as_str() {
+            CLOCK_METHOD => serde_json::to_value(self.clock()),
+            ENTROPY_METHOD => {
+                let arguments: EntropyArguments = serde_json::from_value(method.data.args.clone())
+                    .map_err(ServiceError::InvalidArguments)?;
+
+                serde_json::to_value(self.entropy(arguments.size)?)
+            }
+            name => return Err(ServiceError::UnknownMethod(name.to_string())),
+        };
+
+        Ok(reply.map_err(io::Error::from)?)
+    }
+
+    /// Answer the methods that arrive on `channel` until it is closed. Failing methods are
+    /// answered with an exception named after the error, which doesn't stop the services.
+    pub fn serve(&mut self, channel: &mut CommandChannel) -> Result<(), ChannelError> {
+        loop {
+            let method: Method = match channel.recv() {
+                Ok(method) => method,
+                Err(ChannelError::Closed) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            match self.call(&method) {
+                Ok(reply) => channel.send(Reply::new(reply, vec![]))?,
+                Err(err) => channel.send(Exception::new(err.name(), &format!("{:?}", err), ""))?,
+            };
+        }
+    }
+}
+
+/// `HostServices` for a single module on a unix stream socket, the socket of
+/// `Service::Host`. Connections are served one after the other with the same services, so
+/// they share its entropy budget. The services stop and the socket is removed when this is
+/// dropped.
+#[cfg(unix)]
+pub struct HostServicesSocket {
+    path: PathBuf,
+    stopped: Arc<AtomicBool>,
+}
+
+#[cfg(unix)]
+impl HostServicesSocket {
+    /// Serve `services` on a socket bound at `path`, replacing what a killed module left
+    /// there.
+    pub fn bind(path: &Path, mut services: HostServices) -> io::Result<Self> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+
+        let listener = UnixListener::bind(path)?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        listener.set_nonblocking(true)?;
+
+        let stop = stopped.clone();
+
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if stream.set_nonblocking(false).is_err() {
+                            continue;
+                        }
+
+                        let mut channel = CommandChannel {
+                            transport: Box::new(UnixSTREAMSocket::from(stream)),
+                            protocol: Box::new(JSONProtocol {}),
+                        };
+
+                        let _ = services.serve(&mut channel);
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(ACCEPT_INTERVAL)
+                    }
+                    Err(_) => return,
+                }
+            }
+        });
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            stopped,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for HostServicesSocket {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A reply or an exception, whichever the host sent.
+#[derive(Debug, Deserialize)]
+struct Response {
+    r#type: MessageType,
+    data: serde_json::Value,
+}
+
+impl Message for Response {}
+
+/// Call `method` with `args` on `channel`, returns the reply.
+fn call(
+    channel: &mut CommandChannel,
+    method: &str,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, ServiceError> {
+    let response: Response = channel.send_and_recv(Method::new(method, args))?;
+
+    match response.r#type {
+        MessageType::Exception => {
+            let exception: ExceptionData =
+                serde_json::from_value(response.data).map_err(ServiceError::InvalidReply)?;
+
+            Err(ServiceError::Exception(exception.name, exception.value))
+        }
+        _ => {
+            let reply: ReplyData =
+                serde_json::from_value(response.data).map_err(ServiceError::InvalidReply)?;
+
+            Ok(reply.reply)
+        }
+    }
+}
+
+/// The monotonic clock of the host, for modules.
+pub fn monotonic(channel: &mut CommandChannel) -> Result<Duration, ServiceError> {
+    let reply: ClockReply =
+        serde_json::from_value(call(channel, CLOCK_METHOD, serde_json::json!({}))?)
+            .map_err(ServiceError::InvalidReply)?;
+
+    Ok(Duration::from_nanos(reply.nanoseconds))
+}
+
+/// `size` bytes of entropy from the host, for modules. Larger amounts are asked for in parts.
+pub fn entropy(channel: &mut CommandChannel, size: usize) -> Result<Vec<u8>, ServiceError> {
+    let mut data = Vec::with_capacity(size);
+
+    while data.len() < size {
+        let part = (size - data.len()).min(MAX_ENTROPY_REQUEST);
+        let reply: EntropyReply = serde_json::from_value(call(
+            channel,
+            ENTROPY_METHOD,
+            serde_json::json!({ "size": part }),
+        )?)
+        .map_err(ServiceError::InvalidReply)?;
+
+        data.extend(from_hex(&reply.data).ok_or_else(|| {
+            ServiceError::InvalidReply(serde::de::Error::custom("entropy is not hex"))
+        })?);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::sandbox::communication::channel::{protocol, transport};
+
+    fn memory_pair() -> (CommandChannel, CommandChannel) {
+        let (a, b) = transport::MemoryTransport::pair();
+
+        (
+            CommandChannel {
+                transport: Box::new(a),
+                protocol: Box::new(protocol::JSONProtocol {}),
+            },
+            CommandChannel {
+                transport: Box::new(b),
+                protocol: Box::new(protocol::JSONProtocol {}),
+            },
+        )
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(to_hex(&[0x00, 0x7f, 0xff]), "007fff");
+        assert_eq!(from_hex("007fff").unwrap(), [0x00, 0x7f, 0xff]);
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn services_called() {
+        let (mut host, mut module) = memory_pair();
+
+        let services = std::thread::spawn(move || {
+            let mut services = HostServices::new(MAX_ENTROPY_REQUEST + 100);
+
+            services.serve(&mut host).unwrap();
+            services.budget()
+        });
+
+        let first = monotonic(&mut module).unwrap();
+        let second = monotonic(&mut module).unwrap();
+
+        assert!(second >= first);
+
+        // larger amounts are asked for in parts, until the budget runs out
+        assert_eq!(
+            entropy(&mut module, MAX_ENTROPY_REQUEST + 64)
+                .unwrap()
+                .len(),
+            576
+        );
+        assert!(matches!(
+            entropy(&mut module, 64),
+            Err(ServiceError::Exception(name, _)) if name == "EntropyExhausted"
+        ));
+        assert!(matches!(
+            call(&mut module, "clock.realtime", serde_json::json!({})),
+            Err(ServiceError::Exception(name, _)) if name == "UnknownMethod"
+        ));
+        assert!(matches!(
+            call(&mut module, ENTROPY_METHOD, serde_json::json!({"size": -1})),
+            Err(ServiceError::Exception(name, _)) if name == "InvalidArguments"
+        ));
+
+        module.close().unwrap();
+
+        assert_eq!(services.join().unwrap(), 36);
+    }
+
+    #[test]
+    fn services_served_on_socket() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("host");
+
+        let socket = HostServicesSocket::bind(&path, HostServices::new(32)).unwrap();
+
+        let mut module = CommandChannel {
+            transport: Box::new(
+                <UnixSTREAMSocket as transport::Transport>::new(
+                    path.to_string_lossy().to_string(),
+                    None,
+                )
+                .unwrap(),
+            ),
+            protocol: Box::new(protocol::JSONProtocol {}),
+        };
+
+        assert!(monotonic(&mut module).is_ok());
+        assert_eq!(entropy(&mut module, 32).unwrap().len(), 32);
+        assert!(matches!(
+            entropy(&mut module, 1),
+            Err(ServiceError::Exception(name, _)) if name == "EntropyExhausted"
+        ));
+
+        drop(socket);
+
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use serde::Serialize;
+
+/// The program stages are audited with, see strace(1).
+pub const STRACE: &str = "strace";
+
+/// The program stages are given a network namespace of their own with, see unshare(1).
+pub const UNSHARE: &str = "unshare";
+
+/// The system calls that are audited; those that open files and those that send to
+/// addresses.
+pub const SYSCALLS: &str = "open,openat,openat2,creat,connect,sendto,sendmsg";
+
+/// What stages read besides their inputs; the programs and libraries of the build root,
+/// what the dynamic loader and the C library read of its configuration, scratch space, and
+/// the interfaces of the kernel. The rest of `/etc` is the host's and isn't allowed.
+pub const RUNTIME_PATHS: &[&str] = &[
+    "/usr",
+    "/lib",
+    "/lib64",
+    "/bin",
+    "/sbin",
+    "/etc/ld.so.cache",
+    "/etc/ld.so.preload",
+    "/etc/nsswitch.conf",
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/localtime",
+    "/dev",
+    "/proc",
+    "/sys",
+    "/tmp",
+    "/var/tmp",
+];
+
+/// Something a stage did that a hermetic build doesn't allow.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "access", rename_all = "lowercase")]
+pub enum Access {
+    /// The stage tried to reach an address on the network, whether or not it could.
+    Network { address: String },
+
+    /// The stage opened a path that isn't one of its inputs, or under one.
+    Path { path: PathBuf },
+}
+
+/// An access of a stage that broke hermeticity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    pub stage: String,
+
+    #[serde(flatten)]
+    pub access: Access,
+}
+
+/// The audit of a stage in a hermetic build. The stage is run under strace, and what it
+/// opened and sent to is checked against what it declares: files it opens have to be under
+/// its inputs or `RUNTIME_PATHS`, and it may not talk to addresses of the internet families
+/// at all. Paths are checked as the kernel resolved them, strace decodes the descriptors
+/// opens return. Stages are run in a network namespace of their own, with only loopback,
+/// whether or not they are granted the network; the audit is what tells that a stage tried,
+/// so a build that only works online can't pass as one that doesn't need to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Audit {
+    allowed: Vec<PathBuf>,
+}
+
+impl Audit {
+    /// An audit allowing the runtime and the `declared` paths, with what is under them.
+    pub fn new(declared: impl IntoIterator<Item = PathBuf>) -> Self {
+        Self {
+            allowed: RUNTIME_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .chain(declared)
+                .collect(),
+        }
+    }
+
+    /// The unshare command, before strace, that runs a stage without the network.
+    pub fn unshare() -> Vec<String> {
+        [UNSHARE, "--net", "--"].map(String::from).to_vec()
+    }
+
+    /// The strace arguments, before the program, that write the audit to `log`.
+    pub fn args(log: &Path) -> Vec<String> {
+        vec![
+            "--follow-forks".to_string(),
+            "-qq".to_string(),
+            "--decode-fds=path".to_string(),
+            "--trace".to_string(),
+            SYSCALLS.to_string(),
+            "--output".to_string(),
+            log.to_string_lossy().to_string(),
+            "--".to_string(),
+        ]
+    }
+
+    fn is_allowed(&self, path: &Path) -> bool {
+        self.allowed.iter().any(|allowed| path.starts_with(allowed))
+    }
+
+    /// The accesses in the strace `log` that break hermeticity, each once, in the order they
+    /// were first made. Opens that fail don't read anything and aren't accesses, relative
+    /// paths whose directory isn't known can't be checked and are accesses as they are.
+    pub fn check(&self, log: &str) -> Vec<Access> {
+        let mut accesses: Vec<Access> = vec![];
+
+        // calls of one process are split over two lines when another process makes a call
+        // in between, `<unfinished ...>` and `<... openat resumed>`
+        let mut unfinished: HashMap<&str, &str> = HashMap::new();
+
+        for line in log.lines() {
+            let (pid, call) = match line.split_once(char::is_whitespace) {
+                Some((pid, call)) if pid.chars().all(|c| c.is_ascii_digit()) => {
+                    (pid, call.trim_start())
+                }
+                _ => ("", line),
+            };
+
+            let joined;
+
+            let call = if let Some(start) = call.strip_suffix("<unfinished ...>") {
+                unfinished.insert(pid, start);
+                continue;
+            } else if call.starts_with("<...") {
+                let Some(start) = unfinished.remove(pid) else {
+                    continue;
+                };
+                let Some((_, rest)) = call.split_once("resumed>") else {
+                    continue;
+                };
+
+                joined = format!("{}{}", start.trim_end(), rest);
+                joined.as_str()
+            } else {
+                call
+            };
+
+            if let Some(access) = self.access(call) {
+                if !accesses.contains(&access) {
+                    accesses.push(access);
+                }
+            }
+        }
+
+        accesses
+    }
+
+    /// The access a call is when it breaks hermeticity, `openat(AT_FDCWD, "/a", O_RDONLY) = 3`.
+    fn access(&self, call: &str) -> Option<Access> {
+        let (name, rest) = call.split_once('(')?;
+        let (arguments, result) = rest.rsplit_once(") = ")?;
+
+        match name {
+            "open" | "openat" | "openat2" | "creat" => {
+                if result.starts_with('-') {
+                    return None;
+                }
+
+                let path = opened(name, arguments, result)?;
+
+                (!path.is_absolute() || !self.is_allowed(&path)).then_some(Access::Path { path })
+            }
+            "connect" | "sendto" | "sendmsg" => {
+                let family = arguments.split_once("sa_family=")?.1;
+
+                if !family.starts_with("AF_INET") {
+                    return None;
+                }
+
+                let address = quoted(family).unwrap_or("unknown");
+                let address = match family
+                    .split_once("htons(")
+                    .and_then(|(_, port)| port.split_once(')'))
+                {
+                    Some((port, _)) if family.starts_with("AF_INET6") => {
+                        format!("[{}]:{}", address, port)
+                    }
+                    Some((port, _)) => format!("{}:{}", address, port),
+                    None => address.to_string(),
+                };
+
+                Some(Access::Network { address })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The path a successful open of `name` opened. That is the path of the descriptor it
+/// returned, `= 3</etc/hosts>`, or else the path it was given in the directory it was
+/// given, `openat(3</etc>, "hosts", …)`, normalized. Relative paths of `open` and `creat`
+/// are relative to a working directory strace doesn't tell and are left as they are.
+fn opened(name: &str, arguments: &str, result: &str) -> Option<PathBuf> {
+    if let Some(path) = decoded(result) {
+        return Some(normalized(Path::new(path)));
+    }
+
+    let path = Path::new(quoted(arguments)?);
+
+    if path.is_absolute() {
+        return Some(normalized(path));
+    }
+
+    let directory = match name {
+        "openat" | "openat2" => arguments
+            .split_once(',')
+            .and_then(|(directory, _)| decoded(directory)),
+        _ => None,
+    };
+
+    Some(match directory {
+        Some(directory) => normalized(&Path::new(directory).join(path)),
+        None => path.to_path_buf(),
+    })
+}
+
+/// The path of a descriptor strace decoded, `3</etc/hosts>` or `AT_FDCWD</root>`.
+fn decoded(text: &str) -> Option<&str> {
+    let (_, rest) = text.split_once('<')?;
+
+    rest.rsplit_once('>')
+        .map(|(path, _)| path)
+        .filter(|path| path.starts_with('/'))
+}
+
+/// `path` without `.` and `..` components; `..` of the root is the root.
+fn normalized(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if normalized.parent().is_some() {
+                    normalized.pop();
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// The first quoted string in `text`, strace quotes paths and addresses.
+fn quoted(text: &str) -> Option<&str> {
+    let (_, rest) = text.split_once('"')?;
+
+    rest.split_once('"').map(|(quoted, _)| quoted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LOG: &str = r#"1201  execve("/usr/lib/osbuild/stages/org.osbuild.rpm", ["org.osbuild.rpm"], 0x7ffd /* 3 vars */) = 0
+1201  openat(AT_FDCWD</>, "/etc/ld.so.cache", O_RDONLY|O_CLOEXEC) = 3</etc/ld.so.cache>
+1201  openat(AT_FDCWD</>, "/run/osbuild/inputs/packages/sha256:aa", O_RDONLY) = 4</run/osbuild/inputs/packages/sha256:aa>
+1201  openat(AT_FDCWD</>, "/home/builder/.netrc", O_RDONLY) = -1 ENOENT (No such file or directory)
+1201  openat(AT_FDCWD</>, "/home/builder/secret.key", O_RDONLY <unfinished ...>
+1202  connect(5<socket:[1]>, {sa_family=AF_UNIX, sun_path="/run/dbus/system_bus_socket"}, 110) = 0
+1201  <... openat resumed>) = 6</home/builder/secret.key>
+1202  connect(5<socket:[2]>, {sa_family=AF_INET, sin_port=htons(443), sin_addr=inet_addr("93.184.215.14")}, 16) = -1 ENETUNREACH (Network is unreachable)
+1202  connect(7<socket:[3]>, {sa_family=AF_INET6, sin6_port=htons(80), sin6_flowinfo=htonl(0), inet_pton(AF_INET6, "2606:2800::1", &sin6_addr), sin6_scope_id=0}, 28) = -1 ENETUNREACH (Network is unreachable)
+1201  openat(AT_FDCWD</>, "/home/builder/secret.key", O_RDONLY) = 7</home/builder/secret.key>
+1201  openat(AT_FDCWD</>, "/usr/../etc/shadow", O_RDONLY) = 8</etc/shadow>
+1201  openat(3</usr>, "../etc/machine-id", O_RDONLY) = 9
+1201  openat(AT_FDCWD</run/osbuild/inputs>, "packages/sha256:bb", O_RDONLY) = 10
+1201  open("hosts", O_RDONLY) = 11
+1201  +++ exited with 0 +++
+"#;
+
+    #[test]
+    fn violations_found() {
+        let audit = Audit::new([PathBuf::from("/run/osbuild/inputs")]);
+
+        assert_eq!(
+            audit.check(LOG),
+            [
+                Access::Path {
+                    path: PathBuf::from("/home/builder/secret.key")
+                },
+                Access::Network {
+                    address: "93.184.215.14:443".to_string()
+                },
+                Access::Network {
+                    address: "[2606:2800::1]:80".to_string()
+                },
+                Access::Path {
+                    path: PathBuf::from("/etc/shadow")
+                },
+                Access::Path {
+                    path: PathBuf::from("/etc/machine-id")
+                },
+                Access::Path {
+                    path: PathBuf::from("hosts")
+                },
+            ]
+        );
+
+        // declaring the path makes it an input
+        let audit = Audit::new([
+            PathBuf::from("/run/osbuild/inputs"),
+            PathBuf::from("/home/builder"),
+        ]);
+
+        assert_eq!(audit.check(LOG).len(), 5);
+        assert!(audit.check("").is_empty());
+    }
+
+    #[test]
+    fn paths_normalized() {
+        assert_eq!(
+            normalized(Path::new("/usr/./lib/../../etc")),
+            Path::new("/etc")
+        );
+        assert_eq!(normalized(Path::new("/../etc")), Path::new("/etc"));
+        assert_eq!(
+            opened("openat", "3</usr/lib>, \"../share\", O_RDONLY", "4"),
+            Some(PathBuf::from("/usr/share"))
+        );
+    }
+
+    #[test]
+    fn audit_args() {
+        let args = Audit::args(Path::new("/run/osbuild/audit/org.osbuild.rpm"));
+
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["--output", "/run/osbuild/audit/org.osbuild.rpm"]));
+        assert_eq!(args.last().unwrap(), "--");
+        assert!(args.contains(&"--decode-fds=path".to_string()));
+        assert_eq!(Audit::unshare(), ["unshare", "--net", "--"]);
+    }
+
+    #[test]
+    fn violations_serialized() {
+        let violation = Violation {
+            stage: "org.osbuild.curl".to_string(),
+            access: Access::Network {
+                address: "1.1.1.1:443".to_string(),
+            },
+        };
+
+        assert_eq!(
+            serde_json::to_value(violation).unwrap(),
+            serde_json::json!({"stage": "org.osbuild.curl", "access": "network", "address": "1.1.1.1:443"})
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::Path;
+use std::process::Command;
+
+use crate::core::environment::Environment;
+use crate::module::capability::Capability;
+use crate::sandbox::unprivileged;
+
+/// The program modules are isolated with, see bwrap(1).
+pub const BWRAP: &str = "bwrap";
+
+/// How a module is isolated from the host, it is granted only the capabilities it declares
+/// and gets only the variables of its environment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Isolation {
+    network: bool,
+    devices: bool,
+    privileged: bool,
+
+    /// Whether bwrap runs in a user namespace, see `set_unprivileged`.
+    unprivileged: bool,
+    environment: Environment,
+}
+
+impl Isolation {
+    /// Isolation that grants `capabilities` and nothing else.
+    pub fn granting(capabilities: &[Capability]) -> Self {
+        Self {
+            network: capabilities.contains(&Capability::Network),
+            devices: capabilities.contains(&Capability::Devices),
+            privileged: capabilities.contains(&Capability::Privileged),
+            unprivileged: false,
+            environment: Environment::new(),
+        }
+    }
+
+    /// Isolate for a user namespace, such as of an unprivileged build. Without devices the
+    /// module gets a `/dev` with the device nodes of the host bound into it, see
+    /// `unprivileged::device_binds`.
+    pub fn set_unprivileged(&mut self, unprivileged: bool) {
+        self.unprivileged = unprivileged;
+    }
+
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = environment;
+    }
+
+    /// The capabilities this isolation grants.
+    pub fn granted(&self) -> Vec<Capability> {
+        [
+            (self.network, Capability::Network),
+            (self.devices, Capability::Devices),
+            (self.privileged, Capability::Privileged),
+        ]
+        .into_iter()
+        .filter_map(|(granted, capability)| granted.then_some(capability))
+        .collect()
+    }
+
+    /// The bwrap arguments for this isolation with `root` as the root directory. Without the
+    /// network capability the module gets its own network namespace with only loopback,
+    /// without devices a `/dev` with only the basic device nodes, and without privileges
+    /// all capabilities of root are dropped. The environment is cleared and only the
+    /// isolation's variables are set.
+    pub fn args(&self, root: &Path) -> Vec<String> {
+        let mut args = vec![
+            "--bind".to_string(),
+            root.to_string_lossy().to_string(),
+            "/".to_string(),
+            "--proc".to_string(),
+            "/proc".to_string(),
+            "--die-with-parent".to_string(),
+        ];
+
+        if self.devices {
+            args.extend(["--dev-bind", "/dev", "/dev"].map(String::from));
+        } else if self.unprivileged {
+            args.extend(["--tmpfs", "/dev"].map(String::from));
+
+            for (host, tree) in unprivileged::device_binds(Path::new("/dev")) {
+                args.extend([
+                    "--dev-bind".to_string(),
+                    host.to_string_lossy().to_string(),
+                    tree.to_string_lossy().to_string(),
+                ]);
+            }
+        } else {
+            args.extend(["--dev", "/dev"].map(String::from));
+        }
+
+        if !self.network {
+            args.push("--unshare-net".to_string());
+        }
+
+        if self.privileged {
+            args.extend(["--cap-add", "ALL"].map(String::from));
+        } else {
+            args.extend(["--cap-drop", "ALL"].map(String::from));
+        }
+
+        args.push("--clearenv".to_string());
+
+        for (name, value) in &self.environment {
+            args.extend(["--setenv".to_string(), name.clone(), value.clone()]);
+        }
+
+        args
+    }
+
+    /// A command running `program` in `root` with this isolation.
+    pub fn command(&self, root: &Path, program: &Path) -> Command {
+        let mut command = Command::new(BWRAP);
+
+        command.args(self.args(root)).arg("--").arg(program);
+
+        command
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn grants_only_declared() {
+        let isolation = Isolation::granting(&[]);
+        let args = isolation.args(Path::new("/run/osbuild/root"));
+
+        assert!(isolation.granted().is_empty());
+        assert_eq!(args[..3], ["--bind", "/run/osbuild/root", "/"]);
+        assert!(args.contains(&"--unshare-net".to_string()));
+        assert!(args.windows(2).any(|pair| pair == ["--dev", "/dev"]));
+        assert!(args.windows(2).any(|pair| pair == ["--cap-drop", "ALL"]));
+
+        let isolation = Isolation::granting(&[Capability::Network, Capability::Devices]);
+        let args = isolation.args(Path::new("/"));
+
+        assert_eq!(
+            isolation.granted(),
+            vec![Capability::Network, Capability::Devices]
+        );
+        assert!(!args.contains(&"--unshare-net".to_string()));
+        assert!(args
+            .windows(3)
+            .any(|args| args == ["--dev-bind", "/dev", "/dev"]));
+        assert!(!args.contains(&"--cap-add".to_string()));
+
+        let mut isolation = Isolation::granting(&[]);
+
+        isolation.set_unprivileged(true);
+
+        let args = isolation.args(Path::new("/"));
+
+        assert!(args.windows(2).any(|pair| pair == ["--tmpfs", "/dev"]));
+        assert!(args
+            .windows(3)
+            .any(|args| args == ["--dev-bind", "/dev/null", "/dev/null"]));
+
+        let command = Isolation::granting(&[Capability::Privileged]).command(
+            Path::new("/"),
+            Path::new("/usr/lib/osbuild/stages/org.osbuild.selinux"),
+        );
+        let args: Vec<String> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+
+        assert_eq!(command.get_program(), BWRAP);
+        assert!(args.contains(&"--clearenv".to_string()));
+        assert!(!args.contains(&"--setenv".to_string()));
+        assert!(args.windows(2).any(|pair| pair == ["--cap-add", "ALL"]));
+        assert_eq!(
+            args.last().unwrap(),
+            "/usr/lib/osbuild/stages/org.osbuild.selinux"
+        );
+    }
+
+    #[test]
+    fn environment_set() {
+        let mut isolation = Isolation::granting(&[]);
+        isolation.set_environment(Environment::from([(
+            "SOURCE_DATE_EPOCH".to_string(),
+            "0".to_string(),
+        )]));
+
+        let args = isolation.args(Path::new("/"));
+
+        assert!(args
+            .windows(4)
+            .any(|args| args == ["--clearenv", "--setenv", "SOURCE_DATE_EPOCH", "0"]));
+    }
+}
@@ -1 +1,19 @@
-mod communication;
+#[cfg(feature = "communication")]
+pub mod communication;
+
+/// Building without root privileges, in a user namespace.
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+pub mod unprivileged;
+
+/// Isolating modules so they get only the capabilities they declare.
+#[cfg(all(feature = "sandbox", feature = "executor", target_os = "linux"))]
+pub mod isolation;
+
+/// Auditing stages for access to the network and to paths they don't declare.
+#[cfg(all(feature = "sandbox", feature = "executor", target_os = "linux"))]
+pub mod hermetic;
+
+/// Building in a virtual machine, for manifests that can't be trusted with namespace
+/// isolation alone.
+#[cfg(all(feature = "vm", target_os = "linux"))]
+pub mod vm;
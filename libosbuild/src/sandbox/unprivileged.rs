@@ -0,0 +1,284 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Files listing the subordinate user and group ids users may map, see subuid(5).
+pub const SUBUID_PATH: &str = "/etc/subuid";
+pub const SUBGID_PATH: &str = "/etc/subgid";
+
+/// Device nodes that can't be created without privileges and are bind mounted from the host
+/// into the build root instead.
+pub const DEVICE_NODES: &[&str] = &["null", "zero", "full", "random", "urandom", "tty"];
+
+/// Modules that can't run in a user namespace, with the reason why.
+pub const UNSUPPORTED_MODULES: &[(&str, &str)] = &[
+    ("org.osbuild.loopback", "needs loop devices from the host"),
+    ("org.osbuild.luks2", "needs device-mapper"),
+    ("org.osbuild.lvm2.lv", "needs device-mapper"),
+    ("org.osbuild.selinux", "needs CAP_MAC_ADMIN to set labels"),
+    ("org.osbuild.grub2.inst", "writes to block devices"),
+    ("org.osbuild.bootupd", "writes to block devices"),
+    ("org.osbuild.ext4", "mounting filesystems is not allowed"),
+    ("org.osbuild.xfs", "mounting filesystems is not allowed"),
+    ("org.osbuild.btrfs", "mounting filesystems is not allowed"),
+    ("org.osbuild.fat", "mounting filesystems is not allowed"),
+];
+
+#[derive(Debug)]
+pub enum UnprivilegedError {
+    /// The user has no subordinate ids in the named file.
+    NoSubordinateIds(PathBuf),
+
+    /// Modules that can't run unprivileged, with the reason for each.
+    Unsupported(Vec<(String, String)>),
+
+    IOError(io::Error),
+}
+
+impl From<io::Error> for UnprivilegedError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+/// The real and effective user id of this process, read from `/proc/self/status`.
+pub fn current_uid() -> Result<(u32, u32), UnprivilegedError> {
+    ids_from_status(&fs::read_to_string("/proc/self/status")?, "Uid:")
+}
+
+/// The real and effective group id of this process.
+pub fn current_gid() -> Result<(u32, u32), UnprivilegedError> {
+    ids_from_status(&fs::read_to_string("/proc/self/status")?, "Gid:")
+}
+
+fn ids_from_status(status: &str, key: &str) -> Result<(u32, u32), UnprivilegedError> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("no {} line", key));
+
+    let ids: Vec<u32> = status
+        .lines()
+        .find_map(|line| line.strip_prefix(key))
+        .ok_or_else(invalid)?
+        .split_whitespace()
+        .filter_map(|id| id.parse().ok())
+        .collect();
+
+    match ids.as_slice() {
+        [real, effective, ..] => Ok((*real, *effective)),
+        _ => Err(invalid().into()),
+    }
+}
+
+/// Whether this process runs as root and can build without a user namespace.
+pub fn is_root() -> bool {
+    current_uid().is_ok_and(|(_, effective)| effective == 0)
+}
+
+/// Whether `uid_map`, the contents of `/proc/<pid>/uid_map`, maps all ids to themselves as
+/// the initial user namespace does.
+fn is_initial_uid_map(uid_map: &str) -> bool {
+    uid_map.split_whitespace().collect::<Vec<_>>() == ["0", "0", "4294967295"]
+}
+
+/// Whether this process runs in a user namespace, such as one set up by `Mapping::command`.
+/// Its root can't create device nodes.
+pub fn in_user_namespace() -> bool {
+    fs::read_to_string("/proc/self/uid_map").is_ok_and(|uid_map| !is_initial_uid_map(&uid_map))
+}
+
+/// A range of subordinate ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+/// The first subordinate id range of the user named `user` or with id `id`, from the contents
+/// of a subuid(5) or subgid file.
+pub fn parse_subids(data: &str, user: &str, id: u32) -> Option<IdRange> {
+    data.lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .find_map(|line| {
+            let mut fields = line.trim().split(':');
+            let owner = fields.next()?;
+
+            if owner != user && owner.parse() != Ok(id) {
+                return None;
+            }
+
+            Some(IdRange {
+                start: fields.next()?.parse().ok()?,
+                count: fields.next()?.parse().ok()?,
+            })
+        })
+}
+
+/// The user and group id mappings of a user namespace: the invoking user becomes root and
+/// the subordinate ranges are mapped from id 1 upwards, so trees can contain files owned by
+/// other users.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub uid: u32,
+    pub gid: u32,
+    pub uids: IdRange,
+    pub gids: IdRange,
+}
+
+impl Mapping {
+    /// The mapping for the current user from the system's subordinate id files.
+    pub fn for_current_user() -> Result<Self, UnprivilegedError> {
+        let (uid, _) = current_uid()?;
+        let (gid, _) = current_gid()?;
+        let user = std::env::var("USER").unwrap_or_default();
+
+        let lookup = |path: &str, id| -> Result<IdRange, UnprivilegedError> {
+            let data = fs::read_to_string(path).unwrap_or_default();
+            parse_subids(&data, &user, id)
+                .ok_or_else(|| UnprivilegedError::NoSubordinateIds(PathBuf::from(path)))
+        };
+
+        Ok(Self {
+            uid,
+            gid,
+            uids: lookup(SUBUID_PATH, uid)?,
+            gids: lookup(SUBGID_PATH, gid)?,
+        })
+    }
+
+    /// A command running `program` in new user, mount, and pid namespaces with this mapping.
+    /// util-linux' unshare(1) sets the mapping up with newuidmap(1) and newgidmap(1).
+    pub fn command(&self, program: &Path) -> Command {
+        let mut command = Command::new("unshare");
+
+        command
+            .args(["--user", "--mount", "--pid", "--fork", "--kill-child"])
+            .args(["--map-user=0", "--map-group=0"])
+            .arg(format!(
+                "--map-users=1:{}:{}",
+                self.uids.start, self.uids.count
+            ))
+            .arg(format!(
+                "--map-groups=1:{}:{}",
+                self.gids.start, self.gids.count
+            ))
+            .arg("--")
+            .arg(program);
+
+        command
+    }
+}
+
+/// Bind mounts, as `(host, tree)`, that provide the device nodes in `dev` of a build root.
+pub fn device_binds(dev: &Path) -> Vec<(PathBuf, PathBuf)> {
+    DEVICE_NODES
+        .iter()
+        .map(|node| (Path::new("/dev").join(node), dev.join(node)))
+        .collect()
+}
+
+/// Check that all of `modules` can run unprivileged, the error lists the ones that can't.
+pub fn check_modules<S: AsRef<str>>(modules: &[S]) -> Result<(), UnprivilegedError> {
+    let mut unsupported: Vec<(String, String)> = vec![];
+
+    for module in modules {
+        let module = module.as_ref();
+
+        if let Some((_, reason)) = UNSUPPORTED_MODULES.iter().find(|(name, _)| *name == module) {
+            if !unsupported.iter().any(|(name, _)| name == module) {
+                unsupported.push((module.to_string(), reason.to_string()));
+            }
+        }
+    }
+
+    if unsupported.is_empty() {
+        Ok(())
+    } else {
+        Err(UnprivilegedError::Unsupported(unsupported))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn status_ids() {
+        assert_eq!(
+            ids_from_status("Name:\tcat\nUid:\t1000\t0\t1000\t1000\n", "Uid:").unwrap(),
+            (1000, 0)
+        );
+        assert!(ids_from_status("Name:\tcat\n", "Uid:").is_err());
+        assert!(current_uid().is_ok());
+
+        assert!(is_initial_uid_map("         0          0 4294967295\n"));
+        assert!(!is_initial_uid_map(
+            "         0       1000          1\n         1     100000      65536\n"
+        ));
+    }
+
+    #[test]
+    fn subordinate_ids() {
+        let data = "# comment\nalice:100000:65536\n1001:165536:65536\n";
+
+        assert_eq!(
+            parse_subids(data, "alice", 1000),
+            Some(IdRange {
+                start: 100000,
+                count: 65536
+            })
+        );
+        assert_eq!(
+            parse_subids(data, "bob", 1001).map(|range| range.start),
+            Some(165536)
+        );
+        assert_eq!(parse_subids(data, "carol", 1002), None);
+    }
+
+    #[test]
+    fn mapping_command() {
+        let mapping = Mapping {
+            uid: 1000,
+            gid: 1000,
+            uids: IdRange {
+                start: 100000,
+                count: 65536,
+            },
+            gids: IdRange {
+                start: 200000,
+                count: 65536,
+            },
+        };
+
+        let args: Vec<String> = mapping
+            .command(Path::new("/usr/bin/osbuild"))
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+
+        assert!(args.contains(&"--map-users=1:100000:65536".to_string()));
+        assert!(args.contains(&"--map-groups=1:200000:65536".to_string()));
+        assert_eq!(args.last().unwrap(), "/usr/bin/osbuild");
+    }
+
+    #[test]
+    fn unsupported_modules() {
+        assert!(check_modules(&["org.osbuild.rpm", "org.osbuild.copy"]).is_ok());
+
+        match check_modules(&[
+            "org.osbuild.rpm",
+            "org.osbuild.selinux",
+            "org.osbuild.selinux",
+        ]) {
+            Err(UnprivilegedError::Unsupported(modules)) => {
+                assert_eq!(modules.len(), 1);
+                assert_eq!(modules[0].0, "org.osbuild.selinux");
+            }
+            _ => panic!("selinux should not be supported"),
+        }
+
+        assert_eq!(
+            device_binds(Path::new("/tree/dev"))[0],
+            (PathBuf::from("/dev/null"), PathBuf::from("/tree/dev/null"))
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::core::result::BuildResult;
+use crate::manifest::Manifest;
+use crate::sandbox::communication::channel::protocol::message::{Method, Reply};
+use crate::sandbox::communication::channel::protocol::JSONProtocol;
+use crate::sandbox::communication::channel::transport::{
+    Transport, TransportError, VSockListener, VSockSTREAMSocket, VMADDR_CID_HOST,
+};
+use crate::sandbox::communication::channel::{Channel, ChannelError, CommandChannel};
+
+/// The device qemu needs to run virtual machines with hardware acceleration.
+pub const KVM_PATH: &str = "/dev/kvm";
+
+pub const QEMU: &str = "qemu-system-x86_64";
+pub const VIRTIOFSD: &str = "/usr/libexec/virtiofsd";
+
+/// The kernel and initrd the virtual machine boots, the initrd starts osbuild which connects
+/// back to the host over vsock.
+pub const KERNEL_PATH: &str = "/usr/lib/osbuild/vm/vmlinuz";
+pub const INITRD_PATH: &str = "/usr/lib/osbuild/vm/initrd.img";
+
+/// The vsock port the host listens on for the channel to the virtual machine.
+pub const CHANNEL_PORT: u32 = 1024;
+
+/// Context ids 0 to 2 are reserved, 2 being the host.
+pub const FIRST_GUEST_CID: u32 = 3;
+
+/// How long to wait for virtiofsd to create its socket.
+pub const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The tags the store and the output directory of a build are shared with the guest by.
+pub const STORE_TAG: &str = "store";
+pub const OUTPUT_TAG: &str = "output";
+
+/// The method the host asks the guest to build with, its arguments are a `BuildRequest`
+/// and the guest replies with the `BuildResult`.
+pub const BUILD_METHOD: &str = "build";
+
+#[derive(Debug)]
+pub enum VmError {
+    /// The host has no KVM, the virtual machine would be far too slow to build in.
+    NoKvm,
+
+    /// A helper didn't create its socket in time, contains the path of the socket.
+    Timeout(PathBuf),
+
+    /// The other end asked for a method other than `BUILD_METHOD`, contains its name.
+    UnknownMethod(String),
+
+    /// The arguments or reply of `BUILD_METHOD` could not be decoded.
+    InvalidMessage(serde_json::Error),
+
+    Transport(TransportError),
+    Channel(ChannelError),
+    IOError(io::Error),
+}
+
+impl From<io::Error> for VmError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+impl From<TransportError> for VmError {
+    fn from(err: TransportError) -> Self {
+        Self::Transport(err)
+    }
+}
+
+impl From<ChannelError> for VmError {
+    fn from(err: ChannelError) -> Self {
+        Self::Channel(err)
+    }
+}
+
+impl From<serde_json::Error> for VmError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::InvalidMessage(err)
+    }
+}
+
+/// A directory of the host shared with the virtual machine over virtiofs, the guest mounts it
+/// by its tag.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Share {
+    pub tag: String,
+    pub path: PathBuf,
+    pub readonly: bool,
+}
+
+/// How to run the virtual machine a build runs in. Only the directories that are shared are
+/// visible to the guest and the only way to talk to the host is the vsock channel.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct VmConfig {
+    pub qemu: PathBuf,
+    pub virtiofsd: PathBuf,
+    pub kernel: PathBuf,
+    pub initrd: Option<PathBuf>,
+    pub cpus: u32,
+    pub memory_mb: u64,
+
+    /// The context id of the guest, must be unique among the virtual machines on the host.
+    pub cid: u32,
+
+    /// The vsock port the host listens on for the guest to connect to.
+    pub port: u32,
+
+    pub shares: Vec<Share>,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            qemu: PathBuf::from(QEMU),
+            virtiofsd: PathBuf::from(VIRTIOFSD),
+            kernel: PathBuf::from(KERNEL_PATH),
+            initrd: Some(PathBuf::from(INITRD_PATH)),
+            cpus: 2,
+            memory_mb: 2048,
+            cid: FIRST_GUEST_CID,
+            port: CHANNEL_PORT,
+            shares: vec![],
+        }
+    }
+}
+
+impl VmConfig {
+    pub fn add_share(&mut self, tag: &str, path: &Path, readonly: bool) {
+        self.shares.push(Share {
+            tag: tag.to_string(),
+            path: path.to_path_buf(),
+            readonly,
+        });
+    }
+
+    /// The socket virtiofsd serves `share` on.
+    pub fn socket_path(&self, share: &Share, runtime: &Path) -> PathBuf {
+        runtime.join(format!("virtiofs-{}.sock", share.tag))
+    }
+
+    pub fn virtiofsd_command(&self, share: &Share, runtime: &Path) -> Command {
+        let mut command = Command::new(&self.virtiofsd);
+
+        command
+            .arg(format!(
+                "--socket-path={}",
+                self.socket_path(share, runtime).display()
+            ))
+            .arg(format!("--shared-dir={}", share.path.display()))
+            .arg("--cache=never");
+
+        if share.readonly {
+            command.arg("--readonly");
+        }
+
+        command
+    }
+
+    /// The kernel command line, it tells the guest where to connect to and what to mount.
+    pub fn cmdline(&self) -> String {
+        let tags: Vec<&str> = self.shares.iter().map(|share| share.tag.as_str()).collect();
+
+        format!(
+            "console=hvc0 quiet panic=-1 osbuild.channel={}:{} osbuild.shares={}",
+            VMADDR_CID_HOST,
+            self.port,
+            tags.join(",")
+        )
+    }
+
+    pub fn qemu_command(&self, runtime: &Path) -> Command {
+        let mut command = Command::new(&self.qemu);
+
+        command
+            .args(["-machine", "q35,accel=kvm", "-cpu", "host"])
+            .args(["-nodefaults", "-nographic", "-no-reboot"])
+            .args(["-smp", &self.cpus.to_string()])
+            .args(["-m", &format!("{}M", self.memory_mb)])
+            // virtiofs needs the guest's memory to be shared with virtiofsd
+            .args([
+                "-object",
+                &format!(
+                    "memory-backend-memfd,id=mem,size={}M,share=on",
+                    self.memory_mb
+                ),
+                "-numa",
+                "node,memdev=mem",
+            ])
+            .args([
+                "-device",
+                "virtio-serial",
+                "-device",
+                "virtconsole,chardev=console",
+            ])
+            .args(["-chardev", "stdio,id=console"])
+            .args(["-kernel", &self.kernel.to_string_lossy()]);
+
+        if let Some(initrd) = &self.initrd {
+            command.args(["-initrd", &initrd.to_string_lossy()]);
+        }
+
+        command.args(["-append", &self.cmdline()]);
+
+        for (index, share) in self.shares.iter().enumerate() {
+            command
+                .args([
+                    "-chardev",
+                    &format!(
+                        "socket,id=fs{},path={}",
+                        index,
+                        self.socket_path(share, runtime).display()
+                    ),
+                ])
+                .args([
+                    "-device",
+                    &format!("vhost-user-fs-pci,chardev=fs{},tag={}", index, share.tag),
+                ]);
+        }
+
+        command.args([
+            "-device",
+            &format!("vhost-vsock-pci,guest-cid={}", self.cid),
+        ]);
+
+        command
+    }
+}
+
+/// Wait for `path` to exist.
+fn wait_for(path: &Path, timeout: Duration) -> Result<(), VmError> {
+    let start = Instant::now();
+
+    while !path.exists() {
+        if start.elapsed() > timeout {
+            return Err(VmError::Timeout(path.to_path_buf()));
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    Ok(())
+}
+
+/// A running virtual machine with the virtiofsd processes serving its shares. They are
+/// killed when the `Vm` is dropped.
+pub struct Vm {
+    listener: VSockListener,
+    children: Vec<Child>,
+}
+
+impl Vm {
+    /// Listen for the guest, start the virtiofsd processes, wait for their sockets, and boot
+    /// the virtual machine. Sockets go in `runtime`, a directory that is private to this
+    /// build.
+    pub fn start(config: &VmConfig, runtime: &Path) -> Result<Self, VmError> {
+        if !Path::new(KVM_PATH).exists() {
+            return Err(VmError::NoKvm);
+        }
+
+        // the guest connects as soon as it boots, so the host has to listen before that
+        let mut vm = Self {
+            listener: VSockListener::bind(config.port)?,
+            children: vec![],
+        };
+
+        for share in &config.shares {
+            vm.children
+                .push(config.virtiofsd_command(share, runtime).spawn()?);
+
+            wait_for(&config.socket_path(share, runtime), SOCKET_TIMEOUT)?;
+        }
+
+        vm.children.push(config.qemu_command(runtime).spawn()?);
+
+        Ok(vm)
+    }
+
+    /// Wait for the guest to connect, the transport is used for the channel to the osbuild
+    /// running in the guest.
+    pub fn accept(&self) -> Result<VSockSTREAMSocket, VmError> {
+        Ok(self.listener.accept()?)
+    }
+
+    /// Kill the virtual machine and its virtiofsd processes, qemu first.
+    pub fn stop(&mut self) -> Result<(), VmError> {
+        let mut result = Ok(());
+
+        while let Some(mut child) = self.children.pop() {
+            if let Err(err) = child.kill().and_then(|_| child.wait()) {
+                if result.is_ok() {
+                    result = Err(err.into());
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// What the host asks the guest to build, the arguments of `BUILD_METHOD`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, Deserialize)]
+pub struct BuildRequest {
+    pub manifest: Manifest,
+
+    /// The pipelines whose trees the guest copies into their directories in the output
+    /// directory, finishing the exports is up to the host.
+    pub exports: Vec<String>,
+}
+
+fn channel(transport: VSockSTREAMSocket) -> CommandChannel {
+    CommandChannel {
+        transport: Box::new(transport),
+        protocol: Box::new(JSONProtocol {}),
+    }
+}
+
+/// Build `request` in a virtual machine as `config` says, with `store` and `output` shared
+/// with the guest read-write under `STORE_TAG` and `OUTPUT_TAG`. Nothing else of the host is
+/// visible to it. Returns the result the guest replies with.
+pub fn build(
+    config: &VmConfig,
+    request: &BuildRequest,
+    store: &Path,
+    output: &Path,
+    runtime: &Path,
+) -> Result<BuildResult, VmError> {
+    let mut config = config.clone();
+
+    config.add_share(STORE_TAG, store, false);
+    config.add_share(OUTPUT_TAG, output, false);
+
+    let vm = Vm::start(&config, runtime)?;
+    let mut channel = channel(vm.accept()?);
+
+    let reply: Reply =
+        channel.send_and_recv(Method::new(BUILD_METHOD, serde_json::to_value(request)?))?;
+
+    Ok(serde_json::from_value(reply.data.reply)?)
+}
+
+/// The guest's end of `build`: connect to the host at `address`, the `<cid>:<port>` of the
+/// `osbuild.channel` on the kernel command line, and reply to its request with the result
+/// of `build`.
+pub fn serve<F: FnOnce(BuildRequest) -> BuildResult>(
+    address: &str,
+    build: F,
+) -> Result<(), VmError> {
+    let mut channel = channel(VSockSTREAMSocket::new(address.to_string(), None)?);
+    let method: Method = channel.recv()?;
+
+    if method.data.name != BUILD_METHOD {
+        return Err(VmError::UnknownMethod(method.data.name));
+    }
+
+    let result = build(serde_json::from_value(method.data.args)?);
+
+    channel.send(Reply::new(serde_json::to_value(result)?, vec![]))?;
+    channel.close()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn qemu_arguments() {
+        let mut config = VmConfig {
+            cid: 42,
+            ..Default::default()
+        };
+
+        config.add_share("store", Path::new("/var/cache/osbuild"), false);
+        config.add_share("manifest", Path::new("/tmp/manifest"), true);
+
+        let runtime = Path::new("/run/osbuild/vm");
+        let qemu = args(&config.qemu_command(runtime));
+
+        assert!(qemu.contains(&"vhost-vsock-pci,guest-cid=42".to_string()));
+        assert!(
+            qemu.contains(&"socket,id=fs1,path=/run/osbuild/vm/virtiofs-manifest.sock".to_string())
+        );
+        assert!(qemu.contains(&"vhost-user-fs-pci,chardev=fs0,tag=store".to_string()));
+        assert!(qemu.contains(&"memory-backend-memfd,id=mem,size=2048M,share=on".to_string()));
+        assert!(qemu.windows(2).any(|pair| pair == ["-initrd", INITRD_PATH]));
+        assert!(config
+            .cmdline()
+            .ends_with("osbuild.channel=2:1024 osbuild.shares=store,manifest"));
+
+        let virtiofsd = args(&config.virtiofsd_command(&config.shares[1], runtime));
+
+        assert_eq!(
+            virtiofsd,
+            vec![
+                "--socket-path=/run/osbuild/vm/virtiofs-manifest.sock",
+                "--shared-dir=/tmp/manifest",
+                "--cache=never",
+                "--readonly",
+            ]
+        );
+    }
+
+    #[test]
+    fn socket_timeout() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("virtiofs-store.sock");
+
+        assert!(matches!(
+            wait_for(&path, Duration::from_millis(100)),
+            Err(VmError::Timeout(timed_out)) if timed_out == path
+        ));
+
+        std::fs::write(&path, "").unwrap();
+
+        assert!(wait_for(&path, Duration::from_millis(100)).is_ok());
+    }
+}
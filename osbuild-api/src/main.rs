@@ -6,6 +6,6 @@ fn main() {
 mod test {
     #[test]
     fn dummy() {
-        assert!(true);
+        assert_eq!(1, 1);
     }
 }
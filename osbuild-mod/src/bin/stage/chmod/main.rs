@@ -0,0 +1,255 @@
+//! `org.osbuild.chmod`: change the mode of files in the tree. Options are those of the Python
+//! stage, modes are octal or symbolic as chmod(1) takes them:
+//!
+//! ```json
+//! {"items": {"/usr/bin/tool": {"mode": "u+x,go-w"}, "/etc/ssh": {"mode": "0700", "recursive": true}}}
+//! ```
+//!
+//! Symbolic modes without `u`, `g`, `o`, or `a` apply to all, they are not masked with the
+//! umask as chmod(1) does. Symlinks are left alone.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process::ExitCode;
+
+use serde::Deserialize;
+
+use libosbuild::module::stage::{self, Location, Stage, StageError};
+use libosbuild::module::util::tree;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Chmod {
+    mode: String,
+
+    /// Also change everything below a directory.
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    items: BTreeMap<String, Chmod>,
+}
+
+/// One `<who><op><permissions>` of a symbolic mode, `who` and `permissions` as masks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Clause {
+    who: u32,
+    op: char,
+    permissions: u32,
+
+    /// `X`; execute for directories and files that are executable by anyone.
+    conditional: bool,
+}
+
+/// A mode to set, or symbolic clauses to change the mode a file has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+    Absolute(u32),
+    Symbolic(Vec<Clause>),
+}
+
+impl Mode {
+    fn parse(mode: &str) -> Result<Self, StageError> {
+        let invalid = || {
+            StageError::InvalidOptions(serde::de::Error::custom(format!("invalid mode {:?}", mode)))
+        };
+
+        if !mode.is_empty() && mode.chars().all(|c| c.is_digit(8)) {
+            return match u32::from_str_radix(mode, 8) {
+                Ok(bits) if bits <= 0o7777 => Ok(Self::Absolute(bits)),
+                _ => Err(invalid()),
+            };
+        }
+
+        let mut clauses = vec![];
+
+        for text in mode.split(',') {
+            let mut chars = text.chars().peekable();
+            let mut who = 0;
+
+            while let Some(c) = chars.next_if(|c| "ugoa".contains(*c)) {
+                who |= match c {
+                    'u' => 0o4700,
+                    'g' => 0o2070,
+                    'o' => 0o1007,
+                    _ => 0o7777,
+                };
+            }
+
+            if who == 0 {
+                who = 0o7777;
+            }
+
+            if chars.peek().is_none() {
+                return Err(invalid());
+            }
+
+            while let Some(op) = chars.next() {
+                if !"+-=".contains(op) {
+                    return Err(invalid());
+                }
+
+                let mut clause = Clause {
+                    who,
+                    op,
+                    permissions: 0,
+                    conditional: false,
+                };
+
+                while let Some(c) = chars.next_if(|c| !"+-=".contains(*c)) {
+                    match c {
+                        'r' => clause.permissions |= 0o444,
+                        'w' => clause.permissions |= 0o222,
+                        'x' => clause.permissions |= 0o111,
+                        'X' => clause.conditional = true,
+                        's' => clause.permissions |= 0o6000,
+                        't' => clause.permissions |= 0o1000,
+                        _ => return Err(invalid()),
+                    }
+                }
+
+                clauses.push(clause);
+            }
+        }
+
+        Ok(Self::Symbolic(clauses))
+    }
+
+    /// The mode a file with `mode` gets, `directory` tells if it is one for `X`.
+    fn apply(&self, mode: u32, directory: bool) -> u32 {
+        let clauses = match self {
+            Self::Absolute(bits) => return *bits,
+            Self::Symbolic(clauses) => clauses,
+        };
+
+        clauses.iter().fold(mode & 0o7777, |mode, clause| {
+            let mut permissions = clause.permissions;
+
+            if clause.conditional && (directory || mode & 0o111 != 0) {
+                permissions |= 0o111;
+            }
+
+            let permissions = permissions & clause.who;
+
+            match clause.op {
+                '+' => mode | permissions,
+                '-' => mode & !permissions,
+                _ => (mode & !clause.who) | permissions,
+            }
+        })
+    }
+}
+
+fn chmod(stage: &mut Stage) -> Result<(), StageError> {
+    let options: Options = stage.options()?;
+
+    for (path, item) in &options.items {
+        let mode = Mode::parse(&item.mode)?;
+        let target = stage.resolve(&Location::from_option(path)?)?;
+
+        let mut change = |path: &std::path::Path, metadata: &fs::Metadata| {
+            if metadata.file_type().is_symlink() {
+                return Ok(());
+            }
+
+            let new = mode.apply(metadata.permissions().mode(), metadata.is_dir());
+
+            fs::set_permissions(path, fs::Permissions::from_mode(new))
+        };
+
+        if item.recursive {
+            tree::walk(&target, &mut change)?;
+        } else {
+            change(&target, &fs::symlink_metadata(&target)?)?;
+        }
+
+        stage.log(&format!("changed the mode of {} to {}", path, item.mode));
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    stage::run(chmod)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::Path;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn modes_parsed() {
+        for (mode, from, directory, to) in [
+            ("0755", 0o600, false, 0o755),
+            ("4755", 0o600, false, 0o4755),
+            ("u+x", 0o644, false, 0o744),
+            ("go-w", 0o666, false, 0o644),
+            ("+x", 0o644, false, 0o755),
+            ("a=r", 0o777, false, 0o444),
+            ("u=rw,go=", 0o755, false, 0o600),
+            ("a+X", 0o644, false, 0o644),
+            ("a+X", 0o744, false, 0o755),
+            ("a+X", 0o600, true, 0o711),
+            ("u+s,+t", 0o755, false, 0o5755),
+            ("o=rx-x", 0o700, false, 0o704),
+        ] {
+            assert_eq!(
+                Mode::parse(mode).unwrap().apply(from, directory),
+                to,
+                "{} on {:o}",
+                mode,
+                from
+            );
+        }
+
+        for mode in ["", "u", "10000", "u+q", "z+x", "u+x,", "0o755"] {
+            assert!(Mode::parse(mode).is_err(), "{}", mode);
+        }
+    }
+
+    #[test]
+    fn modes_changed() {
+        let tree = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(tree.path().join("etc/ssh")).unwrap();
+        fs::write(tree.path().join("etc/ssh/sshd_config"), "").unwrap();
+        fs::write(tree.path().join("etc/hostname"), "").unwrap();
+        fs::set_permissions(
+            tree.path().join("etc/hostname"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", tree.path().join("etc/ssh/link")).unwrap();
+
+        chmod(&mut Stage::for_tree(
+            tree.path(),
+            serde_json::json!({"items": {
+                "/etc/ssh": {"mode": "go=", "recursive": true},
+                "tree:///etc/hostname": {"mode": "a+r"},
+            }}),
+        ))
+        .unwrap();
+
+        assert_eq!(mode(&tree.path().join("etc/ssh")) & 0o077, 0);
+        assert_eq!(mode(&tree.path().join("etc/ssh/sshd_config")) & 0o077, 0);
+        assert_eq!(mode(&tree.path().join("etc/hostname")), 0o644);
+
+        let err = chmod(&mut Stage::for_tree(
+            tree.path(),
+            serde_json::json!({"items": {"/etc/hostname": {"mode": "u+q"}}}),
+        ))
+        .unwrap_err();
+
+        assert!(matches!(err, StageError::InvalidOptions(_)));
+    }
+}
@@ -0,0 +1,175 @@
+//! `org.osbuild.chown`: change the owner and group of files in the tree. Options are those
+//! of the Python stage, users and groups are names in the tree's databases or ids:
+//!
+//! ```json
+//! {"items": {"/var/lib/app": {"user": "app", "group": 990, "recursive": true}}}
+//! ```
+//!
+//! Symlinks themselves are changed, not what they point to.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::lchown;
+use std::path::Path;
+use std::process::ExitCode;
+
+use serde::Deserialize;
+
+use libosbuild::module::stage::{self, Location, Stage, StageError};
+use libosbuild::module::util::passwd::{Database, PasswdError};
+use libosbuild::module::util::tree;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Id {
+    Id(u32),
+    Name(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Chown {
+    user: Option<Id>,
+    group: Option<Id>,
+
+    /// Also change everything below a directory.
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    items: BTreeMap<String, Chown>,
+}
+
+fn uid(database: &Database, user: &Id) -> Result<u32, PasswdError> {
+    match user {
+        Id::Id(uid) => Ok(*uid),
+        Id::Name(name) => database
+            .user(name)
+            .map(|entry| entry.uid)
+            .ok_or_else(|| PasswdError::NoSuchUser(name.clone())),
+    }
+}
+
+fn gid(database: &Database, group: &Id) -> Result<u32, PasswdError> {
+    match group {
+        Id::Id(gid) => Ok(*gid),
+        Id::Name(name) => database
+            .group(name)
+            .map(|entry| entry.gid)
+            .ok_or_else(|| PasswdError::NoSuchGroup(name.clone())),
+    }
+}
+
+fn chown(stage: &mut Stage) -> Result<(), StageError> {
+    let options: Options = stage.options()?;
+    let database = Database::open(&stage.arguments.tree)?;
+
+    for (path, item) in &options.items {
+        if item.user.is_none() && item.group.is_none() {
+            return Err(StageError::InvalidOptions(serde::de::Error::custom(
+                format!("{} has neither a user nor a group", path),
+            )));
+        }
+
+        let uid = item
+            .user
+            .as_ref()
+            .map(|user| uid(&database, user))
+            .transpose()?;
+        let gid = item
+            .group
+            .as_ref()
+            .map(|group| gid(&database, group))
+            .transpose()?;
+        let target = stage.resolve(&Location::from_option(path)?)?;
+
+        let mut change = |path: &Path, _: &fs::Metadata| lchown(path, uid, gid);
+
+        if item.recursive {
+            tree::walk(&target, &mut change)?;
+        } else {
+            lchown(&target, uid, gid)?;
+        }
+
+        stage.log(&format!("changed the owner of {}", path));
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    stage::run(chown)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn owners_changed() {
+        let tree = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(tree.path().join("var/lib/app")).unwrap();
+        fs::write(tree.path().join("var/lib/app/data"), "").unwrap();
+
+        // only root can give files away, so the files are given to whoever runs the test
+        let metadata = fs::metadata(tree.path()).unwrap();
+
+        fs::create_dir(tree.path().join("etc")).unwrap();
+        fs::write(
+            tree.path().join("etc/passwd"),
+            format!(
+                "app:x:{}:{}::/var/lib/app:/sbin/nologin\n",
+                metadata.uid(),
+                metadata.gid()
+            ),
+        )
+        .unwrap();
+        fs::write(
+            tree.path().join("etc/group"),
+            format!("app:x:{}:\n", metadata.gid()),
+        )
+        .unwrap();
+
+        chown(&mut Stage::for_tree(
+            tree.path(),
+            serde_json::json!({"items": {
+                "/var/lib/app": {"user": "app", "group": metadata.gid(), "recursive": true},
+                "tree:///var": {"group": "app"},
+            }}),
+        ))
+        .unwrap();
+
+        let data = fs::metadata(tree.path().join("var/lib/app/data")).unwrap();
+
+        assert_eq!((data.uid(), data.gid()), (metadata.uid(), metadata.gid()));
+
+        for (options, expected) in [
+            (
+                serde_json::json!({"items": {"/var": {"user": "nobody"}}}),
+                "PasswdError(NoSuchUser",
+            ),
+            (
+                serde_json::json!({"items": {"/var": {"group": "nobody"}}}),
+                "PasswdError(NoSuchGroup",
+            ),
+            (
+                serde_json::json!({"items": {"/var": {"recursive": true}}}),
+                "InvalidOptions",
+            ),
+            (
+                serde_json::json!({"items": {"/srv": {"user": "app"}}}),
+                "IOError",
+            ),
+        ] {
+            let err = chown(&mut Stage::for_tree(tree.path(), options)).unwrap_err();
+
+            assert!(format!("{:?}", err).starts_with(expected), "{:?}", err);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! `org.osbuild.copy`: copy files and directories from inputs, mounts, or the tree into the
+//! tree or a mount, as `cp -a` does. Options are those of the Python stage:
+//!
+//! ```json
+//! {"paths": [{"from": "input://tree/etc/hostname", "to": "tree:///etc/", "remove_destination": false}]}
+//! ```
+
+use std::fs;
+use std::io;
+use std::process::ExitCode;
+
+use serde::Deserialize;
+
+use libosbuild::module::stage::{self, Location, Stage, StageError};
+use libosbuild::module::util::tree;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Copy {
+    from: String,
+    to: String,
+
+    /// Remove what is at the destination before copying instead of copying over it.
+    #[serde(default)]
+    remove_destination: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    paths: Vec<Copy>,
+}
+
+fn copy(stage: &mut Stage) -> Result<(), StageError> {
+    let options: Options = stage.options()?;
+
+    for path in &options.paths {
+        stage.check_cancelled()?;
+
+        let from = stage.resolve(&path.from.parse::<Location>()?)?;
+        let mut to = stage.resolve(&path.to.parse::<Location>()?)?;
+
+        // like `cp`, copying onto a directory copies into it
+        if to.is_dir() {
+            if let Some(name) = from.file_name() {
+                to.push(name);
+            }
+        }
+
+        if path.remove_destination {
+            match fs::symlink_metadata(&to) {
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&to)?,
+                Ok(_) => fs::remove_file(&to)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let copied = tree::copy_all(&from, &to)?;
+
+        stage.log(&format!(
+            "copied {} to {} ({} files)",
+            path.from, path.to, copied
+        ));
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    stage::run(copy)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths_copied() {
+        let tree = tempfile::tempdir().unwrap();
+        let input = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(input.path().join("etc/ssh")).unwrap();
+        fs::write(input.path().join("etc/hostname"), "builder\n").unwrap();
+        fs::write(input.path().join("etc/ssh/sshd_config"), "Port 22\n").unwrap();
+        fs::create_dir_all(tree.path().join("etc/ssh")).unwrap();
+        fs::write(tree.path().join("etc/ssh/old"), "").unwrap();
+
+        let mut stage = Stage::for_tree(
+            tree.path(),
+            serde_json::json!({"paths": [
+                {"from": "input://files/etc/hostname", "to": "tree:///etc/"},
+                {"from": "input://files/etc/ssh", "to": "tree:///etc", "remove_destination": true},
+                {"from": "tree:///etc/hostname", "to": "tree:///etc/hostname.orig"},
+            ]}),
+        )
+        .with_input("files", input.path());
+
+        copy(&mut stage).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(tree.path().join("etc/hostname")).unwrap(),
+            "builder\n"
+        );
+        assert_eq!(
+            fs::read_to_string(tree.path().join("etc/hostname.orig")).unwrap(),
+            "builder\n"
+        );
+        assert!(tree.path().join("etc/ssh/sshd_config").is_file());
+        assert!(!tree.path().join("etc/ssh/old").exists());
+    }
+
+    #[test]
+    fn paths_refused() {
+        let tree = tempfile::tempdir().unwrap();
+        let input = tempfile::tempdir().unwrap();
+
+        for (options, expected) in [
+            (
+                serde_json::json!({"paths": [{"from": "input://files/../../etc", "to": "tree:///"}]}),
+                "TreeError",
+            ),
+            (
+                serde_json::json!({"paths": [{"from": "input://other/etc", "to": "tree:///"}]}),
+                "NoSuchLocation",
+            ),
+            (
+                serde_json::json!({"paths": [{"from": "/etc", "to": "tree:///"}]}),
+                "InvalidLocation",
+            ),
+            (
+                serde_json::json!({"paths": [{"from": "input://files/", "to": "tree:///", "mode": 1}]}),
+                "InvalidOptions",
+            ),
+        ] {
+            let err =
+                copy(&mut Stage::for_tree(tree.path(), options).with_input("files", input.path()))
+                    .unwrap_err();
+
+            assert!(format!("{:?}", err).starts_with(expected), "{:?}", err);
+        }
+    }
+}
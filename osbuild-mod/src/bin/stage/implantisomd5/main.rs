@@ -0,0 +1,68 @@
+//! `org.osbuild.implantisomd5`: implant the MD5 checksum of media in the tree into them, so
+//! installers can check them. Options are those of the Python stage:
+//!
+//! ```json
+//! {"filename": "installer.iso"}
+//! ```
+
+use std::path::Path;
+use std::process::ExitCode;
+
+use serde::Deserialize;
+
+use libosbuild::module::stage::{self, Stage, StageError};
+use libosbuild::module::util::iso::ImplantIsoMd5;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    filename: String,
+}
+
+fn implantisomd5(stage: &mut Stage) -> Result<(), StageError> {
+    let options: Options = stage.options()?;
+
+    let path = stage
+        .tree()
+        .resolve(Path::new("/").join(&options.filename))?;
+
+    if !path.is_file() {
+        return Err(StageError::NoSuchLocation(options.filename));
+    }
+
+    ImplantIsoMd5::new().implant(&path)?;
+
+    stage.log(&format!("implanted the checksum of {}", options.filename));
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    stage::run(implantisomd5)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn options_refused() {
+        let tree = tempfile::tempdir().unwrap();
+
+        for (options, expected) in [
+            (
+                serde_json::json!({"filename": "installer.iso"}),
+                "NoSuchLocation",
+            ),
+            (
+                serde_json::json!({"filename": "../../etc/passwd"}),
+                "TreeError",
+            ),
+            (serde_json::json!({}), "InvalidOptions"),
+        ] {
+            let err = implantisomd5(&mut Stage::for_tree(tree.path(), options)).unwrap_err();
+
+            assert!(format!("{:?}", err).starts_with(expected), "{:?}", err);
+        }
+    }
+}
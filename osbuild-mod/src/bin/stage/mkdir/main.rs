@@ -0,0 +1,147 @@
+//! `org.osbuild.mkdir`: create directories in the tree or a mount. Options are those of the
+//! Python stage, paths are URLs or absolute paths in the tree:
+//!
+//! ```json
+//! {"paths": [{"path": "/etc/containers", "mode": 493, "parents": true, "exist_ok": true}]}
+//! ```
+//!
+//! Unlike the Python stage the mode of created directories is not subject to the umask, so
+//! trees come out the same wherever they are built.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::process::ExitCode;
+
+use serde::Deserialize;
+
+use libosbuild::module::stage::{self, Location, Stage, StageError};
+
+fn default_mode() -> u32 {
+    0o777
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mkdir {
+    path: String,
+
+    #[serde(default = "default_mode")]
+    mode: u32,
+
+    /// Create missing parents, with the default mode.
+    #[serde(default)]
+    parents: bool,
+
+    /// Succeed when the directory already exists, its mode is left as it is.
+    #[serde(default)]
+    exist_ok: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    paths: Vec<Mkdir>,
+}
+
+fn mkdir(stage: &mut Stage) -> Result<(), StageError> {
+    let options: Options = stage.options()?;
+
+    for path in &options.paths {
+        let target = stage.resolve(&Location::from_option(&path.path)?)?;
+
+        if path.parents {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        match fs::create_dir(&target) {
+            Ok(()) => fs::set_permissions(&target, fs::Permissions::from_mode(path.mode))?,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists && path.exist_ok => {
+                if !target.is_dir() {
+                    return Err(err.into());
+                }
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        stage.log(&format!("created {} ({:o})", path.path, path.mode));
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    stage::run(mkdir)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::Path;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn directories_created() {
+        let tree = tempfile::tempdir().unwrap();
+
+        fs::create_dir(tree.path().join("etc")).unwrap();
+
+        mkdir(&mut Stage::for_tree(
+            tree.path(),
+            serde_json::json!({"paths": [
+                {"path": "/etc/containers", "mode": 0o750},
+                {"path": "tree:///var/lib/containers/storage", "parents": true},
+                {"path": "/etc", "mode": 0o700, "exist_ok": true},
+                {"path": "/etc/containers/../../srv"},
+            ]}),
+        ))
+        .unwrap();
+
+        assert_eq!(mode(&tree.path().join("etc/containers")), 0o750);
+        assert_eq!(mode(&tree.path().join("var/lib/containers/storage")), 0o777);
+        assert_ne!(mode(&tree.path().join("etc")), 0o700);
+        assert!(tree.path().join("srv").is_dir());
+    }
+
+    #[test]
+    fn directories_refused() {
+        let tree = tempfile::tempdir().unwrap();
+
+        fs::create_dir(tree.path().join("etc")).unwrap();
+        fs::write(tree.path().join("etc/hostname"), "").unwrap();
+
+        for (options, expected) in [
+            (serde_json::json!({"paths": [{"path": "/etc"}]}), "IOError"),
+            (
+                serde_json::json!({"paths": [{"path": "/etc/hostname", "exist_ok": true}]}),
+                "IOError",
+            ),
+            (
+                serde_json::json!({"paths": [{"path": "/usr/lib"}]}),
+                "IOError",
+            ),
+            (
+                serde_json::json!({"paths": [{"path": "/../srv"}]}),
+                "TreeError",
+            ),
+            (
+                serde_json::json!({"paths": [{"path": "etc"}]}),
+                "InvalidLocation",
+            ),
+            (
+                serde_json::json!({"paths": [{"path": "/etc", "mode": "0755"}]}),
+                "InvalidOptions",
+            ),
+        ] {
+            let err = mkdir(&mut Stage::for_tree(tree.path(), options)).unwrap_err();
+
+            assert!(format!("{:?}", err).starts_with(expected), "{:?}", err);
+        }
+    }
+}
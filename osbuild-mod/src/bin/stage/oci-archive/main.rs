@@ -0,0 +1,143 @@
+//! `org.osbuild.oci-archive`: assemble the tree of the `base` input into a container image
+//! with a single layer, written to `filename` in the tree. Options are those of the Python
+//! stage, with the format of the archive and the name of the image added:
+//!
+//! ```json
+//! {"architecture": "amd64", "filename": "container.tar", "config": {"Cmd": ["/bin/sh"]}, "format": "docker", "reference": "localhost/image:latest"}
+//! ```
+//!
+//! Modification times in the layer are clamped to `SOURCE_DATE_EPOCH` when it is set, which is
+//! the creation time of the image as well, so the same tree makes the same image.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use serde::Deserialize;
+
+use libosbuild::module::stage::{self, Location, Stage, StageError};
+use libosbuild::module::util::oci::{self, ArchiveFormat, ImageOptions, RuntimeConfig};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    /// The architecture in the terms of Go, such as `amd64` or `arm64`.
+    architecture: String,
+
+    filename: String,
+
+    #[serde(default)]
+    config: RuntimeConfig,
+
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+
+    #[serde(default)]
+    format: ArchiveFormat,
+
+    reference: Option<String>,
+}
+
+fn oci_archive(stage: &mut Stage) -> Result<(), StageError> {
+    let options: Options = stage.options()?;
+
+    // like the Python stage, the archive is a file at the top of the tree
+    if options.filename.is_empty() || options.filename.contains('/') {
+        return Err(StageError::InvalidLocation(options.filename));
+    }
+
+    let base = stage.resolve(&Location::Input("base".to_string(), PathBuf::from("/")))?;
+    let path = stage
+        .tree()
+        .resolve(Path::new("/").join(&options.filename))?;
+
+    let manifest = oci::write_archive(
+        &base,
+        &path,
+        &ImageOptions {
+            architecture: options.architecture,
+            config: options.config,
+            annotations: options.annotations,
+            reference: options.reference,
+            source_date_epoch: env::var("SOURCE_DATE_EPOCH")
+                .ok()
+                .and_then(|epoch| epoch.parse().ok()),
+        },
+        options.format,
+    )?;
+
+    stage.log(&format!(
+        "assembled {} ({})",
+        options.filename, manifest.digest
+    ));
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    stage::run(oci_archive)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn images_assembled() {
+        let tree = tempfile::tempdir().unwrap();
+        let input = tempfile::tempdir().unwrap();
+
+        fs::create_dir(input.path().join("etc")).unwrap();
+        fs::write(input.path().join("etc/hostname"), "container\n").unwrap();
+
+        for format in ["oci", "docker"] {
+            let filename = format!("{}.tar", format);
+
+            let mut stage = Stage::for_tree(
+                tree.path(),
+                serde_json::json!({
+                    "architecture": "amd64",
+                    "filename": filename,
+                    "config": {"Cmd": ["/bin/sh"], "ExposedPorts": ["80/tcp"]},
+                    "format": format,
+                }),
+            )
+            .with_input("base", input.path());
+
+            oci_archive(&mut stage).unwrap();
+
+            assert!(tree.path().join(&filename).is_file());
+        }
+    }
+
+    #[test]
+    fn options_refused() {
+        let tree = tempfile::tempdir().unwrap();
+        let input = tempfile::tempdir().unwrap();
+
+        for (options, expected) in [
+            (
+                serde_json::json!({"architecture": "amd64", "filename": "../image.tar"}),
+                "InvalidLocation",
+            ),
+            (
+                serde_json::json!({"architecture": "amd64", "filename": "image.tar", "config": {"Shell": []}}),
+                "InvalidOptions",
+            ),
+            (
+                serde_json::json!({"architecture": "amd64", "filename": "image.tar", "format": "zip"}),
+                "InvalidOptions",
+            ),
+        ] {
+            let err = oci_archive(
+                &mut Stage::for_tree(tree.path(), options).with_input("base", input.path()),
+            )
+            .unwrap_err();
+
+            assert!(format!("{:?}", err).starts_with(expected), "{:?}", err);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! `org.osbuild.xorrisofs`: build a bootable ISO of the tree of the `tree` input, written to
+//! `filename` in the tree. Options are those of the Python stage, boot images are paths in
+//! the input:
+//!
+//! ```json
+//! {"filename": "installer.iso", "volid": "Fedora-41-x86_64", "boot": {"image": "images/eltorito.img", "catalog": "boot.cat"}, "efi": "images/efiboot.img", "grub2mbr": "/usr/lib/grub/i386-pc/boot_hybrid.img"}
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use serde::Deserialize;
+
+use libosbuild::module::stage::{self, Location, Stage, StageError};
+use libosbuild::module::util::iso::{BiosBoot, Xorrisofs};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Boot {
+    image: PathBuf,
+    catalog: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    filename: String,
+    volid: String,
+    sysid: Option<String>,
+    boot: Option<Boot>,
+    efi: Option<PathBuf>,
+    isohybridmbr: Option<PathBuf>,
+    isolevel: Option<u8>,
+    grub2mbr: Option<PathBuf>,
+}
+
+fn xorrisofs(stage: &mut Stage) -> Result<(), StageError> {
+    let options: Options = stage.options()?;
+
+    // like the Python stage, the media are a file at the top of the tree
+    if options.filename.is_empty() || options.filename.contains('/') {
+        return Err(StageError::InvalidLocation(options.filename));
+    }
+
+    // volume ids are at most 32 characters in ISO 9660
+    if options.volid.is_empty() || options.volid.len() > 32 {
+        return Err(StageError::InvalidOptions(serde::de::Error::custom(
+            format!("invalid volid {:?}", options.volid),
+        )));
+    }
+
+    let tree = stage.resolve(&Location::Input("tree".to_string(), PathBuf::from("/")))?;
+    let path = stage
+        .tree()
+        .resolve(Path::new("/").join(&options.filename))?;
+
+    let xorrisofs = Xorrisofs {
+        sysid: options.sysid,
+        isolevel: options.isolevel,
+        bios: options.boot.map(|boot| BiosBoot {
+            image: boot.image,
+            catalog: boot.catalog,
+        }),
+        efi: options.efi,
+        isohybrid_mbr: options.isohybridmbr,
+        grub2_mbr: options.grub2mbr,
+        ..Xorrisofs::new(&options.volid)
+    };
+
+    xorrisofs.build(&tree, &path)?;
+
+    stage.log(&format!("built {} ({})", options.filename, options.volid));
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    stage::run(xorrisofs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn options_refused() {
+        let tree = tempfile::tempdir().unwrap();
+        let input = tempfile::tempdir().unwrap();
+
+        for (options, expected) in [
+            (
+                serde_json::json!({"filename": "../installer.iso", "volid": "Fedora"}),
+                "InvalidLocation",
+            ),
+            (
+                serde_json::json!({"filename": "installer.iso", "volid": "x".repeat(33)}),
+                "InvalidOptions",
+            ),
+            (
+                serde_json::json!({"filename": "installer.iso", "volid": "Fedora", "boot": {"image": "isolinux.bin"}}),
+                "InvalidOptions",
+            ),
+        ] {
+            let err = xorrisofs(
+                &mut Stage::for_tree(tree.path(), options).with_input("tree", input.path()),
+            )
+            .unwrap_err();
+
+            assert!(format!("{:?}", err).starts_with(expected), "{:?}", err);
+        }
+    }
+}
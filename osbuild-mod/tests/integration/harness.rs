@@ -0,0 +1,335 @@
+//! Builds manifests as osbuild does; modules from a registry, run by the executor, with every
+//! tree they build committed to a store, all in a temporary directory. The stages of this
+//! crate are registered under the names manifests use.
+//!
+//! Nothing needs privileges so the tests run on any CI runner: inputs are copied into place
+//! instead of mounted, stages run on the host instead of in the build root of their
+//! pipeline, and only `org.osbuild.inline` sources are fetched.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use libosbuild::core::executor::inputs::ResolvedReference;
+use libosbuild::core::executor::modules::ModuleServices;
+use libosbuild::core::executor::plan::Plan;
+use libosbuild::core::executor::{Executor, ExecutorError, Services, StageArguments};
+use libosbuild::core::export;
+use libosbuild::core::paths::Workspace;
+use libosbuild::core::store::ObjectStore;
+use libosbuild::manifest::{Manifest, Origin};
+use libosbuild::module::util::tree;
+use libosbuild::module::Registry;
+
+/// The stages of this crate by the names manifests use.
+pub const STAGES: &[(&str, &str)] = &[
+    ("org.osbuild.copy", env!("CARGO_BIN_EXE_stage-copy")),
+    ("org.osbuild.mkdir", env!("CARGO_BIN_EXE_stage-mkdir")),
+    ("org.osbuild.chmod", env!("CARGO_BIN_EXE_stage-chmod")),
+    ("org.osbuild.chown", env!("CARGO_BIN_EXE_stage-chown")),
+    (
+        "org.osbuild.oci-archive",
+        env!("CARGO_BIN_EXE_stage-oci-archive"),
+    ),
+    (
+        "org.osbuild.xorrisofs",
+        env!("CARGO_BIN_EXE_stage-xorrisofs"),
+    ),
+    (
+        "org.osbuild.implantisomd5",
+        env!("CARGO_BIN_EXE_stage-implantisomd5"),
+    ),
+];
+
+/// The only source the harness fetches, its items are in the manifest.
+pub const INLINE_SOURCE: &str = "org.osbuild.inline";
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, byte)| {
+            bits | (*byte as u32) << (16 - 8 * index)
+        });
+
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(BASE64[(bits >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn base64_decode(data: &str) -> Vec<u8> {
+    let mut decoded = vec![];
+    let mut bits = 0u32;
+    let mut count = 0;
+
+    for c in data
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b'=')
+    {
+        let value = BASE64
+            .iter()
+            .position(|other| *other == c)
+            .unwrap_or_else(|| panic!("'{}' is not base64", data));
+
+        bits = (bits << 6 | value as u32) & 0xffff;
+        count += 6;
+
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+
+    decoded
+}
+
+/// Services that run modules from the registry, except for inputs which are copied into
+/// their target; mounting them read-only takes privileges.
+pub struct CopiedInputs<'r> {
+    modules: ModuleServices<'r>,
+}
+
+impl<'r> CopiedInputs<'r> {
+    pub fn new(registry: &'r Registry) -> Self {
+        Self {
+            modules: ModuleServices::new(registry),
+        }
+    }
+}
+
+impl Services for CopiedInputs<'_> {
+    /// Trees of pipelines are copied into the target, source items are copied into it by
+    /// their checksum as `org.osbuild.files` does.
+    fn map_input(
+        &mut self,
+        _kind: &str,
+        origin: Origin,
+        references: &[ResolvedReference],
+        target: &Path,
+        _options: Option<&serde_json::Value>,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        let mut files = serde_json::Map::new();
+
+        for reference in references {
+            match origin {
+                Origin::Pipeline => {
+                    tree::copy_all(&reference.path, target)?;
+                }
+                Origin::Source => {
+                    tree::copy_all(&reference.path, &target.join(&reference.id))?;
+                    files.insert(reference.id.clone(), serde_json::json!({}));
+                }
+            }
+        }
+
+        Ok(match origin {
+            Origin::Pipeline => serde_json::json!({}),
+            Origin::Source => serde_json::json!({ "files": files }),
+        })
+    }
+
+    fn unmap_input(&mut self, _kind: &str, target: &Path) -> Result<(), ExecutorError> {
+        Ok(fs::remove_dir_all(target)?)
+    }
+
+    fn open_device(
+        &mut self,
+        kind: &str,
+        options: Option<&serde_json::Value>,
+        parent: Option<&Path>,
+    ) -> Result<PathBuf, ExecutorError> {
+        self.modules.open_device(kind, options, parent)
+    }
+
+    fn close_device(&mut self, kind: &str, path: &Path) -> Result<(), ExecutorError> {
+        self.modules.close_device(kind, path)
+    }
+
+    fn mount(
+        &mut self,
+        kind: &str,
+        source: Option<&Path>,
+        target: &Path,
+        options: Option<&serde_json::Value>,
+    ) -> Result<(), ExecutorError> {
+        self.modules.mount(kind, source, target, options)
+    }
+
+    fn umount(&mut self, kind: &str, target: &Path) -> Result<(), ExecutorError> {
+        self.modules.umount(kind, target)
+    }
+
+    fn run_stage(&mut self, kind: &str, arguments: &StageArguments) -> Result<(), ExecutorError> {
+        self.modules.run_stage(kind, arguments)
+    }
+}
+
+/// What a build built.
+#[derive(Debug)]
+pub struct Build {
+    pub plan: Plan,
+
+    /// The id of the tree of each pipeline, pipelines without stages have none.
+    pub pipelines: BTreeMap<String, String>,
+}
+
+/// A registry, store, and source cache in a temporary directory that manifests are built
+/// with. The store is kept between builds so later builds use what earlier ones built.
+pub struct Harness {
+    directory: tempfile::TempDir,
+    registry: Registry,
+    store: ObjectStore,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        let directory = tempfile::tempdir().unwrap();
+        let stages = directory.path().join("lib/stages");
+
+        fs::create_dir_all(&stages).unwrap();
+
+        for (name, path) in STAGES {
+            symlink(path, stages.join(name)).unwrap();
+        }
+
+        let mut registry = Registry::new_empty();
+
+        registry.add_libdir(&directory.path().join("lib")).unwrap();
+        registry.set_timeouts(Duration::from_secs(10), Some(Duration::from_secs(60)));
+
+        let store = ObjectStore::new(&directory.path().join("store"));
+
+        Self {
+            directory,
+            registry,
+            store,
+        }
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn store(&self) -> &ObjectStore {
+        &self.store
+    }
+
+    /// The tree of the object `id` in the store.
+    pub fn tree(&self, id: &str) -> PathBuf {
+        self.store.tree_path(id).unwrap()
+    }
+
+    /// The checksum and item of `data` as an `org.osbuild.inline` source.
+    pub fn inline(&self, data: &[u8]) -> (String, serde_json::Value) {
+        let file = tempfile::NamedTempFile::new_in(self.directory.path()).unwrap();
+
+        fs::write(file.path(), data).unwrap();
+
+        (
+            format!("sha256:{}", export::sha256(file.path()).unwrap()),
+            serde_json::json!({"encoding": "base64", "data": base64_encode(data)}),
+        )
+    }
+
+    /// Write the items of the inline sources of `manifest` to the source cache, checking
+    /// their checksums. Returns the cache.
+    fn fetch(&self, manifest: &Manifest) -> PathBuf {
+        let sources = self.directory.path().join("sources");
+
+        for (name, source) in manifest.sources() {
+            assert_eq!(name, INLINE_SOURCE, "only inline sources are fetched");
+
+            let directory = sources.join(name);
+            fs::create_dir_all(&directory).unwrap();
+
+            for (checksum, item) in source["items"].as_object().unwrap() {
+                assert_eq!(item["encoding"], "base64");
+
+                let path = directory.join(checksum);
+                fs::write(&path, base64_decode(item["data"].as_str().unwrap())).unwrap();
+
+                assert_eq!(
+                    format!("sha256:{}", export::sha256(&path).unwrap()),
+                    *checksum
+                );
+            }
+        }
+
+        sources
+    }
+
+    /// Build `manifest`. Each stage runs on a copy of the tree of the stage before it and
+    /// its tree is committed to the store, stages whose tree is in the store are skipped.
+    /// Failures to set up the build panic, failures of the build are returned.
+    pub fn build(&self, manifest: &serde_json::Value) -> Result<Build, ExecutorError> {
+        let manifest: Manifest = serde_json::from_value(manifest.clone()).unwrap();
+        let sources = self.fetch(&manifest);
+        let workspace = Workspace::new(self.directory.path()).unwrap();
+
+        let mut executor = Executor::new(CopiedInputs::new(&self.registry), workspace.runtime());
+
+        executor.content_mut().set_sources(&sources);
+
+        for id in self.store.ids().unwrap() {
+            executor.content_mut().add_tree(&id, &self.tree(&id));
+        }
+
+        let plan = executor.plan(&manifest)?;
+        let mut pipelines = BTreeMap::new();
+        let mut base: Option<&str> = None;
+
+        for planned in &plan.stages {
+            let pipeline = manifest.pipeline(&planned.pipeline).unwrap();
+
+            if planned.index == 0 {
+                base = None;
+            }
+
+            if !planned.cached {
+                let tree = workspace.tree(&planned.id).unwrap();
+
+                if let Some(base) = base {
+                    tree::copy_all(&self.tree(base), &tree).unwrap();
+                }
+
+                executor.run_stage(&pipeline.stages()[planned.index], &tree)?;
+
+                self.store.commit(&planned.id, &tree).unwrap();
+            }
+
+            base = Some(&planned.id);
+
+            if planned.index + 1 == pipeline.stages().len() {
+                executor.commit(&planned.pipeline, &planned.id, &self.tree(&planned.id));
+                pipelines.insert(planned.pipeline.clone(), planned.id.clone());
+            }
+        }
+
+        Ok(Build { plan, pipelines })
+    }
+}
+
+#[test]
+fn base64_round_trip() {
+    for (data, encoded) in [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foobar", "Zm9vYmFy"),
+    ] {
+        assert_eq!(base64_encode(data.as_bytes()), encoded);
+        assert_eq!(base64_decode(encoded), data.as_bytes());
+    }
+}
@@ -0,0 +1,5 @@
+//! Integration tests that build with the real registry, executor, and store.
+
+mod harness;
+mod manifests;
+mod stages;
@@ -0,0 +1,151 @@
+//! Small manifests built end to end, from their sources to the trees of their pipelines.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use libosbuild::core::executor::ExecutorError;
+
+use crate::harness::Harness;
+
+/// A manifest with an `os` pipeline that gets a hostname from an inline source, and an
+/// `image` pipeline with the `/etc` of `os`. `mode` is the mode of the hostname.
+fn manifest(harness: &Harness, mode: &str) -> serde_json::Value {
+    let (checksum, item) = harness.inline(b"builder\n");
+
+    serde_json::json!({
+        "version": "2",
+        "pipelines": [
+            {
+                "name": "os",
+                "stages": [
+                    {
+                        "type": "org.osbuild.mkdir",
+                        "options": {"paths": [{"path": "/etc/ssh", "parents": true, "mode": 0o700}]},
+                    },
+                    {
+                        "type": "org.osbuild.copy",
+                        "inputs": {
+                            "hostname": {
+                                "type": "org.osbuild.files",
+                                "origin": "org.osbuild.source",
+                                "references": {&checksum: {}},
+                            },
+                        },
+                        "options": {"paths": [
+                            {"from": format!("input://hostname/{}", checksum), "to": "tree:///etc/hostname"},
+                        ]},
+                    },
+                    {
+                        "type": "org.osbuild.chmod",
+                        "options": {"items": {"/etc/hostname": {"mode": mode}}},
+                    },
+                ],
+            },
+            {
+                "name": "image",
+                "stages": [
+                    {
+                        "type": "org.osbuild.copy",
+                        "inputs": {
+                            "tree": {
+                                "type": "org.osbuild.tree",
+                                "origin": "org.osbuild.pipeline",
+                                "references": ["name:os"],
+                            },
+                        },
+                        "options": {"paths": [{"from": "input://tree/etc", "to": "tree:///"}]},
+                    },
+                ],
+            },
+        ],
+        "sources": {
+            "org.osbuild.inline": {"items": {&checksum: item}},
+        },
+    })
+}
+
+fn mode(path: &std::path::Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+#[test]
+fn pipelines_built() {
+    let harness = Harness::new();
+    let build = harness.build(&manifest(&harness, "0640")).unwrap();
+
+    assert_eq!(build.plan.stages.len(), 4);
+    assert_eq!(harness.store().ids().unwrap().len(), 4);
+
+    let os = harness.tree(&build.pipelines["os"]);
+    let image = harness.tree(&build.pipelines["image"]);
+
+    for tree in [os.join("etc"), image.join("etc")] {
+        assert_eq!(
+            fs::read_to_string(tree.join("hostname")).unwrap(),
+            "builder\n"
+        );
+        assert_eq!(mode(&tree.join("hostname")), 0o640);
+        assert_eq!(mode(&tree.join("ssh")), 0o700);
+    }
+
+    // every stage builds on a copy of the tree before it, which stays as it was
+    let mkdir = harness.tree(&build.plan.stages[0].id);
+
+    assert!(mkdir.join("etc/ssh").is_dir());
+    assert!(!mkdir.join("etc/hostname").exists());
+}
+
+#[test]
+fn builds_cached() {
+    let harness = Harness::new();
+
+    let first = harness.build(&manifest(&harness, "0640")).unwrap();
+    let second = harness.build(&manifest(&harness, "0640")).unwrap();
+
+    assert_eq!(first.plan.pending().count(), 4);
+    assert_eq!(second.plan.pending().count(), 0);
+    assert_eq!(first.pipelines, second.pipelines);
+
+    // only the stage that changed and those after it run again
+    let changed = harness.build(&manifest(&harness, "0600")).unwrap();
+    let pending: Vec<&str> = changed
+        .plan
+        .pending()
+        .filter(|stage| stage.pipeline == "os")
+        .map(|stage| stage.kind.as_str())
+        .collect();
+
+    assert_eq!(pending, ["org.osbuild.chmod"]);
+    assert_eq!(
+        mode(&harness.tree(&changed.pipelines["os"]).join("etc/hostname")),
+        0o600
+    );
+}
+
+#[test]
+fn failed_stages_not_committed() {
+    let harness = Harness::new();
+    let mut manifest = manifest(&harness, "0640");
+
+    manifest["pipelines"][0]["stages"][2]["options"]["items"]["/etc/hostname"]["mode"] =
+        "u+q".into();
+
+    match harness.build(&manifest) {
+        Err(ExecutorError::ModuleFailed(name, Some(1), stderr)) => {
+            assert_eq!(name, "org.osbuild.chmod");
+            assert!(stderr.contains("invalid mode"), "{}", stderr);
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    // the stages before the one that failed are kept for the next build
+    assert_eq!(harness.store().ids().unwrap().len(), 2);
+
+    manifest["pipelines"][1]["stages"][0]["inputs"]["tree"]["references"] =
+        serde_json::json!(["name:missing"]);
+
+    assert!(matches!(
+        harness.build(&manifest),
+        Err(ExecutorError::MissingInput(name, reference)) if name == "tree" && reference == "name:missing"
+    ));
+}
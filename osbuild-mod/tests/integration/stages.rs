@@ -0,0 +1,117 @@
+//! The stages of this crate run as osbuild runs them; by the executor, with their arguments
+//! on stdin, and with only the variables of their environment.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use libosbuild::core::environment::Environment;
+use libosbuild::core::executor::modules::ModuleServices;
+use libosbuild::core::executor::{Executor, ExecutorError};
+use libosbuild::manifest::Stage;
+
+use libosbuild::core::paths::Workspace;
+
+use crate::harness::Harness;
+
+fn stage(kind: &str, options: serde_json::Value) -> Stage {
+    let mut stage = Stage::new(kind);
+
+    stage.set_options(Some(options));
+    stage
+}
+
+#[test]
+fn stages_run_by_executor() {
+    let harness = Harness::new();
+    let workspace = Workspace::in_default_root().unwrap();
+    let tree = tempfile::tempdir().unwrap();
+
+    let mut services = ModuleServices::new(harness.registry());
+
+    services.set_environment(Environment::from([(
+        "PATH".to_string(),
+        "/usr/bin".to_string(),
+    )]));
+
+    let mut executor = Executor::new(services, workspace.runtime());
+
+    fs::create_dir(tree.path().join("etc")).unwrap();
+    fs::write(tree.path().join("etc/hostname"), "builder\n").unwrap();
+
+    for stage in [
+        stage(
+            "org.osbuild.mkdir",
+            serde_json::json!({"paths": [
+                {"path": "/var/lib/app", "mode": 0o750, "parents": true},
+                {"path": "tree:///etc", "exist_ok": true},
+            ]}),
+        ),
+        stage(
+            "org.osbuild.copy",
+            serde_json::json!({"paths": [{"from": "tree:///etc/hostname", "to": "tree:///var/lib/app"}]}),
+        ),
+        stage(
+            "org.osbuild.chmod",
+            serde_json::json!({"items": {"/var/lib/app": {"mode": "go=", "recursive": true}}}),
+        ),
+        stage(
+            "org.osbuild.chown",
+            serde_json::json!({"items": {"/var/lib/app": {"user": 0, "recursive": true}}}),
+        ),
+    ] {
+        match executor.run_stage(&stage, tree.path()) {
+            Ok(()) => {}
+            // only root can give files away
+            Err(ExecutorError::ModuleFailed(name, _, _)) if name == "org.osbuild.chown" => {}
+            Err(err) => panic!("{} failed: {:?}", stage.kind(), err),
+        }
+    }
+
+    let mode = |path: &str| {
+        fs::metadata(tree.path().join(path))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777
+    };
+
+    assert_eq!(mode("var/lib/app"), 0o700);
+    assert_eq!(mode("var/lib/app/hostname"), 0o600);
+    assert_eq!(
+        fs::read_to_string(tree.path().join("var/lib/app/hostname")).unwrap(),
+        "builder\n"
+    );
+}
+
+#[test]
+fn stage_failures_reported() {
+    let harness = Harness::new();
+    let workspace = Workspace::in_default_root().unwrap();
+    let tree = tempfile::tempdir().unwrap();
+
+    let mut executor = Executor::new(ModuleServices::new(harness.registry()), workspace.runtime());
+
+    let err = executor
+        .run_stage(
+            &stage(
+                "org.osbuild.mkdir",
+                serde_json::json!({"paths": [{"path": "/../etc"}]}),
+            ),
+            tree.path(),
+        )
+        .unwrap_err();
+
+    match err {
+        ExecutorError::ModuleFailed(name, code, stderr) => {
+            assert_eq!(name, "org.osbuild.mkdir");
+            assert_eq!(code, Some(1));
+            assert!(stderr.contains("Escape"), "{}", stderr);
+        }
+        err => panic!("unexpected error: {:?}", err),
+    }
+
+    assert!(matches!(
+        executor.run_stage(&Stage::new("org.osbuild.rpm"), tree.path()),
+        Err(ExecutorError::MissingModule(_))
+    ));
+}
@@ -1,11 +1,175 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use libosbuild::cli;
+use libosbuild::dependency::solver::DnfJsonSolver;
+use libosbuild::preprocessor::{self, Preprocessor};
+
+fn make_cli() -> clap::Command<'static> {
+    clap::command!()
+        .about("Preprocess manifest templates, resolving their mpp directives.")
+        .subcommand_negates_reqs(true)
+        .arg(
+            clap::arg!(<input> "Manifest template to preprocess")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::arg!([output] "Where to write the manifest, standard output when not given")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::arg!(--"dry-run" "Print a trace of the directives that were evaluated instead of the manifest")
+                .required(false),
+        )
+        .arg(
+            clap::arg!(--json "Print the trace as JSON")
+                .required(false)
+                .requires("dry-run"),
+        )
+        .arg(
+            clap::arg!(--solver <path> "Path to the depsolver executable")
+                .required(false)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::arg!(--"dump-cli-json" "Print a machine readable description of the command line")
+                .hide(true)
+                .exclusive(true),
+        )
+        .subcommand(
+            clap::Command::new("completions")
+                .about("Print a shell completion script.")
+                .arg(clap::arg!(<shell> "Shell to complete for").value_parser(clap::value_parser!(clap_complete::Shell))),
+        )
+}
+
+fn completions(matches: &clap::ArgMatches) -> Result<(), String> {
+    let shell = *matches.get_one::<clap_complete::Shell>("shell").unwrap();
+
+    cli::completions(
+        shell,
+        &mut make_cli(),
+        "osbuild-mpp",
+        &[],
+        &mut io::stdout(),
+    )
+    .map_err(|err| err.to_string())
+}
+
+fn preprocess(matches: &clap::ArgMatches) -> Result<(), String> {
+    let solver = match matches.get_one::<PathBuf>("solver") {
+        Some(path) => DnfJsonSolver::new(path.clone()),
+        None => DnfJsonSolver::new_default(),
+    };
+
+    let mut preprocessor = Preprocessor::new().with_solver(Box::new(solver));
+
+    let input = matches.get_one::<PathBuf>("input").unwrap();
+    let manifest = preprocessor
+        .load(input)
+        .map_err(|err| format!("could not preprocess '{}': {:?}", input.display(), err))?;
+
+    if matches.contains_id("dry-run") {
+        if matches.contains_id("json") {
+            let trace = serde_json::to_string_pretty(preprocessor.trace())
+                .map_err(|err| err.to_string())?;
+
+            println!("{}", trace);
+        } else {
+            print!("{}", preprocessor.trace());
+        }
+
+        return Ok(());
+    }
+
+    let data = preprocessor::to_string(&manifest).map_err(|err| err.to_string())?;
+
+    match matches.get_one::<PathBuf>("output") {
+        Some(path) => write(path, &data),
+        None => {
+            print!("{}", data);
+            Ok(())
+        }
+    }
+}
+
+fn write(path: &Path, data: &str) -> Result<(), String> {
+    fs::write(path, data).map_err(|err| format!("could not write '{}': {}", path.display(), err))
+}
+
 fn main() {
-    println!("Hello, world!");
+    let matches = make_cli().get_matches();
+
+    if matches.contains_id("dump-cli-json") {
+        println!("{}", cli::describe(&make_cli(), &[]));
+        return;
+    }
+
+    let result = match matches.subcommand() {
+        Some(("completions", matches)) => completions(matches),
+        _ => preprocess(&matches),
+    };
+
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
     #[test]
-    fn dummy() {
-        assert!(true);
+    fn cli_parsed() {
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild-mpp", "--dry-run", "template.json"])
+            .unwrap();
+
+        assert!(matches.contains_id("dry-run"));
+        assert!(!matches.contains_id("output"));
+
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild-mpp", "--json", "template.json"])
+            .is_err());
+    }
+
+    #[test]
+    fn cli_introspection() {
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild-mpp", "--dump-cli-json"])
+            .is_ok());
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild-mpp", "--dump-cli-json", "template.json"])
+            .is_err());
+
+        let description = cli::describe(&make_cli(), &[]);
+        let args = description["args"].as_array().unwrap();
+
+        assert!(args.iter().any(|arg| arg["name"] == "dry-run"));
+        assert!(args.iter().all(|arg| arg["name"] != "dump-cli-json"));
+    }
+
+    #[test]
+    fn cli_completions() {
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild-mpp", "completions", "bash"])
+            .unwrap();
+
+        assert!(matches.subcommand_matches("completions").is_some());
+
+        let mut script = vec![];
+        cli::completions(
+            clap_complete::Shell::Bash,
+            &mut make_cli(),
+            "osbuild-mpp",
+            &[],
+            &mut script,
+        )
+        .unwrap();
+
+        assert!(String::from_utf8(script).unwrap().contains("--dry-run"));
     }
 }
@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use libosbuild::manifest::description::v2::Validator;
+use libosbuild::manifest::description::validation::Severity;
+use libosbuild::manifest::description::ManifestDescriptionError;
+use libosbuild::manifest::path::Part;
+use libosbuild::manifest::source::{Source, Span};
+use libosbuild::manifest::ManifestError;
+use libosbuild::module::{Kind, Registry};
+
+/// JSON-RPC error code for requests of methods the server doesn't implement.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// JSON-RPC error code for requests with missing or invalid parameters.
+const INVALID_PARAMS: i64 = -32602;
+
+/// The `CompletionItemKind` of modules.
+const COMPLETION_MODULE: u64 = 9;
+
+/// A minimal language server for manifests. It publishes the errors the validator finds in
+/// open manifests, shows the documentation of the module a `type` names on hover, and
+/// completes the names of modules.
+pub struct Server {
+    validator: Validator,
+
+    /// The schemas of modules, their documentation is taken from them.
+    modules: Vec<(Kind, String, Value)>,
+
+    /// The text of open documents by their uri.
+    documents: HashMap<String, String>,
+}
+
+impl Server {
+    pub fn new(validator: Validator) -> Self {
+        Self {
+            validator,
+            modules: vec![],
+            documents: HashMap::new(),
+        }
+    }
+
+    /// A server validating against the modules in `registry` and the manifest format
+    /// schemas, modules whose schema can't be read are left out.
+    pub fn from_registry(registry: &Registry) -> Result<Self, ManifestDescriptionError> {
+        let mut validator = Validator::from_registry(registry)?;
+        validator.add_format_schemas()?;
+
+        let mut server = Self::new(validator);
+
+        for module in registry.modules() {
+            if let Ok(schema) = module.get_schema_json() {
+                server.add_module(module.kind(), module.name(), schema);
+            }
+        }
+
+        Ok(server)
+    }
+
+    /// Add a module to document and complete.
+    pub fn add_module(&mut self, kind: Kind, name: &str, schema: Value) {
+        self.modules.push((kind, name.to_string(), schema));
+    }
+
+    /// Handle a request or notification, returning the messages to send back.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let id = message.get("id").cloned();
+        let params = message.get("params").unwrap_or(&Value::Null);
+        let method = message
+            .get("method")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+
+        let uri = params
+            .pointer("/textDocument/uri")
+            .and_then(Value::as_str)
+            .map(String::from);
+
+        let position = params.get("position").and_then(|position| {
+            Some((
+                position.get("line")?.as_u64()? as usize,
+                position.get("character")?.as_u64()? as usize,
+            ))
+        });
+
+        match (method, uri, position) {
+            ("initialize", _, _) => vec![response(
+                id,
+                json!({
+                    "capabilities": {
+                        // documents are sent in full on every change
+                        "textDocumentSync": 1,
+                        "hoverProvider": true,
+                        "completionProvider": {"triggerCharacters": ["\""]},
+                    },
+                    "serverInfo": {"name": "osbuild", "version": env!("CARGO_PKG_VERSION")},
+                }),
+            )],
+            ("shutdown", _, _) => vec![response(id, Value::Null)],
+            ("textDocument/didOpen", Some(uri), _) => {
+                let text = params
+                    .pointer("/textDocument/text")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+
+                self.documents.insert(uri.clone(), text.to_string());
+
+                vec![self.diagnostics(&uri)]
+            }
+            ("textDocument/didChange", Some(uri), _) => {
+                let text = params
+                    .get("contentChanges")
+                    .and_then(Value::as_array)
+                    .and_then(|changes| changes.last()?.get("text")?.as_str());
+
+                if let Some(text) = text {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+
+                vec![self.diagnostics(&uri)]
+            }
+            ("textDocument/didClose", Some(uri), _) => {
+                self.documents.remove(&uri);
+
+                vec![notification(
+                    "textDocument/publishDiagnostics",
+                    json!({"uri": uri, "diagnostics": []}),
+                )]
+            }
+            ("textDocument/hover", Some(uri), Some((line, character))) => {
+                vec![response(id, self.hover(&uri, line, character))]
+            }
+            ("textDocument/completion", Some(uri), Some((line, character))) => {
+                vec![response(id, self.completion(&uri, line, character))]
+            }
+            ("textDocument/hover" | "textDocument/completion", _, _) => {
+                vec![error_response(
+                    id,
+                    INVALID_PARAMS,
+                    "missing document or position",
+                )]
+            }
+            // notifications that need no answer, such as `initialized` and `exit`
+            _ if id.is_none() => vec![],
+            _ => vec![error_response(
+                id,
+                METHOD_NOT_FOUND,
+                &format!("unknown method '{}'", method),
+            )],
+        }
+    }
+
+    fn diagnostics(&self, uri: &str) -> Value {
+        let text = self
+            .documents
+            .get(uri)
+            .map(String::as_str)
+            .unwrap_or_default();
+
+        let diagnostics: Vec<Value> = match Source::parse(uri, text) {
+            Ok(source) => {
+                // the source parsed, so does the value
+                let manifest: Value = serde_json::from_str(text).unwrap_or_default();
+
+                self.validator
+                    .validate(&manifest)
+                    .diagnostics()
+                    .map(|error| {
+                        json!({
+                            "range": range(text, source.closest_span(&error.path).1),
+                            "severity": severity(error.severity),
+                            "source": "osbuild",
+                            "code": error.rule.id(),
+                            "message": error.message,
+                        })
+                    })
+                    .collect()
+            }
+            Err(err) => {
+                let (message, line) = match &err {
+                    ManifestError::ParseError(err) => (err.to_string(), err.line()),
+                    err => (format!("{:?}", err), 1),
+                };
+
+                let position = json!({"line": line.saturating_sub(1), "character": 0});
+
+                vec![json!({
+                    "range": {"start": position, "end": position},
+                    "severity": 1,
+                    "source": "osbuild",
+                    "message": message,
+                })]
+            }
+        };
+
+        notification(
+            "textDocument/publishDiagnostics",
+            json!({"uri": uri, "diagnostics": diagnostics}),
+        )
+    }
+
+    /// The documentation of the module named by the `type` at a position.
+    fn hover(&self, uri: &str, line: usize, character: usize) -> Value {
+        let Some(text) = self.documents.get(uri) else {
+            return Value::Null;
+        };
+
+        let Ok(source) = Source::parse(uri, text) else {
+            return Value::Null;
+        };
+
+        let Some(path) = source.path_at(offset(text, line, character)) else {
+            return Value::Null;
+        };
+
+        let manifest: Value = serde_json::from_str(text).unwrap_or_default();
+
+        let module = kind_at(&path).and_then(|kind| {
+            let name = path
+                .iter()
+                .try_fold(&manifest, |value, part| match part {
+                    Part::Name(name) => value.get(name),
+                    Part::Index(index) => value.get(index),
+                })?
+                .as_str()?;
+
+            self.modules
+                .iter()
+                .find(|(other, other_name, _)| *other == kind && other_name == name)
+        });
+
+        match (module, source.span(&path)) {
+            (Some(module), Some(span)) => json!({
+                "contents": {"kind": "markdown", "value": documentation(module)},
+                "range": range(text, span),
+            }),
+            _ => Value::Null,
+        }
+    }
+
+    /// The modules a `type` at a position can name, stages when it isn't known.
+    fn completion(&self, uri: &str, line: usize, character: usize) -> Value {
+        let kind = self
+            .documents
+            .get(uri)
+            .and_then(|text| {
+                Source::parse(uri, text)
+                    .ok()?
+                    .path_at(offset(text, line, character))
+            })
+            .and_then(|path| kind_at(&path))
+            .unwrap_or(Kind::Stage);
+
+        let items: Vec<Value> = self
+            .modules
+            .iter()
+            .filter(|(other, _, _)| *other == kind)
+            .map(|module| {
+                json!({
+                    "label": module.1,
+                    "kind": COMPLETION_MODULE,
+                    "detail": kind.name(),
+                    "documentation": {"kind": "markdown", "value": documentation(module)},
+                })
+            })
+            .collect();
+
+        json!({"isIncomplete": false, "items": items})
+    }
+}
+
+/// The kind of module the value at `path` names, for the `type` of stages, inputs, devices,
+/// and mounts.
+fn kind_at(path: &[Part]) -> Option<Kind> {
+    match path {
+        [.., Part::Name(key), _, Part::Name(field)] if field == "type" => match key.as_str() {
+            "stages" => Some(Kind::Stage),
+            "inputs" => Some(Kind::Input),
+            "devices" => Some(Kind::Device),
+            "mounts" => Some(Kind::Mount),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Markdown describing a module and the options its schema has.
+fn documentation((kind, name, schema): &(Kind, String, Value)) -> String {
+    let mut text = format!("**{}** ({})", name, kind);
+
+    for key in ["title", "description"] {
+        if let Some(value) = schema.get(key).and_then(Value::as_str) {
+            text.push_str(&format!("\n\n{}", value));
+        }
+    }
+
+    if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+        text.push_str("\n\nOptions:\n");
+
+        for (option, property) in properties {
+            text.push_str(&format!("\n- `{}`", option));
+
+            if let Some(kind) = property.get("type").and_then(Value::as_str) {
+                text.push_str(&format!(" ({})", kind));
+            }
+
+            if let Some(description) = property.get("description").and_then(Value::as_str) {
+                text.push_str(&format!(": {}", description));
+            }
+        }
+    }
+
+    text
+}
+
+/// The position of the byte `offset` in `text`, lines count from 0 and characters in
+/// UTF-16 code units as language servers do.
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+fn range(text: &str, span: Span) -> Value {
+    json!({"start": position(text, span.start), "end": position(text, span.end)})
+}
+
+/// The `DiagnosticSeverity` of the protocol for `severity`.
+fn severity(severity: Severity) -> u8 {
+    match severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+        Severity::Info => 3,
+    }
+}
+
+/// The byte offset of a position in `text`, see `position`.
+fn offset(text: &str, line: usize, character: usize) -> usize {
+    let line_start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+    let mut units = 0;
+
+    for (index, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + index;
+        }
+
+        units += c.len_utf16();
+    }
+
+    text.len()
+}
+
+fn response(id: Option<Value>, result: Value) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "result": result})
+}
+
+fn error_response(id: Option<Value>, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({"jsonrpc": "2.0", "method": method, "params": params})
+}
+
+/// Read a message framed by a `Content-Length` header, `None` when the client went away.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+
+    loop {
+        let mut header = String::new();
+
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim_end();
+
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message without a Content-Length",
+        )
+    })?;
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// Answer messages from `reader` on `writer` until the client exits or goes away.
+pub fn serve<R: BufRead, W: Write>(
+    server: &mut Server,
+    reader: &mut R,
+    writer: &mut W,
+) -> io::Result<()> {
+    while let Some(message) = read_message(reader)? {
+        if message.get("method").and_then(Value::as_str) == Some("exit") {
+            break;
+        }
+
+        for reply in server.handle(&message) {
+            write_message(writer, &reply)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MANIFEST: &str = "{\n  \"version\": \"2\",\n  \"pipelines\": [{\"name\": \"os\", \"stages\": [{\"type\": \"org.osbuild.rpm\", \"options\": {\"packages\": 1}}]}]\n}\n";
+
+    fn server() -> Server {
+        let schema = json!({
+            "description": "Install packages.",
+            "properties": {"packages": {"type": "array", "description": "Packages to install."}}
+        });
+
+        let mut validator = Validator::against_format_schema().unwrap();
+        validator.add_schema("org.osbuild.rpm", &schema).unwrap();
+
+        let mut server = Server::new(validator);
+        server.add_module(Kind::Stage, "org.osbuild.rpm", schema);
+        server.add_module(Kind::Mount, "org.osbuild.ext4", json!({}));
+
+        server
+    }
+
+    fn request(method: &str, params: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params})
+    }
+
+    #[test]
+    fn framed_messages() {
+        let mut buffer = vec![];
+
+        write_message(&mut buffer, &json!({"jsonrpc": "2.0", "method": "exit"})).unwrap();
+        assert!(buffer.starts_with(b"Content-Length: 33\r\n\r\n{"));
+
+        let mut reader = io::Cursor::new(buffer);
+
+        assert_eq!(
+            read_message(&mut reader).unwrap().unwrap()["method"],
+            "exit"
+        );
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn diagnostics_published() {
+        let mut server = server();
+        let replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": "file:///manifest.json", "text": MANIFEST}}
+        }));
+
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+
+        assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+        assert_eq!(diagnostics[0]["code"], "options");
+        assert_eq!(
+            diagnostics[0]["range"],
+            json!({"start": {"line": 2, "character": 94}, "end": {"line": 2, "character": 95}})
+        );
+
+        let replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {"textDocument": {"uri": "file:///manifest.json"}, "contentChanges": [{"text": "{\n"}]}
+        }));
+
+        assert_eq!(
+            replies[0]["params"]["diagnostics"][0]["range"]["start"]["line"],
+            1
+        );
+    }
+
+    #[test]
+    fn hover_and_completion() {
+        let mut server = server();
+
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": "file:///manifest.json", "text": MANIFEST}}
+        }));
+
+        let document = json!({"uri": "file:///manifest.json"});
+        let hover = &server.handle(&request(
+            "textDocument/hover",
+            json!({"textDocument": document, "position": {"line": 2, "character": 52}}),
+        ))[0]["result"];
+
+        assert_eq!(
+            hover["contents"]["value"],
+            "**org.osbuild.rpm** (stage)\n\nInstall packages.\n\nOptions:\n\n- `packages` (array): Packages to install."
+        );
+
+        let nothing = &server.handle(&request(
+            "textDocument/hover",
+            json!({"textDocument": document, "position": {"line": 1, "character": 14}}),
+        ))[0];
+
+        assert_eq!(nothing["result"], Value::Null);
+
+        let completion = &server.handle(&request(
+            "textDocument/completion",
+            json!({"textDocument": document, "position": {"line": 0, "character": 0}}),
+        ))[0]["result"];
+
+        assert_eq!(completion["items"].as_array().unwrap().len(), 1);
+        assert_eq!(completion["items"][0]["label"], "org.osbuild.rpm");
+
+        assert_eq!(
+            server.handle(&request("textDocument/hover", json!({})))[0]["error"]["code"],
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            server.handle(&request("workspace/symbol", json!({})))[0]["error"]["code"],
+            METHOD_NOT_FOUND
+        );
+    }
+}
@@ -1,9 +1,63 @@
-use libosbuild::module::Registry;
+mod lsp;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+use libosbuild::cli;
+use libosbuild::core::accounting::UsageTable;
+use libosbuild::core::config::{self, BuildConfig, Config};
+use libosbuild::core::executor::build::{build_with_config, BuildError};
+use libosbuild::core::executor::inputs::Content;
+use libosbuild::core::executor::{self, ExecutorError};
+use libosbuild::core::journal;
+use libosbuild::core::runner::{self, RunnerError};
+use libosbuild::core::secrets::Secrets;
+use libosbuild::core::sources::manager::{SourceError, SourceManager};
+use libosbuild::core::sources::offline::MissingReport;
+use libosbuild::core::store::{ObjectStore, StoreError};
+use libosbuild::core::timing::TimeReport;
+use libosbuild::core::worker::{systemd, Worker};
+use libosbuild::core::{monitor, BuildResult, Failure, FailureKind};
+use libosbuild::dependency::diagnosis::Explanation;
+use libosbuild::dependency::solver::{
+    DnfJsonSolver, ModuleSpec, Policy, Request, Solver, SolverError,
+};
+use libosbuild::dependency::{advisory, repository};
+use libosbuild::manifest;
+use libosbuild::manifest::description::schema::Schemas;
+use libosbuild::manifest::description::v2::Validator;
+use libosbuild::manifest::description::validation::sarif::Sarif;
+use libosbuild::manifest::description::validation::Severity;
+use libosbuild::manifest::include::{self, IncludeError};
+use libosbuild::manifest::source::Source;
+use libosbuild::manifest::variables;
+use libosbuild::module::cancel::CancellationToken;
+use libosbuild::module::{Kind, Registry};
+use libosbuild::sandbox::unprivileged::{self, UnprivilegedError};
+
+/// Arguments whose values can't be known when the command line is defined, with where to
+/// get them from. Completion scripts and wrappers ask `osbuild complete <source>` for them.
+const DYNAMIC_VALUES: cli::DynamicValues = &[
+    ("export", "pipelines"),
+    ("checkpoint", "pipelines"),
+    ("name", "modules"),
+];
 
 fn make_cli() -> clap::Command<'static> {
     clap::command!()
         .propagate_version(true)
         .about("Build operating system images.")
+        .after_help(
+            "Exit codes: 0 success, 1 internal error, 2 usage error, 3 validation failed, \
+             4 missing module, 5 stage failed, 130 cancelled.",
+        )
+        .subcommand_negates_reqs(true)
         .arg(
             clap::arg!(-q --quiet "Quiet operation (less output)")
                 .required(false)
@@ -14,23 +68,2234 @@ fn make_cli() -> clap::Command<'static> {
                 .required(false)
                 .conflicts_with("quiet"),
         )
-        .arg(clap::arg!(-m --module <module> "Path to module(s)").required(false))
+        .arg(
+            clap::arg!(-m --module <module> "Path to module(s)")
+                .required(false)
+                .multiple_occurrences(true)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::arg!(--store <dir> "Directory of the object store")
+                .required(false)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::arg!(--workspace <dir> "Directory to create the workspace of the build in")
+                .required(false)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::arg!(--monitor <name> "Monitor to report progress with [default: log]")
+                .required(false)
+                .value_parser(clap::builder::PossibleValuesParser::new(monitor::available())),
+        )
+        .arg(
+            clap::arg!(--"monitor-fd" <fd> "File descriptor to write monitor output to")
+                .required(false)
+                .value_parser(clap::value_parser!(i32).range(0..)),
+        )
+        .arg(
+            clap::arg!(--export <pipeline> "Pipeline(s) to export")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            clap::arg!(--"output-directory" <dir> "Directory to export pipelines into, each in a directory of its name")
+                .required(false)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::arg!(--checkpoint <pipeline> "Pipeline(s) to keep in the store")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            clap::arg!(--"source-date-epoch" <seconds> "SOURCE_DATE_EPOCH for pipelines that don't set one")
+                .required(false)
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            clap::arg!(--unprivileged "Build without root privileges, in a user namespace")
+                .required(false),
+        )
+        .arg(
+            clap::arg!(--"time-report" "Print how long each phase of the run took to stderr")
+                .required(false),
+        )
+        .arg(
+            clap::arg!(--result <file> "File to write the result of the build to as JSON, for 'osbuild report'")
+                .required(false)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::arg!(--"deny-warnings" "Fail the build when it has warnings").required(false),
+        )
+        .arg(
+            clap::arg!(--offline "Build without a network, from the items in the cache of sources")
+                .required(false),
+        )
+        .arg(
+            clap::arg!(--hermetic "Fail the build when a stage uses the network or reads paths it doesn't declare")
+                .required(false),
+        )
+        .arg(
+            clap::arg!(--"track-changes" "Record the files each stage creates, modifies, and deletes")
+                .required(false),
+        )
+        .arg(
+            clap::arg!(--"fetch-only" "Fetch the sources into the cache of sources without building")
+                .required(false)
+                .conflicts_with("offline"),
+        )
+        .arg(
+            clap::arg!(--"dump-cli-json" "Print a machine readable description of the command line")
+                .hide(true)
+                .exclusive(true),
+        )
         .arg(clap::arg!(<manifest> "Path to manifest to build"))
+        .subcommand(
+            clap::Command::new("depsolve")
+                .about("Resolve package specs against repositories and print the result as JSON.")
+                .arg(
+                    clap::arg!(--repo <file> "Repository file(s) to resolve against")
+                        .multiple_occurrences(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    clap::arg!(--spec <spec> "Package spec(s) to resolve, '@' for groups")
+                        .required(false)
+                        .required_unless_present("group")
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    clap::arg!(--group <id> "Comps group(s) to resolve, e.g. 'core'")
+                        .required(false)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    clap::arg!(--"enable-module" <spec> "Module stream(s) to enable, e.g. 'nodejs:20'")
+                        .required(false)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    clap::arg!(--"disable-module" <name> "Module(s) to disable")
+                        .required(false)
+                        .multiple_occurrences(true),
+                )
+                .arg(clap::arg!(--"no-weak-deps" "Leave out weak dependencies"))
+                .arg(
+                    clap::arg!(--"exclude-weak" <spec> "Package spec(s) to never install as weak dependencies")
+                        .required(false)
+                        .multiple_occurrences(true),
+                )
+                .arg(clap::arg!(--best "Require the newest candidates of packages"))
+                .arg(
+                    clap::arg!(--exclude <spec> "Package spec(s) to exclude")
+                        .required(false)
+                        .multiple_occurrences(true),
+                )
+                .arg(clap::arg!(--arch <arch> "Architecture to resolve for"))
+                .arg(
+                    clap::arg!(--releasever <version> "Release version to substitute")
+                        .required(false),
+                )
+                .arg(
+                    clap::arg!(--"platform-id" <id> "Module platform id, e.g. 'platform:f37'")
+                        .required(false),
+                )
+                .arg(
+                    clap::arg!(--cachedir <dir> "Directory for solver caches")
+                        .required(false)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    clap::arg!(--solver <path> "Path to the depsolver executable")
+                        .required(false)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(clap::arg!(--advisories "Print which resolved packages fix, or are affected by, advisories instead")),
+        )
+        .subcommand(
+            clap::Command::new("schema")
+                .about("Inspect the schemas of installed modules.")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("dump")
+                        .about("Write the schema of every module to a directory.")
+                        .arg(
+                            clap::arg!(--out <dir> "Directory to write schemas to")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            clap::arg!(--libdir <dir> "Directory to load modules from instead of the well-known locations")
+                                .required(false)
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("show")
+                        .about("Print the schema of a module.")
+                        .arg(clap::arg!(<name> "Name of the module"))
+                        .arg(
+                            clap::arg!(--kind <kind> "Kind of the module, for names that are used by several kinds")
+                                .required(false)
+                                .value_parser(Kind::ALL.map(|kind| kind.name())),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("store")
+                .about("Inspect and import the objects in the store.")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("diff")
+                        .about("List the files added, removed, and changed from one object to another.")
+                        .arg(clap::arg!(<from> "Id of the object to compare from"))
+                        .arg(clap::arg!(<to> "Id of the object to compare to"))
+                        .arg(clap::arg!(--json "Print the difference as JSON")),
+                )
+                .subcommand(
+                    clap::Command::new("import")
+                        .about("Copy a tree built elsewhere, such as by osbuild, into the store.")
+                        .arg(
+                            clap::arg!(<tree> "Tree to import")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(clap::arg!(<id> "Id of the object the tree is the result of")),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("sources")
+                .about("Carry the cache of sources of the store to other machines.")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("export")
+                        .about("Copy the sources a manifest needs from the cache to a directory.")
+                        .arg(clap::arg!(<manifest> "Manifest whose sources to export"))
+                        .arg(
+                            clap::arg!(<directory> "Directory to export to")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("import")
+                        .about("Copy the sources of an exported directory into the cache.")
+                        .arg(
+                            clap::arg!(<directory> "Directory to import from")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("plan")
+                .about("Print the stages a manifest runs, in the order they run.")
+                .arg(clap::arg!(<manifest> "Manifest to plan"))
+                .arg(clap::arg!(--json "Print the plan as JSON")),
+        )
+        .subcommand(
+            clap::Command::new("validate")
+                .about("Check the structure of a manifest, without needing any modules.")
+                .arg(clap::arg!(<manifest> "Manifest to validate"))
+                .arg(clap::arg!(--sarif "Print the errors as a SARIF log, for code scanning"))
+                .arg(clap::arg!(--"deny-warnings" "Treat warnings as errors"))
+                .arg(
+                    clap::arg!(--"schema-dir" <dir> "Directory of the manifest schemas to validate against, instead of the bundled ones")
+                        .required(false)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("report")
+                .about("Print what each pipeline of a build cost, from the result of the build.")
+                .arg(clap::arg!(<result> "Result of the build, as JSON")),
+        )
+        .subcommand(
+            clap::Command::new("lsp")
+                .about("Run a language server for manifests on stdin and stdout."),
+        )
+        .subcommand(
+            clap::Command::new("worker")
+                .about("Build the manifests submitted over a socket with JSON-RPC.")
+                .arg(
+                    clap::arg!([socket] "Datagram socket to listen on, unless socket activated")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    clap::arg!(--dbus <bus> "Serve on D-Bus instead of a socket")
+                        .value_parser(["session", "system"])
+                        .required(false)
+                        .conflicts_with("socket"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("completions")
+                .about("Print a shell completion script.")
+                .arg(clap::arg!(<shell> "Shell to complete for").value_parser(clap::value_parser!(clap_complete::Shell))),
+        )
+        .subcommand(
+            clap::Command::new("complete")
+                .about("Print the values of arguments that depend on the modules or manifest.")
+                .hide(true)
+                .arg(
+                    clap::arg!(<source> "What to list").value_parser(["modules", "pipelines"]),
+                )
+                .arg(clap::arg!([manifest] "Manifest to list pipelines of")),
+        )
 }
 
-fn main() {
-    let _matches = make_cli().get_matches();
+/// Override the settings in `config` with the flags given on the command line.
+fn apply_flags(config: &mut Config, matches: &clap::ArgMatches) {
+    config.merge(Config {
+        store: matches.get_one::<PathBuf>("store").cloned(),
+        monitor: matches.get_one::<String>("monitor").cloned(),
+        module_paths: matches
+            .get_many::<PathBuf>("module")
+            .map(|paths| paths.cloned().collect()),
+        proxy: None,
+        source_date_epoch: matches.get_one::<u64>("source-date-epoch").copied(),
+        workspace: matches.get_one::<PathBuf>("workspace").cloned(),
+        offline: matches.contains_id("offline").then_some(true),
+        hermetic: matches.contains_id("hermetic").then_some(true),
+        track_changes: matches.contains_id("track-changes").then_some(true),
+        worker: None,
+        modules: None,
+        environment: None,
+        capabilities: None,
+        isolate: None,
+        limits: None,
+        hash_algo: None,
+        sandbox: None,
+        snapshots: None,
+    });
+}
 
+/// The configuration files with the flags given on the command line layered on top.
+fn load_config(matches: &clap::ArgMatches) -> Result<Config, Failure> {
+    let mut config = config::load_layered()
+        .map_err(|err| Failure::internal(format!("Unable to load configuration: {:?}", err)))?;
+
+    apply_flags(&mut config, matches);
+
+    Ok(config)
+}
+
+fn depsolve(matches: &clap::ArgMatches, config: &Config) -> Result<(), Failure> {
+    let arch = matches.get_one::<String>("arch").unwrap().to_string();
+    let releasever = matches.get_one::<String>("releasever").cloned();
+
+    let variables = repository::Variables {
+        arch: arch.clone(),
+        releasever: releasever.clone(),
+    };
+
+    let mut repos = vec![];
+
+    for path in matches.get_many::<PathBuf>("repo").unwrap() {
+        repos.extend(repository::load(path, &variables).map_err(|err| {
+            Failure::internal(format!("could not load '{}': {:?}", path.display(), err))
+        })?);
+    }
+
+    let strings = |id: &str| -> Vec<String> {
+        matches
+            .get_many::<String>(id)
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default()
+    };
+
+    let enable_modules = strings("enable-module")
+        .iter()
+        .map(|spec| spec.parse())
+        .collect::<Result<Vec<ModuleSpec>, _>>()
+        .map_err(|err| Failure::new(FailureKind::Validation, format!("{:?}", err)))?;
+
+    let request = Request {
+        arch,
+        releasever,
+        module_platform_id: matches.get_one::<String>("platform-id").cloned(),
+        cachedir: matches.get_one::<PathBuf>("cachedir").cloned(),
+        proxy: config.proxy.clone(),
+        repos,
+        specs: strings("spec"),
+        exclude: strings("exclude"),
+        groups: strings("group"),
+        enable_modules,
+        disable_modules: strings("disable-module"),
+        policy: Policy {
+            install_weak_deps: !matches.contains_id("no-weak-deps"),
+            exclude_from_weak: strings("exclude-weak"),
+            best: matches.contains_id("best"),
+        },
+    };
+
+    let solver = match matches.get_one::<PathBuf>("solver") {
+        Some(path) => DnfJsonSolver::new(path.clone()),
+        None => DnfJsonSolver::new_default(),
+    };
+
+    let solution = solver.depsolve(&request).map_err(|err| match err {
+        SolverError::Unsolvable {
+            reason, diagnosis, ..
+        } if diagnosis.is_empty() => Failure::internal(format!("could not depsolve: {}", reason)),
+        SolverError::Unsolvable { diagnosis, .. } => Failure::internal(format!(
+            "could not depsolve:\n{}",
+            Explanation::new(&diagnosis, &request.repos)
+        )),
+        err => Failure::internal(format!("could not depsolve: {:?}", err)),
+    })?;
+
+    let output = if matches.contains_id("advisories") {
+        serde_json::to_string_pretty(&advisory::Report::new(&solution))
+    } else {
+        serde_json::to_string_pretty(&solution)
+    };
+
+    println!(
+        "{}",
+        output.map_err(|err| Failure::internal(err.to_string()))?
+    );
+
+    Ok(())
+}
+
+/// Clean up the store after a build that was interrupted, before building in it again.
+fn recover_store(store: Option<&Path>) -> Result<(), Failure> {
+    let Some(store) = store else {
+        return Ok(());
+    };
+
+    let recovery = journal::recover(store).map_err(|err| {
+        Failure::internal(format!(
+            "Unable to recover store {}: {:?}",
+            store.display(),
+            err
+        ))
+    })?;
+
+    if !recovery.is_clean() {
+        eprintln!(
+            "removed {} object(s) an interrupted build left in {}",
+            recovery.staging.len(),
+            store.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// A registry with the modules from the configured module paths, or the well-known ones
+/// when none are configured.
+fn load_registry(module_paths: Option<&[PathBuf]>) -> Result<Registry, Failure> {
     let mut registry = Registry::new_empty();
-    registry.add_well_known();
 
-    println!("Hello, world!");
+    match module_paths {
+        Some(paths) => paths.iter().try_for_each(|path| registry.add_libdir(path)),
+        None => registry.add_well_known(),
+    }
+    .map_err(|err| Failure::internal(format!("Unable to load modules: {:?}", err)))?;
+
+    Ok(registry)
 }
 
-#[cfg(test)]
-mod test {
-    #[test]
-    fn dummy() {
-        assert!(true);
+fn schema_dump(matches: &clap::ArgMatches, config: &Config) -> Result<(), Failure> {
+    let registry = match matches.get_one::<PathBuf>("libdir") {
+        Some(libdir) => load_registry(Some(std::slice::from_ref(libdir)))?,
+        None => load_registry(config.module_paths.as_deref())?,
+    };
+
+    let out = matches.get_one::<PathBuf>("out").unwrap();
+
+    for path in registry
+        .dump_schemas(out)
+        .map_err(|err| Failure::internal(format!("Unable to dump schemas: {:?}", err)))?
+    {
+        println!("{}", path.display());
+    }
+
+    Ok(())
+}
+
+fn schema_show(matches: &clap::ArgMatches, config: &Config) -> Result<(), Failure> {
+    let registry = load_registry(config.module_paths.as_deref())?;
+    let name = matches.get_one::<String>("name").unwrap();
+
+    let kind = matches
+        .get_one::<String>("kind")
+        .map(|kind| kind.parse::<Kind>().unwrap());
+
+    let module = registry
+        .modules()
+        .iter()
+        .find(|module| module.name() == name && kind.is_none_or(|kind| module.kind() == kind))
+        .ok_or_else(|| Failure::new(FailureKind::MissingModule, name.clone()))?;
+
+    let schema = module
+        .get_schema_json()
+        .map_err(|err| Failure::internal(format!("Unable to get schema: {:?}", err)))?;
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&schema).map_err(|err| Failure::internal(err.to_string()))?
+    );
+
+    Ok(())
+}
+
+fn store_diff(matches: &clap::ArgMatches, config: &Config) -> Result<(), Failure> {
+    let root = config
+        .store
+        .as_deref()
+        .ok_or_else(|| Failure::internal("No store configured, pass --store"))?;
+
+    let diff = ObjectStore::new(root)
+        .diff(
+            matches.get_one::<String>("from").unwrap(),
+            matches.get_one::<String>("to").unwrap(),
+        )
+        .map_err(|err| match err {
+            StoreError::NoSuchObject(id) => {
+                Failure::internal(format!("No object {} in the store", id))
+            }
+            err => Failure::internal(format!("Unable to compare objects: {:?}", err)),
+        })?;
+
+    if matches.contains_id("json") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&diff)
+                .map_err(|err| Failure::internal(err.to_string()))?
+        );
+    } else {
+        print!("{}", diff);
+    }
+
+    Ok(())
+}
+
+fn store_import(matches: &clap::ArgMatches, config: &Config) -> Result<(), Failure> {
+    let root = config
+        .store
+        .as_deref()
+        .ok_or_else(|| Failure::internal("No store configured, pass --store"))?;
+
+    let id = matches.get_one::<String>("id").unwrap();
+    let tree = ObjectStore::new(root)
+        .import_tree(matches.get_one::<PathBuf>("tree").unwrap(), id)
+        .map_err(|err| match err {
+            StoreError::ObjectExists(id) => Failure::new(
+                FailureKind::Validation,
+                format!("The object {} is in the store already", id),
+            ),
+            StoreError::NotATree(path) => Failure::new(
+                FailureKind::Validation,
+                format!("'{}' is not a directory", path.display()),
+            ),
+            StoreError::IdError(_) => {
+                Failure::new(FailureKind::Validation, format!("'{}' is not an id", id))
+            }
+            err => Failure::internal(format!("Unable to import the tree: {:?}", err)),
+        })?;
+
+    println!("{}", tree.display());
+
+    Ok(())
+}
+
+/// The cache of sources of the configured store, fetching through the configured proxy
+/// within the configured limits.
+fn source_manager(config: &Config) -> Result<SourceManager, Failure> {
+    let Some(store) = &config.store else {
+        return Err(Failure::new(
+            FailureKind::Validation,
+            "the cache of sources needs a store, pass --store",
+        ));
+    };
+
+    BuildConfig::from_config(config)
+        .source_manager(
+            &ObjectStore::new(store).sources_path(),
+            &Secrets::from_environment(),
+        )
+        .map_err(|err| Failure::internal(format!("{:?}", err)))
+}
+
+fn sources_export(matches: &clap::ArgMatches, config: &Config) -> Result<(), Failure> {
+    let sources = source_manager(config)?;
+    let directory = matches.get_one::<PathBuf>("directory").unwrap();
+
+    let Some(description) =
+        load_description(Path::new(matches.get_one::<String>("manifest").unwrap()))?
+    else {
+        return Ok(());
+    };
+
+    fs::create_dir_all(directory).map_err(|err| {
+        Failure::internal(format!(
+            "Unable to create '{}': {}",
+            directory.display(),
+            err
+        ))
+    })?;
+
+    let index = sources
+        .export_to(&description, directory)
+        .map_err(|err| match err {
+            SourceError::Missing(missing) => Failure::new(
+                FailureKind::Validation,
+                format!(
+                    "{}
+fetch them first with --fetch-only",
+                    MissingReport::new(&missing)
+                ),
+            ),
+            err => Failure::internal(format!("Unable to export sources: {:?}", err)),
+        })?;
+
+    eprintln!(
+        "exported {} item(s), {} bytes",
+        index.items.len(),
+        index.size()
+    );
+
+    Ok(())
+}
+
+fn sources_import(matches: &clap::ArgMatches, config: &Config) -> Result<(), Failure> {
+    let index = source_manager(config)?
+        .import_from(matches.get_one::<PathBuf>("directory").unwrap())
+        .map_err(|err| match err {
+            SourceError::ChecksumMismatch(checksum) => Failure::new(
+                FailureKind::Validation,
+                format!("{} doesn't match its checksum", checksum),
+            ),
+            SourceError::UnsupportedChecksum(checksum) => Failure::new(
+                FailureKind::Validation,
+                format!(
+                    "{} can't be verified, only sha256, sha384, and sha512 can",
+                    checksum
+                ),
+            ),
+            err => Failure::internal(format!("Unable to import sources: {:?}", err)),
+        })?;
+
+    eprintln!("imported {} item(s)", index.items.len());
+
+    Ok(())
+}
+
+/// A failure for the manifest at `path` whose sources could not be included.
+fn include_failure(path: &Path, err: IncludeError) -> Failure {
+    match err {
+        IncludeError::IOError(path, err) => {
+            Failure::internal(format!("Unable to read '{}': {}", path.display(), err))
+        }
+        err => Failure::new(
+            FailureKind::Validation,
+            format!("'{}': {:?}", path.display(), err),
+        ),
+    }
+}
+
+/// The manifest at `path`, with the sources its `sources-from` names merged in and its
+/// variables expanded.
+fn load_manifest(path: &Path) -> Result<serde_json::Value, Failure> {
+    include::load(path).map_err(|err| include_failure(path, err))
+}
+
+fn plan(matches: &clap::ArgMatches, config: &Config) -> Result<(), Failure> {
+    let path = Path::new(matches.get_one::<String>("manifest").unwrap());
+
+    let manifest: manifest::Manifest =
+        serde_json::from_value(load_manifest(path)?).map_err(|err| {
+            Failure::new(
+                FailureKind::Validation,
+                format!("'{}': {}", path.display(), err),
+            )
+        })?;
+
+    let plan = executor::plan::plan(
+        &manifest,
+        &Content::new(),
+        config.hash_algo.unwrap_or_default(),
+    )
+    .map_err(|err| Failure::new(FailureKind::Validation, format!("{:?}", err)))?;
+
+    if matches.contains_id("json") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&plan)
+                .map_err(|err| Failure::internal(err.to_string()))?
+        );
+    } else {
+        print!("{}", plan);
+    }
+
+    Ok(())
+}
+
+fn cost_report(matches: &clap::ArgMatches) -> Result<(), Failure> {
+    let path = Path::new(matches.get_one::<String>("result").unwrap());
+    let data = fs::read(path).map_err(|err| {
+        Failure::internal(format!("Unable to read '{}': {}", path.display(), err))
+    })?;
+
+    let result: BuildResult = serde_json::from_slice(&data).map_err(|err| {
+        Failure::internal(format!(
+            "'{}' is not a build result: {}",
+            path.display(),
+            err
+        ))
+    })?;
+
+    if result.resources.is_empty() {
+        return Err(Failure::internal(format!(
+            "'{}' has no resource accounting",
+            path.display()
+        )));
+    }
+
+    println!("{}", UsageTable::new(&result.resources));
+
+    Ok(())
+}
+
+/// Write `result` to `path` as JSON, as `cost_report` reads it.
+fn write_result(path: &Path, result: &BuildResult) -> Result<(), Failure> {
+    let data =
+        serde_json::to_vec_pretty(result).map_err(|err| Failure::internal(err.to_string()))?;
+
+    fs::write(path, data)
+        .map_err(|err| Failure::internal(format!("Unable to write '{}': {}", path.display(), err)))
+}
+
+fn validate(matches: &clap::ArgMatches) -> Result<(), Failure> {
+    let path = Path::new(matches.get_one::<String>("manifest").unwrap());
+    let text = fs::read_to_string(path).map_err(|err| {
+        Failure::internal(format!("Unable to read '{}': {}", path.display(), err))
+    })?;
+
+    let source = Source::parse(&path.to_string_lossy(), &text).map_err(|err| {
+        Failure::new(
+            FailureKind::Validation,
+            format!("'{}': {:?}", path.display(), err),
+        )
+    })?;
+
+    let mut manifest: serde_json::Value =
+        serde_json::from_str(source.text()).map_err(|err| Failure::internal(err.to_string()))?;
+
+    include::resolve_sources(&mut manifest, path.parent().unwrap_or(Path::new("")))
+        .and_then(|_| Ok(variables::expand(&mut manifest)?))
+        .map_err(|err| include_failure(path, err))?;
+
+    let schemas = match matches.get_one::<PathBuf>("schema-dir") {
+        Some(dir) => Schemas::from_dir(dir).map_err(|err| {
+            Failure::internal(format!(
+                "Unable to read the schemas in '{}': {:?}",
+                dir.display(),
+                err
+            ))
+        })?,
+        None => Schemas::embedded(),
+    };
+
+    let mut result = Validator::against_format_schemas(&schemas)
+        .map_err(|err| Failure::internal(format!("{:?}", err)))?
+        .validate(&manifest);
+
+    if matches.contains_id("deny-warnings") {
+        result.deny_warnings();
+    }
+
+    if matches.contains_id("sarif") {
+        let mut sarif = Sarif::new();
+        sarif.add(&source, &result);
+
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&sarif.to_json())
+                .map_err(|err| Failure::internal(err.to_string()))?
+        );
+    }
+
+    if !result.is_valid() {
+        return Err(Failure::new(
+            FailureKind::Validation,
+            format!(
+                "'{}' is invalid\n\n{}",
+                path.display(),
+                result.render(&source)
+            ),
+        ));
+    }
+
+    if !matches.contains_id("sarif") && !result.warnings().is_empty() {
+        eprintln!("{}", result.render(&source));
+    }
+
+    Ok(())
+}
+
+/// Serve the control protocol of the worker until receiving from the socket fails.
+fn worker(matches: &clap::ArgMatches, config: &Config) -> Result<(), Failure> {
+    if let Some(bus) = matches.get_one::<String>("dbus") {
+        return dbus_worker(bus, config);
+    }
+
+    let activated = systemd::activated_socket()
+        .map_err(|err| Failure::internal(format!("Unable to take the passed socket: {}", err)))?;
+
+    let socket = match (activated, matches.get_one::<PathBuf>("socket")) {
+        (Some(socket), _) => socket,
+        (None, Some(path)) => bind_worker(path)?,
+        (None, None) => {
+            return Err(Failure::internal(
+                "No socket passed by the service manager, pass a socket",
+            ))
+        }
+    };
+
+    let notifier = systemd::Notifier::from_env()
+        .map_err(|err| Failure::internal(format!("Unable to notify: {}", err)))?
+        .map(Arc::new);
+
+    if let Some(notifier) = &notifier {
+        let _ = notifier.ready("Waiting for jobs");
+        notifier.clone().watchdog_from_env();
+    }
+
+    let result = Worker::new(config.clone())
+        .serve(&socket)
+        .map_err(|err| Failure::internal(format!("Unable to serve: {}", err)));
+
+    if let Some(notifier) = &notifier {
+        let _ = notifier.stopping();
+    }
+
+    result
+}
+
+/// Serve the worker as `org.osbuild.Worker1` on the session or system bus.
+#[cfg(feature = "dbus")]
+fn dbus_worker(bus: &str, config: &Config) -> Result<(), Failure> {
+    use libosbuild::core::worker::dbus::{self, Bus};
+
+    let bus = if bus == "system" {
+        Bus::System
+    } else {
+        Bus::Session
+    };
+
+    dbus::serve_bus(Worker::new(config.clone()), bus)
+        .map_err(|err| Failure::internal(format!("Unable to serve on D-Bus: {}", err)))
+}
+
+#[cfg(not(feature = "dbus"))]
+fn dbus_worker(_bus: &str, _config: &Config) -> Result<(), Failure> {
+    Err(Failure::internal(
+        "Built without D-Bus support, enable the `dbus` feature",
+    ))
+}
+
+/// Listen on `path`, replacing a socket left behind by an earlier worker.
+fn bind_worker(path: &Path) -> Result<UnixDatagram, Failure> {
+    // a socket left behind by an earlier worker
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(Failure::internal(format!(
+                "Unable to remove '{}': {}",
+                path.display(),
+                err
+            )))
+        }
+        _ => {}
+    }
+
+    UnixDatagram::bind(path).map_err(|err| {
+        Failure::internal(format!("Unable to listen on '{}': {}", path.display(), err))
+    })
+}
+
+/// Serve editors, without modules manifests are only checked against the format schemas.
+fn language_server(config: &Config) -> Result<(), Failure> {
+    let registry = load_registry(config.module_paths.as_deref()).unwrap_or_else(|failure| {
+        eprintln!("{}, continuing without modules", failure);
+        Registry::new_empty()
+    });
+
+    let mut server = lsp::Server::from_registry(&registry)
+        .map_err(|err| Failure::internal(format!("{:?}", err)))?;
+
+    lsp::serve(
+        &mut server,
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+    )
+    .map_err(|err| Failure::internal(err.to_string()))
+}
+
+fn completions(matches: &clap::ArgMatches) -> Result<(), Failure> {
+    let shell = *matches.get_one::<clap_complete::Shell>("shell").unwrap();
+    let mut command = make_cli();
+
+    cli::completions(
+        shell,
+        &mut command,
+        "osbuild",
+        DYNAMIC_VALUES,
+        &mut io::stdout(),
+    )
+    .map_err(|err| Failure::internal(err.to_string()))
+}
+
+/// The names of the pipelines in the manifest at `path`.
+fn pipeline_names(path: &Path) -> Result<Vec<String>, Failure> {
+    let data = fs::read(path).map_err(|err| {
+        Failure::internal(format!("Unable to read '{}': {}", path.display(), err))
+    })?;
+
+    manifest::pipeline_names(&data).map_err(|err| {
+        Failure::new(
+            FailureKind::Validation,
+            format!("'{}': {:?}", path.display(), err),
+        )
+    })
+}
+
+fn complete(matches: &clap::ArgMatches, config: &Config) -> Result<(), Failure> {
+    let values = match matches.get_one::<String>("source").unwrap().as_str() {
+        "modules" => load_registry(config.module_paths.as_deref())?
+            .modules()
+            .iter()
+            .map(|module| module.name().to_string())
+            .collect(),
+        _ => match matches.get_one::<String>("manifest") {
+            Some(path) => pipeline_names(Path::new(path))?,
+            None => vec![],
+        },
+    };
+
+    for value in values {
+        println!("{}", value);
+    }
+
+    Ok(())
+}
+
+/// Check that the pipelines named by `--export` and `--checkpoint` exist in the manifest.
+fn check_pipelines(manifest: &Path, requested: &[String]) -> Result<(), Failure> {
+    if requested.is_empty() {
+        return Ok(());
+    }
+
+    let names = pipeline_names(manifest)?;
+
+    match requested.iter().find(|name| !names.contains(name)) {
+        Some(name) => Err(Failure::new(
+            FailureKind::Validation,
+            format!("no pipeline named '{}' in the manifest", name),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check that a build can run with the privileges it has. Without root privileges this
+/// has to be asked for explicitly and every module in the manifest must support it.
+fn check_privileges(manifest: &Path, unprivileged: bool) -> Result<(), Failure> {
+    if !unprivileged {
+        if unprivileged::is_root() {
+            return Ok(());
+        }
+
+        return Err(Failure::internal(
+            "osbuild needs root privileges, pass --unprivileged to build in a user namespace",
+        ));
+    }
+
+    let data = fs::read(manifest).map_err(|err| {
+        Failure::internal(format!("Unable to read '{}': {}", manifest.display(), err))
+    })?;
+
+    let modules = manifest::module_names(&data).map_err(|err| {
+        Failure::new(
+            FailureKind::Validation,
+            format!("'{}': {:?}", manifest.display(), err),
+        )
+    })?;
+
+    match unprivileged::check_modules(&modules) {
+        Ok(()) => Ok(()),
+        Err(UnprivilegedError::Unsupported(unsupported)) => Err(Failure::new(
+            FailureKind::Validation,
+            unsupported
+                .iter()
+                .map(|(name, reason)| format!("{} can't run unprivileged: {}", name, reason))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )),
+        Err(err) => Err(Failure::internal(format!("{:?}", err))),
+    }
+}
+
+/// Run this osbuild again with the same arguments in a user namespace in which the user is
+/// root, with the subordinate ids of the user mapped, see `unprivileged::Mapping`. It
+/// reports its result itself, a build that fails exits with its exit code.
+fn build_in_user_namespace() -> Result<(), Failure> {
+    let mapping = unprivileged::Mapping::for_current_user()
+        .map_err(|err| Failure::internal(format!("{:?}", err)))?;
+    let program = env::current_exe()
+        .map_err(|err| Failure::internal(format!("Unable to find osbuild: {}", err)))?;
+
+    let status = mapping
+        .command(&program)
+        .args(env::args_os().skip(1))
+        .status()
+        .map_err(|err| Failure::internal(format!("Unable to run unshare: {}", err)))?;
+
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+
+    Ok(())
+}
+
+/// The manifest at `manifest` when it is a version 2 manifest.
+fn load_description(manifest: &Path) -> Result<Option<manifest::Manifest>, Failure> {
+    let value = load_manifest(manifest)?;
+
+    if manifest::Version::detect(&value).ok() != Some(manifest::Version::V2) {
+        return Ok(None);
+    }
+
+    serde_json::from_value(value).map(Some).map_err(|err| {
+        Failure::new(
+            FailureKind::Validation,
+            format!("'{}': {}", manifest.display(), err),
+        )
+    })
+}
+
+/// Fetch the sources of the manifest at `manifest` into the cache of sources of the store
+/// without running any stage, so an online machine can prepare the cache for an offline
+/// build. Items only their source module can fetch are warned about.
+fn fetch_only(manifest: &Path, config: &Config) -> Result<BuildResult, Failure> {
+    let sources = source_manager(config)?;
+
+    let Some(description) = load_description(manifest)? else {
+        return Ok(BuildResult::success());
+    };
+
+    let prefetched = sources
+        .prefetch(&description)
+        .map_err(|err| Failure::internal(format!("Unable to fetch sources: {:?}", err)))?;
+
+    eprintln!(
+        "fetched {} item(s), {} were cached",
+        prefetched.fetched, prefetched.cached
+    );
+
+    let mut result = BuildResult::success();
+
+    for item in &prefetched.unsupported {
+        result.diagnose(
+            Severity::Warning,
+            format!(
+                "{} of {} can only be fetched by its source module",
+                item.checksum, item.source
+            ),
+        );
+    }
+
+    Ok(result)
+}
+
+/// Check that an offline build finds every item of its network sources in the cache of
+/// sources of the store, listing those it doesn't.
+fn check_offline(manifest: &Path, config: &Config) -> Result<(), Failure> {
+    if config.offline != Some(true) {
+        return Ok(());
+    }
+
+    let Some(store) = &config.store else {
+        return Err(Failure::new(
+            FailureKind::Validation,
+            "offline builds need a store with a cache of sources",
+        ));
+    };
+
+    let Some(description) = load_description(manifest)? else {
+        return Ok(());
+    };
+
+    let build = BuildConfig {
+        offline: true,
+        ..Default::default()
+    };
+
+    build
+        .preflight(&description, &ObjectStore::new(store).sources_path())
+        .map_err(|missing| {
+            Failure::new(
+                FailureKind::Validation,
+                MissingReport::new(&missing).to_string(),
+            )
+        })
+}
+
+/// The runner each pipeline of a version 2 manifest runs with, as `(pipeline, runner)`. A
+/// runner a pipeline asks for is used over the one detected for the host.
+fn select_runners(manifest: &Path, registry: &Registry) -> Result<Vec<(String, String)>, Failure> {
+    let invalid = |err: String| {
+        Failure::new(
+            FailureKind::Validation,
+            format!("'{}': {}", manifest.display(), err),
+        )
+    };
+
+    let value = load_manifest(manifest)?;
+
+    if manifest::Version::detect(&value).map_err(|err| invalid(format!("{:?}", err)))?
+        != manifest::Version::V2
+    {
+        return Ok(vec![]);
+    }
+
+    let description: manifest::Manifest =
+        serde_json::from_value(value).map_err(|err| invalid(err.to_string()))?;
+
+    let available: Vec<String> = registry
+        .by_kind(Kind::Runner)
+        .unwrap_or_default()
+        .iter()
+        .map(|module| module.name().to_string())
+        .collect();
+
+    let host = runner::host_runner_name().map_err(|err| Failure::internal(format!("{:?}", err)))?;
+
+    description
+        .pipelines()
+        .iter()
+        .map(|pipeline| {
+            runner::select(pipeline.runner(), &host, &available)
+                .map(|runner| (pipeline.name().to_string(), runner))
+                .map_err(|err| match err {
+                    RunnerError::NoSuchRunner(name, available) => Failure::new(
+                        FailureKind::Validation,
+                        format!(
+                            "pipeline '{}': runner '{}' is not available, available runners are: {}",
+                            pipeline.name(),
+                            name,
+                            available.join(", ")
+                        ),
+                    ),
+                    RunnerError::NoHostRunner(host) => Failure::new(
+                        FailureKind::MissingModule,
+                        format!("no runner for the host ('{}')", host),
+                    ),
+                    RunnerError::IOError(_) => Failure::internal(format!("{:?}", err)),
+                })
+        })
+        .collect()
+}
+
+/// The failure of a build that `build_with_config` couldn't finish.
+fn build_failure(err: BuildError) -> Failure {
+    match err {
+        BuildError::NoStore => Failure::new(
+            FailureKind::Validation,
+            "building needs a store, pass --store",
+        ),
+        BuildError::MissingSources(missing) => Failure::new(
+            FailureKind::Validation,
+            MissingReport::new(&missing).to_string(),
+        ),
+        BuildError::UnknownExport(name) => Failure::new(
+            FailureKind::Validation,
+            format!("no pipeline named '{}' in the manifest", name),
+        ),
+        BuildError::ExecutorError(err) => match err {
+            ExecutorError::MissingModule(name) => Failure::new(
+                FailureKind::MissingModule,
+                format!("no module named '{}'", name),
+            ),
+            ExecutorError::Cancelled(_) => {
+                Failure::new(FailureKind::Cancelled, format!("{:?}", err))
+            }
+            ExecutorError::InvalidStage(_) | ExecutorError::MissingInput(..) => {
+                Failure::new(FailureKind::Validation, format!("{:?}", err))
+            }
+            ExecutorError::StoreError(_) | ExecutorError::IOError(_) => {
+                Failure::internal(format!("{:?}", err))
+            }
+            err => Failure::new(FailureKind::StageFailure, format!("{:?}", err)),
+        },
+        err => Failure::internal(format!("{:?}", err)),
+    }
+}
+
+/// A token that is cancelled when the process gets SIGINT or SIGTERM, so that a build stops
+/// its stages as `build_with_config` describes instead of dying with them. The signals are
+/// blocked and waited for in a thread of their own; this has to be called before any other
+/// thread is started for them to stay blocked in all of them.
+fn cancel_on_signals() -> Result<CancellationToken, Failure> {
+    let token = CancellationToken::new();
+
+    // SAFETY: the set is initialized by sigemptyset before it is used, and only the mask of
+    // this thread, which threads started later inherit, is changed
+    let signals = unsafe {
+        let mut signals = std::mem::zeroed::<libc::sigset_t>();
+
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+
+        if libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) != 0 {
+            return Err(Failure::internal("Unable to block signals"));
+        }
+
+        signals
+    };
+
+    let cancelled = token.clone();
+
+    thread::spawn(move || loop {
+        let mut signal = 0;
+
+        // SAFETY: both pointers are to locals that outlive the call
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            continue;
+        }
+
+        cancelled.cancel(if signal == libc::SIGINT {
+            "interrupted"
+        } else {
+            "terminated"
+        });
+    });
+
+    Ok(token)
+}
+
+fn build(
+    manifest: &Path,
+    unprivileged: bool,
+    config: &Config,
+    build: &BuildConfig,
+    monitor_fd: Option<i32>,
+    cancellation: Option<&CancellationToken>,
+    report: &mut TimeReport,
+) -> BuildResult {
+    if let Err(failure) = report.time("check", || {
+        check_pipelines(
+            manifest,
+            &[&build.exports[..], &build.checkpoints[..]].concat(),
+        )
+        .and_then(|_| check_privileges(manifest, unprivileged))
+        .and_then(|_| check_offline(manifest, config))
+    }) {
+        return failure.into();
+    }
+
+    if unprivileged && !unprivileged::is_root() {
+        return report
+            .time("build", build_in_user_namespace)
+            .map(|_| BuildResult::success())
+            .unwrap_or_else(BuildResult::from);
+    }
+
+    if let Err(failure) = report.time("recover", || recover_store(config.store.as_deref())) {
+        return failure.into();
+    }
+
+    let name = config.monitor.as_deref().unwrap_or("log");
+
+    let mut monitor = match report.time("monitor", || monitor::make(name, monitor_fd)) {
+        Ok(monitor) => monitor,
+        Err(err) => {
+            return Failure::internal(format!("Unable to create monitor: {:?}", err)).into()
+        }
+    };
+
+    let result = report
+        .time("modules", || load_registry(config.module_paths.as_deref()))
+        .and_then(|registry| report.time("runners", || select_runners(manifest, &registry)))
+        .and_then(|runners| match load_description(manifest)? {
+            Some(description) => Ok((description, runners)),
+            None => Err(Failure::new(
+                FailureKind::Validation,
+                format!(
+                    "'{}': only version 2 manifests can be built",
+                    manifest.display()
+                ),
+            )),
+        })
+        .and_then(|(description, runners)| {
+            let build = BuildConfig {
+                runners: runners.into_iter().collect(),
+                ..build.clone()
+            };
+
+            report.time("build", || {
+                build_with_config(&description, &build, monitor.as_mut(), cancellation)
+                    .map_err(build_failure)
+            })
+        })
+        .unwrap_or_else(BuildResult::from);
+
+    monitor.finish(result.is_success());
+
+    result
+}
+
+fn main() {
+    let matches = make_cli().get_matches();
+
+    if matches.contains_id("dump-cli-json") {
+        println!("{}", cli::describe(&make_cli(), DYNAMIC_VALUES));
+        return;
+    }
+
+    let mut report = TimeReport::new();
+
+    let result = report
+        .time("config", || load_config(&matches))
+        .and_then(|config| match matches.subcommand() {
+            Some(("depsolve", matches)) => depsolve(matches, &config),
+            Some(("schema", matches)) => match matches.subcommand() {
+                Some(("dump", matches)) => schema_dump(matches, &config),
+                Some(("show", matches)) => schema_show(matches, &config),
+                _ => unreachable!(),
+            },
+            Some(("store", matches)) => match matches.subcommand() {
+                Some(("diff", matches)) => store_diff(matches, &config),
+                Some(("import", matches)) => store_import(matches, &config),
+                _ => unreachable!(),
+            },
+            Some(("sources", matches)) => match matches.subcommand() {
+                Some(("export", matches)) => sources_export(matches, &config),
+                Some(("import", matches)) => sources_import(matches, &config),
+                _ => unreachable!(),
+            },
+            Some(("plan", matches)) => plan(matches, &config),
+            Some(("validate", matches)) => validate(matches),
+            Some(("report", matches)) => cost_report(matches),
+            Some(("lsp", _)) => language_server(&config),
+            Some(("worker", matches)) => worker(matches, &config),
+            Some(("completions", matches)) => completions(matches),
+            Some(("complete", matches)) => complete(matches, &config),
+            _ if matches.contains_id("fetch-only") => {
+                let mut result = fetch_only(
+                    Path::new(matches.get_one::<String>("manifest").unwrap()),
+                    &config,
+                )
+                .unwrap_or_else(BuildResult::from);
+
+                for diagnostic in &result.diagnostics {
+                    eprintln!("{}", diagnostic);
+                }
+
+                if matches.contains_id("deny-warnings") {
+                    result.deny_warnings();
+                }
+
+                if let Some(path) = matches.get_one::<PathBuf>("result") {
+                    write_result(path, &result)?;
+                }
+
+                result.into()
+            }
+            _ => {
+                let cancellation = cancel_on_signals()?;
+                let mut build_config = BuildConfig::from_config(&config);
+
+                build_config.exports = matches
+                    .get_many::<String>("export")
+                    .map(|names| names.cloned().collect())
+                    .unwrap_or_default();
+
+                build_config.checkpoints = matches
+                    .get_many::<String>("checkpoint")
+                    .map(|names| names.cloned().collect())
+                    .unwrap_or_default();
+
+                if let Some(directory) = matches.get_one::<PathBuf>("output-directory") {
+                    build_config.output_directory = directory.clone();
+                }
+
+                let mut result = build(
+                    Path::new(matches.get_one::<String>("manifest").unwrap()),
+                    matches.contains_id("unprivileged"),
+                    &config,
+                    &build_config,
+                    matches.get_one::<i32>("monitor-fd").copied(),
+                    Some(&cancellation),
+                    &mut report,
+                );
+
+                for diagnostic in &result.diagnostics {
+                    eprintln!("{}", diagnostic);
+                }
+
+                if matches.contains_id("deny-warnings") {
+                    result.deny_warnings();
+                }
+
+                if let Some(path) = matches.get_one::<PathBuf>("result") {
+                    write_result(path, &result)?;
+                }
+
+                result.into()
+            }
+        });
+
+    if matches.contains_id("time-report") {
+        eprintln!("{}", report);
+    }
+
+    if let Err(failure) = result {
+        eprintln!("{}", failure);
+        process::exit(failure.kind.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cli_verify() {
+        make_cli().debug_assert();
+    }
+
+    #[test]
+    fn cli_schema_show_kind() {
+        let matches = make_cli()
+            .try_get_matches_from([
+                "osbuild",
+                "schema",
+                "show",
+                "--kind",
+                "input",
+                "org.osbuild.ostree",
+            ])
+            .unwrap();
+
+        let (_, matches) = matches.subcommand().unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        assert_eq!(matches.get_one::<String>("kind").unwrap(), "input");
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild", "schema", "show", "--kind", "inputs", "x"])
+            .is_err());
+    }
+
+    #[test]
+    fn cli_depsolve() {
+        let matches = make_cli()
+            .try_get_matches_from([
+                "osbuild",
+                "depsolve",
+                "--repo",
+                "fedora.repo",
+                "--spec",
+                "@core",
+                "--spec",
+                "vim-minimal",
+                "--arch",
+                "aarch64",
+            ])
+            .unwrap();
+
+        let (name, matches) = matches.subcommand().unwrap();
+
+        assert_eq!(name, "depsolve");
+        assert_eq!(matches.get_many::<String>("spec").unwrap().count(), 2);
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild", "depsolve", "--arch", "aarch64"])
+            .is_err());
+
+        // groups alone are enough to resolve
+        let matches = make_cli()
+            .try_get_matches_from([
+                "osbuild",
+                "depsolve",
+                "--repo",
+                "fedora.repo",
+                "--group",
+                "core",
+                "--enable-module",
+                "nodejs:20",
+                "--no-weak-deps",
+                "--arch",
+                "aarch64",
+            ])
+            .unwrap();
+
+        let (_, matches) = matches.subcommand().unwrap();
+
+        assert_eq!(matches.get_many::<String>("group").unwrap().count(), 1);
+        assert!(!matches.contains_id("spec"));
+        assert!(matches.contains_id("no-weak-deps"));
+        assert!(!matches.contains_id("best"));
+    }
+
+    #[test]
+    fn cli_schema_dump() {
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild", "schema", "dump", "--out", "schemas/"])
+            .unwrap();
+
+        let (_, matches) = matches.subcommand().unwrap();
+        let (name, matches) = matches.subcommand().unwrap();
+
+        assert_eq!(name, "dump");
+        assert_eq!(
+            matches.get_one::<PathBuf>("out").unwrap(),
+            &PathBuf::from("schemas/")
+        );
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild", "schema"])
+            .is_err());
+    }
+
+    #[test]
+    fn cli_monitor() {
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild", "manifest.json"])
+            .unwrap();
+
+        assert_eq!(matches.get_one::<String>("monitor"), None);
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild", "--monitor", "nope", "manifest.json"])
+            .is_err());
+
+        let matches = make_cli()
+            .try_get_matches_from([
+                "osbuild",
+                "--monitor",
+                "jsonseq",
+                "--monitor-fd",
+                "3",
+                "manifest.json",
+            ])
+            .unwrap();
+
+        assert_eq!(matches.get_one::<i32>("monitor-fd"), Some(&3));
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild", "--monitor-fd", "-1", "manifest.json"])
+            .is_err());
+    }
+
+    #[test]
+    fn cli_overrides_config() {
+        let matches = make_cli()
+            .try_get_matches_from([
+                "osbuild",
+                "--store",
+                "/store",
+                "-m",
+                "/a",
+                "-m",
+                "/b",
+                "--source-date-epoch",
+                "1700000000",
+                "--workspace",
+                "/run/user/1000/osbuild",
+                "manifest.json",
+            ])
+            .unwrap();
+
+        let mut config = Config {
+            store: Some(PathBuf::from("/configured")),
+            monitor: Some("null".to_string()),
+            ..Default::default()
+        };
+
+        apply_flags(&mut config, &matches);
+
+        assert_eq!(config.store, Some(PathBuf::from("/store")));
+        assert_eq!(config.monitor.as_deref(), Some("null"));
+        assert_eq!(config.source_date_epoch, Some(1700000000));
+        assert_eq!(
+            config.workspace,
+            Some(PathBuf::from("/run/user/1000/osbuild"))
+        );
+        assert_eq!(
+            config.module_paths,
+            Some(vec![PathBuf::from("/a"), PathBuf::from("/b")])
+        );
+    }
+
+    #[test]
+    fn offline_checked() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+
+        fs::write(
+            &path,
+            r#"{"version": "2", "sources": {"org.osbuild.curl": {"items": {"sha256:aa": "https://example.com/a"}}}}"#,
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+
+        assert!(check_offline(&path, &config).is_ok());
+
+        config.offline = Some(true);
+
+        assert_eq!(
+            check_offline(&path, &config).unwrap_err().kind,
+            FailureKind::Validation
+        );
+
+        config.store = Some(directory.path().join("store"));
+
+        let failure = check_offline(&path, &config).unwrap_err();
+
+        assert!(failure.message.contains("sha256:aa"));
+
+        let cache = ObjectStore::new(&directory.path().join("store"))
+            .sources_path()
+            .join("org.osbuild.curl");
+
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(cache.join("sha256:aa"), "a").unwrap();
+
+        assert!(check_offline(&path, &config).is_ok());
+    }
+
+    #[test]
+    fn sources_fetched_only() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+        let file = directory.path().join("file");
+
+        fs::write(&file, "content").unwrap();
+        fs::write(
+            &path,
+            serde_json::json!({
+                "version": "2",
+                "sources": {
+                    "org.osbuild.curl": {"items": {
+                        format!("sha256:{}", libosbuild::core::export::sha256(&file).unwrap()): format!("file://{}", file.display())
+                    }},
+                    "org.osbuild.skopeo": {"items": {"sha256:cc": {"image": {"name": "fedora"}}}}
+                }
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+
+        assert_eq!(
+            fetch_only(&path, &config).unwrap_err().kind,
+            FailureKind::Validation
+        );
+
+        config.store = Some(directory.path().join("store"));
+
+        let mut result = fetch_only(&path, &config).unwrap();
+
+        assert!(result.is_success());
+        assert_eq!(result.warnings().count(), 1);
+
+        // what was fetched is what an offline build finds
+        config.offline = Some(true);
+
+        assert!(check_offline(&path, &config)
+            .unwrap_err()
+            .message
+            .contains("1 item(s) missing"));
+
+        result.deny_warnings();
+
+        assert_eq!(result.exit_code(), 3);
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild", "--offline", "--fetch-only", "manifest.json"])
+            .is_err());
+    }
+
+    #[test]
+    fn sources_carried() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+        let file = directory.path().join("file");
+        let exported = directory.path().join("exported");
+
+        fs::write(&file, "content").unwrap();
+        fs::write(
+            &path,
+            serde_json::json!({
+                "version": "2",
+                "sources": {
+                    "org.osbuild.curl": {"items": {
+                        format!("sha256:{}", libosbuild::core::export::sha256(&file).unwrap()): format!("file://{}", file.display())
+                    }}
+                }
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let mut config = Config {
+            store: Some(directory.path().join("store")),
+            ..Default::default()
+        };
+
+        let run = |args: &[&str], config: &Config| {
+            let matches = make_cli().try_get_matches_from(args).unwrap();
+            let (_, matches) = matches.subcommand().unwrap();
+
+            match matches.subcommand().unwrap() {
+                ("export", matches) => sources_export(matches, config),
+                (_, matches) => sources_import(matches, config),
+            }
+        };
+
+        let export = [
+            "osbuild",
+            "sources",
+            "export",
+            path.to_str().unwrap(),
+            exported.to_str().unwrap(),
+        ];
+
+        assert!(run(&export, &config)
+            .unwrap_err()
+            .message
+            .contains("--fetch-only"));
+
+        fetch_only(&path, &config).unwrap();
+        run(&export, &config).unwrap();
+
+        // another store builds offline from what was carried over
+        config.store = Some(directory.path().join("elsewhere"));
+        config.offline = Some(true);
+
+        assert!(check_offline(&path, &config).is_err());
+
+        run(
+            &["osbuild", "sources", "import", exported.to_str().unwrap()],
+            &config,
+        )
+        .unwrap();
+
+        assert!(check_offline(&path, &config).is_ok());
+    }
+
+    #[test]
+    fn cli_introspection() {
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild", "--dump-cli-json"])
+            .is_ok());
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild", "--dump-cli-json", "manifest.json"])
+            .is_err());
+
+        let description = cli::describe(&make_cli(), DYNAMIC_VALUES);
+        let args = description["args"].as_array().unwrap();
+
+        let export = args.iter().find(|arg| arg["name"] == "export").unwrap();
+        assert_eq!(export["dynamic_values"], "pipelines");
+
+        let monitor = args.iter().find(|arg| arg["name"] == "monitor").unwrap();
+        assert!(monitor["possible_values"]
+            .as_array()
+            .unwrap()
+            .contains(&"log".into()));
+
+        // hidden arguments and subcommands are not described
+        assert!(args.iter().all(|arg| arg["name"] != "dump-cli-json"));
+        assert!(description["subcommands"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|subcommand| subcommand["name"] != "complete"));
+    }
+
+    #[test]
+    fn cli_completions() {
+        let mut script = vec![];
+        cli::completions(
+            clap_complete::Shell::Bash,
+            &mut make_cli(),
+            "osbuild",
+            DYNAMIC_VALUES,
+            &mut script,
+        )
+        .unwrap();
+
+        let script = String::from_utf8(script).unwrap();
+
+        assert!(script.contains("depsolve"));
+        assert!(script.contains("\"${prev}\" == \"--checkpoint\""));
+        assert!(script.contains("osbuild complete modules"));
+    }
+
+    #[test]
+    fn export_must_name_pipeline() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+
+        fs::write(
+            &path,
+            r#"{"version": "2", "pipelines": [{"name": "build"}, {"name": "image"}]}"#,
+        )
+        .unwrap();
+
+        assert!(check_pipelines(&path, &["image".to_string()]).is_ok());
+        assert_eq!(
+            check_pipelines(&path, &["nope".to_string()])
+                .unwrap_err()
+                .kind,
+            FailureKind::Validation
+        );
+        assert!(check_pipelines(&directory.path().join("missing.json"), &[]).is_ok());
+    }
+
+    #[test]
+    fn unprivileged_unsupported_modules() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+
+        fs::write(
+            &path,
+            r#"{"version": "2", "pipelines": [{"name": "tree", "stages": [{"type": "org.osbuild.selinux"}]}]}"#,
+        )
+        .unwrap();
+
+        let failure = check_privileges(&path, true).unwrap_err();
+
+        assert_eq!(failure.kind, FailureKind::Validation);
+        assert!(failure.message.contains("org.osbuild.selinux"));
+
+        fs::write(
+            &path,
+            r#"{"version": "2", "pipelines": [{"name": "tree", "stages": [{"type": "org.osbuild.rpm"}]}]}"#,
+        )
+        .unwrap();
+
+        assert!(check_privileges(&path, true).is_ok());
+    }
+
+    #[test]
+    fn pipeline_runners() {
+        let directory = tempfile::tempdir().unwrap();
+        let runners = directory.path().join("lib/runners");
+
+        fs::create_dir_all(&runners).unwrap();
+
+        for name in ["org.osbuild.fedora38", "org.osbuild.linux"] {
+            fs::write(runners.join(name), "").unwrap();
+        }
+
+        let registry = load_registry(Some(&[directory.path().join("lib")])).unwrap();
+        let path = directory.path().join("manifest.json");
+
+        fs::write(
+            &path,
+            r#"{"version": "2", "pipelines": [{"name": "build", "runner": "org.osbuild.fedora38"}, {"name": "os"}]}"#,
+        )
+        .unwrap();
+
+        let selected = select_runners(&path, &registry).unwrap();
+
+        assert_eq!(
+            selected[0],
+            ("build".to_string(), "org.osbuild.fedora38".to_string())
+        );
+        assert_eq!(selected[1].0, "os");
+
+        fs::write(
+            &path,
+            r#"{"version": "2", "pipelines": [{"name": "build", "runner": "org.osbuild.centos9"}]}"#,
+        )
+        .unwrap();
+
+        let failure = select_runners(&path, &registry).unwrap_err();
+
+        assert_eq!(failure.kind, FailureKind::Validation);
+        assert!(failure
+            .message
+            .contains("available runners are: org.osbuild.fedora38, org.osbuild.linux"));
+
+        fs::write(&path, r#"{"pipeline": {}}"#).unwrap();
+        assert!(select_runners(&path, &registry).unwrap().is_empty());
+    }
+
+    #[test]
+    fn cli_manifest_required() {
+        assert!(make_cli().try_get_matches_from(["osbuild"]).is_err());
+        assert!(make_cli()
+            .try_get_matches_from(["osbuild", "manifest.json"])
+            .is_ok());
+    }
+
+    #[test]
+    fn time_report() {
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild", "--time-report", "manifest.json"])
+            .unwrap();
+
+        assert!(matches.contains_id("time-report"));
+
+        let mut report = TimeReport::new();
+        let result = build(
+            Path::new("/nonexistent/manifest.json"),
+            false,
+            &Config::default(),
+            &BuildConfig {
+                exports: vec!["os".to_string()],
+                ..Default::default()
+            },
+            None,
+            None,
+            &mut report,
+        );
+
+        assert!(!result.is_success());
+        assert_eq!(report.phases().len(), 1);
+        assert_eq!(report.phases()[0].name, "check");
+    }
+
+    #[test]
+    fn manifest_built() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let lib = directory.path().join("lib");
+
+        for (path, body) in [
+            ("runners/org.osbuild.linux", "exec \"$@\""),
+            (
+                "stages/org.osbuild.disk",
+                "[ \"$1\" = --schema ] && echo '{}' && exit 0\n\
+                 tree=$(sed -n 's/.*\"tree\":\"\\([^\"]*\\)\".*/\\1/p')\n\
+                 echo disk > \"$tree/disk.raw\"",
+            ),
+        ] {
+            let path = lib.join(path);
+
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let manifest = directory.path().join("manifest.json");
+
+        fs::write(
+            &manifest,
+            r#"{"version": "2", "pipelines": [{"name": "image", "stages": [{"type": "org.osbuild.disk"}]}]}"#,
+        )
+        .unwrap();
+
+        let mut config = Config {
+            store: Some(directory.path().join("store")),
+            module_paths: Some(vec![lib]),
+            monitor: Some("null".to_string()),
+            ..Default::default()
+        };
+
+        apply_flags(
+            &mut config,
+            &make_cli()
+                .try_get_matches_from(["osbuild", "--source-date-epoch", "7", "manifest.json"])
+                .unwrap(),
+        );
+
+        let mut build_config = BuildConfig::from_config(&config);
+
+        build_config.exports = vec!["image".to_string()];
+        build_config.output_directory = directory.path().join("output");
+
+        let result = build(
+            &manifest,
+            false,
+            &config,
+            &build_config,
+            None,
+            None,
+            &mut TimeReport::new(),
+        );
+
+        assert!(result.is_success(), "{:?}", result);
+        assert_eq!(result.source_date_epochs["image"], 7);
+        assert_eq!(
+            fs::read_to_string(directory.path().join("output/image/disk.raw")).unwrap(),
+            "disk\n"
+        );
+
+        // what the build cost can be reported from its result
+        let path = directory.path().join("result.json");
+
+        write_result(&path, &result).unwrap();
+
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild", "report", &path.to_string_lossy()])
+            .unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        assert!(cost_report(matches).is_ok());
+    }
+
+    #[test]
+    fn store_recovered() {
+        let store = tempfile::tempdir().unwrap();
+        let staging = store.path().join("stage/a");
+
+        fs::create_dir_all(&staging).unwrap();
+
+        journal::Journal::open(store.path())
+            .unwrap()
+            .record(&journal::Entry::Staging {
+                path: staging.clone(),
+            })
+            .unwrap();
+
+        assert!(recover_store(None).is_ok());
+        assert!(recover_store(Some(store.path())).is_ok());
+        assert!(!staging.exists());
+    }
+
+    #[test]
+    fn plan_printed() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+
+        fs::write(
+            &path,
+            r#"{"version": "2", "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.noop"}]}]}"#,
+        )
+        .unwrap();
+
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild", "plan", "--json", &path.to_string_lossy()])
+            .unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        assert!(plan(matches, &Config::default()).is_ok());
+
+        fs::write(&path, "{}").unwrap();
+
+        assert_eq!(
+            plan(matches, &Config::default()).unwrap_err().kind,
+            FailureKind::Validation
+        );
+
+        // sources in a file of their own have to agree with those of the manifest
+        fs::write(
+            directory.path().join("sources.json"),
+            r#"{"org.osbuild.curl": {"items": {"sha256:aa": "https://example.com/a"}}}"#,
+        )
+        .unwrap();
+        fs::write(
+            &path,
+            r#"{"version": "2", "sources-from": "sources.json", "pipelines": [{"name": "os"}]}"#,
+        )
+        .unwrap();
+
+        assert!(plan(matches, &Config::default()).is_ok());
+
+        fs::write(
+            &path,
+            r#"{"version": "2", "sources-from": "sources.json", "sources": {"org.osbuild.curl": {"items": {"sha256:aa": "https://example.com/b"}}}}"#,
+        )
+        .unwrap();
+
+        let failure = plan(matches, &Config::default()).unwrap_err();
+
+        assert_eq!(failure.kind, FailureKind::Validation);
+        assert!(failure.message.contains("Conflict"));
+    }
+
+    #[test]
+    fn report_printed() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("result.json");
+
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild", "report", &path.to_string_lossy()])
+            .unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        assert!(cost_report(matches).is_err());
+
+        fs::write(&path, r#"{"resources": {"os": {"duration_ms": 1200000}}}"#).unwrap();
+
+        assert!(cost_report(matches).is_ok());
+
+        fs::write(&path, r#"{"failure": "yes"}"#).unwrap();
+
+        assert_eq!(
+            cost_report(matches).unwrap_err().kind,
+            FailureKind::Internal
+        );
+    }
+
+    #[test]
+    fn validation_rendered() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+
+        fs::write(
+            &path,
+            "{\n  \"version\": \"2\",\n  \"pipelines\": [{\"name\": \"os\", \"stages\": [{\"type\": 1}]}]\n}\n",
+        )
+        .unwrap();
+
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild", "validate", &path.to_string_lossy()])
+            .unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        let failure = validate(matches).unwrap_err();
+
+        assert_eq!(failure.kind, FailureKind::Validation);
+        assert!(failure
+            .message
+            .contains("3 |   \"pipelines\": [{\"name\": \"os\", \"stages\": [{\"type\": 1}]}]"));
+        assert!(failure
+            .message
+            .contains("= at .pipelines[0].stages[0].type"));
+
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild", "validate", "--sarif", &path.to_string_lossy()])
+            .unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        assert_eq!(validate(matches).unwrap_err().kind, FailureKind::Validation);
+
+        fs::write(&path, r#"{"version": "2", "pipelines": []}"#).unwrap();
+
+        assert!(validate(matches).is_ok());
+    }
+
+    #[test]
+    fn warnings_denied() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+
+        // version 1 manifests are deprecated, which is only a warning
+        fs::write(&path, "{}").unwrap();
+
+        let matches = make_cli()
+            .try_get_matches_from(["osbuild", "validate", &path.to_string_lossy()])
+            .unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        assert!(validate(matches).is_ok());
+
+        let matches = make_cli()
+            .try_get_matches_from([
+                "osbuild",
+                "validate",
+                "--deny-warnings",
+                &path.to_string_lossy(),
+            ])
+            .unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        let failure = validate(matches).unwrap_err();
+
+        assert_eq!(failure.kind, FailureKind::Validation);
+        assert!(failure
+            .message
+            .contains("error: version 1 manifests are deprecated"));
+    }
+
+    #[test]
+    fn schemas_chosen() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+        let schemas = directory.path().join("schemas");
+
+        fs::write(&path, r#"{"version": "2", "pipelines": []}"#).unwrap();
+        fs::create_dir(&schemas).unwrap();
+        fs::write(schemas.join("osbuild1.json"), "{}").unwrap();
+        fs::write(
+            schemas.join("osbuild2.json"),
+            r#"{"required": ["sources"]}"#,
+        )
+        .unwrap();
+
+        let matches = make_cli()
+            .try_get_matches_from([
+                "osbuild",
+                "validate",
+                "--schema-dir",
+                &schemas.to_string_lossy(),
+                &path.to_string_lossy(),
+            ])
+            .unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        assert_eq!(validate(matches).unwrap_err().kind, FailureKind::Validation);
+
+        fs::remove_file(schemas.join("osbuild2.json")).unwrap();
+
+        assert_eq!(validate(matches).unwrap_err().kind, FailureKind::Internal);
+    }
+
+    #[test]
+    fn store_diffed() {
+        let store = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::new(store.path());
+
+        for (id, hostname) in [("aa", "a"), ("bb", "b")] {
+            let tree = objects
+                .object_path(id)
+                .unwrap()
+                .join(libosbuild::core::store::TREE_DIR);
+
+            fs::create_dir_all(&tree).unwrap();
+            fs::write(tree.join("hostname"), hostname).unwrap();
+        }
+
+        let matches = make_cli()
+            .try_get_matches_from([
+                "osbuild",
+                "--store",
+                &store.path().to_string_lossy(),
+                "store",
+                "diff",
+                "aa",
+                "bb",
+            ])
+            .unwrap();
+        let config = load_config(&matches).unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        assert!(store_diff(matches, &config).is_ok());
+        assert!(store_diff(matches, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn store_imported() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = directory.path().join("store");
+        let tree = directory.path().join("tree");
+
+        fs::create_dir(&tree).unwrap();
+        fs::write(tree.join("hostname"), "external").unwrap();
+
+        let store_arg = store.to_string_lossy();
+        let tree_arg = tree.to_string_lossy();
+        let matches = make_cli()
+            .try_get_matches_from([
+                "osbuild", "--store", &store_arg, "store", "import", &tree_arg, "aa",
+            ])
+            .unwrap();
+        let config = load_config(&matches).unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+
+        assert!(store_import(matches, &config).is_ok());
+        assert!(ObjectStore::new(&store).contains("aa"));
+        assert_eq!(
+            store_import(matches, &config).unwrap_err().kind,
+            FailureKind::Validation
+        );
     }
 }
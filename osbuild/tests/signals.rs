@@ -0,0 +1,84 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn module(lib: &Path, path: &str, body: &str) {
+    let path = lib.join(path);
+
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn builds_interrupted() {
+    let directory = tempfile::tempdir().unwrap();
+    let lib = directory.path().join("lib");
+    let started = directory.path().join("started");
+    let finished = directory.path().join("finished");
+
+    module(&lib, "runners/org.osbuild.linux", "exec \"$@\"");
+    module(
+        &lib,
+        "stages/org.osbuild.slow",
+        &format!(
+            "[ \"$1\" = --schema ] && echo '{{}}' && exit 0\ntouch '{}'\nsleep 2",
+            started.display()
+        ),
+    );
+    module(
+        &lib,
+        "stages/org.osbuild.after",
+        &format!(
+            "[ \"$1\" = --schema ] && echo '{{}}' && exit 0\ntouch '{}'",
+            finished.display()
+        ),
+    );
+
+    let manifest = directory.path().join("manifest.json");
+
+    fs::write(
+        &manifest,
+        r#"{"version": "2", "pipelines": [{"name": "os", "stages": [{"type": "org.osbuild.slow"}, {"type": "org.osbuild.after"}]}]}"#,
+    )
+    .unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_osbuild"))
+        .arg("--store")
+        .arg(directory.path().join("store"))
+        .arg("--module")
+        .arg(&lib)
+        .args(["--monitor", "null"])
+        .arg(&manifest)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let waiting = Instant::now();
+
+    while !started.exists() {
+        assert!(waiting.elapsed() < Duration::from_secs(30));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap()
+        .success());
+
+    let output = child.wait_with_output().unwrap();
+
+    // the running stage finishes, the one after it never starts
+    assert_eq!(
+        output.status.code(),
+        Some(130),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!finished.exists());
+}